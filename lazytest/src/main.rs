@@ -1,17 +1,26 @@
 use std::{
     env,
     io::Cursor,
+    ops::ControlFlow,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
 };
 
 use rusqlite::{Connection, OpenFlags, Result, params};
 // ── Imports from the evfs crate for backup API testing ──────────────
 use sqlevfs::backup;
 use sqlevfs::{
+    EvfsBuilder, Mode,
     crypto::keys::KeyScope,
     keyring::Keyring,
-    kms::{KmsProvider, local::DeviceKeyProvider},
+    kms::{
+        KmsProvider,
+        local::DeviceKeyProvider,
+        mock::{Call, MockKmsProvider},
+    },
 };
 
 // ────────────────────────────────────────────────────────────────────
@@ -137,6 +146,18 @@ fn run_sqlshim_tests(t: &mut TestRunner, mode: &str) -> Result<()> {
         }
     }
 
+    // Label syntax errors come back from sec_define_label through the shim
+    let stmt = "DEFINE LABEL 'role admin';";
+    match conn.execute_batch(stmt) {
+        Ok(()) => t.fail(stmt, &"malformed label was accepted"),
+        Err(e) => t.assert_eq(
+            "DEFINE LABEL syntax error",
+            &e.to_string()
+                .contains("expected comparison operator after identifier at position 5"),
+            &true,
+        ),
+    }
+
     // ── DEFINE LEVEL ────────────────────────────────────────────
     t.section("DEFINE LEVEL");
     for (name, val) in [
@@ -152,6 +173,30 @@ fn run_sqlshim_tests(t: &mut TestRunner, mode: &str) -> Result<()> {
         }
     }
 
+    let label_id: i64 = conn.query_row(
+        "SELECT sec_define_label('clearance>=confidential')",
+        [],
+        |row| row.get(0),
+    )?;
+    for (clearance, expected) in [
+        ("public", 0),
+        ("confidential", 1),
+        ("secret", 1),
+        ("top_secret", 1),
+    ] {
+        conn.execute_batch(&format!(
+            "CLEAR CONTEXT; SET CONTEXT clearance = '{clearance}';"
+        ))?;
+        let visible: i64 =
+            conn.query_row("SELECT sec_label_visible(?1)", [label_id], |row| row.get(0))?;
+        t.assert_eq(
+            &format!("clearance>=confidential visible to {clearance}"),
+            &visible,
+            &expected,
+        );
+    }
+    conn.execute_batch("CLEAR CONTEXT;")?;
+
     // ── CREATE POLICY ───────────────────────────────────────────
     t.section("CREATE POLICY");
     let policies = [
@@ -278,6 +323,38 @@ fn run_sqlshim_tests(t: &mut TestRunner, mode: &str) -> Result<()> {
         }
     }
 
+    // UPDATE labels written by the shim are enforced by the view's triggers
+    conn.execute_batch(
+        r#"
+        INSERT INTO __sec_employees (id, name, title, salary, department)
+        VALUES (1, 'Alice', 'Analyst', 50000, 'finance');
+        CLEAR CONTEXT;
+        SET CONTEXT role = 'user';
+        REFRESH SECURE VIEWS;
+        "#,
+    )?;
+    let stmt = "UPDATE employees SET title = 'Director' WHERE id = 1;";
+    match conn.execute_batch(stmt) {
+        Ok(()) => t.fail(stmt, &"update of a protected column was allowed"),
+        Err(e) => t.assert_eq(
+            "UPDATE label denies role=user",
+            &e.to_string().contains("update denied on column title"),
+            &true,
+        ),
+    }
+    conn.execute_batch("CLEAR CONTEXT; SET CONTEXT role = 'hr'; REFRESH SECURE VIEWS;")?;
+    match conn.execute_batch(stmt) {
+        Ok(()) => {
+            let title: String =
+                conn.query_row("SELECT title FROM __sec_employees WHERE id = 1", [], |row| {
+                    row.get(0)
+                })?;
+            t.assert_eq("UPDATE label allows role=hr", &title, &"Director".to_string());
+        }
+        Err(e) => t.fail("UPDATE label allows role=hr", &e),
+    }
+    conn.execute_batch("CLEAR CONTEXT;")?;
+
     // ── Stub Features ───────────────────────────────────────────
     t.section("Stub Features (audit / explain policy)");
     for stmt in [
@@ -352,11 +429,11 @@ fn run_evfs_vfs_tests(t: &mut TestRunner, mode: &str) -> Result<()> {
     println!("DB file created: {}", db_path.exists());
 
     // Set the reserve bytes so SQLite leaves room for the auth tag.
-    // This must match what evfs expects (48 by default in EvfsBuilder,
-    // but the init function uses the default builder which sets 48).
+    // This must match what evfs expects (80 by default in EvfsBuilder,
+    // but the init function uses the default builder which sets 80).
     // PRAGMA must be set before any tables are created.
-    conn.execute_batch("PRAGMA reserve_bytes = 48;")?;
-    t.ok("PRAGMA reserve_bytes = 48");
+    conn.execute_batch("PRAGMA reserve_bytes = 80;")?;
+    t.ok("PRAGMA reserve_bytes = 80");
 
     conn.execute_batch(
         "CREATE TABLE widgets (
@@ -504,6 +581,183 @@ fn run_evfs_vfs_tests(t: &mut TestRunner, mode: &str) -> Result<()> {
 
     drop(conn);
 
+    // ── WAL journal mode ────────────────────────────────────────
+    t.section("EVFS WAL Mode");
+
+    let wal_path = tmp.path("wal.db");
+    let writer = Connection::open_with_flags_and_vfs(
+        &wal_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "evfs",
+    )?;
+    writer.execute_batch("PRAGMA reserve_bytes = 80;")?;
+
+    let mode: String = writer.query_row("PRAGMA journal_mode = WAL", [], |r| r.get(0))?;
+    t.assert_eq("PRAGMA journal_mode = WAL", &mode, &"wal".to_string());
+
+    writer.execute_batch(
+        "PRAGMA wal_autocheckpoint = 0;
+         CREATE TABLE events (id INTEGER PRIMARY KEY, body TEXT NOT NULL);
+         INSERT INTO events (body) VALUES ('first-wal-event');",
+    )?;
+    t.ok("CREATE TABLE events in WAL mode");
+
+    let wal_file = tmp.path("wal.db-wal");
+    let wal_raw = std::fs::read(&wal_file).unwrap_or_default();
+    if wal_raw.is_empty() {
+        t.fail("WAL file written", &"wal file missing or empty");
+    } else if String::from_utf8_lossy(&wal_raw).contains("first-wal-event") {
+        t.fail("WAL ciphertext check", &"plaintext row data found in WAL");
+    } else {
+        t.ok("WAL file does not contain plaintext row data");
+    }
+
+    // A reader's snapshot is unaffected by a concurrent writer.
+    let reader =
+        Connection::open_with_flags_and_vfs(&wal_path, OpenFlags::SQLITE_OPEN_READ_WRITE, "evfs")?;
+    reader.execute_batch("BEGIN;")?;
+    let before: i64 = reader.query_row("SELECT COUNT(*) FROM events", [], |r| r.get(0))?;
+
+    for i in 0..50 {
+        writer.execute(
+            "INSERT INTO events (body) VALUES (?1)",
+            params![format!("event-{i}")],
+        )?;
+    }
+    t.ok("writer INSERT 50 rows while reader holds a snapshot");
+
+    let during: i64 = reader.query_row("SELECT COUNT(*) FROM events", [], |r| r.get(0))?;
+    t.assert_eq("reader snapshot count", &(before, during), &(1i64, 1i64));
+    reader.execute_batch("COMMIT;")?;
+
+    let after: i64 = reader.query_row("SELECT COUNT(*) FROM events", [], |r| r.get(0))?;
+    t.assert_eq("reader count after its snapshot ends", &after, &51i64);
+    drop(reader);
+
+    let (busy, _, _): (i64, i64, i64) =
+        writer.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+        })?;
+    t.assert_eq("wal_checkpoint(TRUNCATE) busy", &busy, &0i64);
+
+    let wal_len = std::fs::metadata(&wal_file).map(|m| m.len()).unwrap_or(0);
+    t.assert_eq("WAL length after checkpoint", &wal_len, &0u64);
+
+    drop(writer);
+
+    let conn =
+        Connection::open_with_flags_and_vfs(&wal_path, OpenFlags::SQLITE_OPEN_READ_WRITE, "evfs")?;
+    t.ok("reopened WAL DB with vfs=evfs");
+
+    let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+    t.assert_eq("integrity_check after reopen", &check, &"ok".to_string());
+
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM events", [], |r| r.get(0))?;
+    t.assert_eq("row count after reopen", &count, &51i64);
+
+    let body: String = conn.query_row("SELECT body FROM events WHERE id = 1", [], |r| r.get(0))?;
+    t.assert_eq("read row id=1", &body, &"first-wal-event".to_string());
+
+    drop(conn);
+
+    Ok(())
+}
+
+/// Register a VFS with 8 KiB pages through `sqlite3_evfs_init`, set up
+/// by environment variables alone, as an application loading the
+/// extension would.
+fn run_evfs_env_geometry_tests(t: &mut TestRunner, mode: &str) -> Result<()> {
+    t.section("EVFS Geometry From the Environment");
+
+    let tmp = TestDir::new("evfs-env-");
+    let keyfile = tmp.write_keyfile("env.key", std::array::from_fn(|i| i as u8 * 7 + 1));
+    let db_path = tmp.path("env8k.db");
+    let load = || -> Result<()> {
+        let loader = Connection::open(":memory:")?;
+        unsafe {
+            loader.load_extension_enable()?;
+            let loaded = loader.load_extension(
+                format!("../sqlevfs/target/{mode}/libsqlevfs"),
+                Some("sqlite3_evfs_init"),
+            );
+            loader.load_extension_disable()?;
+            loaded
+        }
+    };
+    let set = |vars: &[(&str, &str)]| {
+        for (name, value) in vars {
+            unsafe { std::env::set_var(name, value) };
+        }
+    };
+    set(&[("EVFS_KEYFILE", keyfile.to_str().unwrap())]);
+
+    // An invalid page size fails registration rather than registering
+    // a VFS that can't work.
+    set(&[("EVFS_PAGE_SIZE", "3000"), ("EVFS_VFS_NAME", "evfs_env_bad")]);
+    match load() {
+        Ok(()) => t.fail("EVFS_PAGE_SIZE=3000 refused", &"registered"),
+        Err(_) => t.ok("EVFS_PAGE_SIZE=3000 refused"),
+    }
+    let bad = Connection::open_with_flags_and_vfs(
+        tmp.path("bad.db"),
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "evfs_env_bad",
+    );
+    t.assert_eq("no evfs_env_bad VFS", &bad.is_err(), &true);
+    set(&[("EVFS_PAGE_SIZE", "8192"), ("EVFS_RESERVE", "lots")]);
+    t.assert_eq("EVFS_RESERVE=lots refused", &load().is_err(), &true);
+    set(&[("EVFS_RESERVE", "96"), ("EVFS_INNER_VFS", "no-such-vfs")]);
+    t.assert_eq("unknown EVFS_INNER_VFS refused", &load().is_err(), &true);
+
+    set(&[
+        ("EVFS_PAGE_SIZE", "8192"),
+        ("EVFS_RESERVE", "96"),
+        ("EVFS_VFS_NAME", "evfs_env_8k"),
+        ("EVFS_INNER_VFS", "unix-none"),
+    ]);
+    let loaded = load();
+    for name in [
+        "EVFS_PAGE_SIZE",
+        "EVFS_RESERVE",
+        "EVFS_VFS_NAME",
+        "EVFS_INNER_VFS",
+    ] {
+        unsafe { std::env::remove_var(name) };
+    }
+    if let Err(e) = loaded {
+        t.fail("registered evfs_env_8k from the environment", &e);
+        return Ok(());
+    }
+    t.ok("registered evfs_env_8k from the environment");
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "evfs_env_8k",
+    )?;
+    conn.execute_batch(
+        "CREATE TABLE parts (id INTEGER PRIMARY KEY, name TEXT);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 300)
+         INSERT INTO parts SELECT i, 'env-part ' || i FROM n;",
+    )?;
+    let page_size: i64 = conn.query_row("PRAGMA page_size", [], |r| r.get(0))?;
+    t.assert_eq("page_size from EVFS_PAGE_SIZE", &page_size, &8192i64);
+    drop(conn);
+
+    let raw = std::fs::read(&db_path).expect("read raw DB file");
+    t.assert_eq("file is whole 8 KiB pages", &(raw.len() % 8192), &0usize);
+    t.assert_eq("reserve from EVFS_RESERVE", &raw[20], &96u8);
+    let plaintext = String::from_utf8_lossy(&raw).contains("env-part");
+    t.assert_eq("no plaintext rows", &plaintext, &false);
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+        "evfs_env_8k",
+    )?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM parts", [], |r| r.get(0))?;
+    t.assert_eq("row count after reopen", &count, &300i64);
+
     Ok(())
 }
 
@@ -525,7 +779,7 @@ fn run_evfs_backup_tests(t: &mut TestRunner) {
     let db_path = tmp.path("source.db");
 
     let page_size: u32 = 4096;
-    let reserve: usize = 48;
+    let reserve: usize = 80;
     let page_count: usize = 4;
 
     // Build a fake encrypted database on disk using the crypto
@@ -552,6 +806,7 @@ fn run_evfs_backup_tests(t: &mut TestRunner) {
         if let Err(e) = sqlevfs::crypto::page::encrypt_page(
             &mut db_bytes[off..off + page_size as usize],
             i as u32 + 1,
+            None,
             &src_dek,
             reserve,
         ) {
@@ -576,7 +831,7 @@ fn run_evfs_backup_tests(t: &mut TestRunner) {
         page_size,
         reserve,
     ) {
-        Ok(()) => t.ok(&format!("backup created ({} bytes)", backup_buf.len())),
+        Ok(_) => t.ok(&format!("backup created ({} bytes)", backup_buf.len())),
         Err(e) => {
             t.fail("create backup", &e);
             return;
@@ -623,6 +878,71 @@ fn run_evfs_backup_tests(t: &mut TestRunner) {
         Err(e) => t.fail("verify backup", &e),
     }
 
+    // ── Progress and cancellation ───────────────────────────────
+    t.section("EVFS Backup - Progress & Cancellation");
+
+    // Every page, on both passes over the database.
+    let reports = Arc::new(AtomicU32::new(0));
+    let options = backup::BackupOptions::default()
+        .progress_every(1)
+        .progress({
+            let reports = reports.clone();
+            move |progress| {
+                reports.fetch_add(1, Ordering::Relaxed);
+                assert!(progress.pages_done <= progress.pages_total);
+                ControlFlow::Continue(())
+            }
+        });
+    match backup::create_backup_with(
+        &db_path,
+        &mut Vec::new(),
+        &src_keyring,
+        bkp_provider.as_ref(),
+        page_size,
+        reserve,
+        &options,
+    ) {
+        Ok(_) => t.assert_eq(
+            "progress reports",
+            &reports.load(Ordering::Relaxed),
+            &(2 * page_count as u32),
+        ),
+        Err(e) => t.fail("backup with progress", &e),
+    }
+
+    let halfway = backup::BackupOptions::default()
+        .progress_every(1)
+        .progress(move |progress| {
+            if progress.pages_done * 2 >= progress.pages_total {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+    let cancelled_path = tmp.path("cancelled.db");
+    match backup::restore_backup_with(
+        &mut Cursor::new(&backup_buf),
+        &cancelled_path,
+        bkp_provider.as_ref(),
+        &Keyring::new(make_provider(&tgt_key)),
+        &halfway,
+    ) {
+        Ok(()) => t.fail("cancelled restore", &"should have been cancelled"),
+        Err(e) if e.is::<backup::Cancelled>() && !cancelled_path.exists() => {
+            t.ok("restore cancelled halfway, nothing written")
+        }
+        Err(e) => t.fail("cancelled restore", &e),
+    }
+    match backup::verify_backup_with(
+        &mut Cursor::new(&backup_buf),
+        bkp_provider.as_ref(),
+        &halfway,
+    ) {
+        Ok(_) => t.fail("cancelled verify", &"should have been cancelled"),
+        Err(e) if e.is::<backup::Cancelled>() => t.ok("verify cancelled halfway"),
+        Err(e) => t.fail("cancelled verify", &e),
+    }
+
     // ── Verify with wrong key fails ─────────────────────────────
     t.section("EVFS Backup - Wrong Key Rejection");
 
@@ -678,11 +998,12 @@ fn run_evfs_backup_tests(t: &mut TestRunner) {
     let tgt_dek = tgt_keyring
         .dek_for(&KeyScope::Database)
         .expect("get target DEK");
+    let database_id = sqlevfs::keyring::database_id_of(&restored_path);
     let mut all_pages_ok = true;
     for i in 0..page_count {
         let off = i * page_size as usize;
         let mut page = restored_bytes[off..off + page_size as usize].to_vec();
-        match sqlevfs::crypto::page::decrypt_page(&mut page, i as u32 + 1, &tgt_dek, reserve) {
+        match sqlevfs::crypto::page::decrypt_page(&mut page, i as u32 + 1, database_id, &tgt_dek, reserve) {
             Ok(()) => {
                 let expected = (i as u8).wrapping_add(0x41);
                 let payload = &page[..page_size as usize - reserve];
@@ -773,7 +1094,13 @@ fn run_evfs_backup_tests(t: &mut TestRunner) {
         .dek_for(&KeyScope::Database)
         .expect("get tgt2 DEK");
     let mut page1 = restored2_bytes[..page_size as usize].to_vec();
-    match sqlevfs::crypto::page::decrypt_page(&mut page1, 1, &tgt2_dek, reserve) {
+    match sqlevfs::crypto::page::decrypt_page(
+        &mut page1,
+        1,
+        sqlevfs::keyring::database_id_of(&restored2_path),
+        &tgt2_dek,
+        reserve,
+    ) {
         Ok(()) => {
             let expected = 0x41u8; // 'A'
             if page1[..page_size as usize - reserve]
@@ -797,13 +1124,13 @@ fn run_evfs_crypto_tests(t: &mut TestRunner) {
     t.section("EVFS Crypto - Page Round-Trip");
 
     let dek = sqlevfs::crypto::keys::Dek::generate();
-    let reserve = 48;
+    let reserve = 80;
     let page_size = 4096;
 
     let mut page = vec![0xBEu8; page_size];
     let original = page.clone();
 
-    match sqlevfs::crypto::page::encrypt_page(&mut page, 1, &dek, reserve) {
+    match sqlevfs::crypto::page::encrypt_page(&mut page, 1, None, &dek, reserve) {
         Ok(()) => t.ok("encrypt_page succeeded"),
         Err(e) => {
             t.fail("encrypt_page", &e);
@@ -818,7 +1145,7 @@ fn run_evfs_crypto_tests(t: &mut TestRunner) {
         t.fail("ciphertext check", &"ciphertext == plaintext");
     }
 
-    match sqlevfs::crypto::page::decrypt_page(&mut page, 1, &dek, reserve) {
+    match sqlevfs::crypto::page::decrypt_page(&mut page, 1, None, &dek, reserve) {
         Ok(()) => t.ok("decrypt_page succeeded"),
         Err(e) => {
             t.fail("decrypt_page", &e);
@@ -837,9 +1164,9 @@ fn run_evfs_crypto_tests(t: &mut TestRunner) {
 
     let dek2 = sqlevfs::crypto::keys::Dek::generate();
     let mut page = vec![0xCDu8; page_size];
-    sqlevfs::crypto::page::encrypt_page(&mut page, 1, &dek, reserve).unwrap();
+    sqlevfs::crypto::page::encrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
 
-    match sqlevfs::crypto::page::decrypt_page(&mut page, 1, &dek2, reserve) {
+    match sqlevfs::crypto::page::decrypt_page(&mut page, 1, None, &dek2, reserve) {
         Err(_) => t.ok("wrong key correctly rejected"),
         Ok(()) => t.fail("wrong key", &"decryption should have failed"),
     }
@@ -848,9 +1175,9 @@ fn run_evfs_crypto_tests(t: &mut TestRunner) {
     t.section("EVFS Crypto - Wrong Page Number Rejection");
 
     let mut page = vec![0xEFu8; page_size];
-    sqlevfs::crypto::page::encrypt_page(&mut page, 5, &dek, reserve).unwrap();
+    sqlevfs::crypto::page::encrypt_page(&mut page, 5, None, &dek, reserve).unwrap();
 
-    match sqlevfs::crypto::page::decrypt_page(&mut page, 6, &dek, reserve) {
+    match sqlevfs::crypto::page::decrypt_page(&mut page, 6, None, &dek, reserve) {
         Err(_) => t.ok("wrong page_no correctly rejected"),
         Ok(()) => t.fail("wrong page_no", &"decryption should have failed"),
     }
@@ -962,7 +1289,10 @@ fn run_evfs_keyring_tests(t: &mut TestRunner) {
 
     let fake_db = tmp.path("persist-test.db");
     std::fs::write(&fake_db, b"fake").unwrap();
-    keyring.set_sidecar_path(&fake_db);
+    if let Err(e) = keyring.set_sidecar_path(&fake_db) {
+        t.fail("set_sidecar_path", &e);
+        return;
+    }
 
     // Generate a DEK (triggers sidecar write).
     let _ = keyring.dek_for(&KeyScope::Database).unwrap();
@@ -984,9 +1314,265 @@ fn run_evfs_keyring_tests(t: &mut TestRunner) {
     t.section("EVFS Keyring - Rewrap All");
 
     match keyring.rewrap_all() {
-        Ok(()) => t.ok("rewrap_all succeeded"),
+        Ok(summary) if summary.is_complete() => t.ok("rewrap_all succeeded"),
+        Ok(summary) => t.fail("rewrap_all", &format!("{:?}", summary.failed)),
         Err(e) => t.fail("rewrap_all", &e),
     }
+
+    // A keyring bound to the same sidecar caches none of its DEKs, and
+    // must rewrap them all the same.
+    let reader = Keyring::new(make_provider(&kf));
+    match reader.set_sidecar_path(&fake_db).and_then(|()| reader.rewrap_all()) {
+        Ok(summary) if summary.is_complete() && summary.rewrapped.len() == 3 => {
+            t.ok("rewrap_all rewraps DEKs persisted but not cached")
+        }
+        Ok(summary) => t.fail("rewrap_all of uncached DEKs", &format!("{summary:?}")),
+        Err(e) => t.fail("rewrap_all of uncached DEKs", &e),
+    }
+    match reader.dek_for(&KeyScope::Database) {
+        Ok(dek) if dek.as_bytes() == dek1.as_bytes() => {
+            t.ok("rewrapped Database DEK unchanged")
+        }
+        Ok(_) => t.fail("rewrapped Database DEK", &"differs from the original"),
+        Err(e) => t.fail("rewrapped Database DEK", &e),
+    }
+
+    // ── KMS outage and rotation, against the mock KMS ───────────
+    t.section("EVFS Keyring - KMS Outage & Rotation");
+
+    let kms = Arc::new(MockKmsProvider::new());
+    let mock_db = tmp.path("mock-kms.db");
+    let first = Keyring::new(kms.clone());
+    if let Err(e) = first.set_sidecar_path(&mock_db) {
+        t.fail("set_sidecar_path under the mock KMS", &e);
+        return;
+    }
+    let dek = match first.dek_for(&KeyScope::Database) {
+        Ok(d) => d,
+        Err(e) => {
+            t.fail("dek_for under the mock KMS", &e);
+            return;
+        }
+    };
+
+    kms.set_offline(true);
+    match first.dek_for(&KeyScope::Database) {
+        Ok(d) if d.as_bytes() == dek.as_bytes() => t.ok("cached DEK served while the KMS is down"),
+        Ok(_) => t.fail("cached DEK", &"different key"),
+        Err(e) => t.fail("cached DEK", &e),
+    }
+    let second = Keyring::new(kms.clone());
+    match second.set_sidecar_path(&mock_db) {
+        Err(_) => t.ok("sidecar can't be authenticated while the KMS is down"),
+        Ok(()) => t.fail("sidecar authentication", &"should have failed"),
+    }
+    kms.set_offline(false);
+
+    let old = kms.current_kek_id();
+    let new = kms.rotate();
+    let third = Keyring::new(kms.clone());
+    if let Err(e) = third.set_sidecar_path(&mock_db) {
+        t.fail("sidecar after rotation", &e);
+    }
+    t.assert_eq("DEKs rewrapped after rotation", &third.kek_status().current.len(), &1);
+    kms.set_enabled(&old, false);
+    let fourth = Keyring::new(kms.clone());
+    if let Err(e) = fourth.set_sidecar_path(&mock_db) {
+        t.fail("sidecar with the old KEK disabled", &e);
+    }
+    match fourth.dek_for(&KeyScope::Database) {
+        Ok(d) if d.as_bytes() == dek.as_bytes() => {
+            t.ok(&format!("DEK unwraps under {} with {} disabled", new.0, old.0))
+        }
+        Ok(_) => t.fail("DEK after rotation", &"different key"),
+        Err(e) => t.fail("DEK after rotation", &e),
+    }
+    if kms.calls(Call::GetKekById) > 0 {
+        t.ok("mock KMS counted the unwraps");
+    } else {
+        t.fail("mock KMS call count", &"no unwraps counted");
+    }
+}
+
+// ────────────────────────────────────────────────────────────────────
+// EVFS multiple registrations (via Rust API)
+// ────────────────────────────────────────────────────────────────────
+
+fn register_tenant_vfs(name: &str, keyfile: &Path) -> Result<Arc<Keyring>, String> {
+    EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(keyfile.to_path_buf()),
+        passphrase: None,
+    })
+    .vfs_name(name)
+    .register()
+    .map_err(|e| e.to_string())
+}
+
+fn run_evfs_multi_vfs_tests(t: &mut TestRunner) -> Result<()> {
+    t.section("EVFS Multiple VFSes - Register");
+
+    let tmp = TestDir::new("evfs-multi-");
+    let key_a = tmp.write_keyfile("tenant-a.key", [0xA5; 32]);
+    let key_b = tmp.write_keyfile("tenant-b.key", [0xB6; 32]);
+    let tenants = [
+        ("evfs-tenant-a", &key_a, tmp.path("tenant-a.db")),
+        ("evfs-tenant-b", &key_b, tmp.path("tenant-b.db")),
+    ];
+
+    for (vfs, key, _) in &tenants {
+        match register_tenant_vfs(vfs, key) {
+            Ok(_) => t.ok(&format!("registered {vfs}")),
+            Err(e) => {
+                t.fail(&format!("register {vfs}"), &e);
+                return Ok(());
+            }
+        }
+    }
+
+    let open = |db: &Path, vfs: &str| {
+        Connection::open_with_flags_and_vfs(
+            db,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+    };
+    let read_back = |db: &Path, vfs: &str| -> Result<String> {
+        open(db, vfs)?.query_row("SELECT secret FROM t WHERE id = 1", [], |r| r.get(0))
+    };
+
+    for (vfs, _, db) in &tenants {
+        let conn = open(db, vfs)?;
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, secret TEXT)")?;
+        conn.execute("INSERT INTO t (secret) VALUES (?1)", params![vfs])?;
+        t.ok(&format!("wrote a database through {vfs}"));
+    }
+
+    // ── Each database decrypts only through its own VFS ──────────
+    t.section("EVFS Multiple VFSes - Isolation");
+
+    for (vfs, _, db) in &tenants {
+        match read_back(db, vfs) {
+            Ok(secret) => t.assert_eq(&format!("read through {vfs}"), &secret.as_str(), vfs),
+            Err(e) => t.fail(&format!("read through {vfs}"), &e),
+        }
+    }
+    for ((_, _, db), (other, _, _)) in [(&tenants[0], &tenants[1]), (&tenants[1], &tenants[0])] {
+        match read_back(db, other) {
+            Ok(secret) => t.fail(
+                &format!("{} through {other}", db.display()),
+                &format!("decrypted {secret:?} with the wrong key"),
+            ),
+            Err(_) => t.ok(&format!("{other} cannot decrypt the other tenant's database")),
+        }
+    }
+
+    // ── Re-register and unregister ───────────────────────────────
+    t.section("EVFS Multiple VFSes - Re-register / Unregister");
+
+    let key_c = tmp.write_keyfile("tenant-a-new.key", [0xC7; 32]);
+    let (vfs_a, _, db_a) = &tenants[0];
+    match register_tenant_vfs(vfs_a, &key_c) {
+        Ok(_) => t.ok(&format!("re-registered {vfs_a} with a new key")),
+        Err(e) => t.fail(&format!("re-register {vfs_a}"), &e),
+    }
+    match read_back(db_a, vfs_a) {
+        Ok(_) => t.fail("re-registered keyring", &"old key still in use"),
+        Err(_) => t.ok("re-registered VFS uses the new keyring"),
+    }
+
+    match sqlevfs::unregister(vfs_a) {
+        Ok(()) => t.ok(&format!("unregistered {vfs_a}")),
+        Err(e) => t.fail(&format!("unregister {vfs_a}"), &e),
+    }
+    match open(db_a, vfs_a) {
+        Ok(_) => t.fail("unregistered VFS", &"still usable"),
+        Err(_) => t.ok("unregistered VFS can no longer be used"),
+    }
+    match register_tenant_vfs(vfs_a, &key_a) {
+        Ok(_) => match read_back(db_a, vfs_a) {
+            Ok(secret) => t.assert_eq("read after re-registering", &secret.as_str(), vfs_a),
+            Err(e) => t.fail("read after re-registering", &e),
+        },
+        Err(e) => t.fail(&format!("register {vfs_a} again"), &e),
+    }
+
+    Ok(())
+}
+
+// ────────────────────────────────────────────────────────────────────
+// EVFS integrity scan (PRAGMA evfs_verify and the Rust API)
+// ────────────────────────────────────────────────────────────────────
+
+fn run_evfs_verify_tests(t: &mut TestRunner) -> Result<()> {
+    t.section("EVFS Verify - Clean Database");
+
+    let tmp = TestDir::new("evfs-verify-");
+    let key = tmp.write_keyfile("verify.key", [0x5E; 32]);
+    let db = tmp.path("verify.db");
+    let vfs = "evfs-verify";
+    let keyring = match register_tenant_vfs(vfs, &key) {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            t.fail(&format!("register {vfs}"), &e);
+            return Ok(());
+        }
+    };
+
+    let open = || {
+        Connection::open_with_flags_and_vfs(
+            &db,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+    };
+    let verify = |conn: &Connection| -> Result<String> {
+        conn.query_row("PRAGMA evfs_verify", [], |r| r.get(0))
+    };
+
+    let conn = open()?;
+    conn.execute_batch(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+         INSERT INTO t SELECT i, hex(randomblob(200)) FROM n;",
+    )?;
+    let page_count: u32 = conn.query_row("PRAGMA page_count", [], |r| r.get(0))?;
+    match verify(&conn) {
+        Ok(report) => t.assert_eq(
+            "evfs_verify on a clean database",
+            &report,
+            &format!("{page_count} pages, 0 bad"),
+        ),
+        Err(e) => t.fail("evfs_verify on a clean database", &e),
+    }
+    drop(conn);
+
+    // ── Flip one byte and find it ────────────────────────────────
+    t.section("EVFS Verify - Corrupted Page");
+
+    let corrupted = 4u32;
+    let mut bytes = std::fs::read(&db).expect("read database");
+    bytes[(corrupted as usize - 1) * 4096 + 123] ^= 0x01;
+    std::fs::write(&db, &bytes).expect("write database");
+    t.ok(&format!("flipped one byte of page {corrupted}"));
+
+    match verify(&open()?) {
+        Ok(report) => t.assert_eq(
+            "evfs_verify pinpoints the page",
+            &report,
+            &format!("{page_count} pages, 1 bad: page {corrupted} bad tag"),
+        ),
+        Err(e) => t.fail("evfs_verify on a corrupted database", &e),
+    }
+    match sqlevfs::verify::verify_database(&db, &keyring, 4096, 80) {
+        Ok(result) => t.assert_eq(
+            "verify_database pinpoints the page",
+            &result.bad_pages,
+            &vec![(corrupted, sqlevfs::verify::PageFault::BadTag)],
+        ),
+        Err(e) => t.fail("verify_database", &e),
+    }
+
+    Ok(())
 }
 
 // ────────────────────────────────────────────────────────────────────
@@ -1035,6 +1621,16 @@ fn main() {
     // ── EVFS backup tests ───────────────────────────────────────
     run_evfs_backup_tests(&mut t);
 
+    // ── EVFS multiple VFS registrations ─────────────────────────
+    if let Err(e) = run_evfs_multi_vfs_tests(&mut t) {
+        t.fail("evfs multiple VFS test suite", &e);
+    }
+
+    // ── EVFS integrity scan ─────────────────────────────────────
+    if let Err(e) = run_evfs_verify_tests(&mut t) {
+        t.fail("evfs verify test suite", &e);
+    }
+
     // ── EVFS VFS integration tests ──────────────────────────────
     let evfs_path_str = format!("../sqlevfs/target/{}/libsqlevfs.so", mode);
     let evfs_path = Path::new(&evfs_path_str);
@@ -1044,6 +1640,9 @@ fn main() {
             Ok(()) => {}
             Err(e) => t.fail("evfs VFS test suite", &e),
         }
+        if let Err(e) = run_evfs_env_geometry_tests(&mut t, mode) {
+            t.fail("evfs environment geometry test suite", &e);
+        }
     } else {
         println!("\n⚠ Skipping EVFS VFS tests ({})", evfs_path.display());
     }
@@ -4,25 +4,68 @@
 //! DEK so the backup can be restored anywhere the backup KEK is
 //! available.
 
+pub mod schedule;
+
 use std::{
-    io::{Read, Write},
-    path::Path,
+    collections::{BTreeMap, HashMap},
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    num::NonZeroUsize,
+    ops::ControlFlow,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use bincode::config;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use libsqlite3_sys::*;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
 
 use crate::{
+    btree,
     crypto::{
         envelope,
-        keys::{Dek, WrappedDek},
-        page as page_crypto,
+        keys::{Algorithm, DatabaseId, Dek, KeyScope, WrappedDek},
+        page::{self as page_crypto, MAX_NONCE_LEN},
     },
-    keyring::Keyring,
+    keyring::{self, ConcealedHeader, Keyring},
     kms::KmsProvider,
+    migrate,
+    rekey,
 };
 
 const BACKUP_MAGIC: &[u8; 8] = b"EVFSBKUP";
-const BACKUP_VERSION: u32 = 1;
+/// Version 8 backups record when they were taken and the file name of
+/// their database.
+/// Version 7 backups record the pages under scopes of their own.
+/// Version 6 backups record a digest of their pages' plaintext.
+/// Version 5 backups record an ID, and may be incremental over
+/// another backup. Versions 8 to 4 follow their header with an
+/// HMAC-SHA256 of the magic, header length and header, keyed by the
+/// backup DEK, which older versions don't. Versions 8 to 3 hold pages
+/// in the current page formats; versions 2 and 1 hold `EVFSv2` and
+/// `EVFSv1` pages, which still decrypt.
+const BACKUP_VERSION: u32 = 8;
+/// First version whose header is authenticated.
+const AUTHENTICATED_BACKUP_VERSION: u32 = 4;
+const LEGACY_BACKUP_VERSION: u32 = 1;
+/// Length a header is zero padded to.
+const MIN_HEADER_LEN: usize = 2048;
+/// Length of the MAC after an authenticated header.
+const HEADER_MAC_LEN: usize = 32;
+const MANIFEST_MAGIC: &[u8; 8] = b"EVFSMANI";
+const MANIFEST_VERSION: u32 = 1;
 
 /// Header at the start of every backup file.
 #[derive(bincode::Encode, bincode::Decode)]
@@ -33,13 +76,321 @@ pub struct BackupHeader {
     pub reserve_size: u32,
     /// The backup DEK, wrapped under the backup KEK.
     pub wrapped_dek: WrappedDek,
+    /// Algorithm the backup's pages are encrypted with, also sealed in
+    /// `wrapped_dek`. Headers from before it was recorded are zero
+    /// padded, and decode as AES-256-GCM, which they all used.
+    pub algorithm: Algorithm,
+    /// ID of the source database, which the backup's pages are bound
+    /// to as its own were. `None` for a database from before IDs, and
+    /// in headers from before they were recorded.
+    pub database_id: Option<DatabaseId>,
+    /// ID of this backup, which incremental backups over it name as
+    /// their base. `None` in headers from before it was recorded.
+    pub backup_id: Option<BackupId>,
+    /// For an incremental backup, the backup it holds the changes
+    /// since. `None` for a full backup.
+    pub base: Option<BackupBase>,
+    /// SHA-256 of the number and [`BackupManifest`] digest of each page
+    /// the backup holds, in order, which restoring and verifying check
+    /// the decrypted pages against. `None` in headers from before it
+    /// was recorded.
+    pub digest_root: Option<[u8; 32]>,
+    /// Pages of the database under a DEK of their table's rather than
+    /// the database's, as it was when backed up, which restoring puts
+    /// under the same scopes. Empty in headers from before they were
+    /// recorded.
+    pub scopes: Vec<ScopedPages>,
+    /// When the backup was taken, in seconds since the Unix epoch.
+    /// `None` in headers from before it was recorded.
+    pub created_at: Option<u64>,
+    /// File name of the database backed up. `None` in headers from
+    /// before it was recorded.
+    pub source: Option<String>,
+}
+
+/// The pages of a database under `scope`, as runs of consecutive
+/// pages: the first page of each and how many there are.
+#[derive(Clone, Debug, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct ScopedPages {
+    pub scope: KeyScope,
+    pub runs: Vec<(u32, u32)>,
+}
+
+impl ScopedPages {
+    /// The runs of each scope in `page_scopes`, a map from page to
+    /// scope like the VFS keeps.
+    fn from_map(page_scopes: &HashMap<u32, KeyScope>) -> Vec<Self> {
+        let mut by_scope: BTreeMap<String, (KeyScope, Vec<u32>)> = BTreeMap::new();
+        for (&page_no, scope) in page_scopes {
+            by_scope
+                .entry(scope.to_string())
+                .or_insert_with(|| (scope.clone(), Vec::new()))
+                .1
+                .push(page_no);
+        }
+        by_scope
+            .into_values()
+            .map(|(scope, mut pages)| {
+                pages.sort_unstable();
+                let mut runs: Vec<(u32, u32)> = Vec::new();
+                for page_no in pages {
+                    match runs.last_mut() {
+                        Some((first, count)) if *first + *count == page_no => *count += 1,
+                        _ => runs.push((page_no, 1)),
+                    }
+                }
+                Self { scope, runs }
+            })
+            .collect()
+    }
+
+    /// The map from page to scope of `scopes`.
+    fn to_map(scopes: &[Self]) -> HashMap<u32, KeyScope> {
+        let mut map = HashMap::new();
+        for scoped in scopes {
+            for &(first, count) in &scoped.runs {
+                for page_no in first..first.saturating_add(count) {
+                    map.insert(page_no, scoped.scope.clone());
+                }
+            }
+        }
+        map
+    }
+}
+
+/// Random identifier of a backup.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct BackupId(pub [u8; 16]);
+
+impl BackupId {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 16];
+        getrandom::fill(&mut bytes).expect("getrandom failed");
+        Self(bytes)
+    }
+}
+
+impl std::fmt::Display for BackupId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+/// What an incremental backup is over. It holds `changed_pages`
+/// pages, in page order, each after its page number as a u32 LE; the
+/// others are as in the base.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct BackupBase {
+    pub backup_id: BackupId,
+    pub changed_pages: u32,
+}
+
+/// Digests of a database's pages as a backup holds them, counting
+/// those an incremental backup leaves to its base. Every backup
+/// returns one, to be stored alongside it and passed to
+/// [`create_incremental_backup`] to back up only the pages changed
+/// since.
+///
+/// Each digest is the SHA-256 of a page's decrypted payload, so the
+/// manifest tells which pages are equal and confirms guesses at a
+/// page's contents: keep it as private as the database.
+#[derive(Clone, Debug, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct BackupManifest {
+    pub backup_id: BackupId,
+    pub database_id: Option<DatabaseId>,
+    pub page_size: u32,
+    pub reserve_size: u32,
+    /// Digest of each page, page 1 first.
+    pub digests: Vec<[u8; 32]>,
+}
+
+impl BackupManifest {
+    pub fn write(&self, dest: &mut dyn Write) -> anyhow::Result<()> {
+        dest.write_all(MANIFEST_MAGIC)?;
+        dest.write_all(&MANIFEST_VERSION.to_le_bytes())?;
+        bincode::encode_into_std_write(self, &mut &mut *dest, config::standard())?;
+        Ok(())
+    }
+
+    pub fn read(source: &mut dyn Read) -> anyhow::Result<Self> {
+        let mut prefix = [0u8; MANIFEST_MAGIC.len() + 4];
+        source.read_exact(&mut prefix)?;
+        anyhow::ensure!(
+            prefix.starts_with(MANIFEST_MAGIC),
+            "invalid backup manifest: bad magic"
+        );
+        let version = u32::from_le_bytes(prefix[MANIFEST_MAGIC.len()..].try_into()?);
+        anyhow::ensure!(
+            version == MANIFEST_VERSION,
+            "unsupported backup manifest version: {version}"
+        );
+        Ok(bincode::decode_from_std_read(
+            &mut &mut *source,
+            config::standard(),
+        )?)
+    }
+}
+
+/// Settings for the `_with` variants of the backup functions.
+#[derive(Clone)]
+pub struct BackupOptions {
+    /// Threads that encrypt and decrypt pages, while the calling
+    /// thread reads and writes them in order. At 1, pages are
+    /// processed on the calling thread. Defaults to the available
+    /// parallelism.
+    pub threads: usize,
+    /// Algorithm a new backup's pages are encrypted with. Restoring
+    /// encrypts pages with the target keyring's algorithm instead.
+    pub algorithm: Algorithm,
+    /// Called on the calling thread every `progress_every` pages and
+    /// once all are done. Returning [`ControlFlow::Break`] stops the
+    /// operation with a [`Cancelled`] error.
+    pub progress: Option<Arc<ProgressFn>>,
+    pub progress_every: u32,
+    /// Time a new backup records it was taken at, if not now.
+    pub(crate) created_at: Option<SystemTime>,
+}
+
+/// A [`BackupOptions::progress`] callback.
+pub type ProgressFn = dyn Fn(BackupProgress) -> ControlFlow<()> + Send + Sync;
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            threads: std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            algorithm: Algorithm::default(),
+            progress: None,
+            progress_every: 1024,
+            created_at: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for BackupOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackupOptions")
+            .field("threads", &self.threads)
+            .field("algorithm", &self.algorithm)
+            .field("progress", &self.progress.as_ref().map(|_| ".."))
+            .field("progress_every", &self.progress_every)
+            .finish()
+    }
+}
+
+impl BackupOptions {
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn progress(
+        mut self,
+        progress: impl Fn(BackupProgress) -> ControlFlow<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    pub fn progress_every(mut self, pages: u32) -> Self {
+        self.progress_every = pages.max(1);
+        self
+    }
+
+    /// The time a new backup records it was taken at.
+    fn created_at(&self) -> anyhow::Result<Option<u64>> {
+        let created_at = self.created_at.unwrap_or_else(SystemTime::now);
+        Ok(Some(created_at.duration_since(UNIX_EPOCH)?.as_secs()))
+    }
+}
+
+/// The file name a backup of the database at `path` records.
+fn source_name(path: &Path) -> Option<String> {
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+/// How far a backup, restore or verify has got, passed to
+/// [`BackupOptions::progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupProgress {
+    pub pages_done: u32,
+    /// Pages the operation processes. Creating a backup passes over
+    /// the database's pages twice, once to digest them and once to
+    /// encrypt them, and counts both; restoring a chain counts each
+    /// incremental backup's pages once its header has been read.
+    pub pages_total: u32,
+    /// Bytes written to the backup or restored database so far.
+    pub bytes_written: u64,
+}
+
+/// A [`BackupOptions::progress`] callback stopped the operation.
+///
+/// A restore leaves nothing behind, but a backup leaves what it wrote
+/// to its destination so far, which won't verify: discard it.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("cancelled by the progress callback")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Reports an operation's [`BackupProgress`] to its options' callback.
+struct Progress<'a> {
+    callback: Option<&'a ProgressFn>,
+    every: u32,
+    state: BackupProgress,
 }
 
-/// Create an encrypted backup.
+impl<'a> Progress<'a> {
+    fn new(options: &'a BackupOptions, pages_total: u32) -> Self {
+        Self {
+            callback: options.progress.as_deref(),
+            every: options.progress_every.max(1),
+            state: BackupProgress {
+                pages_done: 0,
+                pages_total,
+                bytes_written: 0,
+            },
+        }
+    }
+
+    /// Count `pages` more pages done and `bytes` more written, and call
+    /// the callback if that crosses a multiple of `every` pages or
+    /// finishes.
+    fn advance(&mut self, pages: u32, bytes: u64) -> anyhow::Result<()> {
+        let before = self.state.pages_done;
+        self.state.pages_done += pages;
+        self.state.bytes_written += bytes;
+        let done = self.state.pages_done;
+        let crossed = done / self.every > before / self.every;
+        let finished = pages > 0 && done == self.state.pages_total;
+        match self.callback {
+            Some(callback) if crossed || finished => match callback(self.state) {
+                ControlFlow::Continue(()) => Ok(()),
+                ControlFlow::Break(()) => Err(Cancelled.into()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Create an encrypted backup, and return its [`BackupManifest`].
 ///
 /// Reads the source database (which is already encrypted on disk),
 /// decrypts each page with the source keyring, re-encrypts under a
 /// fresh backup DEK, and writes the result to `dest`.
+///
+/// The file is read whole and without a lock, so it must not be
+/// written meanwhile; [`create_backup_live`] backs up a database in
+/// use.
 pub fn create_backup(
     source_path: &Path,
     dest: &mut dyn Write,
@@ -47,17 +398,43 @@ pub fn create_backup(
     backup_kms: &dyn KmsProvider,
     page_size: u32,
     reserve: usize,
-) -> anyhow::Result<()> {
-    let raw = std::fs::read(source_path)?;
-    anyhow::ensure!(
-        raw.len() % page_size as usize == 0,
-        "database size {} is not a multiple of page_size {page_size}",
-        raw.len()
-    );
+) -> anyhow::Result<BackupManifest> {
+    create_backup_with(
+        source_path,
+        dest,
+        source_keyring,
+        backup_kms,
+        page_size,
+        reserve,
+        &BackupOptions::default(),
+    )
+}
+
+/// [`create_backup`] with explicit [`BackupOptions`].
+pub fn create_backup_with(
+    source_path: &Path,
+    dest: &mut dyn Write,
+    source_keyring: &Keyring,
+    backup_kms: &dyn KmsProvider,
+    page_size: u32,
+    reserve: usize,
+    options: &BackupOptions,
+) -> anyhow::Result<BackupManifest> {
+    let (raw, source) = read_source(source_path, source_keyring, page_size, reserve)?;
     let page_count = raw.len() / page_size as usize;
+    let mut progress = Progress::new(options, 2 * page_count as u32);
+    // Digest every page first, so the header can record them.
+    let digests = source_digests(
+        &raw,
+        page_size,
+        reserve,
+        &source,
+        options.threads,
+        &mut progress,
+    )?;
 
     // Fresh DEK for the backup.
-    let backup_dek = Dek::generate();
+    let backup_dek = Dek::generate_for(options.algorithm);
     let wrapped = envelope::wrap_dek(&backup_dek, backup_kms)?;
 
     let header = BackupHeader {
@@ -66,223 +443,1767 @@ pub fn create_backup(
         page_count: page_count as u32,
         reserve_size: reserve as u32,
         wrapped_dek: wrapped,
+        algorithm: options.algorithm,
+        database_id: source.database_id,
+        backup_id: Some(BackupId::generate()),
+        base: None,
+        digest_root: Some(DigestRoot::of((1..).zip(&digests))),
+        scopes: ScopedPages::from_map(&source.page_scopes),
+        created_at: options.created_at()?,
+        source: source_name(source_path),
     };
-    let mut header_bytes = vec![0u8; 2048];
-    bincode::encode_into_slice(&header, &mut header_bytes, config::standard())?;
-
-    // Write magic + header-length + header.
-    dest.write_all(BACKUP_MAGIC)?;
-    dest.write_all(&(header_bytes.len() as u32).to_le_bytes())?;
-    dest.write_all(&header_bytes)?;
-
-    // Process each page.
-    for i in 0..page_count {
-        let offset = i * page_size as usize;
-        let mut page_buf = raw[offset..offset + page_size as usize].to_vec();
-        let page_no = i as u32 + 1;
-
-        // Skip decryption for page 1 if it's a fresh/plaintext DB.
-        let needs_decrypt = !(page_no == 1 && is_plaintext_header(&page_buf));
-
-        if needs_decrypt {
-            let src_dek = source_keyring.dek_for(&crate::crypto::keys::KeyScope::Database)?;
-            page_crypto::decrypt_page(&mut page_buf, page_no, &src_dek, reserve)?;
-        }
-
-        // Re-encrypt under backup DEK.
-        page_crypto::encrypt_page(&mut page_buf, page_no, &backup_dek, reserve)?;
-
-        dest.write_all(&page_buf)?;
-    }
+    let header_len = write_header(dest, &header, &backup_dek)?;
+    progress.advance(0, header_len)?;
+
+    let encrypted = backup_pages(
+        &raw,
+        &header,
+        &source,
+        &backup_dek,
+        dest,
+        options.threads,
+        &mut page_crypto::rand_nonce,
+        &mut progress,
+    )?;
+    // What was encrypted must be what the header records.
+    anyhow::ensure!(
+        encrypted == digests,
+        "pages changed in memory while they were backed up"
+    );
 
     dest.flush()?;
     log::info!("backup created: {page_count} pages");
-    Ok(())
+    Ok(manifest_of(&header, digests))
 }
 
-/// Restore from an encrypted backup.
+/// Create an encrypted backup of the pages of the source database
+/// that changed since the backup `base` is the manifest of, and return
+/// the manifest of the database as backed up now.
 ///
-/// Decrypts each page with the backup DEK (unwrapped via
-/// `backup_kms`), then re-encrypts under the target keyring's
-/// current DEK, and writes the restored database to `target_path`.
-pub fn restore_backup(
-    source: &mut dyn Read,
-    target_path: &Path,
+/// The pages' digests are compared with `base`'s, and only those that
+/// differ, or are past its end, are written to `dest`; restoring it
+/// takes [`restore_backup_chain`] over the backups it builds on. The
+/// database must have kept its page size and reserve since `base`.
+pub fn create_incremental_backup(
+    source_path: &Path,
+    dest: &mut dyn Write,
+    source_keyring: &Keyring,
     backup_kms: &dyn KmsProvider,
-    target_keyring: &Keyring,
-) -> anyhow::Result<()> {
-    // Read and validate magic.
-    let mut magic = [0u8; 8];
-    source.read_exact(&mut magic)?;
-    anyhow::ensure!(&magic == BACKUP_MAGIC, "invalid backup file: bad magic");
-
-    // Read header.
-    let mut hdr_len_buf = [0u8; 4];
-    source.read_exact(&mut hdr_len_buf)?;
-    let hdr_len = u32::from_le_bytes(hdr_len_buf) as usize;
-    anyhow::ensure!(hdr_len < 1024 * 1024, "header too large: {hdr_len}");
+    base: &BackupManifest,
+) -> anyhow::Result<BackupManifest> {
+    create_incremental_backup_with(
+        source_path,
+        dest,
+        source_keyring,
+        backup_kms,
+        base,
+        &BackupOptions::default(),
+    )
+}
 
-    let mut hdr_buf = vec![0u8; hdr_len];
-    source.read_exact(&mut hdr_buf)?;
-    let header: BackupHeader = bincode::decode_from_slice(&hdr_buf, config::standard())?.0;
+/// [`create_incremental_backup`] with explicit [`BackupOptions`].
+pub fn create_incremental_backup_with(
+    source_path: &Path,
+    dest: &mut dyn Write,
+    source_keyring: &Keyring,
+    backup_kms: &dyn KmsProvider,
+    base: &BackupManifest,
+    options: &BackupOptions,
+) -> anyhow::Result<BackupManifest> {
+    let page_size = base.page_size;
+    let reserve = base.reserve_size as usize;
+    let (raw, source) = read_source(source_path, source_keyring, page_size, reserve)?;
     anyhow::ensure!(
-        header.version == BACKUP_VERSION,
-        "unsupported backup version: {}",
-        header.version
+        source.database_id == base.database_id,
+        "the base backup is of another database than {}",
+        source_path.display()
     );
+    let page_count = (raw.len() / page_size as usize) as u32;
+    let mut progress = Progress::new(options, 2 * page_count);
 
-    let page_size = header.page_size as usize;
-    let reserve = header.reserve_size as usize;
-    let page_count = header.page_count as usize;
-
-    // Unwrap the backup DEK.
-    let backup_dek = envelope::unwrap_dek(&header.wrapped_dek, backup_kms)?;
-
-    // Ensure the target keyring has a database DEK ready.
-    let target_dek = target_keyring.dek_for(&crate::crypto::keys::KeyScope::Database)?;
+    // Digest every page first, so the header can say how many changed.
+    let digests = source_digests(
+        &raw,
+        page_size,
+        reserve,
+        &source,
+        options.threads,
+        &mut progress,
+    )?;
+    let changed: Vec<u32> = (1..=page_count)
+        .filter(|&page_no| {
+            let i = page_no as usize - 1;
+            base.digests.get(i) != Some(&digests[i])
+        })
+        .collect();
+    progress.state.pages_total = page_count + changed.len() as u32;
+
+    let backup_dek = Dek::generate_for(options.algorithm);
+    let wrapped = envelope::wrap_dek(&backup_dek, backup_kms)?;
+    let header = BackupHeader {
+        version: BACKUP_VERSION,
+        page_size,
+        page_count,
+        reserve_size: reserve as u32,
+        wrapped_dek: wrapped,
+        algorithm: options.algorithm,
+        database_id: source.database_id,
+        backup_id: Some(BackupId::generate()),
+        base: Some(BackupBase {
+            backup_id: base.backup_id,
+            changed_pages: changed.len() as u32,
+        }),
+        digest_root: Some(DigestRoot::of(
+            changed
+                .iter()
+                .map(|&page_no| (page_no, &digests[page_no as usize - 1])),
+        )),
+        scopes: ScopedPages::from_map(&source.page_scopes),
+        created_at: options.created_at()?,
+        source: source_name(source_path),
+    };
+    let header_len = write_header(dest, &header, &backup_dek)?;
+    progress.advance(0, header_len)?;
+
+    process_pages(
+        changed.len() as u32,
+        options.threads,
+        |i| {
+            let page_no = changed[i as usize - 1];
+            Ok((
+                page_no,
+                raw_page(&raw, page_no, page_size),
+                page_crypto::rand_nonce(),
+            ))
+        },
+        |_, (page_no, page, nonce)| {
+            let (page, digest) = backup_page(page, page_no, &source, &backup_dek, reserve, nonce)?;
+            anyhow::ensure!(
+                digest == digests[page_no as usize - 1],
+                "page {page_no} changed in memory while it was backed up"
+            );
+            Ok((page_no, page))
+        },
+        |_, (page_no, page)| {
+            dest.write_all(&page_no.to_le_bytes())?;
+            dest.write_all(&page)?;
+            progress.advance(1, 4 + page.len() as u64)
+        },
+    )?;
 
-    let mut output = Vec::with_capacity(page_count * page_size);
+    dest.flush()?;
+    log::info!(
+        "incremental backup created: {} of {page_count} pages changed",
+        changed.len()
+    );
+    Ok(manifest_of(&header, digests))
+}
 
-    for i in 0..page_count {
-        let mut page_buf = vec![0u8; page_size];
-        source.read_exact(&mut page_buf)?;
-        let page_no = i as u32 + 1;
+/// Read the whole source database, with the keys its pages are under.
+fn read_source<'a>(
+    source_path: &Path,
+    source_keyring: &'a Keyring,
+    page_size: u32,
+    reserve: usize,
+) -> anyhow::Result<(Vec<u8>, SourceKeys<'a>)> {
+    let raw = std::fs::read(source_path)?;
+    anyhow::ensure!(
+        raw.len() % page_size as usize == 0,
+        "database size {} is not a multiple of page_size {page_size}",
+        raw.len()
+    );
+    let mut source = SourceKeys::new(source_keyring, keyring::database_id_of(source_path))?;
+    source.map_scopes(&raw, page_size, reserve);
+    Ok((raw, source))
+}
 
-        // Decrypt with backup DEK.
-        page_crypto::decrypt_page(&mut page_buf, page_no, &backup_dek, reserve)?;
+/// What decrypts the pages of a backup's source database.
+struct SourceKeys<'a> {
+    keyring: &'a Keyring,
+    database_id: Option<DatabaseId>,
+    /// Scope of each page of the tables with a DEK of their own.
+    page_scopes: HashMap<u32, KeyScope>,
+    /// Every DEK of the keyring, tried for a page that doesn't decrypt
+    /// under its scope's: one written before the VFS mapped it to its
+    /// table, or under a scope since dropped.
+    all_deks: Vec<Dek>,
+}
 
-        // Re-encrypt with target DEK.
-        page_crypto::encrypt_page(&mut page_buf, page_no, &target_dek, reserve)?;
+impl<'a> SourceKeys<'a> {
+    fn new(keyring: &'a Keyring, database_id: Option<DatabaseId>) -> anyhow::Result<Self> {
+        let mut all_deks = vec![keyring.dek_for(&KeyScope::Database)?];
+        all_deks.extend(keyring.all_deks()?);
+        Ok(Self {
+            keyring,
+            database_id,
+            page_scopes: HashMap::new(),
+            all_deks,
+        })
+    }
 
-        output.extend_from_slice(&page_buf);
+    /// Map the pages of the keyring's scoped tables, as the VFS does,
+    /// by walking their b-trees in the database `raw`. If the walk
+    /// fails every page is left to the Database scope.
+    fn map_scopes(&mut self, raw: &[u8], page_size: u32, reserve: usize) {
+        let tables = self.keyring.table_scopes();
+        if tables.is_empty() || raw.is_empty() {
+            return;
+        }
+        let read = |page_no: u32| {
+            anyhow::ensure!(
+                (page_no as usize) * (page_size as usize) <= raw.len(),
+                "page {page_no} is past the end of the database"
+            );
+            let mut page = raw_page(raw, page_no, page_size);
+            decrypt_source_page(
+                &mut page,
+                page_no,
+                self.database_id,
+                &self.all_deks,
+                reserve,
+            )?;
+            Ok(page)
+        };
+        match btree::table_pages(read, page_size as usize - reserve, &tables) {
+            Ok(pages) => {
+                self.page_scopes = pages
+                    .into_iter()
+                    .map(|(table, page_no)| (page_no, KeyScope::Table(table)))
+                    .collect()
+            }
+            Err(e) => log::warn!("backup: could not map the pages of scoped tables: {e}"),
+        }
     }
 
-    std::fs::write(target_path, &output)?;
-    log::info!(
-        "backup restored: {page_count} pages -> {}",
-        target_path.display()
-    );
-    Ok(())
+    /// Decrypt a page in place under the DEK of its scope, or any other
+    /// of the keyring's.
+    fn decrypt(&self, page: &mut [u8], page_no: u32, reserve: usize) -> anyhow::Result<()> {
+        let dek = self
+            .keyring
+            .dek_for_page(page_no, Some(&self.page_scopes))?;
+        decrypt_source_page(page, page_no, self.database_id, &[dek], reserve).or_else(|_| {
+            decrypt_source_page(page, page_no, self.database_id, &self.all_deks, reserve)
+        })
+    }
 }
 
-/// Verify a backup's integrity without fully restoring it.
-///
-/// Unwraps the DEK and attempts to decrypt every page, checking
-/// that the AES-GCM auth tags validate.
-pub fn verify_backup(
-    source: &mut dyn Read,
-    backup_kms: &dyn KmsProvider,
-) -> anyhow::Result<VerifyResult> {
-    let mut magic = [0u8; 8];
-    source.read_exact(&mut magic)?;
-    anyhow::ensure!(&magic == BACKUP_MAGIC, "bad magic");
-
-    let mut hdr_len_buf = [0u8; 4];
-    source.read_exact(&mut hdr_len_buf)?;
-    let hdr_len = u32::from_le_bytes(hdr_len_buf) as usize;
-
-    let mut hdr_buf = vec![0u8; hdr_len];
-    source.read_exact(&mut hdr_buf)?;
-    let header: BackupHeader = bincode::decode_from_slice(&hdr_buf, config::standard())?.0;
+/// Digests of the pages of the database `raw`, decrypted with
+/// `source`'s keys.
+fn source_digests(
+    raw: &[u8],
+    page_size: u32,
+    reserve: usize,
+    source: &SourceKeys,
+    threads: usize,
+    progress: &mut Progress,
+) -> anyhow::Result<Vec<[u8; 32]>> {
+    let page_count = (raw.len() / page_size as usize) as u32;
+    let mut digests = Vec::with_capacity(page_count as usize);
+    process_pages(
+        page_count,
+        threads,
+        |page_no| Ok(raw_page(raw, page_no, page_size)),
+        |page_no, mut page| {
+            source.decrypt(&mut page, page_no, reserve)?;
+            Ok(page_digest(&page, reserve))
+        },
+        |_, digest| {
+            digests.push(digest);
+            progress.advance(1, 0)
+        },
+    )?;
+    Ok(digests)
+}
 
-    let page_size = header.page_size as usize;
-    let reserve = header.reserve_size as usize;
-    let page_count = header.page_count as usize;
+fn raw_page(raw: &[u8], page_no: u32, page_size: u32) -> Zeroizing<Vec<u8>> {
+    let offset = rekey::page_offset(page_no, page_size) as usize;
+    Zeroizing::new(raw[offset..offset + page_size as usize].to_vec())
+}
 
-    let backup_dek = envelope::unwrap_dek(&header.wrapped_dek, backup_kms)?;
+fn manifest_of(header: &BackupHeader, digests: Vec<[u8; 32]>) -> BackupManifest {
+    BackupManifest {
+        backup_id: header.backup_id.expect("new backups have an ID"),
+        database_id: header.database_id,
+        page_size: header.page_size,
+        reserve_size: header.reserve_size,
+        digests,
+    }
+}
 
-    let mut pages_ok: u32 = 0;
-    let mut pages_bad: u32 = 0;
+/// SHA-256 of the payload of a decrypted page.
+fn page_digest(page: &[u8], reserve: usize) -> [u8; 32] {
+    Sha256::digest(&page[..page.len() - reserve]).into()
+}
 
-    for i in 0..page_count {
-        let mut page_buf = vec![0u8; page_size];
-        source.read_exact(&mut page_buf)?;
-        let page_no = i as u32 + 1;
+/// A backup's `digest_root`, fed the number and digest of each page it
+/// holds in order.
+#[derive(Default)]
+struct DigestRoot(Sha256);
 
-        match page_crypto::decrypt_page(&mut page_buf, page_no, &backup_dek, reserve) {
-            Ok(()) => pages_ok += 1,
-            Err(e) => {
-                log::warn!("verify: page {page_no} failed: {e}");
-                pages_bad += 1;
-            }
+impl DigestRoot {
+    fn of<'a>(pages: impl IntoIterator<Item = (u32, &'a [u8; 32])>) -> [u8; 32] {
+        let mut root = Self::default();
+        for (page_no, digest) in pages {
+            root.add(page_no, digest);
         }
+        root.finish()
     }
 
-    Ok(VerifyResult {
-        page_count: page_count as u32,
-        pages_ok,
-        pages_bad,
-    })
-}
-
-#[derive(Debug)]
-pub struct VerifyResult {
-    pub page_count: u32,
-    pub pages_ok: u32,
-    pub pages_bad: u32,
-}
+    fn add(&mut self, page_no: u32, digest: &[u8; 32]) {
+        self.0.update(page_no.to_le_bytes());
+        self.0.update(digest);
+    }
 
-impl VerifyResult {
-    pub fn is_ok(&self) -> bool {
-        self.pages_bad == 0
+    fn finish(self) -> [u8; 32] {
+        self.0.finalize().into()
     }
 }
 
-/// Rotate backup encryption: re-wrap the backup DEK under a new KEK
-/// without re-encrypting every page.
+/// Create an encrypted backup of a database that may be in use, through
+/// SQLite's online backup API.
 ///
-/// This is O(1) - only the header is rewritten.
-pub fn rotate_backup_kek(
-    backup_path: &Path,
-    old_kms: &dyn KmsProvider,
-    new_kms: &dyn KmsProvider,
-) -> anyhow::Result<()> {
-    let data = std::fs::read(backup_path)?;
+/// The database at `source_path` is opened read-only through the evfs
+/// VFS registered as `vfs`, and copied from a single read transaction,
+/// so the backup holds one committed state of it however other
+/// connections write meanwhile. In WAL mode they carry on writing; in
+/// rollback journal mode their commits wait for the copy to finish.
+///
+/// SQLite hands over decrypted pages, which are encrypted under the
+/// backup DEK as they arrive and held in an unlinked temporary file
+/// until the copy is complete, so memory stays bounded however large
+/// the database. Pages are encrypted on the calling thread, so
+/// [`BackupOptions::threads`] is not used. Returns the backup's
+/// [`BackupManifest`].
+pub fn create_backup_live(
+    source_path: &Path,
+    vfs: &str,
+    dest: &mut dyn Write,
+    backup_kms: &dyn KmsProvider,
+) -> anyhow::Result<BackupManifest> {
+    create_backup_live_with(
+        source_path,
+        vfs,
+        dest,
+        backup_kms,
+        &BackupOptions::default(),
+    )
+}
+
+/// [`create_backup_live`] with explicit [`BackupOptions`].
+pub fn create_backup_live_with(
+    source_path: &Path,
+    vfs: &str,
+    dest: &mut dyn Write,
+    backup_kms: &dyn KmsProvider,
+    options: &BackupOptions,
+) -> anyhow::Result<BackupManifest> {
+    let source = Db::open(source_path, SQLITE_OPEN_READONLY, vfs)?;
+    unsafe { sqlite3_busy_timeout(source.0, LIVE_BUSY_TIMEOUT_MS) };
+    // The backup runs inside this transaction, so the page size and
+    // reserve read here are those of the state it copies.
+    source.exec(c"BEGIN; SELECT count(*) FROM sqlite_master;")?;
+    let page_size = source.query_int(c"PRAGMA page_size")? as u32;
+    let reserve = source.reserve()?;
+    // Pages are copied into the sink, then from it to `dest`.
+    let mut progress = Progress::new(options, 2 * source.query_int(c"PRAGMA page_count")? as u32);
+    let min_reserve = page_crypto::min_uncommitted_reserve(options.algorithm);
     anyhow::ensure!(
-        data.len() >= 12 && &data[0..8] == BACKUP_MAGIC,
-        "not a valid backup file"
+        reserve >= min_reserve,
+        "{} reserves {reserve} bytes per page, but {} needs {min_reserve}",
+        source_path.display(),
+        options.algorithm
     );
 
-    let hdr_len = u32::from_le_bytes(data[8..12].try_into()?) as usize;
-    let hdr_buf = &data[12..12 + hdr_len];
-    let header: BackupHeader = bincode::decode_from_slice(hdr_buf, config::standard())?.0;
+    let backup_dek = Dek::generate_for(options.algorithm);
+    let wrapped = envelope::wrap_dek(&backup_dek, backup_kms)?;
+    let sink = PageSink {
+        file: temp_file()?,
+        page_size: page_size as usize,
+        reserve,
+        database_id: keyring::database_id_of(source_path),
+        backup_dek: &backup_dek,
+        digests: Mutex::new(Vec::new()),
+        error: Mutex::new(None),
+    };
+    {
+        let sink_vfs = SinkVfs::register(&sink)?;
+        let copy = Db::open(
+            Path::new("backup"),
+            SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+            sink_vfs.name(),
+        )?;
+        // The copy is one transaction no one else sees: it needs no
+        // journal, and a small cache spills pages to the sink early.
+        copy.exec(c"PRAGMA journal_mode=OFF; PRAGMA synchronous=OFF; PRAGMA cache_size=64;")?;
+        copy.backup_from(&source, options.progress_every, |pages| {
+            progress.advance(pages, 0)
+        })
+        .map_err(|e| sink.error.lock().take().unwrap_or(e))?;
+    }
+    drop(source);
 
-    // Unwrap DEK with old KEK, re-wrap with new KEK.
-    let dek = envelope::unwrap_dek(&header.wrapped_dek, old_kms)?;
-    let new_wrapped = envelope::wrap_dek(&dek, new_kms)?;
+    let size = sink.file.metadata()?.len();
+    anyhow::ensure!(
+        size % page_size as u64 == 0,
+        "backup copy size {size} is not a multiple of page_size {page_size}"
+    );
+    let page_count = (size / page_size as u64) as u32;
+
+    // SQLite never writes the page holding the lock byte, which
+    // leaves a hole of zeros in the copy.
+    let mut page = vec![0u8; page_size as usize];
+    let hole = page_digest(&page, reserve);
+    let mut digests: Vec<_> = std::mem::take(&mut *sink.digests.lock())
+        .into_iter()
+        .map(|digest| digest.unwrap_or(hole))
+        .collect();
+    digests.resize(page_count as usize, hole);
 
-    let new_header = BackupHeader {
-        wrapped_dek: new_wrapped,
-        ..header
+    let header = BackupHeader {
+        version: BACKUP_VERSION,
+        page_size,
+        page_count,
+        reserve_size: reserve as u32,
+        wrapped_dek: wrapped,
+        algorithm: options.algorithm,
+        database_id: sink.database_id,
+        backup_id: Some(BackupId::generate()),
+        base: None,
+        digest_root: Some(DigestRoot::of((1..).zip(&digests))),
+        scopes: Vec::new(),
+        created_at: options.created_at()?,
+        source: source_name(source_path),
     };
-    let mut new_hdr_bytes = vec![0u8; 2048];
-    bincode::encode_into_slice(&new_header, &mut new_hdr_bytes, config::standard())?;
-
-    // Rewrite the file: magic + new header + same page data.
-    let mut out = Vec::with_capacity(data.len());
-    out.extend_from_slice(BACKUP_MAGIC);
-    out.extend_from_slice(&(new_hdr_bytes.len() as u32).to_le_bytes());
-    out.extend_from_slice(&new_hdr_bytes);
-    out.extend_from_slice(&data[12 + hdr_len..]);
+    let header_len = write_header(dest, &header, &backup_dek)?;
+    progress.advance(0, header_len)?;
+
+    for page_no in 1..=page_count {
+        sink.file
+            .read_exact_at(&mut page, rekey::page_offset(page_no, page_size) as u64)?;
+        if page.iter().all(|&b| b == 0) {
+            sink.encrypt(&mut page, page_no)?;
+        }
+        dest.write_all(&page)?;
+        progress.advance(1, page.len() as u64)?;
+    }
 
-    std::fs::write(backup_path, &out)?;
-    log::info!("backup KEK rotated for {}", backup_path.display());
-    Ok(())
+    dest.flush()?;
+    log::info!("live backup created: {page_count} pages");
+    Ok(manifest_of(&header, digests))
 }
 
-fn is_plaintext_header(page: &[u8]) -> bool {
-    page.len() >= 16 && &page[0..16] == b"SQLite format 3\0"
+/// Re-encrypt the pages of the database `raw` under `backup_dek` and
+/// write them to `dest`, each under the next nonce from `next_nonce`.
+/// Returns the pages' digests.
+#[allow(clippy::too_many_arguments)]
+fn backup_pages(
+    raw: &[u8],
+    header: &BackupHeader,
+    source: &SourceKeys,
+    backup_dek: &Dek,
+    dest: &mut dyn Write,
+    threads: usize,
+    next_nonce: &mut dyn FnMut() -> [u8; MAX_NONCE_LEN],
+    progress: &mut Progress,
+) -> anyhow::Result<Vec<[u8; 32]>> {
+    let reserve = header.reserve_size as usize;
+    let mut digests = Vec::with_capacity(header.page_count as usize);
+    process_pages(
+        header.page_count,
+        threads,
+        |page_no| Ok((raw_page(raw, page_no, header.page_size), next_nonce())),
+        |page_no, (page, nonce)| backup_page(page, page_no, source, backup_dek, reserve, nonce),
+        |_, (page, digest)| {
+            digests.push(digest);
+            dest.write_all(&page)?;
+            progress.advance(1, page.len() as u64)
+        },
+    )?;
+    Ok(digests)
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{io::Cursor, sync::Arc};
+/// Decrypt a page of the source database and encrypt it under the
+/// backup DEK, bound to the same database ID, returning it and its
+/// digest. The buffer holds plaintext in between, and is zeroized if
+/// encrypting fails.
+fn backup_page(
+    mut page: Zeroizing<Vec<u8>>,
+    page_no: u32,
+    source: &SourceKeys,
+    backup_dek: &Dek,
+    reserve: usize,
+    nonce: [u8; MAX_NONCE_LEN],
+) -> anyhow::Result<(Zeroizing<Vec<u8>>, [u8; 32])> {
+    source.decrypt(&mut page, page_no, reserve)?;
+    let digest = page_digest(&page, reserve);
+    page_crypto::encrypt_page_with_nonce(
+        &mut page,
+        page_no,
+        source.database_id,
+        backup_dek,
+        reserve,
+        nonce,
+    )?;
+    Ok((page, digest))
+}
 
-    use super::*;
-    use crate::{crypto::keys::KeyScope, keyring::Keyring, kms::local::DeviceKeyProvider};
+/// Decrypt a page of the source database in place with whichever of
+/// `source_deks` it is under.
+fn decrypt_source_page(
+    page: &mut [u8],
+    page_no: u32,
+    database_id: Option<DatabaseId>,
+    source_deks: &[Dek],
+    reserve: usize,
+) -> anyhow::Result<()> {
+    // Page 1 is plaintext unless the database conceals its header.
+    if page_no == 1 && is_plaintext_header(page) {
+        return Ok(());
+    }
+    let mut result = Ok(());
+    for dek in source_deks {
+        result = page_crypto::decrypt_page(page, page_no, database_id, dek, reserve);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result.map_err(|e| anyhow::anyhow!("page {page_no}: {e}"))
+}
 
-    fn test_provider(key: [u8; 32]) -> Arc<dyn KmsProvider> {
-        // Write a temp keyfile.
+/// Restore from an encrypted backup.
+///
+/// Decrypts each page with the backup DEK (unwrapped via
+/// `backup_kms`), then re-encrypts under the target keyring's
+/// current DEK, and writes the restored database to `target_path`.
+///
+/// Pages are written to `target_path` with `.partial` appended as
+/// they are restored, so memory use doesn't grow with the database.
+/// That file is synced and renamed over `target_path` once complete,
+/// and removed if the restore fails.
+///
+/// The restored database takes the source database's ID, or a new
+/// one if the backup predates IDs, and its sidecar records that and
+/// the target DEK, so it opens through a VFS with the target keyring's
+/// KEK. Its page 1 is encrypted like every other page, so it conceals
+/// its header whether or not the source did.
+///
+/// `source` must be a full backup; see [`restore_backup_chain`] for
+/// incremental ones.
+pub fn restore_backup(
+    source: &mut dyn Read,
+    target_path: &Path,
+    backup_kms: &dyn KmsProvider,
+    target_keyring: &Keyring,
+) -> anyhow::Result<()> {
+    restore_backup_with(
+        source,
+        target_path,
+        backup_kms,
+        target_keyring,
+        &BackupOptions::default(),
+    )
+}
+
+/// [`restore_backup`] with explicit [`BackupOptions`].
+pub fn restore_backup_with(
+    source: &mut dyn Read,
+    target_path: &Path,
+    backup_kms: &dyn KmsProvider,
+    target_keyring: &Keyring,
+    options: &BackupOptions,
+) -> anyhow::Result<()> {
+    restore_backup_chain_with(
+        source,
+        &mut [],
+        target_path,
+        backup_kms,
+        target_keyring,
+        options,
+    )
+}
+
+/// Restore the full backup `full` with the incremental backups in
+/// `incrementals` layered over it, as [`restore_backup`] does a full
+/// backup alone.
+///
+/// Each incremental backup must be over the backup before it in the
+/// chain, which is checked before its pages are applied.
+pub fn restore_backup_chain(
+    full: &mut dyn Read,
+    incrementals: &mut [&mut dyn Read],
+    target_path: &Path,
+    backup_kms: &dyn KmsProvider,
+    target_keyring: &Keyring,
+) -> anyhow::Result<()> {
+    restore_backup_chain_with(
+        full,
+        incrementals,
+        target_path,
+        backup_kms,
+        target_keyring,
+        &BackupOptions::default(),
+    )
+}
+
+/// [`restore_backup_chain`] with explicit [`BackupOptions`].
+pub fn restore_backup_chain_with(
+    full: &mut dyn Read,
+    incrementals: &mut [&mut dyn Read],
+    target_path: &Path,
+    backup_kms: &dyn KmsProvider,
+    target_keyring: &Keyring,
+    options: &BackupOptions,
+) -> anyhow::Result<()> {
+    let (first, backup_dek) = read_header(full, backup_kms)?;
+    anyhow::ensure!(
+        first.base.is_none(),
+        "backup is incremental; restore it with restore_backup_chain over its base"
+    );
+    // Every link of the chain is checked before any pages are read.
+    let mut headers = vec![(first, backup_dek)];
+    for (i, source) in incrementals.iter_mut().enumerate() {
+        let (next, backup_dek) = read_header(*source, backup_kms)?;
+        let (header, _) = &headers[i];
+        let base = next
+            .base
+            .ok_or_else(|| anyhow::anyhow!("backup {} of the chain is not incremental", i + 2))?;
+        anyhow::ensure!(
+            header.backup_id == Some(base.backup_id),
+            "backup {} of the chain is over backup {}, not the one before it",
+            i + 2,
+            base.backup_id
+        );
+        anyhow::ensure!(
+            (next.page_size, next.reserve_size, next.database_id)
+                == (header.page_size, header.reserve_size, header.database_id),
+            "backup {} of the chain is of a database with another page size, reserve or ID",
+            i + 2
+        );
+        headers.push((next, backup_dek));
+    }
+    let pages_total = headers.iter().map(|(header, _)| stored_pages(header)).sum();
+    let mut progress = Progress::new(options, pages_total);
+
+    let database_id = headers[0]
+        .0
+        .database_id
+        .unwrap_or_else(DatabaseId::generate);
+
+    let (header, _) = headers.last().expect("the chain has a full backup");
+    let page_count = header.page_count;
+    // Pages go under the target keyring's DEKs for the scopes they had
+    // in the database as last backed up, which are made ready first.
+    let page_scopes = ScopedPages::to_map(&header.scopes);
+    let mut target_deks = vec![(
+        KeyScope::Database,
+        target_keyring.dek_for(&KeyScope::Database)?,
+    )];
+    for scoped in &header.scopes {
+        target_deks.push((scoped.scope.clone(), target_keyring.dek_for(&scoped.scope)?));
+    }
+    let target_dek = |page_no| target_keyring.dek_for_page(page_no, Some(&page_scopes));
+    let partial = partial_path(target_path);
+    (|| {
+        let output = File::create(&partial)?;
+        for (i, (header, backup_dek)) in headers.iter().enumerate() {
+            let source: &mut dyn Read = match i {
+                0 => &mut *full,
+                _ => &mut *incrementals[i - 1],
+            };
+            restore_pages(
+                source,
+                header,
+                backup_dek,
+                &target_dek,
+                database_id,
+                options.threads,
+                &mut page_crypto::rand_nonce,
+                &output,
+                &mut progress,
+            )?;
+        }
+        output.sync_all()?;
+
+        // The sidecar is written before the pages are renamed into
+        // place, so they never sit at `target_path` bound to an ID it
+        // doesn't record.
+        target_keyring.install_database_id(target_path, database_id)?;
+        target_keyring.install_concealed_header(
+            target_path,
+            ConcealedHeader {
+                page_size: header.page_size,
+                reserve_size: header.reserve_size,
+            },
+        )?;
+        for (scope, dek) in target_deks {
+            let wrapped = envelope::wrap_dek(&dek, target_keyring.provider())?;
+            target_keyring.install_dek(target_path, &scope, dek, wrapped)?;
+        }
+        migrate::replace(&partial, target_path)
+    })()
+    .inspect_err(|_| {
+        let _ = std::fs::remove_file(&partial);
+    })?;
+    log::info!(
+        "backup restored: {page_count} pages -> {}",
+        target_path.display()
+    );
+    Ok(())
+}
+
+/// Path a database is restored, or a backup rotated, to before being
+/// renamed to `target_path`.
+fn partial_path(target_path: &Path) -> PathBuf {
+    let mut partial = target_path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+/// Read the backup's pages from `source`, decrypt them with the backup
+/// DEK and encrypt them under the DEK `target_dek` gives each, bound to
+/// `database_id`,
+/// each under the next nonce from `next_nonce`, into the restored
+/// database `output` as they are done. An incremental backup's pages
+/// replace those of the database restored so far, which is resized to
+/// its page count.
+///
+/// Each page's plaintext is digested before it is encrypted again,
+/// and the pages must match the header's `digest_root` if it has one.
+#[allow(clippy::too_many_arguments)]
+fn restore_pages(
+    source: &mut dyn Read,
+    header: &BackupHeader,
+    backup_dek: &Dek,
+    target_dek: &(dyn Fn(u32) -> anyhow::Result<Dek> + Sync),
+    database_id: DatabaseId,
+    threads: usize,
+    next_nonce: &mut dyn FnMut() -> [u8; MAX_NONCE_LEN],
+    output: &File,
+    progress: &mut Progress,
+) -> anyhow::Result<()> {
+    let page_size = header.page_size as usize;
+    let reserve = header.reserve_size as usize;
+    let previous_count = (output.metadata()?.len() / page_size as u64) as u32;
+    output.set_len(header.page_count as u64 * page_size as u64)?;
+    let mut last_page_no = 0;
+    let mut new_pages = 0;
+    let mut root = DigestRoot::default();
+    process_pages(
+        stored_pages(header),
+        threads,
+        |_| {
+            let page_no = next_page_no(source, header, &mut last_page_no)?;
+            let mut page = Zeroizing::new(vec![0u8; page_size]);
+            source.read_exact(&mut page)?;
+            Ok((page_no, page, next_nonce()))
+        },
+        |_, (page_no, mut page, nonce)| {
+            decrypt_backup_page(&mut page, page_no, header, backup_dek)?;
+            let digest = page_digest(&page, reserve);
+            page_crypto::encrypt_page_with_nonce(
+                &mut page,
+                page_no,
+                Some(database_id),
+                &target_dek(page_no)?,
+                reserve,
+                nonce,
+            )?;
+            Ok((page_no, page, digest))
+        },
+        |_, (page_no, page, digest)| {
+            output.write_all_at(&page, rekey::page_offset(page_no, header.page_size) as u64)?;
+            new_pages += u32::from(page_no > previous_count);
+            root.add(page_no, &digest);
+            progress.advance(1, page_size as u64)
+        },
+    )?;
+    anyhow::ensure!(
+        header
+            .digest_root
+            .is_none_or(|expected| expected == root.finish()),
+        "backup pages do not match its digest root"
+    );
+    // Pages past the end of the base aren't in it, so the backup must
+    // hold them all.
+    anyhow::ensure!(
+        new_pages == header.page_count.saturating_sub(previous_count),
+        "backup is missing pages past the end of its base"
+    );
+    Ok(())
+}
+
+/// Pages stored in a backup: all of them, or an incremental backup's
+/// changed ones.
+fn stored_pages(header: &BackupHeader) -> u32 {
+    header
+        .base
+        .map_or(header.page_count, |base| base.changed_pages)
+}
+
+/// Page number of the next page stored in a backup, after
+/// `last_page_no`: read from before the page in an incremental backup,
+/// where it must be in order, and the next one in a full backup.
+fn next_page_no(
+    source: &mut dyn Read,
+    header: &BackupHeader,
+    last_page_no: &mut u32,
+) -> anyhow::Result<u32> {
+    let page_no = if header.base.is_some() {
+        let mut bytes = [0u8; 4];
+        source.read_exact(&mut bytes)?;
+        u32::from_le_bytes(bytes)
+    } else {
+        *last_page_no + 1
+    };
+    anyhow::ensure!(
+        page_no > *last_page_no && page_no <= header.page_count,
+        "backup holds page {page_no} out of order"
+    );
+    *last_page_no = page_no;
+    Ok(page_no)
+}
+
+/// Verify a backup's integrity without fully restoring it.
+///
+/// Unwraps the DEK and attempts to decrypt every page, checking
+/// that the auth tags validate, and that the decrypted pages match
+/// the header's digest root if it has one.
+pub fn verify_backup(
+    source: &mut dyn Read,
+    backup_kms: &dyn KmsProvider,
+) -> anyhow::Result<VerifyResult> {
+    verify_backup_with(source, backup_kms, &BackupOptions::default())
+}
+
+/// [`verify_backup`] with explicit [`BackupOptions`].
+pub fn verify_backup_with(
+    source: &mut dyn Read,
+    backup_kms: &dyn KmsProvider,
+    options: &BackupOptions,
+) -> anyhow::Result<VerifyResult> {
+    verify_pages(source, backup_kms, None, options)
+}
+
+/// [`verify_backup`], also comparing each page's digest with the one in
+/// `manifest`, the manifest returned when the backup was created.
+pub fn verify_backup_against(
+    source: &mut dyn Read,
+    backup_kms: &dyn KmsProvider,
+    manifest: &BackupManifest,
+) -> anyhow::Result<VerifyResult> {
+    verify_pages(
+        source,
+        backup_kms,
+        Some(manifest),
+        &BackupOptions::default(),
+    )
+}
+
+fn verify_pages(
+    source: &mut dyn Read,
+    backup_kms: &dyn KmsProvider,
+    manifest: Option<&BackupManifest>,
+    options: &BackupOptions,
+) -> anyhow::Result<VerifyResult> {
+    let (header, backup_dek) = read_header(source, backup_kms)?;
+    let page_size = header.page_size as usize;
+    let reserve = header.reserve_size as usize;
+    if let Some(manifest) = manifest {
+        anyhow::ensure!(
+            header.backup_id == Some(manifest.backup_id),
+            "manifest is of backup {}, not this one",
+            manifest.backup_id
+        );
+    }
+
+    let mut pages_ok: u32 = 0;
+    let mut pages_bad: u32 = 0;
+    let mut pages_mismatched: u32 = 0;
+    let mut last_page_no = 0;
+    let mut root = DigestRoot::default();
+    let mut root_complete = true;
+    let mut progress = Progress::new(options, stored_pages(&header));
+
+    process_pages(
+        stored_pages(&header),
+        options.threads,
+        |_| {
+            let page_no = next_page_no(source, &header, &mut last_page_no)?;
+            let mut page = Zeroizing::new(vec![0u8; page_size]);
+            source.read_exact(&mut page)?;
+            Ok((page_no, page))
+        },
+        |_, (page_no, mut page)| {
+            let result = decrypt_backup_page(&mut page, page_no, &header, &backup_dek);
+            if let Err(e) = &result {
+                log::warn!("verify: page {page_no} failed: {e}");
+            }
+            Ok((page_no, result.ok().map(|()| page_digest(&page, reserve))))
+        },
+        |_, (page_no, digest)| {
+            progress.advance(1, 0)?;
+            let Some(digest) = digest else {
+                pages_bad += 1;
+                root_complete = false;
+                return Ok(());
+            };
+            pages_ok += 1;
+            root.add(page_no, &digest);
+            let expected = manifest.and_then(|m| m.digests.get(page_no as usize - 1));
+            if expected.is_some_and(|expected| *expected != digest) {
+                log::warn!("verify: page {page_no} does not match the manifest");
+                pages_mismatched += 1;
+            }
+            Ok(())
+        },
+    )?;
+
+    // A page that fails its tag is already counted, and leaves no
+    // digest to check the root with.
+    let digests_ok = header
+        .digest_root
+        .filter(|_| root_complete)
+        .map(|expected| expected == root.finish());
+    Ok(VerifyResult {
+        page_count: header.page_count,
+        pages_ok,
+        pages_bad,
+        pages_mismatched,
+        digests_ok,
+    })
+}
+
+/// Verify a restored database against the `manifest` of the backup it
+/// was restored from, decrypting its pages with the DEKs in its sidecar
+/// under `keyring`'s KEK.
+///
+/// Every page must match the digest the backup recorded, so run it
+/// before the database is written to: SQLite updates page 1 on the
+/// first write.
+pub fn verify_restored(
+    db_path: &Path,
+    manifest: &BackupManifest,
+    keyring: &Keyring,
+) -> anyhow::Result<VerifyResult> {
+    let raw = std::fs::read(db_path)?;
+    anyhow::ensure!(
+        raw.len() % manifest.page_size as usize == 0,
+        "database size {} is not a multiple of page_size {}",
+        raw.len(),
+        manifest.page_size
+    );
+    let deks = keyring::sidecar_deks(db_path, keyring.provider())?;
+    let database_id = keyring::database_id_of(db_path);
+    let reserve = manifest.reserve_size as usize;
+    let page_count = (raw.len() / manifest.page_size as usize) as u32;
+
+    let mut pages_ok: u32 = 0;
+    let mut pages_bad: u32 = 0;
+    let mut pages_mismatched: u32 = 0;
+    process_pages(
+        page_count,
+        BackupOptions::default().threads,
+        |page_no| Ok(raw_page(&raw, page_no, manifest.page_size)),
+        |page_no, mut page| {
+            let result = decrypt_source_page(&mut page, page_no, database_id, &deks, reserve);
+            if let Err(e) = &result {
+                log::warn!("verify: {e}");
+            }
+            Ok(result.ok().map(|()| page_digest(&page, reserve)))
+        },
+        |page_no, digest| {
+            match digest {
+                None => pages_bad += 1,
+                Some(digest) if manifest.digests.get(page_no as usize - 1) == Some(&digest) => {
+                    pages_ok += 1
+                }
+                Some(_) => {
+                    log::warn!("verify: page {page_no} does not match the manifest");
+                    pages_ok += 1;
+                    pages_mismatched += 1;
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok(VerifyResult {
+        page_count,
+        pages_ok,
+        pages_bad,
+        pages_mismatched,
+        digests_ok: Some(page_count as usize == manifest.digests.len()),
+    })
+}
+
+/// Write the magic, the header and, if its version authenticates it,
+/// the MAC of them under `backup_dek`, and return the bytes written.
+fn write_header(
+    dest: &mut dyn Write,
+    header: &BackupHeader,
+    backup_dek: &Dek,
+) -> anyhow::Result<u64> {
+    // Zero padded to at least 2048 bytes, which is what readers from
+    // before scopes were recorded expect.
+    let mut header_bytes = bincode::encode_to_vec(header, config::standard())?;
+    header_bytes.resize(header_bytes.len().max(MIN_HEADER_LEN), 0);
+
+    // Write magic + header-length + header.
+    let mut prefix = BACKUP_MAGIC.to_vec();
+    prefix.extend_from_slice(&(header_bytes.len() as u32).to_le_bytes());
+    prefix.extend_from_slice(&header_bytes);
+    dest.write_all(&prefix)?;
+    if header.version >= AUTHENTICATED_BACKUP_VERSION {
+        dest.write_all(&header_mac(backup_dek, &prefix).finalize().into_bytes())?;
+        return Ok((prefix.len() + HEADER_MAC_LEN) as u64);
+    }
+    Ok(prefix.len() as u64)
+}
+
+/// Read a backup's header and unwrap its DEK, leaving `source` at the
+/// first page. An authenticated header is checked against its MAC
+/// before any of it but the wrapped DEK is used; an older header
+/// can't be, and is used as is with a warning.
+fn read_header(
+    source: &mut dyn Read,
+    backup_kms: &dyn KmsProvider,
+) -> anyhow::Result<(BackupHeader, Dek)> {
+    let mut prefix = vec![0u8; BACKUP_MAGIC.len() + 4];
+    source.read_exact(&mut prefix)?;
+    anyhow::ensure!(
+        prefix.starts_with(BACKUP_MAGIC),
+        "invalid backup file: bad magic"
+    );
+    let hdr_len = u32::from_le_bytes(prefix[BACKUP_MAGIC.len()..].try_into()?) as usize;
+    anyhow::ensure!(hdr_len < 1024 * 1024, "header too large: {hdr_len}");
+
+    let hdr_start = prefix.len();
+    prefix.resize(hdr_start + hdr_len, 0);
+    source.read_exact(&mut prefix[hdr_start..])?;
+    // A header longer than it is padded to ends with its last field;
+    // decode the fields added since it was written from zeros, as a
+    // padded one's are.
+    let mut header_bytes = prefix[hdr_start..].to_vec();
+    header_bytes.resize(hdr_len + 16, 0);
+    let header: BackupHeader = bincode::decode_from_slice(&header_bytes, config::standard())?.0;
+    ensure_backup_version(header.version)?;
+
+    // The MAC is keyed by the DEK, so that is unwrapped first. Its
+    // wrapping authenticates it under the KEK, and a DEK swapped in
+    // from another backup keys a MAC this header doesn't carry.
+    let backup_dek = unwrap_backup_dek(&header, backup_kms)?;
+    if header.version >= AUTHENTICATED_BACKUP_VERSION {
+        let mut mac = [0u8; HEADER_MAC_LEN];
+        source.read_exact(&mut mac)?;
+        header_mac(&backup_dek, &prefix)
+            .verify_slice(&mac)
+            .map_err(|_| anyhow::anyhow!("backup header failed authentication"))?;
+    } else {
+        log::warn!(
+            "backup version {} has an unauthenticated header: its page size, reserve \
+             and wrapped DEK are trusted as read; take a new backup to authenticate them",
+            header.version
+        );
+    }
+    Ok((header, backup_dek))
+}
+
+/// HMAC-SHA256 of a backup's magic, header length and header
+/// (`prefix`), under a key derived from the backup DEK.
+fn header_mac(backup_dek: &Dek, prefix: &[u8]) -> Hmac<Sha256> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, backup_dek.as_bytes())
+        .expand(b"evfs backup header", key.as_mut())
+        .expect("HKDF output length");
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_ref()).expect("HMAC takes any key");
+    mac.update(prefix);
+    mac
+}
+
+/// Unwrap the backup DEK, which must be for the algorithm the header
+/// records.
+fn unwrap_backup_dek(header: &BackupHeader, backup_kms: &dyn KmsProvider) -> anyhow::Result<Dek> {
+    let dek = envelope::unwrap_dek(&header.wrapped_dek, backup_kms)?;
+    anyhow::ensure!(
+        dek.algorithm() == header.algorithm,
+        "backup header records {} but its DEK is for {}",
+        header.algorithm,
+        dek.algorithm()
+    );
+    Ok(dek)
+}
+
+/// Pages read but not yet written, per thread.
+const PAGES_IN_FLIGHT_PER_THREAD: usize = 4;
+
+/// Run pages `1..=page_count` through `crypt` on `threads` threads.
+///
+/// The calling thread reads each page with `read` and writes each
+/// result with `write`, both in page order, and keeps at most a few
+/// pages per thread in flight, so memory stays bounded however large
+/// the database. `crypt` only sees owned buffers, so its results
+/// don't depend on how pages are spread over threads.
+fn process_pages<J: Send, T: Send>(
+    page_count: u32,
+    threads: usize,
+    mut read: impl FnMut(u32) -> anyhow::Result<J>,
+    crypt: impl Fn(u32, J) -> anyhow::Result<T> + Sync,
+    mut write: impl FnMut(u32, T) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if threads <= 1 {
+        for page_no in 1..=page_count {
+            let job = read(page_no)?;
+            write(page_no, crypt(page_no, job)?)?;
+        }
+        return Ok(());
+    }
+
+    let in_flight = (threads * PAGES_IN_FLIGHT_PER_THREAD) as u32;
+    let (job_tx, job_rx) = mpsc::sync_channel::<(u32, J)>(in_flight as usize);
+    let job_rx = Mutex::new(job_rx);
+    let (done_tx, done_rx) = mpsc::channel::<(u32, anyhow::Result<T>)>();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            let (job_rx, done_tx, crypt) = (&job_rx, done_tx.clone(), &crypt);
+            scope.spawn(move || {
+                loop {
+                    let Ok((page_no, job)) = job_rx.lock().recv() else {
+                        break;
+                    };
+                    if done_tx.send((page_no, crypt(page_no, job))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(done_tx);
+
+        let mut pending = BTreeMap::new();
+        let (mut next_read, mut next_write) = (1, 1);
+        let mut run = || -> anyhow::Result<()> {
+            while next_write <= page_count {
+                while next_read <= page_count && next_read - next_write < in_flight {
+                    job_tx
+                        .send((next_read, read(next_read)?))
+                        .map_err(|_| anyhow::anyhow!("backup worker threads exited"))?;
+                    next_read += 1;
+                }
+                let (page_no, result) = done_rx.recv()?;
+                pending.insert(page_no, result);
+                while let Some(result) = pending.remove(&next_write) {
+                    write(next_write, result?)?;
+                    next_write += 1;
+                }
+            }
+            Ok(())
+        };
+        let result = run();
+        // Workers stop once the queue drains.
+        drop(job_tx);
+        result
+    })
+}
+
+#[derive(Debug)]
+pub struct VerifyResult {
+    /// Pages in the backed up database. An incremental backup holds
+    /// only `pages_ok + pages_bad` of them.
+    pub page_count: u32,
+    pub pages_ok: u32,
+    pub pages_bad: u32,
+    /// Pages that decrypted but don't match their digest in the
+    /// manifest checked against, if any.
+    pub pages_mismatched: u32,
+    /// Whether the pages match the backup's digest root, or the
+    /// restored database has the manifest's page count. `None` if the
+    /// backup predates digests, or a page failed to decrypt.
+    pub digests_ok: Option<bool>,
+}
+
+impl VerifyResult {
+    pub fn is_ok(&self) -> bool {
+        self.pages_bad == 0 && self.pages_mismatched == 0 && self.digests_ok != Some(false)
+    }
+}
+
+/// Rotate backup encryption: re-wrap the backup DEK under a new KEK
+/// without re-encrypting every page.
+///
+/// Only the header changes. The header is checked first, and an
+/// authenticated one is authenticated again; a backup keeps its
+/// version. The rotated backup is written to `<backup>.partial` with
+/// the pages copied as they are, synced, and read back under `new_kms`
+/// before it is renamed over the original, so a crash leaves either
+/// the old backup or the new one.
+pub fn rotate_backup_kek(
+    backup_path: &Path,
+    old_kms: &dyn KmsProvider,
+    new_kms: &dyn KmsProvider,
+) -> anyhow::Result<()> {
+    rotate_kek(backup_path, old_kms, new_kms, None)
+}
+
+/// A step of [`rotate_kek`] that a test stops it after.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RotateStep {
+    Written,
+    Verified,
+}
+
+fn rotate_kek(
+    backup_path: &Path,
+    old_kms: &dyn KmsProvider,
+    new_kms: &dyn KmsProvider,
+    interrupt_after: Option<RotateStep>,
+) -> anyhow::Result<()> {
+    let mut source = BufReader::new(File::open(backup_path)?);
+
+    // Unwrap DEK with old KEK, re-wrap with new KEK.
+    let (header, dek) = read_header(&mut source, old_kms)?;
+    let new_wrapped = envelope::wrap_dek(&dek, new_kms)?;
+    let new_header = BackupHeader {
+        wrapped_dek: new_wrapped,
+        ..header
+    };
+
+    let partial = partial_path(backup_path);
+    (|| {
+        // Magic + new header + same page data.
+        let mut out = BufWriter::new(File::create(&partial)?);
+        write_header(&mut out, &new_header, &dek)?;
+        let page_bytes = io::copy(&mut source, &mut out)?;
+        out.into_inner()?.sync_all()?;
+        if interrupt_after == Some(RotateStep::Written) {
+            anyhow::bail!("KEK rotation interrupted");
+        }
+
+        let mut written = BufReader::new(File::open(&partial)?);
+        let (read_back, read_back_dek) = read_header(&mut written, new_kms)?;
+        anyhow::ensure!(
+            read_back_dek.as_bytes() == dek.as_bytes()
+                && bincode::encode_to_vec(&read_back, config::standard())?
+                    == bincode::encode_to_vec(&new_header, config::standard())?
+                && io::copy(&mut written, &mut io::sink())? == page_bytes,
+            "rotated backup {} doesn't read back as written",
+            partial.display()
+        );
+        if interrupt_after == Some(RotateStep::Verified) {
+            anyhow::bail!("KEK rotation interrupted");
+        }
+        migrate::replace(&partial, backup_path)
+    })()
+    .inspect_err(|_| {
+        let _ = std::fs::remove_file(&partial);
+    })?;
+    log::info!("backup KEK rotated for {}", backup_path.display());
+    Ok(())
+}
+
+fn ensure_backup_version(version: u32) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        (LEGACY_BACKUP_VERSION..=BACKUP_VERSION).contains(&version),
+        "unsupported backup version: {version}"
+    );
+    Ok(())
+}
+
+/// Decrypt a page of the backup with `header`. Only version 1 backups
+/// hold `EVFSv1` pages, which they read without `EVFS_LEGACY_PAGES`.
+fn decrypt_backup_page(
+    page: &mut [u8],
+    page_no: u32,
+    header: &BackupHeader,
+    backup_dek: &Dek,
+) -> anyhow::Result<()> {
+    page_crypto::decrypt_page_with(
+        page,
+        page_no,
+        header.database_id,
+        backup_dek,
+        header.reserve_size as usize,
+        || header.version == LEGACY_BACKUP_VERSION,
+    )
+}
+
+fn is_plaintext_header(page: &[u8]) -> bool {
+    page.len() >= 16 && &page[0..16] == b"SQLite format 3\0"
+}
+
+// ── Live backup ─────────────────────────────────────────────────────
+
+/// How long a live backup waits to start its read transaction, e.g.
+/// while another connection recovers the WAL.
+const LIVE_BUSY_TIMEOUT_MS: c_int = 5000;
+
+/// A SQLite connection, closed on drop.
+struct Db(*mut sqlite3);
+
+impl Db {
+    fn open(path: &Path, flags: c_int, vfs: &str) -> anyhow::Result<Self> {
+        let c_path = CString::new(
+            path.to_str()
+                .ok_or_else(|| anyhow::anyhow!("non UTF-8 path {}", path.display()))?,
+        )?;
+        let c_vfs = CString::new(vfs)?;
+        let mut db = ptr::null_mut();
+        let rc = unsafe { sqlite3_open_v2(c_path.as_ptr(), &mut db, flags, c_vfs.as_ptr()) };
+        // Dropping closes the handle, even if opening failed.
+        let db = Self(db);
+        anyhow::ensure!(
+            rc == SQLITE_OK,
+            "open {} through {vfs}: {}",
+            path.display(),
+            db.errmsg()
+        );
+        Ok(db)
+    }
+
+    fn errmsg(&self) -> String {
+        unsafe { CStr::from_ptr(sqlite3_errmsg(self.0)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn exec(&self, sql: &CStr) -> anyhow::Result<()> {
+        let rc =
+            unsafe { sqlite3_exec(self.0, sql.as_ptr(), None, ptr::null_mut(), ptr::null_mut()) };
+        anyhow::ensure!(rc == SQLITE_OK, "{}", self.errmsg());
+        Ok(())
+    }
+
+    fn query_int(&self, sql: &CStr) -> anyhow::Result<i64> {
+        let mut stmt = ptr::null_mut();
+        let rc =
+            unsafe { sqlite3_prepare_v2(self.0, sql.as_ptr(), -1, &mut stmt, ptr::null_mut()) };
+        anyhow::ensure!(rc == SQLITE_OK, "{}", self.errmsg());
+        let value = unsafe {
+            let rc = sqlite3_step(stmt);
+            let value = (rc == SQLITE_ROW).then(|| sqlite3_column_int64(stmt, 0));
+            sqlite3_finalize(stmt);
+            value
+        };
+        value.ok_or_else(|| anyhow::anyhow!("{}", self.errmsg()))
+    }
+
+    /// Bytes reserved per page of the main database.
+    fn reserve(&self) -> anyhow::Result<usize> {
+        // Out of range, so only reads the reserve back.
+        let mut arg: c_int = -1;
+        let rc = unsafe {
+            sqlite3_file_control(
+                self.0,
+                c"main".as_ptr(),
+                SQLITE_FCNTL_RESERVE_BYTES,
+                &mut arg as *mut c_int as *mut c_void,
+            )
+        };
+        anyhow::ensure!(rc == SQLITE_OK, "read reserve: {}", self.errmsg());
+        Ok(arg as usize)
+    }
+
+    /// Copy all of `source` into this database in one backup step.
+    /// Copy `source` into this database, `step` pages at a time, passing
+    /// `on_step` the pages each step copied.
+    fn backup_from(
+        &self,
+        source: &Db,
+        step: u32,
+        mut on_step: impl FnMut(u32) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let backup =
+            unsafe { sqlite3_backup_init(self.0, c"main".as_ptr(), source.0, c"main".as_ptr()) };
+        anyhow::ensure!(!backup.is_null(), "backup: {}", self.errmsg());
+        let mut copied = 0;
+        let result = loop {
+            let rc = unsafe { sqlite3_backup_step(backup, step as c_int) };
+            if rc != SQLITE_OK && rc != SQLITE_DONE {
+                break Err(anyhow::anyhow!("backup: {}", self.errmsg()));
+            }
+            let done =
+                unsafe { sqlite3_backup_pagecount(backup) - sqlite3_backup_remaining(backup) };
+            if let Err(e) = on_step(done as u32 - copied) {
+                break Err(e);
+            }
+            copied = done as u32;
+            if rc == SQLITE_DONE {
+                break Ok(());
+            }
+        };
+        unsafe { sqlite3_backup_finish(backup) };
+        result
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.0) };
+    }
+}
+
+/// An unlinked temporary file, gone once closed.
+fn temp_file() -> anyhow::Result<File> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "evfs-backup-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// Where a live backup's copy of the database goes: a file of pages
+/// encrypted under the backup DEK, which decrypt again if SQLite reads
+/// them back.
+struct PageSink<'a> {
+    file: File,
+    page_size: usize,
+    reserve: usize,
+    database_id: Option<DatabaseId>,
+    backup_dek: &'a Dek,
+    /// Digest of each page written, by page number.
+    digests: Mutex<Vec<Option<[u8; 32]>>>,
+    /// The first error behind an I/O error returned to SQLite, which
+    /// is reported in its place.
+    error: Mutex<Option<anyhow::Error>>,
+}
+
+impl PageSink<'_> {
+    fn encrypt(&self, page: &mut [u8], page_no: u32) -> anyhow::Result<()> {
+        page_crypto::encrypt_page(
+            page,
+            page_no,
+            self.database_id,
+            self.backup_dek,
+            self.reserve,
+        )
+    }
+
+    fn write(&self, data: &[u8], offset: u64) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            data.len() == self.page_size && offset.is_multiple_of(self.page_size as u64),
+            "unaligned write of {} bytes at {offset}",
+            data.len()
+        );
+        let page_no = (offset / self.page_size as u64) as u32 + 1;
+        let mut page = Zeroizing::new(data.to_vec());
+        {
+            let mut digests = self.digests.lock();
+            if digests.len() < page_no as usize {
+                digests.resize(page_no as usize, None);
+            }
+            digests[page_no as usize - 1] = Some(page_digest(&page, self.reserve));
+        }
+        self.encrypt(&mut page, page_no)?;
+        Ok(self.file.write_all_at(&page, offset)?)
+    }
+
+    fn truncate(&self, size: u64) -> anyhow::Result<()> {
+        self.digests
+            .lock()
+            .truncate(size.div_ceil(self.page_size as u64) as usize);
+        Ok(self.file.set_len(size)?)
+    }
+
+    /// Fill `out` from the copy, returning false if it reaches past the
+    /// end of it.
+    fn read(&self, out: &mut [u8], offset: u64) -> anyhow::Result<bool> {
+        let page_size = self.page_size as u64;
+        let start = offset - offset % page_size;
+        anyhow::ensure!(
+            offset + out.len() as u64 <= start + page_size,
+            "read of {} bytes at {offset} spans pages",
+            out.len()
+        );
+        if start + page_size > self.file.metadata()?.len() {
+            out.fill(0);
+            return Ok(false);
+        }
+        let mut page = Zeroizing::new(vec![0u8; self.page_size]);
+        self.file.read_exact_at(&mut page, start)?;
+        if page.iter().any(|&b| b != 0) {
+            let page_no = (start / page_size) as u32 + 1;
+            page_crypto::decrypt_page(
+                &mut page,
+                page_no,
+                self.database_id,
+                self.backup_dek,
+                self.reserve,
+            )?;
+        }
+        let at = (offset - start) as usize;
+        out.copy_from_slice(&page[at..at + out.len()]);
+        Ok(true)
+    }
+
+    fn fail(&self, e: anyhow::Error, rc: c_int) -> c_int {
+        log::error!("live backup: {e}");
+        self.error.lock().get_or_insert(e);
+        rc
+    }
+}
+
+/// A VFS whose only file is a [`PageSink`], registered under a name of
+/// its own for as long as it lives.
+struct SinkVfs(*mut sqlite3_vfs);
+
+/// Must start with `sqlite3_file` so SQLite can cast between them.
+#[repr(C)]
+struct SinkFile {
+    base: sqlite3_file,
+    sink: *const PageSink<'static>,
+}
+
+static SINK_IO_METHODS: sqlite3_io_methods = sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(sink_close),
+    xRead: Some(sink_read),
+    xWrite: Some(sink_write),
+    xTruncate: Some(sink_truncate),
+    xSync: Some(sink_sync),
+    xFileSize: Some(sink_file_size),
+    xLock: Some(sink_lock),
+    xUnlock: Some(sink_lock),
+    xCheckReservedLock: Some(sink_check_reserved_lock),
+    xFileControl: Some(sink_file_control),
+    xSectorSize: Some(sink_sector_size),
+    xDeviceCharacteristics: Some(sink_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+impl SinkVfs {
+    fn register(sink: &PageSink) -> anyhow::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = CString::new(format!(
+            "evfs-backup-sink-{}",
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ))?;
+        let default_vfs = unsafe { sqlite3_vfs_find(ptr::null()) };
+        anyhow::ensure!(!default_vfs.is_null(), "no default sqlite3 VFS found");
+        let vfs = Box::into_raw(Box::new(sqlite3_vfs {
+            iVersion: 1,
+            szOsFile: std::mem::size_of::<SinkFile>() as c_int,
+            mxPathname: unsafe { (*default_vfs).mxPathname },
+            pNext: ptr::null_mut(),
+            zName: name.into_raw(),
+            pAppData: sink as *const PageSink as *mut c_void,
+            xOpen: Some(sink_open),
+            xDelete: Some(sink_delete),
+            xAccess: Some(sink_access),
+            xFullPathname: Some(sink_full_pathname),
+            xDlOpen: None,
+            xDlError: None,
+            xDlSym: None,
+            xDlClose: None,
+            // Only the default VFS's are used, but SQLite may call them
+            // through this one.
+            xRandomness: unsafe { (*default_vfs).xRandomness },
+            xSleep: unsafe { (*default_vfs).xSleep },
+            xCurrentTime: unsafe { (*default_vfs).xCurrentTime },
+            xGetLastError: None,
+            xCurrentTimeInt64: None,
+            xSetSystemCall: None,
+            xGetSystemCall: None,
+            xNextSystemCall: None,
+        }));
+        let rc = unsafe { sqlite3_vfs_register(vfs, 0) };
+        let sink_vfs = Self(vfs);
+        anyhow::ensure!(rc == SQLITE_OK, "sqlite3_vfs_register failed: {rc}");
+        Ok(sink_vfs)
+    }
+
+    fn name(&self) -> &str {
+        unsafe { CStr::from_ptr((*self.0).zName) }
+            .to_str()
+            .expect("sink VFS names are ASCII")
+    }
+}
+
+impl Drop for SinkVfs {
+    fn drop(&mut self) {
+        unsafe {
+            sqlite3_vfs_unregister(self.0);
+            let vfs = Box::from_raw(self.0);
+            drop(CString::from_raw(vfs.zName as *mut c_char));
+        }
+    }
+}
+
+unsafe fn sink_of<'a>(file: *mut sqlite3_file) -> &'a PageSink<'a> {
+    unsafe { &*(*(file as *mut SinkFile)).sink }
+}
+
+unsafe extern "C" fn sink_open(
+    vfs: *mut sqlite3_vfs,
+    _z_name: *const c_char,
+    file: *mut sqlite3_file,
+    flags: c_int,
+    p_out_flags: *mut c_int,
+) -> c_int {
+    // Without a journal, only the database itself is opened.
+    if flags & SQLITE_OPEN_MAIN_DB == 0 {
+        return SQLITE_CANTOPEN;
+    }
+    unsafe {
+        let sfile = file as *mut SinkFile;
+        (*sfile).sink = (*vfs).pAppData as *const PageSink;
+        (*sfile).base.pMethods = &SINK_IO_METHODS;
+        if !p_out_flags.is_null() {
+            *p_out_flags = flags;
+        }
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn sink_delete(
+    _vfs: *mut sqlite3_vfs,
+    _z_name: *const c_char,
+    _sync_dir: c_int,
+) -> c_int {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn sink_access(
+    _vfs: *mut sqlite3_vfs,
+    _z_name: *const c_char,
+    _flags: c_int,
+    p_res_out: *mut c_int,
+) -> c_int {
+    // The sink's own file aside, nothing exists.
+    unsafe { *p_res_out = 0 };
+    SQLITE_OK
+}
+
+unsafe extern "C" fn sink_full_pathname(
+    _vfs: *mut sqlite3_vfs,
+    z_name: *const c_char,
+    n_out: c_int,
+    z_out: *mut c_char,
+) -> c_int {
+    unsafe {
+        let name = CStr::from_ptr(z_name).to_bytes_with_nul();
+        if name.len() > n_out as usize {
+            return SQLITE_CANTOPEN;
+        }
+        ptr::copy_nonoverlapping(name.as_ptr() as *const c_char, z_out, name.len());
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn sink_close(_file: *mut sqlite3_file) -> c_int {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn sink_read(
+    file: *mut sqlite3_file,
+    buf: *mut c_void,
+    amt: c_int,
+    ofst: i64,
+) -> c_int {
+    unsafe {
+        let sink = sink_of(file);
+        let out = std::slice::from_raw_parts_mut(buf as *mut u8, amt as usize);
+        match sink.read(out, ofst as u64) {
+            Ok(true) => SQLITE_OK,
+            Ok(false) => SQLITE_IOERR_SHORT_READ,
+            Err(e) => sink.fail(e, SQLITE_IOERR_READ),
+        }
+    }
+}
+
+unsafe extern "C" fn sink_write(
+    file: *mut sqlite3_file,
+    buf: *const c_void,
+    amt: c_int,
+    ofst: i64,
+) -> c_int {
+    unsafe {
+        let sink = sink_of(file);
+        let data = std::slice::from_raw_parts(buf as *const u8, amt as usize);
+        match sink.write(data, ofst as u64) {
+            Ok(()) => SQLITE_OK,
+            Err(e) => sink.fail(e, SQLITE_IOERR_WRITE),
+        }
+    }
+}
+
+unsafe extern "C" fn sink_truncate(file: *mut sqlite3_file, size: i64) -> c_int {
+    unsafe {
+        let sink = sink_of(file);
+        match sink.truncate(size as u64) {
+            Ok(()) => SQLITE_OK,
+            Err(e) => sink.fail(e, SQLITE_IOERR_TRUNCATE),
+        }
+    }
+}
+
+unsafe extern "C" fn sink_sync(_file: *mut sqlite3_file, _flags: c_int) -> c_int {
+    // The copy is read back before the backup is written, and is gone
+    // after.
+    SQLITE_OK
+}
+
+unsafe extern "C" fn sink_file_size(file: *mut sqlite3_file, p_size: *mut i64) -> c_int {
+    unsafe {
+        let sink = sink_of(file);
+        match sink.file.metadata() {
+            Ok(metadata) => {
+                *p_size = metadata.len() as i64;
+                SQLITE_OK
+            }
+            Err(e) => sink.fail(e.into(), SQLITE_IOERR_FSTAT),
+        }
+    }
+}
+
+unsafe extern "C" fn sink_lock(_file: *mut sqlite3_file, _lock_type: c_int) -> c_int {
+    // No other connection can open the sink.
+    SQLITE_OK
+}
+
+unsafe extern "C" fn sink_check_reserved_lock(
+    _file: *mut sqlite3_file,
+    p_res_out: *mut c_int,
+) -> c_int {
+    unsafe { *p_res_out = 0 };
+    SQLITE_OK
+}
+
+unsafe extern "C" fn sink_file_control(
+    _file: *mut sqlite3_file,
+    _op: c_int,
+    _p_arg: *mut c_void,
+) -> c_int {
+    SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn sink_sector_size(_file: *mut sqlite3_file) -> c_int {
+    4096
+}
+
+unsafe extern "C" fn sink_device_characteristics(_file: *mut sqlite3_file) -> c_int {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use super::*;
+    use crate::{crypto::keys::KeyScope, keyring::Keyring, kms::local::DeviceKeyProvider};
+
+    fn test_provider(key: [u8; 32]) -> Arc<dyn KmsProvider> {
+        // Write a temp keyfile.
         let dir = std::env::temp_dir().join("evfs-test");
         std::fs::create_dir_all(&dir).unwrap();
         let path = dir.join(format!(
@@ -299,7 +2220,7 @@ mod tests {
     #[test]
     fn backup_round_trip() {
         let page_size: u32 = 4096;
-        let reserve: usize = 32;
+        let reserve: usize = 80;
         let page_count = 4;
 
         // Create a fake encrypted database.
@@ -317,6 +2238,7 @@ mod tests {
             crate::crypto::page::encrypt_page(
                 &mut db_bytes[offset..offset + page_size as usize],
                 page_no,
+                None,
                 &src_dek,
                 reserve,
             )
@@ -329,71 +2251,215 @@ mod tests {
         let db_path = dir.join("test.db");
         std::fs::write(&db_path, &db_bytes).unwrap();
 
-        // Create backup.
-        let backup_provider = test_provider([0xBB; 32]);
-        let mut backup_buf = Vec::new();
+        // Create backup.
+        let backup_provider = test_provider([0xBB; 32]);
+        let mut backup_buf = Vec::new();
+        create_backup(
+            &db_path,
+            &mut backup_buf,
+            &src_keyring,
+            backup_provider.as_ref(),
+            page_size,
+            reserve,
+        )
+        .unwrap();
+
+        // Verify backup.
+        let verify =
+            verify_backup(&mut Cursor::new(&backup_buf), backup_provider.as_ref()).unwrap();
+        assert!(verify.is_ok());
+        assert_eq!(verify.page_count, page_count as u32);
+
+        // Restore backup to a new DB with a different key.
+        let tgt_provider = test_provider([0xCC; 32]);
+        let tgt_keyring = Arc::new(Keyring::new(tgt_provider.clone()));
+        let restored_path = dir.join("restored.db");
+
+        restore_backup(
+            &mut Cursor::new(&backup_buf),
+            &restored_path,
+            backup_provider.as_ref(),
+            &tgt_keyring,
+        )
+        .unwrap();
+
+        // Verify restored DB decrypts correctly.
+        let restored_bytes = std::fs::read(&restored_path).unwrap();
+        let tgt_dek = tgt_keyring.dek_for(&KeyScope::Database).unwrap();
+        // The source predates IDs, so the restore minted one.
+        let database_id = keyring::database_id_of(&restored_path);
+        assert!(database_id.is_some());
+
+        for i in 0..page_count {
+            let offset = i * page_size as usize;
+            let mut page = restored_bytes[offset..offset + page_size as usize].to_vec();
+            let page_no = i as u32 + 1;
+            crate::crypto::page::decrypt_page(&mut page, page_no, database_id, &tgt_dek, reserve)
+                .unwrap();
+            let expected = (i as u8).wrapping_add(1);
+            assert!(
+                page[..page_size as usize - reserve]
+                    .iter()
+                    .all(|&b| b == expected),
+                "page {page_no} content mismatch after restore"
+            );
+        }
+
+        // Cleanup.
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn backup_algorithm_is_recorded() {
+        let page_size: u32 = 4096;
+        let reserve: usize = 80;
+
+        let src_keyring =
+            Keyring::new(test_provider([0x61; 32])).with_algorithm(Algorithm::XChaCha20Poly1305);
+        let src_dek = src_keyring.dek_for(&KeyScope::Database).unwrap();
+        let mut db_bytes = vec![0x62u8; 2 * page_size as usize];
+        for (i, page) in db_bytes.chunks_mut(page_size as usize).enumerate() {
+            page_crypto::encrypt_page(page, i as u32 + 1, None, &src_dek, reserve).unwrap();
+        }
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("test.db");
+        std::fs::write(&db_path, &db_bytes).unwrap();
+
+        // An AES-256-GCM backup of an XChaCha20-Poly1305 database.
+        let backup_provider = test_provider([0x63; 32]);
+        let mut backup = Vec::new();
+        create_backup_with(
+            &db_path,
+            &mut backup,
+            &src_keyring,
+            backup_provider.as_ref(),
+            page_size,
+            reserve,
+            &BackupOptions::default().algorithm(Algorithm::Aes256Gcm),
+        )
+        .unwrap();
+        let header: BackupHeader = bincode::decode_from_slice(&backup[12..], config::standard())
+            .unwrap()
+            .0;
+        assert_eq!(header.algorithm, Algorithm::Aes256Gcm);
+        let first_page = 12 + 2048 + HEADER_MAC_LEN;
+        assert_eq!(
+            page_crypto::page_algorithm(&backup[first_page..first_page + 4096], reserve).unwrap(),
+            Some(Algorithm::Aes256Gcm)
+        );
+        assert!(
+            verify_backup(&mut Cursor::new(&backup), backup_provider.as_ref())
+                .unwrap()
+                .is_ok()
+        );
+
+        // Restored pages take the target keyring's algorithm.
+        let tgt_keyring =
+            Keyring::new(test_provider([0x64; 32])).with_algorithm(Algorithm::XChaCha20Poly1305);
+        let restored_path = dir.path().join("restored.db");
+        restore_backup(
+            &mut Cursor::new(&backup),
+            &restored_path,
+            backup_provider.as_ref(),
+            &tgt_keyring,
+        )
+        .unwrap();
+        let restored = std::fs::read(&restored_path).unwrap();
+        assert_eq!(
+            page_crypto::page_algorithm(&restored[4096..], reserve).unwrap(),
+            Some(Algorithm::XChaCha20Poly1305)
+        );
+
+        // A header that disagrees with its sealed DEK is refused.
+        let mut forged = header;
+        forged.algorithm = Algorithm::XChaCha20Poly1305;
+        let mut header_bytes = vec![0u8; 2048];
+        bincode::encode_into_slice(&forged, &mut header_bytes, config::standard()).unwrap();
+        backup[12..12 + 2048].copy_from_slice(&header_bytes);
+        let err = verify_backup(&mut Cursor::new(&backup), backup_provider.as_ref()).unwrap_err();
+        assert!(err.to_string().contains("backup header records"), "{err}");
+    }
+
+    #[test]
+    fn backup_carries_database_id() {
+        let page_size: u32 = 4096;
+        let reserve: usize = 80;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("test.db");
+        let src_keyring = Keyring::new(test_provider([0x71; 32]));
+        let src_dek = src_keyring.dek_for(&KeyScope::Database).unwrap();
+        let database_id = DatabaseId::generate();
+        src_keyring
+            .install_database_id(&db_path, database_id)
+            .unwrap();
+        let mut db_bytes = vec![0x72u8; 2 * page_size as usize];
+        for (i, page) in db_bytes.chunks_mut(page_size as usize).enumerate() {
+            page_crypto::encrypt_page(page, i as u32 + 1, Some(database_id), &src_dek, reserve)
+                .unwrap();
+        }
+        std::fs::write(&db_path, &db_bytes).unwrap();
+
+        let backup_provider = test_provider([0x73; 32]);
+        let mut backup = Vec::new();
         create_backup(
             &db_path,
-            &mut backup_buf,
+            &mut backup,
             &src_keyring,
             backup_provider.as_ref(),
             page_size,
             reserve,
         )
         .unwrap();
-
-        // Verify backup.
-        let verify =
-            verify_backup(&mut Cursor::new(&backup_buf), backup_provider.as_ref()).unwrap();
-        assert!(verify.is_ok());
-        assert_eq!(verify.page_count, page_count as u32);
-
-        // Restore backup to a new DB with a different key.
-        let tgt_provider = test_provider([0xCC; 32]);
-        let tgt_keyring = Arc::new(Keyring::new(tgt_provider.clone()));
-        let restored_path = dir.join("restored.db");
-
+        let header: BackupHeader = bincode::decode_from_slice(&backup[12..], config::standard())
+            .unwrap()
+            .0;
+        assert_eq!(header.database_id, Some(database_id));
+
+        // The restored database keeps the source's ID, and opens with
+        // the target KEK alone.
+        let tgt_provider = test_provider([0x74; 32]);
+        let restored_path = dir.path().join("restored.db");
         restore_backup(
-            &mut Cursor::new(&backup_buf),
+            &mut Cursor::new(&backup),
             &restored_path,
             backup_provider.as_ref(),
-            &tgt_keyring,
+            &Keyring::new(tgt_provider.clone()),
         )
         .unwrap();
-
-        // Verify restored DB decrypts correctly.
-        let restored_bytes = std::fs::read(&restored_path).unwrap();
-        let tgt_dek = tgt_keyring.dek_for(&KeyScope::Database).unwrap();
-
-        for i in 0..page_count {
-            let offset = i * page_size as usize;
-            let mut page = restored_bytes[offset..offset + page_size as usize].to_vec();
+        assert_eq!(keyring::database_id_of(&restored_path), Some(database_id));
+        let reopened = Keyring::new(tgt_provider);
+        reopened.set_sidecar_path(&restored_path).unwrap();
+        let tgt_dek = reopened.dek_for(&KeyScope::Database).unwrap();
+        let restored = std::fs::read(&restored_path).unwrap();
+        for (i, page) in restored.chunks(page_size as usize).enumerate() {
+            let mut page = page.to_vec();
             let page_no = i as u32 + 1;
-            crate::crypto::page::decrypt_page(&mut page, page_no, &tgt_dek, reserve).unwrap();
-            let expected = (i as u8).wrapping_add(1);
+            let mut unbound = page.clone();
+            assert!(
+                page_crypto::decrypt_page(&mut unbound, page_no, None, &tgt_dek, reserve).is_err()
+            );
+            page_crypto::decrypt_page(&mut page, page_no, Some(database_id), &tgt_dek, reserve)
+                .unwrap();
             assert!(
                 page[..page_size as usize - reserve]
                     .iter()
-                    .all(|&b| b == expected),
-                "page {page_no} content mismatch after restore"
+                    .all(|&b| b == 0x72)
             );
         }
-
-        // Cleanup.
-        let _ = std::fs::remove_dir_all(&dir);
     }
 
     #[test]
     fn kek_rotation_preserves_data() {
         let page_size: u32 = 4096;
-        let reserve: usize = 32;
+        let reserve: usize = 80;
 
         let src_provider = test_provider([0x11; 32]);
         let src_keyring = Arc::new(Keyring::new(src_provider.clone()));
         let src_dek = src_keyring.dek_for(&KeyScope::Database).unwrap();
 
         let mut db_bytes = vec![0x42u8; page_size as usize];
-        crate::crypto::page::encrypt_page(&mut db_bytes, 1, &src_dek, reserve).unwrap();
+        crate::crypto::page::encrypt_page(&mut db_bytes, 1, None, &src_dek, reserve).unwrap();
 
         let dir = std::env::temp_dir().join("evfs-rotate-test");
         std::fs::create_dir_all(&dir).unwrap();
@@ -431,4 +2497,1057 @@ mod tests {
 
         let _ = std::fs::remove_dir_all(&dir);
     }
+
+    #[test]
+    fn interrupted_kek_rotation_keeps_the_backup() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let src_keyring = Keyring::new(test_provider([0x11; 32]));
+        let src_dek = src_keyring.dek_for(&KeyScope::Database).unwrap();
+        let mut db_bytes = Vec::new();
+        for page_no in 1..=3 {
+            let mut page = vec![page_no as u8; 4096];
+            page_crypto::encrypt_page(&mut page, page_no, None, &src_dek, 80).unwrap();
+            db_bytes.extend_from_slice(&page);
+        }
+        let db_path = dir.path().join("test.db");
+        std::fs::write(&db_path, &db_bytes).unwrap();
+
+        let old_kms = test_provider([0x22; 32]);
+        let new_kms = test_provider([0x33; 32]);
+        let backup_path = dir.path().join("test.evfs-backup");
+        let mut f = File::create(&backup_path).unwrap();
+        create_backup(&db_path, &mut f, &src_keyring, old_kms.as_ref(), 4096, 80).unwrap();
+        drop(f);
+        let original = std::fs::read(&backup_path).unwrap();
+
+        // Stopped between any two steps, the original is untouched.
+        for step in [RotateStep::Written, RotateStep::Verified] {
+            let err = rotate_kek(&backup_path, old_kms.as_ref(), new_kms.as_ref(), Some(step))
+                .unwrap_err();
+            assert!(err.to_string().contains("interrupted"));
+            assert_eq!(std::fs::read(&backup_path).unwrap(), original);
+            assert!(!partial_path(&backup_path).exists());
+            assert!(verify_backup(&mut Cursor::new(&original), old_kms.as_ref()).is_ok());
+        }
+
+        // A crash leaves a partial file behind, which the next rotation
+        // overwrites. Under the wrong KEK nothing is written.
+        std::fs::write(partial_path(&backup_path), b"torn").unwrap();
+        assert!(rotate_backup_kek(&backup_path, new_kms.as_ref(), old_kms.as_ref()).is_err());
+        assert_eq!(std::fs::read(&backup_path).unwrap(), original);
+        rotate_backup_kek(&backup_path, old_kms.as_ref(), new_kms.as_ref()).unwrap();
+        assert!(!partial_path(&backup_path).exists());
+
+        let rotated = std::fs::read(&backup_path).unwrap();
+        assert_eq!(rotated.len(), original.len());
+        let pages = 3 * 4096;
+        assert_eq!(
+            rotated[rotated.len() - pages..],
+            original[original.len() - pages..]
+        );
+        assert!(
+            verify_backup(&mut Cursor::new(&rotated), new_kms.as_ref())
+                .unwrap()
+                .is_ok()
+        );
+    }
+
+    /// A backup file with `version` in its header, authenticated under
+    /// `backup_dek` if the version is, and `pages` as-is.
+    fn backup_file(
+        version: u32,
+        backup_dek: &Dek,
+        wrapped_dek: WrappedDek,
+        reserve: usize,
+        pages: &[u8],
+    ) -> Vec<u8> {
+        let header = BackupHeader {
+            version,
+            page_size: 4096,
+            page_count: (pages.len() / 4096) as u32,
+            reserve_size: reserve as u32,
+            wrapped_dek,
+            algorithm: Algorithm::Aes256Gcm,
+            database_id: None,
+            backup_id: None,
+            base: None,
+            digest_root: None,
+            scopes: Vec::new(),
+            created_at: None,
+            source: None,
+        };
+        let mut out = Vec::new();
+        write_header(&mut out, &header, backup_dek).unwrap();
+        out.extend_from_slice(pages);
+        out
+    }
+
+    /// `backup` with its header decoded, changed by `f` and encoded
+    /// again in place, leaving the MAC after it as it was.
+    fn with_header(backup: &[u8], f: impl FnOnce(&mut BackupHeader)) -> Vec<u8> {
+        let (mut header, _): (BackupHeader, _) =
+            bincode::decode_from_slice(&backup[12..], config::standard()).unwrap();
+        f(&mut header);
+        let mut out = backup.to_vec();
+        out[12..12 + 2048].fill(0);
+        bincode::encode_into_slice(&header, &mut out[12..12 + 2048], config::standard()).unwrap();
+        out
+    }
+
+    /// Nonces that repeat from run to run, so two runs can be compared
+    /// byte for byte.
+    fn counting_nonces() -> impl FnMut() -> [u8; MAX_NONCE_LEN] {
+        let mut n = 0u64;
+        move || {
+            n += 1;
+            let mut nonce = [0u8; MAX_NONCE_LEN];
+            nonce[..8].copy_from_slice(&n.to_le_bytes());
+            nonce
+        }
+    }
+
+    #[test]
+    fn parallel_pages_match_sequential() {
+        let reserve: usize = 80;
+        // Not a multiple of any thread count's in-flight window.
+        let page_count: u32 = 257;
+        let source_keyring = Keyring::new(test_provider([0x76; 32]));
+        let source_dek = source_keyring.dek_for(&KeyScope::Database).unwrap();
+        let source = SourceKeys::new(&source_keyring, None).unwrap();
+        let mut raw = vec![0u8; page_count as usize * 4096];
+        for (i, page) in raw.chunks_mut(4096).enumerate() {
+            page[..4096 - reserve].fill(i as u8);
+            page_crypto::encrypt_page(page, i as u32 + 1, None, &source_dek, reserve).unwrap();
+        }
+
+        let backup_provider = test_provider([0x77; 32]);
+        let backup_dek = Dek::generate();
+        let target_dek = Dek::generate();
+        let wrapped = envelope::wrap_dek(&backup_dek, backup_provider.as_ref()).unwrap();
+        let header = BackupHeader {
+            version: BACKUP_VERSION,
+            page_size: 4096,
+            page_count,
+            reserve_size: reserve as u32,
+            wrapped_dek: wrapped.clone(),
+            algorithm: Algorithm::Aes256Gcm,
+            database_id: None,
+            backup_id: None,
+            base: None,
+            digest_root: None,
+            scopes: Vec::new(),
+            created_at: None,
+            source: None,
+        };
+        let database_id = DatabaseId::generate();
+
+        let backup = |threads| {
+            let mut out = Vec::new();
+            let mut nonces = counting_nonces();
+            backup_pages(
+                &raw,
+                &header,
+                &source,
+                &backup_dek,
+                &mut out,
+                threads,
+                &mut nonces,
+                &mut Progress::new(&BackupOptions::default(), page_count),
+            )
+            .unwrap();
+            out
+        };
+        let pages = backup(1);
+        for threads in [2, 4, 16] {
+            assert!(
+                backup(threads) == pages,
+                "backup on {threads} threads differs"
+            );
+        }
+
+        let restore = |threads| {
+            let mut nonces = counting_nonces();
+            let mut source = Cursor::new(&pages);
+            let output = tempfile::tempfile().unwrap();
+            restore_pages(
+                &mut source,
+                &header,
+                &backup_dek,
+                &|_| Ok(target_dek.clone()),
+                database_id,
+                threads,
+                &mut nonces,
+                &output,
+                &mut Progress::new(&BackupOptions::default(), page_count),
+            )
+            .unwrap();
+            let mut restored = vec![0u8; page_count as usize * 4096];
+            output.read_exact_at(&mut restored, 0).unwrap();
+            restored
+        };
+        let restored = restore(1);
+        for threads in [2, 4, 16] {
+            assert!(
+                restore(threads) == restored,
+                "restore on {threads} threads differs"
+            );
+        }
+        for (i, page) in restored.chunks(4096).enumerate() {
+            let mut page = page.to_vec();
+            page_crypto::decrypt_page(
+                &mut page,
+                i as u32 + 1,
+                Some(database_id),
+                &target_dek,
+                reserve,
+            )
+            .unwrap();
+            assert!(page[..4096 - reserve].iter().all(|&b| b == i as u8));
+        }
+
+        let mut corrupted = pages.clone();
+        corrupted[100 * 4096 + 7] ^= 0x01;
+        let backup = backup_file(BACKUP_VERSION, &backup_dek, wrapped, reserve, &corrupted);
+        for threads in [1, 4] {
+            let options = BackupOptions::default().threads(threads);
+            let mut source = Cursor::new(&backup);
+            let verify =
+                verify_backup_with(&mut source, backup_provider.as_ref(), &options).unwrap();
+            assert_eq!((verify.pages_ok, verify.pages_bad), (page_count - 1, 1));
+        }
+    }
+
+    #[test]
+    fn legacy_backup_restores_in_current_format() {
+        let reserve: usize = 80;
+        let backup_provider = test_provider([0x44; 32]);
+        let backup_dek = Dek::generate();
+
+        // Version 1 backups hold legacy-format pages.
+        let mut pages = vec![0x5Au8; 2 * 4096];
+        for (i, page) in pages.chunks_mut(4096).enumerate() {
+            page_crypto::encrypt_legacy_page(page, i as u32 + 1, &backup_dek, reserve);
+        }
+        let wrapped = envelope::wrap_dek(&backup_dek, backup_provider.as_ref()).unwrap();
+        let backup = backup_file(LEGACY_BACKUP_VERSION, &backup_dek, wrapped, reserve, &pages);
+
+        let verify = verify_backup(&mut Cursor::new(&backup), backup_provider.as_ref()).unwrap();
+        assert!(verify.is_ok());
+
+        let dir = std::env::temp_dir().join("evfs-legacy-backup-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let restored_path = dir.join("restored.db");
+        let tgt_keyring = Keyring::new(test_provider([0x55; 32]));
+        restore_backup(
+            &mut Cursor::new(&backup),
+            &restored_path,
+            backup_provider.as_ref(),
+            &tgt_keyring,
+        )
+        .unwrap();
+
+        let restored = std::fs::read(&restored_path).unwrap();
+        let tgt_dek = tgt_keyring.dek_for(&KeyScope::Database).unwrap();
+        let database_id = keyring::database_id_of(&restored_path);
+        for (i, page) in restored.chunks(4096).enumerate() {
+            assert_eq!(
+                page_crypto::page_format(page, reserve).unwrap(),
+                Some(page_crypto::PAGE_FORMAT)
+            );
+            let mut page = page.to_vec();
+            page_crypto::decrypt_page(&mut page, i as u32 + 1, database_id, &tgt_dek, reserve)
+                .unwrap();
+            assert!(page[..4096 - reserve].iter().all(|&b| b == 0x5A));
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unknown_backup_version_is_rejected() {
+        let backup_provider = test_provider([0x66; 32]);
+        let dek = Dek::generate();
+        let wrapped = envelope::wrap_dek(&dek, backup_provider.as_ref()).unwrap();
+        let backup = backup_file(BACKUP_VERSION + 1, &dek, wrapped, 80, &[]);
+
+        let err = verify_backup(&mut Cursor::new(&backup), backup_provider.as_ref()).unwrap_err();
+        assert!(err.to_string().contains("unsupported backup version"));
+    }
+
+    #[test]
+    fn flipped_header_bytes_are_rejected() {
+        let backup_provider = test_provider([0x88; 32]);
+        let dek = Dek::generate();
+        let wrapped = envelope::wrap_dek(&dek, backup_provider.as_ref()).unwrap();
+        let backup = backup_file(BACKUP_VERSION, &dek, wrapped, 80, &[]);
+        assert_eq!(backup.len(), 12 + 2048 + HEADER_MAC_LEN);
+        assert!(
+            verify_backup(&mut Cursor::new(&backup), backup_provider.as_ref())
+                .unwrap()
+                .is_ok()
+        );
+
+        // The magic, header length, header with its padding and MAC.
+        for i in 0..backup.len() {
+            let mut tampered = backup.clone();
+            tampered[i] ^= 0x01;
+            assert!(
+                verify_backup(&mut Cursor::new(&tampered), backup_provider.as_ref()).is_err(),
+                "flipped byte {i} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn tampered_header_fields_are_rejected() {
+        let page_size: u32 = 4096;
+        let reserve: usize = 80;
+
+        let src_provider = test_provider([0x99; 32]);
+        let src_keyring = Arc::new(Keyring::new(src_provider.clone()));
+        let src_dek = src_keyring.dek_for(&KeyScope::Database).unwrap();
+        let mut db_bytes = vec![0x42u8; 2 * page_size as usize];
+        for (i, page) in db_bytes.chunks_mut(page_size as usize).enumerate() {
+            page_crypto::encrypt_page(page, i as u32 + 1, None, &src_dek, reserve).unwrap();
+        }
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("test.db");
+        std::fs::write(&db_path, &db_bytes).unwrap();
+
+        let backup_provider = test_provider([0xAB; 32]);
+        let new_provider = test_provider([0xAC; 32]);
+        let backup = || {
+            let mut out = Vec::new();
+            create_backup(
+                &db_path,
+                &mut out,
+                &src_keyring,
+                backup_provider.as_ref(),
+                page_size,
+                reserve,
+            )
+            .unwrap();
+            out
+        };
+        let original = backup();
+        // Another backup's wrapped DEK, valid under the same KEK.
+        let (other, _): (BackupHeader, _) =
+            bincode::decode_from_slice(&backup()[12..], config::standard()).unwrap();
+
+        let tampered = [
+            with_header(&original, |h| h.reserve_size = 48),
+            with_header(&original, |h| h.page_size = 8192),
+            with_header(&original, |h| h.page_count = 1),
+            with_header(&original, |h| h.database_id = Some(DatabaseId::generate())),
+            with_header(&original, |h| h.wrapped_dek = other.wrapped_dek.clone()),
+        ];
+        for backup in tampered {
+            let err =
+                verify_backup(&mut Cursor::new(&backup), backup_provider.as_ref()).unwrap_err();
+            assert!(err.to_string().contains("failed authentication"), "{err}");
+
+            let restored_path = dir.path().join("restored.db");
+            let tgt_keyring = Keyring::new(test_provider([0xAD; 32]));
+            let err = restore_backup(
+                &mut Cursor::new(&backup),
+                &restored_path,
+                backup_provider.as_ref(),
+                &tgt_keyring,
+            )
+            .unwrap_err();
+            assert!(err.to_string().contains("failed authentication"), "{err}");
+            assert!(!restored_path.exists());
+
+            let backup_path = dir.path().join("test.evfs-backup");
+            std::fs::write(&backup_path, &backup).unwrap();
+            let err = rotate_backup_kek(
+                &backup_path,
+                backup_provider.as_ref(),
+                new_provider.as_ref(),
+            )
+            .unwrap_err();
+            assert!(err.to_string().contains("failed authentication"), "{err}");
+            assert_eq!(std::fs::read(&backup_path).unwrap(), backup);
+        }
+
+        // Rotation authenticates the new header.
+        let backup_path = dir.path().join("test.evfs-backup");
+        std::fs::write(&backup_path, &original).unwrap();
+        rotate_backup_kek(
+            &backup_path,
+            backup_provider.as_ref(),
+            new_provider.as_ref(),
+        )
+        .unwrap();
+        let rotated = std::fs::read(&backup_path).unwrap();
+        assert!(
+            verify_backup(&mut Cursor::new(&rotated), new_provider.as_ref())
+                .unwrap()
+                .is_ok()
+        );
+        let tampered = with_header(&rotated, |h| h.reserve_size = 48);
+        assert!(verify_backup(&mut Cursor::new(&tampered), new_provider.as_ref()).is_err());
+    }
+
+    /// Open a restored database through `vfs`. Its keyring is bound to
+    /// the source, so the copy takes a keyring of its own.
+    fn open_restored(path: &Path, keyfile: &Path, vfs: &str) -> rusqlite::Connection {
+        rusqlite::Connection::open_with_flags_and_vfs(
+            format!("file:{}?evfs_keyfile={}", path.display(), keyfile.display()),
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_URI,
+            vfs,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn live_backup_while_writing() {
+        use std::sync::atomic::AtomicBool;
+
+        use rusqlite::{Connection, OpenFlags};
+
+        use crate::{EvfsBuilder, Mode};
+
+        const VFS: &str = "backup-live";
+        const ROWS: i64 = 2000;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let keyfile = dir.path().join("live.key");
+        std::fs::write(&keyfile, [0x71; 32]).unwrap();
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name(VFS)
+        .register()
+        .unwrap();
+        let open = |path: &Path| {
+            let conn = Connection::open_with_flags_and_vfs(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                VFS,
+            )
+            .unwrap();
+            conn.busy_timeout(std::time::Duration::from_secs(5))
+                .unwrap();
+            conn
+        };
+
+        let db_path = dir.path().join("live.db");
+        let conn = open(&db_path);
+        conn.execute_batch(
+            "PRAGMA journal_mode=WAL;
+             CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB);",
+        )
+        .unwrap();
+        conn.execute(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?1)
+             INSERT INTO t (body) SELECT randomblob(500) FROM n",
+            [ROWS],
+        )
+        .unwrap();
+
+        let backup_provider = test_provider([0x72; 32]);
+        let writing = AtomicBool::new(true);
+        let backups = std::thread::scope(|scope| {
+            // Another connection inserts rows, committing each, for as
+            // long as backups are taken.
+            let writer = scope.spawn(|| {
+                let conn = open(&db_path);
+                let mut inserted = 0;
+                while writing.load(Ordering::Relaxed) {
+                    conn.execute("INSERT INTO t (body) VALUES (randomblob(500))", [])
+                        .unwrap();
+                    inserted += 1;
+                }
+                inserted
+            });
+            let backups: Vec<_> = (0..3)
+                .map(|_| {
+                    // Copied a few pages per step, so writes land
+                    // between steps.
+                    let last = Arc::new(Mutex::new(None));
+                    let options = BackupOptions::default().progress_every(16).progress({
+                        let last = last.clone();
+                        move |progress| {
+                            *last.lock() = Some(progress);
+                            ControlFlow::Continue(())
+                        }
+                    });
+                    let mut backup = Vec::new();
+                    let manifest = create_backup_live_with(
+                        &db_path,
+                        VFS,
+                        &mut backup,
+                        backup_provider.as_ref(),
+                        &options,
+                    )
+                    .unwrap();
+                    let last = last.lock().unwrap();
+                    assert_eq!(last.pages_done, last.pages_total);
+                    assert_eq!(last.bytes_written, backup.len() as u64);
+                    (backup, manifest)
+                })
+                .collect();
+            writing.store(false, Ordering::Relaxed);
+            assert!(writer.join().unwrap() > 0);
+            backups
+        });
+
+        for (i, (backup, manifest)) in backups.iter().enumerate() {
+            let verify = verify_backup(&mut Cursor::new(backup), backup_provider.as_ref()).unwrap();
+            assert!(verify.is_ok(), "{verify:?}");
+            assert_eq!(manifest.digests.len(), verify.page_count as usize);
+
+            // Each backup restores to a consistent database holding at
+            // least the rows committed before it started.
+            let restored_path = dir.path().join(format!("restored-{i}.db"));
+            let tgt_keyring =
+                Keyring::new(Arc::new(DeviceKeyProvider::from_keyfile(keyfile.clone())));
+            restore_backup(
+                &mut Cursor::new(backup),
+                &restored_path,
+                backup_provider.as_ref(),
+                &tgt_keyring,
+            )
+            .unwrap();
+            let restored = open_restored(&restored_path, &keyfile, VFS);
+            let check: String = restored
+                .query_row("PRAGMA integrity_check", [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(check, "ok");
+            let rows: i64 = restored
+                .query_row("SELECT count(*) FROM t", [], |r| r.get(0))
+                .unwrap();
+            assert!(rows >= ROWS, "{rows} rows");
+        }
+    }
+
+    #[test]
+    fn incremental_chain_restores_each_generation() {
+        use rusqlite::{Connection, OpenFlags};
+
+        use crate::{EvfsBuilder, Mode};
+
+        const VFS: &str = "backup-incremental";
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let keyfile = dir.path().join("incremental.key");
+        std::fs::write(&keyfile, [0x81; 32]).unwrap();
+        let keyring = EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name(VFS)
+        .register()
+        .unwrap();
+        let db_path = dir.path().join("incremental.db");
+        let conn = Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            VFS,
+        )
+        .unwrap();
+        let keyring = keyring.for_database(&db_path).unwrap();
+        let rows = |conn: &Connection| -> Vec<(i64, Vec<u8>)> {
+            let mut stmt = conn.prepare("SELECT id, body FROM t ORDER BY id").unwrap();
+            stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .map(Result::unwrap)
+                .collect()
+        };
+        let backup_provider = test_provider([0x82; 32]);
+
+        // A full backup, then three generations of changes: rows
+        // rewritten in place, the database shrunk, then grown past its
+        // original size.
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+             INSERT INTO t SELECT i, randomblob(300) FROM n;",
+        )
+        .unwrap();
+        let mut backups = vec![Vec::new()];
+        let mut manifest = create_backup(
+            &db_path,
+            &mut backups[0],
+            &keyring,
+            backup_provider.as_ref(),
+            4096,
+            80,
+        )
+        .unwrap();
+        let mut generations = vec![rows(&conn)];
+        let mut manifests = vec![manifest.clone()];
+        for change in [
+            "UPDATE t SET body = randomblob(300) WHERE id % 100 = 0",
+            "DELETE FROM t WHERE id > 500; VACUUM",
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1500)
+             INSERT INTO t (body) SELECT randomblob(300) FROM n",
+        ] {
+            conn.execute_batch(change).unwrap();
+            // Manifests are stored alongside their backups.
+            let mut stored = Vec::new();
+            manifest.write(&mut stored).unwrap();
+            manifest = BackupManifest::read(&mut stored.as_slice()).unwrap();
+
+            let mut backup = Vec::new();
+            manifest = create_incremental_backup(
+                &db_path,
+                &mut backup,
+                &keyring,
+                backup_provider.as_ref(),
+                &manifest,
+            )
+            .unwrap();
+            assert!(
+                verify_backup(&mut Cursor::new(&backup), backup_provider.as_ref())
+                    .unwrap()
+                    .is_ok()
+            );
+            backups.push(backup);
+            generations.push(rows(&conn));
+            manifests.push(manifest.clone());
+        }
+        assert_eq!(
+            manifest.digests.len() as u64 * 4096,
+            std::fs::metadata(&db_path).unwrap().len()
+        );
+        // Only the rewritten rows' pages are in the first incremental.
+        assert!(backups[1].len() < backups[0].len() / 4);
+
+        // Nothing changed, so nothing is backed up.
+        let mut unchanged = Vec::new();
+        let same = create_incremental_backup(
+            &db_path,
+            &mut unchanged,
+            &keyring,
+            backup_provider.as_ref(),
+            &manifest,
+        )
+        .unwrap();
+        assert_eq!(same.digests, manifest.digests);
+        let header: BackupHeader = bincode::decode_from_slice(&unchanged[12..], config::standard())
+            .unwrap()
+            .0;
+        assert_eq!(header.base.unwrap().changed_pages, 0);
+
+        // Each prefix of the chain restores its generation.
+        for (i, expected) in generations.iter().enumerate() {
+            let restored_path = dir.path().join(format!("restored-{i}.db"));
+            let tgt_keyring =
+                Keyring::new(Arc::new(DeviceKeyProvider::from_keyfile(keyfile.clone())));
+            let (full, incrementals) = backups[..=i].split_first().unwrap();
+            let mut incrementals: Vec<_> = incrementals.iter().map(Cursor::new).collect();
+            let mut incrementals: Vec<&mut dyn Read> = incrementals
+                .iter_mut()
+                .map(|c| c as &mut dyn Read)
+                .collect();
+            restore_backup_chain(
+                &mut Cursor::new(full),
+                &mut incrementals,
+                &restored_path,
+                backup_provider.as_ref(),
+                &tgt_keyring,
+            )
+            .unwrap();
+            let verify = verify_restored(&restored_path, &manifests[i], &tgt_keyring).unwrap();
+            assert!(verify.is_ok(), "generation {i}: {verify:?}");
+            let restored = open_restored(&restored_path, &keyfile, VFS);
+            let check: String = restored
+                .query_row("PRAGMA integrity_check", [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(check, "ok", "generation {i}");
+            assert!(rows(&restored) == *expected, "generation {i} differs");
+        }
+
+        // Links of the chain can't be skipped, nor an incremental
+        // backup restored alone.
+        let tgt_keyring = Keyring::new(test_provider([0x83; 32]));
+        let err = restore_backup_chain(
+            &mut Cursor::new(&backups[0]),
+            &mut [&mut Cursor::new(&backups[2])],
+            &dir.path().join("skipped.db"),
+            backup_provider.as_ref(),
+            &tgt_keyring,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("not the one before it"), "{err}");
+        let err = restore_backup(
+            &mut Cursor::new(&backups[1]),
+            &dir.path().join("alone.db"),
+            backup_provider.as_ref(),
+            &tgt_keyring,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("is incremental"), "{err}");
+    }
+
+    #[test]
+    fn table_scopes_survive_restore() {
+        use rusqlite::{Connection, OpenFlags};
+
+        use crate::{EvfsBuilder, Mode};
+
+        const VFS: &str = "backup-scopes";
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let keyfile = dir.path().join("scopes.key");
+        std::fs::write(&keyfile, [0xa1; 32]).unwrap();
+        let keyring = EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name(VFS)
+        .table_scope("alpha")
+        .table_scope("beta")
+        .register()
+        .unwrap();
+        let db_path = dir.path().join("scopes.db");
+        let rows = |conn: &Connection| -> Vec<(String, i64, String)> {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT 'alpha', * FROM alpha UNION ALL SELECT 'beta', * FROM beta
+                     UNION ALL SELECT 'gamma', * FROM gamma",
+                )
+                .unwrap();
+            stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .unwrap()
+                .map(Result::unwrap)
+                .collect()
+        };
+        let expected = {
+            let conn = Connection::open_with_flags_and_vfs(
+                &db_path,
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                VFS,
+            )
+            .unwrap();
+            conn.execute_batch(
+                "CREATE TABLE alpha (id INTEGER PRIMARY KEY, body TEXT);
+                 CREATE TABLE beta (id INTEGER PRIMARY KEY, body TEXT);
+                 CREATE INDEX beta_body ON beta (body);
+                 CREATE TABLE gamma (id INTEGER PRIMARY KEY, body TEXT);",
+            )
+            .unwrap();
+            for table in ["alpha", "beta", "gamma"] {
+                conn.execute_batch(&format!(
+                    "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 300)
+                     INSERT INTO {table} SELECT i, '{table} ' || hex(randomblob(100)) FROM n;"
+                ))
+                .unwrap();
+            }
+            rows(&conn)
+        };
+
+        // The scope of the DEK each page after the first is under.
+        let scopes_of = |path: &Path| -> Vec<String> {
+            let sidecar = keyring::load_sidecar(&keyring::sidecar_path_for(path)).unwrap();
+            let provider = DeviceKeyProvider::from_keyfile(keyfile.clone());
+            let deks: Vec<_> = sidecar
+                .keys
+                .iter()
+                .map(|(scope, wrapped)| (scope, envelope::unwrap_dek(wrapped, &provider).unwrap()))
+                .collect();
+            let raw = std::fs::read(path).unwrap();
+            (2..=(raw.len() / 4096) as u32)
+                .map(|page_no| {
+                    let opened: Vec<_> = deks
+                        .iter()
+                        .filter(|(_, dek)| {
+                            let mut page = raw_page(&raw, page_no, 4096);
+                            page_crypto::decrypt_page(
+                                &mut page,
+                                page_no,
+                                sidecar.database_id,
+                                dek,
+                                80,
+                            )
+                            .is_ok()
+                        })
+                        .map(|(scope, _)| scope.as_str())
+                        .collect();
+                    assert_eq!(opened.len(), 1, "page {page_no} opens under {opened:?}");
+                    opened[0].to_string()
+                })
+                .collect()
+        };
+        let source_scopes = scopes_of(&db_path);
+        for scope in ["table:alpha", "table:beta", "database"] {
+            assert!(source_scopes.iter().any(|s| s == scope), "no {scope} pages");
+        }
+
+        let backup_provider = test_provider([0xa2; 32]);
+        let keyring = keyring.for_database(&db_path).unwrap();
+        let mut backup = Vec::new();
+        create_backup(
+            &db_path,
+            &mut backup,
+            &keyring,
+            backup_provider.as_ref(),
+            4096,
+            80,
+        )
+        .unwrap();
+        let header: BackupHeader = bincode::decode_from_slice(&backup[12..], config::standard())
+            .unwrap()
+            .0;
+        let scoped: Vec<_> = header.scopes.iter().map(|s| s.scope.to_string()).collect();
+        assert_eq!(scoped, ["table:alpha", "table:beta"]);
+
+        let restored_path = dir.path().join("restored.db");
+        let tgt_keyring = Keyring::new(Arc::new(DeviceKeyProvider::from_keyfile(keyfile.clone())));
+        restore_backup(
+            &mut Cursor::new(&backup),
+            &restored_path,
+            backup_provider.as_ref(),
+            &tgt_keyring,
+        )
+        .unwrap();
+        assert_eq!(scopes_of(&restored_path), source_scopes);
+        let restored = open_restored(&restored_path, &keyfile, VFS);
+        assert!(rows(&restored) == expected);
+    }
+
+    #[test]
+    fn digests_catch_plaintext_changed_before_encryption() {
+        let reserve: usize = 80;
+        let page_count: u32 = 8;
+        let source_keyring = Keyring::new(test_provider([0x90; 32]));
+        let source_dek = source_keyring.dek_for(&KeyScope::Database).unwrap();
+        let mut raw = vec![0u8; page_count as usize * 4096];
+        for (i, page) in raw.chunks_mut(4096).enumerate() {
+            page[..4096 - reserve].fill(i as u8);
+            page_crypto::encrypt_page(page, i as u32 + 1, None, &source_dek, reserve).unwrap();
+        }
+        let source = SourceKeys::new(&source_keyring, None).unwrap();
+        let options = BackupOptions::default();
+        let digests = source_digests(
+            &raw,
+            4096,
+            reserve,
+            &source,
+            1,
+            &mut Progress::new(&options, page_count),
+        )
+        .unwrap();
+
+        // A byte of page 5 flips between the digests being taken and
+        // the page being encrypted for the backup, so its tag is good.
+        let offset = rekey::page_offset(5, 4096) as usize;
+        let page = &mut raw[offset..offset + 4096];
+        page_crypto::decrypt_page(page, 5, None, &source_dek, reserve).unwrap();
+        page[1000] ^= 0x01;
+        page_crypto::encrypt_page(page, 5, None, &source_dek, reserve).unwrap();
+
+        let backup_provider = test_provider([0x91; 32]);
+        let backup_dek = Dek::generate();
+        let header = BackupHeader {
+            version: BACKUP_VERSION,
+            page_size: 4096,
+            page_count,
+            reserve_size: reserve as u32,
+            wrapped_dek: envelope::wrap_dek(&backup_dek, backup_provider.as_ref()).unwrap(),
+            algorithm: Algorithm::Aes256Gcm,
+            database_id: None,
+            backup_id: Some(BackupId::generate()),
+            base: None,
+            digest_root: Some(DigestRoot::of((1..).zip(&digests))),
+            scopes: Vec::new(),
+            created_at: None,
+            source: None,
+        };
+        let mut backup = Vec::new();
+        write_header(&mut backup, &header, &backup_dek).unwrap();
+        backup_pages(
+            &raw,
+            &header,
+            &source,
+            &backup_dek,
+            &mut backup,
+            1,
+            &mut page_crypto::rand_nonce,
+            &mut Progress::new(&options, page_count),
+        )
+        .unwrap();
+
+        let verify = verify_backup(&mut Cursor::new(&backup), backup_provider.as_ref()).unwrap();
+        assert_eq!((verify.pages_ok, verify.pages_bad), (page_count, 0));
+        assert_eq!(verify.digests_ok, Some(false));
+        assert!(!verify.is_ok());
+
+        let manifest = manifest_of(&header, digests);
+        let verify = verify_backup_against(
+            &mut Cursor::new(&backup),
+            backup_provider.as_ref(),
+            &manifest,
+        )
+        .unwrap();
+        assert_eq!(verify.pages_mismatched, 1);
+        assert!(!verify.is_ok());
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let target_path = dir.path().join("restored.db");
+        let target_keyring = Keyring::new(test_provider([0x92; 32]));
+        let err = restore_backup(
+            &mut Cursor::new(&backup),
+            &target_path,
+            backup_provider.as_ref(),
+            &target_keyring,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("digest root"), "{err}");
+        assert!(!target_path.exists());
+        assert!(!partial_path(&target_path).exists());
+    }
+
+    #[test]
+    fn progress_is_reported_and_cancels() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("source.db");
+        let source_keyring = Keyring::new(test_provider([0x96; 32]));
+        let source_dek = source_keyring.dek_for(&KeyScope::Database).unwrap();
+        let reserve: usize = 80;
+        let page_count: u32 = 40;
+        let mut raw = vec![0u8; page_count as usize * 4096];
+        for (i, page) in raw.chunks_mut(4096).enumerate() {
+            page[..4096 - reserve].fill(i as u8 + 1);
+            page_crypto::encrypt_page(page, i as u32 + 1, None, &source_dek, reserve).unwrap();
+        }
+        std::fs::write(&db_path, &raw).unwrap();
+        let backup_provider = test_provider([0x97; 32]);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let options = BackupOptions::default()
+            .threads(4)
+            .progress_every(8)
+            .progress({
+                let seen = seen.clone();
+                move |progress| {
+                    seen.lock().push(progress);
+                    ControlFlow::Continue(())
+                }
+            });
+        let mut backup = Vec::new();
+        create_backup_with(
+            &db_path,
+            &mut backup,
+            &source_keyring,
+            backup_provider.as_ref(),
+            4096,
+            reserve,
+            &options,
+        )
+        .unwrap();
+        // Both passes over the pages are counted, every 8 pages.
+        let seen = std::mem::take(&mut *seen.lock());
+        let done: Vec<u32> = seen.iter().map(|p| p.pages_done).collect();
+        assert_eq!(done, (1..=10).map(|i| i * 8).collect::<Vec<_>>());
+        assert!(seen.iter().all(|p| p.pages_total == 2 * page_count));
+        assert_eq!(seen.last().unwrap().bytes_written, backup.len() as u64);
+
+        // Stopping halfway fails each operation with `Cancelled`.
+        let options = BackupOptions::default()
+            .threads(4)
+            .progress_every(8)
+            .progress(|progress| match progress.pages_done {
+                ..16 => ControlFlow::Continue(()),
+                _ => ControlFlow::Break(()),
+            });
+        let err = create_backup_with(
+            &db_path,
+            &mut Vec::new(),
+            &source_keyring,
+            backup_provider.as_ref(),
+            4096,
+            reserve,
+            &options,
+        )
+        .unwrap_err();
+        assert!(err.is::<Cancelled>(), "{err}");
+        let err = verify_backup_with(
+            &mut Cursor::new(&backup),
+            backup_provider.as_ref(),
+            &options,
+        )
+        .unwrap_err();
+        assert!(err.is::<Cancelled>(), "{err}");
+
+        let target_path = dir.path().join("restored.db");
+        let target_keyring = Keyring::new(test_provider([0x98; 32]));
+        let err = restore_backup_with(
+            &mut Cursor::new(&backup),
+            &target_path,
+            backup_provider.as_ref(),
+            &target_keyring,
+            &options,
+        )
+        .unwrap_err();
+        assert!(err.is::<Cancelled>(), "{err}");
+        assert!(!target_path.exists());
+        assert!(!partial_path(&target_path).exists());
+    }
+
+    #[test]
+    fn verify_restored_catches_changed_pages() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("source.db");
+        let source_keyring = Keyring::new(test_provider([0x93; 32]));
+        let source_dek = source_keyring.dek_for(&KeyScope::Database).unwrap();
+        let reserve: usize = 80;
+        let mut raw = vec![0u8; 6 * 4096];
+        for (i, page) in raw.chunks_mut(4096).enumerate() {
+            page[..4096 - reserve].fill(i as u8 + 1);
+            page_crypto::encrypt_page(page, i as u32 + 1, None, &source_dek, reserve).unwrap();
+        }
+        std::fs::write(&db_path, &raw).unwrap();
+
+        let backup_provider = test_provider([0x94; 32]);
+        let mut backup = Vec::new();
+        let manifest = create_backup(
+            &db_path,
+            &mut backup,
+            &source_keyring,
+            backup_provider.as_ref(),
+            4096,
+            reserve,
+        )
+        .unwrap();
+        let target_path = dir.path().join("restored.db");
+        let target_provider = test_provider([0x95; 32]);
+        let target_keyring = Keyring::new(target_provider.clone());
+        restore_backup(
+            &mut Cursor::new(&backup),
+            &target_path,
+            backup_provider.as_ref(),
+            &target_keyring,
+        )
+        .unwrap();
+        let verify = verify_restored(&target_path, &manifest, &target_keyring).unwrap();
+        assert!(verify.is_ok(), "{verify:?}");
+        assert_eq!(verify.pages_ok, 6);
+
+        // Page 3 re-encrypted under the target DEK with a byte changed
+        // passes its tag but not its digest.
+        let target_deks = keyring::sidecar_deks(&target_path, target_provider.as_ref()).unwrap();
+        let database_id = keyring::database_id_of(&target_path);
+        let mut restored = std::fs::read(&target_path).unwrap();
+        let offset = rekey::page_offset(3, 4096) as usize;
+        let page = &mut restored[offset..offset + 4096];
+        page_crypto::decrypt_page(page, 3, database_id, &target_deks[0], reserve).unwrap();
+        page[10] ^= 0x01;
+        page_crypto::encrypt_page(page, 3, database_id, &target_deks[0], reserve).unwrap();
+        std::fs::write(&target_path, &restored).unwrap();
+        let verify = verify_restored(&target_path, &manifest, &target_keyring).unwrap();
+        assert_eq!((verify.pages_bad, verify.pages_mismatched), (0, 1));
+        assert!(!verify.is_ok());
+
+        // So does a page lost off the end.
+        restored.truncate(5 * 4096);
+        std::fs::write(&target_path, &restored).unwrap();
+        let verify = verify_restored(&target_path, &manifest, &target_keyring).unwrap();
+        assert_eq!(verify.digests_ok, Some(false));
+        assert!(!verify.is_ok());
+    }
 }
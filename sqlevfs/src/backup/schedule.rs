@@ -0,0 +1,384 @@
+//! Backups on a schedule, pruned to a retention policy.
+//!
+//! [`run_due`] is meant to be called from an embedder's own scheduler,
+//! say every few minutes: it takes a backup once the newest one in the
+//! destination directory is [`BackupPolicy::every`] old, verifies it,
+//! and deletes those the policy no longer keeps. Backups are found by
+//! the time and database their headers record, so only files the
+//! backup KEK opens are ever deleted.
+
+use std::{
+    cmp::Reverse,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{BackupOptions, partial_path, read_header, source_name};
+use crate::{
+    keyring::{self, Keyring},
+    kms::KmsProvider,
+    migrate,
+    vfs,
+};
+
+const EXTENSION: &str = ".evfs-backup";
+const DAY: u64 = 86_400;
+
+/// When to back a database up, and which backups to keep.
+#[derive(Clone, Debug)]
+pub struct BackupPolicy {
+    /// Age of the newest backup at which the next one is due.
+    pub every: Duration,
+    /// Keep the newest backup of each of the last `keep_daily` days
+    /// that have one, in UTC.
+    pub keep_daily: u32,
+    /// Keep the newest backup of each of the last `keep_weekly` weeks
+    /// that have one, starting on Mondays, in UTC.
+    pub keep_weekly: u32,
+    /// Directory the backups are written to, as
+    /// `<database file name>.<UTC time>.evfs-backup`.
+    pub dest_dir: PathBuf,
+}
+
+/// What [`run_due`] did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RunReport {
+    /// The backup taken, if one was due.
+    pub created: Option<PathBuf>,
+    /// Backups deleted, newest first.
+    pub pruned: Vec<PathBuf>,
+}
+
+/// A backup of the database in the destination directory.
+struct Existing {
+    path: PathBuf,
+    created_at: u64,
+}
+
+/// Back up the database at `db_path` into `policy.dest_dir` if a
+/// backup is due, and delete the backups `policy` doesn't keep.
+///
+/// A backup is due if the newest there is `policy.every` old, or there
+/// is none. It is written to a `.partial` file and verified under
+/// `backup_kms` before it is renamed into place; if that fails, the
+/// error is returned and nothing is pruned. The newest backup is always
+/// kept, whatever the counts.
+pub fn run_due(
+    policy: &BackupPolicy,
+    db_path: &Path,
+    keyring: &Keyring,
+    backup_kms: &dyn KmsProvider,
+) -> anyhow::Result<RunReport> {
+    run_due_at(policy, db_path, keyring, backup_kms, SystemTime::now())
+}
+
+fn run_due_at(
+    policy: &BackupPolicy,
+    db_path: &Path,
+    keyring: &Keyring,
+    backup_kms: &dyn KmsProvider,
+    now: SystemTime,
+) -> anyhow::Result<RunReport> {
+    let now_secs = now.duration_since(UNIX_EPOCH)?.as_secs();
+    let mut backups = existing_backups(policy, db_path, backup_kms)?;
+    let mut report = RunReport::default();
+
+    let newest = backups.iter().map(|backup| backup.created_at).max();
+    if newest.is_none_or(|newest| now_secs.saturating_sub(newest) >= policy.every.as_secs()) {
+        let path = create_verified(policy, db_path, keyring, backup_kms, now)?;
+        log::info!(
+            "scheduled backup of {} to {}",
+            db_path.display(),
+            path.display()
+        );
+        backups.push(Existing {
+            path: path.clone(),
+            created_at: now_secs,
+        });
+        report.created = Some(path);
+    }
+
+    for path in expired(&mut backups, policy) {
+        std::fs::remove_file(&path)?;
+        log::info!("pruned backup {}", path.display());
+        report.pruned.push(path);
+    }
+    Ok(report)
+}
+
+/// The backups of the database at `db_path` in `policy.dest_dir`:
+/// files named as [`run_due`] names them whose headers authenticate
+/// under `backup_kms` and record the database's file name and ID, and
+/// when they were taken.
+fn existing_backups(
+    policy: &BackupPolicy,
+    db_path: &Path,
+    backup_kms: &dyn KmsProvider,
+) -> anyhow::Result<Vec<Existing>> {
+    let entries = match std::fs::read_dir(&policy.dest_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let source = source_name(db_path);
+    let database_id = keyring::database_id_of(db_path);
+    let prefix = format!("{}.", source.as_deref().unwrap_or_default());
+
+    let mut backups = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !name.starts_with(&prefix) || !name.ends_with(EXTENSION) {
+            continue;
+        }
+        let header = match File::open(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| read_header(&mut BufReader::new(file), backup_kms))
+        {
+            Ok((header, _)) => header,
+            Err(e) => {
+                log::warn!("skipping {}: {e:#}", path.display());
+                continue;
+            }
+        };
+        if let Some(created_at) = header.created_at
+            && header.source == source
+            && header.database_id == database_id
+        {
+            backups.push(Existing { path, created_at });
+        }
+    }
+    Ok(backups)
+}
+
+/// Back up the database at `db_path` as taken at `now`, verify the
+/// backup and rename it into place, returning its path.
+fn create_verified(
+    policy: &BackupPolicy,
+    db_path: &Path,
+    keyring: &Keyring,
+    backup_kms: &dyn KmsProvider,
+    now: SystemTime,
+) -> anyhow::Result<PathBuf> {
+    let (page_size, reserve) = vfs::page_geometry_of(db_path)?.ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} records no page size in its header or sidecar",
+            db_path.display()
+        )
+    })?;
+    std::fs::create_dir_all(&policy.dest_dir)?;
+    let name = format!(
+        "{}.{}{EXTENSION}",
+        source_name(db_path).unwrap_or_default(),
+        utc_time(now.duration_since(UNIX_EPOCH)?.as_secs())
+    );
+    let path = policy.dest_dir.join(name);
+    anyhow::ensure!(!path.exists(), "{} already exists", path.display());
+
+    let partial = partial_path(&path);
+    (|| {
+        let options = BackupOptions {
+            created_at: Some(now),
+            ..BackupOptions::default()
+        };
+        let mut out = BufWriter::new(File::create(&partial)?);
+        super::create_backup_with(
+            db_path, &mut out, keyring, backup_kms, page_size, reserve, &options,
+        )?;
+        out.into_inner()?.sync_all()?;
+
+        let result = super::verify_backup(&mut BufReader::new(File::open(&partial)?), backup_kms)?;
+        anyhow::ensure!(
+            result.is_ok(),
+            "backup of {} failed verification: {result:?}",
+            db_path.display()
+        );
+        migrate::replace(&partial, &path)
+    })()
+    .inspect_err(|_| {
+        let _ = std::fs::remove_file(&partial);
+    })?;
+    Ok(path)
+}
+
+/// The backups `policy` doesn't keep, newest first.
+fn expired(backups: &mut [Existing], policy: &BackupPolicy) -> Vec<PathBuf> {
+    backups.sort_by_key(|backup| Reverse(backup.created_at));
+    let mut keep = vec![false; backups.len()];
+    if let Some(newest) = keep.first_mut() {
+        *newest = true;
+    }
+    // 1970-01-01 was a Thursday.
+    let day = |t: u64| t / DAY;
+    let week = |t: u64| (t / DAY + 3) / 7;
+    for (count, period) in [
+        (policy.keep_daily, &day as &dyn Fn(u64) -> u64),
+        (policy.keep_weekly, &week),
+    ] {
+        let mut last = None;
+        let mut kept = 0;
+        for (i, backup) in backups.iter().enumerate() {
+            let period = period(backup.created_at);
+            if kept == count {
+                break;
+            }
+            if last != Some(period) {
+                keep[i] = true;
+                last = Some(period);
+                kept += 1;
+            }
+        }
+    }
+    backups
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| !keep)
+        .map(|(backup, _)| backup.path.clone())
+        .collect()
+}
+
+/// `secs` since the Unix epoch as `YYYYMMDD'T'HHMMSS'Z'`.
+fn utc_time(secs: u64) -> String {
+    crate::sigv4::amz_date(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, sync::Arc};
+
+    use rusqlite::{Connection, OpenFlags};
+
+    use super::*;
+    use crate::{EvfsBuilder, Mode, kms::local::DeviceKeyProvider};
+
+    const HOUR: u64 = 3600;
+    /// 2026-01-05, a Monday.
+    const MONDAY: u64 = 1_767_571_200;
+
+    fn names(dir: &Path) -> BTreeSet<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn backup_name(t: u64) -> String {
+        format!("sched.db.{}.evfs-backup", utc_time(t))
+    }
+
+    #[test]
+    fn runs_keep_the_newest_of_each_day_and_week() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keyfile = dir.path().join("sched.key");
+        std::fs::write(&keyfile, [0x71; 32]).unwrap();
+        let keyring = EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile),
+            passphrase: None,
+        })
+        .vfs_name("backup-schedule")
+        .register()
+        .unwrap();
+        let db_path = dir.path().join("sched.db");
+        let conn = Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "backup-schedule",
+        )
+        .unwrap();
+        conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (1);")
+            .unwrap();
+        let keyring = keyring.for_database(&db_path).unwrap();
+
+        let backup_keyfile = dir.path().join("backup.key");
+        std::fs::write(&backup_keyfile, [0x72; 32]).unwrap();
+        let backup_kms = Arc::new(DeviceKeyProvider::from_keyfile(backup_keyfile));
+        let policy = BackupPolicy {
+            every: Duration::from_secs(6 * HOUR),
+            keep_daily: 3,
+            keep_weekly: 2,
+            dest_dir: dir.path().join("backups"),
+        };
+        let run = |t: u64| {
+            run_due_at(
+                &policy,
+                &db_path,
+                &keyring,
+                backup_kms.as_ref(),
+                UNIX_EPOCH + Duration::from_secs(t),
+            )
+            .unwrap()
+        };
+
+        // A file the backup KEK doesn't open is left alone.
+        std::fs::create_dir_all(&policy.dest_dir).unwrap();
+        let stranger = backup_name(MONDAY - 30 * DAY);
+        std::fs::write(policy.dest_dir.join(&stranger), b"not a backup").unwrap();
+
+        let first = run(MONDAY);
+        assert_eq!(
+            first.created,
+            Some(policy.dest_dir.join(backup_name(MONDAY)))
+        );
+        assert!(first.pruned.is_empty());
+        // Not due until six hours on.
+        assert_eq!(run(MONDAY + 5 * HOUR), RunReport::default());
+
+        // Every 3 hours for 12 days, so a backup every 6.
+        for step in 2..12 * 8 {
+            let report = run(MONDAY + step * 3 * HOUR);
+            assert_eq!(report.created.is_some(), step % 2 == 0, "step {step}");
+        }
+        // The last run, on Friday of the second week at 18:00, is kept;
+        // so is the newest of Thursday and Wednesday, and of the first
+        // week, its Sunday's.
+        let last = MONDAY + 11 * DAY + 18 * HOUR;
+        let expected: BTreeSet<String> = [
+            stranger,
+            backup_name(last),
+            backup_name(last - DAY),
+            backup_name(last - 2 * DAY),
+            backup_name(MONDAY + 6 * DAY + 18 * HOUR),
+        ]
+        .into();
+        assert_eq!(names(&policy.dest_dir), expected);
+
+        // Each backup records the time it was taken at, and restores.
+        let path = policy.dest_dir.join(backup_name(last - DAY));
+        let (header, _) = read_header(
+            &mut BufReader::new(File::open(&path).unwrap()),
+            backup_kms.as_ref(),
+        )
+        .unwrap();
+        assert_eq!(header.created_at, Some(last - DAY));
+        assert_eq!(header.source.as_deref(), Some("sched.db"));
+        let result = super::super::verify_backup(
+            &mut BufReader::new(File::open(&path).unwrap()),
+            backup_kms.as_ref(),
+        )
+        .unwrap();
+        assert!(result.is_ok());
+
+        // With nothing to keep but the newest, a run prunes the rest.
+        let policy = BackupPolicy {
+            keep_daily: 0,
+            keep_weekly: 0,
+            ..policy.clone()
+        };
+        let report = run_due_at(
+            &policy,
+            &db_path,
+            &keyring,
+            backup_kms.as_ref(),
+            UNIX_EPOCH + Duration::from_secs(last + HOUR),
+        )
+        .unwrap();
+        assert_eq!(report.created, None);
+        assert_eq!(report.pruned.len(), 3);
+        assert_eq!(
+            names(&policy.dest_dir),
+            [backup_name(last), backup_name(MONDAY - 30 * DAY)].into()
+        );
+    }
+}
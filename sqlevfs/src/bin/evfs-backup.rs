@@ -0,0 +1,481 @@
+//! `evfs-backup`: back up, verify and restore evfs databases, rotate
+//! the KEK of a backup, move a database's keyring to another KEK and
+//! change its passphrase, from the command line.
+//!
+//! Exits with 0 on success, 1 if a backup or restored database fails
+//! verification, 2 on a usage error, and 3 on any other error: I/O,
+//! the KMS, or a file that isn't what it should be. With `--json`, the
+//! result or the error is printed to stdout as one JSON object.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+    process::ExitCode,
+    str::FromStr,
+    sync::Arc,
+};
+
+use serde_json::{Map, Value, json};
+use sqlevfs::{
+    Mode,
+    backup::{self, BackupManifest, VerifyResult},
+    keyring::{self, Keyring},
+    kms::{KmsProvider, local::DeviceKeyProvider},
+    vfs,
+};
+
+const USAGE: &str = "\
+Usage:
+  evfs-backup create <database> <backup> [KEY] [BACKUP KEY]
+                     [--page-size N] [--reserve N] [--manifest FILE] [--json]
+  evfs-backup verify <backup> [BACKUP KEY] [--manifest FILE] [--json]
+  evfs-backup restore <backup> <database> [KEY] [BACKUP KEY] [--manifest FILE] [--json]
+  evfs-backup rotate-kek <backup> [BACKUP KEY] NEW KEY [--json]
+  evfs-backup migrate-keyring <database> KEY NEW KEY [--json]
+  evfs-backup change-passphrase <database> --passphrase OLD --new-passphrase NEW [--json]
+
+KEY is the KEK of the database, one of
+  --keyfile FILE | --passphrase PASSPHRASE | --kms-key-id ID [--kms-endpoint URL]
+BACKUP KEY is the KEK of the backup, given by the same options prefixed
+with `backup-` (--backup-keyfile, ...); it defaults to KEY. NEW KEY is the
+KEK `rotate-kek` wraps the backup's DEK under, and `migrate-keyring` the
+database's DEKs, prefixed with `new-`. `change-passphrase` rewraps the DEKs
+of a passphrase-keyed database under a KEK derived from NEW, checking OLD
+first. Close every connection to a database before migrating its keyring or
+changing its passphrase.
+
+Options:
+  --page-size N    page size of the database, by default read from its header
+  --reserve N      reserved bytes per page, by default read from its header
+  --manifest FILE  write the backup's manifest to FILE when creating it, and
+                   check a backup or restored database against it
+  --json           print the result as JSON
+
+Exit status: 0 on success, 1 if verification fails, 2 on a usage error,
+3 on any other error.
+";
+
+const VERIFY_FAILED: u8 = 1;
+const USAGE_ERROR: u8 = 2;
+const ERROR: u8 = 3;
+
+/// Geometry of a database whose header and sidecar don't give it, as
+/// `EvfsBuilder` defaults to.
+const DEFAULT_PAGE_SIZE: u32 = 4096;
+const DEFAULT_RESERVE: usize = 80;
+
+const KEY_OPTIONS: [&str; 4] = ["keyfile", "passphrase", "kms-key-id", "kms-endpoint"];
+
+/// Why a command didn't run to a result.
+enum Failure {
+    Usage(String),
+    Error(anyhow::Error),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for Failure {
+    fn from(e: E) -> Self {
+        Failure::Error(e.into())
+    }
+}
+
+/// What a command found: printed as `text`, or with `--json` as
+/// `fields` with `command` and `ok` added.
+struct Report {
+    ok: bool,
+    fields: Map<String, Value>,
+    text: String,
+}
+
+/// The arguments after the command.
+struct Args {
+    paths: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    /// Parse `args`, taking the options named in `prefixes` followed
+    /// by a key option, and `extra`, each with a value.
+    fn parse(args: &[String], prefixes: &[&str], extra: &[&str]) -> Result<Self, Failure> {
+        let allowed = |name: &str| {
+            extra.contains(&name)
+                || prefixes.iter().any(|prefix| {
+                    name.strip_prefix(prefix)
+                        .is_some_and(|option| KEY_OPTIONS.contains(&option))
+                })
+        };
+        let mut parsed = Args {
+            paths: Vec::new(),
+            options: HashMap::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--json" {
+                continue;
+            }
+            let Some(option) = arg.strip_prefix("--") else {
+                parsed.paths.push(arg.clone());
+                continue;
+            };
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (option, None),
+            };
+            if !allowed(name) {
+                return Err(Failure::Usage(format!("unknown option --{name}")));
+            }
+            // A value starting with `--` is taken for a missing one; pass
+            // it as `--name=value`.
+            let value = match value {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .filter(|value| !value.starts_with("--"))
+                    .cloned()
+                    .ok_or_else(|| Failure::Usage(format!("--{name} needs a value")))?,
+            };
+            if parsed.options.insert(name.to_string(), value).is_some() {
+                return Err(Failure::Usage(format!("--{name} given twice")));
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn paths<const N: usize>(&self) -> Result<[&Path; N], Failure> {
+        let paths: Vec<&Path> = self.paths.iter().map(Path::new).collect();
+        paths.try_into().map_err(|paths: Vec<_>| {
+            Failure::Usage(format!("expected {N} paths, got {}", paths.len()))
+        })
+    }
+
+    fn path(&self, name: &str) -> Option<&Path> {
+        self.options.get(name).map(Path::new)
+    }
+
+    fn number<T: FromStr>(&self, name: &str) -> Result<Option<T>, Failure> {
+        self.options
+            .get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| Failure::Usage(format!("--{name} takes a number, not {value:?}")))
+            })
+            .transpose()
+    }
+
+    /// The key given by the key options with `prefix`, if any.
+    fn mode(&self, prefix: &str) -> Result<Option<Mode>, Failure> {
+        let option = |name: &str| self.options.get(&format!("{prefix}{name}")).cloned();
+        let endpoint = option("kms-endpoint");
+        let mode = match (
+            option("keyfile"),
+            option("passphrase"),
+            option("kms-key-id"),
+        ) {
+            (None, None, None) => None,
+            (Some(keyfile), None, None) => Some(Mode::DeviceKey {
+                keyfile: Some(keyfile.into()),
+                passphrase: None,
+            }),
+            (None, Some(passphrase), None) => Some(Mode::DeviceKey {
+                keyfile: None,
+                passphrase: Some(passphrase),
+            }),
+            (None, None, Some(key_id)) => {
+                return Ok(Some(Mode::TenantKey { key_id, endpoint }));
+            }
+            _ => {
+                return Err(Failure::Usage(format!(
+                    "give only one of --{prefix}keyfile, --{prefix}passphrase and \
+                     --{prefix}kms-key-id"
+                )));
+            }
+        };
+        if endpoint.is_some() {
+            return Err(Failure::Usage(format!(
+                "--{prefix}kms-endpoint needs --{prefix}kms-key-id"
+            )));
+        }
+        Ok(mode)
+    }
+
+    /// The KMS provider of the key given with the first of `prefixes`
+    /// that has one.
+    fn kms(&self, prefixes: &[&str]) -> Result<Arc<dyn KmsProvider>, Failure> {
+        for prefix in prefixes {
+            if let Some(mode) = self.mode(prefix)? {
+                return Ok(mode.into_provider()?);
+            }
+        }
+        let prefix = prefixes[0];
+        Err(Failure::Usage(format!(
+            "no key given: pass --{prefix}keyfile, --{prefix}passphrase or --{prefix}kms-key-id"
+        )))
+    }
+}
+
+fn create(args: &Args) -> Result<Report, Failure> {
+    let [database, backup_path] = args.paths()?;
+    let keyring = Keyring::new(args.kms(&[""])?);
+    let backup_kms = args.kms(&["backup-", ""])?;
+
+    let geometry = vfs::page_geometry_of(database)?;
+    let page_size = args
+        .number("page-size")?
+        .or(geometry.map(|(page_size, _)| page_size))
+        .unwrap_or(DEFAULT_PAGE_SIZE);
+    let reserve = args
+        .number("reserve")?
+        .or(geometry.map(|(_, reserve)| reserve))
+        .unwrap_or(DEFAULT_RESERVE);
+    bind(&keyring, database)?;
+
+    // Never overwrite a backup, so a failed one can be removed.
+    let mut out = BufWriter::new(File::create_new(backup_path)?);
+    let manifest = (|| {
+        let manifest = backup::create_backup(
+            database,
+            &mut out,
+            &keyring,
+            backup_kms.as_ref(),
+            page_size,
+            reserve,
+        )?;
+        out.into_inner()?.sync_all()?;
+        if let Some(path) = args.path("manifest") {
+            let mut out = BufWriter::new(File::create(path)?);
+            manifest.write(&mut out)?;
+            out.into_inner()?.sync_all()?;
+        }
+        anyhow::Ok(manifest)
+    })()
+    .inspect_err(|_| {
+        let _ = std::fs::remove_file(backup_path);
+    })?;
+
+    let page_count = manifest.digests.len();
+    Ok(Report {
+        ok: true,
+        fields: fields(json!({
+            "database": database,
+            "backup": backup_path,
+            "backup_id": manifest.backup_id.to_string(),
+            "page_count": page_count,
+            "page_size": page_size,
+            "reserve": reserve,
+        })),
+        text: format!(
+            "backed up {page_count} pages of {} to {} (backup {})",
+            database.display(),
+            backup_path.display(),
+            manifest.backup_id
+        ),
+    })
+}
+
+fn verify(args: &Args) -> Result<Report, Failure> {
+    let [backup_path] = args.paths()?;
+    let backup_kms = args.kms(&["backup-", ""])?;
+    let mut source = BufReader::new(File::open(backup_path)?);
+    let result = match args.path("manifest") {
+        Some(path) => {
+            backup::verify_backup_against(&mut source, backup_kms.as_ref(), &read_manifest(path)?)?
+        }
+        None => backup::verify_backup(&mut source, backup_kms.as_ref())?,
+    };
+    Ok(verify_report(json!({ "backup": backup_path }), &result))
+}
+
+fn restore(args: &Args) -> Result<Report, Failure> {
+    let [backup_path, database] = args.paths()?;
+    let keyring = Keyring::new(args.kms(&[""])?);
+    let backup_kms = args.kms(&["backup-", ""])?;
+    let manifest = args.path("manifest").map(read_manifest).transpose()?;
+    if database.exists() {
+        return Err(anyhow::anyhow!("{} already exists", database.display()).into());
+    }
+
+    backup::restore_backup(
+        &mut BufReader::new(File::open(backup_path)?),
+        database,
+        backup_kms.as_ref(),
+        &keyring,
+    )?;
+    let restored = json!({ "backup": backup_path, "database": database });
+    match manifest {
+        Some(manifest) => {
+            let result = backup::verify_restored(database, &manifest, &keyring)?;
+            Ok(verify_report(restored, &result))
+        }
+        None => Ok(Report {
+            ok: true,
+            fields: fields(restored),
+            text: format!(
+                "restored {} to {}",
+                backup_path.display(),
+                database.display()
+            ),
+        }),
+    }
+}
+
+fn rotate_kek(args: &Args) -> Result<Report, Failure> {
+    let [backup_path] = args.paths()?;
+    let old_kms = args.kms(&["backup-", ""])?;
+    let new_kms = args.kms(&["new-"])?;
+    backup::rotate_backup_kek(backup_path, old_kms.as_ref(), new_kms.as_ref())?;
+    Ok(Report {
+        ok: true,
+        fields: fields(json!({ "backup": backup_path })),
+        text: format!("rotated the KEK of {}", backup_path.display()),
+    })
+}
+
+fn migrate_keyring(args: &Args) -> Result<Report, Failure> {
+    let [database] = args.paths()?;
+    let old_kms = args.kms(&[""])?;
+    let new_kms = args.kms(&["new-"])?;
+    let keyring = Keyring::new(old_kms.clone());
+    bind(&keyring, database)?;
+    let export = keyring.export(old_kms.as_ref())?;
+    let scopes: Vec<String> = export.scopes().into_iter().map(str::to_owned).collect();
+    Keyring::import(export, new_kms)?;
+    Ok(Report {
+        ok: true,
+        fields: fields(json!({ "database": database, "scopes": scopes })),
+        text: format!(
+            "moved {} DEKs of {} to the new KEK",
+            scopes.len(),
+            database.display()
+        ),
+    })
+}
+
+fn change_passphrase(args: &Args) -> Result<Report, Failure> {
+    let [database] = args.paths()?;
+    let passphrase = |name: &str| {
+        args.options
+            .get(name)
+            .ok_or_else(|| Failure::Usage(format!("--{name} is required")))
+    };
+    let (old, new) = (passphrase("passphrase")?, passphrase("new-passphrase")?);
+    DeviceKeyProvider::change_passphrase(database, old, new)?;
+    Ok(Report {
+        ok: true,
+        fields: fields(json!({ "database": database })),
+        text: format!("changed the passphrase of {}", database.display()),
+    })
+}
+
+/// Bind `keyring` to the sidecar of `database`, which must have one:
+/// binding it to a database without one would give it a new DEK, which
+/// decrypts none of its pages.
+fn bind(keyring: &Keyring, database: &Path) -> anyhow::Result<()> {
+    let sidecar = keyring::sidecar_path_for(database);
+    anyhow::ensure!(
+        sidecar.exists(),
+        "{} has no sidecar at {}; is it an evfs database?",
+        database.display(),
+        sidecar.display()
+    );
+    keyring.set_sidecar_path(database)
+}
+
+fn read_manifest(path: &Path) -> anyhow::Result<BackupManifest> {
+    BackupManifest::read(&mut BufReader::new(File::open(path)?))
+}
+
+fn verify_report(subject: Value, result: &VerifyResult) -> Report {
+    let mut fields = fields(subject);
+    fields.extend(self::fields(json!({
+        "page_count": result.page_count,
+        "pages_ok": result.pages_ok,
+        "pages_bad": result.pages_bad,
+        "pages_mismatched": result.pages_mismatched,
+        "digests_ok": result.digests_ok,
+    })));
+    let digests = match result.digests_ok {
+        Some(true) => "match",
+        Some(false) => "don't match",
+        None => "unchecked",
+    };
+    Report {
+        ok: result.is_ok(),
+        fields,
+        text: format!(
+            "{}: {} pages, {} ok, {} bad, {} mismatched; digests {digests}",
+            if result.is_ok() { "ok" } else { "FAILED" },
+            result.page_count,
+            result.pages_ok,
+            result.pages_bad,
+            result.pages_mismatched
+        ),
+    }
+}
+
+fn fields(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(fields) => fields,
+        _ => unreachable!("report fields are built as objects"),
+    }
+}
+
+fn run(command: Option<&str>, args: &[String]) -> Result<Report, Failure> {
+    let both = ["", "backup-"];
+    match command {
+        Some("create") => create(&Args::parse(
+            args,
+            &both,
+            &["page-size", "reserve", "manifest"],
+        )?),
+        Some("verify") => verify(&Args::parse(args, &both, &["manifest"])?),
+        Some("restore") => restore(&Args::parse(args, &both, &["manifest"])?),
+        Some("rotate-kek") => rotate_kek(&Args::parse(args, &["", "backup-", "new-"], &[])?),
+        Some("migrate-keyring") => migrate_keyring(&Args::parse(args, &["", "new-"], &[])?),
+        Some("change-passphrase") => {
+            change_passphrase(&Args::parse(args, &[], &["passphrase", "new-passphrase"])?)
+        }
+        Some(other) => Err(Failure::Usage(format!("unknown command {other:?}"))),
+        None => Err(Failure::Usage("no command given".into())),
+    }
+}
+
+fn main() -> ExitCode {
+    let _ = env_logger::try_init();
+
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+    let args: Vec<String> = args.collect();
+    if matches!(command.as_deref(), Some("-h" | "--help" | "help")) {
+        print!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let json = args.iter().any(|arg| arg == "--json");
+
+    let (code, mut fields, error) = match run(command.as_deref(), &args) {
+        Ok(report) => {
+            if !json {
+                println!("{}", report.text);
+            }
+            let code = if report.ok { 0 } else { VERIFY_FAILED };
+            (code, report.fields, None)
+        }
+        Err(Failure::Usage(message)) => (USAGE_ERROR, Map::new(), Some(message)),
+        Err(Failure::Error(e)) => (ERROR, Map::new(), Some(format!("{e:#}"))),
+    };
+    if json {
+        fields.insert("command".into(), json!(command));
+        fields.insert("ok".into(), json!(code == 0));
+        if let Some(error) = &error {
+            fields.insert("error".into(), json!(error));
+        }
+        println!("{}", Value::Object(fields));
+    } else if let Some(error) = error {
+        eprintln!("evfs-backup: {error}");
+        if code == USAGE_ERROR {
+            eprint!("\n{USAGE}");
+        }
+    }
+    ExitCode::from(code)
+}
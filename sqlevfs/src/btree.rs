@@ -0,0 +1,429 @@
+//! Just enough of the SQLite file format to tell which pages belong to
+//! which table, so a table's pages can be keyed by its own scope.
+//!
+//! Pages are supplied decrypted by the caller, in buffers zeroized
+//! when the walk is done with them. The schema b-tree
+//! rooted at page 1 names every table and index with its root page;
+//! walking a b-tree from its root reaches its interior, leaf and
+//! overflow pages.
+
+use std::collections::HashSet;
+
+use zeroize::Zeroizing;
+
+/// Page types from the b-tree page header.
+const INTERIOR_INDEX: u8 = 2;
+const INTERIOR_TABLE: u8 = 5;
+const LEAF_INDEX: u8 = 10;
+const LEAF_TABLE: u8 = 13;
+
+/// A b-tree named in `sqlite_master`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaEntry {
+    /// `table` or `index`.
+    pub kind: String,
+    pub name: String,
+    /// The table an index belongs to; a table's own name.
+    pub table: String,
+    pub root_page: u32,
+}
+
+/// `(table, page)` for every page of the b-trees of `tables` and their
+/// indexes, with each table named as it appears in `tables`.
+///
+/// `read` returns the plaintext of a page; `usable` is the page size
+/// less the reserved bytes.
+pub fn table_pages<F>(
+    read: F,
+    usable: usize,
+    tables: &[String],
+) -> anyhow::Result<Vec<(String, u32)>>
+where
+    F: FnMut(u32) -> anyhow::Result<Zeroizing<Vec<u8>>>,
+{
+    let mut walker = Walker::new(read, usable);
+    let schema = walker.schema()?;
+
+    let mut pages = Vec::new();
+    for entry in schema.iter().filter(|e| e.root_page > 0) {
+        let Some(table) = tables.iter().find(|t| t.eq_ignore_ascii_case(&entry.table)) else {
+            continue;
+        };
+        for page_no in walker.tree_pages(entry.root_page)? {
+            pages.push((table.clone(), page_no));
+        }
+    }
+    Ok(pages)
+}
+
+/// Every b-tree named in `sqlite_master`.
+pub fn schema<F>(read: F, usable: usize) -> anyhow::Result<Vec<SchemaEntry>>
+where
+    F: FnMut(u32) -> anyhow::Result<Zeroizing<Vec<u8>>>,
+{
+    Walker::new(read, usable).schema()
+}
+
+struct Walker<F> {
+    read: F,
+    usable: usize,
+    /// Pages reached so far, across all trees, to stop on cycles.
+    seen: HashSet<u32>,
+}
+
+impl<F> Walker<F>
+where
+    F: FnMut(u32) -> anyhow::Result<Zeroizing<Vec<u8>>>,
+{
+    fn new(read: F, usable: usize) -> Self {
+        Self {
+            read,
+            usable,
+            seen: HashSet::new(),
+        }
+    }
+
+    fn schema(&mut self) -> anyhow::Result<Vec<SchemaEntry>> {
+        let mut payloads = Vec::new();
+        self.walk(1, Some(&mut payloads))?;
+
+        let mut entries = Vec::new();
+        for payload in payloads {
+            let columns = record_columns(&payload, 4)
+                .ok_or_else(|| anyhow::anyhow!("malformed sqlite_master record"))?;
+            let (Value::Text(kind), Value::Text(name), Value::Text(table)) =
+                (&columns[0], &columns[1], &columns[2])
+            else {
+                continue;
+            };
+            let root_page = match columns[3] {
+                Value::Int(n) => u32::try_from(n).unwrap_or(0),
+                _ => 0,
+            };
+            entries.push(SchemaEntry {
+                kind: kind.clone(),
+                name: name.clone(),
+                table: table.clone(),
+                root_page,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn tree_pages(&mut self, root: u32) -> anyhow::Result<Vec<u32>> {
+        self.walk(root, None)
+    }
+
+    /// Pages of the b-tree at `root`, collecting table-leaf payloads
+    /// into `payloads` if given.
+    fn walk(
+        &mut self,
+        root: u32,
+        mut payloads: Option<&mut Vec<Vec<u8>>>,
+    ) -> anyhow::Result<Vec<u32>> {
+        let usable = self.usable;
+        let mut pages = Vec::new();
+        let mut stack = vec![root];
+
+        while let Some(page_no) = stack.pop() {
+            let page = self.visit(page_no, &mut pages)?;
+            let hdr = if page_no == 1 { 100 } else { 0 };
+            let kind = *page
+                .get(hdr)
+                .ok_or_else(|| anyhow::anyhow!("page {page_no} too short"))?;
+            let interior = match kind {
+                INTERIOR_INDEX | INTERIOR_TABLE => true,
+                LEAF_INDEX | LEAF_TABLE => false,
+                _ => anyhow::bail!("page {page_no} is not a b-tree page (type {kind})"),
+            };
+            let header_len = if interior { 12 } else { 8 };
+            let cells = be16(&page, hdr + 3)? as usize;
+            if interior {
+                stack.push(be32(&page, hdr + 8)?);
+            }
+
+            for i in 0..cells {
+                let mut off = be16(&page, hdr + header_len + 2 * i)? as usize;
+                if interior {
+                    stack.push(be32(&page, off)?);
+                    off += 4;
+                }
+                if kind == INTERIOR_TABLE {
+                    continue;
+                }
+
+                let (payload_len, n) = varint(page.get(off..usable).unwrap_or_default())
+                    .ok_or_else(|| anyhow::anyhow!("bad cell on page {page_no}"))?;
+                off += n;
+                if kind == LEAF_TABLE {
+                    let (_rowid, n) = varint(page.get(off..usable).unwrap_or_default())
+                        .ok_or_else(|| anyhow::anyhow!("bad cell on page {page_no}"))?;
+                    off += n;
+                }
+
+                let payload_len = payload_len as usize;
+                let local = local_payload(payload_len, usable, kind == LEAF_TABLE);
+                let local_bytes = page
+                    .get(off..off + local)
+                    .ok_or_else(|| anyhow::anyhow!("cell overruns page {page_no}"))?;
+                let first = if local < payload_len {
+                    be32(&page, off + local)?
+                } else {
+                    0
+                };
+                // Only schema payloads are kept; a table's rows are
+                // walked past without being copied.
+                let mut payload = match payloads {
+                    Some(_) if kind == LEAF_TABLE => Some(local_bytes.to_vec()),
+                    _ => None,
+                };
+                self.overflow(first, payload_len - local, payload.as_mut(), &mut pages)?;
+                if let (Some(payloads), Some(payload)) = (payloads.as_deref_mut(), payload) {
+                    payloads.push(payload);
+                }
+            }
+        }
+        Ok(pages)
+    }
+
+    /// Follow an overflow chain holding the last `remaining` bytes of a
+    /// payload, appending them to `payload` if given.
+    fn overflow(
+        &mut self,
+        mut page_no: u32,
+        mut remaining: usize,
+        mut payload: Option<&mut Vec<u8>>,
+        pages: &mut Vec<u32>,
+    ) -> anyhow::Result<()> {
+        while page_no != 0 && remaining > 0 {
+            let page = self.visit(page_no, pages)?;
+            let take = remaining.min(self.usable - 4);
+            let content = page
+                .get(4..4 + take)
+                .ok_or_else(|| anyhow::anyhow!("overflow page {page_no} too short"))?;
+            if let Some(payload) = payload.as_deref_mut() {
+                payload.extend_from_slice(content);
+            }
+            remaining -= take;
+            page_no = be32(&page, 0)?;
+        }
+        Ok(())
+    }
+
+    fn visit(&mut self, page_no: u32, pages: &mut Vec<u32>) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        anyhow::ensure!(page_no != 0, "b-tree points at page 0");
+        anyhow::ensure!(self.seen.insert(page_no), "page {page_no} reached twice");
+        pages.push(page_no);
+        let page = (self.read)(page_no)?;
+        anyhow::ensure!(page.len() >= self.usable, "page {page_no} too short");
+        Ok(page)
+    }
+}
+
+/// Bytes of a payload of `payload_len` stored on the b-tree page
+/// itself; the rest spills to overflow pages.
+fn local_payload(payload_len: usize, usable: usize, table_leaf: bool) -> usize {
+    let max_local = if table_leaf {
+        usable - 35
+    } else {
+        (usable - 12) * 64 / 255 - 23
+    };
+    if payload_len <= max_local {
+        return payload_len;
+    }
+    let min_local = (usable - 12) * 32 / 255 - 23;
+    let local = min_local + (payload_len - min_local) % (usable - 4);
+    if local <= max_local { local } else { min_local }
+}
+
+enum Value {
+    Null,
+    Int(i64),
+    Text(String),
+    Other,
+}
+
+/// The first `count` columns of a record.
+fn record_columns(record: &[u8], count: usize) -> Option<Vec<Value>> {
+    let (header_len, mut pos) = varint(record)?;
+    let header_len = header_len as usize;
+    let mut body = header_len;
+    let mut columns = Vec::with_capacity(count);
+
+    while pos < header_len && columns.len() < count {
+        let (serial, n) = varint(record.get(pos..header_len)?)?;
+        pos += n;
+        let (len, value) = match serial {
+            0 => (0, Value::Null),
+            1..=6 => {
+                let len = [1, 2, 3, 4, 6, 8][serial as usize - 1];
+                let bytes = record.get(body..body + len)?;
+                let mut n = if bytes[0] & 0x80 != 0 { -1i64 } else { 0 };
+                for b in bytes {
+                    n = (n << 8) | *b as i64;
+                }
+                (len, Value::Int(n))
+            }
+            7 => (8, Value::Other),
+            8 => (0, Value::Int(0)),
+            9 => (0, Value::Int(1)),
+            s if s >= 13 && s % 2 == 1 => {
+                let len = (s as usize - 13) / 2;
+                let text = String::from_utf8_lossy(record.get(body..body + len)?).into_owned();
+                (len, Value::Text(text))
+            }
+            s if s >= 12 => ((s as usize - 12) / 2, Value::Other),
+            _ => return None,
+        };
+        body += len;
+        columns.push(value);
+    }
+    while columns.len() < count {
+        columns.push(Value::Null);
+    }
+    Some(columns)
+}
+
+/// A SQLite varint and its length in bytes.
+fn varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, b) in buf.iter().take(9).enumerate() {
+        if i == 8 {
+            return Some(((value << 8) | *b as u64, 9));
+        }
+        value = (value << 7) | (*b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn be16(page: &[u8], off: usize) -> anyhow::Result<u16> {
+    let b = page
+        .get(off..off + 2)
+        .ok_or_else(|| anyhow::anyhow!("read past end of page at {off}"))?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn be32(page: &[u8], off: usize) -> anyhow::Result<u32> {
+    let b = page
+        .get(off..off + 4)
+        .ok_or_else(|| anyhow::anyhow!("read past end of page at {off}"))?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A plain (unencrypted) database and a reader for its pages.
+    fn plain_db(dir: &TempDir, sql: &str) -> (Vec<u8>, usize) {
+        let path = dir.path().join("plain.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(sql).unwrap();
+        drop(conn);
+        (std::fs::read(&path).unwrap(), 4096)
+    }
+
+    fn reader(
+        raw: &[u8],
+        page_size: usize,
+    ) -> impl FnMut(u32) -> anyhow::Result<Zeroizing<Vec<u8>>> + '_ {
+        move |page_no| {
+            let start = (page_no as usize - 1) * page_size;
+            raw.get(start..start + page_size)
+                .map(|page| Zeroizing::new(page.to_vec()))
+                .ok_or_else(|| anyhow::anyhow!("no page {page_no}"))
+        }
+    }
+
+    #[test]
+    fn varint_decodes() {
+        assert_eq!(varint(&[0x00]), Some((0, 1)));
+        assert_eq!(varint(&[0x7f]), Some((127, 1)));
+        assert_eq!(varint(&[0x81, 0x00]), Some((128, 2)));
+        assert_eq!(varint(&[0xff; 9]), Some((u64::MAX, 9)));
+        assert_eq!(varint(&[0x81]), None);
+    }
+
+    #[test]
+    fn local_payload_matches_file_format() {
+        // 4096-byte pages, no reserve.
+        assert_eq!(local_payload(100, 4096, true), 100);
+        assert_eq!(local_payload(4061, 4096, true), 4061);
+        assert_eq!(local_payload(4062, 4096, true), 489);
+        assert_eq!(local_payload(8000, 4096, true), 489 + (8000 - 489) % 4092);
+        assert_eq!(local_payload(1002, 4096, false), 1002);
+        assert_eq!(local_payload(1003, 4096, false), 489);
+    }
+
+    #[test]
+    fn reads_schema() {
+        let dir = TempDir::new().unwrap();
+        let (raw, page_size) = plain_db(
+            &dir,
+            "CREATE TABLE a (x); CREATE TABLE b (y); CREATE INDEX b_y ON b (y);",
+        );
+        let schema = schema(reader(&raw, page_size), page_size).unwrap();
+        let names: Vec<_> = schema
+            .iter()
+            .map(|e| (e.kind.as_str(), e.name.as_str(), e.table.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("table", "a", "a"),
+                ("table", "b", "b"),
+                ("index", "b_y", "b")
+            ]
+        );
+        assert!(schema.iter().all(|e| e.root_page > 1));
+    }
+
+    #[test]
+    fn pages_of_each_table_partition_the_file() {
+        let dir = TempDir::new().unwrap();
+        let mut sql =
+            String::from("CREATE TABLE a (x); CREATE TABLE b (y); CREATE INDEX b_y ON b (y);");
+        for i in 0..300 {
+            // Large enough for overflow pages in both tables and the index.
+            sql += &format!(
+                "INSERT INTO a VALUES ('{i}{}'); INSERT INTO b VALUES ('{i}{}');",
+                "a".repeat(5000),
+                "b".repeat(1500)
+            );
+        }
+        let (raw, page_size) = plain_db(&dir, &sql);
+        let page_count = raw.len() / page_size;
+
+        let tables = ["A".to_string(), "b".to_string()];
+        let pages = table_pages(reader(&raw, page_size), page_size, &tables).unwrap();
+
+        let mut seen: Vec<u32> = pages.iter().map(|(_, p)| *p).collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), pages.len(), "a page was claimed twice");
+        assert_eq!(seen, (2..=page_count as u32).collect::<Vec<_>>());
+        assert!(pages.iter().any(|(t, _)| t == "A"));
+        assert!(pages.iter().any(|(t, _)| t == "b"));
+
+        let only_a = table_pages(reader(&raw, page_size), page_size, &tables[..1]).unwrap();
+        assert!(only_a.iter().all(|(t, _)| t == "A"));
+        assert!(only_a.len() < pages.len());
+    }
+
+    #[test]
+    fn cycle_is_an_error() {
+        let dir = TempDir::new().unwrap();
+        let (mut raw, page_size) = plain_db(&dir, "CREATE TABLE a (x);");
+        // Make page 1's schema tree an interior page whose right child
+        // is itself.
+        raw[100] = INTERIOR_TABLE;
+        raw[108..112].copy_from_slice(&1u32.to_be_bytes());
+        assert!(schema(reader(&raw, page_size), page_size).is_err());
+    }
+}
@@ -1,10 +1,31 @@
 use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
+use zeroize::Zeroizing;
 
-use super::keys::{Dek, WrappedDek};
+use super::keys::{Algorithm, Dek, KekId, WrappedDek};
 use crate::kms::KmsProvider;
 
-/// Wrap a DEK under the current KEK from the provider.
+// A wrapped DEK seals the key followed by its algorithm's id, so the
+// algorithm can't be changed without the KEK. AES-256-GCM keys are
+// sealed without one, as they were before the algorithm was
+// selectable, and a 32-byte plaintext unwraps as AES-256-GCM.
+
+/// Wrap a DEK under the current KEK from the provider: by the KMS
+/// itself if it [prefers to](KmsProvider::prefers_direct_wrap), and
+/// otherwise locally.
 pub fn wrap_dek(dek: &Dek, provider: &dyn KmsProvider) -> anyhow::Result<WrappedDek> {
+    let mut plaintext = Zeroizing::new(Vec::with_capacity(33));
+    plaintext.extend_from_slice(dek.as_bytes());
+    if dek.algorithm() != Algorithm::Aes256Gcm {
+        plaintext.push(dek.algorithm().id());
+    }
+    if provider.prefers_direct_wrap() {
+        return Ok(WrappedDek {
+            ciphertext: provider.wrap_blob(&plaintext)?,
+            nonce: [0; 12],
+            kek_id: KekId(WrappedDek::DIRECT_KEK_ID.into()),
+        });
+    }
+
     let (kek_id, kek_bytes) = provider.get_kek()?;
     anyhow::ensure!(kek_bytes.len() == 32, "KEK must be 32 bytes");
 
@@ -12,7 +33,7 @@ pub fn wrap_dek(dek: &Dek, provider: &dyn KmsProvider) -> anyhow::Result<Wrapped
     let nonce_bytes = rand_nonce();
     let nonce = Nonce::from_slice(&nonce_bytes);
     let ciphertext = cipher
-        .encrypt(nonce, dek.as_bytes().as_ref())
+        .encrypt(nonce, plaintext.as_ref())
         .map_err(|e| anyhow::anyhow!("wrap encrypt failed: {e}"))?;
 
     Ok(WrappedDek {
@@ -22,22 +43,48 @@ pub fn wrap_dek(dek: &Dek, provider: &dyn KmsProvider) -> anyhow::Result<Wrapped
     })
 }
 
-/// Unwrap a DEK using the provider to resolve the KEK.
+/// Unwrap a DEK the way it was wrapped: by the KMS itself, or locally
+/// under the KEK the provider resolves its id to.
 pub fn unwrap_dek(wrapped: &WrappedDek, provider: &dyn KmsProvider) -> anyhow::Result<Dek> {
-    let kek_bytes = provider.get_kek_by_id(&wrapped.kek_id)?;
-    anyhow::ensure!(kek_bytes.len() == 32, "KEK must be 32 bytes");
-
-    let cipher = Aes256Gcm::new_from_slice(&kek_bytes)?;
-    let nonce = Nonce::from_slice(&wrapped.nonce);
-    let plaintext = cipher
-        .decrypt(nonce, wrapped.ciphertext.as_ref())
-        .map_err(|e| anyhow::anyhow!("unwrap decrypt failed: {e}"))?;
-
-    anyhow::ensure!(plaintext.len() == 32, "DEK plaintext must be 32 bytes");
-    let mut buf = [0u8; 32];
-    buf.copy_from_slice(&plaintext);
+    let plaintext = if wrapped.is_direct() {
+        provider
+            .unwrap_blob(&wrapped.ciphertext)
+            .map_err(|e| anyhow::anyhow!("cannot unwrap a DEK the KMS wrapped itself: {e}"))?
+    } else {
+        let kek_bytes = provider.get_kek_by_id(&wrapped.kek_id)?;
+        anyhow::ensure!(kek_bytes.len() == 32, "KEK must be 32 bytes");
+
+        let cipher = Aes256Gcm::new_from_slice(&kek_bytes)?;
+        let nonce = Nonce::from_slice(&wrapped.nonce);
+        Zeroizing::new(
+            cipher
+                .decrypt(nonce, wrapped.ciphertext.as_ref())
+                .map_err(|e| anyhow::anyhow!("unwrap decrypt failed: {e}"))?,
+        )
+    };
+
+    let algorithm = match plaintext.len() {
+        32 => Algorithm::Aes256Gcm,
+        33 => Algorithm::from_id(plaintext[32])
+            .ok_or_else(|| anyhow::anyhow!("DEK for unknown algorithm id {}", plaintext[32]))?,
+        _ => anyhow::bail!("DEK plaintext must be 32 bytes"),
+    };
+    let mut buf = Zeroizing::new([0u8; 32]);
+    buf.copy_from_slice(&plaintext[..32]);
+
+    Ok(Dek::from_bytes_for(*buf, algorithm))
+}
 
-    Ok(Dek::from_bytes(buf))
+/// Whether `wrapped` is wrapped the way `provider` wraps DEKs now, and
+/// under its current KEK. The KMS keeps track of the key versions of
+/// DEKs it wrapped itself, so those are current while the provider
+/// still wraps directly.
+pub fn is_current(wrapped: &WrappedDek, provider: &dyn KmsProvider) -> anyhow::Result<bool> {
+    match (wrapped.is_direct(), provider.prefers_direct_wrap()) {
+        (true, direct) => Ok(direct),
+        (false, true) => Ok(false),
+        (false, false) => provider.is_current_kek(&wrapped.kek_id),
+    }
 }
 
 fn rand_nonce() -> [u8; 12] {
@@ -48,51 +95,19 @@ fn rand_nonce() -> [u8; 12] {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Mutex;
-
     use super::*;
-    use crate::crypto::keys::KekId;
-
-    // Mock KmsProvider for testing
-    struct MockKmsProvider {
-        kek: Vec<u8>,
-        kek_id: String,
-        call_count: Mutex<usize>,
-    }
-
-    impl MockKmsProvider {
-        fn new(kek: Vec<u8>, kek_id: &str) -> Self {
-            assert!(kek.len() == 32, "test KEK must be 32 bytes");
-            Self {
-                kek,
-                kek_id: kek_id.to_string(),
-                call_count: Mutex::new(0),
-            }
-        }
-
-        fn new_default() -> Self {
-            Self::new(vec![0xAAu8; 32], "kek-v1")
-        }
-    }
-
-    impl KmsProvider for MockKmsProvider {
-        fn get_kek(&self) -> anyhow::Result<(KekId, Vec<u8>)> {
-            *self.call_count.lock().unwrap() += 1;
-            Ok((KekId(self.kek_id.clone()), self.kek.clone()))
-        }
-
-        fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<Vec<u8>> {
-            if id == &KekId(self.kek_id.clone()) {
-                Ok(self.kek.clone())
-            } else {
-                anyhow::bail!("KEK not found")
-            }
-        }
-    }
+    use crate::{
+        crypto::keys::KekId,
+        kms::{
+            KekBytes,
+            local::DeviceKeyProvider,
+            mock::{Call, MockKmsProvider},
+        },
+    };
 
     #[test]
     fn test_wrap_unwrap_round_trip() {
-        let provider = MockKmsProvider::new_default();
+        let provider = MockKmsProvider::new();
         let dek = Dek::generate();
 
         let wrapped = wrap_dek(&dek, &provider).unwrap();
@@ -103,17 +118,18 @@ mod tests {
 
     #[test]
     fn test_wrap_sets_kek_id() {
-        let provider = MockKmsProvider::new(vec![0xBBu8; 32], "kek-v2");
+        let provider = MockKmsProvider::new();
+        let v2 = provider.rotate();
         let dek = Dek::generate();
 
         let wrapped = wrap_dek(&dek, &provider).unwrap();
 
-        assert_eq!(wrapped.kek_id, KekId("kek-v2".to_string()));
+        assert_eq!(wrapped.kek_id, v2);
     }
 
     #[test]
     fn test_wrap_produces_ciphertext() {
-        let provider = MockKmsProvider::new_default();
+        let provider = MockKmsProvider::new();
         let dek = Dek::generate();
 
         let wrapped = wrap_dek(&dek, &provider).unwrap();
@@ -126,7 +142,7 @@ mod tests {
 
     #[test]
     fn test_wrap_uses_random_nonce() {
-        let provider = MockKmsProvider::new_default();
+        let provider = MockKmsProvider::new();
         let dek = Dek::generate();
 
         let wrapped1 = wrap_dek(&dek, &provider).unwrap();
@@ -140,13 +156,13 @@ mod tests {
 
     #[test]
     fn test_unwrap_with_wrong_kek_fails() {
-        let provider1 = MockKmsProvider::new(vec![0xAAu8; 32], "kek-v1");
+        let provider1 = MockKmsProvider::new().with_kek([0xAA; 32]);
         let dek = Dek::generate();
 
         let wrapped = wrap_dek(&dek, &provider1).unwrap();
 
         // Try to unwrap with different KEK
-        let provider2 = MockKmsProvider::new(vec![0xBBu8; 32], "kek-v1");
+        let provider2 = MockKmsProvider::new().with_kek([0xBB; 32]);
         let result = unwrap_dek(&wrapped, &provider2);
 
         assert!(result.is_err());
@@ -154,13 +170,14 @@ mod tests {
 
     #[test]
     fn test_unwrap_with_missing_kek_fails() {
-        let provider1 = MockKmsProvider::new(vec![0xCCu8; 32], "kek-v1");
+        let provider1 = MockKmsProvider::new();
+        provider1.rotate();
         let dek = Dek::generate();
 
         let wrapped = wrap_dek(&dek, &provider1).unwrap();
 
         // Try to unwrap with provider that doesn't have this KEK
-        let provider2 = MockKmsProvider::new(vec![0xDDu8; 32], "kek-v2");
+        let provider2 = MockKmsProvider::new();
         let result = unwrap_dek(&wrapped, &provider2);
 
         assert!(result.is_err());
@@ -168,7 +185,7 @@ mod tests {
 
     #[test]
     fn test_unwrap_tampered_ciphertext_fails() {
-        let provider = MockKmsProvider::new_default();
+        let provider = MockKmsProvider::new();
         let dek = Dek::generate();
 
         let mut wrapped = wrap_dek(&dek, &provider).unwrap();
@@ -185,15 +202,15 @@ mod tests {
         struct BadKmsProvider;
 
         impl KmsProvider for BadKmsProvider {
-            fn get_kek(&self) -> anyhow::Result<(KekId, Vec<u8>)> {
+            fn get_kek(&self) -> anyhow::Result<(KekId, KekBytes)> {
                 Ok((
                     KekId("bad".to_string()),
-                    vec![0xAAu8; 16], // Wrong length
+                    vec![0xAAu8; 16].into(), // Wrong length
                 ))
             }
 
-            fn get_kek_by_id(&self, _id: &KekId) -> anyhow::Result<Vec<u8>> {
-                Ok(vec![0xAAu8; 16])
+            fn get_kek_by_id(&self, _id: &KekId) -> anyhow::Result<KekBytes> {
+                Ok(vec![0xAAu8; 16].into())
             }
         }
 
@@ -206,7 +223,7 @@ mod tests {
 
     #[test]
     fn test_multiple_deks_different_wrappings() {
-        let provider = MockKmsProvider::new_default();
+        let provider = MockKmsProvider::new();
         let dek1 = Dek::generate();
         let dek2 = Dek::generate();
 
@@ -224,7 +241,7 @@ mod tests {
 
     #[test]
     fn test_wrapped_dek_structure() {
-        let provider = MockKmsProvider::new_default();
+        let provider = MockKmsProvider::new();
         let dek = Dek::generate();
 
         let wrapped = wrap_dek(&dek, &provider).unwrap();
@@ -232,12 +249,25 @@ mod tests {
         // Verify structure
         assert_eq!(wrapped.nonce.len(), 12);
         assert_eq!(wrapped.ciphertext.len(), 48); // 32 + 16 tag
-        assert_eq!(wrapped.kek_id, KekId("kek-v1".to_string()));
+        assert_eq!(wrapped.kek_id, KekId("mock-v1".to_string()));
+    }
+
+    #[test]
+    fn test_wrap_records_algorithm() {
+        let provider = MockKmsProvider::new();
+        let dek = Dek::generate_for(Algorithm::XChaCha20Poly1305);
+
+        let wrapped = wrap_dek(&dek, &provider).unwrap();
+        assert_eq!(wrapped.ciphertext.len(), 33 + 16);
+
+        let unwrapped = unwrap_dek(&wrapped, &provider).unwrap();
+        assert_eq!(unwrapped.algorithm(), Algorithm::XChaCha20Poly1305);
+        assert_eq!(dek, unwrapped);
     }
 
     #[test]
     fn test_unwrap_invalid_plaintext_length() {
-        let provider = MockKmsProvider::new_default();
+        let provider = MockKmsProvider::new().with_kek([0xBB; 32]);
 
         // Create a wrapped DEK with wrong plaintext length
         let short_plaintext = vec![0xAAu8; 16]; // Should be 32
@@ -249,7 +279,7 @@ mod tests {
         let wrapped = WrappedDek {
             ciphertext,
             nonce,
-            kek_id: KekId("kek-v1".to_string()),
+            kek_id: KekId("mock-v1".to_string()),
         };
 
         let result = unwrap_dek(&wrapped, &provider);
@@ -258,7 +288,7 @@ mod tests {
 
     #[test]
     fn test_same_dek_same_nonce_deterministic() {
-        let provider = MockKmsProvider::new_default();
+        let provider = MockKmsProvider::new();
         let dek = Dek::generate();
 
         // Wrap twice and verify nonces are different (due to randomness)
@@ -272,4 +302,68 @@ mod tests {
             unwrap_dek(&w2, &provider).unwrap()
         );
     }
+
+    #[test]
+    fn test_direct_wrap_round_trip() {
+        let provider = MockKmsProvider::new().with_direct_wrap();
+        let dek = Dek::generate_for(Algorithm::XChaCha20Poly1305);
+
+        let wrapped = wrap_dek(&dek, &provider).unwrap();
+        assert!(wrapped.is_direct());
+        assert_eq!(provider.calls(Call::WrapBlob), 1);
+        assert_eq!(provider.calls(Call::GetKek), 0);
+
+        // The KMS finds the key version itself, even after a rotation.
+        provider.rotate();
+        assert_eq!(unwrap_dek(&wrapped, &provider).unwrap(), dek);
+        assert_eq!(provider.calls(Call::UnwrapBlob), 1);
+        assert_eq!(provider.calls(Call::GetKekById), 0);
+    }
+
+    #[test]
+    fn test_direct_wrap_rejected_by_local_only_provider() {
+        let provider = MockKmsProvider::new().with_direct_wrap();
+        let wrapped = wrap_dek(&Dek::generate(), &provider).unwrap();
+
+        let local = DeviceKeyProvider::from_passphrase("test");
+        let err = unwrap_dek(&wrapped, &local).unwrap_err().to_string();
+        assert!(
+            err.contains("cannot unwrap a DEK the KMS wrapped itself"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_local_wrap_unwraps_with_direct_provider() {
+        let local = MockKmsProvider::new().with_kek([0xAA; 32]);
+        let dek = Dek::generate();
+        let wrapped = wrap_dek(&dek, &local).unwrap();
+        assert!(!wrapped.is_direct());
+
+        let direct = MockKmsProvider::new()
+            .with_kek([0xAA; 32])
+            .with_direct_wrap();
+        assert_eq!(unwrap_dek(&wrapped, &direct).unwrap(), dek);
+        assert_eq!(direct.calls(Call::GetKekById), 1);
+    }
+
+    #[test]
+    fn test_is_current_follows_how_the_provider_wraps() {
+        let local = MockKmsProvider::new().with_kek([0xAA; 32]);
+        let direct = MockKmsProvider::new()
+            .with_kek([0xAA; 32])
+            .with_direct_wrap();
+        let locally = wrap_dek(&Dek::generate(), &local).unwrap();
+        let directly = wrap_dek(&Dek::generate(), &direct).unwrap();
+
+        assert!(is_current(&locally, &local).unwrap());
+        assert!(!is_current(&locally, &direct).unwrap());
+        assert!(is_current(&directly, &direct).unwrap());
+        assert!(!is_current(&directly, &local).unwrap());
+
+        direct.rotate();
+        assert!(is_current(&directly, &direct).unwrap());
+        local.rotate();
+        assert!(!is_current(&locally, &local).unwrap());
+    }
 }
@@ -1,11 +1,88 @@
-use std::fmt;
+use std::{
+    fmt,
+    sync::{Arc, OnceLock},
+};
 
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use chacha20poly1305::XChaCha20Poly1305;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// AEAD algorithm a DEK encrypts pages with.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq, bincode::Encode, bincode::Decode)]
+pub enum Algorithm {
+    /// AES-256-GCM with a 96-bit random nonce.
+    #[default]
+    Aes256Gcm,
+    /// XChaCha20-Poly1305 with a 192-bit random nonce. Faster than
+    /// AES-GCM without AES hardware, and its nonce is long enough that
+    /// random ones never realistically collide.
+    XChaCha20Poly1305,
+}
+
+impl Algorithm {
+    /// Id stored in page markers and wrapped DEKs.
+    pub fn id(self) -> u8 {
+        match self {
+            Algorithm::Aes256Gcm => 0,
+            Algorithm::XChaCha20Poly1305 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Algorithm::Aes256Gcm),
+            1 => Some(Algorithm::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Algorithm::Aes256Gcm => 12,
+            Algorithm::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Algorithm::Aes256Gcm => "aes-256-gcm",
+            Algorithm::XChaCha20Poly1305 => "xchacha20-poly1305",
+        })
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "aes-256-gcm" => Ok(Algorithm::Aes256Gcm),
+            "xchacha20-poly1305" => Ok(Algorithm::XChaCha20Poly1305),
+            other => anyhow::bail!("unknown algorithm {other:?}"),
+        }
+    }
+}
 
 /// A 256-bit data encryption key. Zeroized on drop.
-#[derive(Clone, Zeroize, ZeroizeOnDrop, PartialEq, Eq)]
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
 pub struct Dek {
     bytes: [u8; 32],
+    /// Algorithm pages are encrypted with. Pages record theirs, so any
+    /// are decrypted whatever this is.
+    #[zeroize(skip)]
+    algorithm: Algorithm,
+    /// Ciphers for the key, each set up on first use and shared by
+    /// clones. Their key schedules are zeroized when the last one drops.
+    #[zeroize(skip)]
+    ciphers: Arc<Ciphers>,
+}
+
+#[derive(Default)]
+struct Ciphers {
+    aes_gcm: OnceLock<Aes256Gcm>,
+    xchacha: OnceLock<XChaCha20Poly1305>,
 }
 
 /// A wrapped (ciphertext) DEK - safe to persist to disk.
@@ -13,10 +90,42 @@ pub struct Dek {
 pub struct WrappedDek {
     pub ciphertext: Vec<u8>,
     pub nonce: [u8; 12],
-    /// Identifies which KEK wrapped this DEK.
+    /// Identifies which KEK wrapped this DEK, or is
+    /// [`Self::DIRECT_KEK_ID`] for one the KMS wrapped itself.
     pub kek_id: KekId,
 }
 
+impl WrappedDek {
+    /// The `kek_id` of a DEK wrapped by `KmsProvider::wrap_blob`,
+    /// whose `ciphertext` is the KMS's blob and whose `nonce` is unused.
+    pub const DIRECT_KEK_ID: &str = "kms:direct";
+
+    /// Whether the KMS wrapped this DEK itself, rather than it being
+    /// wrapped locally under a KEK the KMS handed out.
+    pub fn is_direct(&self) -> bool {
+        self.kek_id.0 == Self::DIRECT_KEK_ID
+    }
+}
+
+/// Random identifier of an encrypted database, bound into its pages
+/// so they don't decrypt in another database, even under the same DEK.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct DatabaseId(pub [u8; 16]);
+
+impl DatabaseId {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 16];
+        getrandom::fill(&mut bytes).expect("getrandom failed");
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for DatabaseId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
 /// Opaque KEK identifier.
 #[derive(Clone, Debug, Hash, Eq, PartialEq, bincode::Encode, bincode::Decode)]
 pub struct KekId(pub String);
@@ -30,24 +139,67 @@ pub enum KeyScope {
     Table(String),
     /// Single column.
     Column { table: String, column: String },
+    /// Whole database, under a named key chosen when it is opened
+    /// (the `evfs_scope` URI parameter).
+    Named(String),
 }
 
 impl Dek {
+    /// Generate an AES-256-GCM key.
     pub fn generate() -> Self {
-        let mut bytes = [0u8; 32];
-        getrandom::fill(&mut bytes).expect("getrandom failed");
-        Self { bytes }
+        Self::generate_for(Algorithm::default())
+    }
+
+    pub fn generate_for(algorithm: Algorithm) -> Self {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        getrandom::fill(&mut *bytes).expect("getrandom failed");
+        Self::from_bytes_for(*bytes, algorithm)
     }
 
+    /// An AES-256-GCM key from raw bytes.
     pub fn from_bytes(bytes: [u8; 32]) -> Self {
-        Self { bytes }
+        Self::from_bytes_for(bytes, Algorithm::default())
+    }
+
+    pub fn from_bytes_for(bytes: [u8; 32], algorithm: Algorithm) -> Self {
+        Self {
+            bytes,
+            algorithm,
+            ciphers: Arc::default(),
+        }
     }
 
     pub fn as_bytes(&self) -> &[u8; 32] {
         &self.bytes
     }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The AES-GCM cipher keyed with this DEK.
+    pub fn aes_gcm(&self) -> &Aes256Gcm {
+        self.ciphers
+            .aes_gcm
+            .get_or_init(|| Aes256Gcm::new(&self.bytes.into()))
+    }
+
+    /// The XChaCha20-Poly1305 cipher keyed with this DEK.
+    pub fn xchacha(&self) -> &XChaCha20Poly1305 {
+        self.ciphers
+            .xchacha
+            .get_or_init(|| XChaCha20Poly1305::new(&self.bytes.into()))
+    }
 }
 
+impl PartialEq for Dek {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes && self.algorithm == other.algorithm
+    }
+}
+
+impl Eq for Dek {}
+
 impl fmt::Debug for Dek {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("Dek(***)")
@@ -62,6 +214,28 @@ impl fmt::Display for KeyScope {
             KeyScope::Column { table, column } => {
                 write!(f, "column:{table}.{column}")
             }
+            KeyScope::Named(name) => write!(f, "scope:{name}"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::mem::ManuallyDrop;
+
+    use super::*;
+
+    #[test]
+    fn dek_is_zeroized_on_drop() {
+        // Best effort: drop the key in place, where its storage stays
+        // ours to read, and check what the drop left there.
+        let mut dek = ManuallyDrop::new(Dek::from_bytes([0x5A; 32]));
+        let _ = dek.aes_gcm();
+        let dek: *mut Dek = &mut *dek;
+        let after = unsafe {
+            std::ptr::drop_in_place(dek);
+            std::ptr::read_volatile(&raw const (*dek).bytes)
+        };
+        assert_eq!(after, [0; 32]);
+    }
+}
@@ -1,99 +1,487 @@
-use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
-
-use super::keys::Dek;
+use aes_gcm::{Nonce, Tag, aead::AeadInPlace};
+use chacha20poly1305::XNonce;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use super::keys::{Algorithm, DatabaseId, Dek};
+
+// Reserved bytes at the end of an encrypted page:
+//
+//   payload | tag (16) | marker (6) | nonce (12 or 24) | commitment (32) | spare
+//
+// The marker is `EVFS`, a format version byte and a flags byte, and
+// sits at the same offset in every format so any page can be
+// recognised. The low bits of the flags byte are the id of the AEAD
+// algorithm the page was encrypted with, which sets the nonce length,
+// so pages are decrypted with whichever algorithm wrote them. A page
+// carrying `EVFS` there is always decrypted, so one in a format, with
+// flags or under an algorithm this build doesn't know is an error
+// rather than plaintext. The nonce is random per write and the page
+// number is bound in as AAD, so a page only decrypts where it was
+// written, and relocating or rewriting it never reuses a nonce. Pages
+// of a database with an ID also bind that, and set
+// [`DATABASE_ID_FLAG`] to say so, so a page doesn't decrypt in another
+// database that shares its DEK. Pages without the flag were written
+// before the database had an ID.
+//
+// Neither AEAD commits to its key: a ciphertext can be crafted that
+// authenticates under two keys, e.g. a page's DEK and one swapped in
+// through a backup's unauthenticated header. So a page isn't encrypted
+// under the DEK itself but under a key derived from it and the nonce
+// with HKDF-SHA256, which also derives a commitment to the DEK that is
+// stored after the nonce and checked before the page is decrypted.
+//
+// Older pages are still read, and are rewritten in the current format:
+//
+// - Format 3 pages carry no commitment and are encrypted under the DEK
+//   itself. They are still written to databases whose reserve has no
+//   room for the commitment, which was created before it was added and
+//   keeps its reserve until migrated to a larger one. Only there are
+//   they read: where the reserve holds a commitment, a format 3 page
+//   could only be one relabelled to skip the check, and fails to
+//   authenticate.
+// - `EVFSv2` pages are AES-256-GCM pages laid out as current ones, with
+//   `v` and an ASCII version digit in place of the version and flags
+//   bytes. They carry no commitment either, and are refused as format
+//   3 pages are where the reserve holds one.
+// - `EVFSv1` pages derived their nonce from the page number, carry no
+//   nonce field and bind no AAD, so one can be moved between databases
+//   sharing a DEK. They are only read when `EVFS_LEGACY_PAGES=1` is
+//   set, or from a version 1 backup, which holds nothing else.
+// - Pages written before the marker existed are `EVFSv1` pages with
+//   nothing after the tag. Nothing tells them apart from plaintext, so
+//   they are only tried with [`decrypt_unmarked_page`], which the VFS
+//   does when `EVFS_LEGACY_PAGES=1` is set.
 
 pub const TAG_LEN: usize = 16;
-pub const MARKER: &[u8; 6] = b"EVFSv1";
+pub const MARKER_PREFIX: &[u8; 4] = b"EVFS";
 pub const MARKER_LEN: usize = 6;
+/// Page format written by [`encrypt_page`]: random nonce and key
+/// commitment in the reserve, and a flags byte in the marker.
+pub const PAGE_FORMAT: u8 = 4;
+/// Page format of pages without a key commitment, encrypted under the
+/// DEK itself.
+pub const UNCOMMITTED_PAGE_FORMAT: u8 = 3;
+/// `EVFSv2` page format: as [`UNCOMMITTED_PAGE_FORMAT`], without the
+/// flags byte.
+pub const V2_PAGE_FORMAT: u8 = 2;
+/// Legacy `EVFSv1` page format: nonce derived from the page number.
+pub const LEGACY_PAGE_FORMAT: u8 = 1;
+/// Bits of the marker's flags byte holding the page's algorithm id
+/// ([`Algorithm::id`]).
+pub const ALGORITHM_MASK: u8 = 0x0F;
+/// Flag of pages whose AAD binds their database's ID.
+pub const DATABASE_ID_FLAG: u8 = 0x10;
+/// Flags a page may carry in its marker besides its algorithm. A page
+/// with any other set is refused.
+pub const KNOWN_PAGE_FLAGS: u8 = DATABASE_ID_FLAG;
+/// Environment variable that makes the VFS read `EVFSv1` pages, and
+/// try unmarked pages as ones written before the marker existed.
+pub const LEGACY_PAGES_ENV: &str = "EVFS_LEGACY_PAGES";
+/// Nonce length of AES-256-GCM, and of every page before algorithms
+/// were selectable.
+pub const NONCE_LEN: usize = 12;
+/// Longest nonce of any algorithm (XChaCha20-Poly1305's).
+pub const MAX_NONCE_LEN: usize = 24;
+/// Length of the key commitment stored after the nonce.
+pub const COMMITMENT_LEN: usize = 32;
+/// Smallest reserve that holds the tag, marker and an AES-256-GCM
+/// nonce, which is the least any encrypted page needs.
+pub const MIN_RESERVE: usize = TAG_LEN + MARKER_LEN + NONCE_LEN;
+
+/// Smallest reserve that holds the tag, marker, nonce and key
+/// commitment of pages encrypted with `algorithm`.
+pub fn min_reserve(algorithm: Algorithm) -> usize {
+    min_uncommitted_reserve(algorithm) + COMMITMENT_LEN
+}
+
+/// Smallest reserve that holds pages encrypted with `algorithm`
+/// without a key commitment, as they are where [`min_reserve`] doesn't
+/// fit.
+pub fn min_uncommitted_reserve(algorithm: Algorithm) -> usize {
+    TAG_LEN + MARKER_LEN + algorithm.nonce_len()
+}
 
-fn ensure_reserve(reserve: usize) -> anyhow::Result<()> {
+fn ensure_reserve(reserve: usize, needed: usize, what: &str) -> anyhow::Result<()> {
     anyhow::ensure!(
-        reserve >= TAG_LEN + MARKER_LEN,
-        "reserve ({reserve}) must be >= {} (tag+marker)",
-        TAG_LEN + MARKER_LEN
+        reserve >= needed,
+        "reserve ({reserve}) must be >= {needed} ({what})"
     );
     Ok(())
 }
 
-pub fn is_encrypted_page(page: &[u8], reserve: usize) -> bool {
+/// The page's marker, if it carries one.
+fn marker(page: &[u8], reserve: usize) -> Option<&[u8]> {
     if reserve < TAG_LEN + MARKER_LEN || page.len() < reserve {
-        return false;
+        return None;
     }
-    let payload_len = page.len() - reserve;
-    let mr = (payload_len + TAG_LEN)..(payload_len + TAG_LEN + MARKER_LEN);
-    page.get(mr) == Some(MARKER.as_slice())
+    let marker = page.get(marker_range(page.len() - reserve))?;
+    marker.starts_with(MARKER_PREFIX).then_some(marker)
+}
+
+/// How an encrypted page was written, from its marker.
+struct PageKind {
+    format: u8,
+    algorithm: Algorithm,
+    flags: u8,
+}
+
+/// How an encrypted page was written, `None` if the page carries no
+/// marker, or an error if its format, flags or algorithm are unknown.
+fn page_kind(page: &[u8], reserve: usize) -> anyhow::Result<Option<PageKind>> {
+    let Some(marker) = marker(page, reserve) else {
+        return Ok(None);
+    };
+    let unflagged = |format| {
+        Ok(Some(PageKind {
+            format,
+            algorithm: Algorithm::Aes256Gcm,
+            flags: 0,
+        }))
+    };
+    match (marker[4], marker[5]) {
+        (b'v', b'1') => unflagged(LEGACY_PAGE_FORMAT),
+        (b'v', b'2') => unflagged(V2_PAGE_FORMAT),
+        (b'v', v) => anyhow::bail!("unknown page format EVFSv{}", v.escape_ascii()),
+        (format @ (UNCOMMITTED_PAGE_FORMAT | PAGE_FORMAT), flags) => {
+            anyhow::ensure!(
+                flags & !ALGORITHM_MASK & !KNOWN_PAGE_FLAGS == 0,
+                "unknown page flags {flags:#04x}"
+            );
+            let id = flags & ALGORITHM_MASK;
+            let algorithm = Algorithm::from_id(id)
+                .ok_or_else(|| anyhow::anyhow!("unknown page algorithm id {id}"))?;
+            Ok(Some(PageKind {
+                format,
+                algorithm,
+                flags: flags & !ALGORITHM_MASK,
+            }))
+        }
+        (v, _) => anyhow::bail!("unknown page format version {v}"),
+    }
+}
+
+/// Format version of an encrypted page, `None` if the page carries no
+/// marker, or an error if its format, flags or algorithm are unknown.
+pub fn page_format(page: &[u8], reserve: usize) -> anyhow::Result<Option<u8>> {
+    Ok(page_kind(page, reserve)?.map(|kind| kind.format))
+}
+
+/// Algorithm an encrypted page was written with, `None` if the page
+/// carries no marker, or an error as from [`page_format`].
+pub fn page_algorithm(page: &[u8], reserve: usize) -> anyhow::Result<Option<Algorithm>> {
+    Ok(page_kind(page, reserve)?.map(|kind| kind.algorithm))
+}
+
+/// Whether an encrypted page binds its database's ID, `None` if the
+/// page carries no marker, or an error as from [`page_format`].
+pub fn page_binds_database_id(page: &[u8], reserve: usize) -> anyhow::Result<Option<bool>> {
+    Ok(page_kind(page, reserve)?.map(|kind| kind.flags & DATABASE_ID_FLAG != 0))
+}
+
+/// Whether the page carries an EVFS marker, in a known format or not.
+pub fn is_encrypted_page(page: &[u8], reserve: usize) -> bool {
+    marker(page, reserve).is_some()
+}
+
+/// Whether `EVFSv1` pages are read, and the VFS should try unmarked
+/// pages with [`decrypt_unmarked_page`], per [`LEGACY_PAGES_ENV`].
+pub fn legacy_pages_enabled() -> bool {
+    std::env::var_os(LEGACY_PAGES_ENV).is_some_and(|v| v == "1")
 }
 
 fn marker_range(payload_len: usize) -> std::ops::Range<usize> {
     (payload_len + TAG_LEN)..(payload_len + TAG_LEN + MARKER_LEN)
 }
 
-/// Encrypt a database page in place.
+fn nonce_range(payload_len: usize, algorithm: Algorithm) -> std::ops::Range<usize> {
+    let start = payload_len + TAG_LEN + MARKER_LEN;
+    start..start + algorithm.nonce_len()
+}
+
+fn commitment_range(payload_len: usize, algorithm: Algorithm) -> std::ops::Range<usize> {
+    let start = nonce_range(payload_len, algorithm).end;
+    start..start + COMMITMENT_LEN
+}
+
+/// Key a page is encrypted under, and the commitment to `dek` stored
+/// with it, derived from `dek` and the page's nonce.
+fn page_key(dek: &Dek, algorithm: Algorithm, nonce: &[u8]) -> (Dek, [u8; COMMITMENT_LEN]) {
+    let hkdf = Hkdf::<Sha256>::new(Some(nonce), dek.as_bytes());
+    let mut key = Zeroizing::new([0u8; 32]);
+    let mut commitment = [0u8; COMMITMENT_LEN];
+    // Both lengths are far below HKDF-SHA256's limit.
+    hkdf.expand_multi_info(&[b"evfs page key ", &[algorithm.id()]], key.as_mut())
+        .expect("HKDF output length");
+    hkdf.expand_multi_info(
+        &[b"evfs page commitment ", &[algorithm.id()]],
+        &mut commitment,
+    )
+    .expect("HKDF output length");
+    (Dek::from_bytes_for(*key, algorithm), commitment)
+}
+
+/// AAD of a page: its number, then its database's ID if bound.
+fn page_aad(page_no: u32, database_id: Option<DatabaseId>) -> Vec<u8> {
+    let mut aad = page_no.to_le_bytes().to_vec();
+    if let Some(id) = database_id {
+        aad.extend_from_slice(&id.0);
+    }
+    aad
+}
+
+/// Encrypt a database page in place, with the DEK's algorithm, bound
+/// to `page_no` and to `database_id` if the database has one. The page
+/// commits to the DEK if `reserve` holds [`min_reserve`] bytes.
 pub fn encrypt_page(
     page: &mut [u8],
     page_no: u32,
+    database_id: Option<DatabaseId>,
+    dek: &Dek,
+    reserve: usize,
+) -> anyhow::Result<()> {
+    encrypt_page_with_nonce(page, page_no, database_id, dek, reserve, rand_nonce())
+}
+
+/// Encrypt a database page in place under the leading bytes of
+/// `nonce_bytes`, which must never have been used with `dek` before.
+pub(crate) fn encrypt_page_with_nonce(
+    page: &mut [u8],
+    page_no: u32,
+    database_id: Option<DatabaseId>,
     dek: &Dek,
     reserve: usize,
+    nonce_bytes: [u8; MAX_NONCE_LEN],
 ) -> anyhow::Result<()> {
-    ensure_reserve(reserve)?;
+    let algorithm = dek.algorithm();
+    ensure_reserve(
+        reserve,
+        min_uncommitted_reserve(algorithm),
+        "tag+marker+nonce",
+    )?;
     let page_len = page.len();
     let payload_len = page_len - reserve;
+    let nonce = &nonce_bytes[..algorithm.nonce_len()];
+    let committed = reserve >= min_reserve(algorithm);
+    let page_key = committed.then(|| page_key(dek, algorithm, nonce));
+    let key = page_key.as_ref().map_or(dek, |(key, _)| key);
+
+    // Encrypt the payload portion in place, so no copy of the
+    // plaintext is left behind, bound to the page number.
+    let aad = page_aad(page_no, database_id);
+    let payload = &mut page[..payload_len];
+    let tag = match algorithm {
+        Algorithm::Aes256Gcm => {
+            key.aes_gcm()
+                .encrypt_in_place_detached(Nonce::from_slice(nonce), &aad, payload)
+        }
+        Algorithm::XChaCha20Poly1305 => {
+            key.xchacha()
+                .encrypt_in_place_detached(XNonce::from_slice(nonce), &aad, payload)
+        }
+    }
+    .map_err(|e| anyhow::anyhow!("page encrypt failed: {e}"))?;
+    page[payload_len..payload_len + TAG_LEN].copy_from_slice(&tag);
 
-    let nonce_bytes = page_nonce(page_no);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    let cipher = Aes256Gcm::new_from_slice(dek.as_bytes())?;
+    // Write marker and nonce after tag.
+    let mr = marker_range(payload_len);
+    page[mr.start..mr.start + MARKER_PREFIX.len()].copy_from_slice(MARKER_PREFIX);
+    page[mr.end - 2] = if committed {
+        PAGE_FORMAT
+    } else {
+        UNCOMMITTED_PAGE_FORMAT
+    };
+    page[mr.end - 1] = algorithm.id()
+        | if database_id.is_some() {
+            DATABASE_ID_FLAG
+        } else {
+            0
+        };
+    page[nonce_range(payload_len, algorithm)].copy_from_slice(nonce);
+    if let Some((_, commitment)) = page_key {
+        page[commitment_range(payload_len, algorithm)].copy_from_slice(&commitment);
+    }
 
-    // Encrypt the payload portion only.
-    let ciphertext = cipher
-        .encrypt(nonce, &page[..payload_len])
-        .map_err(|e| anyhow::anyhow!("page encrypt failed: {e}"))?;
+    Ok(())
+}
 
-    // ciphertext = encrypted_payload || tag
-    let ct_len = ciphertext.len() - TAG_LEN;
-    debug_assert_eq!(ct_len, payload_len);
+/// A page failed to authenticate: it was encrypted under another key,
+/// at another page number or in another database, or was altered. The
+/// VFS reports this as `SQLITE_IOERR_AUTH`.
+#[derive(Debug)]
+pub struct AuthenticationFailed {
+    pub reason: &'static str,
+}
 
-    page[..ct_len].copy_from_slice(&ciphertext[..ct_len]);
-    page[payload_len..payload_len + TAG_LEN].copy_from_slice(&ciphertext[ct_len..]);
+impl std::fmt::Display for AuthenticationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "authentication failed: {} (wrong key?)", self.reason)
+    }
+}
 
-    // Write marker after tag.
-    let mr = marker_range(payload_len);
-    page[mr].copy_from_slice(MARKER);
+impl std::error::Error for AuthenticationFailed {}
 
-    Ok(())
+/// Decrypt a database page in place, with the algorithm its marker
+/// names whatever the DEK's own. `database_id` is only used if the
+/// page binds it, and one that does fails without it. A page that
+/// commits to a DEK other than `dek` fails before it is decrypted,
+/// and an `EVFSv1` page fails unless [`legacy_pages_enabled`].
+pub fn decrypt_page(
+    page: &mut [u8],
+    page_no: u32,
+    database_id: Option<DatabaseId>,
+    dek: &Dek,
+    reserve: usize,
+) -> anyhow::Result<()> {
+    decrypt_page_with(
+        page,
+        page_no,
+        database_id,
+        dek,
+        reserve,
+        legacy_pages_enabled,
+    )
 }
 
-/// Decrypt a database page in place.
-pub fn decrypt_page(
+/// [`decrypt_page`], reading an `EVFSv1` page only if `legacy_pages`,
+/// which is only called when the page is one.
+pub(crate) fn decrypt_page_with(
     page: &mut [u8],
     page_no: u32,
+    database_id: Option<DatabaseId>,
     dek: &Dek,
     reserve: usize,
+    legacy_pages: impl FnOnce() -> bool,
 ) -> anyhow::Result<()> {
-    ensure_reserve(reserve)?;
-    let page_len = page.len();
-    let payload_len = page_len - reserve;
+    ensure_reserve(reserve, TAG_LEN + MARKER_LEN, "tag+marker")?;
+    let payload_len = page.len() - reserve;
 
     // Verify marker before attempting AEAD decrypt.
-    let mr = marker_range(payload_len);
-    anyhow::ensure!(
-        page.get(mr.clone()) == Some(MARKER.as_slice()),
-        "missing EVFS marker"
-    );
+    match page_kind(page, reserve)? {
+        // Legacy pages were bound to their page number by the nonce alone.
+        Some(PageKind {
+            format: LEGACY_PAGE_FORMAT,
+            ..
+        }) => {
+            anyhow::ensure!(
+                legacy_pages(),
+                "EVFSv1 page, which is only read with {LEGACY_PAGES_ENV}=1"
+            );
+            open_payload(
+                page,
+                payload_len,
+                Algorithm::Aes256Gcm,
+                &legacy_page_nonce(page_no),
+                &[],
+                dek,
+            )
+        }
+        Some(PageKind {
+            format: PAGE_FORMAT,
+            algorithm,
+            flags,
+        }) => {
+            ensure_reserve(
+                reserve,
+                min_reserve(algorithm),
+                "tag+marker+nonce+commitment",
+            )?;
+            let nonce = &page[nonce_range(payload_len, algorithm)];
+            let (key, commitment) = page_key(dek, algorithm, nonce);
+            if !bool::from(commitment.ct_eq(&page[commitment_range(payload_len, algorithm)])) {
+                return Err(AuthenticationFailed {
+                    reason: "page commits to a different key",
+                }
+                .into());
+            }
+            let nonce = nonce.to_vec();
+            let aad = page_aad(page_no, bound_database_id(flags, database_id)?);
+            open_payload(page, payload_len, algorithm, &nonce, &aad, &key)
+        }
+        Some(PageKind {
+            format: UNCOMMITTED_PAGE_FORMAT | V2_PAGE_FORMAT,
+            algorithm,
+            ..
+        }) if reserve >= min_reserve(algorithm) => Err(AuthenticationFailed {
+            reason: "page has no key commitment where the reserve holds one",
+        }
+        .into()),
+        Some(PageKind {
+            algorithm, flags, ..
+        }) => {
+            ensure_reserve(
+                reserve,
+                min_uncommitted_reserve(algorithm),
+                "tag+marker+nonce",
+            )?;
+            let nonce = page[nonce_range(payload_len, algorithm)].to_vec();
+            let aad = page_aad(page_no, bound_database_id(flags, database_id)?);
+            open_payload(page, payload_len, algorithm, &nonce, &aad, dek)
+        }
+        None => anyhow::bail!("missing EVFS marker"),
+    }
+}
 
-    let nonce_bytes = page_nonce(page_no);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    let cipher = Aes256Gcm::new_from_slice(dek.as_bytes())?;
+/// The database ID a page with `flags` binds, which must be known if
+/// it binds one.
+fn bound_database_id(
+    flags: u8,
+    database_id: Option<DatabaseId>,
+) -> anyhow::Result<Option<DatabaseId>> {
+    if flags & DATABASE_ID_FLAG == 0 {
+        return Ok(None);
+    }
+    database_id
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("page is bound to a database ID, and none is known"))
+}
 
-    // Reassemble the ciphertext+tag buffer aes-gcm expects.
-    let mut buf = Vec::with_capacity(payload_len + TAG_LEN);
-    buf.extend_from_slice(&page[..payload_len]);
-    buf.extend_from_slice(&page[payload_len..payload_len + TAG_LEN]);
+/// Decrypt in place a page written before pages carried a marker. It
+/// has no marker to say whether it is encrypted, so a page that fails
+/// to authenticate is left untouched and may be plaintext.
+pub fn decrypt_unmarked_page(
+    page: &mut [u8],
+    page_no: u32,
+    dek: &Dek,
+    reserve: usize,
+) -> anyhow::Result<()> {
+    ensure_reserve(reserve, TAG_LEN, "tag")?;
+    let payload_len = page.len() - reserve;
+    let nonce = legacy_page_nonce(page_no);
+    open_payload(page, payload_len, Algorithm::Aes256Gcm, &nonce, &[], dek)
+}
 
-    let plaintext = cipher
-        .decrypt(nonce, buf.as_ref())
-        .map_err(|e| anyhow::anyhow!("page decrypt failed: {e}"))?;
+/// Authenticate and decrypt the payload and the tag after it, leaving
+/// the page untouched if that fails.
+fn open_payload(
+    page: &mut [u8],
+    payload_len: usize,
+    algorithm: Algorithm,
+    nonce: &[u8],
+    aad: &[u8],
+    dek: &Dek,
+) -> anyhow::Result<()> {
+    // Decrypt a copy, which AES-GCM fills with plaintext even when the
+    // tag doesn't match, and which is zeroized either way.
+    let mut buf = Zeroizing::new(page[..payload_len].to_vec());
+    let tag = Tag::from_slice(&page[payload_len..payload_len + TAG_LEN]);
+    match algorithm {
+        Algorithm::Aes256Gcm => {
+            dek.aes_gcm()
+                .decrypt_in_place_detached(Nonce::from_slice(nonce), aad, &mut buf, tag)
+        }
+        Algorithm::XChaCha20Poly1305 => {
+            dek.xchacha()
+                .decrypt_in_place_detached(XNonce::from_slice(nonce), aad, &mut buf, tag)
+        }
+    }
+    .map_err(|_| AuthenticationFailed {
+        reason: "tag mismatch",
+    })?;
 
-    page[..plaintext.len()].copy_from_slice(&plaintext);
+    page[..payload_len].copy_from_slice(&buf);
     // Zero out the tag area in the reserved region.
     page[payload_len..payload_len + TAG_LEN].fill(0);
     // Keep marker intact (it's in reserved bytes and helps detect encryption).
@@ -101,13 +489,37 @@ pub fn decrypt_page(
     Ok(())
 }
 
-/// Deterministic nonce from page number.
-fn page_nonce(page_no: u32) -> [u8; 12] {
-    let mut n = [0u8; 12];
+/// Fresh random nonce for each page write, long enough for any
+/// algorithm.
+pub(crate) fn rand_nonce() -> [u8; MAX_NONCE_LEN] {
+    let mut n = [0u8; MAX_NONCE_LEN];
+    getrandom::fill(&mut n).expect("getrandom failed");
+    n
+}
+
+/// Nonce of legacy `EVFSv1` pages, derived from the page number.
+fn legacy_page_nonce(page_no: u32) -> [u8; NONCE_LEN] {
+    let mut n = [0u8; NONCE_LEN];
     n[0..4].copy_from_slice(&page_no.to_le_bytes());
     n
 }
 
+/// Encrypt `page` in the legacy format, for testing that it still reads.
+#[cfg(test)]
+pub(crate) fn encrypt_legacy_page(page: &mut [u8], page_no: u32, dek: &Dek, reserve: usize) {
+    let payload_len = page.len() - reserve;
+    let tag = dek
+        .aes_gcm()
+        .encrypt_in_place_detached(
+            Nonce::from_slice(&legacy_page_nonce(page_no)),
+            &[],
+            &mut page[..payload_len],
+        )
+        .unwrap();
+    page[payload_len..payload_len + TAG_LEN].copy_from_slice(&tag);
+    page[marker_range(payload_len)].copy_from_slice(b"EVFSv1");
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,18 +528,18 @@ mod tests {
     #[test]
     fn round_trip() {
         let dek = Dek::generate();
-        let reserve = 32;
+        let reserve = 80;
         let page_size = 4096;
         let mut page = vec![0xABu8; page_size];
         let original = page.clone();
 
-        encrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
         assert_ne!(
             &page[..page_size - reserve],
             &original[..page_size - reserve]
         );
 
-        decrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        decrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
         assert_eq!(
             &page[..page_size - reserve],
             &original[..page_size - reserve]
@@ -137,18 +549,18 @@ mod tests {
     #[test]
     fn round_trip_basic() {
         let dek = Dek::generate();
-        let reserve = 32;
+        let reserve = 80;
         let page_size = 4096;
         let mut page = vec![0xABu8; page_size];
         let original = page.clone();
 
-        encrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
         assert_ne!(
             &page[..page_size - reserve],
             &original[..page_size - reserve]
         );
 
-        decrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        decrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
         assert_eq!(
             &page[..page_size - reserve],
             &original[..page_size - reserve]
@@ -156,20 +568,20 @@ mod tests {
     }
 
     #[test]
-    fn round_trip_reserve_equals_tag_len() {
+    fn round_trip_minimum_reserve() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 4096;
         let mut page = vec![0xCDu8; page_size];
         let original = page.clone();
 
-        encrypt_page(&mut page, 5, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 5, None, &dek, reserve).unwrap();
         assert_ne!(
             &page[..page_size - reserve],
             &original[..page_size - reserve]
         );
 
-        decrypt_page(&mut page, 5, &dek, reserve).unwrap();
+        decrypt_page(&mut page, 5, None, &dek, reserve).unwrap();
         assert_eq!(
             &page[..page_size - reserve],
             &original[..page_size - reserve]
@@ -179,12 +591,12 @@ mod tests {
     #[test]
     fn tag_placement() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 4096;
         let mut page = vec![0x42u8; page_size];
         let payload_len = page_size - reserve;
 
-        encrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
 
         // Tag should be at [payload_len..payload_len+TAG_LEN]
         let tag = &page[payload_len..payload_len + TAG_LEN];
@@ -195,14 +607,14 @@ mod tests {
     #[test]
     fn reserved_area_preserved_after_decrypt() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 4096;
         let mut page = vec![0xFFu8; page_size];
         let payload_len = page_size - reserve;
 
-        encrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
 
-        decrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        decrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
 
         // After decrypt, the tag area should be zeroed
         let reserved_after = page[payload_len..].to_vec();
@@ -216,65 +628,67 @@ mod tests {
     fn wrong_key_fails() {
         let dek1 = Dek::generate();
         let dek2 = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let mut page = vec![0xCDu8; 4096];
 
-        encrypt_page(&mut page, 1, &dek1, reserve).unwrap();
-        assert!(decrypt_page(&mut page, 1, &dek2, reserve).is_err());
+        encrypt_page(&mut page, 1, None, &dek1, reserve).unwrap();
+        let err = decrypt_page(&mut page, 1, None, &dek2, reserve).unwrap_err();
+        assert!(err.is::<AuthenticationFailed>(), "{err}");
     }
 
     #[test]
     fn wrong_page_no_fails() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let mut page = vec![0xEFu8; 4096];
 
-        encrypt_page(&mut page, 1, &dek, reserve).unwrap();
-        assert!(decrypt_page(&mut page, 2, &dek, reserve).is_err());
+        encrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
+        let err = decrypt_page(&mut page, 2, None, &dek, reserve).unwrap_err();
+        assert!(err.to_string().contains("tag mismatch"), "{err}");
     }
 
     #[test]
     fn tampered_ciphertext_fails() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 4096;
         let mut page = vec![0x55u8; page_size];
 
-        encrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
 
         // Tamper with the ciphertext
         page[100] ^= 0xFF;
 
-        assert!(decrypt_page(&mut page, 1, &dek, reserve).is_err());
+        assert!(decrypt_page(&mut page, 1, None, &dek, reserve).is_err());
     }
 
     #[test]
     fn tampered_tag_fails() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 4096;
         let mut page = vec![0x77u8; page_size];
         let payload_len = page_size - reserve;
 
-        encrypt_page(&mut page, 1, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 1, None, &dek, reserve).unwrap();
 
         // Tamper with the tag
         page[payload_len] ^= 0xFF;
 
-        assert!(decrypt_page(&mut page, 1, &dek, reserve).is_err());
+        assert!(decrypt_page(&mut page, 1, None, &dek, reserve).is_err());
     }
 
     #[test]
     fn different_page_numbers_produce_different_ciphertexts() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 4096;
 
         let mut page1 = vec![0x99u8; page_size];
         let mut page2 = page1.clone();
 
-        encrypt_page(&mut page1, 1, &dek, reserve).unwrap();
-        encrypt_page(&mut page2, 2, &dek, reserve).unwrap();
+        encrypt_page(&mut page1, 1, None, &dek, reserve).unwrap();
+        encrypt_page(&mut page2, 2, None, &dek, reserve).unwrap();
 
         // Different page numbers should produce different ciphertexts
         // (due to different nonces)
@@ -282,43 +696,48 @@ mod tests {
     }
 
     #[test]
-    fn same_page_number_same_plaintext_produces_same_ciphertext() {
+    fn same_page_number_same_plaintext_produces_different_ciphertext() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 4096;
 
         let mut page1 = vec![0x88u8; page_size];
         let mut page2 = page1.clone();
 
-        encrypt_page(&mut page1, 1, &dek, reserve).unwrap();
-        encrypt_page(&mut page2, 1, &dek, reserve).unwrap();
+        encrypt_page(&mut page1, 1, None, &dek, reserve).unwrap();
+        encrypt_page(&mut page2, 1, None, &dek, reserve).unwrap();
 
-        // Same page number and plaintext should produce identical ciphertext
-        assert_eq!(page1, page2);
+        // Every write draws a fresh nonce, so rewrites never reuse one
+        let payload_len = page_size - reserve;
+        assert_ne!(
+            page1[nonce_range(payload_len, Algorithm::Aes256Gcm)],
+            page2[nonce_range(payload_len, Algorithm::Aes256Gcm)]
+        );
+        assert_ne!(page1, page2);
     }
 
     #[test]
     fn reserve_too_small_fails() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN - 1;
+        let reserve = MIN_RESERVE - 1;
         let mut page = vec![0x11u8; 4096];
 
-        let result = encrypt_page(&mut page, 1, &dek, reserve);
+        let result = encrypt_page(&mut page, 1, None, &dek, reserve);
         assert!(result.is_err());
     }
 
     #[test]
     fn large_page_size() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 65536;
         let mut page = vec![0x33u8; page_size];
         let original = page.clone();
 
-        encrypt_page(&mut page, 10, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 10, None, &dek, reserve).unwrap();
         assert_ne!(page, original);
 
-        decrypt_page(&mut page, 10, &dek, reserve).unwrap();
+        decrypt_page(&mut page, 10, None, &dek, reserve).unwrap();
         assert_eq!(
             &page[..page_size - reserve],
             &original[..page_size - reserve]
@@ -328,13 +747,13 @@ mod tests {
     #[test]
     fn small_page_size() {
         let dek = Dek::generate();
-        let reserve = TAG_LEN + MARKER_LEN;
+        let reserve = MIN_RESERVE;
         let page_size = 512;
         let mut page = vec![0x44u8; page_size];
         let original = page.clone();
 
-        encrypt_page(&mut page, 15, &dek, reserve).unwrap();
-        decrypt_page(&mut page, 15, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 15, None, &dek, reserve).unwrap();
+        decrypt_page(&mut page, 15, None, &dek, reserve).unwrap();
         assert_eq!(
             &page[..page_size - reserve],
             &original[..page_size - reserve]
@@ -342,38 +761,413 @@ mod tests {
     }
 
     #[test]
-    fn page_nonce_deterministic() {
-        let nonce1 = page_nonce(42);
-        let nonce2 = page_nonce(42);
+    fn legacy_page_nonce_deterministic() {
+        let nonce1 = legacy_page_nonce(42);
+        let nonce2 = legacy_page_nonce(42);
         assert_eq!(nonce1, nonce2);
     }
 
     #[test]
-    fn page_nonce_different_for_different_pages() {
-        let nonce1 = page_nonce(1);
-        let nonce2 = page_nonce(2);
+    fn legacy_page_nonce_different_for_different_pages() {
+        let nonce1 = legacy_page_nonce(1);
+        let nonce2 = legacy_page_nonce(2);
         assert_ne!(nonce1, nonce2);
     }
 
+    #[test]
+    fn legacy_page_decrypts_and_is_rewritten_in_current_format() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let page_size = 4096;
+        let payload_len = page_size - reserve;
+        let mut page = vec![0x5Au8; page_size];
+        let original = page.clone();
+
+        encrypt_legacy_page(&mut page, 7, &dek, reserve);
+        assert_eq!(
+            page_format(&page, reserve).unwrap(),
+            Some(LEGACY_PAGE_FORMAT)
+        );
+        assert!(decrypt_page_with(&mut page.clone(), 8, None, &dek, reserve, || true).is_err());
+
+        decrypt_page_with(&mut page, 7, None, &dek, reserve, || true).unwrap();
+        assert_eq!(&page[..payload_len], &original[..payload_len]);
+
+        encrypt_page(&mut page, 7, None, &dek, reserve).unwrap();
+        assert_eq!(page_format(&page, reserve).unwrap(), Some(PAGE_FORMAT));
+        decrypt_page(&mut page, 7, None, &dek, reserve).unwrap();
+        assert_eq!(&page[..payload_len], &original[..payload_len]);
+    }
+
+    #[test]
+    fn legacy_page_decrypts_with_legacy_minimum_reserve() {
+        let dek = Dek::generate();
+        let reserve = TAG_LEN + MARKER_LEN;
+        let mut page = vec![0x6Bu8; 4096];
+        let original = page.clone();
+
+        encrypt_legacy_page(&mut page, 3, &dek, reserve);
+        decrypt_page_with(&mut page, 3, None, &dek, reserve, || true).unwrap();
+        assert_eq!(&page[..4096 - reserve], &original[..4096 - reserve]);
+    }
+
+    #[test]
+    fn legacy_page_is_refused_unless_legacy_pages_are_enabled() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0x6Cu8; 4096];
+
+        encrypt_legacy_page(&mut page, 3, &dek, reserve);
+        let before = page.clone();
+        let err = decrypt_page_with(&mut page, 3, None, &dek, reserve, || false).unwrap_err();
+        assert!(err.to_string().contains(LEGACY_PAGES_ENV), "{err}");
+        assert_eq!(page, before);
+
+        // Other formats never ask.
+        encrypt_page(&mut page, 3, None, &dek, reserve).unwrap();
+        decrypt_page_with(&mut page, 3, None, &dek, reserve, || unreachable!()).unwrap();
+    }
+
+    #[test]
+    fn tampered_nonce_fails() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let page_size = 4096;
+        let mut page = vec![0x21u8; page_size];
+
+        encrypt_page(&mut page, 4, None, &dek, reserve).unwrap();
+        page[nonce_range(page_size - reserve, Algorithm::Aes256Gcm).start] ^= 0xFF;
+
+        assert!(decrypt_page(&mut page, 4, None, &dek, reserve).is_err());
+    }
+
     #[test]
     fn marker_written_and_checked() {
         let dek = Dek::generate();
-        let reserve = 48;
+        let reserve = 80;
         let mut page = vec![0x11u8; 4096];
 
-        encrypt_page(&mut page, 2, &dek, reserve).unwrap();
+        encrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
         assert!(is_encrypted_page(&page, reserve));
 
-        decrypt_page(&mut page, 2, &dek, reserve).unwrap();
+        decrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
         // Marker should still be present after decrypt.
         assert!(is_encrypted_page(&page, reserve));
     }
 
     #[test]
-    fn decrypt_without_marker_fails() {
+    fn marker_layout() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0x13u8; 4096];
+
+        encrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
+        assert_eq!(&page[marker_range(4096 - reserve)], b"EVFS\x04\x00");
+    }
+
+    #[test]
+    fn commitment_is_checked() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let payload_len = 4096 - reserve;
+        let mut page = vec![0x17u8; 4096];
+
+        encrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
+        let commitment = commitment_range(payload_len, Algorithm::Aes256Gcm);
+        assert_eq!(
+            page[commitment.clone()],
+            page_key(
+                &dek,
+                Algorithm::Aes256Gcm,
+                &page[nonce_range(payload_len, Algorithm::Aes256Gcm)]
+            )
+            .1
+        );
+
+        let err = decrypt_page(&mut page.clone(), 2, None, &Dek::generate(), reserve).unwrap_err();
+        assert!(
+            err.to_string().contains("commits to a different key"),
+            "{err}"
+        );
+
+        page[commitment.start] ^= 0x01;
+        let before = page.clone();
+        let err = decrypt_page(&mut page, 2, None, &dek, reserve).unwrap_err();
+        assert!(
+            err.to_string().contains("commits to a different key"),
+            "{err}"
+        );
+        assert_eq!(page, before);
+    }
+
+    #[test]
+    fn uncommitted_page_written_where_commitment_doesnt_fit() {
+        let dek = Dek::generate();
+        let reserve = min_reserve(Algorithm::Aes256Gcm) - 1;
+        let payload_len = 4096 - reserve;
+        let mut page = vec![0x18u8; 4096];
+        let original = page.clone();
+
+        encrypt_page(&mut page, 5, None, &dek, reserve).unwrap();
+        assert_eq!(
+            page_format(&page, reserve).unwrap(),
+            Some(UNCOMMITTED_PAGE_FORMAT)
+        );
+        assert!(decrypt_page(&mut page.clone(), 5, None, &Dek::generate(), reserve).is_err());
+        decrypt_page(&mut page, 5, None, &dek, reserve).unwrap();
+        assert_eq!(&page[..payload_len], &original[..payload_len]);
+    }
+
+    #[test]
+    fn committed_page_is_not_under_the_dek_itself() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0x1Du8; 4096];
+
+        // Relabelled as uncommitted, the page is opened under the DEK
+        // itself, which it wasn't encrypted under.
+        encrypt_page(&mut page, 6, None, &dek, reserve).unwrap();
+        page[marker_range(4096 - reserve).end - 2] = UNCOMMITTED_PAGE_FORMAT;
+        assert!(decrypt_page(&mut page, 6, None, &dek, reserve).is_err());
+    }
+
+    #[test]
+    fn uncommitted_page_is_refused_where_commitment_fits() {
+        let dek = Dek::generate();
+        let small = min_reserve(Algorithm::Aes256Gcm) - 1;
+        let mut page = vec![0x1Eu8; 4096];
+
+        // A genuine uncommitted page, read as if the reserve were one
+        // step larger: everything it needs is still in place, but the
+        // reserve has room for a commitment it skips.
+        encrypt_page(&mut page[..4095], 6, None, &dek, small).unwrap();
+        let err = decrypt_page(&mut page, 6, None, &dek, small + 1).unwrap_err();
+        assert!(
+            err.downcast_ref::<AuthenticationFailed>().is_some(),
+            "{err}"
+        );
+        assert!(err.to_string().contains("no key commitment"), "{err}");
+    }
+
+    #[test]
+    fn unknown_format_version_is_an_error() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0x12u8; 4096];
+
+        encrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
+        assert_eq!(page_format(&page, reserve).unwrap(), Some(PAGE_FORMAT));
+
+        let version = marker_range(4096 - reserve).start + MARKER_PREFIX.len();
+        page[version] = 9;
+        assert!(is_encrypted_page(&page, reserve));
+        let err = page_format(&page, reserve).unwrap_err();
+        assert!(err.to_string().contains("unknown page format version 9"));
+        let err = decrypt_page(&mut page, 2, None, &dek, reserve).unwrap_err();
+        assert!(err.to_string().contains("unknown page format"));
+
+        page[version..version + 2].copy_from_slice(b"v9");
+        assert!(page_format(&page, reserve).is_err());
+    }
+
+    #[test]
+    fn unknown_flags_are_an_error() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0x14u8; 4096];
+
+        encrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
+        page[marker_range(4096 - reserve).end - 1] = 0x80;
+        let err = decrypt_page(&mut page, 2, None, &dek, reserve).unwrap_err();
+        assert!(err.to_string().contains("unknown page flags 0x80"));
+    }
+
+    #[test]
+    fn xchacha_round_trip() {
+        let dek = Dek::generate_for(Algorithm::XChaCha20Poly1305);
+        let reserve = 80;
+        let payload_len = 4096 - reserve;
+        let mut page = vec![0x19u8; 4096];
+        let original = page.clone();
+
+        encrypt_page(&mut page, 3, None, &dek, reserve).unwrap();
+        assert_eq!(&page[marker_range(payload_len)], b"EVFS\x04\x01");
+        assert_eq!(
+            page_algorithm(&page, reserve).unwrap(),
+            Some(Algorithm::XChaCha20Poly1305)
+        );
+        assert!(decrypt_page(&mut page.clone(), 4, None, &dek, reserve).is_err());
+
+        // The page names its algorithm, so any DEK with the same key
+        // decrypts it.
+        let aes_dek = Dek::from_bytes(*dek.as_bytes());
+        decrypt_page(&mut page, 3, None, &aes_dek, reserve).unwrap();
+        assert_eq!(&page[..payload_len], &original[..payload_len]);
+    }
+
+    #[test]
+    fn xchacha_needs_a_larger_reserve() {
+        let dek = Dek::generate_for(Algorithm::XChaCha20Poly1305);
+        let mut page = vec![0x1Au8; 4096];
+
+        assert_eq!(min_uncommitted_reserve(Algorithm::XChaCha20Poly1305), 46);
+        assert_eq!(min_reserve(Algorithm::XChaCha20Poly1305), 78);
+        assert!(encrypt_page(&mut page, 2, None, &dek, MIN_RESERVE).is_err());
+        encrypt_page(&mut page, 2, None, &dek, 46).unwrap();
+        decrypt_page(&mut page, 2, None, &dek, 46).unwrap();
+        encrypt_page(&mut page, 2, None, &dek, 78).unwrap();
+        assert_eq!(page_format(&page, 78).unwrap(), Some(PAGE_FORMAT));
+        decrypt_page(&mut page, 2, None, &dek, 78).unwrap();
+    }
+
+    #[test]
+    fn unknown_algorithm_is_an_error() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0x1Bu8; 4096];
+
+        encrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
+        page[marker_range(4096 - reserve).end - 1] = 0x0F;
+        assert!(is_encrypted_page(&page, reserve));
+        let err = decrypt_page(&mut page, 2, None, &dek, reserve).unwrap_err();
+        assert!(err.to_string().contains("unknown page algorithm id 15"));
+    }
+
+    #[test]
+    fn database_id_is_bound() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let id = DatabaseId::generate();
+        let original = vec![0x1Cu8; 4096];
+
+        let mut page = original.clone();
+        encrypt_page(&mut page, 2, Some(id), &dek, reserve).unwrap();
+        assert_eq!(page_binds_database_id(&page, reserve).unwrap(), Some(true));
+        assert!(
+            decrypt_page(
+                &mut page.clone(),
+                2,
+                Some(DatabaseId::generate()),
+                &dek,
+                reserve
+            )
+            .is_err()
+        );
+        let err = decrypt_page(&mut page.clone(), 2, None, &dek, reserve).unwrap_err();
+        assert!(err.to_string().contains("bound to a database ID"), "{err}");
+        decrypt_page(&mut page, 2, Some(id), &dek, reserve).unwrap();
+        assert_eq!(page[..4096 - reserve], original[..4096 - reserve]);
+
+        // Unbound pages ignore the ID they're opened with.
+        let mut page = original.clone();
+        encrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
+        assert_eq!(page_binds_database_id(&page, reserve).unwrap(), Some(false));
+        decrypt_page(&mut page, 2, Some(id), &dek, reserve).unwrap();
+        assert_eq!(page[..4096 - reserve], original[..4096 - reserve]);
+    }
+
+    #[test]
+    fn corrupted_marker_prefix_is_plaintext() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0x15u8; 4096];
+
+        encrypt_page(&mut page, 2, None, &dek, reserve).unwrap();
+        page[marker_range(4096 - reserve).start] ^= 0xFF;
+        assert!(!is_encrypted_page(&page, reserve));
+        assert_eq!(page_format(&page, reserve).unwrap(), None);
+        assert!(decrypt_page(&mut page, 2, None, &dek, reserve).is_err());
+    }
+
+    #[test]
+    fn v2_page_still_decrypts() {
         let dek = Dek::generate();
         let reserve = 48;
+        let payload_len = 4096 - reserve;
+        let mut page = vec![0x16u8; 4096];
+        let original = page.clone();
+
+        // `EVFSv2` pages differ from current ones only in their marker.
+        encrypt_page(&mut page, 9, None, &dek, reserve).unwrap();
+        page[marker_range(payload_len)].copy_from_slice(b"EVFSv2");
+        assert_eq!(page_format(&page, reserve).unwrap(), Some(V2_PAGE_FORMAT));
+
+        decrypt_page(&mut page, 9, None, &dek, reserve).unwrap();
+        assert_eq!(&page[..payload_len], &original[..payload_len]);
+    }
+
+    #[test]
+    fn v2_page_is_refused_where_commitment_fits() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let payload_len = 4096 - reserve;
+        let mut page = vec![0x19u8; 4096];
+
+        // A genuine `EVFSv2` page, laid out for a reserve of 80 by
+        // encrypting it without a commitment into a shorter page.
+        let small = min_reserve(Algorithm::Aes256Gcm) - 1;
+        encrypt_page(&mut page[..payload_len + small], 9, None, &dek, small).unwrap();
+        page[marker_range(payload_len)].copy_from_slice(b"EVFSv2");
+        assert_eq!(page_format(&page, reserve).unwrap(), Some(V2_PAGE_FORMAT));
+
+        let err = decrypt_page(&mut page, 9, None, &dek, reserve).unwrap_err();
+        assert!(
+            err.downcast_ref::<AuthenticationFailed>().is_some(),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn zero_page_is_plaintext() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0u8; 4096];
+
+        assert!(!is_encrypted_page(&page, reserve));
+        assert_eq!(page_format(&page, reserve).unwrap(), None);
+        assert!(decrypt_unmarked_page(&mut page, 3, &dek, reserve).is_err());
+        assert!(page.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn unmarked_page_decrypts_only_as_legacy() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let payload_len = 4096 - reserve;
+        let mut page = vec![0x17u8; 4096];
+        let original = page.clone();
+
+        // A page from before the marker: an `EVFSv1` page without it.
+        encrypt_legacy_page(&mut page, 6, &dek, reserve);
+        page[marker_range(payload_len)].fill(0);
+        assert!(!is_encrypted_page(&page, reserve));
+        assert!(decrypt_page(&mut page.clone(), 6, None, &dek, reserve).is_err());
+
+        let ciphertext = page.clone();
+        assert!(decrypt_unmarked_page(&mut page, 5, &dek, reserve).is_err());
+        assert_eq!(page, ciphertext);
+        decrypt_unmarked_page(&mut page, 6, &dek, reserve).unwrap();
+        assert_eq!(&page[..payload_len], &original[..payload_len]);
+    }
+
+    #[test]
+    fn plaintext_page_is_left_untouched_by_unmarked_decrypt() {
+        let dek = Dek::generate();
+        let reserve = 80;
+        let mut page = vec![0x18u8; 4096];
+        page[4096 - reserve..].fill(0);
+        let original = page.clone();
+
+        assert!(!is_encrypted_page(&page, reserve));
+        assert!(decrypt_unmarked_page(&mut page, 2, &dek, reserve).is_err());
+        assert_eq!(page, original);
+    }
+
+    #[test]
+    fn decrypt_without_marker_fails() {
+        let dek = Dek::generate();
+        let reserve = 80;
         let mut page = vec![0u8; 4096]; // plaintext / no marker
-        assert!(decrypt_page(&mut page, 2, &dek, reserve).is_err());
+        assert!(decrypt_page(&mut page, 2, None, &dek, reserve).is_err());
     }
 }
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use aes_gcm::{Nonce, Tag, aead::AeadInPlace};
+
+use super::keys::Dek;
+
+// Temp files (temp databases, sort spills, statement and temp
+// journals) have no reserved bytes and are read and written at any
+// offset. Each block is sealed with AES-256-GCM like a database page,
+// under a DEK generated for the file, but the nonce and tag stay in
+// memory with the DEK: the file holds only same-length ciphertext,
+// which nothing can decrypt once the process lets go of the file.
+
+const NONCE_LEN: usize = 12;
+
+/// Nonce, tag and length of the sealed bytes of one block.
+struct Sealed {
+    nonce: [u8; NONCE_LEN],
+    tag: Tag,
+    len: usize,
+}
+
+/// Per-file cipher for a temp file, keyed by an ephemeral DEK.
+pub struct TempCipher {
+    dek: Dek,
+    block_size: usize,
+    blocks: HashMap<u64, Sealed>,
+}
+
+impl TempCipher {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            dek: Dek::generate(),
+            block_size,
+            blocks: HashMap::new(),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Length of block `block`'s sealed bytes, if it has been written.
+    pub fn sealed_len(&self, block: u64) -> Option<usize> {
+        self.blocks.get(&block).map(|s| s.len)
+    }
+
+    /// Encrypt `data` in place as the new content of `block`, from its
+    /// start.
+    pub fn seal(&mut self, block: u64, data: &mut [u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(data.len() <= self.block_size, "data exceeds the block size");
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::fill(&mut nonce).expect("getrandom failed");
+        let tag = self
+            .dek
+            .aes_gcm()
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &block.to_le_bytes(), data)
+            .map_err(|e| anyhow::anyhow!("temp block encrypt failed: {e}"))?;
+        self.blocks.insert(
+            block,
+            Sealed {
+                nonce,
+                tag,
+                len: data.len(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Decrypt `data`, the sealed bytes of `block`, in place.
+    pub fn open(&self, block: u64, data: &mut [u8]) -> anyhow::Result<()> {
+        let sealed = self
+            .blocks
+            .get(&block)
+            .ok_or_else(|| anyhow::anyhow!("temp block {block} was never written"))?;
+        anyhow::ensure!(
+            data.len() == sealed.len,
+            "temp block {block} holds {} bytes, not {}",
+            sealed.len,
+            data.len()
+        );
+        self.dek
+            .aes_gcm()
+            .decrypt_in_place_detached(
+                Nonce::from_slice(&sealed.nonce),
+                &block.to_le_bytes(),
+                data,
+                &sealed.tag,
+            )
+            .map_err(|e| anyhow::anyhow!("temp block decrypt failed: {e}"))
+    }
+
+    /// Forget the blocks starting at or past `size` bytes. A block
+    /// `size` cuts through must be resealed by the caller.
+    pub fn truncate(&mut self, size: u64) {
+        let block_size = self.block_size as u64;
+        self.blocks.retain(|block, _| block * block_size < size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_open_round_trip() {
+        let mut cipher = TempCipher::new(4096);
+        let plain = b"temp spill row".to_vec();
+        let mut data = plain.clone();
+        cipher.seal(3, &mut data).unwrap();
+        assert_ne!(data, plain);
+        assert_eq!(cipher.sealed_len(3), Some(plain.len()));
+
+        cipher.open(3, &mut data).unwrap();
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn resealing_uses_a_fresh_nonce() {
+        let mut cipher = TempCipher::new(4096);
+        let mut first = vec![0x42u8; 64];
+        let mut second = first.clone();
+        cipher.seal(0, &mut first).unwrap();
+        cipher.seal(0, &mut second).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn open_checks_block_and_length() {
+        let mut cipher = TempCipher::new(4096);
+        let mut data = vec![7u8; 100];
+        cipher.seal(1, &mut data).unwrap();
+
+        assert!(cipher.open(2, &mut data.clone()).is_err());
+        assert!(cipher.open(1, &mut data[..50].to_vec()).is_err());
+
+        // Sealed under block 1, so moving it to block 2 fails.
+        let mut moved = vec![7u8; 100];
+        cipher.seal(2, &mut moved).unwrap();
+        assert!(cipher.open(2, &mut data).is_err());
+    }
+
+    #[test]
+    fn ciphers_do_not_share_keys() {
+        let mut a = TempCipher::new(4096);
+        let mut b = TempCipher::new(4096);
+        let mut data = vec![1u8; 32];
+        a.seal(0, &mut data).unwrap();
+        let mut other = vec![1u8; 32];
+        b.seal(0, &mut other).unwrap();
+        // Even given a's nonce and tag, b's key doesn't open a's block.
+        b.blocks.insert(0, a.blocks.remove(&0).unwrap());
+        assert!(b.open(0, &mut data).is_err());
+    }
+
+    #[test]
+    fn truncate_drops_blocks_past_the_end() {
+        let mut cipher = TempCipher::new(100);
+        for block in 0..4 {
+            cipher.seal(block, &mut [0u8; 100]).unwrap();
+        }
+        cipher.truncate(250);
+        assert!(cipher.sealed_len(2).is_some());
+        assert!(cipher.sealed_len(3).is_none());
+        cipher.truncate(200);
+        assert!(cipher.sealed_len(1).is_some());
+        assert!(cipher.sealed_len(2).is_none());
+    }
+}
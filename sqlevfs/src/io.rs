@@ -1,13 +1,20 @@
 //! Helpers used by the VFS I/O layer.
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use crate::{
     crypto::{
-        keys::KeyScope,
-        page::{decrypt_page, encrypt_page},
+        keys::{DatabaseId, Dek, KeyScope},
+        page::{
+            decrypt_page_with,
+            decrypt_unmarked_page,
+            encrypt_page,
+            is_encrypted_page,
+            page_binds_database_id,
+        },
+        temp::TempCipher,
     },
-    keyring::Keyring,
+    keyring::{self, Keyring},
 };
 
 /// Shared context carried by every open file handle.
@@ -16,35 +23,143 @@ pub struct FileContext {
     pub page_size: u32,
     pub reserve_size: usize,
     pub encrypt_enabled: bool,
-    /// Lazily-built map from btree root page → KeyScope.
-    /// `None` means "use Database scope for everything".
+    /// A WAL file: only the page images in its frames are encrypted.
+    pub wal: bool,
+    /// A temp file, encrypted under its own ephemeral DEK.
+    pub temp: Option<TempCipher>,
+    /// Opened read-only: DEKs missing from the sidecar are never
+    /// created.
+    pub read_only: bool,
+    /// Pass `SQLITE_FCNTL_MMAP_SIZE` through to the inner VFS.
+    pub allow_mmap: bool,
+    /// Page 1 is encrypted too; its geometry is kept in the sidecar.
+    pub conceal_header: bool,
+    /// Read `EVFSv1` pages, and try unmarked pages as ones written
+    /// before pages carried a marker (`EVFS_LEGACY_PAGES=1`).
+    pub legacy_pages: bool,
+    /// Path of a main database file, for its sidecar and rekey journal.
+    pub db_path: Option<PathBuf>,
+    /// ID of the database, from its sidecar, bound into the pages of
+    /// the database and its WAL. `None` for a database from before
+    /// databases had IDs, and for files of no database.
+    pub database_id: Option<DatabaseId>,
+    /// Scope of the pages no table scope claims: `Database`, unless
+    /// the database was opened with an `evfs_scope` URI parameter.
+    pub file_scope: KeyScope,
+    /// Tables of a main database whose pages, and their indexes',
+    /// are encrypted under their own `KeyScope::Table` DEK.
+    pub scoped_tables: Vec<String>,
+    /// Map from each page of a scoped table → KeyScope, rebuilt when
+    /// the file is synced. `None` means "use Database scope for
+    /// everything".
     pub page_scope_map: Option<HashMap<u32, KeyScope>>,
+    /// Pages written since the map was last rebuilt, with the scope
+    /// each was encrypted under.
+    pub written_scopes: HashMap<u32, KeyScope>,
 }
 
 impl FileContext {
+    fn dek(&self, scope: &KeyScope) -> anyhow::Result<Dek> {
+        if self.read_only {
+            self.keyring.existing_dek(scope)
+        } else {
+            self.keyring.dek_for(scope)
+        }
+    }
+
+    /// The database ID to decrypt `page` with. A database from before
+    /// IDs gets one when another connection rekeys it, so a bound page
+    /// with none known yet takes the one in the sidecar.
+    fn database_id_for(&self, page: &[u8]) -> Option<DatabaseId> {
+        if self.database_id.is_none()
+            && matches!(
+                page_binds_database_id(page, self.reserve_size),
+                Ok(Some(true))
+            )
+        {
+            return self.db_path.as_deref().and_then(keyring::database_id_of);
+        }
+        self.database_id
+    }
+
     pub fn encrypt_page(&self, page: &mut [u8], page_no: u32) -> anyhow::Result<()> {
-        let dek = self
-            .keyring
-            .dek_for_page(page_no, self.page_scope_map.as_ref())?;
-        encrypt_page(page, page_no, &dek, self.reserve_size)
+        let dek = self.dek(&self.scope_for(page_no))?;
+        encrypt_page(page, page_no, self.database_id, &dek, self.reserve_size)
     }
 
+    /// Decrypt under the page's mapped scope, falling back to the
+    /// keyring's other DEKs: a page written before the map caught up
+    /// with its table, or under a scope no longer configured, is
+    /// still readable.
     pub fn decrypt_page(&self, page: &mut [u8], page_no: u32) -> anyhow::Result<()> {
-        let dek = self
-            .keyring
-            .dek_for_page(page_no, self.page_scope_map.as_ref())?;
-        decrypt_page(page, page_no, &dek, self.reserve_size)
+        let dek = self.dek(&self.scope_for(page_no))?;
+        let database_id = self.database_id_for(page);
+        let decrypt = |page: &mut [u8], dek: &Dek| {
+            decrypt_page_with(page, page_no, database_id, dek, self.reserve_size, || {
+                self.legacy_pages
+            })
+        };
+        let Err(e) = decrypt(page, &dek) else {
+            return Ok(());
+        };
+        for other in self.keyring.all_deks()? {
+            if other != dek && decrypt(page, &other).is_ok() {
+                return Ok(());
+            }
+        }
+        Err(e)
+    }
+
+    /// Decrypt in place a page of the database just read from disk.
+    /// Plaintext pages are passed through, and so are unmarked pages,
+    /// unless `legacy_pages` is set and one decrypts as a page written
+    /// before the marker existed.
+    pub fn read_page(&self, page: &mut [u8], page_no: u32) -> anyhow::Result<()> {
+        if self.is_plaintext_page(page_no) {
+            return Ok(());
+        }
+        if is_encrypted_page(page, self.reserve_size) {
+            return self.decrypt_page(page, page_no);
+        }
+        if self.legacy_pages {
+            for dek in self.keyring.all_deks()? {
+                if decrypt_unmarked_page(page, page_no, &dek, self.reserve_size).is_ok() {
+                    break;
+                }
+            }
+        }
+        Ok(())
     }
 
-    /// Build the page→scope map by querying sqlite_master.
-    ///
-    /// Called lazily on first read/write if per-table encryption is
-    /// enabled. Requires a separate read of page 1 (the schema
-    /// table) which is always encrypted under `KeyScope::Database`.
-    pub fn build_page_scope_map(&mut self, root_pages: &[(String, u32)]) {
+    /// Whether `page_no` is stored as plaintext: page 1, unless the
+    /// database conceals its header.
+    pub fn is_plaintext_page(&self, page_no: u32) -> bool {
+        page_no == 1 && !self.conceal_header
+    }
+
+    /// Record that `page_no` was just encrypted, for the scope check
+    /// when the file is next synced.
+    pub fn note_written(&mut self, page_no: u32) {
+        if !self.scoped_tables.is_empty() {
+            self.written_scopes.insert(page_no, self.scope_for(page_no));
+        }
+    }
+
+    /// The scope a page is encrypted under when written.
+    pub fn scope_for(&self, page_no: u32) -> KeyScope {
+        self.page_scope_map
+            .as_ref()
+            .and_then(|m| m.get(&page_no))
+            .cloned()
+            .unwrap_or_else(|| self.file_scope.clone())
+    }
+
+    /// Build the page→scope map from `(table, page)` pairs covering
+    /// every page of each scoped table's b-trees.
+    pub fn build_page_scope_map(&mut self, pages: &[(String, u32)]) {
         let mut map = HashMap::new();
-        for (table_name, root_page) in root_pages {
-            map.insert(*root_page, KeyScope::Table(table_name.clone()));
+        for (table_name, page_no) in pages {
+            map.insert(*page_no, KeyScope::Table(table_name.clone()));
         }
         self.page_scope_map = Some(map);
     }
@@ -53,19 +168,32 @@ impl FileContext {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{crypto::keys::KeyScope, tests::MockKmsProvider};
+    use crate::{
+        crypto::{keys::KeyScope, page::decrypt_page},
+        kms::mock::MockKmsProvider,
+    };
 
     // Helper to create a test FileContext
     fn create_test_context(with_map: bool) -> FileContext {
-        let provider = MockKmsProvider::new();
-        let keyring = Arc::new(Keyring::new(provider));
+        let keyring = Arc::new(Keyring::new(Arc::new(MockKmsProvider::new())));
 
         let mut ctx = FileContext {
             keyring,
             page_size: 4096,
-            reserve_size: 24,
+            reserve_size: 48,
             encrypt_enabled: true,
+            wal: false,
+            temp: None,
+            read_only: false,
+            allow_mmap: false,
+            conceal_header: false,
+            legacy_pages: false,
+            db_path: None,
+            database_id: None,
+            file_scope: KeyScope::Database,
+            scoped_tables: Vec::new(),
             page_scope_map: None,
+            written_scopes: HashMap::new(),
         };
 
         if with_map {
@@ -79,7 +207,7 @@ mod tests {
     fn test_file_context_creation() {
         let ctx = create_test_context(false);
         assert_eq!(ctx.page_size, 4096);
-        assert_eq!(ctx.reserve_size, 24);
+        assert_eq!(ctx.reserve_size, 48);
         assert!(ctx.page_scope_map.is_none());
     }
 
@@ -160,6 +288,77 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_read_page_tries_unmarked_pages_only_in_legacy_mode() {
+        let mut ctx = create_test_context(false);
+        let reserve = ctx.reserve_size;
+        let payload_len = 4096 - reserve;
+        let dek = ctx.keyring.dek_for(&KeyScope::Database).unwrap();
+
+        let mut unmarked = vec![0xA5u8; 4096];
+        let original = unmarked.clone();
+        crate::crypto::page::encrypt_legacy_page(&mut unmarked, 4, &dek, reserve);
+        unmarked[payload_len + crate::crypto::page::TAG_LEN..].fill(0);
+        let zero = vec![0u8; 4096];
+
+        let mut page = unmarked.clone();
+        ctx.read_page(&mut page, 4).unwrap();
+        assert_eq!(page, unmarked);
+
+        ctx.legacy_pages = true;
+        ctx.read_page(&mut page, 4).unwrap();
+        assert_eq!(&page[..payload_len], &original[..payload_len]);
+
+        let mut page = zero.clone();
+        ctx.read_page(&mut page, 4).unwrap();
+        assert_eq!(page, zero);
+    }
+
+    #[test]
+    fn test_read_page_reads_evfs_v1_pages_only_in_legacy_mode() {
+        let mut ctx = create_test_context(false);
+        let reserve = ctx.reserve_size;
+        let payload_len = 4096 - reserve;
+        let dek = ctx.keyring.dek_for(&KeyScope::Database).unwrap();
+
+        let mut legacy = vec![0xA6u8; 4096];
+        let original = legacy.clone();
+        crate::crypto::page::encrypt_legacy_page(&mut legacy, 4, &dek, reserve);
+
+        let mut page = legacy.clone();
+        let err = ctx.read_page(&mut page, 4).unwrap_err();
+        assert!(err.to_string().contains("EVFS_LEGACY_PAGES"), "{err}");
+        assert_eq!(page, legacy);
+
+        ctx.legacy_pages = true;
+        ctx.read_page(&mut page, 4).unwrap();
+        assert_eq!(&page[..payload_len], &original[..payload_len]);
+    }
+
+    #[test]
+    fn test_decrypt_bound_page_with_no_id_reads_the_sidecar() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("test.db");
+        let mut ctx = create_test_context(false);
+        ctx.db_path = Some(db_path.clone());
+        let dek = ctx.keyring.dek_for(&KeyScope::Database).unwrap();
+
+        // Another connection gave the database an ID after this one
+        // opened it.
+        let id = DatabaseId::generate();
+        let mut page = vec![0xABu8; 4096];
+        let original = page.clone();
+        encrypt_page(&mut page, 3, Some(id), &dek, ctx.reserve_size).unwrap();
+        assert!(ctx.decrypt_page(&mut page.clone(), 3).is_err());
+
+        ctx.keyring.install_database_id(&db_path, id).unwrap();
+        ctx.decrypt_page(&mut page, 3).unwrap();
+        assert_eq!(
+            &page[..4096 - ctx.reserve_size],
+            &original[..4096 - ctx.reserve_size]
+        );
+    }
+
     #[test]
     fn test_decrypt_page_with_scope_map() {
         let ctx = create_test_context(true);
@@ -258,4 +457,44 @@ mod tests {
         assert!(map2.contains_key(&20));
         assert!(map2.contains_key(&30));
     }
+
+    #[test]
+    fn test_scope_for() {
+        let ctx = create_test_context(true);
+        assert_eq!(ctx.scope_for(10), KeyScope::Table("users".to_string()));
+        assert_eq!(ctx.scope_for(99), KeyScope::Database);
+    }
+
+    #[test]
+    fn test_file_scope_replaces_database_scope() -> anyhow::Result<()> {
+        let mut ctx = create_test_context(true);
+        ctx.file_scope = KeyScope::Named("tenant1".to_string());
+        assert_eq!(ctx.scope_for(10), KeyScope::Table("users".to_string()));
+        assert_eq!(ctx.scope_for(99), KeyScope::Named("tenant1".to_string()));
+
+        let mut page = vec![0x11u8; 4096];
+        ctx.encrypt_page(&mut page, 99)?;
+        let named = ctx
+            .keyring
+            .dek_for(&KeyScope::Named("tenant1".to_string()))?;
+        decrypt_page(&mut page, 99, None, &named, ctx.reserve_size)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_page_after_scope_moves() {
+        let mut ctx = create_test_context(true);
+        let mut page = vec![0xEEu8; 4096];
+        let original = page.clone();
+
+        // Written under the users scope, read once the map no longer
+        // has the page.
+        ctx.encrypt_page(&mut page, 10).unwrap();
+        ctx.page_scope_map = None;
+        ctx.decrypt_page(&mut page, 10).unwrap();
+        assert_eq!(
+            &page[..4096 - ctx.reserve_size],
+            &original[..4096 - ctx.reserve_size]
+        );
+    }
 }
@@ -1,35 +1,421 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
 };
 
 use bincode::config;
-use parking_lot::RwLock;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use parking_lot::{Mutex, RwLock};
+use sha2::Sha256;
+use zeroize::Zeroizing;
 
 use crate::{
     crypto::{
         envelope,
-        keys::{Dek, KeyScope, WrappedDek},
+        keys::{Algorithm, DatabaseId, Dek, KekId, KeyScope, WrappedDek},
     },
     kms::KmsProvider,
 };
 
+const SIDECAR_MAGIC: &[u8; 8] = b"EVFSKEYR";
+/// Version 1 sidecars are authenticated: the magic and version are
+/// followed by the ID of a KEK and the keyring, then an HMAC-SHA256 of
+/// all of it under a key derived from that KEK. Sidecars from before
+/// are a bare keyring, in one of the formats [`PersistedKeyring::decode`]
+/// reads.
+const SIDECAR_VERSION: u32 = 1;
+/// Length of the MAC ending an authenticated sidecar.
+const SIDECAR_MAC_LEN: usize = 32;
+
 /// On-disk format: only wrapped DEKs, never plaintext.
 #[derive(Clone, Default, bincode::Encode, bincode::Decode)]
 pub struct PersistedKeyring {
     pub keys: HashMap<String, WrappedDek>,
+    /// Set when the database's page 1 is encrypted like the rest, so
+    /// its header can't give the page geometry.
+    pub concealed_header: Option<ConcealedHeader>,
+    /// ID bound into the database's pages. Set when the database is
+    /// created, and for one from before IDs, when it is rekeyed.
+    pub database_id: Option<DatabaseId>,
+}
+
+/// Sidecar written before sidecars recorded a database ID.
+#[derive(bincode::Decode)]
+struct PersistedKeyringV2 {
+    keys: HashMap<String, WrappedDek>,
+    concealed_header: Option<ConcealedHeader>,
+}
+
+/// Page geometry of a database that conceals its header, which the
+/// VFS needs before it can decrypt page 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct ConcealedHeader {
+    pub page_size: u32,
+    pub reserve_size: u32,
+}
+
+impl PersistedKeyring {
+    /// Decode a sidecar without authenticating it, including one written
+    /// before sidecars were authenticated, before they recorded a
+    /// database ID, or before they recorded a header format, which
+    /// holds only the wrapped DEKs.
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if data.starts_with(SIDECAR_MAGIC) {
+            return Ok(parse_authenticated(data)?.1);
+        }
+        let e = match bincode::decode_from_slice(data, config::standard()) {
+            Ok((persisted, _)) => return Ok(persisted),
+            Err(e) => e,
+        };
+        if let Ok((v2, _)) =
+            bincode::decode_from_slice::<PersistedKeyringV2, _>(data, config::standard())
+        {
+            return Ok(Self {
+                keys: v2.keys,
+                concealed_header: v2.concealed_header,
+                database_id: None,
+            });
+        }
+        let keys = bincode::decode_from_slice(data, config::standard())
+            .map_err(|_| anyhow::anyhow!("invalid keyring sidecar: {e}"))?
+            .0;
+        Ok(Self {
+            keys,
+            concealed_header: None,
+            database_id: None,
+        })
+    }
+
+    /// Encode as an authenticated sidecar, MACed under `mac_key`, the
+    /// [`sidecar_mac_key`] of the KEK `kek_id`.
+    pub(crate) fn encode_authenticated(
+        &self,
+        kek_id: &KekId,
+        mac_key: &[u8; 32],
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut data = SIDECAR_MAGIC.to_vec();
+        data.extend_from_slice(&SIDECAR_VERSION.to_le_bytes());
+        data.extend_from_slice(&bincode::encode_to_vec((kek_id, self), config::standard())?);
+        let mac = sidecar_mac(mac_key, &data).finalize().into_bytes();
+        data.extend_from_slice(&mac);
+        Ok(data)
+    }
+
+    /// Decode a sidecar, authenticating it under the MAC key `mac_key`
+    /// gives for the KEK it names. Returns that KEK's ID, or `None` for
+    /// a sidecar from before sidecars were authenticated.
+    pub(crate) fn decode_authenticated(
+        data: &[u8],
+        mac_key: impl FnOnce(&KekId) -> anyhow::Result<Zeroizing<[u8; 32]>>,
+    ) -> anyhow::Result<(Self, Option<KekId>)> {
+        if !data.starts_with(SIDECAR_MAGIC) {
+            return Ok((Self::decode(data)?, None));
+        }
+        let (kek_id, persisted) = parse_authenticated(data)?;
+        let (body, mac) = data.split_at(data.len() - SIDECAR_MAC_LEN);
+        sidecar_mac(&*mac_key(&kek_id)?, body)
+            .verify_slice(mac)
+            .map_err(|_| anyhow::anyhow!("keyring sidecar failed authentication (wrong key?)"))?;
+        Ok((persisted, Some(kek_id)))
+    }
+}
+
+/// The KEK ID and keyring of an authenticated sidecar, not yet
+/// authenticated.
+fn parse_authenticated(data: &[u8]) -> anyhow::Result<(KekId, PersistedKeyring)> {
+    let header_len = SIDECAR_MAGIC.len() + 4;
+    anyhow::ensure!(
+        data.len() >= header_len + SIDECAR_MAC_LEN,
+        "invalid keyring sidecar: truncated"
+    );
+    let version = u32::from_le_bytes(data[SIDECAR_MAGIC.len()..header_len].try_into()?);
+    anyhow::ensure!(
+        version == SIDECAR_VERSION,
+        "unsupported keyring sidecar version: {version}"
+    );
+    let body = &data[header_len..data.len() - SIDECAR_MAC_LEN];
+    let (parsed, len) = bincode::decode_from_slice(body, config::standard())
+        .map_err(|e| anyhow::anyhow!("invalid keyring sidecar: {e}"))?;
+    anyhow::ensure!(len == body.len(), "invalid keyring sidecar: trailing bytes");
+    Ok(parsed)
+}
+
+/// The key sidecars are MACed under, derived from the KEK `kek`.
+pub(crate) fn sidecar_mac_key(kek: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, kek)
+        .expand(b"evfs keyring sidecar", key.as_mut())
+        .expect("HKDF output length");
+    key
+}
+
+/// HMAC-SHA256 of a sidecar's magic, version, KEK ID and keyring
+/// (`data`) under `mac_key`.
+fn sidecar_mac(mac_key: &[u8; 32], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC takes any key");
+    mac.update(data);
+    mac
+}
+
+/// Path of the keyring sidecar for the database at `db_path`.
+pub fn sidecar_path_for(db_path: &Path) -> PathBuf {
+    db_path.with_extension("evfs-keyring")
+}
+
+/// The sidecar of the database at `db_path`, through any symlinks, so
+/// that one database has one key in [`Keyring::for_database`]'s map
+/// however it is named. A database not created yet is resolved
+/// through its directory.
+fn canonical_sidecar_path(db_path: &Path) -> PathBuf {
+    let canonical = std::fs::canonicalize(db_path).ok().or_else(|| {
+        let dir = db_path.parent().filter(|dir| !dir.as_os_str().is_empty())?;
+        Some(std::fs::canonicalize(dir).ok()?.join(db_path.file_name()?))
+    });
+    sidecar_path_for(canonical.as_deref().unwrap_or(db_path))
+}
+
+/// The sidecar at `path`, unauthenticated: for what only breaks the
+/// database if it is tampered with, such as its page geometry.
+pub(crate) fn load_sidecar(path: &Path) -> Option<PersistedKeyring> {
+    PersistedKeyring::decode(&std::fs::read(path).ok()?).ok()
+}
+
+/// The sidecar at `path`, authenticated under KEKs from `provider`. One
+/// from before sidecars were authenticated is read with a warning: for
+/// reading the database, never for adding DEKs to it.
+pub(crate) fn read_sidecar(
+    path: &Path,
+    provider: &dyn KmsProvider,
+) -> anyhow::Result<PersistedKeyring> {
+    let data = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("cannot read keyring {}: {e}", path.display()))?;
+    let (persisted, kek_id) = PersistedKeyring::decode_authenticated(&data, |id| {
+        Ok(sidecar_mac_key(&provider.get_kek_by_id(id)?))
+    })
+    .map_err(|e| anyhow::anyhow!("{}: {e:#}", path.display()))?;
+    if kek_id.is_none() {
+        log::warn!(
+            "keyring {} is not authenticated: its DEKs are trusted as read; open the database \
+             with unauthenticated sidecar upgrades enabled to authenticate it",
+            path.display()
+        );
+    }
+    Ok(persisted)
+}
+
+/// The concealed header recorded in the sidecar of the database at
+/// `db_path`, if it conceals its header.
+pub(crate) fn concealed_header_of(db_path: &Path) -> Option<ConcealedHeader> {
+    load_sidecar(&sidecar_path_for(db_path))?.concealed_header
+}
+
+/// The ID recorded in the sidecar of the database at `db_path`, if it
+/// has one.
+pub fn database_id_of(db_path: &Path) -> Option<DatabaseId> {
+    load_sidecar(&sidecar_path_for(db_path))?.database_id
+}
+
+/// Every DEK in the sidecar of the database at `db_path`, unwrapped
+/// with `provider`. The Database DEK covers most pages, so it comes
+/// first.
+pub(crate) fn sidecar_deks(db_path: &Path, provider: &dyn KmsProvider) -> anyhow::Result<Vec<Dek>> {
+    let persisted = read_sidecar(&sidecar_path_for(db_path), provider)?;
+    let database = KeyScope::Database.to_string();
+    let mut wrapped: Vec<_> = persisted.keys.iter().collect();
+    wrapped.sort_by_key(|(key, _)| **key != database);
+    wrapped
+        .into_iter()
+        .map(|(_, wrapped)| envelope::unwrap_dek(wrapped, provider))
+        .collect()
+}
+
+/// Replace `path` with `data` so a crash leaves either the old or the
+/// new contents, never a mix. On Unix the file is readable by its owner
+/// only, as it holds wrapped DEKs.
+pub(crate) fn write_atomically(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    // Unique, so that keyrings flushing the same sidecar at once don't
+    // write into each other's temporary file.
+    let mut suffix = [0u8; 8];
+    getrandom::fill(&mut suffix).expect("getrandom failed");
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{:016x}.tmp", u64::from_le_bytes(suffix)));
+    let tmp = PathBuf::from(tmp);
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(&tmp)
+        .map_err(|e| anyhow::anyhow!("create {}: {e}", tmp.display()))?;
+    (|| {
+        std::io::Write::write_all(&mut file, data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    })()
+    .map_err(|e| {
+        let _ = std::fs::remove_file(&tmp);
+        anyhow::anyhow!("write {}: {e}", path.display())
+    })?;
+    // Make the rename itself durable.
+    #[cfg(unix)]
+    {
+        let dir = path
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        std::fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| anyhow::anyhow!("sync {}: {e}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// A DEK was needed that the sidecar doesn't hold, and it couldn't be
+/// created: the database is open read-only, or its sidecar can't be
+/// written. The VFS reports this as `SQLITE_READONLY`.
+#[derive(Debug)]
+pub struct SidecarReadOnly {
+    /// Scope of the missing DEK.
+    pub scope: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for SidecarReadOnly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no {} DEK and none can be created: {}",
+            self.scope, self.reason
+        )
+    }
+}
+
+impl std::error::Error for SidecarReadOnly {}
+
+/// How often cached DEKs are unwrapped through the KMS again, and how
+/// long they are still served while it can't be reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Revalidation {
+    /// Age at which a cached DEK is unwrapped again.
+    pub every: Duration,
+    /// How long past `every` a cached DEK is served while unwrapping it
+    /// fails. After that it is dropped, and pages of its scope fail to
+    /// read or write until the KMS answers again.
+    pub offline_grace: Duration,
+}
+
+/// Which KEKs the DEKs in a keyring are wrapped under, from
+/// [`Keyring::kek_status`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KekStatus {
+    /// Scopes whose DEK is wrapped under the current KEK.
+    pub current: Vec<String>,
+    /// Scopes whose DEK is still wrapped under an older KEK, or locally
+    /// where the KMS now wraps DEKs itself, and still unwraps: rewrapping
+    /// it failed, e.g. as the sidecar can't be written.
+    pub stale: Vec<String>,
+    /// Scopes whose KEK couldn't be checked, or is an older one that no
+    /// longer unwraps their DEK, with why. Once their DEKs aren't
+    /// cached, their pages can't be read.
+    pub unreachable: Vec<(String, String)>,
+}
+
+impl KekStatus {
+    /// Whether every DEK is wrapped under the current KEK.
+    pub fn is_current(&self) -> bool {
+        self.stale.is_empty() && self.unreachable.is_empty()
+    }
+}
+
+/// What [`Keyring::rewrap_all`] did with each DEK in the keyring.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RewrapSummary {
+    /// Scopes whose DEK is now wrapped under the current KEK.
+    pub rewrapped: Vec<String>,
+    /// Scopes whose DEK another connection replaced meanwhile, and
+    /// which were left to it.
+    pub skipped: Vec<String>,
+    /// Scopes whose DEK couldn't be unwrapped or rewrapped, with why,
+    /// left wrapped as they were.
+    pub failed: Vec<(String, String)>,
+}
+
+impl RewrapSummary {
+    /// Whether every DEK was rewrapped, or left to another connection.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Every DEK of a database's keyring, unwrapped by
+/// [`Keyring::export`] for [`Keyring::import`] to wrap under another
+/// KEK. It holds the keys in plaintext: keep it in memory, and only for
+/// as long as it takes.
+pub struct KeyringExport {
+    database: PathBuf,
+    persisted: PersistedKeyring,
+    /// Sorted by scope.
+    deks: Vec<(String, Dek)>,
+}
+
+impl KeyringExport {
+    /// The scopes whose DEKs were exported.
+    pub fn scopes(&self) -> Vec<&str> {
+        self.deks.iter().map(|(scope, _)| scope.as_str()).collect()
+    }
+}
+
+/// When a cached DEK was last unwrapped, and when it is next due to be.
+#[derive(Clone, Copy)]
+struct Validity {
+    at: Instant,
+    next_check: Instant,
+}
+
+/// A cached DEK, with the wrapping it was unwrapped from or wrapped to:
+/// the cache may still hold the DEK of another database the keyring was
+/// bound to before, under the same scope.
+struct CachedDek {
+    dek: Dek,
+    wrapped: WrappedDek,
 }
 
 /// Runtime keyring - holds unwrapped DEKs in memory.
 pub struct Keyring {
     provider: Arc<dyn KmsProvider>,
-    /// scope-string → plaintext DEK (zeroized on drop).
-    cache: RwLock<HashMap<String, Dek>>,
+    /// scope-string → plaintext DEK (zeroized on drop). Boxed, so
+    /// growing the map doesn't leave copies of the keys behind.
+    cache: RwLock<HashMap<String, Box<CachedDek>>>,
     /// On-disk representation (wrapped DEKs).
     persisted: RwLock<PersistedKeyring>,
     /// Optional path to persist the keyring sidecar.
     sidecar_path: RwLock<Option<PathBuf>>,
+    /// The database whose sidecar that is.
+    database_path: RwLock<Option<PathBuf>>,
+    /// scope-string → lock held while that scope's DEK is unwrapped or
+    /// created, so the KMS is asked once per scope, and slow or retried
+    /// KMS calls don't block other scopes.
+    loading: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// Cached DEKs are kept for good unless set.
+    revalidation: Option<Revalidation>,
+    /// scope-string → when its cached DEK was unwrapped.
+    validity: RwLock<HashMap<String, Validity>>,
+    /// Algorithm of DEKs created from now on.
+    algorithm: Algorithm,
+    /// KEK ID → the key sidecars are MACed under with it, kept so that
+    /// a sidecar still authenticates while the KMS can't be reached.
+    mac_keys: Mutex<HashMap<KekId, Zeroizing<[u8; 32]>>>,
+    /// Load sidecars from before they were authenticated, and
+    /// authenticate them.
+    upgrade_unauthenticated: bool,
+    /// Sidecar path → the keyring bound to it, for each database
+    /// [`Keyring::for_database`] has handed out one for and that is
+    /// still in use.
+    databases: Mutex<HashMap<PathBuf, Weak<Keyring>>>,
 }
 
 impl Keyring {
@@ -39,76 +425,465 @@ impl Keyring {
             cache: RwLock::new(HashMap::new()),
             persisted: RwLock::new(PersistedKeyring::default()),
             sidecar_path: RwLock::new(None),
+            database_path: RwLock::new(None),
+            loading: Mutex::new(HashMap::new()),
+            revalidation: None,
+            validity: RwLock::new(HashMap::new()),
+            algorithm: Algorithm::default(),
+            mac_keys: Mutex::new(HashMap::new()),
+            upgrade_unauthenticated: false,
+            databases: Mutex::new(HashMap::new()),
         }
     }
 
+    /// An unbound keyring with this one's settings, wrapping DEKs under
+    /// `provider`.
+    pub(crate) fn sibling(&self, provider: Arc<dyn KmsProvider>) -> Keyring {
+        Self {
+            revalidation: self.revalidation,
+            algorithm: self.algorithm,
+            upgrade_unauthenticated: self.upgrade_unauthenticated,
+            ..Self::new(provider)
+        }
+    }
+
+    /// A keyring bound to the sidecar of the database at `db_path`,
+    /// with this one's KMS provider and settings. While it is in use,
+    /// the same one is returned for that database, so the VFS's open
+    /// files and the caller share its DEKs; pass it to the backup API.
+    ///
+    /// The VFS gives every database it opens a keyring of its own this
+    /// way, from the keyring it was registered with, which stays
+    /// unbound.
+    pub fn for_database(&self, db_path: &Path) -> anyhow::Result<Arc<Keyring>> {
+        let sidecar = canonical_sidecar_path(db_path);
+        let mut databases = self.databases.lock();
+        databases.retain(|_, keyring| keyring.strong_count() > 0);
+        if let Some(keyring) = databases.get(&sidecar).and_then(Weak::upgrade) {
+            return Ok(keyring);
+        }
+        let keyring = Arc::new(self.sibling(self.provider.clone()));
+        keyring.set_sidecar_path(db_path)?;
+        databases.insert(sidecar, Arc::downgrade(&keyring));
+        Ok(keyring)
+    }
+
+    /// The keyring [`Keyring::for_database`] has in use for the
+    /// database at `db_path`, if any.
+    fn open_database(&self, db_path: &Path) -> Option<Arc<Keyring>> {
+        let sidecar = canonical_sidecar_path(db_path);
+        self.databases.lock().get(&sidecar).and_then(Weak::upgrade)
+    }
+
+    /// Create new DEKs for `algorithm`. DEKs already in a sidecar keep
+    /// the algorithm they were created with, so a database keeps its
+    /// algorithm until its data key is rotated.
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Unwrap cached DEKs through the KMS again as `revalidation` says,
+    /// rather than keeping them for good: a revoked KEK then stops
+    /// being usable, after at most `every` and `offline_grace`.
+    pub fn with_revalidation(mut self, revalidation: Revalidation) -> Self {
+        self.revalidation = Some(revalidation);
+        self
+    }
+
+    /// Load sidecars written before sidecars were authenticated, rather
+    /// than refusing them, and authenticate them as they are loaded.
+    ///
+    /// Off by default: such a sidecar can't be told from one an
+    /// attacker wrote. Turn it on once, to upgrade databases known to
+    /// be untouched.
+    pub fn with_unauthenticated_upgrade(mut self, upgrade: bool) -> Self {
+        self.upgrade_unauthenticated = upgrade;
+        self
+    }
+
     /// Bind this keyring to a sidecar file next to the database.
-    /// Called when the VFS opens a database file.
-    pub fn set_sidecar_path(&self, db_path: &Path) {
+    /// Called when the VFS opens a database file. An existing sidecar
+    /// is authenticated and loaded, and its DEKs wrapped under a KEK
+    /// the KMS has since rotated away from are rewrapped under the
+    /// current one; a missing sidecar is only created once a DEK is.
+    ///
+    /// Fails, leaving the keyring as it was, if the sidecar can't be
+    /// read or fails authentication, or is unauthenticated and
+    /// [upgrades](Keyring::with_unauthenticated_upgrade) are off.
+    pub fn set_sidecar_path(&self, db_path: &Path) -> anyhow::Result<()> {
         let mut guard = self.sidecar_path.write();
-        let sidecar = db_path.with_extension("evfs-keyring");
-        // Try to load existing keyring.
-        if sidecar.exists()
-            && let Ok(data) = std::fs::read(&sidecar)
-            && let Ok(kr) = bincode::decode_from_slice(&data, config::standard()).map(|r| r.0)
-        {
+        let sidecar = sidecar_path_for(db_path);
+        let loaded = self.authenticated_sidecar(&sidecar)?;
+        let rewrap = loaded.is_some();
+        let mut reauthenticate = false;
+        if let Some((kr, kek_id)) = loaded {
             *self.persisted.write() = kr;
+            reauthenticate = match kek_id {
+                Some(id) => !self.provider.is_current_kek(&id).unwrap_or(true),
+                None => {
+                    log::warn!("authenticating keyring {}", sidecar.display());
+                    true
+                }
+            };
         }
         *guard = Some(sidecar);
+        *self.database_path.write() = Some(db_path.to_path_buf());
+        drop(guard);
+        if rewrap {
+            self.rewrap_stale(reauthenticate);
+        }
+        Ok(())
+    }
+
+    /// The sidecar at `path`, authenticated, and the ID of the KEK it
+    /// is MACed under, or `None` if it is from before sidecars were
+    /// authenticated; `None` altogether if there is no sidecar.
+    fn authenticated_sidecar(
+        &self,
+        path: &Path,
+    ) -> anyhow::Result<Option<(PersistedKeyring, Option<KekId>)>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => anyhow::bail!("cannot read keyring {}: {e}", path.display()),
+        };
+        let (persisted, kek_id) =
+            PersistedKeyring::decode_authenticated(&data, |id| self.mac_key(id))
+                .map_err(|e| anyhow::anyhow!("{}: {e:#}", path.display()))?;
+        anyhow::ensure!(
+            kek_id.is_some() || self.upgrade_unauthenticated,
+            "keyring {} is not authenticated; enable unauthenticated sidecar upgrades to load \
+             and authenticate it",
+            path.display()
+        );
+        Ok(Some((persisted, kek_id)))
+    }
+
+    /// The key sidecars MACed under the KEK `id` are authenticated with.
+    fn mac_key(&self, id: &KekId) -> anyhow::Result<Zeroizing<[u8; 32]>> {
+        if let Some(key) = self.mac_keys.lock().get(id) {
+            return Ok(key.clone());
+        }
+        let key = sidecar_mac_key(&self.provider.get_kek_by_id(id)?);
+        self.mac_keys.lock().insert(id.clone(), key.clone());
+        Ok(key)
+    }
+
+    /// `persisted`, encoded as a sidecar MACed under the current KEK.
+    fn encode_sidecar(&self, persisted: &PersistedKeyring) -> anyhow::Result<Vec<u8>> {
+        let (kek_id, kek) = self.provider.get_kek()?;
+        let key = sidecar_mac_key(&kek);
+        self.mac_keys.lock().insert(kek_id.clone(), key.clone());
+        persisted.encode_authenticated(&kek_id, &key)
+    }
+
+    /// Rewrap the DEKs wrapped under a KEK the KMS has rotated away
+    /// from under the current one, and flush them, as well as the
+    /// sidecar if it is to be MACed again under the current KEK.
+    /// Failures are logged and leave the DEKs as they were, for
+    /// [`Keyring::kek_status`] to report.
+    fn rewrap_stale(&self, reauthenticate: bool) {
+        let persisted: Vec<_> = self.persisted.read().keys.clone().into_iter().collect();
+        let mut rewrapped = Vec::new();
+        for (key, old) in persisted {
+            match envelope::is_current(&old, self.provider.as_ref()) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    log::warn!("cannot check the KEK of the {key} DEK: {e:#}");
+                    continue;
+                }
+            }
+            // Not from the cache: it may hold the DEK of another
+            // database this keyring was bound to before.
+            let new = envelope::unwrap_dek(&old, self.provider.as_ref())
+                .and_then(|dek| envelope::wrap_dek(&dek, self.provider.as_ref()));
+            match new {
+                Ok(new) => rewrapped.push((key, old, new)),
+                Err(e) => log::error!(
+                    "the {key} DEK is wrapped under a rotated KEK and can't be rewrapped: {e:#}"
+                ),
+            }
+        }
+        if rewrapped.is_empty() && !reauthenticate {
+            return;
+        }
+
+        {
+            let mut persisted = self.persisted.write();
+            // Unless another connection replaced it meanwhile.
+            rewrapped.retain(|(key, old, _)| persisted.keys.get(key) == Some(old));
+            for (key, _, new) in &rewrapped {
+                persisted.keys.insert(key.clone(), new.clone());
+            }
+        }
+        match self.flush() {
+            Ok(()) if rewrapped.is_empty() => {
+                log::info!("authenticated the keyring sidecar under the current KEK")
+            }
+            Ok(()) => log::info!(
+                "rewrapped {} DEKs wrapped under a rotated KEK under the current one",
+                rewrapped.len()
+            ),
+            Err(e) => {
+                let mut persisted = self.persisted.write();
+                for (key, old, new) in rewrapped {
+                    if persisted.keys.get(&key) == Some(&new) {
+                        persisted.keys.insert(key, old);
+                    }
+                }
+                log::warn!("cannot rewrap DEKs wrapped under a rotated KEK: {e:#}");
+            }
+        }
+    }
+
+    /// Which KEK each DEK in the keyring is wrapped under, asking the
+    /// KMS. Alert on anything but [`KekStatus::is_current`]: a DEK left
+    /// wrapped under an older KEK is lost once that KEK is disabled.
+    pub fn kek_status(&self) -> KekStatus {
+        let mut persisted: Vec<_> = self.persisted.read().keys.clone().into_iter().collect();
+        persisted.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut status = KekStatus::default();
+        for (key, wrapped) in persisted {
+            let checked =
+                envelope::is_current(&wrapped, self.provider.as_ref()).and_then(|current| {
+                    if !current {
+                        envelope::unwrap_dek(&wrapped, self.provider.as_ref())?;
+                    }
+                    Ok(current)
+                });
+            match checked {
+                Ok(true) => status.current.push(key),
+                Ok(false) => status.stale.push(key),
+                Err(e) => status.unreachable.push((key, format!("{e:#}"))),
+            }
+        }
+        status
+    }
+
+    /// Pick up DEKs that another keyring bound to the same sidecar (a
+    /// second connection to the database) has added since it was
+    /// loaded.
+    fn merge_sidecar(&self) {
+        let guard = self.sidecar_path.read();
+        let Some(path) = guard.as_deref() else {
+            return;
+        };
+        match self.authenticated_sidecar(path) {
+            Ok(Some((kr, _))) => {
+                let mut persisted = self.persisted.write();
+                for (scope, wrapped) in kr.keys {
+                    persisted.keys.entry(scope).or_insert(wrapped);
+                }
+                persisted.concealed_header = persisted.concealed_header.or(kr.concealed_header);
+                persisted.database_id = persisted.database_id.or(kr.database_id);
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("not merging the keyring sidecar: {e:#}"),
+        }
     }
 
     /// Flush wrapped DEKs to the sidecar file.
-    fn flush(&self) {
+    ///
+    /// An ID already in the file is kept whatever this keyring holds:
+    /// the keyring may have been bound to another database since it
+    /// loaded one, and the database's pages are bound to its own. The
+    /// file is authenticated before that, and the flush fails if it
+    /// doesn't, rather than replace it.
+    fn flush(&self) -> anyhow::Result<()> {
         let guard = self.sidecar_path.read();
         if let Some(ref path) = *guard {
-            let persisted = self.persisted.read();
-            if let Ok(data) = bincode::encode_to_vec(&*persisted, config::standard()) {
-                let _ = std::fs::write(path, data);
+            // Held until the file is written, so that flushes of DEKs
+            // created at once for different scopes can't lose either.
+            let current = self.persisted.upgradable_read();
+            let mut persisted = current.clone();
+            if let Some(id) = self
+                .authenticated_sidecar(path)?
+                .and_then(|(kr, _)| kr.database_id)
+            {
+                persisted.database_id = Some(id);
             }
+            write_atomically(path, &self.encode_sidecar(&persisted)?)?;
         }
+        Ok(())
     }
 
     /// Get or create the DEK for a given scope.
+    ///
+    /// A new DEK is only handed out once it is in the sidecar; if the
+    /// sidecar can't be written this fails with [`SidecarReadOnly`].
     pub fn dek_for(&self, scope: &KeyScope) -> anyhow::Result<Dek> {
+        self.dek(scope, true)
+    }
+
+    /// The DEK for `scope` if the keyring has one. Never creates one,
+    /// failing with [`SidecarReadOnly`] instead: for databases open
+    /// read-only.
+    pub fn existing_dek(&self, scope: &KeyScope) -> anyhow::Result<Dek> {
+        self.dek(scope, false)
+    }
+
+    fn dek(&self, scope: &KeyScope, create: bool) -> anyhow::Result<Dek> {
         let key = scope.to_string();
 
         // Fast path.
         {
             let cache = self.cache.read();
-            if let Some(dek) = cache.get(&key) {
-                return Ok(dek.clone());
+            if let Some(cached) = cache.get(&key)
+                && !self.due_for_check(&key)
+            {
+                return Ok(cached.dek.clone());
             }
         }
 
-        // Slow path - acquire write lock.
-        let mut cache = self.cache.write();
+        // Slow path - only one caller loads each scope, and the cache
+        // isn't locked while the KMS is called.
+        let loading = self.loading.lock().entry(key.clone()).or_default().clone();
+        let _loading = loading.lock();
         // Double-check.
-        if let Some(dek) = cache.get(&key) {
-            return Ok(dek.clone());
-        }
-
-        let dek = {
-            let persisted = self.persisted.read();
-            if let Some(wrapped) = persisted.keys.get(&key) {
-                envelope::unwrap_dek(wrapped, self.provider.as_ref())?
-            } else {
-                drop(persisted);
-                let dek = Dek::generate();
-                let wrapped = envelope::wrap_dek(&dek, self.provider.as_ref())?;
-                self.persisted.write().keys.insert(key.clone(), wrapped);
-                self.flush();
-                dek
+        if let Some(cached) = self.cache.read().get(&key)
+            && !self.due_for_check(&key)
+        {
+            return Ok(cached.dek.clone());
+        }
+
+        if !self.persisted.read().keys.contains_key(&key) {
+            self.merge_sidecar();
+        }
+        let wrapped = self.persisted.read().keys.get(&key).cloned();
+        let (dek, wrapped) = if let Some(wrapped) = wrapped {
+            match envelope::unwrap_dek(&wrapped, self.provider.as_ref()) {
+                Ok(dek) => (dek, wrapped),
+                Err(e) => return self.serve_offline(&key, e),
+            }
+        } else if !create {
+            return Err(SidecarReadOnly {
+                scope: key,
+                reason: "the database is open read-only".into(),
             }
+            .into());
+        } else {
+            let dek = Dek::generate_for(self.algorithm);
+            let wrapped = envelope::wrap_dek(&dek, self.provider.as_ref())?;
+            self.persisted
+                .write()
+                .keys
+                .insert(key.clone(), wrapped.clone());
+            if let Err(e) = self.flush() {
+                self.persisted.write().keys.remove(&key);
+                return Err(SidecarReadOnly {
+                    scope: key,
+                    reason: e.to_string(),
+                }
+                .into());
+            }
+            (dek, wrapped)
         };
 
-        cache.insert(key, dek.clone());
+        self.cache_dek(key, dek.clone(), wrapped);
+        Ok(dek)
+    }
+
+    /// Cache `dek` as the DEK for the scope `key`, unwrapped from or
+    /// wrapped to `wrapped` just now.
+    fn cache_dek(&self, key: String, dek: Dek, wrapped: WrappedDek) {
+        if let Some(revalidation) = self.revalidation {
+            let now = Instant::now();
+            let validity = Validity {
+                at: now,
+                next_check: now + revalidation.every,
+            };
+            self.validity.write().insert(key.clone(), validity);
+        }
+        self.cache
+            .write()
+            .insert(key, Box::new(CachedDek { dek, wrapped }));
+    }
+
+    /// Whether the cached DEK for the scope `key` is to be unwrapped
+    /// again before it is used.
+    fn due_for_check(&self, key: &str) -> bool {
+        self.revalidation.is_some()
+            && self
+                .validity
+                .read()
+                .get(key)
+                .is_some_and(|validity| Instant::now() >= validity.next_check)
+    }
+
+    /// Unwrapping the DEK for the scope `key` failed with `e`: serve the
+    /// cached one if its offline grace hasn't run out, and otherwise
+    /// drop it and fail.
+    fn serve_offline(&self, key: &str, e: anyhow::Error) -> anyhow::Result<Dek> {
+        let validity = self.validity.read().get(key).copied();
+        let cached = self.cache.read().get(key).map(|cached| cached.dek.clone());
+        let (Some(revalidation), Some(validity), Some(dek)) = (self.revalidation, validity, cached)
+        else {
+            return Err(e);
+        };
+        let now = Instant::now();
+        let expires = validity.at + revalidation.every + revalidation.offline_grace;
+        if now >= expires {
+            self.cache.write().remove(key);
+            self.validity.write().remove(key);
+            log::error!(
+                "KMS still failing after the offline grace; dropped the cached {key} DEK: {e:#}"
+            );
+            return Err(e.context(format!("the offline grace of the {key} DEK has run out")));
+        }
+        log::warn!(
+            "KMS failing, serving the cached {key} DEK for at most {:?} more: {e:#}",
+            expires - now
+        );
+        let validity = Validity {
+            next_check: (now + revalidation.every).min(expires),
+            ..validity
+        };
+        self.validity.write().insert(key.to_string(), validity);
         Ok(dek)
     }
 
+    /// Every DEK in the keyring, whatever its scope.
+    pub fn all_deks(&self) -> anyhow::Result<Vec<Dek>> {
+        let keys: Vec<String> = self.persisted.read().keys.keys().cloned().collect();
+        let mut deks = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(cached) = self.cache.read().get(&key) {
+                deks.push(cached.dek.clone());
+                continue;
+            }
+            let wrapped = self.persisted.read().keys.get(&key).cloned();
+            if let Some(wrapped) = wrapped {
+                let dek = envelope::unwrap_dek(&wrapped, self.provider.as_ref())?;
+                self.cache_dek(key, dek.clone(), wrapped);
+                deks.push(dek);
+            }
+        }
+        Ok(deks)
+    }
+
+    /// Tables with a DEK of their own (`KeyScope::Table`) in the
+    /// keyring.
+    pub fn table_scopes(&self) -> Vec<String> {
+        self.persisted
+            .read()
+            .keys
+            .keys()
+            .filter_map(|key| key.strip_prefix("table:"))
+            .map(str::to_owned)
+            .collect()
+    }
+
     /// Resolve which DEK to use for a given page number.
     ///
-    /// `page_scope_map` maps root page numbers to scopes (built from
-    /// sqlite_master). Pages not in the map use `Database` scope.
+    /// `page_scope_map` maps the pages of scoped tables to their scope
+    /// (built by walking their b-trees). Pages not in the map use
+    /// `Database` scope.
     pub fn dek_for_page(
         &self,
         page_no: u32,
@@ -121,21 +896,334 @@ impl Keyring {
         self.dek_for(&scope)
     }
 
-    /// Re-wrap all DEKs under the current KEK. Call this after a KEK
-    /// rotation to update the persisted keyring.
-    pub fn rewrap_all(&self) -> anyhow::Result<()> {
-        let cache = self.cache.read();
-        let mut persisted = self.persisted.write();
-        for (scope_key, dek) in cache.iter() {
-            let wrapped = envelope::wrap_dek(dek, self.provider.as_ref())?;
-            persisted.keys.insert(scope_key.clone(), wrapped);
+    /// Re-wrap every DEK in the keyring under the current KEK, whether
+    /// or not it has been used since the keyring was loaded, and flush
+    /// them. Call this after a KEK rotation to update the persisted
+    /// keyring.
+    ///
+    /// Each DEK is unwrapped under the KEK its wrapping names, or, if
+    /// the KMS can't, taken from the cache when that holds it for the
+    /// same wrapping. DEKs that can't be had either way are left as
+    /// they were and reported in the summary; fails, leaving every DEK
+    /// as it was, only if the sidecar can't be written.
+    pub fn rewrap_all(&self) -> anyhow::Result<RewrapSummary> {
+        let mut persisted: Vec<_> = self.persisted.read().keys.clone().into_iter().collect();
+        persisted.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut summary = RewrapSummary::default();
+        let mut rewrapped = Vec::new();
+        for (key, old) in persisted {
+            let new = envelope::unwrap_dek(&old, self.provider.as_ref())
+                .or_else(|e| {
+                    let cache = self.cache.read();
+                    let cached = cache.get(&key).filter(|cached| cached.wrapped == old);
+                    cached.map(|cached| cached.dek.clone()).ok_or(e)
+                })
+                .and_then(|dek| envelope::wrap_dek(&dek, self.provider.as_ref()));
+            match new {
+                Ok(new) => rewrapped.push((key, old, new)),
+                Err(e) => summary.failed.push((key, format!("{e:#}"))),
+            }
+        }
+        if rewrapped.is_empty() {
+            return Ok(summary);
+        }
+
+        {
+            let mut persisted = self.persisted.write();
+            // Unless another connection replaced it meanwhile.
+            rewrapped.retain(|(key, old, _)| {
+                let unchanged = persisted.keys.get(key) == Some(old);
+                if !unchanged {
+                    summary.skipped.push(key.clone());
+                }
+                unchanged
+            });
+            for (key, _, new) in &rewrapped {
+                persisted.keys.insert(key.clone(), new.clone());
+            }
+        }
+        if let Err(e) = self.flush() {
+            let mut persisted = self.persisted.write();
+            for (key, old, new) in rewrapped {
+                if persisted.keys.get(&key) == Some(&new) {
+                    persisted.keys.insert(key, old);
+                }
+            }
+            return Err(e);
+        }
+
+        let mut cache = self.cache.write();
+        for (key, old, new) in rewrapped {
+            if let Some(cached) = cache.get_mut(&key)
+                && cached.wrapped == old
+            {
+                cached.wrapped = new;
+            }
+            summary.rewrapped.push(key);
+        }
+        Ok(summary)
+    }
+
+    /// Destroy the DEK of `scope`, e.g. once its table is dropped:
+    /// remove it from the sidecar, and from the cache, zeroized.
+    /// Returns whether the keyring had one. See [`Keyring::prune`].
+    pub fn remove_scope(&self, scope: &KeyScope, force: bool) -> anyhow::Result<bool> {
+        let key = scope.to_string();
+        Ok(!self.prune(|candidate| candidate == key, force)?.is_empty())
+    }
+
+    /// Destroy the DEK of every scope `remove` picks, given its name in
+    /// the sidecar (`table:<name>`, `column:<table>.<column>`,
+    /// `scope:<name>`), and return those scopes. The `database` scope's
+    /// DEK is never removed.
+    ///
+    /// Unless `force`, this fails, removing nothing, if any page of the
+    /// bound database as last checkpointed still decrypts under one of
+    /// the DEKs, or a DEK can't be unwrapped to check: those pages
+    /// would be lost. Another process's keyring that has the DEKs
+    /// loaded writes them back when it next creates one; remove scopes
+    /// with other processes' connections closed.
+    pub fn prune(
+        &self,
+        mut remove: impl FnMut(&str) -> bool,
+        force: bool,
+    ) -> anyhow::Result<Vec<String>> {
+        self.merge_sidecar();
+        let mut removed: Vec<(String, WrappedDek)> = self
+            .persisted
+            .read()
+            .keys
+            .iter()
+            .filter(|(key, _)| *key != "database" && remove(key))
+            .map(|(key, wrapped)| (key.clone(), wrapped.clone()))
+            .collect();
+        removed.sort_by(|a, b| a.0.cmp(&b.0));
+        if removed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let database = self.database_path.read().clone();
+        if let Some(database) = database.filter(|_| !force) {
+            for (key, wrapped) in &removed {
+                let pages = envelope::unwrap_dek(wrapped, self.provider.as_ref())
+                    .and_then(|dek| crate::verify::pages_under(&database, &dek))
+                    .map_err(|e| anyhow::anyhow!("cannot check the {key} DEK is unused: {e:#}"))?;
+                anyhow::ensure!(
+                    pages == 0,
+                    "{pages} pages of {} are still encrypted under the {key} DEK",
+                    database.display()
+                );
+            }
+        }
+
+        {
+            let mut persisted = self.persisted.write();
+            for (key, _) in &removed {
+                persisted.keys.remove(key);
+            }
+        }
+        if let Err(e) = self.flush() {
+            let mut persisted = self.persisted.write();
+            for (key, wrapped) in removed {
+                persisted.keys.entry(key).or_insert(wrapped);
+            }
+            return Err(e);
+        }
+        let mut cache = self.cache.write();
+        for (key, _) in &removed {
+            // The boxed DEK zeroizes itself as it is dropped.
+            cache.remove(key);
+            self.validity.write().remove(key);
+        }
+        let removed: Vec<String> = removed.into_iter().map(|(key, _)| key).collect();
+        log::info!("removed the DEKs of {removed:?}");
+        Ok(removed)
+    }
+
+    /// Unwrap every DEK in the sidecar of the bound database under
+    /// `provider`, usually this keyring's own, for [`Keyring::import`]
+    /// to move the database to another KEK. The sidecar is read afresh,
+    /// and must authenticate; fails if any DEK doesn't unwrap.
+    pub fn export(&self, provider: &dyn KmsProvider) -> anyhow::Result<KeyringExport> {
+        let database = self
+            .database_path
+            .read()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("the keyring is not bound to a database"))?;
+        let sidecar = sidecar_path_for(&database);
+        let (persisted, _) = self
+            .authenticated_sidecar(&sidecar)?
+            .ok_or_else(|| anyhow::anyhow!("no keyring at {}", sidecar.display()))?;
+        let mut deks = persisted
+            .keys
+            .iter()
+            .map(|(scope, wrapped)| {
+                let dek = envelope::unwrap_dek(wrapped, provider)
+                    .map_err(|e| anyhow::anyhow!("cannot unwrap the {scope} DEK: {e:#}"))?;
+                Ok((scope.clone(), dek))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        deks.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(KeyringExport {
+            database,
+            persisted,
+            deks,
+        })
+    }
+
+    /// Wrap the DEKs of `export` under `provider` and replace the
+    /// database's sidecar with them, MACed under its KEK, returning a
+    /// keyring bound to it: from then on the database opens with
+    /// `provider` and not the KEK it was exported under.
+    ///
+    /// Each DEK is unwrapped again before the sidecar is written, so a
+    /// KEK that doesn't round-trip leaves the old sidecar in place, and
+    /// the new sidecar is read back once written. Close every connection to the
+    /// database first: an open one keeps writing under the old KEK.
+    pub fn import(export: KeyringExport, provider: Arc<dyn KmsProvider>) -> anyhow::Result<Self> {
+        let KeyringExport {
+            database,
+            mut persisted,
+            deks,
+        } = export;
+        let sidecar = sidecar_path_for(&database);
+        for (scope, dek) in &deks {
+            let wrapped = envelope::wrap_dek(dek, provider.as_ref())?;
+            anyhow::ensure!(
+                envelope::unwrap_dek(&wrapped, provider.as_ref())? == *dek,
+                "the {scope} DEK doesn't unwrap as it was wrapped"
+            );
+            persisted.keys.insert(scope.clone(), wrapped);
+        }
+
+        let keyring = Self::new(provider);
+        write_atomically(&sidecar, &keyring.encode_sidecar(&persisted)?)?;
+        let read_back = keyring.authenticated_sidecar(&sidecar)?;
+        anyhow::ensure!(
+            read_back.is_some_and(|(read_back, _)| read_back.keys == persisted.keys),
+            "keyring {} doesn't read back as written",
+            sidecar.display()
+        );
+        *keyring.persisted.write() = persisted;
+        *keyring.sidecar_path.write() = Some(sidecar.clone());
+        *keyring.database_path.write() = Some(database);
+        log::info!("keyring {} moved to a new KEK", sidecar.display());
+        Ok(keyring)
+    }
+
+    /// Record in the sidecar that the bound database conceals its
+    /// header, with the page geometry needed to read it.
+    pub(crate) fn conceal_header(&self, header: ConcealedHeader) -> anyhow::Result<()> {
+        let previous = self.persisted.write().concealed_header.replace(header);
+        self.flush().inspect_err(|_| {
+            self.persisted.write().concealed_header = previous;
+        })
+    }
+
+    /// Record in the sidecar the ID of the bound database, a new one
+    /// that has none yet.
+    pub(crate) fn record_database_id(&self, id: DatabaseId) -> anyhow::Result<()> {
+        let previous = self.persisted.write().database_id.replace(id);
+        self.flush().inspect_err(|_| {
+            self.persisted.write().database_id = previous;
+        })
+    }
+
+    /// The ID of the database at `db_path`, recording a new one in its
+    /// sidecar if it has none.
+    pub(crate) fn database_id_for(&self, db_path: &Path) -> anyhow::Result<DatabaseId> {
+        if let Some(id) = database_id_of(db_path) {
+            return Ok(id);
+        }
+        let id = DatabaseId::generate();
+        self.install_database_id(db_path, id)?;
+        Ok(id)
+    }
+
+    /// Record `id` as the ID of the database at `db_path`, replacing
+    /// any in its sidecar, as [`Keyring::install_dek`] does a DEK.
+    pub(crate) fn install_database_id(&self, db_path: &Path, id: DatabaseId) -> anyhow::Result<()> {
+        self.update_sidecar(db_path, |persisted| persisted.database_id = Some(id))?;
+        Ok(())
+    }
+
+    /// Record in the sidecar of the database at `db_path` that it
+    /// conceals its header, as [`Keyring::install_database_id`] does
+    /// its ID.
+    pub(crate) fn install_concealed_header(
+        &self,
+        db_path: &Path,
+        header: ConcealedHeader,
+    ) -> anyhow::Result<()> {
+        self.update_sidecar(db_path, |persisted| {
+            persisted.concealed_header = Some(header)
+        })?;
+        Ok(())
+    }
+
+    /// Re-encrypt the database at `db_path` under a fresh
+    /// Database-scope DEK. See [`crate::rekey::rekey_database`].
+    pub fn rotate_data_key(&self, db_path: &Path) -> anyhow::Result<crate::rekey::RekeyReport> {
+        crate::rekey::rekey_from_header(db_path, self)
+    }
+
+    /// Record `dek` as the DEK for `scope` in the sidecar of the
+    /// database at `db_path`, replacing the file atomically. The
+    /// in-memory copy is updated too when this keyring is bound to
+    /// that database, or has a keyring in use for it.
+    pub(crate) fn install_dek(
+        &self,
+        db_path: &Path,
+        scope: &KeyScope,
+        dek: Dek,
+        wrapped: WrappedDek,
+    ) -> anyhow::Result<()> {
+        if let Some(keyring) = self.open_database(db_path) {
+            return keyring.install_dek(db_path, scope, dek, wrapped);
+        }
+        let key = scope.to_string();
+        let bound = self.update_sidecar(db_path, |persisted| {
+            persisted.keys.insert(key.clone(), wrapped.clone());
+        })?;
+        if bound {
+            self.cache_dek(key, dek, wrapped);
         }
-        drop(persisted);
-        drop(cache);
-        self.flush();
         Ok(())
     }
 
+    /// Apply `update` to the sidecar of the database at `db_path`,
+    /// replacing the file atomically, and to the in-memory copy when
+    /// this keyring is bound to that database. Returns whether it is.
+    /// A keyring in use for that database is updated instead of this
+    /// one.
+    fn update_sidecar(
+        &self,
+        db_path: &Path,
+        update: impl FnOnce(&mut PersistedKeyring),
+    ) -> anyhow::Result<bool> {
+        if let Some(keyring) = self.open_database(db_path) {
+            keyring.update_sidecar(db_path, update)?;
+            return Ok(false);
+        }
+        let sidecar = sidecar_path_for(db_path);
+        let bound = self.sidecar_path.read().as_deref() == Some(sidecar.as_path());
+
+        let mut persisted = if bound {
+            self.persisted.read().clone()
+        } else {
+            self.authenticated_sidecar(&sidecar)?
+                .map(|(persisted, _)| persisted)
+                .unwrap_or_default()
+        };
+        update(&mut persisted);
+        write_atomically(&sidecar, &self.encode_sidecar(&persisted)?)?;
+
+        if bound {
+            *self.persisted.write() = persisted;
+        }
+        Ok(bound)
+    }
+
     pub fn provider(&self) -> &dyn KmsProvider {
         self.provider.as_ref()
     }
@@ -143,12 +1231,22 @@ impl Keyring {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::mpsc;
+
     use super::*;
-    use crate::tests::MockKmsProvider;
+    use crate::{
+        crypto::keys::KekId,
+        kms::{
+            KekBytes,
+            KmsProvider,
+            local::DeviceKeyProvider,
+            mock::{Call, MockKmsProvider},
+        },
+    };
 
     #[test]
     fn test_new_keyring() {
-        let provider = MockKmsProvider::new();
+        let provider = Arc::new(MockKmsProvider::new());
         let keyring = Keyring::new(provider.clone());
         assert_eq!(keyring.cache.read().len(), 0);
         assert_eq!(keyring.persisted.read().keys.len(), 0);
@@ -156,7 +1254,7 @@ mod tests {
 
     #[test]
     fn test_dek_for_new_scope() {
-        let provider = MockKmsProvider::new();
+        let provider = Arc::new(MockKmsProvider::new());
         let keyring = Keyring::new(provider.clone());
         let scope = KeyScope::Database;
 
@@ -168,22 +1266,22 @@ mod tests {
 
     #[test]
     fn test_dek_cache_hit() {
-        let provider = MockKmsProvider::new();
+        let provider = Arc::new(MockKmsProvider::new());
         let keyring = Keyring::new(provider.clone());
         let scope = KeyScope::Database;
 
         let dek1 = keyring.dek_for(&scope).unwrap();
-        let wrap_count = *provider.wrap_count.lock().unwrap();
+        let calls = provider.total_calls();
 
         let dek2 = keyring.dek_for(&scope).unwrap();
-        // Should use cached DEK, no additional wrap
-        assert_eq!(*provider.wrap_count.lock().unwrap(), wrap_count);
+        // Should use cached DEK, no additional KMS call
+        assert_eq!(provider.total_calls(), calls);
         assert_eq!(dek1, dek2);
     }
 
     #[test]
     fn test_multiple_scopes() {
-        let provider = MockKmsProvider::new();
+        let provider = Arc::new(MockKmsProvider::new());
         let keyring = Keyring::new(provider.clone());
 
         let db_scope = KeyScope::Database;
@@ -200,7 +1298,7 @@ mod tests {
 
     #[test]
     fn test_dek_for_page_default_scope() {
-        let provider = MockKmsProvider::new();
+        let provider = Arc::new(MockKmsProvider::new());
         let keyring = Keyring::new(provider.clone());
 
         let dek = keyring.dek_for_page(42, None).unwrap();
@@ -211,7 +1309,7 @@ mod tests {
 
     #[test]
     fn test_dek_for_page_with_map() {
-        let provider = MockKmsProvider::new();
+        let provider = Arc::new(MockKmsProvider::new());
         let keyring = Keyring::new(provider.clone());
 
         let mut page_map = HashMap::new();
@@ -230,7 +1328,7 @@ mod tests {
 
     #[test]
     fn test_rewrap_all() {
-        let provider = MockKmsProvider::new();
+        let provider = Arc::new(MockKmsProvider::new());
         let keyring = Keyring::new(provider.clone());
 
         keyring.dek_for(&KeyScope::Database).unwrap();
@@ -239,7 +1337,9 @@ mod tests {
         let persisted_before = keyring.persisted.read().keys.clone();
         let keys_before: Vec<_> = persisted_before.values().cloned().collect();
 
-        keyring.rewrap_all().unwrap();
+        let summary = keyring.rewrap_all().unwrap();
+        assert_eq!(summary.rewrapped, ["database", "table:t1"]);
+        assert!(summary.is_complete());
 
         let persisted_after = keyring.persisted.read().keys.clone();
         let keys_after: Vec<_> = persisted_after.values().cloned().collect();
@@ -251,9 +1351,630 @@ mod tests {
         assert_ne!(keys_before, keys_after);
     }
 
+    #[test]
+    fn test_keyrings_on_one_sidecar_share_new_deks() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("shared.db");
+        let keyfile = dir.path().join("shared.key");
+        std::fs::write(&keyfile, [0x3C; 32]).unwrap();
+        let provider = Arc::new(crate::kms::local::DeviceKeyProvider::from_keyfile(keyfile));
+        let first = Keyring::new(provider.clone());
+        let second = Keyring::new(provider.clone());
+        first.set_sidecar_path(&db_path).unwrap();
+        second.set_sidecar_path(&db_path).unwrap();
+
+        // `second` loaded the sidecar before `first` created the DEK.
+        let scope = KeyScope::Named("tenant1".to_string());
+        let dek = first.dek_for(&scope).unwrap();
+        assert_eq!(second.dek_for(&scope).unwrap(), dek);
+    }
+
+    /// Blocks unwrapping while armed, until released.
+    struct GatedProvider {
+        inner: crate::kms::local::DeviceKeyProvider,
+        gate: Mutex<Option<(mpsc::Sender<()>, mpsc::Receiver<()>)>>,
+    }
+
+    impl KmsProvider for GatedProvider {
+        fn get_kek(&self) -> anyhow::Result<(KekId, KekBytes)> {
+            self.inner.get_kek()
+        }
+
+        fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<KekBytes> {
+            if let Some((entered, release)) = self.gate.lock().take() {
+                entered.send(()).unwrap();
+                release.recv().unwrap();
+            }
+            self.inner.get_kek_by_id(id)
+        }
+    }
+
+    #[test]
+    fn test_slow_kms_blocks_only_its_scope() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("slow.db");
+        let keyfile = dir.path().join("slow.key");
+        std::fs::write(&keyfile, [0x4D; 32]).unwrap();
+        let first = Keyring::new(Arc::new(
+            crate::kms::local::DeviceKeyProvider::from_keyfile(keyfile.clone()),
+        ));
+        first.set_sidecar_path(&db_path).unwrap();
+        let slow = KeyScope::Named("slow".to_string());
+        let slow_dek = first.dek_for(&slow).unwrap();
+        let database_dek = first.dek_for(&KeyScope::Database).unwrap();
+
+        let provider = Arc::new(GatedProvider {
+            inner: crate::kms::local::DeviceKeyProvider::from_keyfile(keyfile),
+            gate: Mutex::new(None),
+        });
+        let second = Keyring::new(provider.clone());
+        second.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(second.dek_for(&KeyScope::Database).unwrap(), database_dek);
+
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        *provider.gate.lock() = Some((entered_tx, release_rx));
+        std::thread::scope(|s| {
+            let loading = s.spawn(|| second.dek_for(&slow).unwrap());
+            entered_rx.recv().unwrap();
+            // While the KMS is unwrapping `slow`, other scopes are
+            // served, and created.
+            assert_eq!(second.dek_for(&KeyScope::Database).unwrap(), database_dek);
+            let new = second.dek_for(&KeyScope::Named("new".to_string())).unwrap();
+            release_tx.send(()).unwrap();
+            assert_eq!(loading.join().unwrap(), slow_dek);
+            assert_eq!(
+                first.dek_for(&KeyScope::Named("new".to_string())).unwrap(),
+                new
+            );
+        });
+    }
+
+    #[test]
+    fn test_cached_deks_outlive_the_kms_for_the_grace() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let provider = Arc::new(MockKmsProvider::new());
+        let keyring = Keyring::new(provider.clone()).with_revalidation(Revalidation {
+            every: Duration::from_millis(100),
+            offline_grace: Duration::from_millis(300),
+        });
+        keyring
+            .set_sidecar_path(&dir.path().join("grace.db"))
+            .unwrap();
+        let start = Instant::now();
+        let dek = keyring.dek_for(&KeyScope::Database).unwrap();
+
+        provider.set_offline(true);
+        assert_eq!(keyring.dek_for(&KeyScope::Database).unwrap(), dek);
+        assert_eq!(provider.calls(Call::GetKekById), 0);
+        // New scopes need the KMS.
+        let named = KeyScope::Named("new".to_string());
+        assert!(keyring.dek_for(&named).is_err());
+
+        // Due for a check, which fails; served from the cache, and not
+        // checked again until due.
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(keyring.dek_for(&KeyScope::Database).unwrap(), dek);
+        assert_eq!(keyring.dek_for(&KeyScope::Database).unwrap(), dek);
+        assert_eq!(provider.calls(Call::GetKekById), 1);
+
+        // The grace ran out.
+        std::thread::sleep(Duration::from_millis(450).saturating_sub(start.elapsed()));
+        let e = keyring.dek_for(&KeyScope::Database).unwrap_err();
+        assert!(format!("{e:#}").contains("offline grace"), "{e:#}");
+        assert!(keyring.dek_for(&KeyScope::Database).is_err());
+
+        provider.set_offline(false);
+        assert_eq!(keyring.dek_for(&KeyScope::Database).unwrap(), dek);
+        assert_eq!(
+            keyring.dek_for(&named).unwrap(),
+            keyring.dek_for(&named).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_existing_dek_never_creates() {
+        let keyring = Keyring::new(Arc::new(MockKmsProvider::new()));
+        let err = keyring.existing_dek(&KeyScope::Database).unwrap_err();
+        assert!(err.is::<SidecarReadOnly>());
+        assert!(keyring.persisted.read().keys.is_empty());
+
+        let dek = keyring.dek_for(&KeyScope::Database).unwrap();
+        assert_eq!(keyring.existing_dek(&KeyScope::Database).unwrap(), dek);
+    }
+
+    #[test]
+    fn test_deks_are_rewrapped_after_kek_rotation() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("rotated.db");
+        let sidecar_kek_ids = || -> Vec<String> {
+            let mut ids: Vec<_> = load_sidecar(&sidecar_path_for(&db_path))
+                .unwrap()
+                .keys
+                .into_values()
+                .map(|wrapped| wrapped.kek_id.0)
+                .collect();
+            ids.sort();
+            ids
+        };
+        let provider = Arc::new(MockKmsProvider::new());
+        let v1 = provider.current_kek_id();
+        let first = Keyring::new(provider.clone());
+        first.set_sidecar_path(&db_path).unwrap();
+        let database = first.dek_for(&KeyScope::Database).unwrap();
+        let table = first.dek_for(&KeyScope::Table("t".into())).unwrap();
+        assert!(first.kek_status().is_current());
+
+        let v2 = provider.rotate();
+        assert_eq!(
+            first.kek_status(),
+            KekStatus {
+                stale: vec!["database".into(), "table:t".into()],
+                ..KekStatus::default()
+            }
+        );
+
+        // Loading the sidecar rewraps both DEKs under v2.
+        let second = Keyring::new(provider.clone());
+        second.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(sidecar_kek_ids(), [v2.0.as_str(); 2]);
+        assert_eq!(second.kek_status().current.len(), 2);
+        provider.set_enabled(&v1, false);
+        let third = Keyring::new(provider.clone());
+        third.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(third.dek_for(&KeyScope::Database).unwrap(), database);
+        assert_eq!(third.dek_for(&KeyScope::Table("t".into())).unwrap(), table);
+
+        // v2 is disabled before the sidecar is loaded again: it can't
+        // be authenticated but by a keyring that has before.
+        provider.rotate();
+        provider.set_enabled(&v2, false);
+        let err = Keyring::new(provider.clone())
+            .set_sidecar_path(&db_path)
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("mock-v2\" is disabled"),
+            "{err:#}"
+        );
+        third.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(sidecar_kek_ids(), [v2.0.as_str(); 2]);
+        let status = third.kek_status();
+        assert!(!status.is_current());
+        assert_eq!(status.unreachable.len(), 2);
+        assert!(
+            status.unreachable[0].1.contains("mock-v2\" is disabled"),
+            "{status:?}"
+        );
+        // Which authenticated it again under the current KEK.
+        let fourth = Keyring::new(provider.clone());
+        fourth.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(fourth.kek_status().unreachable.len(), 2);
+    }
+
+    #[test]
+    fn test_rewrap_all_covers_deks_not_cached() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("uncached.db");
+        let provider = Arc::new(MockKmsProvider::new());
+        let v1 = provider.current_kek_id();
+        let first = Keyring::new(provider.clone());
+        first.set_sidecar_path(&db_path).unwrap();
+        let database = first.dek_for(&KeyScope::Database).unwrap();
+        let table = first.dek_for(&KeyScope::Table("t".into())).unwrap();
+
+        // Bound after both DEKs were made, and using neither.
+        let second = Keyring::new(provider.clone());
+        second.set_sidecar_path(&db_path).unwrap();
+        assert!(second.cache.read().is_empty());
+        let v2 = provider.rotate();
+        let summary = second.rewrap_all().unwrap();
+        assert_eq!(summary.rewrapped, ["database", "table:t"]);
+        assert!(summary.is_complete());
+
+        provider.set_enabled(&v1, false);
+        let third = Keyring::new(provider.clone());
+        third.set_sidecar_path(&db_path).unwrap();
+        assert!(third.kek_status().is_current());
+        assert_eq!(third.dek_for(&KeyScope::Database).unwrap(), database);
+        assert_eq!(third.dek_for(&KeyScope::Table("t".into())).unwrap(), table);
+
+        // v2 is disabled before rewrapping: only the DEK cached for its
+        // wrapping can still be had.
+        provider.rotate();
+        provider.set_enabled(&v2, false);
+        let summary = third.rewrap_all().unwrap();
+        assert_eq!(summary.rewrapped, ["database", "table:t"]);
+        assert!(third.kek_status().is_current());
+        let fourth = Keyring::new(provider.clone());
+        fourth.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(fourth.dek_for(&KeyScope::Database).unwrap(), database);
+    }
+
+    #[test]
+    fn test_rewrap_all_reports_deks_it_cannot_unwrap() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("partial.db");
+        let provider = Arc::new(MockKmsProvider::new());
+        let v1 = provider.current_kek_id();
+        let first = Keyring::new(provider.clone());
+        first.set_sidecar_path(&db_path).unwrap();
+        first.dek_for(&KeyScope::Table("t".into())).unwrap();
+        let second = Keyring::new(provider.clone());
+        second.set_sidecar_path(&db_path).unwrap();
+        let database = second.dek_for(&KeyScope::Database).unwrap();
+        let other = dir.path().join("other.db");
+        let other_keyring = Keyring::new(provider.clone());
+        other_keyring.set_sidecar_path(&other).unwrap();
+        other_keyring.dek_for(&KeyScope::Database).unwrap();
+
+        provider.rotate();
+        provider.set_enabled(&v1, false);
+        let summary = second.rewrap_all().unwrap();
+        assert_eq!(summary.rewrapped, ["database"]);
+        assert!(!summary.is_complete());
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, "table:t");
+        assert!(
+            summary.failed[0].1.contains("mock-v1\" is disabled"),
+            "{summary:?}"
+        );
+
+        let third = Keyring::new(provider.clone());
+        third.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(third.dek_for(&KeyScope::Database).unwrap(), database);
+
+        // Bound to another database, whose DEK the cache doesn't hold
+        // though it holds one for the same scope.
+        let before = load_sidecar(&sidecar_path_for(&other)).unwrap();
+        second.set_sidecar_path(&other).unwrap();
+        let summary = second.rewrap_all().unwrap();
+        assert!(summary.rewrapped.is_empty());
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, "database");
+        let after = load_sidecar(&sidecar_path_for(&other)).unwrap();
+        assert_eq!(after.keys, before.keys);
+    }
+
+    #[test]
+    fn test_removed_scopes_leave_the_sidecar_and_cache() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("pruned.db");
+        let keyring = Keyring::new(Arc::new(MockKmsProvider::new()));
+        keyring.set_sidecar_path(&db_path).unwrap();
+        let database = keyring.dek_for(&KeyScope::Database).unwrap();
+        for table in ["a", "b", "c"] {
+            keyring.dek_for(&KeyScope::Table(table.into())).unwrap();
+        }
+        let column = KeyScope::Column {
+            table: "a".into(),
+            column: "ssn".into(),
+        };
+        keyring.dek_for(&column).unwrap();
+        let sidecar_scopes = || -> Vec<String> {
+            let mut scopes: Vec<_> = load_sidecar(&sidecar_path_for(&db_path))
+                .unwrap()
+                .keys
+                .into_keys()
+                .collect();
+            scopes.sort();
+            scopes
+        };
+
+        // No database file, so no pages under any DEK.
+        assert!(keyring.remove_scope(&column, false).unwrap());
+        assert!(!keyring.remove_scope(&column, false).unwrap());
+        let removed = keyring.prune(|scope| scope != "table:b", false).unwrap();
+        assert_eq!(removed, ["table:a", "table:c"]);
+        assert_eq!(sidecar_scopes(), ["database", "table:b"]);
+        assert_eq!(keyring.cache.read().len(), 2);
+
+        // The database's own DEK stays whatever is asked.
+        assert!(!keyring.remove_scope(&KeyScope::Database, true).unwrap());
+        assert_eq!(keyring.existing_dek(&KeyScope::Database).unwrap(), database);
+        let fresh = Keyring::new(keyring.provider.clone());
+        fresh.set_sidecar_path(&db_path).unwrap();
+        assert!(fresh.existing_dek(&KeyScope::Table("a".into())).is_err());
+    }
+
+    #[test]
+    fn test_export_import_moves_deks_to_another_kek() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("moved.db");
+        let old = Arc::new(MockKmsProvider::new().with_kek([0x01; 32]));
+        let first = Keyring::new(old.clone());
+        first.set_sidecar_path(&db_path).unwrap();
+        let database = first.dek_for(&KeyScope::Database).unwrap();
+        let table = first.dek_for(&KeyScope::Table("t".into())).unwrap();
+        first.record_database_id(DatabaseId::generate()).unwrap();
+        let id = database_id_of(&db_path);
+
+        let export = first.export(old.as_ref()).unwrap();
+        assert_eq!(export.scopes(), ["database", "table:t"]);
+        let new = Arc::new(MockKmsProvider::new().with_kek([0x02; 32]));
+        let imported = Keyring::import(export, new.clone()).unwrap();
+        assert_eq!(imported.dek_for(&KeyScope::Database).unwrap(), database);
+        assert!(imported.kek_status().is_current());
+        assert_eq!(database_id_of(&db_path), id);
+
+        // Only the new KEK opens it now.
+        let reopened = Keyring::new(new.clone());
+        reopened.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(reopened.dek_for(&KeyScope::Database).unwrap(), database);
+        assert_eq!(
+            reopened.dek_for(&KeyScope::Table("t".into())).unwrap(),
+            table
+        );
+        let err = Keyring::new(old.clone())
+            .set_sidecar_path(&db_path)
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("failed authentication"),
+            "{err:#}"
+        );
+
+        // Exporting under a KEK that doesn't unwrap the DEKs fails.
+        assert!(reopened.export(old.as_ref()).is_err());
+        assert!(Keyring::new(new).export(old.as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_deks_move_to_direct_wrapping() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("direct.db");
+        let local = Arc::new(MockKmsProvider::new().with_kek([0xAA; 32]));
+        let first = Keyring::new(local.clone());
+        first.set_sidecar_path(&db_path).unwrap();
+        let dek = first.dek_for(&KeyScope::Database).unwrap();
+
+        // The same key, now behind a KMS that wraps DEKs itself.
+        let direct = Arc::new(
+            MockKmsProvider::new()
+                .with_kek([0xAA; 32])
+                .with_direct_wrap(),
+        );
+        let second = Keyring::new(direct.clone());
+        second.set_sidecar_path(&db_path).unwrap();
+        let sidecar = load_sidecar(&sidecar_path_for(&db_path)).unwrap();
+        assert!(sidecar.keys.values().all(WrappedDek::is_direct));
+        assert!(second.kek_status().is_current());
+        assert_eq!(direct.calls(Call::WrapBlob), 1);
+        assert_eq!(direct.calls(Call::UnwrapBlob), 0);
+
+        let third = Keyring::new(direct.clone());
+        third.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(third.dek_for(&KeyScope::Database).unwrap(), dek);
+
+        // A key source without the KEK can't even authenticate them.
+        let device = Keyring::new(Arc::new(DeviceKeyProvider::from_passphrase("test")));
+        assert!(device.set_sidecar_path(&db_path).is_err());
+
+        // And going back to local wrapping rewraps them again.
+        let fourth = Keyring::new(local);
+        fourth.set_sidecar_path(&db_path).unwrap();
+        let sidecar = load_sidecar(&sidecar_path_for(&db_path)).unwrap();
+        assert!(!sidecar.keys.values().any(WrappedDek::is_direct));
+        assert_eq!(fourth.dek_for(&KeyScope::Database).unwrap(), dek);
+    }
+
+    #[test]
+    fn test_rewrapping_ignores_deks_cached_for_another_database() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (a, b) = (dir.path().join("a.db"), dir.path().join("b.db"));
+        let provider = Arc::new(MockKmsProvider::new());
+        let keyring = Keyring::new(provider.clone());
+        keyring.set_sidecar_path(&b).unwrap();
+        let b_dek = keyring.dek_for(&KeyScope::Database).unwrap();
+
+        provider.rotate();
+        let shared = Keyring::new(provider.clone());
+        shared.set_sidecar_path(&a).unwrap();
+        let a_dek = shared.dek_for(&KeyScope::Database).unwrap();
+        // Still caching the DEK of `a` as it loads `b`.
+        shared.set_sidecar_path(&b).unwrap();
+
+        let reopened = Keyring::new(provider.clone());
+        reopened.set_sidecar_path(&b).unwrap();
+        assert!(reopened.kek_status().is_current());
+        let dek = reopened.dek_for(&KeyScope::Database).unwrap();
+        assert_eq!(dek, b_dek);
+        assert_ne!(dek, a_dek);
+    }
+
+    #[test]
+    fn test_unwritable_sidecar_keeps_no_dek() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keyring = Keyring::new(Arc::new(MockKmsProvider::new()));
+        keyring
+            .set_sidecar_path(&dir.path().join("missing-dir").join("db"))
+            .unwrap();
+
+        let err = keyring.dek_for(&KeyScope::Database).unwrap_err();
+        assert!(err.is::<SidecarReadOnly>(), "{err}");
+        assert!(keyring.persisted.read().keys.is_empty());
+        assert!(keyring.cache.read().is_empty());
+    }
+
+    #[test]
+    fn test_failed_rename_keeps_no_dek() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("db");
+        // A directory in the way fails the rename over it, whoever runs
+        // the test.
+        let sidecar = sidecar_path_for(&db_path);
+        let keyring = Keyring::new(Arc::new(MockKmsProvider::new()));
+        keyring.set_sidecar_path(&db_path).unwrap();
+        std::fs::create_dir(&sidecar).unwrap();
+        std::fs::write(sidecar.join("in-the-way"), b"").unwrap();
+
+        assert!(write_atomically(&sidecar, b"keys").is_err());
+        let err = keyring.dek_for(&KeyScope::Database).unwrap_err();
+        assert!(err.is::<SidecarReadOnly>(), "{err}");
+        assert!(keyring.cache.read().is_empty());
+        // Nor is the temporary file left behind.
+        let entries: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, [sidecar.file_name().unwrap()]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sidecar_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("db");
+        let sidecar = sidecar_path_for(&db_path);
+        let mode = || std::fs::metadata(&sidecar).unwrap().permissions().mode() & 0o777;
+        let keyring = Keyring::new(Arc::new(MockKmsProvider::new()));
+        keyring.set_sidecar_path(&db_path).unwrap();
+        keyring.dek_for(&KeyScope::Database).unwrap();
+        assert_eq!(mode(), 0o600);
+
+        // A sidecar written before is made private when next written.
+        std::fs::set_permissions(&sidecar, std::fs::Permissions::from_mode(0o644)).unwrap();
+        keyring.dek_for(&KeyScope::Table("t".into())).unwrap();
+        assert_eq!(mode(), 0o600);
+    }
+
+    #[test]
+    fn test_tampered_sidecar_is_refused() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("tampered.db");
+        let sidecar = sidecar_path_for(&db_path);
+        let provider = Arc::new(MockKmsProvider::new());
+        let keyring = Keyring::new(provider.clone());
+        keyring.set_sidecar_path(&db_path).unwrap();
+        keyring.dek_for(&KeyScope::Database).unwrap();
+        let original = std::fs::read(&sidecar).unwrap();
+        let persisted = PersistedKeyring::decode(&original).unwrap();
+        assert!(persisted.keys.contains_key("database"));
+
+        let refused = |data: &[u8]| {
+            std::fs::write(&sidecar, data).unwrap();
+            let err = Keyring::new(provider.clone())
+                .set_sidecar_path(&db_path)
+                .unwrap_err();
+            format!("{err:#}")
+        };
+        let mut flipped = original.clone();
+        let middle = flipped.len() / 2;
+        flipped[middle] ^= 1;
+        assert!(refused(&flipped).contains("failed authentication"));
+
+        // Dropping the DEK, MACed under a key of the attacker's.
+        let emptied = PersistedKeyring {
+            keys: HashMap::new(),
+            ..persisted.clone()
+        };
+        let kek_id = provider.current_kek_id();
+        let forged = emptied.encode_authenticated(&kek_id, &[0x66; 32]).unwrap();
+        assert!(refused(&forged).contains("failed authentication"));
+
+        // Swapping in another DEK, unauthenticated.
+        let mut swapped = persisted.clone();
+        let other = envelope::wrap_dek(&Dek::generate(), provider.as_ref()).unwrap();
+        swapped.keys.insert("database".into(), other);
+        let unauthenticated = bincode::encode_to_vec(&swapped, config::standard()).unwrap();
+        assert!(refused(&unauthenticated).contains("is not authenticated"));
+
+        // The keyring bound to it creates no DEK over it either.
+        let err = keyring.dek_for(&KeyScope::Table("t".into())).unwrap_err();
+        assert!(err.is::<SidecarReadOnly>(), "{err}");
+        assert_eq!(std::fs::read(&sidecar).unwrap(), unauthenticated);
+    }
+
+    #[test]
+    fn test_unauthenticated_sidecar_is_upgraded_on_request() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db_path = dir.path().join("legacy.db");
+        let sidecar = sidecar_path_for(&db_path);
+        let provider = Arc::new(MockKmsProvider::new());
+        let dek = Dek::generate();
+        let legacy = PersistedKeyring {
+            keys: HashMap::from([(
+                "database".into(),
+                envelope::wrap_dek(&dek, provider.as_ref()).unwrap(),
+            )]),
+            ..PersistedKeyring::default()
+        };
+        let data = bincode::encode_to_vec(&legacy, config::standard()).unwrap();
+        std::fs::write(&sidecar, &data).unwrap();
+
+        // Read for reading the database, with a warning...
+        let deks = sidecar_deks(&db_path, provider.as_ref()).unwrap();
+        assert_eq!(deks.len(), 1);
+        assert_eq!(deks[0], dek);
+        // ...but not bound to without the upgrade.
+        let err = Keyring::new(provider.clone())
+            .set_sidecar_path(&db_path)
+            .unwrap_err();
+        assert!(err.to_string().contains("is not authenticated"), "{err}");
+        assert_eq!(std::fs::read(&sidecar).unwrap(), data);
+
+        let upgrading = Keyring::new(provider.clone()).with_unauthenticated_upgrade(true);
+        upgrading.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(upgrading.dek_for(&KeyScope::Database).unwrap(), dek);
+        assert!(std::fs::read(&sidecar).unwrap().starts_with(SIDECAR_MAGIC));
+
+        let keyring = Keyring::new(provider);
+        keyring.set_sidecar_path(&db_path).unwrap();
+        assert_eq!(keyring.dek_for(&KeyScope::Database).unwrap(), dek);
+    }
+
+    #[test]
+    fn test_decode_legacy_sidecar() {
+        let keyring = Keyring::new(Arc::new(MockKmsProvider::new()));
+        keyring.dek_for(&KeyScope::Database).unwrap();
+        let keys = keyring.persisted.read().keys.clone();
+
+        // Sidecars from before the header format was recorded.
+        let legacy = bincode::encode_to_vec(&keys, config::standard()).unwrap();
+        let persisted = PersistedKeyring::decode(&legacy).unwrap();
+        assert_eq!(persisted.keys, keys);
+        assert_eq!(persisted.concealed_header, None);
+
+        let header = Some(ConcealedHeader {
+            page_size: 8192,
+            reserve_size: 48,
+        });
+        #[derive(bincode::Encode)]
+        struct V2 {
+            keys: HashMap<String, WrappedDek>,
+            concealed_header: Option<ConcealedHeader>,
+        }
+        let v2 = bincode::encode_to_vec(
+            V2 {
+                keys: keys.clone(),
+                concealed_header: header,
+            },
+            config::standard(),
+        )
+        .unwrap();
+        let persisted = PersistedKeyring::decode(&v2).unwrap();
+        assert_eq!(persisted.concealed_header, header);
+        assert_eq!(persisted.database_id, None);
+
+        let id = Some(DatabaseId([7; 16]));
+        let current = bincode::encode_to_vec(
+            PersistedKeyring {
+                keys: keys.clone(),
+                concealed_header: header,
+                database_id: id,
+            },
+            config::standard(),
+        )
+        .unwrap();
+        let persisted = PersistedKeyring::decode(&current).unwrap();
+        assert_eq!(persisted.concealed_header, header);
+        assert_eq!(persisted.database_id, id);
+        assert!(PersistedKeyring::decode(b"junk").is_err());
+    }
+
     #[test]
     fn test_provider_access() {
-        let provider = MockKmsProvider::new();
+        let provider = Arc::new(MockKmsProvider::new());
         let keyring = Keyring::new(provider.clone());
         let _ = keyring.provider();
     }
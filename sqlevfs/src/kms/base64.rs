@@ -0,0 +1,269 @@
+//! Strict base64 (RFC 4648) for the key material KMS providers send and
+//! receive, here and in providers of your own.
+//!
+//! Decoding accepts only canonical input: no whitespace or characters
+//! from outside the alphabet, padding only where and as much as the
+//! length calls for, and zero bits after the last byte. Anything else is
+//! an error rather than silently dropped, so a corrupted or truncated
+//! field can't decode into a different key.
+
+use std::fmt;
+
+/// The two alphabets of RFC 4648.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alphabet {
+    /// `+` and `/`, always padded. AWS KMS uses this one.
+    Standard,
+    /// `-` and `_`, padded or not. GCP uses this one.
+    UrlSafe,
+}
+
+/// Why input didn't decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A byte outside the alphabet, at `offset`.
+    InvalidByte { offset: usize, byte: u8 },
+    /// `=` anywhere but at the end, or more or less of it than the
+    /// length calls for.
+    InvalidPadding,
+    /// A length no encoding has.
+    InvalidLength(usize),
+    /// The bits after the last byte weren't zero.
+    TrailingBits,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::InvalidByte { offset, byte } => write!(
+                f,
+                "invalid base64 character {:?} at offset {offset}",
+                char::from(byte)
+            ),
+            Self::InvalidPadding => f.write_str("invalid base64 padding"),
+            Self::InvalidLength(len) => write!(f, "invalid base64 length {len}"),
+            Self::TrailingBits => f.write_str("non-zero trailing bits in base64"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Alphabet {
+    fn symbols(self) -> &'static [u8; 64] {
+        match self {
+            Self::Standard => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/",
+            Self::UrlSafe => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_",
+        }
+    }
+
+    fn value(self, byte: u8) -> Option<u32> {
+        let value = match (byte, self) {
+            (b'A'..=b'Z', _) => byte - b'A',
+            (b'a'..=b'z', _) => byte - b'a' + 26,
+            (b'0'..=b'9', _) => byte - b'0' + 52,
+            (b'+', Self::Standard) | (b'-', Self::UrlSafe) => 62,
+            (b'/', Self::Standard) | (b'_', Self::UrlSafe) => 63,
+            _ => return None,
+        };
+        Some(value.into())
+    }
+
+    /// Encode `input`, padded.
+    pub fn encode(self, input: &[u8]) -> String {
+        let symbols = self.symbols();
+        let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+        for chunk in input.chunks(3) {
+            let bits = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |bits, (i, &b)| bits | u32::from(b) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(symbols[(bits >> (18 - 6 * i) & 0x3f) as usize].into());
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    /// Decode `input`, which must be canonical.
+    ///
+    /// The result is allocated once at its final size, so wrapping it
+    /// in `Zeroizing` leaves no copies of key material behind.
+    pub fn decode(self, input: &str) -> Result<Vec<u8>, DecodeError> {
+        let input = input.as_bytes();
+        let padding = input.iter().rev().take_while(|&&b| b == b'=').count();
+        let data = &input[..input.len() - padding];
+        if let Some(offset) = data.iter().position(|&b| self.value(b).is_none()) {
+            return Err(match data[offset] {
+                b'=' => DecodeError::InvalidPadding,
+                byte => DecodeError::InvalidByte { offset, byte },
+            });
+        }
+        if data.len() % 4 == 1 {
+            return Err(DecodeError::InvalidLength(input.len()));
+        }
+        let expected_padding = (4 - data.len() % 4) % 4;
+        let padded = self == Self::Standard || padding > 0;
+        if padded && padding != expected_padding {
+            return Err(DecodeError::InvalidPadding);
+        }
+
+        let mut out = Vec::with_capacity(data.len() * 3 / 4);
+        for chunk in data.chunks(4) {
+            let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| {
+                bits | self.value(b).unwrap_or_default() << (18 - 6 * i)
+            });
+            let bytes = chunk.len() - 1;
+            if bits & (0xff_ffff >> (8 * bytes)) != 0 {
+                return Err(DecodeError::TrailingBits);
+            }
+            out.extend_from_slice(&bits.to_be_bytes()[1..=bytes]);
+        }
+        Ok(out)
+    }
+}
+
+/// Encode `input` with the standard alphabet, padded.
+pub fn encode(input: &[u8]) -> String {
+    Alphabet::Standard.encode(input)
+}
+
+/// Decode standard, padded base64.
+pub fn decode(input: &str) -> Result<Vec<u8>, DecodeError> {
+    Alphabet::Standard.decode(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 4648, section 10.
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn rfc_4648_vectors() {
+        for (plain, encoded) in VECTORS {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded).unwrap(), plain.as_bytes(), "{encoded}");
+        }
+    }
+
+    #[test]
+    fn every_length_and_byte_round_trips() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for alphabet in [Alphabet::Standard, Alphabet::UrlSafe] {
+            for len in 0..bytes.len() {
+                let input = &bytes[bytes.len() - len..];
+                let encoded = alphabet.encode(input);
+                assert_eq!(alphabet.decode(&encoded).unwrap(), input);
+            }
+        }
+    }
+
+    #[test]
+    fn url_safe_uses_its_own_alphabet() {
+        let bytes = [0xfb, 0xff, 0xbf];
+        assert_eq!(Alphabet::Standard.encode(&bytes), "+/+/");
+        assert_eq!(Alphabet::UrlSafe.encode(&bytes), "-_-_");
+        assert_eq!(Alphabet::UrlSafe.decode("-_-_").unwrap(), bytes);
+        assert_eq!(
+            Alphabet::Standard.decode("-_-_"),
+            Err(DecodeError::InvalidByte {
+                offset: 0,
+                byte: b'-'
+            })
+        );
+        assert_eq!(
+            Alphabet::UrlSafe.decode("+/+/"),
+            Err(DecodeError::InvalidByte {
+                offset: 0,
+                byte: b'+'
+            })
+        );
+    }
+
+    #[test]
+    fn url_safe_padding_is_optional_but_exact() {
+        assert_eq!(Alphabet::UrlSafe.decode("Zg").unwrap(), b"f");
+        assert_eq!(Alphabet::UrlSafe.decode("Zg==").unwrap(), b"f");
+        assert_eq!(Alphabet::UrlSafe.decode("Zm8").unwrap(), b"fo");
+        assert_eq!(
+            Alphabet::UrlSafe.decode("Zg="),
+            Err(DecodeError::InvalidPadding)
+        );
+        assert_eq!(
+            Alphabet::UrlSafe.decode("Zm9v="),
+            Err(DecodeError::InvalidPadding)
+        );
+    }
+
+    #[test]
+    fn invalid_characters_are_rejected() {
+        for (input, offset, byte) in [
+            ("Zm9v YmFy", 4, b' '),
+            ("Zm9v\nYmFy", 4, b'\n'),
+            ("Zm9vYmFy\n", 8, b'\n'),
+            ("Zm9v*mFy", 4, b'*'),
+            ("Zm9vYmF\0", 7, 0),
+            ("Zm9vYmFé", 7, 0xc3),
+        ] {
+            assert_eq!(
+                decode(input),
+                Err(DecodeError::InvalidByte { offset, byte }),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_padding_is_rejected() {
+        let missing = ["Zg", "Zm8"];
+        let too_little = ["Zg="];
+        let too_much = ["Zm8==", "Zm9v=", "Zm9v====", "=", "===="];
+        let followed_by_garbage = ["Zg==Zg==", "Zg=a", "Zm8=Zm9v", "Zg==\n"];
+        for input in [&missing[..], &too_little, &too_much, &followed_by_garbage].concat() {
+            assert_eq!(decode(input), Err(DecodeError::InvalidPadding), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn impossible_lengths_are_rejected() {
+        assert_eq!(decode("Z"), Err(DecodeError::InvalidLength(1)));
+        assert_eq!(decode("Zm9vY"), Err(DecodeError::InvalidLength(5)));
+        assert_eq!(
+            Alphabet::UrlSafe.decode("Zm9vY"),
+            Err(DecodeError::InvalidLength(5))
+        );
+    }
+
+    #[test]
+    fn non_canonical_encodings_are_rejected() {
+        // "Zh==" and "Zm9=" carry the same bytes as "Zg==" and "Zm8=" but
+        // with bits set after them.
+        assert_eq!(decode("Zh=="), Err(DecodeError::TrailingBits));
+        assert_eq!(decode("Zm9="), Err(DecodeError::TrailingBits));
+        assert_eq!(
+            Alphabet::UrlSafe.decode("Zh"),
+            Err(DecodeError::TrailingBits)
+        );
+    }
+
+    #[test]
+    fn empty_input_decodes_to_nothing() {
+        assert_eq!(decode(""), Ok(Vec::new()));
+        assert_eq!(Alphabet::UrlSafe.decode(""), Ok(Vec::new()));
+        assert_eq!(encode(b""), "");
+    }
+}
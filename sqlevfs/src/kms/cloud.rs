@@ -1,23 +1,41 @@
-use parking_lot::Mutex;
-use serde::{Deserialize, Serialize};
-
-use super::KmsProvider;
-use crate::crypto::keys::KekId;
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-/// Cloud KMS provider that talks to an HTTP endpoint.
-///
-/// Compatible with:
-/// - AWS KMS (`GenerateDataKey` / `Decrypt`)
-/// - Any KMS that exposes a similar JSON API
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use zeroize::Zeroizing;
+
+use super::{KekBytes, KmsProvider, base64, retry::RetryPolicy};
+use crate::{
+    crypto::keys::KekId,
+    sigv4::{self, Credentials, CredentialsProvider, EnvCredentials},
+};
+
+/// Cloud KMS provider that talks to AWS KMS, or anything that speaks
+/// its JSON API (`GenerateDataKey`, `Decrypt` and `Encrypt`), such as
+/// LocalStack.
 ///
-/// For production AWS use, swap the HTTP calls for the real SDK.
-/// This implementation shows the protocol shape.
+/// Requests are signed with SigV4 for the `kms` service, with
+/// credentials from the environment unless [`Self::credentials`] says
+/// otherwise. Transient failures are retried per [`Self::retry`], and
+/// requests time out per [`Self::timeouts`].
 pub struct CloudKmsProvider {
     key_id: String,
     endpoint: Option<String>,
+    region: String,
+    credentials: Arc<dyn CredentialsProvider>,
+    retry: RetryPolicy,
+    /// Carries the timeouts.
+    agent: ureq::Agent,
     /// Cache the last generated data key so we don't call KMS on
     /// every page write.
-    cached_kek: Mutex<Option<(KekId, Vec<u8>)>>,
+    cached_kek: Mutex<Option<(KekId, KekBytes)>>,
+    /// ARN of the key that generated the cached data key: the one
+    /// `key_id` names now, which an alias moves on rotation.
+    key_arn: Mutex<Option<String>>,
 }
 
 #[derive(Serialize)]
@@ -46,39 +64,151 @@ struct DecryptRequest<'a> {
 
 #[derive(Deserialize)]
 struct DecryptResponse {
+    #[serde(rename = "KeyId")]
+    key_id: Option<String>,
     #[serde(rename = "Plaintext")]
     plaintext: String,
 }
 
+/// The body of an AWS JSON error response.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    #[serde(rename = "__type")]
+    error_type: Option<String>,
+    #[serde(alias = "Message")]
+    message: Option<String>,
+}
+
 impl CloudKmsProvider {
+    /// Use the key `key_id` at `endpoint`, or at AWS KMS. Requests are
+    /// signed for the region of an AWS endpoint, or else the one
+    /// `AWS_REGION` or `AWS_DEFAULT_REGION` names, or `us-east-1`.
     pub fn new(key_id: String, endpoint: Option<String>) -> Self {
+        let region = endpoint
+            .as_deref()
+            .and_then(region_of)
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+            .unwrap_or_else(|| "us-east-1".into());
         Self {
             key_id,
-            endpoint,
+            endpoint: endpoint.map(|e| e.trim_end_matches('/').to_string()),
+            region,
+            credentials: Arc::new(EnvCredentials),
+            retry: RetryPolicy::default(),
+            agent: agent(DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT),
             cached_kek: Mutex::new(None),
+            key_arn: Mutex::new(None),
         }
     }
 
-    fn base_url(&self) -> &str {
-        self.endpoint
-            .as_deref()
-            .unwrap_or("https://kms.us-east-1.amazonaws.com")
+    /// Sign requests for `region`, and send them there unless an
+    /// endpoint was given.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = region.into();
+        self
+    }
+
+    /// Sign requests with credentials from `credentials`.
+    pub fn credentials(mut self, credentials: impl CredentialsProvider + 'static) -> Self {
+        self.credentials = Arc::new(credentials);
+        self
     }
 
-    fn generate_data_key(&self) -> anyhow::Result<(KekId, Vec<u8>)> {
+    /// Give up connecting to the KMS after `connect`, and on a request
+    /// once sending it or reading its response has stalled for `read`;
+    /// 5 and 15 seconds by default. A request that times out is
+    /// retried as the retry policy says.
+    pub fn timeouts(mut self, connect: Duration, read: Duration) -> Self {
+        self.agent = agent(connect, read);
+        self
+    }
+
+    /// Retry transient failures as `policy` says, rather than as
+    /// [`RetryPolicy::default`] does.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    fn base_url(&self) -> String {
+        match &self.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => format!("https://kms.{}.amazonaws.com", self.region),
+        }
+    }
+
+    /// Send the KMS `action` with `body`, signed, and decode its
+    /// response, retrying transient failures as the retry policy says.
+    fn call<T: DeserializeOwned>(&self, action: &str, body: &impl Serialize) -> anyhow::Result<T> {
         let url = self.base_url();
+        let body = serde_json::to_vec(body)?;
+        let credentials = self.credentials.credentials()?;
+        let response = self.retry.run(
+            &format!("KMS {action}"),
+            || self.send(action, &url, &body, &credentials),
+            |e| is_transient(e),
+        );
+        match response.map_err(|e| *e) {
+            Ok(response) => Ok(response.into_json()?),
+            Err(ureq::Error::Status(status, response)) => {
+                let text = response.into_string().unwrap_or_default();
+                Err(kms_error(action, status, &text))
+            }
+            Err(e) => Err(anyhow::anyhow!("KMS {action} at {url}: {e}")),
+        }
+    }
+
+    /// Sign and send one attempt at the KMS `action`.
+    fn send(
+        &self,
+        action: &str,
+        url: &str,
+        body: &[u8],
+        credentials: &Credentials,
+    ) -> Result<ureq::Response, Box<ureq::Error>> {
+        let host = url.split_once("://").map_or(url, |(_, host)| host);
+        let host = host.split_once('/').map_or(host, |(host, _)| host);
+        let payload_sha256 = sigv4::sha256_hex(body);
+        let mut request = sigv4::Request {
+            method: "POST",
+            path: "/",
+            query: &[],
+            headers: vec![
+                ("host".into(), host.into()),
+                ("content-type".into(), "application/x-amz-json-1.1".into()),
+                ("x-amz-target".into(), format!("TrentService.{action}")),
+            ],
+            payload_sha256: &payload_sha256,
+        };
+        let authorization = sigv4::sign(
+            &mut request,
+            credentials,
+            &self.region,
+            "kms",
+            SystemTime::now(),
+        );
+
+        let mut http = self.agent.post(&format!("{}/", url.trim_end_matches('/')));
+        for (name, value) in &request.headers {
+            http = http.set(name, value);
+        }
+        http.set("authorization", &authorization)
+            .send_bytes(body)
+            .map_err(Box::new)
+    }
+
+    /// Generate a data key, returning it with the ARN of the key that
+    /// generated it.
+    fn generate_data_key(&self) -> anyhow::Result<(KekId, KekBytes, String)> {
         let body = GenerateDataKeyRequest {
             key_id: &self.key_id,
             key_spec: "AES_256",
         };
+        let resp: GenerateDataKeyResponse = self.call("GenerateDataKey", &body)?;
 
-        let resp: GenerateDataKeyResponse = ureq::post(url)
-            .set("X-Amz-Target", "TrentService.GenerateDataKey")
-            .set("Content-Type", "application/x-amz-json-1.1")
-            .send_json(serde_json::to_value(&body)?)?
-            .into_json()?;
-
-        let plaintext = base64_decode(&resp.plaintext)?;
+        let encoded = Zeroizing::new(resp.plaintext);
+        let plaintext = Zeroizing::new(decode_field("GenerateDataKey", "Plaintext", &encoded)?);
         anyhow::ensure!(
             plaintext.len() == 32,
             "KMS returned {} byte key, expected 32",
@@ -87,38 +217,48 @@ impl CloudKmsProvider {
 
         // The KekId stores the ciphertext blob so we can decrypt it
         // later without needing the plaintext KEK.
+        decode_field("GenerateDataKey", "CiphertextBlob", &resp.ciphertext_blob)?;
         let id = KekId(resp.ciphertext_blob);
-        Ok((id, plaintext))
+        Ok((id, plaintext, resp.key_id))
     }
 
-    fn decrypt_data_key(&self, ciphertext_b64: &str) -> anyhow::Result<Vec<u8>> {
-        let url = self.base_url();
+    /// Decrypt a data key, returning it with the ARN of the key that
+    /// decrypted it, if the KMS says.
+    fn decrypt_data_key(&self, ciphertext_b64: &str) -> anyhow::Result<(KekBytes, Option<String>)> {
         let body = DecryptRequest {
             ciphertext_blob: ciphertext_b64,
         };
+        let resp: DecryptResponse = self.call("Decrypt", &body)?;
 
-        let resp: DecryptResponse = ureq::post(url)
-            .set("X-Amz-Target", "TrentService.Decrypt")
-            .set("Content-Type", "application/x-amz-json-1.1")
-            .send_json(serde_json::to_value(&body)?)?
-            .into_json()?;
+        let encoded = Zeroizing::new(resp.plaintext);
+        let plaintext = Zeroizing::new(decode_field("Decrypt", "Plaintext", &encoded)?);
+        Ok((plaintext, resp.key_id))
+    }
+}
 
-        base64_decode(&resp.plaintext)
+impl fmt::Debug for CloudKmsProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CloudKmsProvider")
+            .field("key_id", &self.key_id)
+            .field("endpoint", &self.base_url())
+            .field("region", &self.region)
+            .finish_non_exhaustive()
     }
 }
 
 impl KmsProvider for CloudKmsProvider {
-    fn get_kek(&self) -> anyhow::Result<(KekId, Vec<u8>)> {
+    fn get_kek(&self) -> anyhow::Result<(KekId, KekBytes)> {
         let mut guard = self.cached_kek.lock();
         if let Some(ref cached) = *guard {
             return Ok(cached.clone());
         }
-        let result = self.generate_data_key()?;
-        *guard = Some(result.clone());
-        Ok(result)
+        let (id, kek, key_arn) = self.generate_data_key()?;
+        *guard = Some((id.clone(), kek.clone()));
+        *self.key_arn.lock() = Some(key_arn);
+        Ok((id, kek))
     }
 
-    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<Vec<u8>> {
+    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<KekBytes> {
         // Check cache first.
         {
             let guard = self.cached_kek.lock();
@@ -129,12 +269,37 @@ impl KmsProvider for CloudKmsProvider {
             }
         }
         // Call KMS Decrypt with the ciphertext blob stored in the id.
-        self.decrypt_data_key(&id.0)
+        Ok(self.decrypt_data_key(&id.0)?.0)
     }
 
-    fn wrap_blob(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let url = self.base_url();
+    /// Data keys are generated afresh by each process, so any can be
+    /// current: one is if the key that generated it is the one
+    /// `key_id` names now. After the key's alias is moved to a new key,
+    /// DEKs wrapped under data keys of the old one are rewrapped.
+    /// Automatic rotation keeps the key's ARN, and old key material
+    /// with it, so it needs no rewrapping.
+    fn is_current_kek(&self, id: &KekId) -> anyhow::Result<bool> {
+        let (current, _) = self.get_kek()?;
+        if current == *id {
+            return Ok(true);
+        }
+        let key_arn = self.key_arn.lock().clone();
+        let (_, decrypted_by) = self.decrypt_data_key(&id.0)?;
+        Ok(match (key_arn, decrypted_by) {
+            (Some(key_arn), Some(decrypted_by)) => key_arn == decrypted_by,
+            // A KMS that doesn't say can't tell.
+            _ => true,
+        })
+    }
+
+    /// DEKs are encrypted by KMS `Encrypt` under the key itself, so
+    /// neither they nor the key are ever wrapped by a data key held
+    /// here.
+    fn prefers_direct_wrap(&self) -> bool {
+        true
+    }
 
+    fn wrap_blob(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
         #[derive(Serialize)]
         struct EncryptRequest<'a> {
             #[serde(rename = "KeyId")]
@@ -151,132 +316,465 @@ impl KmsProvider for CloudKmsProvider {
 
         let body = EncryptRequest {
             key_id: &self.key_id,
-            plaintext: base64_encode(plaintext),
+            plaintext: base64::encode(plaintext),
         };
+        let resp: EncryptResponse = self.call("Encrypt", &body)?;
 
-        let resp: EncryptResponse = ureq::post(url)
-            .set("X-Amz-Target", "TrentService.Encrypt")
-            .set("Content-Type", "application/x-amz-json-1.1")
-            .send_json(serde_json::to_value(&body)?)?
-            .into_json()?;
-
-        base64_decode(&resp.ciphertext_blob)
+        decode_field("Encrypt", "CiphertextBlob", &resp.ciphertext_blob)
     }
 
-    fn unwrap_blob(&self, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let b64 = base64_encode(ciphertext);
-        self.decrypt_data_key(&b64)
+    fn unwrap_blob(&self, ciphertext: &[u8]) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let b64 = base64::encode(ciphertext);
+        Ok(self.decrypt_data_key(&b64)?.0)
     }
 }
 
-fn base64_decode(input: &str) -> anyhow::Result<Vec<u8>> {
-    // Minimal base64 decode without pulling in another crate.
-    // In production, use the `base64` crate.
-    use std::io::Read;
-    let mut decoder = base64_reader::DecoderReader::new(input.as_bytes());
-    let mut out = Vec::new();
-    decoder.read_to_end(&mut out)?;
-    Ok(out)
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(15);
+
+fn agent(connect: Duration, read: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(connect)
+        .timeout_read(read)
+        .timeout_write(read)
+        .build()
 }
 
-fn base64_encode(input: &[u8]) -> String {
-    base64_writer::encode(input)
+/// Whether `e` may pass if the request is sent again: a 429 or a 5xx,
+/// or a timeout or other failure to reach the service.
+fn is_transient(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(status, _) => *status == 429 || *status >= 500,
+        ureq::Error::Transport(transport) => matches!(
+            transport.kind(),
+            ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+        ),
+    }
 }
 
-/// Minimal inline base64 so we don't need an extra crate.
-mod base64_reader {
-    use std::io::{self, Read};
-
-    fn decode_byte(b: u8) -> Option<u8> {
-        match b {
-            b'A'..=b'Z' => Some(b - b'A'),
-            b'a'..=b'z' => Some(b - b'a' + 26),
-            b'0'..=b'9' => Some(b - b'0' + 52),
-            b'+' => Some(62),
-            b'/' => Some(63),
-            _ => None,
+/// The region of an AWS KMS endpoint such as
+/// `https://kms.eu-west-1.amazonaws.com`.
+fn region_of(endpoint: &str) -> Option<String> {
+    let host = endpoint
+        .split_once("://")
+        .map_or(endpoint, |(_, host)| host);
+    let rest = host
+        .strip_prefix("kms.")
+        .or_else(|| host.strip_prefix("kms-fips."))?;
+    let (region, domain) = rest.split_once('.')?;
+    domain
+        .starts_with("amazonaws.com")
+        .then(|| region.to_string())
+}
+
+/// An error for the KMS `action` failing with HTTP `status` and the
+/// AWS JSON error `body`, saying what to check for the common causes.
+fn kms_error(action: &str, status: u16, body: &str) -> anyhow::Error {
+    let Ok(ErrorResponse {
+        error_type,
+        message,
+    }) = serde_json::from_str(body)
+    else {
+        return anyhow::anyhow!("KMS {action} failed with HTTP {status}: {body}");
+    };
+    // Types may be qualified, as in `com.amazonaws.kms#NotFoundException`.
+    let error_type = error_type.unwrap_or_default();
+    let error_type = error_type.rsplit('#').next().unwrap_or_default();
+    let hint = match error_type {
+        "UnrecognizedClientException" | "InvalidSignatureException" => {
+            "check AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN, and that the \
+             region matches the key's"
+        }
+        "ExpiredTokenException" => "the session token has expired; refresh the credentials",
+        "AccessDeniedException" => {
+            "the credentials' IAM policy or the key policy must allow kms:GenerateDataKey, \
+             kms:Decrypt and kms:Encrypt on the key"
         }
+        "NotFoundException" => "check the key ID or ARN, and that it is in this region",
+        "DisabledException" => "the key is disabled; enable it to use it",
+        "KMSInvalidStateException" => "the key is pending deletion or import, or unavailable",
+        "InvalidCiphertextException" | "IncorrectKeyException" => {
+            "the blob was not wrapped by this key, or is corrupt"
+        }
+        "ThrottlingException" => "the request rate exceeds the KMS quota; retry later",
+        _ => "",
+    };
+    let mut text = format!("KMS {action} failed with HTTP {status}: {error_type}");
+    if let Some(message) = message {
+        text.push_str(&format!(": {message}"));
     }
-
-    pub struct DecoderReader<'a> {
-        input: &'a [u8],
-        pos: usize,
+    if !hint.is_empty() {
+        text.push_str(&format!(" ({hint})"));
     }
+    anyhow::anyhow!(text)
+}
 
-    impl<'a> DecoderReader<'a> {
-        pub fn new(input: &'a [u8]) -> Self {
-            Self { input, pos: 0 }
-        }
+/// Decode the base64 `field` of an `action` response.
+fn decode_field(action: &str, field: &str, value: &str) -> anyhow::Result<Vec<u8>> {
+    base64::decode(value)
+        .map_err(|e| anyhow::anyhow!("KMS {action} returned an invalid {field}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::{
+        crypto::{envelope, keys::Dek},
+        sigv4::Credentials,
+    };
+
+    /// A request the fake KMS received: its headers, lowercased, and
+    /// body.
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
     }
 
-    impl Read for DecoderReader<'_> {
-        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            let mut written = 0;
-            while written < buf.len() {
-                // Skip whitespace / padding.
-                while self.pos < self.input.len()
-                    && (self.input[self.pos] == b'=' || self.input[self.pos].is_ascii_whitespace())
-                {
-                    self.pos += 1;
-                }
-                if self.pos >= self.input.len() {
-                    break;
-                }
-                // Need up to 4 characters for a group.
-                let mut group = [0u8; 4];
-                let mut count = 0;
-                while count < 4 && self.pos < self.input.len() {
-                    let b = self.input[self.pos];
-                    self.pos += 1;
-                    if b == b'=' || b.is_ascii_whitespace() {
-                        continue;
-                    }
-                    if let Some(val) = decode_byte(b) {
-                        group[count] = val;
-                        count += 1;
-                    }
-                }
-                if count >= 2 && written < buf.len() {
-                    buf[written] = (group[0] << 2) | (group[1] >> 4);
-                    written += 1;
-                }
-                if count >= 3 && written < buf.len() {
-                    buf[written] = (group[1] << 4) | (group[2] >> 2);
-                    written += 1;
-                }
-                if count >= 4 && written < buf.len() {
-                    buf[written] = (group[2] << 6) | group[3];
-                    written += 1;
+    /// Serve `responses` to successive requests, in order, and return
+    /// the endpoint and a handle yielding the requests received.
+    fn fake_kms(
+        responses: Vec<(&'static str, String)>,
+    ) -> (String, thread::JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut received = Vec::new();
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                assert_eq!(line.trim_end(), "POST / HTTP/1.1");
+                let mut headers = Vec::new();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let Some((name, value)) = header.trim_end().split_once(": ") else {
+                        break;
+                    };
+                    headers.push((name.to_ascii_lowercase(), value.to_string()));
                 }
+                let length = headers
+                    .iter()
+                    .find(|(name, _)| name == "content-length")
+                    .map_or(0, |(_, value)| value.parse().unwrap());
+                let mut request_body = vec![0u8; length];
+                reader.read_exact(&mut request_body).unwrap();
+                received.push(Received {
+                    headers,
+                    body: request_body,
+                });
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Type: application/x-amz-json-1.1\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
             }
-            Ok(written)
+            received
+        });
+        (endpoint, server)
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: Zeroizing::new("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into()),
+            session_token: None,
         }
     }
-}
 
-mod base64_writer {
-    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
-
-    pub fn encode(input: &[u8]) -> String {
-        let mut out = Vec::with_capacity(input.len().div_ceil(3) * 4);
-        for chunk in input.chunks(3) {
-            let b0 = chunk[0] as u32;
-            let b1 = if chunk.len() > 1 { chunk[1] as u32 } else { 0 };
-            let b2 = if chunk.len() > 2 { chunk[2] as u32 } else { 0 };
-            let triple = (b0 << 16) | (b1 << 8) | b2;
-            out.push(TABLE[((triple >> 18) & 0x3F) as usize]);
-            out.push(TABLE[((triple >> 12) & 0x3F) as usize]);
-            if chunk.len() > 1 {
-                out.push(TABLE[((triple >> 6) & 0x3F) as usize]);
-            } else {
-                out.push(b'=');
-            }
-            if chunk.len() > 2 {
-                out.push(TABLE[(triple & 0x3F) as usize]);
-            } else {
-                out.push(b'=');
-            }
+    /// Check `received` was signed for `kms` in `region` by
+    /// [`credentials`], over the headers and body it was sent with.
+    fn assert_signed(received: &Received, region: &str, sent_after: SystemTime) {
+        let header = |name: &str| {
+            let values: Vec<_> = received
+                .headers
+                .iter()
+                .filter(|(n, _)| n == name)
+                .map(|(_, value)| value.as_str())
+                .collect();
+            assert_eq!(values.len(), 1, "{name} header in {:?}", received.headers);
+            values[0]
+        };
+        let amz_date = header("x-amz-date");
+        let start = sent_after.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let now = (start - 1..start + 30)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            .find(|&t| sigv4::amz_date(t) == amz_date)
+            .unwrap();
+        let payload_sha256 = sigv4::sha256_hex(&received.body);
+        let mut request = sigv4::Request {
+            method: "POST",
+            path: "/",
+            query: &[],
+            headers: ["host", "content-type", "x-amz-target"]
+                .into_iter()
+                .map(|name| (name.to_string(), header(name).to_string()))
+                .collect(),
+            payload_sha256: &payload_sha256,
+        };
+        let expected = sigv4::sign(&mut request, &credentials(), region, "kms", now);
+        assert_eq!(header("authorization"), expected);
+        assert!(expected.contains(&format!("/{region}/kms/aws4_request, ")));
+        assert!(expected.contains("SignedHeaders=content-type;host;x-amz-date;x-amz-target,"));
+    }
+
+    #[test]
+    fn requests_are_signed_for_kms() {
+        let key = base64::encode(&[0x5a; 32]);
+        let blob = base64::encode(b"wrapped kek");
+        let (endpoint, server) = fake_kms(vec![
+            (
+                "200 OK",
+                format!(r#"{{"KeyId":"k","Plaintext":"{key}","CiphertextBlob":"{blob}"}}"#),
+            ),
+            ("200 OK", format!(r#"{{"Plaintext":"{key}"}}"#)),
+        ]);
+        let kms = CloudKmsProvider::new("alias/evfs".into(), Some(endpoint))
+            .region("eu-west-1")
+            .credentials(credentials());
+        let sent_after = SystemTime::now();
+
+        let (id, kek) = kms.get_kek().unwrap();
+        assert_eq!(id.0, blob);
+        assert_eq!(*kek, [0x5a; 32]);
+        // Not the cached KEK, so KMS decrypts it.
+        let other = KekId(base64::encode(b"other kek"));
+        assert_eq!(*kms.get_kek_by_id(&other).unwrap(), [0x5a; 32]);
+
+        let received = server.join().unwrap();
+        for (request, target) in received.iter().zip(["GenerateDataKey", "Decrypt"]) {
+            assert_signed(request, "eu-west-1", sent_after);
+            assert!(
+                request
+                    .headers
+                    .contains(&("x-amz-target".into(), format!("TrentService.{target}")))
+            );
+        }
+        let body: serde_json::Value = serde_json::from_slice(&received[0].body).unwrap();
+        assert_eq!(body["KeyId"], "alias/evfs");
+        let body: serde_json::Value = serde_json::from_slice(&received[1].body).unwrap();
+        assert_eq!(body["CiphertextBlob"], other.0);
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let key = base64::encode(&[0x5b; 32]);
+        let (endpoint, server) = fake_kms(vec![
+            ("503 Service Unavailable", String::new()),
+            (
+                "429 Too Many Requests",
+                r#"{"__type":"ThrottlingException"}"#.into(),
+            ),
+            (
+                "200 OK",
+                format!(r#"{{"KeyId":"k","Plaintext":"{key}","CiphertextBlob":"YmxvYg=="}}"#),
+            ),
+            (
+                "403 Forbidden",
+                r#"{"__type":"UnrecognizedClientException","message":"bad token"}"#.into(),
+            ),
+        ]);
+        let kms = CloudKmsProvider::new("k".into(), Some(endpoint))
+            .credentials(credentials())
+            .retry(RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            });
+        assert_eq!(*kms.get_kek().unwrap().1, [0x5b; 32]);
+
+        // Not retried: the fake would refuse a fifth connection.
+        let e = kms.get_kek_by_id(&KekId("b3RoZXI=".into())).unwrap_err();
+        assert!(e.to_string().contains("UnrecognizedClientException"), "{e}");
+        assert_eq!(server.join().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn hung_requests_time_out() {
+        // Accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let held: Vec<_> = listener.incoming().collect();
+            drop(held);
+        });
+        let kms = CloudKmsProvider::new("k".into(), Some(endpoint))
+            .credentials(credentials())
+            .timeouts(Duration::from_secs(1), Duration::from_millis(200))
+            .retry(RetryPolicy {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            });
+        let start = std::time::Instant::now();
+        let e = kms.get_kek().unwrap_err();
+        assert!(e.to_string().contains("timed out"), "{e}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn errors_say_what_to_check() {
+        let (endpoint, server) = fake_kms(vec![(
+            "400 Bad Request",
+            r#"{"__type":"com.amazonaws.kms#NotFoundException","message":"Key 'arn:x' does not exist"}"#
+                .into(),
+        )]);
+        let kms = CloudKmsProvider::new("arn:x".into(), Some(endpoint)).credentials(credentials());
+        let e = kms.get_kek().unwrap_err().to_string();
+        server.join().unwrap();
+        assert_eq!(
+            e,
+            "KMS GenerateDataKey failed with HTTP 400: NotFoundException: Key 'arn:x' does not \
+             exist (check the key ID or ARN, and that it is in this region)"
+        );
+
+        let e = kms_error(
+            "Decrypt",
+            400,
+            r#"{"__type":"AccessDeniedException","Message":"not authorized"}"#,
+        );
+        assert!(e.to_string().starts_with(
+            "KMS Decrypt failed with HTTP 400: AccessDeniedException: not authorized (the \
+             credentials' IAM policy"
+        ));
+        let e = kms_error("Encrypt", 502, "<html>Bad Gateway</html>");
+        assert_eq!(
+            e.to_string(),
+            "KMS Encrypt failed with HTTP 502: <html>Bad Gateway</html>"
+        );
+    }
+
+    #[test]
+    fn data_keys_of_another_key_are_not_current() {
+        let key = base64::encode(&[0x5d; 32]);
+        let (endpoint, server) = fake_kms(vec![
+            (
+                "200 OK",
+                format!(
+                    r#"{{"KeyId":"arn:new","Plaintext":"{key}","CiphertextBlob":"Y3VycmVudA=="}}"#
+                ),
+            ),
+            (
+                "200 OK",
+                format!(r#"{{"KeyId":"arn:old","Plaintext":"{key}"}}"#),
+            ),
+            (
+                "200 OK",
+                format!(r#"{{"KeyId":"arn:new","Plaintext":"{key}"}}"#),
+            ),
+        ]);
+        let kms =
+            CloudKmsProvider::new("alias/evfs".into(), Some(endpoint)).credentials(credentials());
+
+        let (current, _) = kms.get_kek().unwrap();
+        assert!(kms.is_current_kek(&current).unwrap());
+        // Generated by the key the alias named before it was moved.
+        assert!(!kms.is_current_kek(&KekId("b2xk".into())).unwrap());
+        // Generated by the same key, by another process.
+        assert!(kms.is_current_kek(&KekId("c2FtZQ==".into())).unwrap());
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn deks_are_wrapped_by_kms_itself() {
+        let dek = Dek::generate();
+        let plaintext = base64::encode(dek.as_bytes());
+        let blob = base64::encode(b"encrypted dek");
+        let (endpoint, server) = fake_kms(vec![
+            ("200 OK", format!(r#"{{"CiphertextBlob":"{blob}"}}"#)),
+            (
+                "200 OK",
+                format!(r#"{{"KeyId":"arn:key","Plaintext":"{plaintext}"}}"#),
+            ),
+        ]);
+        let kms =
+            CloudKmsProvider::new("alias/evfs".into(), Some(endpoint)).credentials(credentials());
+
+        let wrapped = envelope::wrap_dek(&dek, &kms).unwrap();
+        assert!(wrapped.is_direct());
+        assert_eq!(wrapped.ciphertext, b"encrypted dek");
+        assert_eq!(envelope::unwrap_dek(&wrapped, &kms).unwrap(), dek);
+
+        // No data key is generated to wrap it under.
+        let received = server.join().unwrap();
+        for (request, target) in received.iter().zip(["Encrypt", "Decrypt"]) {
+            assert!(
+                request
+                    .headers
+                    .contains(&("x-amz-target".into(), format!("TrentService.{target}")))
+            );
         }
-        String::from_utf8(out).unwrap()
+        let body: serde_json::Value = serde_json::from_slice(&received[0].body).unwrap();
+        assert_eq!(body["KeyId"], "alias/evfs");
+        assert_eq!(body["Plaintext"], plaintext);
+    }
+
+    #[test]
+    fn malformed_base64_names_the_field() {
+        let key = base64::encode(&[0x5c; 32]);
+        let (endpoint, server) = fake_kms(vec![
+            (
+                "200 OK",
+                r#"{"KeyId":"k","Plaintext":"AAAA AAAA","CiphertextBlob":"YmxvYg=="}"#.into(),
+            ),
+            (
+                "200 OK",
+                format!(r#"{{"KeyId":"k","Plaintext":"{key}","CiphertextBlob":"YmxvYg"}}"#),
+            ),
+            ("200 OK", format!(r#"{{"Plaintext":"{key}garbage"}}"#)),
+            ("200 OK", r#"{"CiphertextBlob":"YmxvYg==="}"#.into()),
+        ]);
+        let kms = CloudKmsProvider::new("k".into(), Some(endpoint)).credentials(credentials());
+
+        let e = kms.get_kek().unwrap_err().to_string();
+        assert_eq!(
+            e,
+            "KMS GenerateDataKey returned an invalid Plaintext: invalid base64 character ' ' \
+             at offset 4"
+        );
+        let e = kms.get_kek().unwrap_err().to_string();
+        assert_eq!(
+            e,
+            "KMS GenerateDataKey returned an invalid CiphertextBlob: invalid base64 padding"
+        );
+        let e = kms.get_kek_by_id(&KekId("YmxvYg==".into())).unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("KMS Decrypt returned an invalid Plaintext: "),
+            "{e}"
+        );
+        let e = kms.wrap_blob(b"dek").unwrap_err().to_string();
+        assert_eq!(
+            e,
+            "KMS Encrypt returned an invalid CiphertextBlob: invalid base64 padding"
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn region_comes_from_aws_endpoints() {
+        assert_eq!(
+            region_of("https://kms.eu-west-1.amazonaws.com").as_deref(),
+            Some("eu-west-1")
+        );
+        assert_eq!(
+            region_of("https://kms-fips.us-east-2.amazonaws.com").as_deref(),
+            Some("us-east-2")
+        );
+        assert_eq!(
+            region_of("https://kms.us-gov-west-1.amazonaws.com/").as_deref(),
+            Some("us-gov-west-1")
+        );
+        assert_eq!(region_of("http://localhost:4566"), None);
     }
 }
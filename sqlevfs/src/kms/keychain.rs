@@ -0,0 +1,292 @@
+//! A device KEK kept in the OS keychain: the Secret Service on Linux,
+//! the Keychain on macOS and the Credential Manager on Windows.
+
+use std::{sync::Arc, time::Duration};
+
+use keychain::Entry;
+use parking_lot::Mutex;
+use zeroize::Zeroizing;
+
+use super::{KekBytes, KmsProvider};
+use crate::crypto::keys::KekId;
+
+/// How long to wait for the keychain by default. The Secret Service
+/// may prompt to unlock it, and libdbus may try to launch a bus.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// KEK provider reading a 32-byte key from an OS keychain entry,
+/// named by a service and an account, and creating it with a random
+/// key if there is none.
+pub struct KeychainProvider {
+    id: KekId,
+    service: String,
+    account: String,
+    /// The entry, or `None` for the platform's, opened when the key is
+    /// first loaded.
+    entry: Option<Arc<Entry>>,
+    timeout: Duration,
+    /// Cached KEK bytes - read once, then reused.
+    cached: Mutex<Option<KekBytes>>,
+}
+
+impl KeychainProvider {
+    /// The key of the entry `account` of `service` in the platform's
+    /// keychain.
+    pub fn new(service: impl Into<String>, account: impl Into<String>) -> Self {
+        Self::with_store(service.into(), account.into(), None)
+    }
+
+    /// The key of `entry`, from a credential store of your own, known
+    /// as `account` of `service`.
+    pub fn from_entry(
+        service: impl Into<String>,
+        account: impl Into<String>,
+        entry: Entry,
+    ) -> Self {
+        Self::with_store(service.into(), account.into(), Some(Arc::new(entry)))
+    }
+
+    fn with_store(service: String, account: String, entry: Option<Arc<Entry>>) -> Self {
+        Self {
+            id: KekId(format!("keychain:{service}:{account}")),
+            service,
+            account,
+            entry,
+            timeout: DEFAULT_TIMEOUT,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// How long to wait for the keychain before failing, 10 seconds by
+    /// default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The key in the entry, created if there is none, or an error if
+    /// the keychain doesn't answer within the timeout. The thread
+    /// asking it is left behind if it hangs.
+    fn load_kek(&self) -> anyhow::Result<KekBytes> {
+        let name = format!("{}:{}", self.service, self.account);
+        if self.entry.is_none() {
+            require_session_bus().map_err(|e| anyhow::anyhow!("keychain entry {name}: {e}"))?;
+        }
+        let (service, account, entry) = (
+            self.service.clone(),
+            self.account.clone(),
+            self.entry.clone(),
+        );
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("evfs-keychain".into())
+            .spawn(move || {
+                let _ = tx.send(load_or_create(&service, &account, entry));
+            })?;
+        match rx.recv_timeout(self.timeout) {
+            Ok(kek) => kek.map_err(|e| anyhow::anyhow!("keychain entry {name}: {e:#}")),
+            Err(_) => anyhow::bail!(
+                "keychain entry {name}: the keychain didn't answer within {:?}; is it running \
+                 and unlocked?",
+                self.timeout
+            ),
+        }
+    }
+
+    fn get_cached_or_load(&self) -> anyhow::Result<KekBytes> {
+        let mut guard = self.cached.lock();
+        if let Some(ref cached) = *guard {
+            return Ok(cached.clone());
+        }
+        let kek = self.load_kek()?;
+        *guard = Some(kek.clone());
+        Ok(kek)
+    }
+}
+
+/// Fail without asking the Secret Service when there is no session bus
+/// to reach it on, rather than waiting for libdbus to give up.
+#[cfg(target_os = "linux")]
+fn require_session_bus() -> anyhow::Result<()> {
+    let runtime_bus = std::env::var_os("XDG_RUNTIME_DIR")
+        .is_some_and(|dir| std::path::Path::new(&dir).join("bus").exists());
+    anyhow::ensure!(
+        std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some() || runtime_bus,
+        "no D-Bus session bus to reach the Secret Service on (DBUS_SESSION_BUS_ADDRESS is not \
+         set); run a keychain daemon such as gnome-keyring, or use a keyfile"
+    );
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn require_session_bus() -> anyhow::Result<()> {
+    Ok(())
+}
+
+fn load_or_create(
+    service: &str,
+    account: &str,
+    entry: Option<Arc<Entry>>,
+) -> anyhow::Result<KekBytes> {
+    let entry = match entry {
+        Some(entry) => entry,
+        None => Arc::new(Entry::new(service, account)?),
+    };
+    let secret = match entry.get_secret() {
+        Ok(secret) => Zeroizing::new(secret),
+        Err(keychain::Error::NoEntry) => {
+            let mut kek = Zeroizing::new(vec![0u8; 32]);
+            getrandom::fill(&mut kek).expect("getrandom failed");
+            entry.set_secret(&kek)?;
+            log::info!("created a KEK in keychain entry {service}:{account}");
+            // As stored, should another process have created it too.
+            Zeroizing::new(entry.get_secret()?)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    anyhow::ensure!(
+        secret.len() == 32,
+        "holds {} bytes, not a 32-byte key",
+        secret.len()
+    );
+    Ok(secret)
+}
+
+impl KmsProvider for KeychainProvider {
+    fn get_kek(&self) -> anyhow::Result<(KekId, KekBytes)> {
+        let bytes = self.get_cached_or_load()?;
+        Ok((self.id.clone(), bytes))
+    }
+
+    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<KekBytes> {
+        anyhow::ensure!(
+            id == &self.id,
+            "unknown KEK id: {id:?} (expected {:?})",
+            self.id
+        );
+        self.get_cached_or_load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keychain::mock::MockCredential;
+
+    use super::*;
+
+    fn mock_entry() -> Entry {
+        Entry::new_with_credential(Box::new(MockCredential::default()))
+    }
+
+    fn mock(entry: &Entry) -> &MockCredential {
+        entry.get_credential().downcast_ref().unwrap()
+    }
+
+    #[test]
+    fn test_key_is_created_on_first_use() -> anyhow::Result<()> {
+        let provider = KeychainProvider::from_entry("evfs-test", "device", mock_entry());
+        let (id, kek) = provider.get_kek()?;
+
+        assert_eq!(id, KekId("keychain:evfs-test:device".into()));
+        assert_eq!(kek.len(), 32);
+        assert!(!kek.iter().all(|b| *b == 0));
+        assert_eq!(provider.get_kek_by_id(&id)?, kek);
+        let stored = provider.entry.as_ref().unwrap().get_secret()?;
+        assert_eq!(*kek, stored);
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_key_is_used() -> anyhow::Result<()> {
+        let entry = mock_entry();
+        entry.set_secret(&[0x42; 32])?;
+        let provider = KeychainProvider::from_entry("evfs-test", "device", entry);
+
+        assert_eq!(*provider.get_kek()?.1, [0x42; 32]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_ids_are_unknown() {
+        let provider = KeychainProvider::from_entry("evfs-test", "device", mock_entry());
+        let err = provider
+            .get_kek_by_id(&KekId("keychain:evfs-test:other".into()))
+            .unwrap_err();
+
+        assert!(err.to_string().contains("unknown KEK"));
+    }
+
+    #[test]
+    fn test_wrong_length_secret_is_refused() -> anyhow::Result<()> {
+        let entry = mock_entry();
+        entry.set_secret(b"hunter2")?;
+        let provider = KeychainProvider::from_entry("evfs-test", "device", entry);
+
+        let err = provider.get_kek().unwrap_err().to_string();
+        assert!(err.contains("evfs-test:device"), "{err}");
+        assert!(err.contains("holds 7 bytes"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_keychain_errors_name_the_entry() {
+        let entry = mock_entry();
+        mock(&entry).set_error(keychain::Error::NoStorageAccess("locked".into()));
+        let provider = KeychainProvider::from_entry("evfs-test", "device", entry);
+
+        let err = provider.get_kek().unwrap_err().to_string();
+        assert!(err.starts_with("keychain entry evfs-test:device:"), "{err}");
+        assert!(err.contains("locked"), "{err}");
+        // Nothing was cached.
+        assert!(provider.cached.lock().is_none());
+    }
+
+    /// A keychain waiting on an unlock prompt no one answers.
+    struct Unanswered;
+
+    impl keychain::credential::CredentialApi for Unanswered {
+        fn set_secret(&self, _: &[u8]) -> keychain::Result<()> {
+            unreachable!()
+        }
+
+        fn get_secret(&self) -> keychain::Result<Vec<u8>> {
+            std::thread::sleep(Duration::from_secs(30));
+            Err(keychain::Error::NoEntry)
+        }
+
+        fn delete_credential(&self) -> keychain::Result<()> {
+            unreachable!()
+        }
+
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_unanswered_keychain_times_out() {
+        let entry = Entry::new_with_credential(Box::new(Unanswered));
+        let provider = KeychainProvider::from_entry("evfs-test", "device", entry)
+            .with_timeout(Duration::from_millis(100));
+        let started = std::time::Instant::now();
+
+        let err = provider.get_kek().unwrap_err().to_string();
+        assert!(err.contains("didn't answer within 100ms"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_missing_session_bus_is_a_clear_error() {
+        if require_session_bus().is_ok() {
+            eprintln!("a D-Bus session bus is configured, skipping");
+            return;
+        }
+        let provider = KeychainProvider::new("evfs-test", "device");
+        let started = std::time::Instant::now();
+
+        let err = provider.get_kek().unwrap_err().to_string();
+        assert!(err.contains("no D-Bus session bus"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -1,93 +1,388 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Version};
 use parking_lot::Mutex;
+use zeroize::Zeroizing;
 
-use super::KmsProvider;
-use crate::crypto::keys::KekId;
+use super::{
+    KekBytes,
+    KmsProvider,
+    base64::Alphabet::{self, UrlSafe},
+};
+use crate::{crypto::keys::KekId, keyring::Keyring};
 
 /// Device-local KEK provider. Reads a 32-byte key from a file, or
 /// derives one from a passphrase via Argon2id.
+///
+/// A passphrase KEK is derived under a random salt drawn when the
+/// provider is created. The salt and the Argon2id parameters are
+/// recorded in the KEK ID, which the sidecar stores with every DEK, so
+/// KEKs derived under another salt or older parameters can still be
+/// re-derived to unwrap them, and are rewrapped under the current KEK
+/// when the database is opened.
 pub struct DeviceKeyProvider {
     id: KekId,
-    /// Cached KEK bytes - computed once, then reused.
-    cached: Mutex<Option<Vec<u8>>>,
+    /// KEK bytes by KEK ID - computed once, then reused.
+    cached: Mutex<HashMap<KekId, KekBytes>>,
     source: KeySource,
 }
 
 enum KeySource {
-    File(PathBuf),
-    Passphrase(String),
+    File {
+        path: PathBuf,
+        /// Fail rather than warn on a low-entropy key.
+        refuse_weak: bool,
+    },
+    Passphrase {
+        passphrase: Zeroizing<String>,
+        params: Argon2Params,
+    },
 }
 
-/// Fixed salt for passphrase derivation. In production, store a
-/// random salt alongside the database and pass it in.
-const DEFAULT_SALT: &[u8; 16] = b"evfs-default-slt";
+/// Argon2id cost parameters for deriving a KEK from a passphrase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// Memory, in KiB.
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    const MAX_ITERATIONS: u32 = 64;
+    /// Upper bounds on what a KEK ID read from a sidecar may ask for,
+    /// so a tampered sidecar can't make opening it exhaust memory or
+    /// spin for hours before failing authentication.
+    const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+    const MAX_PARALLELISM: u32 = 16;
+
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.memory_kib <= Self::MAX_MEMORY_KIB
+                && self.iterations <= Self::MAX_ITERATIONS
+                && self.parallelism <= Self::MAX_PARALLELISM,
+            "argon2 parameters {self:?} exceed the limit of {} KiB, {} iterations, {} lanes",
+            Self::MAX_MEMORY_KIB,
+            Self::MAX_ITERATIONS,
+            Self::MAX_PARALLELISM
+        );
+        Ok(())
+    }
+}
+
+impl Default for Argon2Params {
+    /// 19 MiB, 2 iterations, 1 lane: the `argon2` crate's defaults.
+    fn default() -> Self {
+        Self {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Keys with fewer distinct byte values than this are taken for
+/// placeholders rather than random keys, which have around 30.
+const MIN_DISTINCT_KEY_BYTES: usize = 8;
+
+/// The 32-byte key in the keyfile at `path`, holding `bytes`: either
+/// the raw key, or 64 hex or 44 base64 (standard or URL-safe)
+/// characters, optionally followed by whitespace such as a newline.
+fn decode_keyfile(path: &Path, bytes: &[u8]) -> anyhow::Result<KekBytes> {
+    let fail = |problem: String| anyhow::anyhow!("keyfile {}: {problem}", path.display());
+    if bytes.len() == 32 {
+        return Ok(Zeroizing::new(bytes.to_vec()));
+    }
+    let text = bytes.trim_ascii_end();
+    let key = match text.len() {
+        64 => decode_hex(text).ok_or_else(|| fail("64 characters, but not hex".into()))?,
+        44 => std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| {
+                Alphabet::Standard
+                    .decode(text)
+                    .or_else(|_| UrlSafe.decode(text))
+                    .ok()
+            })
+            .map(Zeroizing::new)
+            .ok_or_else(|| fail("44 characters, but not base64".into()))?,
+        _ => {
+            return Err(fail(format!(
+                "must be exactly 32 raw bytes, 64 hex characters or 44 base64 characters, got {} \
+                 bytes",
+                bytes.len()
+            )));
+        }
+    };
+    anyhow::ensure!(
+        key.len() == 32,
+        "keyfile {}: base64 of {} bytes, not 32",
+        path.display(),
+        key.len()
+    );
+    Ok(key)
+}
+
+fn decode_hex(text: &[u8]) -> Option<KekBytes> {
+    let digit = |c: u8| char::from(c).to_digit(16);
+    let mut key = Zeroizing::new(Vec::with_capacity(text.len() / 2));
+    for pair in text.chunks_exact(2) {
+        key.push((digit(pair[0])? << 4 | digit(pair[1])?) as u8);
+    }
+    Some(key)
+}
+
+/// The number of distinct byte values in `key`.
+fn distinct_bytes(key: &[u8]) -> usize {
+    let mut seen = [false; 256];
+    for &b in key {
+        seen[usize::from(b)] = true;
+    }
+    seen.iter().filter(|&&seen| seen).count()
+}
+
+/// KEK ID of passphrase KEKs derived before each provider drew its own
+/// salt; they were all derived under [`LEGACY_SALT`] and the default
+/// parameters.
+const LEGACY_PASSPHRASE_ID: &str = "device:passphrase";
+
+/// Fixed salt legacy passphrase KEKs were derived under.
+const LEGACY_SALT: [u8; 16] = *b"evfs-default-slt";
+
+/// The salt and parameters a passphrase KEK is derived under, as its
+/// KEK ID records them:
+/// `device:passphrase:argon2id:m=<KiB>,t=<iterations>,p=<lanes>:<salt>`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Derivation {
+    params: Argon2Params,
+    salt: [u8; 16],
+}
+
+impl Derivation {
+    fn random(params: Argon2Params) -> Self {
+        let mut salt = [0u8; 16];
+        getrandom::fill(&mut salt).expect("getrandom failed");
+        Self { params, salt }
+    }
+
+    fn legacy() -> Self {
+        Self {
+            params: Argon2Params::default(),
+            salt: LEGACY_SALT,
+        }
+    }
+
+    fn id(&self) -> KekId {
+        let Argon2Params {
+            memory_kib,
+            iterations,
+            parallelism,
+        } = self.params;
+        KekId(format!(
+            "{LEGACY_PASSPHRASE_ID}:argon2id:m={memory_kib},t={iterations},p={parallelism}:{}",
+            UrlSafe.encode(&self.salt)
+        ))
+    }
+
+    /// The derivation `id` records, if it is a passphrase KEK ID.
+    fn parse(id: &KekId) -> Option<Self> {
+        if id.0 == LEGACY_PASSPHRASE_ID {
+            return Some(Self::legacy());
+        }
+        let rest =
+            id.0.strip_prefix(LEGACY_PASSPHRASE_ID)?
+                .strip_prefix(":argon2id:")?;
+        let (costs, salt) = rest.split_once(':')?;
+        let mut costs = costs.split(',');
+        let mut cost = |name: &str| costs.next()?.strip_prefix(name)?.parse().ok();
+        let params = Argon2Params {
+            memory_kib: cost("m=")?,
+            iterations: cost("t=")?,
+            parallelism: cost("p=")?,
+        };
+        if costs.next().is_some() {
+            return None;
+        }
+        let salt = UrlSafe.decode(salt).ok()?.try_into().ok()?;
+        Some(Self { params, salt })
+    }
+
+    fn derive(&self, passphrase: &str) -> anyhow::Result<KekBytes> {
+        self.params.check()?;
+        let params = argon2::Params::new(
+            self.params.memory_kib,
+            self.params.iterations,
+            self.params.parallelism,
+            Some(32),
+        )
+        .map_err(|e| anyhow::anyhow!("invalid argon2 parameters {:?}: {e}", self.params))?;
+        let mut kek = Zeroizing::new(vec![0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut kek)
+            .map_err(|e| anyhow::anyhow!("argon2 failed: {e}"))?;
+        Ok(kek)
+    }
+}
 
 impl DeviceKeyProvider {
     pub fn from_keyfile(path: PathBuf) -> Self {
         let id = KekId(format!("device:file:{}", path.display()));
         Self {
             id,
-            cached: Mutex::new(None),
-            source: KeySource::File(path),
+            cached: Mutex::new(HashMap::new()),
+            source: KeySource::File {
+                path,
+                refuse_weak: false,
+            },
         }
     }
 
+    /// Fail to load a keyfile whose key has fewer than 8 distinct byte
+    /// values, such as all zeroes, rather than only warning about it.
+    /// Has no effect on a passphrase provider.
+    pub fn with_weak_keys_refused(mut self, refuse: bool) -> Self {
+        if let KeySource::File { refuse_weak, .. } = &mut self.source {
+            *refuse_weak = refuse;
+        }
+        self
+    }
+
+    /// A provider deriving its KEK from `passphrase` with the default
+    /// [`Argon2Params`].
     pub fn from_passphrase(passphrase: &str) -> Self {
-        let id = KekId("device:passphrase".into());
+        Self::from_passphrase_with_params(passphrase, Argon2Params::default())
+    }
+
+    /// A provider deriving its KEK from `passphrase` with `params`.
+    /// DEKs wrapped under a KEK derived with other parameters are
+    /// rewrapped under this one when their database is opened.
+    pub fn from_passphrase_with_params(passphrase: &str, params: Argon2Params) -> Self {
+        Self::derived(passphrase, Derivation::random(params))
+    }
+
+    /// Change the passphrase of the database at `db_path` from `old` to
+    /// `new`, returning the provider to open it with from then on.
+    ///
+    /// `old` must authenticate the database's sidecar and unwrap every
+    /// DEK in it; each is then rewrapped under a KEK derived from `new`
+    /// under a fresh salt, and the sidecar replaced atomically, as
+    /// [`Keyring::import`] does. A wrong `old` leaves the sidecar as it
+    /// was. Close every connection to the database first.
+    pub fn change_passphrase(db_path: &Path, old: &str, new: &str) -> anyhow::Result<Arc<Self>> {
+        let old: Arc<Self> = Arc::new(Self::from_passphrase(old));
+        let keyring = Keyring::new(old.clone());
+        keyring.set_sidecar_path(db_path)?;
+        let export = keyring.export(old.as_ref())?;
+        let new = Arc::new(Self::from_passphrase(new));
+        Keyring::import(export, new.clone())?;
+        Ok(new)
+    }
+
+    fn derived(passphrase: &str, derivation: Derivation) -> Self {
         Self {
-            id,
-            cached: Mutex::new(None),
-            source: KeySource::Passphrase(passphrase.to_owned()),
+            id: derivation.id(),
+            cached: Mutex::new(HashMap::new()),
+            source: KeySource::Passphrase {
+                passphrase: Zeroizing::new(passphrase.to_owned()),
+                params: derivation.params,
+            },
         }
     }
 
-    fn load_kek(&self) -> anyhow::Result<Vec<u8>> {
+    /// A provider deriving its KEK the way passphrase KEKs were before
+    /// each had its own salt.
+    #[cfg(test)]
+    pub(crate) fn from_passphrase_legacy(passphrase: &str) -> Self {
+        let mut provider = Self::derived(passphrase, Derivation::legacy());
+        provider.id = KekId(LEGACY_PASSPHRASE_ID.into());
+        provider
+    }
+
+    #[cfg(test)]
+    fn load_kek(&self) -> anyhow::Result<KekBytes> {
+        self.load_kek_by_id(&self.id)
+    }
+
+    fn load_kek_by_id(&self, id: &KekId) -> anyhow::Result<KekBytes> {
         match &self.source {
-            KeySource::File(path) => {
-                let bytes = std::fs::read(path)?;
-                anyhow::ensure!(
-                    bytes.len() == 32,
-                    "keyfile must be exactly 32 bytes, got {}",
-                    bytes.len()
-                );
-                Ok(bytes)
-            }
-            KeySource::Passphrase(pw) => {
-                let mut kek = [0u8; 32];
-                Argon2::default()
-                    .hash_password_into(pw.as_bytes(), DEFAULT_SALT, &mut kek)
-                    .map_err(|e| anyhow::anyhow!("argon2 failed: {e}"))?;
-                Ok(kek.to_vec())
+            KeySource::File { path, refuse_weak } => {
+                let bytes =
+                    Zeroizing::new(std::fs::read(path).map_err(|e| {
+                        anyhow::anyhow!("cannot read keyfile {}: {e}", path.display())
+                    })?);
+                let key = decode_keyfile(path, &bytes)?;
+                let distinct = distinct_bytes(&key);
+                if distinct < MIN_DISTINCT_KEY_BYTES {
+                    let problem = format!(
+                        "keyfile {}: the key has only {distinct} distinct byte values, so is \
+                         unlikely to be random",
+                        path.display()
+                    );
+                    anyhow::ensure!(!refuse_weak, problem);
+                    log::warn!("{problem}");
+                }
+                Ok(key)
             }
+            KeySource::Passphrase { passphrase, .. } => Derivation::parse(id)
+                .ok_or_else(|| anyhow::anyhow!("not a passphrase KEK id: {id:?}"))?
+                .derive(passphrase),
         }
     }
 
-    fn get_cached_or_load(&self) -> anyhow::Result<Vec<u8>> {
+    fn get_cached_or_load(&self) -> anyhow::Result<KekBytes> {
+        self.cached_or_load(&self.id)
+    }
+
+    fn cached_or_load(&self, id: &KekId) -> anyhow::Result<KekBytes> {
         let mut guard = self.cached.lock();
-        if let Some(ref cached) = *guard {
+        if let Some(cached) = guard.get(id) {
             return Ok(cached.clone());
         }
-        let kek = self.load_kek()?;
-        *guard = Some(kek.clone());
+        let kek = self.load_kek_by_id(id)?;
+        guard.insert(id.clone(), kek.clone());
         Ok(kek)
     }
+
+    /// Whether this provider can derive or read the KEK `id`.
+    fn knows(&self, id: &KekId) -> bool {
+        match &self.source {
+            KeySource::File { .. } => *id == self.id,
+            KeySource::Passphrase { .. } => Derivation::parse(id).is_some(),
+        }
+    }
 }
 
 impl KmsProvider for DeviceKeyProvider {
-    fn get_kek(&self) -> anyhow::Result<(KekId, Vec<u8>)> {
+    fn get_kek(&self) -> anyhow::Result<(KekId, KekBytes)> {
         let bytes = self.get_cached_or_load()?;
         Ok((self.id.clone(), bytes))
     }
 
-    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<Vec<u8>> {
+    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<KekBytes> {
         anyhow::ensure!(
-            id == &self.id,
+            self.knows(id),
             "unknown KEK id: {id:?} (expected {:?})",
             self.id
         );
-        self.get_cached_or_load()
+        self.cached_or_load(id)
+    }
+
+    /// A passphrase KEK is current if it was derived with this
+    /// provider's parameters, whatever its salt: rewrapping it would
+    /// change nothing but the salt. Legacy KEKs never are.
+    fn is_current_kek(&self, id: &KekId) -> anyhow::Result<bool> {
+        Ok(match &self.source {
+            KeySource::File { .. } => *id == self.id,
+            KeySource::Passphrase { params, .. } => {
+                *id == self.id
+                    || id.0 != LEGACY_PASSPHRASE_ID
+                        && Derivation::parse(id).is_some_and(|d| d.params == *params)
+            }
+        })
     }
 }
 
@@ -112,7 +407,15 @@ mod tests {
     #[test]
     fn test_from_passphrase_id() {
         let provider = DeviceKeyProvider::from_passphrase("test");
-        assert_eq!(provider.id, KekId("device:passphrase".into()));
+        assert!(
+            provider
+                .id
+                .0
+                .starts_with("device:passphrase:argon2id:m=19456,t=2,p=1:")
+        );
+        let derivation = Derivation::parse(&provider.id).unwrap();
+        assert_eq!(derivation.params, Argon2Params::default());
+        assert_eq!(derivation.id(), provider.id);
     }
 
     #[test]
@@ -125,7 +428,7 @@ mod tests {
         let provider = DeviceKeyProvider::from_keyfile(file.path().to_path_buf());
         let kek = provider.load_kek()?;
 
-        assert_eq!(kek, key_bytes.to_vec());
+        assert_eq!(*kek, key_bytes.to_vec());
         Ok(())
     }
 
@@ -138,8 +441,10 @@ mod tests {
         let provider = DeviceKeyProvider::from_keyfile(file.path().to_path_buf());
         let result = provider.load_kek();
 
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("exactly 32 bytes"));
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("exactly 32 raw bytes"), "{err}");
+        assert!(err.contains("got 16 bytes"), "{err}");
+        assert!(err.contains(&file.path().display().to_string()), "{err}");
         Ok(())
     }
 
@@ -161,7 +466,98 @@ mod tests {
         let provider = DeviceKeyProvider::from_keyfile(PathBuf::from("/nonexistent/path/key.bin"));
         let result = provider.load_kek();
 
-        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("/nonexistent/path/key.bin"), "{err}");
+    }
+
+    /// A key of 32 distinct bytes.
+    fn random_key() -> Vec<u8> {
+        (0..32u8).map(|i| i.wrapping_mul(37) ^ 0x5c).collect()
+    }
+
+    /// Load the key of a keyfile holding `contents`.
+    fn load_keyfile(contents: &[u8]) -> anyhow::Result<KekBytes> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(contents)?;
+        file.flush()?;
+        DeviceKeyProvider::from_keyfile(file.path().to_path_buf()).load_kek()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_keyfile_encodings() -> anyhow::Result<()> {
+        let key = random_key();
+        let encodings = [
+            hex(&key),
+            format!("{}\n", hex(&key)),
+            hex(&key).to_uppercase(),
+            format!("{}\r\n", Alphabet::Standard.encode(&key)),
+            UrlSafe.encode(&key),
+        ];
+        for encoded in encodings {
+            assert_eq!(*load_keyfile(encoded.as_bytes())?, key, "{encoded:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_keyfile_with_trailing_newline_is_refused() {
+        let mut contents = random_key();
+        contents.push(b'\n');
+        let err = load_keyfile(&contents).unwrap_err().to_string();
+        assert!(err.contains("got 33 bytes"), "{err}");
+    }
+
+    #[test]
+    fn test_malformed_encoded_keyfiles() {
+        let key = random_key();
+        let mut bad_hex = hex(&key);
+        bad_hex.replace_range(10..11, "g");
+        let mut bad_base64 = Alphabet::Standard.encode(&key);
+        bad_base64.replace_range(3..4, "!");
+        let unpadded = format!("{}AA", &UrlSafe.encode(&key)[..42]);
+
+        for (contents, problem) in [
+            (bad_hex, "not hex"),
+            (bad_base64, "not base64"),
+            (unpadded, "base64 of 33 bytes"),
+            (
+                format!("{} {}", &hex(&key)[..31], &hex(&key)[32..]),
+                "not hex",
+            ),
+        ] {
+            let err = load_keyfile(contents.as_bytes()).unwrap_err().to_string();
+            assert!(err.contains(problem), "{contents:?}: {err}");
+            assert!(err.starts_with("keyfile "), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_weak_keyfile_warns_or_is_refused() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&[0u8; 32])?;
+        file.flush()?;
+        let path = file.path().to_path_buf();
+
+        // Accepted, with a warning, by default...
+        assert_eq!(
+            *DeviceKeyProvider::from_keyfile(path.clone()).load_kek()?,
+            [0u8; 32]
+        );
+        // ...refused on request.
+        let refusing = DeviceKeyProvider::from_keyfile(path).with_weak_keys_refused(true);
+        let err = refusing.load_kek().unwrap_err().to_string();
+        assert!(err.contains("only 1 distinct byte values"), "{err}");
+
+        let strong = NamedTempFile::new()?;
+        std::fs::write(strong.path(), random_key())?;
+        DeviceKeyProvider::from_keyfile(strong.path().to_path_buf())
+            .with_weak_keys_refused(true)
+            .load_kek()?;
+        Ok(())
     }
 
     #[test]
@@ -177,15 +573,21 @@ mod tests {
     }
 
     #[test]
-    fn test_passphrase_deterministic() -> anyhow::Result<()> {
+    fn test_passphrase_salts_differ() -> anyhow::Result<()> {
         let provider1 = DeviceKeyProvider::from_passphrase("test");
         let provider2 = DeviceKeyProvider::from_passphrase("test");
+        assert_ne!(provider1.id, provider2.id);
 
-        let kek1 = provider1.load_kek()?;
-        let kek2 = provider2.load_kek()?;
+        let (id1, kek1) = provider1.get_kek()?;
+        let (id2, kek2) = provider2.get_kek()?;
 
-        // Same passphrase should produce same KEK
-        assert_eq!(kek1, kek2);
+        // Same passphrase, different salts: different KEKs...
+        assert_ne!(kek1, kek2);
+        // ...each re-derivable from its ID with the passphrase.
+        assert_eq!(provider2.get_kek_by_id(&id1)?, kek1);
+        assert_eq!(provider1.get_kek_by_id(&id2)?, kek2);
+        // Either is current, having been derived with the same parameters.
+        assert!(provider1.is_current_kek(&id2)?);
         Ok(())
     }
 
@@ -213,7 +615,7 @@ mod tests {
         let (id, kek) = provider.get_kek()?;
 
         assert_eq!(id, provider.id);
-        assert_eq!(kek, key_bytes.to_vec());
+        assert_eq!(*kek, key_bytes.to_vec());
         Ok(())
     }
 
@@ -222,7 +624,7 @@ mod tests {
         let provider = DeviceKeyProvider::from_passphrase("mysecret");
         let (id, kek) = provider.get_kek()?;
 
-        assert_eq!(id, KekId("device:passphrase".into()));
+        assert_eq!(id, provider.id);
         assert_eq!(kek.len(), 32);
         Ok(())
     }
@@ -238,7 +640,7 @@ mod tests {
         let (id, _) = provider.get_kek()?;
 
         let kek = provider.get_kek_by_id(&id)?;
-        assert_eq!(kek, key_bytes.to_vec());
+        assert_eq!(*kek, key_bytes.to_vec());
         Ok(())
     }
 
@@ -289,7 +691,7 @@ mod tests {
 
         // Verify cache is populated
         let cached = provider.cached.lock();
-        assert!(cached.is_some());
+        assert!(cached.contains_key(&provider.id));
         Ok(())
     }
 
@@ -337,4 +739,137 @@ mod tests {
         assert_eq!(kek.len(), 32);
         Ok(())
     }
+
+    #[test]
+    fn test_legacy_passphrase_kek() -> anyhow::Result<()> {
+        let provider = DeviceKeyProvider::from_passphrase("legacy");
+        let legacy = KekId("device:passphrase".into());
+
+        let mut expected = Zeroizing::new(vec![0u8; 32]);
+        Argon2::default()
+            .hash_password_into(b"legacy", &LEGACY_SALT, &mut expected)
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        assert_eq!(provider.get_kek_by_id(&legacy)?, expected);
+        assert!(!provider.is_current_kek(&legacy)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_changed_params_are_not_current() -> anyhow::Result<()> {
+        let params = Argon2Params {
+            memory_kib: 8 * 1024,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let cheap = DeviceKeyProvider::from_passphrase_with_params("test", params);
+        let provider = DeviceKeyProvider::from_passphrase("test");
+        let (id, kek) = cheap.get_kek()?;
+
+        assert!(id.0.contains(":m=8192,t=1,p=1:"));
+        assert!(!provider.is_current_kek(&id)?);
+        assert_eq!(provider.get_kek_by_id(&id)?, kek);
+        Ok(())
+    }
+
+    #[test]
+    fn test_excessive_params_are_refused() {
+        let provider = DeviceKeyProvider::from_passphrase("test");
+        let salt = UrlSafe.encode(&[0u8; 16]);
+        let id = KekId(format!(
+            "device:passphrase:argon2id:m=4194304,t=2,p=1:{salt}"
+        ));
+
+        let err = provider.get_kek_by_id(&id).unwrap_err();
+        assert!(err.to_string().contains("exceed the limit"));
+    }
+
+    #[test]
+    fn test_malformed_passphrase_ids_are_unknown() {
+        let provider = DeviceKeyProvider::from_passphrase("test");
+        for id in [
+            "device:passphrase:argon2id:m=19456,t=2:AAAAAAAAAAAAAAAAAAAAAA==",
+            "device:passphrase:argon2id:m=19456,t=2,p=1,x=1:AAAAAAAAAAAAAAAAAAAAAA==",
+            "device:passphrase:argon2id:m=19456,t=2,p=1:AAAA",
+            "device:passphrase:argon2i:m=19456,t=2,p=1:AAAAAAAAAAAAAAAAAAAAAA==",
+        ] {
+            let err = provider.get_kek_by_id(&KekId(id.into())).unwrap_err();
+            assert!(err.to_string().contains("unknown KEK"), "{id}");
+        }
+    }
+
+    #[test]
+    fn test_wrong_passphrase_fails_at_dek_unwrap() -> anyhow::Result<()> {
+        let right = DeviceKeyProvider::from_passphrase("right");
+        let wrong = DeviceKeyProvider::from_passphrase("wrong");
+        let dek = crate::crypto::keys::Dek::generate();
+        let wrapped = crate::crypto::envelope::wrap_dek(&dek, &right)?;
+
+        assert!(crate::crypto::envelope::unwrap_dek(&wrapped, &wrong).is_err());
+        let unwrapped = crate::crypto::envelope::unwrap_dek(&wrapped, &right)?;
+        assert_eq!(unwrapped.as_bytes(), dek.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_keyring_is_migrated_on_open() -> anyhow::Result<()> {
+        use std::sync::Arc;
+
+        use crate::crypto::keys::KeyScope;
+
+        let dir = tempfile::tempdir()?;
+        let db = dir.path().join("legacy.db");
+        let legacy = Keyring::new(Arc::new(DeviceKeyProvider::from_passphrase_legacy("pw")));
+        legacy.set_sidecar_path(&db)?;
+        let dek = legacy.dek_for(&KeyScope::Database)?;
+
+        // A wrong passphrase fails when the sidecar is authenticated,
+        // before any page is read.
+        let wrong = Keyring::new(Arc::new(DeviceKeyProvider::from_passphrase("nope")));
+        assert!(wrong.set_sidecar_path(&db).is_err());
+
+        let keyring = Keyring::new(Arc::new(DeviceKeyProvider::from_passphrase("pw")));
+        keyring.set_sidecar_path(&db)?;
+        assert!(keyring.kek_status().is_current());
+        assert_eq!(
+            keyring.dek_for(&KeyScope::Database)?.as_bytes(),
+            dek.as_bytes()
+        );
+
+        // The sidecar now records the salted derivation.
+        let reopened = Keyring::new(Arc::new(DeviceKeyProvider::from_passphrase("pw")));
+        reopened.set_sidecar_path(&db)?;
+        assert!(reopened.kek_status().is_current());
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_passphrase() -> anyhow::Result<()> {
+        use crate::crypto::keys::KeyScope;
+
+        let dir = tempfile::tempdir()?;
+        let db = dir.path().join("changed.db");
+        let keyring = Keyring::new(Arc::new(DeviceKeyProvider::from_passphrase("old")));
+        keyring.set_sidecar_path(&db)?;
+        let dek = keyring.dek_for(&KeyScope::Database)?;
+        let sidecar = crate::keyring::sidecar_path_for(&db);
+        let before = std::fs::read(&sidecar)?;
+
+        // A wrong old passphrase leaves the sidecar alone.
+        assert!(DeviceKeyProvider::change_passphrase(&db, "wrong", "new").is_err());
+        assert_eq!(std::fs::read(&sidecar)?, before);
+
+        let provider = DeviceKeyProvider::change_passphrase(&db, "old", "new")?;
+        let changed = Keyring::new(provider);
+        changed.set_sidecar_path(&db)?;
+        assert_eq!(
+            changed.existing_dek(&KeyScope::Database)?.as_bytes(),
+            dek.as_bytes()
+        );
+        // Only the new passphrase opens it now.
+        let reopened = Keyring::new(Arc::new(DeviceKeyProvider::from_passphrase("new")));
+        reopened.set_sidecar_path(&db)?;
+        let old = Keyring::new(Arc::new(DeviceKeyProvider::from_passphrase("old")));
+        assert!(old.set_sidecar_path(&db).is_err());
+        Ok(())
+    }
 }
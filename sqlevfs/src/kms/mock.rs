@@ -0,0 +1,345 @@
+//! An in-memory KMS for tests, here and downstream.
+//!
+//! [`MockKmsProvider`] keeps its KEKs in memory and can be told to
+//! fail, stall or rotate, so KMS outages and key rotation can be tested
+//! without keyfiles or a KMS to talk to.
+
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
+use parking_lot::Mutex;
+use zeroize::Zeroizing;
+
+use super::{KekBytes, KmsProvider};
+use crate::crypto::keys::KekId;
+
+/// A [`KmsProvider`] call, to count or fail.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Call {
+    GetKek,
+    GetKekById,
+    WrapBlob,
+    UnwrapBlob,
+}
+
+impl Call {
+    const ALL: [Self; 4] = [
+        Self::GetKek,
+        Self::GetKekById,
+        Self::WrapBlob,
+        Self::UnwrapBlob,
+    ];
+}
+
+/// One version of the mock's key.
+struct Version {
+    kek: Zeroizing<[u8; 32]>,
+    enabled: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// Oldest first; the last is current.
+    versions: Vec<Version>,
+    offline: bool,
+    /// Calls to fail, by kind and number.
+    failures: HashSet<(Call, usize)>,
+}
+
+/// An in-memory [`KmsProvider`].
+///
+/// Its key starts with one version, whose KEK has the id `mock-v1`;
+/// [`Self::rotate`] adds `mock-v2` and so on, and the last is current.
+/// `wrap_blob` and `unwrap_blob` seal blobs with AES-256-GCM under the
+/// current version, as a KMS that never hands out its keys would.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use sqlevfs::{
+///     crypto::keys::KeyScope,
+///     keyring::Keyring,
+///     kms::mock::{Call, MockKmsProvider},
+/// };
+///
+/// let kms = Arc::new(MockKmsProvider::new());
+/// let keyring = Keyring::new(kms.clone());
+/// keyring.dek_for(&KeyScope::Database)?;
+/// assert_eq!(kms.calls(Call::GetKek), 1);
+///
+/// kms.set_offline(true);
+/// assert!(Keyring::new(kms.clone()).dek_for(&KeyScope::Database).is_err());
+/// # anyhow::Ok(())
+/// ```
+pub struct MockKmsProvider {
+    state: Mutex<State>,
+    latency: Duration,
+    direct_wrap: bool,
+    /// Calls made, by [`Call`], including failed ones.
+    calls: [AtomicUsize; 4],
+}
+
+impl Default for MockKmsProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockKmsProvider {
+    /// A KMS whose key has one version, with a random KEK.
+    pub fn new() -> Self {
+        let mock = Self {
+            state: Mutex::new(State::default()),
+            latency: Duration::ZERO,
+            direct_wrap: false,
+            calls: Default::default(),
+        };
+        mock.rotate();
+        mock
+    }
+
+    /// Make the KEK of the current version `kek`.
+    pub fn with_kek(self, kek: [u8; 32]) -> Self {
+        if let Some(version) = self.state.lock().versions.last_mut() {
+            version.kek = Zeroizing::new(kek);
+        }
+        self
+    }
+
+    /// Take `latency` to answer each call, as a remote KMS would.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Have DEKs wrapped with `wrap_blob`, as by a KMS that never hands
+    /// out its keys, rather than locally under a KEK from `get_kek`.
+    pub fn with_direct_wrap(mut self) -> Self {
+        self.direct_wrap = true;
+        self
+    }
+
+    /// Rotate the key: add a version with a random KEK, and make it
+    /// current. Returns its id.
+    pub fn rotate(&self) -> KekId {
+        let mut kek = Zeroizing::new([0u8; 32]);
+        getrandom::fill(kek.as_mut()).expect("getrandom failed");
+        let mut state = self.state.lock();
+        state.versions.push(Version { kek, enabled: true });
+        version_id(state.versions.len())
+    }
+
+    /// The id of the current version's KEK.
+    pub fn current_kek_id(&self) -> KekId {
+        version_id(self.state.lock().versions.len())
+    }
+
+    /// Disable the version whose KEK is `id`, or enable it again: what
+    /// it wrapped can't be unwrapped while it is disabled. Disabling
+    /// the current version fails wrapping too.
+    pub fn set_enabled(&self, id: &KekId, enabled: bool) {
+        let mut state = self.state.lock();
+        let version = version_of(id)
+            .and_then(|version| state.versions.get_mut(version - 1))
+            .unwrap_or_else(|| panic!("mock KMS has no KEK {:?}", id.0));
+        version.enabled = enabled;
+    }
+
+    /// Fail every call while `offline`, as when the KMS can't be
+    /// reached.
+    pub fn set_offline(&self, offline: bool) {
+        self.state.lock().offline = offline;
+    }
+
+    /// Fail the `n`th call to `call`, counting from 1 and including the
+    /// calls made already.
+    pub fn fail_nth(&self, call: Call, n: usize) {
+        self.state.lock().failures.insert((call, n));
+    }
+
+    /// How many times `call` has been made, including failed calls.
+    pub fn calls(&self, call: Call) -> usize {
+        self.calls[call as usize].load(Ordering::SeqCst)
+    }
+
+    /// How many calls of any kind have been made.
+    pub fn total_calls(&self) -> usize {
+        Call::ALL.into_iter().map(|call| self.calls(call)).sum()
+    }
+
+    /// Count `call`, wait out the latency, and fail it if it is to
+    /// fail. Returns the state to answer it from.
+    fn answer(&self, call: Call) -> anyhow::Result<parking_lot::MutexGuard<'_, State>> {
+        let n = self.calls[call as usize].fetch_add(1, Ordering::SeqCst) + 1;
+        if !self.latency.is_zero() {
+            thread::sleep(self.latency);
+        }
+        let state = self.state.lock();
+        anyhow::ensure!(!state.offline, "mock KMS is offline");
+        anyhow::ensure!(
+            !state.failures.contains(&(call, n)),
+            "mock KMS failed {call:?} call {n} as told"
+        );
+        Ok(state)
+    }
+}
+
+fn version_id(version: usize) -> KekId {
+    KekId(format!("mock-v{version}"))
+}
+
+fn version_of(id: &KekId) -> Option<usize> {
+    id.0.strip_prefix("mock-v")?.parse().ok()
+}
+
+impl State {
+    /// The KEK of `version`, counting from 1, if it is enabled.
+    fn kek(&self, version: usize) -> anyhow::Result<&[u8; 32]> {
+        let found = version
+            .checked_sub(1)
+            .and_then(|index| self.versions.get(index))
+            .ok_or_else(|| anyhow::anyhow!("mock KMS has no KEK {:?}", version_id(version).0))?;
+        anyhow::ensure!(
+            found.enabled,
+            "mock KEK {:?} is disabled",
+            version_id(version).0
+        );
+        Ok(&found.kek)
+    }
+}
+
+impl KmsProvider for MockKmsProvider {
+    fn get_kek(&self) -> anyhow::Result<(KekId, KekBytes)> {
+        let state = self.answer(Call::GetKek)?;
+        let version = state.versions.len();
+        let kek = state.kek(version)?;
+        Ok((version_id(version), Zeroizing::new(kek.to_vec())))
+    }
+
+    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<KekBytes> {
+        let state = self.answer(Call::GetKekById)?;
+        let version =
+            version_of(id).ok_or_else(|| anyhow::anyhow!("mock KMS has no KEK {:?}", id.0))?;
+        Ok(Zeroizing::new(state.kek(version)?.to_vec()))
+    }
+
+    fn prefers_direct_wrap(&self) -> bool {
+        self.direct_wrap
+    }
+
+    /// Seals `plaintext` under the current version, prefixed by the
+    /// version, as 4 bytes big-endian, and the nonce.
+    fn wrap_blob(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let state = self.answer(Call::WrapBlob)?;
+        let version = state.versions.len();
+        let cipher = Aes256Gcm::new_from_slice(state.kek(version)?)?;
+        let mut nonce = [0u8; 12];
+        getrandom::fill(&mut nonce).expect("getrandom failed");
+        let sealed = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|e| anyhow::anyhow!("mock wrap failed: {e}"))?;
+        let mut blob = (version as u32).to_be_bytes().to_vec();
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(&sealed);
+        Ok(blob)
+    }
+
+    fn unwrap_blob(&self, ciphertext: &[u8]) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let state = self.answer(Call::UnwrapBlob)?;
+        anyhow::ensure!(ciphertext.len() >= 16, "mock blob is truncated");
+        let (version, rest) = ciphertext.split_at(4);
+        let (nonce, sealed) = rest.split_at(12);
+        let version = u32::from_be_bytes(version.try_into()?) as usize;
+        let cipher = Aes256Gcm::new_from_slice(state.kek(version)?)?;
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| anyhow::anyhow!("mock unwrap failed: wrong KEK or corrupted blob"))?;
+        Ok(Zeroizing::new(plaintext))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keks_are_kept_by_version() {
+        let kms = MockKmsProvider::new().with_kek([7; 32]);
+        let (v1, kek) = kms.get_kek().unwrap();
+        assert_eq!(v1, KekId("mock-v1".into()));
+        assert_eq!(*kek, [7; 32]);
+
+        let v2 = kms.rotate();
+        assert_eq!(kms.current_kek_id(), v2);
+        assert_eq!(kms.get_kek().unwrap().0, v2);
+        assert_eq!(*kms.get_kek_by_id(&v1).unwrap(), [7; 32]);
+        assert_ne!(*kms.get_kek_by_id(&v2).unwrap(), [7; 32]);
+
+        kms.set_enabled(&v1, false);
+        let e = kms.get_kek_by_id(&v1).unwrap_err();
+        assert_eq!(e.to_string(), r#"mock KEK "mock-v1" is disabled"#);
+        assert!(kms.get_kek_by_id(&KekId("mock-v3".into())).is_err());
+        assert!(kms.get_kek_by_id(&KekId("other".into())).is_err());
+        kms.set_enabled(&v1, true);
+        assert!(kms.get_kek_by_id(&v1).is_ok());
+    }
+
+    #[test]
+    fn blobs_are_wrapped_under_the_current_version() {
+        let kms = MockKmsProvider::new();
+        let blob = kms.wrap_blob(b"dek").unwrap();
+        assert_eq!(*kms.unwrap_blob(&blob).unwrap(), b"dek");
+
+        let v2 = kms.rotate();
+        let newer = kms.wrap_blob(b"dek").unwrap();
+        assert_eq!(newer[..4], 2u32.to_be_bytes());
+        kms.set_enabled(&KekId("mock-v1".into()), false);
+        assert!(kms.unwrap_blob(&blob).is_err());
+        assert_eq!(*kms.unwrap_blob(&newer).unwrap(), b"dek");
+
+        let mut tampered = newer.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(kms.unwrap_blob(&tampered).is_err());
+        assert!(kms.unwrap_blob(&newer[..10]).is_err());
+        // Another mock's KEKs differ.
+        assert!(MockKmsProvider::new().unwrap_blob(&blob).is_err());
+
+        kms.set_enabled(&v2, false);
+        assert!(kms.wrap_blob(b"dek").is_err());
+    }
+
+    #[test]
+    fn failures_are_injected_and_calls_counted() {
+        let kms = MockKmsProvider::new();
+        kms.fail_nth(Call::GetKek, 2);
+        assert!(kms.get_kek().is_ok());
+        let e = kms.get_kek().unwrap_err();
+        assert_eq!(e.to_string(), "mock KMS failed GetKek call 2 as told");
+        assert!(kms.get_kek().is_ok());
+
+        kms.set_offline(true);
+        assert!(kms.get_kek_by_id(&KekId("mock-v1".into())).is_err());
+        assert!(kms.wrap_blob(b"x").is_err());
+        kms.set_offline(false);
+        assert!(kms.wrap_blob(b"x").is_ok());
+
+        assert_eq!(kms.calls(Call::GetKek), 3);
+        assert_eq!(kms.calls(Call::GetKekById), 1);
+        assert_eq!(kms.calls(Call::WrapBlob), 2);
+        assert_eq!(kms.calls(Call::UnwrapBlob), 0);
+        assert_eq!(kms.total_calls(), 6);
+    }
+
+    #[test]
+    fn calls_take_the_latency() {
+        let kms = MockKmsProvider::new().with_latency(Duration::from_millis(50));
+        let start = std::time::Instant::now();
+        kms.get_kek().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
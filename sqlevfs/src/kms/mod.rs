@@ -1,26 +1,55 @@
+pub mod base64;
 pub mod cloud;
+#[cfg(feature = "keychain")]
+pub mod keychain;
 pub mod local;
+pub mod mock;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
+pub mod retry;
+
+use zeroize::Zeroizing;
 
 use crate::crypto::keys::KekId;
 
+/// Raw KEK bytes, zeroized when dropped.
+pub type KekBytes = Zeroizing<Vec<u8>>;
+
 /// Synchronous interface for obtaining key-encryption keys.
 pub trait KmsProvider: Send + Sync + 'static {
     /// Return the current active KEK (id + raw bytes).
-    fn get_kek(&self) -> anyhow::Result<(KekId, Vec<u8>)>;
+    fn get_kek(&self) -> anyhow::Result<(KekId, KekBytes)>;
 
     /// Retrieve a specific KEK by id (needed during rotation to
     /// unwrap DEKs wrapped under older KEKs).
-    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<Vec<u8>>;
+    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<KekBytes>;
+
+    /// Whether `id` is the KEK new DEKs are wrapped under, rather than
+    /// one the KMS has since rotated away from. A keyring rewraps DEKs
+    /// wrapped under an older KEK when it loads them, so they stay
+    /// readable once that KEK is disabled. By default only the id
+    /// [`Self::get_kek`] returns is current.
+    fn is_current_kek(&self, id: &KekId) -> anyhow::Result<bool> {
+        Ok(self.get_kek()?.0 == *id)
+    }
+
+    /// Whether DEKs are to be wrapped with [`Self::wrap_blob`], so the
+    /// KEK never leaves the KMS, rather than locally under the KEK
+    /// [`Self::get_kek`] hands out. DEKs wrapped locally are still
+    /// unwrapped with [`Self::get_kek_by_id`]. By default, false.
+    fn prefers_direct_wrap(&self) -> bool {
+        false
+    }
 
     /// Optional: ask the KMS to wrap a blob directly (for providers
-    /// where the KEK never leaves the HSM). Default falls back to
-    /// local envelope encryption.
+    /// where the KEK never leaves the HSM). Unsupported by default.
     fn wrap_blob(&self, _plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
-        anyhow::bail!("direct wrap not supported; use local envelope")
+        anyhow::bail!("this key source can't wrap blobs directly")
     }
 
-    /// Optional: ask the KMS to unwrap a blob directly.
-    fn unwrap_blob(&self, _ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
-        anyhow::bail!("direct unwrap not supported; use local envelope")
+    /// Optional: ask the KMS to unwrap a blob directly. The plaintext
+    /// is zeroized when dropped. Unsupported by default.
+    fn unwrap_blob(&self, _ciphertext: &[u8]) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        anyhow::bail!("this key source can't unwrap blobs directly")
     }
 }
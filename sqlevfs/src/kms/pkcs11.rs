@@ -0,0 +1,442 @@
+//! KEKs wrapped by an AES key that never leaves an HSM, through the
+//! HSM's PKCS#11 module.
+
+use std::{fmt, path::PathBuf};
+
+use cryptoki::{
+    context::{CInitializeArgs, CInitializeFlags, Pkcs11},
+    error::{Error, RvError},
+    mechanism::Mechanism,
+    object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, SessionState, UserType},
+    slot::Slot,
+    types::AuthPin,
+};
+use parking_lot::Mutex;
+use zeroize::{Zeroize, Zeroizing};
+
+use super::{KekBytes, KmsProvider, base64};
+use crate::crypto::keys::KekId;
+
+/// Prefix of the IDs of KEKs this provider wraps; the rest is the
+/// wrapped KEK, base64 encoded.
+const ID_PREFIX: &str = "pkcs11:";
+
+/// Which HSM key [`Pkcs11Provider`] wraps KEKs with, and how it logs in.
+#[derive(Clone)]
+pub struct Pkcs11Config {
+    /// Path of the PKCS#11 module, e.g.
+    /// `/usr/lib/softhsm/libsofthsm2.so`.
+    pub module: PathBuf,
+    /// Label of the token holding the key.
+    pub token_label: String,
+    /// The token's user PIN.
+    pub pin: Zeroizing<String>,
+    /// Label of the AES key, which must allow `CKA_WRAP` and
+    /// `CKA_UNWRAP`.
+    pub key_label: String,
+    /// Sessions kept open between calls, 4 by default.
+    pub max_idle_sessions: usize,
+}
+
+impl Pkcs11Config {
+    pub fn new(
+        module: impl Into<PathBuf>,
+        token_label: impl Into<String>,
+        pin: &str,
+        key_label: impl Into<String>,
+    ) -> Self {
+        Self {
+            module: module.into(),
+            token_label: token_label.into(),
+            pin: Zeroizing::new(pin.to_owned()),
+            key_label: key_label.into(),
+            max_idle_sessions: 4,
+        }
+    }
+
+    pub fn max_idle_sessions(mut self, sessions: usize) -> Self {
+        self.max_idle_sessions = sessions;
+        self
+    }
+}
+
+impl fmt::Debug for Pkcs11Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs11Config")
+            .field("module", &self.module)
+            .field("token_label", &self.token_label)
+            .field("pin", &"..")
+            .field("key_label", &self.key_label)
+            .field("max_idle_sessions", &self.max_idle_sessions)
+            .finish()
+    }
+}
+
+/// HSM-backed KEK provider.
+///
+/// Like [`CloudKmsProvider`](super::cloud::CloudKmsProvider), it
+/// generates a random KEK locally and keeps only its wrapped form, in
+/// the [`KekId`]; the wrapping key stays in the HSM, which wraps and
+/// unwraps with `CKM_AES_KEY_WRAP_PAD`.
+///
+/// Sessions are pooled. One found closed or logged out, e.g. after the
+/// token was reset, is replaced by a new one, logged in again.
+pub struct Pkcs11Provider {
+    pkcs11: Pkcs11,
+    slot: Slot,
+    config: Pkcs11Config,
+    idle: Mutex<Vec<Session>>,
+    /// Cache the last generated KEK so we don't call the HSM on every
+    /// page write.
+    cached_kek: Mutex<Option<(KekId, KekBytes)>>,
+}
+
+impl Pkcs11Provider {
+    /// Load `config.module`, log in to the token and find the key,
+    /// failing if any of them can't be.
+    pub fn new(config: Pkcs11Config) -> anyhow::Result<Self> {
+        let pkcs11 = Pkcs11::new(&config.module).map_err(|e| {
+            anyhow::anyhow!("loading PKCS#11 module {}: {e}", config.module.display())
+        })?;
+        // Another provider in this process may have initialized the
+        // module already.
+        match pkcs11.initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK)) {
+            Ok(()) | Err(Error::Pkcs11(RvError::CryptokiAlreadyInitialized, _)) => {}
+            Err(e) => anyhow::bail!("initializing {}: {e}", config.module.display()),
+        }
+        let mut slot = None;
+        for candidate in pkcs11.get_slots_with_token()? {
+            if pkcs11.get_token_info(candidate)?.label().trim_end() == config.token_label {
+                slot = Some(candidate);
+                break;
+            }
+        }
+        let slot = slot.ok_or_else(|| {
+            anyhow::anyhow!(
+                "no token labelled {:?} in {}",
+                config.token_label,
+                config.module.display()
+            )
+        })?;
+
+        let provider = Self {
+            pkcs11,
+            slot,
+            config,
+            idle: Mutex::new(Vec::new()),
+            cached_kek: Mutex::new(None),
+        };
+        provider.with_session(|session| provider.wrapping_key(session).map(drop))?;
+        Ok(provider)
+    }
+
+    /// Run `op` in a logged-in session, taken from the pool or opened,
+    /// and return the session to the pool. If the session turns out to
+    /// be invalid, `op` is retried once in a new one.
+    fn with_session<T>(&self, op: impl Fn(&Session) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let idle = self.idle.lock().pop();
+        let session = match idle {
+            Some(session) => session,
+            None => self.open_session()?,
+        };
+        let (session, result) = match self.ensure_logged_in(&session).and_then(|()| op(&session)) {
+            Err(e) if is_stale(&e) => {
+                log::warn!("PKCS#11 session is no longer valid ({e:#}); opening another");
+                // The others are likely as stale.
+                self.idle.lock().clear();
+                drop(session);
+                let session = self.open_session()?;
+                let result = op(&session);
+                (session, result)
+            }
+            result => (session, result),
+        };
+        let mut idle = self.idle.lock();
+        if idle.len() < self.config.max_idle_sessions {
+            idle.push(session);
+        }
+        result
+    }
+
+    fn open_session(&self) -> anyhow::Result<Session> {
+        let session = self.pkcs11.open_rw_session(self.slot)?;
+        self.login(&session)?;
+        Ok(session)
+    }
+
+    /// Log `session` in, unless it, or another session of this process
+    /// on the token, is logged in already.
+    fn login(&self, session: &Session) -> anyhow::Result<()> {
+        let pin = AuthPin::from(self.config.pin.as_str());
+        match session.login(UserType::User, Some(&pin)) {
+            Ok(()) | Err(Error::Pkcs11(RvError::UserAlreadyLoggedIn, _)) => Ok(()),
+            Err(e) => Err(anyhow::anyhow!(
+                "logging in to token {:?}: {e}",
+                self.config.token_label
+            )),
+        }
+    }
+
+    /// Log a pooled `session` in again if the token has logged it out.
+    fn ensure_logged_in(&self, session: &Session) -> anyhow::Result<()> {
+        match session.get_session_info()?.session_state() {
+            SessionState::RoPublic | SessionState::RwPublic => {
+                log::info!("PKCS#11 session was logged out; logging in again");
+                self.login(session)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn wrapping_key(&self, session: &Session) -> anyhow::Result<ObjectHandle> {
+        let keys = session.find_objects(&[
+            Attribute::Class(ObjectClass::SECRET_KEY),
+            Attribute::KeyType(KeyType::AES),
+            Attribute::Label(self.config.key_label.as_bytes().to_vec()),
+        ])?;
+        match keys[..] {
+            [key] => Ok(key),
+            [] => anyhow::bail!(
+                "no AES key labelled {:?} on token {:?}",
+                self.config.key_label,
+                self.config.token_label
+            ),
+            _ => anyhow::bail!(
+                "{} AES keys are labelled {:?} on token {:?}; labels must be unique",
+                keys.len(),
+                self.config.key_label,
+                self.config.token_label
+            ),
+        }
+    }
+
+    /// Wrap `plaintext` by importing it as a session object and wrapping
+    /// that.
+    fn wrap(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.with_session(|session| {
+            let key = self.wrapping_key(session)?;
+            let mut template = secret_template();
+            template.push(Attribute::Value(plaintext.to_vec()));
+            let object = session.create_object(&template);
+            if let Some(Attribute::Value(value)) = template.last_mut() {
+                value.zeroize();
+            }
+            let object = object?;
+            let wrapped = session.wrap_key(&Mechanism::AesKeyWrapPad, key, object);
+            let _ = session.destroy_object(object);
+            Ok(wrapped?)
+        })
+    }
+
+    /// Unwrap `ciphertext` into a session object and read its value.
+    fn unwrap(&self, ciphertext: &[u8]) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        self.with_session(|session| {
+            let key = self.wrapping_key(session)?;
+            let object = session
+                .unwrap_key(
+                    &Mechanism::AesKeyWrapPad,
+                    key,
+                    ciphertext,
+                    &secret_template(),
+                )
+                .map_err(|e| anyhow::anyhow!("unwrapping with {:?}: {e}", self.config.key_label))?;
+            let value = session.get_attributes(object, &[AttributeType::Value]);
+            let _ = session.destroy_object(object);
+            match value?.pop() {
+                Some(Attribute::Value(value)) => Ok(Zeroizing::new(value)),
+                _ => anyhow::bail!("the HSM did not return the unwrapped value"),
+            }
+        })
+    }
+}
+
+/// Attributes of the short-lived session objects wrapped and unwrapped.
+fn secret_template() -> Vec<Attribute> {
+    vec![
+        Attribute::Class(ObjectClass::SECRET_KEY),
+        Attribute::KeyType(KeyType::GENERIC_SECRET),
+        Attribute::Token(false),
+        Attribute::Sensitive(false),
+        Attribute::Extractable(true),
+    ]
+}
+
+/// Whether `e` means the session, or its login, is gone.
+fn is_stale(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<Error>(),
+        Some(Error::Pkcs11(
+            RvError::SessionHandleInvalid
+                | RvError::SessionClosed
+                | RvError::UserNotLoggedIn
+                | RvError::DeviceRemoved,
+            _
+        ))
+    )
+}
+
+impl fmt::Debug for Pkcs11Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs11Provider")
+            .field("config", &self.config)
+            .field("slot", &self.slot)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KmsProvider for Pkcs11Provider {
+    fn get_kek(&self) -> anyhow::Result<(KekId, KekBytes)> {
+        let mut guard = self.cached_kek.lock();
+        if let Some(ref cached) = *guard {
+            return Ok(cached.clone());
+        }
+        let mut kek = Zeroizing::new(vec![0u8; 32]);
+        getrandom::fill(&mut kek).expect("getrandom failed");
+        let id = KekId(format!("{ID_PREFIX}{}", base64::encode(&self.wrap(&kek)?)));
+        *guard = Some((id.clone(), kek.clone()));
+        Ok((id, kek))
+    }
+
+    fn get_kek_by_id(&self, id: &KekId) -> anyhow::Result<KekBytes> {
+        {
+            let guard = self.cached_kek.lock();
+            if let Some((ref cached_id, ref bytes)) = *guard
+                && cached_id == id
+            {
+                return Ok(bytes.clone());
+            }
+        }
+        let wrapped = id.0.strip_prefix(ID_PREFIX).ok_or_else(|| {
+            anyhow::anyhow!("KEK {:?} was not wrapped by a PKCS#11 provider", id.0)
+        })?;
+        let wrapped = base64::decode(wrapped)
+            .map_err(|e| anyhow::anyhow!("KEK {:?} is not a valid PKCS#11 KEK id: {e}", id.0))?;
+        let kek = self.unwrap(&wrapped)?;
+        anyhow::ensure!(
+            kek.len() == 32,
+            "HSM unwrapped a {} byte KEK, expected 32",
+            kek.len()
+        );
+        Ok(kek)
+    }
+
+    /// KEKs are generated afresh by each process, all wrapped by the
+    /// one HSM key, so every KEK this provider wrapped is current.
+    fn is_current_kek(&self, id: &KekId) -> anyhow::Result<bool> {
+        Ok(id.0.starts_with(ID_PREFIX))
+    }
+
+    /// DEKs are wrapped by the HSM key itself.
+    fn prefers_direct_wrap(&self) -> bool {
+        true
+    }
+
+    fn wrap_blob(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.wrap(plaintext)
+    }
+
+    fn unwrap_blob(&self, ciphertext: &[u8]) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        self.unwrap(ciphertext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_hides_the_pin() {
+        let config = Pkcs11Config::new("/usr/lib/softhsm/libsofthsm2.so", "evfs", "1234", "kek");
+        let debug = format!("{config:?}");
+        assert!(debug.contains(r#"pin: "..""#), "{debug}");
+        assert!(!debug.contains("1234"), "{debug}");
+    }
+
+    #[test]
+    fn missing_module_is_reported() {
+        let config = Pkcs11Config::new("/nonexistent/libpkcs11.so", "evfs", "1234", "kek");
+        let e = Pkcs11Provider::new(config).unwrap_err().to_string();
+        assert!(
+            e.starts_with("loading PKCS#11 module /nonexistent/libpkcs11.so: "),
+            "{e}"
+        );
+    }
+
+    /// Wraps KEKs with a key generated on the token
+    /// `EVFS_PKCS11_TEST_TOKEN`, with the user PIN
+    /// `EVFS_PKCS11_TEST_PIN`, of the module `EVFS_PKCS11_TEST_MODULE`,
+    /// e.g. SoftHSM2's. Skipped unless all three are set.
+    #[test]
+    fn softhsm_round_trip() {
+        let (Ok(module), Ok(token), Ok(pin)) = (
+            std::env::var("EVFS_PKCS11_TEST_MODULE"),
+            std::env::var("EVFS_PKCS11_TEST_TOKEN"),
+            std::env::var("EVFS_PKCS11_TEST_PIN"),
+        ) else {
+            eprintln!(
+                "EVFS_PKCS11_TEST_MODULE, EVFS_PKCS11_TEST_TOKEN and EVFS_PKCS11_TEST_PIN not \
+                 set, skipping"
+            );
+            return;
+        };
+        let label = format!("evfs-test-{}", std::process::id());
+        let config = Pkcs11Config::new(&module, &token, &pin, &label).max_idle_sessions(1);
+        // No key yet.
+        let e = Pkcs11Provider::new(config.clone()).unwrap_err();
+        assert!(e.to_string().starts_with("no AES key labelled"), "{e}");
+
+        let pkcs11 = Pkcs11::new(&module).unwrap();
+        match pkcs11.initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK)) {
+            Ok(()) | Err(Error::Pkcs11(RvError::CryptokiAlreadyInitialized, _)) => {}
+            Err(e) => panic!("{e}"),
+        }
+        let slot = pkcs11
+            .get_slots_with_token()
+            .unwrap()
+            .into_iter()
+            .find(|&slot| pkcs11.get_token_info(slot).unwrap().label().trim_end() == token)
+            .unwrap();
+        let admin = pkcs11.open_rw_session(slot).unwrap();
+        admin
+            .login(UserType::User, Some(&AuthPin::from(pin.as_str())))
+            .unwrap();
+        let key = admin
+            .generate_key(
+                &Mechanism::AesKeyGen,
+                &[
+                    Attribute::Token(true),
+                    Attribute::Private(true),
+                    Attribute::ValueLen(32.into()),
+                    Attribute::Label(label.as_bytes().to_vec()),
+                    Attribute::Wrap(true),
+                    Attribute::Unwrap(true),
+                ],
+            )
+            .unwrap();
+
+        let provider = Pkcs11Provider::new(config.clone()).unwrap();
+        let (id, kek) = provider.get_kek().unwrap();
+        assert!(id.0.starts_with(ID_PREFIX));
+        assert_eq!(provider.get_kek().unwrap().0, id);
+
+        // Another provider unwraps it through the HSM.
+        let other = Pkcs11Provider::new(config).unwrap();
+        assert_eq!(other.get_kek_by_id(&id).unwrap(), kek);
+        let blob = other.wrap_blob(b"a DEK of any length").unwrap();
+        assert_eq!(
+            &*provider.unwrap_blob(&blob).unwrap(),
+            b"a DEK of any length"
+        );
+        let mut tampered = blob.clone();
+        tampered[3] ^= 1;
+        assert!(provider.unwrap_blob(&tampered).is_err());
+
+        // Logging out logs out every session of the process; pooled
+        // sessions log in again.
+        admin.logout().unwrap();
+        assert_eq!(other.get_kek_by_id(&id).unwrap(), kek);
+
+        admin.destroy_object(key).unwrap();
+    }
+}
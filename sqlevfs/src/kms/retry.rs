@@ -0,0 +1,209 @@
+//! Retrying KMS calls that fail transiently.
+
+use std::{fmt, thread, time::Duration};
+
+/// How often, and how long apart, a KMS call is retried after a
+/// transient failure: a timeout or other transport error, a 429 or a
+/// 5xx. Errors the service reports, such as bad credentials, are never
+/// retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in all, counting the first; 1 never retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub base_delay: Duration,
+    /// Longest delay between attempts.
+    pub max_delay: Duration,
+    /// Wait a random time between half the delay and all of it, so that
+    /// clients failing together don't retry together.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    /// 4 attempts, 100 ms apart at first and 5 s at most, with jitter.
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Run `op` until it succeeds, fails with an error `is_transient`
+    /// rejects, or has been attempted `max_attempts` times, returning
+    /// its last result.
+    pub(crate) fn run<T, E: fmt::Display>(
+        &self,
+        what: &str,
+        op: impl FnMut() -> Result<T, E>,
+        is_transient: impl Fn(&E) -> bool,
+    ) -> Result<T, E> {
+        self.run_with(what, op, is_transient, thread::sleep)
+    }
+
+    fn run_with<T, E: fmt::Display>(
+        &self,
+        what: &str,
+        mut op: impl FnMut() -> Result<T, E>,
+        is_transient: impl Fn(&E) -> bool,
+        mut sleep: impl FnMut(Duration),
+    ) -> Result<T, E> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if attempt < self.max_attempts && is_transient(&e) => {
+                    let delay = self.delay(attempt - 1);
+                    log::warn!(
+                        "{what} failed ({e}); retrying in {delay:?}, attempt {} of {}",
+                        attempt + 1,
+                        self.max_attempts
+                    );
+                    sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The delay before retry `retry`, counting from 0.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        if !self.jitter {
+            return delay;
+        }
+        let mut random = [0u8; 4];
+        getrandom::fill(&mut random).expect("getrandom failed");
+        let fraction = f64::from(u32::from_le_bytes(random)) / f64::from(u32::MAX);
+        delay / 2 + (delay / 2).mul_f64(fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A transport failing its first `failures` calls with `error`.
+    struct FlakyTransport {
+        failures: u32,
+        error: &'static str,
+        calls: u32,
+    }
+
+    impl FlakyTransport {
+        fn call(&mut self) -> Result<&'static str, &'static str> {
+            self.calls += 1;
+            if self.calls <= self.failures {
+                Err(self.error)
+            } else {
+                Ok("ok")
+            }
+        }
+    }
+
+    fn is_transient(e: &&str) -> bool {
+        *e == "503"
+    }
+
+    fn run(
+        policy: &RetryPolicy,
+        transport: &mut FlakyTransport,
+    ) -> (Result<&'static str, &'static str>, Vec<Duration>) {
+        let mut delays = Vec::new();
+        let result = policy.run_with(
+            "call",
+            || transport.call(),
+            is_transient,
+            |delay| delays.push(delay),
+        );
+        (result, delays)
+    }
+
+    #[test]
+    fn transient_failures_are_retried_with_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            jitter: false,
+        };
+        let mut transport = FlakyTransport {
+            failures: 4,
+            error: "503",
+            calls: 0,
+        };
+        let (result, delays) = run(&policy, &mut transport);
+        assert_eq!(result, Ok("ok"));
+        assert_eq!(transport.calls, 5);
+        assert_eq!(
+            delays,
+            [100, 200, 300, 300].map(Duration::from_millis).to_vec()
+        );
+
+        // One failure more than the policy allows.
+        let mut transport = FlakyTransport {
+            failures: 5,
+            error: "503",
+            calls: 0,
+        };
+        let (result, delays) = run(&policy, &mut transport);
+        assert_eq!(result, Err("503"));
+        assert_eq!(transport.calls, 5);
+        assert_eq!(delays.len(), 4);
+    }
+
+    #[test]
+    fn other_failures_are_not_retried() {
+        let mut transport = FlakyTransport {
+            failures: 1,
+            error: "403",
+            calls: 0,
+        };
+        let (result, delays) = run(&RetryPolicy::default(), &mut transport);
+        assert_eq!(result, Err("403"));
+        assert_eq!(transport.calls, 1);
+        assert!(delays.is_empty());
+
+        let mut transport = FlakyTransport {
+            failures: 1,
+            error: "503",
+            calls: 0,
+        };
+        let (result, _) = run(&RetryPolicy::none(), &mut transport);
+        assert_eq!(result, Err("503"));
+        assert_eq!(transport.calls, 1);
+    }
+
+    #[test]
+    fn jitter_stays_within_the_upper_half() {
+        let policy = RetryPolicy {
+            max_attempts: 8,
+            ..RetryPolicy::default()
+        };
+        for retry in 0..8 {
+            let full = RetryPolicy {
+                jitter: false,
+                ..policy
+            }
+            .delay(retry);
+            for _ in 0..20 {
+                let delay = policy.delay(retry);
+                assert!(delay >= full / 2 && delay <= full, "{delay:?} of {full:?}");
+            }
+        }
+    }
+}
@@ -1,23 +1,34 @@
 pub mod backup;
+pub mod btree;
 pub mod crypto;
 pub mod policy;
 pub mod io;
 pub mod keyring;
 pub mod kms;
+pub mod migrate;
+pub mod object_store;
+pub mod rekey;
+pub mod sigv4;
+pub mod verify;
 pub mod vfs;
 
-use std::{path::PathBuf, sync::Arc};
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
 
-use keyring::Keyring;
+use crypto::keys::Algorithm;
+use keyring::{Keyring, Revalidation};
 use kms::KmsProvider;
 
-/// Two high-level operational modes.
+/// High-level operational modes.
 pub enum Mode {
     /// Single device - KEK from a local keyfile or passphrase.
     DeviceKey {
         keyfile: Option<PathBuf>,
         passphrase: Option<String>,
     },
+    /// Single device - KEK kept in the OS keychain under `account` of
+    /// `service`, created on first use.
+    #[cfg(feature = "keychain")]
+    Keychain { service: String, account: String },
     /// Multi-tenant SaaS - each tenant has a cloud KMS key.
     TenantKey {
         /// Cloud KMS key identifier (ARN, resource name, key URI, …).
@@ -31,12 +42,68 @@ pub struct EvfsBuilder {
     pub name: String,
     pub page_size: u32,
     pub reserve_size: usize,
-    pub provider: Arc<dyn KmsProvider>,
+    pub allow_mmap: bool,
+    pub conceal_header: bool,
+    pub table_scopes: Vec<String>,
+    pub algorithm: Algorithm,
+    /// `None` if the mode gave no key source.
+    pub provider: Option<Arc<dyn KmsProvider>>,
+    pub kms_revalidation: Option<Revalidation>,
+    pub upgrade_unauthenticated_sidecars: bool,
+    /// `None` for SQLite's default VFS.
+    pub inner_vfs: Option<String>,
 }
 
-impl EvfsBuilder {
-    pub fn new(mode: Mode) -> Self {
-        let provider: Arc<dyn KmsProvider> = match mode {
+/// Why an [`EvfsBuilder`] can't register its VFS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// `DeviceKey` mode with neither a keyfile nor a passphrase.
+    MissingKeySource,
+    /// Not a power of two from 512 to 65536.
+    InvalidPageSize { page_size: u32 },
+    /// Too small for the tag, marker, nonce and key commitment of
+    /// `algorithm`.
+    ReserveTooSmall {
+        reserve: usize,
+        min_required: usize,
+        algorithm: Algorithm,
+    },
+    /// More than fits SQLite's one-byte field, or than leaves the 480
+    /// usable bytes SQLite needs of each page.
+    ReserveTooLarge { reserve: usize, max: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingKeySource => f.write_str("DeviceKey mode requires keyfile or passphrase"),
+            Self::InvalidPageSize { page_size } => write!(
+                f,
+                "page_size ({page_size}) must be a power of two from 512 to 65536"
+            ),
+            Self::ReserveTooSmall {
+                reserve,
+                min_required,
+                algorithm,
+            } => write!(
+                f,
+                "reserve_size ({reserve}) must be >= {min_required} for {algorithm}"
+            ),
+            Self::ReserveTooLarge { reserve, max } => write!(
+                f,
+                "reserve_size ({reserve}) must be at most {max}, to fit in a byte and leave 480 \
+                 bytes of each page"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl Mode {
+    /// The KMS provider giving this mode's KEK.
+    pub fn into_provider(self) -> Result<Arc<dyn KmsProvider>, BuildError> {
+        Ok(match self {
             Mode::DeviceKey {
                 keyfile,
                 passphrase,
@@ -46,52 +113,234 @@ impl EvfsBuilder {
                 } else if let Some(pw) = passphrase {
                     Arc::new(kms::local::DeviceKeyProvider::from_passphrase(&pw))
                 } else {
-                    panic!("DeviceKey mode requires keyfile or passphrase");
+                    return Err(BuildError::MissingKeySource);
                 }
             }
+            #[cfg(feature = "keychain")]
+            Mode::Keychain { service, account } => {
+                Arc::new(kms::keychain::KeychainProvider::new(service, account))
+            }
             Mode::TenantKey { key_id, endpoint } => {
                 Arc::new(kms::cloud::CloudKmsProvider::new(key_id, endpoint))
             }
-        };
+        })
+    }
+}
+
+impl EvfsBuilder {
+    /// A builder for `mode`, with the defaults. A mode without a key
+    /// source fails at [`EvfsBuilder::register`], unless a provider is
+    /// given with [`EvfsBuilder::provider`].
+    pub fn new(mode: Mode) -> Self {
+        let provider = mode.into_provider().ok();
         Self {
             name: "evfs".into(),
             page_size: 4096,
-            reserve_size: 48, // 16 tag + 6 marker + 26 spare
+            reserve_size: 80, // 16 tag + 6 marker + 12 nonce + 32 commitment + 14 spare
+            allow_mmap: false,
+            conceal_header: false,
+            table_scopes: Vec::new(),
+            algorithm: Algorithm::default(),
             provider,
+            kms_revalidation: None,
+            upgrade_unauthenticated_sidecars: false,
+            inner_vfs: None,
         }
     }
 
+    /// Take the KEK from `provider` rather than the mode's key source.
+    pub fn provider(mut self, provider: Arc<dyn KmsProvider>) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
     pub fn page_size(mut self, size: u32) -> Self {
         self.page_size = size;
         self
     }
 
+    /// Bytes reserved at the end of each page of new databases, 80 by
+    /// default. [`EvfsBuilder::register`] refuses one without room
+    /// for the key commitment, such as 48, the default before it;
+    /// existing databases keep the reserve in their header whatever
+    /// this is.
     pub fn reserve_size(mut self, size: usize) -> Self {
         self.reserve_size = size;
         self
     }
 
+    /// Let `SQLITE_FCNTL_MMAP_SIZE` reach the inner VFS for encrypted files.
+    ///
+    /// Off by default. Memory-mapped pages would hand SQLite ciphertext
+    /// without passing through xRead, so the VFS exposes no xFetch and
+    /// pins the mmap limit of encrypted files to 0 (`PRAGMA mmap_size`
+    /// reports 0). Enabling this only lets the inner VFS map the file for
+    /// its own reads; SQLite still reads every page through decryption.
+    pub fn allow_mmap(mut self, allow: bool) -> Self {
+        self.allow_mmap = allow;
+        self
+    }
+
+    /// Encrypt page 1 like every other page, so the file doesn't show
+    /// it is a SQLite database, nor its schema cookie or change
+    /// counters.
+    ///
+    /// Off by default. Applies to databases created from then on: the
+    /// page size and reserve SQLite needs to read page 1 are recorded
+    /// in the sidecar instead, which the database can then not be
+    /// opened without. An existing database keeps the header format it
+    /// was created with.
+    pub fn conceal_header(mut self, conceal: bool) -> Self {
+        self.conceal_header = conceal;
+        self
+    }
+
+    /// Encrypt `table`'s pages, and its indexes', under a DEK of their
+    /// own (`KeyScope::Table`) rather than the database's.
+    ///
+    /// Pages are mapped to tables by walking their b-trees, which is
+    /// redone on every commit that writes to a database with scoped
+    /// tables.
+    ///
+    /// A dropped table's DEK stays in the sidecar; once the drop is
+    /// committed, destroy it with [`Keyring::remove_scope`] on the
+    /// database's keyring, from [`Keyring::for_database`].
+    pub fn table_scope(mut self, table: impl Into<String>) -> Self {
+        self.table_scopes.push(table.into());
+        self
+    }
+
+    /// AEAD algorithm new DEKs encrypt pages with. AES-256-GCM by
+    /// default; XChaCha20-Poly1305 is faster on CPUs without AES
+    /// instructions, and needs a reserve of at least 78 bytes.
+    ///
+    /// Each page records its algorithm, so databases written with
+    /// either are read whatever this is set to. A database's own DEK
+    /// keeps its algorithm until [`Keyring::rotate_data_key`], which
+    /// creates the new one with this.
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Unwrap cached DEKs through the KMS again once they are `every`
+    /// old, so that revoking the KEK takes effect. While the KMS can't
+    /// be reached, cached DEKs are served for `offline_grace` longer,
+    /// with a warning logged each time they are due again; DEKs of new
+    /// scopes can't be created. Without this, a DEK is unwrapped once
+    /// and cached for as long as the VFS is registered.
+    pub fn kms_revalidation(mut self, every: Duration, offline_grace: Duration) -> Self {
+        self.kms_revalidation = Some(Revalidation {
+            every,
+            offline_grace,
+        });
+        self
+    }
+
+    /// Open databases whose sidecars were written before sidecars were
+    /// authenticated, authenticating them as they are opened.
+    ///
+    /// Off by default, when such a database fails to open: its sidecar
+    /// can't be told from one an attacker wrote, which could hand the
+    /// VFS DEKs of their choosing. Turn it on to upgrade databases
+    /// known to be untouched, then off again.
+    pub fn upgrade_unauthenticated_sidecars(mut self, upgrade: bool) -> Self {
+        self.upgrade_unauthenticated_sidecars = upgrade;
+        self
+    }
+
     pub fn vfs_name(mut self, name: impl Into<String>) -> Self {
         self.name = name.into();
         self
     }
 
-    /// Register the VFS with SQLite. Returns the keyring for use with
-    /// the backup API.
+    /// Open files through the VFS registered as `name`, e.g.
+    /// `"unix-excl"` for exclusive locking, rather than SQLite's
+    /// default VFS. It is looked up by [`EvfsBuilder::register`],
+    /// which fails if there is none, and the VFS takes its path length
+    /// limit and device characteristics from it.
+    pub fn inner_vfs(mut self, name: &str) -> Self {
+        self.inner_vfs = Some(name.to_owned());
+        self
+    }
+
+    /// Check the builder has a key source, and a page size and reserve
+    /// SQLite and the algorithm can work with, as
+    /// [`EvfsBuilder::register`] does before registering anything.
+    pub fn check(&self) -> Result<(), BuildError> {
+        if self.provider.is_none() {
+            return Err(BuildError::MissingKeySource);
+        }
+        if !self.page_size.is_power_of_two() || !(512..=65536).contains(&self.page_size) {
+            return Err(BuildError::InvalidPageSize {
+                page_size: self.page_size,
+            });
+        }
+        let min_required = crypto::page::min_reserve(self.algorithm);
+        if self.reserve_size < min_required {
+            return Err(BuildError::ReserveTooSmall {
+                reserve: self.reserve_size,
+                min_required,
+                algorithm: self.algorithm,
+            });
+        }
+        let max = (u8::MAX as usize).min(self.page_size as usize - 480);
+        if self.reserve_size > max {
+            return Err(BuildError::ReserveTooLarge {
+                reserve: self.reserve_size,
+                max,
+            });
+        }
+        Ok(())
+    }
+
+    /// Register the VFS with SQLite. Returns its keyring, for
+    /// [`Keyring::rotate_data_key`], and for [`Keyring::for_database`]
+    /// to hand out the keyring of each database, which the backup API
+    /// takes.
+    ///
+    /// Each name is a separate VFS with its own keyring. Registering a
+    /// name again replaces its keyring and settings for files opened
+    /// from then on, e.g. after the KMS is reconfigured.
+    ///
+    /// Fails with a [`BuildError`] if [`EvfsBuilder::check`] does.
     pub fn register(self) -> anyhow::Result<Arc<Keyring>> {
-        let keyring = Arc::new(Keyring::new(self.provider));
+        self.check()?;
+        let provider = self.provider.ok_or(BuildError::MissingKeySource)?;
+        let mut keyring = Keyring::new(provider)
+            .with_algorithm(self.algorithm)
+            .with_unauthenticated_upgrade(self.upgrade_unauthenticated_sidecars);
+        if let Some(revalidation) = self.kms_revalidation {
+            keyring = keyring.with_revalidation(revalidation);
+        }
+        let keyring = Arc::new(keyring);
         vfs::register_evfs(
             &self.name,
             keyring.clone(),
             self.page_size,
             self.reserve_size,
+            self.allow_mmap,
+            self.conceal_header,
+            self.table_scopes,
+            self.inner_vfs.as_deref(),
         )?;
         Ok(keyring)
     }
 }
 
+/// Unregister a VFS registered with [`EvfsBuilder::register`] and free
+/// it. Fails while any file is open through it.
+pub fn unregister(name: &str) -> anyhow::Result<()> {
+    vfs::unregister_evfs(name)
+}
+
 /// Auto-register a default device-key VFS when loaded via LD_PRELOAD.
-/// Set `EVFS_KEYFILE` or `EVFS_PASSPHRASE` to activate.
+/// Set `EVFS_KEYFILE`, `EVFS_PASSPHRASE`, `EVFS_KEYCHAIN=service:account`
+/// (with the `keychain` feature) or `EVFS_KMS_KEY_ID` to activate, and
+/// `EVFS_UPGRADE_SIDECARS=1` to upgrade unauthenticated sidecars.
+/// `EVFS_PAGE_SIZE`, `EVFS_RESERVE` and `EVFS_VFS_NAME` override the
+/// builder's page size, reserve and name, and `EVFS_INNER_VFS` names
+/// the VFS to wrap; an invalid or unknown one fails registration.
 #[unsafe(no_mangle)]
 pub extern "C" fn sqlite3_evfs_init(
     _db: *mut std::ffi::c_void,
@@ -110,6 +359,14 @@ pub extern "C" fn sqlite3_evfs_init(
             keyfile: None,
             passphrase: Some(pw),
         }
+    } else if let Ok(entry) = std::env::var("EVFS_KEYCHAIN") {
+        match keychain_mode(&entry) {
+            Ok(mode) => mode,
+            Err(e) => {
+                log::error!("sqlite-evfs: {e}");
+                return 1;
+            }
+        }
     } else if let Ok(key_id) = std::env::var("EVFS_KMS_KEY_ID") {
         Mode::TenantKey {
             key_id,
@@ -120,9 +377,22 @@ pub extern "C" fn sqlite3_evfs_init(
         return 1; // SQLITE_ERROR
     };
 
-    match EvfsBuilder::new(mode).register() {
+    let upgrade = std::env::var("EVFS_UPGRADE_SIDECARS").is_ok_and(|v| v == "1");
+    let builder = match geometry_from_env(EvfsBuilder::new(mode)) {
+        Ok(builder) => builder.upgrade_unauthenticated_sidecars(upgrade),
+        Err(e) => {
+            log::error!("sqlite-evfs: {e}, not registering");
+            return 1;
+        }
+    };
+    if let Err(e) = builder.check() {
+        log::error!("sqlite-evfs: {e} ({e:?}), not registering");
+        return 1;
+    }
+    let name = builder.name.clone();
+    match builder.register() {
         Ok(_) => {
-            log::info!("sqlite-evfs: VFS 'evfs' registered");
+            log::info!("sqlite-evfs: VFS '{name}' registered");
             0 // SQLITE_OK
         }
         Err(e) => {
@@ -132,52 +402,60 @@ pub extern "C" fn sqlite3_evfs_init(
     }
 }
 
-#[cfg(test)]
-pub(crate) mod tests {
-    use std::sync::{Arc, Mutex};
-
-    use crate::{crypto::keys::KekId, kms::KmsProvider};
+/// `builder` with the page size, reserve, VFS name and inner VFS given
+/// by `EVFS_PAGE_SIZE`, `EVFS_RESERVE`, `EVFS_VFS_NAME` and
+/// `EVFS_INNER_VFS`, where set.
+/// [`EvfsBuilder::check`] checks the page size and reserve.
+fn geometry_from_env(mut builder: EvfsBuilder) -> anyhow::Result<EvfsBuilder> {
+    fn number<T: std::str::FromStr>(var: &str) -> anyhow::Result<Option<T>> {
+        match std::env::var(var) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| anyhow::anyhow!("{var} must be a number, not {value:?}")),
+            Err(_) => Ok(None),
+        }
+    }
 
-    // Mock KmsProvider for testing
-    pub struct MockKmsProvider {
-        pub wrap_count: Mutex<usize>,
-        pub unwrap_count: Mutex<usize>,
+    if let Some(page_size) = number("EVFS_PAGE_SIZE")? {
+        builder = builder.page_size(page_size);
+    }
+    if let Some(reserve) = number("EVFS_RESERVE")? {
+        builder = builder.reserve_size(reserve);
     }
+    if let Ok(name) = std::env::var("EVFS_VFS_NAME") {
+        anyhow::ensure!(
+            !name.is_empty() && !name.contains('\0'),
+            "EVFS_VFS_NAME must be a non-empty name, not {name:?}"
+        );
+        builder = builder.vfs_name(name);
+    }
+    if let Ok(inner) = std::env::var("EVFS_INNER_VFS") {
+        anyhow::ensure!(
+            !inner.is_empty() && !inner.contains('\0'),
+            "EVFS_INNER_VFS must be a non-empty name, not {inner:?}"
+        );
+        builder = builder.inner_vfs(&inner);
+    }
+    Ok(builder)
+}
 
-    impl MockKmsProvider {
-        pub fn new() -> Arc<Self> {
-            Arc::new(Self {
-                wrap_count: Mutex::new(0),
-                unwrap_count: Mutex::new(0),
+/// The mode `EVFS_KEYCHAIN=<service>:<account>` selects.
+#[cfg(feature = "keychain")]
+fn keychain_mode(entry: &str) -> anyhow::Result<Mode> {
+    match entry.split_once(':') {
+        Some((service, account)) if !service.is_empty() && !account.is_empty() => {
+            Ok(Mode::Keychain {
+                service: service.into(),
+                account: account.into(),
             })
         }
+        _ => anyhow::bail!("EVFS_KEYCHAIN must be <service>:<account>, not {entry:?}"),
     }
+}
 
-    impl KmsProvider for MockKmsProvider {
-        fn get_kek(&self) -> anyhow::Result<(KekId, Vec<u8>)> {
-            let kek_id = KekId("test".to_string());
-            Ok((kek_id, vec![0xAA; 32])) // Dummy KEK
-        }
-
-        fn get_kek_by_id(&self, _id: &KekId) -> anyhow::Result<Vec<u8>> {
-            Ok(vec![0xBB; 32]) // Dummy KEK
-        }
-
-        fn wrap_blob(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
-            *self.wrap_count.lock().unwrap() += 1;
-            // Simple mock: prepend marker byte
-            let mut result = vec![0xFF];
-            result.extend_from_slice(plaintext);
-            Ok(result)
-        }
-
-        fn unwrap_blob(&self, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
-            *self.unwrap_count.lock().unwrap() += 1;
-            // Simple mock: strip marker byte
-            if ciphertext.is_empty() || ciphertext[0] != 0xFF {
-                anyhow::bail!("invalid mock ciphertext")
-            }
-            Ok(ciphertext[1..].to_vec())
-        }
-    }
+#[cfg(not(feature = "keychain"))]
+fn keychain_mode(_entry: &str) -> anyhow::Result<Mode> {
+    anyhow::bail!("EVFS_KEYCHAIN is set, but sqlevfs was built without the keychain feature")
 }
@@ -0,0 +1,617 @@
+//! Conversion of whole databases between plaintext and encrypted.
+//!
+//! [`encrypt_database`] turns an existing plaintext SQLite database
+//! into one the VFS can open, and [`decrypt_database`] exports an
+//! encrypted database as a plaintext copy any SQLite can read, e.g. in
+//! an emergency. Both write the converted database next to its final
+//! path and rename it into place, so a crash leaves either the old file
+//! or the new one.
+
+use std::{
+    ffi::{CStr, CString, c_void},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use libsqlite3_sys::*;
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{
+        envelope,
+        keys::{DatabaseId, Dek, KeyScope},
+        page as page_crypto,
+    },
+    keyring::{self, Keyring},
+    rekey::{self, DbLock},
+};
+
+/// Outcome of a completed migration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// Pages in the database.
+    pub page_count: u32,
+    /// Reserved bytes per page in the written database.
+    pub reserve: usize,
+    /// Whether the database had to be rebuilt with `VACUUM INTO` to
+    /// make room for the reserve. Never set by [`decrypt_database`].
+    pub rebuilt: bool,
+}
+
+/// Encrypt the plaintext database at `path` in place under a freshly
+/// generated Database-scope DEK, recorded in its keyring sidecar.
+///
+/// `reserve` is the number of bytes per page to reserve for the tag,
+/// marker, nonce and key commitment, as for
+/// [`crate::EvfsBuilder::reserve_size`]. A database that already
+/// reserves at least that much is encrypted page by page; otherwise
+/// it is first rebuilt with `VACUUM INTO`, which needs as much free
+/// disk space again as the database.
+///
+/// Fails if the database doesn't pass `PRAGMA quick_check`, is already
+/// encrypted, is in WAL mode, or is being written by another
+/// connection. The file is replaced by rename, so close every other
+/// connection to it first: one left open keeps reading the plaintext.
+pub fn encrypt_database(
+    path: &Path,
+    keyring: &Keyring,
+    reserve: usize,
+) -> anyhow::Result<MigrateReport> {
+    let min_reserve = page_crypto::min_reserve(keyring.algorithm());
+    anyhow::ensure!(
+        (min_reserve..=u8::MAX as usize).contains(&reserve),
+        "reserve must be between {min_reserve} and 255 bytes, not {reserve}"
+    );
+
+    // Readers may carry on, which lets VACUUM INTO below read the
+    // database, but writers are kept out until the rename.
+    let lock = DbLock::acquire(path, c"BEGIN IMMEDIATE")?;
+    let file = lock.file()?;
+    let (page_size, current_reserve, concealed) = read_header(file, path)?;
+    anyhow::ensure!(!concealed, "{} is already encrypted", path.display());
+    quick_check(lock.handle())
+        .map_err(|e| anyhow::anyhow!("{} is not a plaintext database: {e}", path.display()))?;
+
+    let tmp = migrate_path(path);
+    let rebuilt = current_reserve < reserve;
+    let reserve = if rebuilt { reserve } else { current_reserve };
+    let dek = Dek::generate_for(keyring.algorithm());
+    let database_id = DatabaseId::generate();
+    let wrapped = envelope::wrap_dek(&dek, keyring.provider())?;
+
+    let written = (|| {
+        if rebuilt {
+            vacuum_into(path, &tmp, reserve)?;
+            let mut src = std::fs::File::open(&tmp)?;
+            let mut out = std::fs::OpenOptions::new().write(true).open(&tmp)?;
+            let file_size = src.metadata()?.len();
+            convert_pages(
+                page_size,
+                file_size,
+                &mut out,
+                |buf, offset| {
+                    src.seek(SeekFrom::Start(offset))?;
+                    Ok(src.read_exact(buf)?)
+                },
+                |page, page_no| encrypt_page(page, page_no, Some(database_id), &dek, reserve),
+            )
+        } else {
+            let mut out = std::fs::File::create(&tmp)?;
+            convert_pages(
+                page_size,
+                file_size(file)?,
+                &mut out,
+                |buf, offset| rekey::read_at(file, buf, offset as i64),
+                |page, page_no| encrypt_page(page, page_no, Some(database_id), &dek, reserve),
+            )
+        }
+    })();
+    let page_count = written.inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })?;
+    std::fs::set_permissions(&tmp, std::fs::metadata(path)?.permissions())?;
+
+    // The ID and DEK are durable before any encrypted page can be seen
+    // at `path`; a crash in between leaves the plaintext database.
+    keyring.install_database_id(path, database_id)?;
+    keyring.install_dek(path, &KeyScope::Database, dek, wrapped)?;
+    replace(&tmp, path)?;
+    drop(lock);
+
+    log::info!(
+        "encrypted {}: {page_count} pages, reserve={reserve}{}",
+        path.display(),
+        if rebuilt { " (rebuilt)" } else { "" }
+    );
+    Ok(MigrateReport {
+        page_count,
+        reserve,
+        rebuilt,
+    })
+}
+
+/// Write a plaintext copy of the encrypted database at `src` to `dest`,
+/// decrypting its pages with every DEK in `src`'s keyring sidecar,
+/// unwrapped through `keyring`'s KMS provider.
+///
+/// The copy keeps `src`'s page size and reserve, which stock SQLite
+/// reads as is, and has a plaintext header even if `src` conceals its
+/// own. `src` is left untouched, and writers are kept out of it
+/// while it is copied. Fails if `src` is in WAL mode; checkpoint it and
+/// set journal_mode=DELETE first.
+pub fn decrypt_database(
+    src: &Path,
+    dest: &Path,
+    keyring: &Keyring,
+) -> anyhow::Result<MigrateReport> {
+    anyhow::ensure!(
+        !dest.exists() || std::fs::canonicalize(src)? != std::fs::canonicalize(dest)?,
+        "cannot decrypt {} onto itself",
+        src.display()
+    );
+
+    let deks = keyring::sidecar_deks(src, keyring.provider())?;
+    let database_id = keyring::database_id_of(src);
+
+    let lock = DbLock::acquire_any(src, c"BEGIN IMMEDIATE", SQLITE_LOCK_RESERVED)?;
+    let file = lock.file()?;
+    let (page_size, reserve, _) = read_header(file, src)?;
+
+    let tmp = migrate_path(dest);
+    let page_count = std::fs::File::create(&tmp)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut out| {
+            convert_pages(
+                page_size,
+                file_size(file)?,
+                &mut out,
+                |buf, offset| rekey::read_at(file, buf, offset as i64),
+                |page, page_no| decrypt_page(page, page_no, database_id, &deks, reserve),
+            )
+        })
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })?;
+    replace(&tmp, dest)?;
+
+    log::info!(
+        "decrypted {} to {}: {page_count} pages",
+        src.display(),
+        dest.display()
+    );
+    Ok(MigrateReport {
+        page_count,
+        reserve,
+        rebuilt: false,
+    })
+}
+
+/// Path the converted database is written to before being renamed over
+/// `path`.
+fn migrate_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".evfs-migrate");
+    PathBuf::from(tmp)
+}
+
+/// Page size and reserve of the database behind `file`, and whether
+/// it conceals its header. A plaintext header must not be in WAL mode;
+/// a concealed one is checked once page 1 is decrypted.
+fn read_header(file: *mut sqlite3_file, path: &Path) -> anyhow::Result<(u32, usize, bool)> {
+    let mut header = [0u8; 100];
+    rekey::read_at(file, &mut header, 0)?;
+    let geometry = crate::vfs::stored_db_geometry(&header, path)?
+        .ok_or_else(|| anyhow::anyhow!("{} is not a SQLite database", path.display()))?;
+    if !geometry.2 {
+        ensure_not_wal(&header)?;
+    }
+    Ok(geometry)
+}
+
+fn ensure_not_wal(header: &[u8]) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        header[18] != 2 && header[19] != 2,
+        "cannot migrate a database in WAL mode; checkpoint it and set journal_mode=DELETE first"
+    );
+    Ok(())
+}
+
+fn file_size(file: *mut sqlite3_file) -> anyhow::Result<u64> {
+    let mut size: i64 = 0;
+    let rc = unsafe { ((*(*file).pMethods).xFileSize.unwrap())(file, &mut size) };
+    anyhow::ensure!(rc == SQLITE_OK, "read database size: {rc}");
+    Ok(size as u64)
+}
+
+/// Copy every page from `read` to `out`, passing each through
+/// `convert`, and sync `out`. Returns the number of pages.
+fn convert_pages(
+    page_size: u32,
+    file_size: u64,
+    out: &mut std::fs::File,
+    mut read: impl FnMut(&mut [u8], u64) -> anyhow::Result<()>,
+    mut convert: impl FnMut(&mut [u8], u32) -> anyhow::Result<()>,
+) -> anyhow::Result<u32> {
+    let page_count = (file_size / page_size as u64) as u32;
+    let mut page = Zeroizing::new(vec![0u8; page_size as usize]);
+    out.seek(SeekFrom::Start(0))?;
+    for page_no in 1..=page_count {
+        read(&mut page, (page_no as u64 - 1) * page_size as u64)?;
+        convert(&mut page, page_no)?;
+        out.write_all(&page)?;
+    }
+    out.set_len(page_count as u64 * page_size as u64)?;
+    out.sync_all()?;
+    Ok(page_count)
+}
+
+fn encrypt_page(
+    page: &mut [u8],
+    page_no: u32,
+    database_id: Option<DatabaseId>,
+    dek: &Dek,
+    reserve: usize,
+) -> anyhow::Result<()> {
+    // Page 1 stays plaintext, as the VFS writes it by default.
+    if page_no == 1 {
+        return Ok(());
+    }
+    anyhow::ensure!(
+        !page_crypto::is_encrypted_page(page, reserve),
+        "page {page_no} is already encrypted"
+    );
+    page_crypto::encrypt_page(page, page_no, database_id, dek, reserve)
+}
+
+fn decrypt_page(
+    page: &mut [u8],
+    page_no: u32,
+    database_id: Option<DatabaseId>,
+    deks: &[Dek],
+    reserve: usize,
+) -> anyhow::Result<()> {
+    if !page_crypto::is_encrypted_page(page, reserve) {
+        return Ok(());
+    }
+    let decrypts = |dek: &&Dek| {
+        let mut plain = Zeroizing::new(page.to_vec());
+        page_crypto::decrypt_page(&mut plain, page_no, database_id, dek, reserve).is_ok()
+    };
+    let dek = deks
+        .iter()
+        .find(decrypts)
+        .ok_or_else(|| anyhow::anyhow!("page {page_no} does not decrypt under any DEK"))?;
+    page_crypto::decrypt_page(page, page_no, database_id, dek, reserve)?;
+    // Clear the marker and nonce too, so nothing reads the page as
+    // encrypted.
+    let payload_len = page.len() - reserve;
+    page[payload_len..].fill(0);
+    // The database concealed its header, which is only now readable.
+    if page_no == 1 {
+        ensure_not_wal(page)?;
+    }
+    Ok(())
+}
+
+/// Rename `tmp` over `path` and make the rename durable.
+pub(crate) fn replace(tmp: &Path, path: &Path) -> anyhow::Result<()> {
+    std::fs::rename(tmp, path)?;
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty())
+        && let Ok(dir) = std::fs::File::open(dir)
+    {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+// ── SQLite helpers ──────────────────────────────────────────────────
+
+fn errmsg(db: *mut sqlite3) -> String {
+    unsafe { CStr::from_ptr(sqlite3_errmsg(db)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Run `PRAGMA quick_check` on `db`, failing unless it reports `ok`.
+fn quick_check(db: *mut sqlite3) -> anyhow::Result<()> {
+    let mut stmt = ptr::null_mut();
+    let rc = unsafe {
+        sqlite3_prepare_v2(
+            db,
+            c"PRAGMA quick_check(1)".as_ptr(),
+            -1,
+            &mut stmt,
+            ptr::null_mut(),
+        )
+    };
+    anyhow::ensure!(rc == SQLITE_OK, "{}", errmsg(db));
+    let result = unsafe {
+        let rc = sqlite3_step(stmt);
+        let text = sqlite3_column_text(stmt, 0);
+        let result = if rc != SQLITE_ROW {
+            Err(errmsg(db))
+        } else if text.is_null() {
+            Err("quick_check returned nothing".to_string())
+        } else {
+            Ok(CStr::from_ptr(text as *const _)
+                .to_string_lossy()
+                .into_owned())
+        };
+        sqlite3_finalize(stmt);
+        result
+    };
+    match result {
+        Ok(result) if result == "ok" => Ok(()),
+        Ok(result) => anyhow::bail!("quick_check: {result}"),
+        Err(e) => anyhow::bail!("{e}"),
+    }
+}
+
+/// Rebuild the database at `src` into `dest` with `reserve` bytes
+/// reserved per page, through a separate read-only connection.
+fn vacuum_into(src: &Path, dest: &Path, reserve: usize) -> anyhow::Result<()> {
+    let to_c = |path: &Path| {
+        CString::new(
+            path.to_str()
+                .ok_or_else(|| anyhow::anyhow!("non UTF-8 path {}", path.display()))?,
+        )
+        .map_err(anyhow::Error::from)
+    };
+    let (c_src, c_dest) = (to_c(src)?, to_c(dest)?);
+    let _ = std::fs::remove_file(dest);
+
+    let mut db = ptr::null_mut();
+    let rc = unsafe { sqlite3_open_v2(c_src.as_ptr(), &mut db, SQLITE_OPEN_READONLY, ptr::null()) };
+    let result = (|| {
+        anyhow::ensure!(rc == SQLITE_OK, "open {}: {rc}", src.display());
+        // Set from the argument, which is then overwritten with the
+        // previous value.
+        let mut arg = reserve as i32;
+        let rc = unsafe {
+            sqlite3_file_control(
+                db,
+                c"main".as_ptr(),
+                SQLITE_FCNTL_RESERVE_BYTES,
+                &mut arg as *mut i32 as *mut c_void,
+            )
+        };
+        anyhow::ensure!(rc == SQLITE_OK, "set reserve: {}", errmsg(db));
+
+        let mut stmt = ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_prepare_v2(
+                db,
+                c"VACUUM INTO ?1".as_ptr(),
+                -1,
+                &mut stmt,
+                ptr::null_mut(),
+            )
+        };
+        anyhow::ensure!(rc == SQLITE_OK, "{}", errmsg(db));
+        let rc = unsafe {
+            sqlite3_bind_text(stmt, 1, c_dest.as_ptr(), -1, SQLITE_TRANSIENT());
+            let rc = sqlite3_step(stmt);
+            sqlite3_finalize(stmt);
+            rc
+        };
+        anyhow::ensure!(
+            rc == SQLITE_DONE,
+            "VACUUM INTO {}: {}",
+            dest.display(),
+            errmsg(db)
+        );
+
+        let mut header = [0u8; 100];
+        std::fs::File::open(dest)?.read_exact(&mut header)?;
+        anyhow::ensure!(
+            crate::vfs::parse_db_header(&header).is_some_and(|(_, r)| r == reserve),
+            "VACUUM INTO {} did not reserve {reserve} bytes per page",
+            dest.display()
+        );
+        Ok(())
+    })();
+    unsafe { sqlite3_close(db) };
+    if result.is_err() {
+        let _ = std::fs::remove_file(dest);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{Connection, OpenFlags};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{EvfsBuilder, Mode};
+
+    /// A plaintext database with a few tables, indexes, a WITHOUT ROWID
+    /// table and rows spilling onto overflow pages, created with
+    /// `reserve` bytes reserved per page.
+    fn plain_db(dir: &TempDir, reserve: i32) -> PathBuf {
+        let path = dir.path().join("plain.db");
+        let conn = Connection::open(&path).unwrap();
+        if reserve > 0 {
+            let mut reserve = reserve;
+            let rc = unsafe {
+                sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    SQLITE_FCNTL_RESERVE_BYTES,
+                    &mut reserve as *mut i32 as *mut c_void,
+                )
+            };
+            assert_eq!(rc, SQLITE_OK);
+        }
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT UNIQUE, name TEXT);
+             CREATE TABLE posts (id INTEGER PRIMARY KEY, user_id INTEGER REFERENCES users(id),
+                                 title TEXT, body TEXT);
+             CREATE INDEX posts_by_user ON posts (user_id, title);
+             CREATE TABLE tags (post_id INTEGER, tag TEXT, PRIMARY KEY (post_id, tag)) WITHOUT ROWID;
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+             INSERT INTO users SELECT i, 'user' || i || '@example.com', 'User ' || i FROM n;
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 300)
+             INSERT INTO posts SELECT i, i % 500 + 1, 'Post ' || i, hex(randomblob(3000)) FROM n;
+             INSERT INTO tags SELECT id, 'tag' || (id % 7) FROM posts;
+             INSERT INTO tags SELECT id, 'extra' FROM posts WHERE id % 3 = 0;",
+        )
+        .unwrap();
+        path
+    }
+
+    fn keyring(dir: &TempDir, vfs: &str) -> std::sync::Arc<Keyring> {
+        let keyfile = dir.path().join("migrate.key");
+        std::fs::write(&keyfile, [0x3C; 32]).unwrap();
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile),
+            passphrase: None,
+        })
+        .vfs_name(vfs)
+        .register()
+        .unwrap()
+    }
+
+    /// Every row of the fixture's tables, plus the integrity check.
+    fn contents(conn: &Connection) -> Vec<String> {
+        let mut rows = vec![
+            conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))
+                .unwrap(),
+        ];
+        for sql in [
+            "SELECT id || email || name FROM users ORDER BY id",
+            "SELECT id || user_id || title || body FROM posts ORDER BY id",
+            "SELECT post_id || tag FROM tags",
+            "SELECT title FROM posts INDEXED BY posts_by_user WHERE user_id > 250",
+        ] {
+            let mut stmt = conn.prepare(sql).unwrap();
+            rows.extend(
+                stmt.query_map([], |r| r.get::<_, String>(0))
+                    .unwrap()
+                    .map(Result::unwrap),
+            );
+        }
+        rows
+    }
+
+    fn open_evfs(path: &Path, vfs: &str) -> Connection {
+        Connection::open_with_flags_and_vfs(path, OpenFlags::SQLITE_OPEN_READ_WRITE, vfs).unwrap()
+    }
+
+    fn assert_encrypted(path: &Path, page_size: usize, reserve: usize) {
+        let data = std::fs::read(path).unwrap();
+        assert_eq!(data[20] as usize, reserve);
+        for page in data.chunks(page_size).skip(1) {
+            assert!(page_crypto::is_encrypted_page(page, reserve));
+        }
+    }
+
+    #[test]
+    fn test_encrypt_rebuilds_database_without_reserve() {
+        let dir = TempDir::new().unwrap();
+        let path = plain_db(&dir, 0);
+        let expected = contents(&Connection::open(&path).unwrap());
+        let keyring = keyring(&dir, "evfs-migrate-rebuild");
+
+        let report = encrypt_database(&path, &keyring, 80).unwrap();
+        assert!(report.rebuilt);
+        assert_eq!(report.reserve, 80);
+        assert_encrypted(&path, 4096, 80);
+        assert!(!migrate_path(&path).exists());
+
+        assert_eq!(
+            contents(&open_evfs(&path, "evfs-migrate-rebuild")),
+            expected
+        );
+        let plain = Connection::open(&path).unwrap();
+        assert!(
+            plain
+                .query_row("SELECT count(*) FROM posts", [], |r| r.get::<_, i64>(0))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_encrypt_page_by_page_with_existing_reserve() {
+        let dir = TempDir::new().unwrap();
+        let path = plain_db(&dir, 96);
+        let expected = contents(&Connection::open(&path).unwrap());
+        let size = std::fs::metadata(&path).unwrap().len();
+        let keyring = keyring(&dir, "evfs-migrate-inplace");
+
+        let report = encrypt_database(&path, &keyring, 80).unwrap();
+        assert!(!report.rebuilt);
+        assert_eq!(report.reserve, 96);
+        assert_eq!(report.page_count as u64 * 4096, size);
+        assert_encrypted(&path, 4096, 96);
+
+        let conn = open_evfs(&path, "evfs-migrate-inplace");
+        assert_eq!(contents(&conn), expected);
+        // Still writable through the VFS.
+        conn.execute(
+            "INSERT INTO users (email, name) VALUES ('new@example.com', 'New')",
+            [],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_decrypt_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = plain_db(&dir, 0);
+        let expected = contents(&Connection::open(&path).unwrap());
+        let keyring = keyring(&dir, "evfs-migrate-decrypt");
+        encrypt_database(&path, &keyring, 80).unwrap();
+        {
+            // Pages written through the VFS after the migration decrypt
+            // too.
+            let conn = open_evfs(&path, "evfs-migrate-decrypt");
+            conn.execute("DELETE FROM tags WHERE tag = 'extra'", [])
+                .unwrap();
+        }
+        let expected: Vec<_> = expected
+            .into_iter()
+            .filter(|r| !r.ends_with("extra"))
+            .collect();
+
+        let dest = dir.path().join("export.db");
+        let report = decrypt_database(&path, &dest, &keyring).unwrap();
+        assert_eq!(report.reserve, 80);
+        let data = std::fs::read(&dest).unwrap();
+        assert!(
+            data.chunks(4096)
+                .all(|page| !page_crypto::is_encrypted_page(page, 80))
+        );
+        assert_eq!(contents(&Connection::open(&dest).unwrap()), expected);
+    }
+
+    #[test]
+    fn test_refuses_unsuitable_databases() {
+        let dir = TempDir::new().unwrap();
+        let path = plain_db(&dir, 0);
+        let keyring = keyring(&dir, "evfs-migrate-refuse");
+
+        assert!(encrypt_database(&path, &keyring, 16).is_err());
+        // Too small for the key commitment.
+        assert!(encrypt_database(&path, &keyring, 48).is_err());
+        assert!(decrypt_database(&path, &path, &keyring).is_err());
+
+        encrypt_database(&path, &keyring, 80).unwrap();
+        let before = std::fs::read(&path).unwrap();
+        assert!(encrypt_database(&path, &keyring, 80).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+
+        let wal = dir.path().join("wal.db");
+        Connection::open(&wal)
+            .unwrap()
+            .execute_batch("PRAGMA journal_mode=WAL; CREATE TABLE t (x);")
+            .unwrap();
+        let err = encrypt_database(&wal, &keyring, 80).unwrap_err();
+        assert!(err.to_string().contains("WAL"), "{err}");
+
+        let junk = dir.path().join("junk.db");
+        std::fs::write(&junk, vec![0x42; 8192]).unwrap();
+        assert!(encrypt_database(&junk, &keyring, 80).is_err());
+    }
+}
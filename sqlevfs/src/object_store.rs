@@ -0,0 +1,673 @@
+//! Streaming backups to S3-compatible object storage.
+//!
+//! [`ObjectStoreWriter`] is a `std::io::Write` that uploads what is
+//! written to it as an S3 multipart upload, a part at a time, so a
+//! backup can be written straight to a bucket without a local copy:
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! # use sqlevfs::{backup, keyring::Keyring, kms::KmsProvider};
+//! # use sqlevfs::object_store::{ObjectStoreConfig, ObjectStoreWriter};
+//! # fn run(keyring: &Keyring, kms: &dyn KmsProvider) -> anyhow::Result<()> {
+//! let config = ObjectStoreConfig::from_env("http://localhost:9000", "backups")?;
+//! let mut out = ObjectStoreWriter::new(config, "my.evfs-backup");
+//! backup::create_backup(Path::new("my.db"), &mut out, keyring, kms, 4096, 80)?;
+//! out.finish()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests are signed with SigV4 and addressed path-style, as
+//! `<endpoint>/<bucket>/<prefix><key>`, which AWS S3 and MinIO both
+//! serve.
+
+use std::{
+    io::{self, Write},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::sigv4::{self, Credentials};
+
+/// Smallest part S3 takes, but for the last.
+pub const MIN_PART_SIZE: usize = 5 << 20;
+/// Most parts S3 takes per upload.
+const MAX_PARTS: usize = 10_000;
+/// Wait before the first retry, doubled for each one after.
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Where and how [`ObjectStoreWriter`] uploads.
+#[derive(Clone, Debug)]
+pub struct ObjectStoreConfig {
+    /// Base URL of the service, e.g. `https://s3.eu-west-1.amazonaws.com`
+    /// or `http://localhost:9000`.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Put before the key of every object, e.g. `backups/`.
+    pub prefix: String,
+    pub credentials: Credentials,
+    /// Bytes buffered per part, 8 MiB by default. Parts but the last
+    /// must be at least [`MIN_PART_SIZE`], and an object has at most
+    /// 10,000.
+    pub part_size: usize,
+    /// Times a request is retried after a transport error, a 429 or a
+    /// 5xx, 3 by default.
+    pub max_retries: u32,
+    /// Timeout of each request, 60 seconds by default.
+    pub timeout: Duration,
+}
+
+impl ObjectStoreConfig {
+    /// Upload to `bucket` at `endpoint`, with credentials from
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+    /// `AWS_SESSION_TOKEN`, in the region `AWS_REGION` or
+    /// `AWS_DEFAULT_REGION` names, or `us-east-1`.
+    pub fn from_env(
+        endpoint: impl Into<String>,
+        bucket: impl Into<String>,
+    ) -> anyhow::Result<Self> {
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".into());
+        Ok(Self {
+            endpoint: endpoint.into().trim_end_matches('/').to_string(),
+            region,
+            bucket: bucket.into(),
+            prefix: String::new(),
+            credentials: Credentials::from_env()?,
+            part_size: 8 << 20,
+            max_retries: 3,
+            timeout: Duration::from_secs(60),
+        })
+    }
+
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = region.into();
+        self
+    }
+
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub fn part_size(mut self, bytes: usize) -> Self {
+        self.part_size = bytes;
+        self
+    }
+
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Uploads what is written to it to one object.
+///
+/// Each `part_size` bytes written are uploaded as a part of a
+/// multipart upload, started with the first, and [`finish`] uploads
+/// the rest and completes the upload; an object smaller than a part
+/// is put whole. `flush` uploads nothing, since a part can't be cut
+/// short.
+///
+/// If a request fails for good, the upload is aborted, deleting the
+/// parts uploaded so far, and writes fail from then on. Dropping a
+/// writer that wasn't finished aborts its upload too, so a backup that
+/// fails partway leaves no object behind.
+///
+/// [`finish`]: ObjectStoreWriter::finish
+pub struct ObjectStoreWriter {
+    config: ObjectStoreConfig,
+    agent: ureq::Agent,
+    /// Path of the object, URI encoded.
+    path: String,
+    buffer: Vec<u8>,
+    upload_id: Option<String>,
+    /// ETag of each part uploaded, part 1 first.
+    etags: Vec<String>,
+    failed: bool,
+    finished: bool,
+}
+
+impl ObjectStoreWriter {
+    /// Upload to the object `key`, under the config's prefix.
+    pub fn new(config: ObjectStoreConfig, key: &str) -> Self {
+        let path = format!(
+            "/{}/{}",
+            sigv4::uri_encode(&config.bucket, true),
+            sigv4::uri_encode(&format!("{}{key}", config.prefix), false)
+        );
+        let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+        Self {
+            config,
+            agent,
+            path,
+            buffer: Vec::new(),
+            upload_id: None,
+            etags: Vec::new(),
+            failed: false,
+            finished: false,
+        }
+    }
+
+    /// Upload what is buffered and complete the upload.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.failed, "upload to {} already failed", self.path);
+        let result = self.complete();
+        match &result {
+            Ok(()) => self.finished = true,
+            Err(_) => self.fail(),
+        }
+        result
+    }
+
+    /// Cancel the upload, deleting the parts uploaded so far.
+    pub fn abort(mut self) -> anyhow::Result<()> {
+        self.abort_upload()
+    }
+
+    fn complete(&mut self) -> anyhow::Result<()> {
+        let Some(upload_id) = self.upload_id.clone() else {
+            self.send("PUT", &[], &self.buffer)?;
+            log::info!("uploaded {} ({} bytes)", self.path, self.buffer.len());
+            return Ok(());
+        };
+        if !self.buffer.is_empty() {
+            self.upload_part()?;
+        }
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in self.etags.iter().enumerate() {
+            body += &format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                xml_escape(etag)
+            );
+        }
+        body += "</CompleteMultipartUpload>";
+        let response = self.send("POST", &[("uploadId", &upload_id)], body.as_bytes())?;
+        // S3 can report a failed completion with a 200.
+        let text = response.into_string()?;
+        anyhow::ensure!(
+            !text.contains("<Error>"),
+            "completing the upload of {}: {text}",
+            self.path
+        );
+        self.upload_id = None;
+        log::info!("uploaded {} in {} parts", self.path, self.etags.len());
+        Ok(())
+    }
+
+    fn upload_part(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.etags.len() < MAX_PARTS,
+            "{} needs more than {MAX_PARTS} parts; raise part_size",
+            self.path
+        );
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let response = self.send("POST", &[("uploads", "")], &[])?;
+                let text = response.into_string()?;
+                let upload_id = xml_element(&text, "UploadId").ok_or_else(|| {
+                    anyhow::anyhow!("no UploadId starting the upload of {}: {text}", self.path)
+                })?;
+                self.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+        let part_number = (self.etags.len() + 1).to_string();
+        let response = self.send(
+            "PUT",
+            &[("partNumber", &part_number), ("uploadId", &upload_id)],
+            &self.buffer,
+        )?;
+        let etag = response
+            .header("ETag")
+            .ok_or_else(|| anyhow::anyhow!("no ETag for part {part_number} of {}", self.path))?;
+        self.etags.push(etag.to_string());
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Give up on the upload after a request failed for good.
+    fn fail(&mut self) {
+        self.failed = true;
+        if let Err(e) = self.abort_upload() {
+            log::warn!("cannot abort the upload of {}: {e:#}", self.path);
+        }
+    }
+
+    fn abort_upload(&mut self) -> anyhow::Result<()> {
+        if let Some(upload_id) = self.upload_id.take() {
+            self.send("DELETE", &[("uploadId", &upload_id)], &[])?;
+            log::info!("aborted the upload of {}", self.path);
+        }
+        Ok(())
+    }
+
+    /// Send a signed request for the object, retrying transient
+    /// failures.
+    fn send(
+        &self,
+        method: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> anyhow::Result<ureq::Response> {
+        let payload_sha256 = sigv4::sha256_hex(body);
+        let mut attempt = 0;
+        loop {
+            match self
+                .send_once(method, query, body, &payload_sha256)
+                .map_err(|e| *e)
+            {
+                Ok(response) => return Ok(response),
+                Err(e) if attempt < self.config.max_retries && is_transient(&e) => {
+                    let delay = RETRY_DELAY * 2u32.pow(attempt);
+                    log::warn!("{method} {}: {e}; retrying in {delay:?}", self.path);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    let text = response.into_string().unwrap_or_default();
+                    anyhow::bail!("{method} {}: HTTP {status}: {text}", self.path);
+                }
+                Err(e) => return Err(anyhow::anyhow!("{method} {}: {e}", self.path)),
+            }
+        }
+    }
+
+    fn send_once(
+        &self,
+        method: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+        payload_sha256: &str,
+    ) -> Result<ureq::Response, Box<ureq::Error>> {
+        let host = self
+            .config
+            .endpoint
+            .split_once("://")
+            .map_or(self.config.endpoint.as_str(), |(_, host)| host);
+        let mut request = sigv4::Request {
+            method,
+            path: &self.path,
+            query,
+            headers: vec![
+                ("host".into(), host.into()),
+                ("x-amz-content-sha256".into(), payload_sha256.into()),
+            ],
+            payload_sha256,
+        };
+        let authorization = sigv4::sign(
+            &mut request,
+            &self.config.credentials,
+            &self.config.region,
+            "s3",
+            SystemTime::now(),
+        );
+
+        let mut url = format!("{}{}", self.config.endpoint, self.path);
+        if !query.is_empty() {
+            url = format!("{url}?{}", sigv4::canonical_query(query));
+        }
+        let mut http = self.agent.request(method, &url);
+        for (name, value) in &request.headers {
+            http = http.set(name, value);
+        }
+        http.set("authorization", &authorization)
+            .send_bytes(body)
+            .map_err(Box::new)
+    }
+}
+
+impl Write for ObjectStoreWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::other(format!(
+                "upload to {} already failed",
+                self.path
+            )));
+        }
+        let n = buf.len().min(self.config.part_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.config.part_size
+            && let Err(e) = self.upload_part()
+        {
+            self.fail();
+            return Err(io::Error::other(e));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ObjectStoreWriter {
+    fn drop(&mut self) {
+        if !self.finished
+            && let Err(e) = self.abort_upload()
+        {
+            log::warn!("cannot abort the upload of {}: {e:#}", self.path);
+        }
+    }
+}
+
+fn is_transient(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(status, _) => *status == 429 || *status >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// Text of the first `<name>` element in `xml`.
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{name}>"))?;
+    Some(xml[start..start + len].to_string())
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use zeroize::Zeroizing;
+
+    use super::*;
+
+    /// What [`FakeS3`] was asked and holds.
+    #[derive(Default)]
+    struct FakeState {
+        /// Method, path and query of each request, in order.
+        requests: Vec<String>,
+        /// Indices of the requests answered with a 503.
+        fail: Vec<usize>,
+        parts: BTreeMap<u32, Vec<u8>>,
+        objects: BTreeMap<String, Vec<u8>>,
+        aborted: bool,
+    }
+
+    /// An S3 endpoint holding uploads in memory, answering one request
+    /// per connection.
+    struct FakeS3 {
+        endpoint: String,
+        state: Arc<Mutex<FakeState>>,
+    }
+
+    impl FakeS3 {
+        fn start(fail: Vec<usize>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let state = Arc::new(Mutex::new(FakeState {
+                fail,
+                ..Default::default()
+            }));
+            let server_state = state.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let (status, headers, body) =
+                        Self::handle(&mut BufReader::new(&stream), &server_state);
+                    write!(
+                        stream,
+                        "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\n\
+                         Connection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .unwrap();
+                }
+            });
+            Self { endpoint, state }
+        }
+
+        fn handle(
+            stream: &mut impl BufRead,
+            state: &Mutex<FakeState>,
+        ) -> (&'static str, String, String) {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap().to_string();
+            let target = parts.next().unwrap().to_string();
+            let mut content_length = 0;
+            let mut authorization = String::new();
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).unwrap();
+                let Some((name, value)) = header.trim_end().split_once(": ") else {
+                    break;
+                };
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.parse().unwrap(),
+                    "authorization" => authorization = value.to_string(),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            stream.read_exact(&mut body).unwrap();
+
+            let mut state = state.lock().unwrap();
+            state.requests.push(format!("{method} {target}"));
+            if state.fail.contains(&(state.requests.len() - 1)) {
+                return ("503 Slow Down", String::new(), String::new());
+            }
+            assert!(
+                authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDTEST/"),
+                "{authorization}"
+            );
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+            match (method.as_str(), query) {
+                ("POST", "uploads=") => (
+                    "200 OK",
+                    String::new(),
+                    "<InitiateMultipartUploadResult><UploadId>up-1</UploadId>\
+                     </InitiateMultipartUploadResult>"
+                        .into(),
+                ),
+                ("PUT", query) if query.starts_with("partNumber=") => {
+                    let part: u32 = query["partNumber=".len()..]
+                        .split('&')
+                        .next()
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    state.parts.insert(part, body);
+                    (
+                        "200 OK",
+                        format!("ETag: \"etag-{part}\"\r\n"),
+                        String::new(),
+                    )
+                }
+                ("POST", "uploadId=up-1") => {
+                    let body = String::from_utf8(body).unwrap();
+                    let object: Vec<u8> = state
+                        .parts
+                        .iter()
+                        .inspect(|(part, _)| {
+                            assert!(body.contains(&format!(
+                                "<PartNumber>{part}</PartNumber><ETag>&quot;etag-{part}&quot;"
+                            )))
+                        })
+                        .flat_map(|(_, data)| data.clone())
+                        .collect();
+                    state.objects.insert(path.to_string(), object);
+                    (
+                        "200 OK",
+                        String::new(),
+                        "<CompleteMultipartUploadResult/>".into(),
+                    )
+                }
+                ("PUT", "") => {
+                    state.objects.insert(path.to_string(), body);
+                    ("200 OK", "ETag: \"whole\"\r\n".into(), String::new())
+                }
+                ("DELETE", "uploadId=up-1") => {
+                    state.aborted = true;
+                    state.parts.clear();
+                    ("204 No Content", String::new(), String::new())
+                }
+                _ => panic!("unexpected request {method} {target}"),
+            }
+        }
+
+        fn config(&self) -> ObjectStoreConfig {
+            ObjectStoreConfig {
+                endpoint: self.endpoint.clone(),
+                region: "us-east-1".into(),
+                bucket: "backups".into(),
+                prefix: "nightly/".into(),
+                credentials: Credentials {
+                    access_key_id: "AKIDTEST".into(),
+                    secret_access_key: Zeroizing::new("secret".into()),
+                    session_token: None,
+                },
+                part_size: 1024,
+                max_retries: 2,
+                timeout: Duration::from_secs(5),
+            }
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.state.lock().unwrap().requests.clone()
+        }
+    }
+
+    #[test]
+    fn backup_streams_as_a_multipart_upload() {
+        use crate::{
+            backup,
+            crypto::keys::KeyScope,
+            keyring::Keyring,
+            kms::local::DeviceKeyProvider,
+        };
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let keyfile = dir.path().join("test.key");
+        std::fs::write(&keyfile, [0x61; 32]).unwrap();
+        let kms = Arc::new(DeviceKeyProvider::from_keyfile(keyfile));
+        let keyring = Keyring::new(kms.clone());
+        let dek = keyring.dek_for(&KeyScope::Database).unwrap();
+        let db_path = dir.path().join("test.db");
+        let mut db = Vec::new();
+        for page_no in 1..=4 {
+            let mut page = vec![page_no as u8; 4096];
+            crate::crypto::page::encrypt_page(&mut page, page_no, None, &dek, 80).unwrap();
+            db.extend_from_slice(&page);
+        }
+        std::fs::write(&db_path, &db).unwrap();
+
+        // The second part fails once, and is retried.
+        let s3 = FakeS3::start(vec![2]);
+        let mut out = ObjectStoreWriter::new(s3.config(), "my db.evfs-backup");
+        backup::create_backup(&db_path, &mut out, &keyring, kms.as_ref(), 4096, 80).unwrap();
+        out.finish().unwrap();
+
+        let requests = s3.requests();
+        assert_eq!(
+            requests[0],
+            "POST /backups/nightly/my%20db.evfs-backup?uploads="
+        );
+        assert_eq!(requests[2], requests[3]);
+        assert!(requests.last().unwrap().ends_with("?uploadId=up-1"));
+        let state = s3.state.lock().unwrap();
+        assert!(state.parts.len() > 2);
+        assert!(!state.aborted);
+        let object = &state.objects["/backups/nightly/my%20db.evfs-backup"];
+        let result = backup::verify_backup(&mut object.as_slice(), kms.as_ref()).unwrap();
+        assert!(result.is_ok());
+        assert_eq!(result.pages_ok, 4);
+    }
+
+    #[test]
+    fn small_object_is_put_whole() {
+        let s3 = FakeS3::start(Vec::new());
+        let mut out = ObjectStoreWriter::new(s3.config(), "small");
+        out.write_all(b"not a part's worth").unwrap();
+        out.finish().unwrap();
+        assert_eq!(s3.requests(), ["PUT /backups/nightly/small"]);
+        assert_eq!(
+            s3.state.lock().unwrap().objects["/backups/nightly/small"],
+            b"not a part's worth"
+        );
+    }
+
+    #[test]
+    fn failed_part_aborts_the_upload() {
+        // Part 1 fails on every attempt.
+        let s3 = FakeS3::start(vec![1, 2, 3]);
+        let mut out = ObjectStoreWriter::new(s3.config(), "failed");
+        let err = out.write_all(&[0x5a; 4096]).unwrap_err();
+        assert!(err.to_string().contains("503"), "{err}");
+        assert!(s3.state.lock().unwrap().aborted);
+        assert!(out.write_all(b"more").is_err());
+        assert!(out.finish().is_err());
+        assert!(s3.state.lock().unwrap().objects.is_empty());
+    }
+
+    #[test]
+    fn dropped_writer_aborts_the_upload() {
+        let s3 = FakeS3::start(Vec::new());
+        let mut out = ObjectStoreWriter::new(s3.config(), "dropped");
+        out.write_all(&[0x5a; 1500]).unwrap();
+        drop(out);
+        let state = s3.state.lock().unwrap();
+        assert!(state.aborted);
+        assert!(state.objects.is_empty());
+        assert_eq!(
+            state.requests.last().unwrap(),
+            "DELETE /backups/nightly/dropped?uploadId=up-1"
+        );
+    }
+
+    /// Uploads a backup of several parts to the bucket
+    /// `EVFS_S3_TEST_BUCKET` at `EVFS_S3_TEST_ENDPOINT`, e.g. a local
+    /// MinIO, with credentials from the environment, and reads it back.
+    /// Skipped unless both are set.
+    #[test]
+    fn s3_round_trip() {
+        let (Ok(endpoint), Ok(bucket)) = (
+            std::env::var("EVFS_S3_TEST_ENDPOINT"),
+            std::env::var("EVFS_S3_TEST_BUCKET"),
+        ) else {
+            eprintln!("EVFS_S3_TEST_ENDPOINT and EVFS_S3_TEST_BUCKET not set, skipping");
+            return;
+        };
+        let config = ObjectStoreConfig::from_env(endpoint, bucket)
+            .unwrap()
+            .prefix("evfs-test/");
+        let data: Vec<u8> = (0..MIN_PART_SIZE * 2 + 12_345)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut out = ObjectStoreWriter::new(config.clone(), "round-trip");
+        out.write_all(&data).unwrap();
+        out.finish().unwrap();
+
+        let reader = ObjectStoreWriter::new(config, "round-trip");
+        let response = reader.send("GET", &[], &[]).unwrap();
+        let mut read = Vec::new();
+        response.into_reader().read_to_end(&mut read).unwrap();
+        assert!(read == data);
+        reader.send("DELETE", &[], &[]).unwrap();
+        // Nothing was uploaded through `reader`, so dropping it sends
+        // nothing.
+    }
+}
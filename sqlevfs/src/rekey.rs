@@ -0,0 +1,706 @@
+//! In-place rotation of a database's data encryption key.
+//!
+//! Every page is re-encrypted under a fresh Database-scope DEK while
+//! an exclusive SQLite lock is held on the file, then the keyring
+//! sidecar is swapped atomically. A rekey journal next to the
+//! database holds the new DEK (wrapped) and the original bytes of the
+//! batch of pages being rewritten, so an interrupted rekey - even one
+//! that tore a page - is finished by running it again. The VFS will
+//! not open a database while its rekey journal exists.
+//!
+//! The VFS also runs a rekey on an open connection's own file handle
+//! for `PRAGMA evfs_rekey`, rotating the DEK of the database's own
+//! scope (`KeyScope::Named` if it was opened with `evfs_scope`).
+
+use std::{
+    ffi::{CStr, CString, c_int, c_void},
+    fmt,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use bincode::config;
+use libsqlite3_sys::*;
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{
+        envelope,
+        keys::{DatabaseId, Dek, KekId, KeyScope, WrappedDek},
+        page as page_crypto,
+    },
+    keyring::{self, Keyring, PersistedKeyring},
+};
+
+const JOURNAL_MAGIC: &[u8; 8] = b"EVFSRKEY";
+const JOURNAL_VERSION: u32 = 2;
+/// Pages rewritten per journal sync.
+const BATCH_PAGES: u32 = 256;
+
+/// Contents of the rekey journal.
+#[derive(bincode::Encode, bincode::Decode)]
+struct RekeyJournal {
+    version: u32,
+    page_size: u32,
+    reserve_size: u32,
+    /// Scope whose DEK is being rotated.
+    scope: KeyScope,
+    /// The DEK pages are moving to, wrapped under the KEK.
+    new_dek: WrappedDek,
+    /// First page of the batch being rewritten.
+    batch_start: u32,
+    /// Original bytes of the batch's pages.
+    batch: Vec<u8>,
+}
+
+/// Outcome of a completed rekey.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RekeyReport {
+    /// Pages re-encrypted by this run.
+    pub pages_rewritten: u32,
+    /// Pages in the database.
+    pub page_count: u32,
+    /// Whether this run finished an interrupted rekey.
+    pub resumed: bool,
+    /// KEK wrapping the new DEK.
+    pub kek_id: KekId,
+}
+
+impl fmt::Display for RekeyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ok: re-encrypted {} of {} pages{}, kek_id={}",
+            self.pages_rewritten,
+            self.page_count,
+            if self.resumed { " (resumed)" } else { "" },
+            self.kek_id.0
+        )
+    }
+}
+
+/// Key state of a database, as recorded in its sidecar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStatus {
+    /// KEK wrapping the DEK of the database's scope.
+    pub kek_id: KekId,
+    /// Wrapped DEKs in the sidecar, across all scopes.
+    pub dek_count: usize,
+    /// Whether an interrupted rekey needs finishing.
+    pub rekey_pending: bool,
+}
+
+impl fmt::Display for KeyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kek_id={} deks={} rekey={}",
+            self.kek_id.0,
+            self.dek_count,
+            if self.rekey_pending {
+                "pending"
+            } else {
+                "idle"
+            }
+        )
+    }
+}
+
+/// Key state of the database at `db_path`.
+pub fn key_status(db_path: &Path) -> anyhow::Result<KeyStatus> {
+    scoped_key_status(db_path, &KeyScope::Database)
+}
+
+/// Key state of the database at `db_path`, whose pages are under
+/// `scope`.
+pub(crate) fn scoped_key_status(db_path: &Path, scope: &KeyScope) -> anyhow::Result<KeyStatus> {
+    let (persisted, wrapped) = file_dek(db_path, scope)?;
+    Ok(KeyStatus {
+        kek_id: wrapped.kek_id,
+        dek_count: persisted.keys.len(),
+        rekey_pending: rekey_pending(db_path),
+    })
+}
+
+/// The sidecar of `db_path` and the wrapped DEK of `scope` in it.
+fn file_dek(db_path: &Path, scope: &KeyScope) -> anyhow::Result<(PersistedKeyring, WrappedDek)> {
+    let sidecar = keyring::sidecar_path_for(db_path);
+    let persisted = PersistedKeyring::decode(
+        &std::fs::read(&sidecar)
+            .map_err(|e| anyhow::anyhow!("read keyring {}: {e}", sidecar.display()))?,
+    )?;
+    let wrapped = persisted
+        .keys
+        .get(&scope.to_string())
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("no {scope} DEK in {}", sidecar.display()))?;
+    Ok((persisted, wrapped))
+}
+
+/// Path of the rekey journal for the database at `db_path`.
+pub fn journal_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("evfs-rekey")
+}
+
+/// Whether an interrupted rekey of `db_path` needs finishing.
+pub fn rekey_pending(db_path: &Path) -> bool {
+    journal_path(db_path).exists()
+}
+
+/// Re-encrypt every page of the database at `path` under a freshly
+/// generated Database-scope DEK and record it in the keyring sidecar.
+///
+/// `page_size` and `reserve` must match the database header. Fails if
+/// the database is locked by another connection or is in WAL mode.
+/// Connections in other processes that stay open across a rekey keep
+/// using the old DEK, so close them first. If a previous rekey was
+/// interrupted this finishes it instead of starting a new one.
+pub fn rekey_database(
+    path: &Path,
+    keyring: &Keyring,
+    page_size: u32,
+    reserve: usize,
+) -> anyhow::Result<RekeyReport> {
+    rekey(path, keyring, Some((page_size, reserve)), None)
+}
+
+/// Rekey taking the page geometry from the database header.
+pub(crate) fn rekey_from_header(path: &Path, keyring: &Keyring) -> anyhow::Result<RekeyReport> {
+    rekey(path, keyring, None, None)
+}
+
+fn rekey(
+    path: &Path,
+    keyring: &Keyring,
+    expected: Option<(u32, usize)>,
+    interrupt_after: Option<u32>,
+) -> anyhow::Result<RekeyReport> {
+    let lock = DbLock::acquire_any(path, c"BEGIN EXCLUSIVE", SQLITE_LOCK_EXCLUSIVE)?;
+    rekey_file(
+        lock.file()?,
+        path,
+        keyring,
+        &KeyScope::Database,
+        expected,
+        interrupt_after,
+    )
+}
+
+/// Rekey the database at `path` through `file`, a raw handle on it
+/// that already holds an exclusive lock, rotating the DEK of `scope`.
+/// An interrupted rekey is finished for the scope it started on.
+pub(crate) fn rekey_file(
+    file: *mut sqlite3_file,
+    path: &Path,
+    keyring: &Keyring,
+    scope: &KeyScope,
+    expected: Option<(u32, usize)>,
+    interrupt_after: Option<u32>,
+) -> anyhow::Result<RekeyReport> {
+    let mut header = [0u8; 100];
+    read_at(file, &mut header, 0)?;
+    let (page_size, reserve, concealed) = crate::vfs::stored_db_geometry(&header, path)?
+        .ok_or_else(|| anyhow::anyhow!("{} is not a SQLite database", path.display()))?;
+    if !concealed {
+        ensure_not_wal(&header)?;
+    }
+    if let Some((want_page_size, want_reserve)) = expected {
+        anyhow::ensure!(
+            (want_page_size, want_reserve) == (page_size, reserve),
+            "database has page_size={page_size}, reserve={reserve}, not \
+             page_size={want_page_size}, reserve={want_reserve}"
+        );
+    }
+
+    let jpath = journal_path(path);
+    let resumed = jpath.exists();
+    let pending = if resumed {
+        Some(read_journal(&jpath)?)
+    } else {
+        None
+    };
+    let scope = pending.as_ref().map_or(scope, |j| &j.scope).clone();
+
+    let (persisted, old_wrapped) = file_dek(path, &scope)?;
+    let old_dek = envelope::unwrap_dek(&old_wrapped, keyring.provider())?;
+    // Pages of scoped tables keep their table's DEK.
+    let table_deks = persisted
+        .keys
+        .iter()
+        .filter(|(key, _)| **key != scope.to_string())
+        .map(|(_, wrapped)| envelope::unwrap_dek(wrapped, keyring.provider()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // A database from before IDs gets one, recorded before any page
+    // is bound to it.
+    let database_id = Some(keyring.database_id_for(path)?);
+    // A concealed header is only readable once decrypted. A resumed
+    // rekey was checked when it started.
+    if concealed && pending.is_none() {
+        let mut page1 = Zeroizing::new(vec![0u8; page_size as usize]);
+        read_at(file, &mut page1, 0)?;
+        page_crypto::decrypt_page(&mut page1, 1, database_id, &old_dek, reserve)?;
+        ensure_not_wal(&page1)?;
+    }
+
+    let (new_dek, mut journal) = if let Some(journal) = pending {
+        anyhow::ensure!(
+            (journal.page_size, journal.reserve_size as usize) == (page_size, reserve),
+            "rekey journal {} does not match the database's page geometry",
+            jpath.display()
+        );
+        let new_dek = envelope::unwrap_dek(&journal.new_dek, keyring.provider())?;
+        restore_torn_pages(
+            file,
+            &journal,
+            database_id,
+            &[&old_dek, &new_dek],
+            &table_deks,
+        )?;
+        log::info!("resuming interrupted rekey of {}", path.display());
+        (new_dek, journal)
+    } else {
+        let algorithm = keyring.algorithm();
+        anyhow::ensure!(
+            reserve >= page_crypto::min_uncommitted_reserve(algorithm),
+            "reserve ({reserve}) is too small for {algorithm} pages"
+        );
+        let new_dek = Dek::generate_for(algorithm);
+        let journal = RekeyJournal {
+            version: JOURNAL_VERSION,
+            page_size,
+            reserve_size: reserve as u32,
+            scope: scope.clone(),
+            new_dek: envelope::wrap_dek(&new_dek, keyring.provider())?,
+            batch_start: 1,
+            batch: Vec::new(),
+        };
+        write_journal(&jpath, &journal)?;
+        (new_dek, journal)
+    };
+
+    let mut file_size: i64 = 0;
+    let rc = unsafe { ((*(*file).pMethods).xFileSize.unwrap())(file, &mut file_size) };
+    anyhow::ensure!(rc == SQLITE_OK, "read database size: {rc}");
+    let page_len = page_size as usize;
+    let page_count = (file_size / page_size as i64) as u32;
+
+    let mut rewritten = 0u32;
+    let mut batch_start = 1u32;
+    while batch_start <= page_count {
+        let batch_len = BATCH_PAGES.min(page_count - batch_start + 1);
+        let offset = page_offset(batch_start, page_size);
+
+        // The batch's original bytes are durable before any page in it
+        // is overwritten.
+        // Zeroized, as a page whose re-encryption fails is left in it
+        // as plaintext.
+        let mut batch = Zeroizing::new(vec![0u8; batch_len as usize * page_len]);
+        read_at(file, &mut batch, offset)?;
+        journal.batch_start = batch_start;
+        journal.batch = batch.to_vec();
+        write_journal(&jpath, &journal)?;
+
+        for (i, page) in batch.chunks_mut(page_len).enumerate() {
+            let page_no = batch_start + i as u32;
+            if !rekey_page(
+                page,
+                page_no,
+                database_id,
+                &old_dek,
+                &new_dek,
+                &table_deks,
+                reserve,
+            )? {
+                continue;
+            }
+            if interrupt_after == Some(rewritten) {
+                anyhow::bail!("rekey interrupted");
+            }
+            write_at(file, page, page_offset(page_no, page_size))?;
+            rewritten += 1;
+        }
+        sync(file)?;
+        batch_start += batch_len;
+    }
+
+    let kek_id = journal.new_dek.kek_id.clone();
+    keyring.install_dek(path, &scope, new_dek, journal.new_dek)?;
+    std::fs::remove_file(&jpath)?;
+    log::info!(
+        "rekeyed {}: {rewritten} of {page_count} pages re-encrypted",
+        path.display()
+    );
+    Ok(RekeyReport {
+        pages_rewritten: rewritten,
+        page_count,
+        resumed,
+        kek_id,
+    })
+}
+
+/// Move `page` from `old_dek` to `new_dek`. `Ok(false)` if it needs no
+/// writing: it is plaintext (as page 1 is, unless the database
+/// conceals its header), already under `new_dek`, or a scoped table's
+/// page under one of `table_deks`.
+fn rekey_page(
+    page: &mut [u8],
+    page_no: u32,
+    database_id: Option<DatabaseId>,
+    old_dek: &Dek,
+    new_dek: &Dek,
+    table_deks: &[Dek],
+    reserve: usize,
+) -> anyhow::Result<bool> {
+    if !page_crypto::is_encrypted_page(page, reserve) {
+        return Ok(false);
+    }
+    let decrypts = |dek: &Dek| {
+        let mut plain = Zeroizing::new(page.to_vec());
+        page_crypto::decrypt_page(&mut plain, page_no, database_id, dek, reserve)
+    };
+    if decrypts(new_dek).is_ok() {
+        return Ok(false);
+    }
+    if let Err(e) = decrypts(old_dek) {
+        if table_deks.iter().any(|dek| decrypts(dek).is_ok()) {
+            return Ok(false);
+        }
+        anyhow::bail!("page {page_no} does not decrypt under the current DEK: {e}");
+    }
+    page_crypto::decrypt_page(page, page_no, database_id, old_dek, reserve)?;
+    page_crypto::encrypt_page(page, page_no, database_id, new_dek, reserve)?;
+    Ok(true)
+}
+
+/// Put back journalled pages of the interrupted batch that decrypt
+/// under neither Database DEK nor a table's, i.e. were torn mid-write.
+fn restore_torn_pages(
+    file: *mut sqlite3_file,
+    journal: &RekeyJournal,
+    database_id: Option<DatabaseId>,
+    database_deks: &[&Dek],
+    table_deks: &[Dek],
+) -> anyhow::Result<()> {
+    let page_size = journal.page_size;
+    let reserve = journal.reserve_size as usize;
+    for (i, original) in journal.batch.chunks(page_size as usize).enumerate() {
+        let page_no = journal.batch_start + i as u32;
+        let offset = page_offset(page_no, page_size);
+        let mut current = vec![0u8; page_size as usize];
+        read_at(file, &mut current, offset)?;
+        if current == original
+            || !page_crypto::is_encrypted_page(original, reserve)
+            || database_deks.iter().copied().chain(table_deks).any(|dek| {
+                let mut plain = Zeroizing::new(current.clone());
+                page_crypto::decrypt_page(&mut plain, page_no, database_id, dek, reserve).is_ok()
+            })
+        {
+            continue;
+        }
+        log::warn!("rekey: restoring torn page {page_no} from the journal");
+        write_at(file, original, offset)?;
+    }
+    sync(file)
+}
+
+fn ensure_not_wal(header: &[u8]) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        header[18] != 2 && header[19] != 2,
+        "cannot rekey a database in WAL mode; checkpoint it and set journal_mode=DELETE first"
+    );
+    Ok(())
+}
+
+pub(crate) fn page_offset(page_no: u32, page_size: u32) -> i64 {
+    (page_no as i64 - 1) * page_size as i64
+}
+
+fn read_journal(path: &Path) -> anyhow::Result<RekeyJournal> {
+    let data = std::fs::read(path)?;
+    anyhow::ensure!(
+        data.len() > JOURNAL_MAGIC.len() && &data[..JOURNAL_MAGIC.len()] == JOURNAL_MAGIC,
+        "invalid rekey journal {}",
+        path.display()
+    );
+    let journal: RekeyJournal =
+        bincode::decode_from_slice(&data[JOURNAL_MAGIC.len()..], config::standard())?.0;
+    anyhow::ensure!(
+        journal.version == JOURNAL_VERSION,
+        "unsupported rekey journal version: {}",
+        journal.version
+    );
+    Ok(journal)
+}
+
+fn write_journal(path: &Path, journal: &RekeyJournal) -> anyhow::Result<()> {
+    let mut data = JOURNAL_MAGIC.to_vec();
+    data.extend_from_slice(&bincode::encode_to_vec(journal, config::standard())?);
+    keyring::write_atomically(path, &data)
+}
+
+pub(crate) fn read_at(file: *mut sqlite3_file, buf: &mut [u8], offset: i64) -> anyhow::Result<()> {
+    let rc = unsafe {
+        ((*(*file).pMethods).xRead.unwrap())(
+            file,
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len() as c_int,
+            offset,
+        )
+    };
+    anyhow::ensure!(rc == SQLITE_OK, "read at {offset}: {rc}");
+    Ok(())
+}
+
+fn write_at(file: *mut sqlite3_file, buf: &[u8], offset: i64) -> anyhow::Result<()> {
+    let rc = unsafe {
+        ((*(*file).pMethods).xWrite.unwrap())(
+            file,
+            buf.as_ptr() as *const c_void,
+            buf.len() as c_int,
+            offset,
+        )
+    };
+    anyhow::ensure!(rc == SQLITE_OK, "write at {offset}: {rc}");
+    Ok(())
+}
+
+fn sync(file: *mut sqlite3_file) -> anyhow::Result<()> {
+    let rc = unsafe { ((*(*file).pMethods).xSync.unwrap())(file, SQLITE_SYNC_FULL) };
+    anyhow::ensure!(rc == SQLITE_OK, "sync: {rc}");
+    Ok(())
+}
+
+// ── Locking ─────────────────────────────────────────────────────────
+
+/// A connection through the default VFS holding a lock on the
+/// database for as long as its `begin` transaction is open. Pages are
+/// read and written through its own file handle, since closing any
+/// other descriptor for the file would drop the process's POSIX locks
+/// on it.
+pub(crate) struct DbLock {
+    db: *mut sqlite3,
+}
+
+impl DbLock {
+    pub(crate) fn acquire(path: &Path, begin: &CStr) -> anyhow::Result<Self> {
+        let lock = Self::open(path)?;
+        let rc = unsafe {
+            sqlite3_exec(
+                lock.db,
+                begin.as_ptr(),
+                None,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        anyhow::ensure!(
+            rc == SQLITE_OK,
+            "{} is in use by another connection ({rc})",
+            path.display()
+        );
+        Ok(lock)
+    }
+
+    /// Take `level` on the file handle itself, for a database whose
+    /// header the default VFS can't read because it is concealed. The
+    /// connection must then run no SQL: its pager would drop the lock.
+    pub(crate) fn acquire_file(path: &Path, level: c_int) -> anyhow::Result<Self> {
+        let lock = Self::open(path)?;
+        let file = lock.file()?;
+        for level in [SQLITE_LOCK_SHARED, level] {
+            let rc = unsafe { ((*(*file).pMethods).xLock.unwrap())(file, level) };
+            anyhow::ensure!(
+                rc == SQLITE_OK,
+                "{} is in use by another connection ({rc})",
+                path.display()
+            );
+        }
+        Ok(lock)
+    }
+
+    /// Lock `path` through a transaction if the default VFS can read its
+    /// header, otherwise on the file handle.
+    pub(crate) fn acquire_any(path: &Path, begin: &CStr, level: c_int) -> anyhow::Result<Self> {
+        if keyring::concealed_header_of(path).is_some() {
+            Self::acquire_file(path, level)
+        } else {
+            Self::acquire(path, begin)
+        }
+    }
+
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let c_path = CString::new(
+            path.to_str()
+                .ok_or_else(|| anyhow::anyhow!("non UTF-8 path {}", path.display()))?,
+        )?;
+
+        let mut db = ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_open_v2(c_path.as_ptr(), &mut db, SQLITE_OPEN_READWRITE, ptr::null())
+        };
+        // Dropping closes the handle, even if opening failed.
+        let lock = Self { db };
+        anyhow::ensure!(rc == SQLITE_OK, "open {}: {rc}", path.display());
+        Ok(lock)
+    }
+
+    pub(crate) fn handle(&self) -> *mut sqlite3 {
+        self.db
+    }
+
+    pub(crate) fn file(&self) -> anyhow::Result<*mut sqlite3_file> {
+        let mut file: *mut sqlite3_file = ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_file_control(
+                self.db,
+                c"main".as_ptr(),
+                SQLITE_FCNTL_FILE_POINTER,
+                &mut file as *mut *mut sqlite3_file as *mut c_void,
+            )
+        };
+        anyhow::ensure!(
+            rc == SQLITE_OK && !file.is_null() && !unsafe { (*file).pMethods }.is_null(),
+            "no file handle for the database"
+        );
+        Ok(file)
+    }
+}
+
+impl Drop for DbLock {
+    fn drop(&mut self) {
+        unsafe {
+            sqlite3_exec(
+                self.db,
+                c"ROLLBACK".as_ptr(),
+                None,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            sqlite3_close(self.db);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rusqlite::{Connection, OpenFlags};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{EvfsBuilder, Mode};
+
+    const ROWS: i64 = 2000;
+
+    /// A database spanning a few rekey batches, written through a
+    /// freshly registered VFS.
+    fn setup(dir: &TempDir, vfs: &str) -> (PathBuf, Arc<Keyring>) {
+        let keyfile = dir.path().join("rekey.key");
+        std::fs::write(&keyfile, [0x5A; 32]).unwrap();
+        let keyring = EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile),
+            passphrase: None,
+        })
+        .vfs_name(vfs)
+        .register()
+        .unwrap();
+
+        let db_path = dir.path().join("rekey.db");
+        let conn = Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+        .unwrap();
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT)")
+            .unwrap();
+        for i in 0..ROWS {
+            conn.execute(
+                "INSERT INTO t (body) VALUES (?1)",
+                [format!("row {i} {}", "y".repeat(500))],
+            )
+            .unwrap();
+        }
+        (db_path, keyring)
+    }
+
+    fn check(db_path: &Path, vfs: &str) {
+        let conn =
+            Connection::open_with_flags_and_vfs(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY, vfs)
+                .unwrap();
+        let check: String = conn
+            .query_row("PRAGMA integrity_check", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(check, "ok");
+        let count: i64 = conn
+            .query_row("SELECT count(*) FROM t", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(count, ROWS);
+    }
+
+    fn wrapped_dek(db_path: &Path) -> WrappedDek {
+        file_dek(db_path, &KeyScope::Database).unwrap().1
+    }
+
+    #[test]
+    fn interrupted_rekey_resumes() {
+        let dir = TempDir::new().unwrap();
+        let (db_path, keyring) = setup(&dir, "evfs_rekey_resume");
+        let old_dek = wrapped_dek(&db_path);
+
+        // Stop partway through the second batch.
+        let err = rekey(&db_path, &keyring, None, Some(BATCH_PAGES + 10)).unwrap_err();
+        assert!(err.to_string().contains("interrupted"));
+        assert!(rekey_pending(&db_path));
+        assert_eq!(wrapped_dek(&db_path), old_dek);
+
+        // The VFS refuses the half-rekeyed database.
+        assert!(
+            Connection::open_with_flags_and_vfs(
+                &db_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY,
+                "evfs_rekey_resume",
+            )
+            .is_err()
+        );
+
+        let report = keyring.rotate_data_key(&db_path).unwrap();
+        assert!(report.resumed);
+        assert!(report.pages_rewritten < report.page_count);
+        assert!(!rekey_pending(&db_path));
+        assert_ne!(wrapped_dek(&db_path), old_dek);
+        check(&db_path, "evfs_rekey_resume");
+    }
+
+    #[test]
+    fn torn_page_is_restored_from_journal() {
+        let dir = TempDir::new().unwrap();
+        let (db_path, keyring) = setup(&dir, "evfs_rekey_torn");
+        let page_size = 4096u64;
+
+        rekey(&db_path, &keyring, None, Some(BATCH_PAGES + 10)).unwrap_err();
+
+        // Tear the page the rekey stopped at: its first half is
+        // garbage, as if the write was cut short.
+        let journal = read_journal(&journal_path(&db_path)).unwrap();
+        let page_no = journal.batch_start as u64 + 11;
+        let mut raw = std::fs::read(&db_path).unwrap();
+        let offset = ((page_no - 1) * page_size) as usize;
+        raw[offset..offset + page_size as usize / 2].fill(0xEE);
+        std::fs::write(&db_path, raw).unwrap();
+
+        rekey_database(&db_path, &keyring, 4096, 80).unwrap();
+        check(&db_path, "evfs_rekey_torn");
+    }
+
+    #[test]
+    fn geometry_mismatch_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let (db_path, keyring) = setup(&dir, "evfs_rekey_geometry");
+
+        let err = rekey_database(&db_path, &keyring, 8192, 80).unwrap_err();
+        assert!(err.to_string().contains("page_size=4096"));
+        assert!(!rekey_pending(&db_path));
+    }
+}
@@ -4,14 +4,32 @@
 //! adding page-level encryption on every read/write.
 
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     ffi::{CStr, CString, c_char, c_int, c_void},
+    path::{Path, PathBuf},
     ptr,
-    sync::Arc,
+    sync::{
+        Arc,
+        LazyLock,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 
 use libsqlite3_sys::*;
-
-use crate::{crypto::page::is_encrypted_page, io::FileContext, keyring::Keyring};
+use parking_lot::{Mutex, RwLock};
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{
+        keys::{DatabaseId, KeyScope},
+        page::{self, MIN_RESERVE, is_encrypted_page, legacy_pages_enabled},
+        temp::TempCipher,
+    },
+    io::FileContext,
+    keyring::{self, ConcealedHeader, Keyring},
+    kms::{KmsProvider, local::DeviceKeyProvider},
+};
 
 // ── Our extended file struct ────────────────────────────────────────
 
@@ -25,24 +43,66 @@ struct EvfsFile {
     inner_file: *mut sqlite3_file,
     /// Shared encryption context.
     ctx: *mut FileContext,
+    /// The VFS this file was opened through.
+    global: *const EvfsGlobal,
+    /// Lock level SQLite holds through this handle.
+    lock_level: c_int,
 }
 
-// ── Global VFS context (leaked, lives for the process) ─────────────
-
-struct EvfsGlobal {
+/// `sqlite3_file_control` opcode that rekeys a database in place, as
+/// `PRAGMA evfs_rekey` does. The argument is a `*mut *mut c_char` that
+/// receives the result or error message, to be freed with
+/// `sqlite3_free`.
+pub const EVFS_FCNTL_REKEY: c_int = 0x4556_5301;
+/// Like [`EVFS_FCNTL_REKEY`], but reports the key status, as
+/// `PRAGMA evfs_key_status` does.
+pub const EVFS_FCNTL_KEY_STATUS: c_int = 0x4556_5302;
+/// Like [`EVFS_FCNTL_REKEY`], but scans every page for ones that fail
+/// to decrypt, as `PRAGMA evfs_verify` does.
+pub const EVFS_FCNTL_VERIFY: c_int = 0x4556_5303;
+
+// ── Global VFS context (one per registered name) ───────────────────
+
+/// Builder settings of a registered VFS. Registering the name again
+/// swaps them for files opened afterwards; open files keep the ones
+/// they were opened with.
+struct EvfsConfig {
     keyring: Arc<Keyring>,
     page_size: u32,
     reserve_size: usize,
+    allow_mmap: bool,
+    conceal_header: bool,
+    table_scopes: Vec<String>,
+}
+
+struct EvfsGlobal {
+    config: RwLock<Arc<EvfsConfig>>,
+    /// The VFS files are opened through, fixed for the registration.
     inner_vfs: *mut sqlite3_vfs,
-    /// Our io_methods table (static lifetime).
+    /// Our io_methods table, which every open file points at.
     io_methods: sqlite3_io_methods,
+    /// Files open through this VFS. It is only freed at zero.
+    open_files: AtomicUsize,
 }
 
 // Safety: the inner_vfs pointer comes from SQLite and is valid for
-// the process lifetime. EvfsGlobal is leaked and never mutated.
+// the process lifetime. EvfsGlobal lives until `unregister_evfs`,
+// which frees it only once no file refers to it.
 unsafe impl Send for EvfsGlobal {}
 unsafe impl Sync for EvfsGlobal {}
 
+/// A VFS registered by [`register_evfs`]. It owns the `sqlite3_vfs`,
+/// its name and the `EvfsGlobal` in its `pAppData`.
+struct Registration(*mut sqlite3_vfs);
+
+// Safety: the registry only hands the pointer to SQLite and to
+// `unregister_evfs`, both under its lock.
+unsafe impl Send for Registration {}
+
+/// VFSes registered by this crate, by name.
+static REGISTRY: LazyLock<Mutex<HashMap<String, Registration>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
 // ── xOpen ───────────────────────────────────────────────────────────
 
 unsafe fn inner_filesize(inner: *mut sqlite3_file) -> Option<i64> {
@@ -53,7 +113,7 @@ unsafe fn inner_filesize(inner: *mut sqlite3_file) -> Option<i64> {
     }
 }
 
-fn try_reserve_page1(global: &EvfsGlobal, inner: *mut sqlite3_file) -> c_int {
+fn try_reserve_page1(ctx: &FileContext, inner: *mut sqlite3_file) -> c_int {
     unsafe {
         let Some(sz) = inner_filesize(inner) else {
             return SQLITE_IOERR;
@@ -62,8 +122,8 @@ fn try_reserve_page1(global: &EvfsGlobal, inner: *mut sqlite3_file) -> c_int {
             return SQLITE_OK;
         }
 
-        let page_size = global.page_size as usize;
-        let reserve = global.reserve_size;
+        let page_size = ctx.page_size as usize;
+        let reserve = ctx.reserve_size;
 
         if !(512..=65536).contains(&page_size) {
             return SQLITE_IOERR;
@@ -71,8 +131,8 @@ fn try_reserve_page1(global: &EvfsGlobal, inner: *mut sqlite3_file) -> c_int {
         if reserve > u8::MAX as usize {
             return SQLITE_IOERR;
         }
-        if reserve < 22 {
-            // 16 tag + 6 marker (EVFSv1)
+        if reserve < MIN_RESERVE {
+            // 16 tag + 6 marker + 12 nonce
             return SQLITE_IOERR;
         }
         if page_size < 100 + 8 {
@@ -150,6 +210,15 @@ fn try_reserve_page1(global: &EvfsGlobal, inner: *mut sqlite3_file) -> c_int {
         // Fragmented free bytes = 0
         page1[107] = 0;
 
+        if !ctx.is_plaintext_page(1)
+            && let Err(e) = ctx.encrypt_page(&mut page1, 1)
+        {
+            return report(
+                page_crypto_rc(&e, SQLITE_IOERR_WRITE),
+                format!("evfs encrypt page 1 of a new database: {e}"),
+            );
+        }
+
         // Write it.
         let rcw = ((*(*inner).pMethods).xWrite.unwrap())(
             inner,
@@ -166,6 +235,227 @@ fn try_reserve_page1(global: &EvfsGlobal, inner: *mut sqlite3_file) -> c_int {
     }
 }
 
+/// Page size and reserved bytes from a database header, or `None` if
+/// `header` is not one.
+pub(crate) fn parse_db_header(header: &[u8]) -> Option<(u32, usize)> {
+    if header.len() < 100 || &header[..16] != b"SQLite format 3\0" {
+        return None;
+    }
+    // Big-endian u16; value 1 means 65536.
+    let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+        1 => 65536,
+        ps => ps as u32,
+    };
+    if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+        return None;
+    }
+    Some((page_size, header[20] as usize))
+}
+
+/// Set up a main DB file that is still empty: record in the sidecar
+/// a new database ID, unless a sidecar left behind already has one,
+/// and that it conceals its header, before page 1 can be written, and
+/// pre-create page 1 if SQLite may create the file.
+fn init_new_db(ctx: &mut FileContext, flags: c_int, inner: *mut sqlite3_file) -> c_int {
+    if unsafe { inner_filesize(inner) } != Some(0) {
+        return SQLITE_OK;
+    }
+    if ctx.database_id.is_none() && ctx.db_path.is_some() {
+        let id = DatabaseId::generate();
+        if let Err(e) = ctx.keyring.record_database_id(id) {
+            return report(
+                SQLITE_CANTOPEN,
+                format!("evfs: cannot record the database ID in the sidecar: {e}"),
+            );
+        }
+        ctx.database_id = Some(id);
+    }
+    if ctx.conceal_header {
+        let header = ConcealedHeader {
+            page_size: ctx.page_size,
+            reserve_size: ctx.reserve_size as u32,
+        };
+        if let Err(e) = ctx.keyring.conceal_header(header) {
+            return report(
+                SQLITE_CANTOPEN,
+                format!("evfs: cannot record the concealed header in the sidecar: {e}"),
+            );
+        }
+    }
+    if (flags & SQLITE_OPEN_CREATE) != 0 {
+        return try_reserve_page1(ctx, inner);
+    }
+    SQLITE_OK
+}
+
+/// Page geometry of the database at `db_path`, whose file starts with
+/// `header`, and whether it conceals its header. A plaintext header
+/// gives the geometry; otherwise the sidecar does, if it records a
+/// concealed header. `None` if neither does.
+pub(crate) fn stored_db_geometry(
+    header: &[u8],
+    db_path: &Path,
+) -> anyhow::Result<Option<(u32, usize, bool)>> {
+    match (
+        parse_db_header(header),
+        keyring::concealed_header_of(db_path),
+    ) {
+        (Some(_), Some(_)) => anyhow::bail!(
+            "{} has a plaintext header but its sidecar says it is concealed",
+            db_path.display()
+        ),
+        (Some((page_size, reserve_size)), None) => Ok(Some((page_size, reserve_size, false))),
+        (None, Some(concealed)) => Ok(Some((
+            concealed.page_size,
+            concealed.reserve_size as usize,
+            true,
+        ))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Page size and reserve of the database at `db_path`, from its header
+/// or, if it conceals it, its sidecar. `None` if neither records them,
+/// e.g. for a file SQLite hasn't written yet.
+pub fn page_geometry_of(db_path: &Path) -> anyhow::Result<Option<(u32, usize)>> {
+    use std::io::Read;
+
+    let mut header = Vec::with_capacity(100);
+    std::fs::File::open(db_path)?.take(100).read_to_end(&mut header)?;
+    let geometry = stored_db_geometry(&header, db_path)?;
+    Ok(geometry.map(|(page_size, reserve_size, _)| (page_size, reserve_size)))
+}
+
+/// Page geometry of a main DB, and whether it conceals its header: as
+/// stored for an existing DB, otherwise the builder's.
+fn db_page_geometry(
+    config: &EvfsConfig,
+    inner: *mut sqlite3_file,
+    db_path: Option<&Path>,
+) -> anyhow::Result<(u32, usize, bool)> {
+    let mut header = [0u8; 100];
+    let rc = unsafe {
+        ((*(*inner).pMethods).xRead.unwrap())(
+            inner,
+            header.as_mut_ptr() as *mut c_void,
+            header.len() as c_int,
+            0,
+        )
+    };
+    if rc != SQLITE_OK {
+        header.fill(0);
+    }
+    let stored = match db_path {
+        Some(path) => stored_db_geometry(&header, path)?,
+        None => parse_db_header(&header).map(|(ps, rs)| (ps, rs, false)),
+    };
+    let Some((page_size, reserve_size, concealed)) = stored else {
+        // Anything but an empty file is left to SQLite to reject.
+        // A header can only be concealed with a sidecar to record it in.
+        let empty = unsafe { inner_filesize(inner) } == Some(0);
+        return Ok((
+            config.page_size,
+            config.reserve_size,
+            config.conceal_header && empty && db_path.is_some(),
+        ));
+    };
+
+    if concealed != config.conceal_header {
+        log::info!(
+            "evfs: database {} its header; the VFS's conceal_header={} only applies to new \
+             databases",
+            if concealed {
+                "conceals"
+            } else {
+                "does not conceal"
+            },
+            config.conceal_header
+        );
+    }
+    if (page_size, reserve_size) != (config.page_size, config.reserve_size) {
+        log::warn!(
+            "evfs: database has page_size={page_size}, reserve={reserve_size} but the VFS was \
+             registered with page_size={}, reserve={}; using the database's values",
+            config.page_size,
+            config.reserve_size
+        );
+    }
+    Ok((page_size, reserve_size, concealed))
+}
+
+/// Context of a WAL's main DB, when the main DB is open through this
+/// VFS. The WAL follows its page geometry and keys.
+unsafe fn wal_db_context<'a>(
+    global: &EvfsGlobal,
+    z_name: *const c_char,
+) -> Option<&'a FileContext> {
+    if z_name.is_null() {
+        return None;
+    }
+    unsafe {
+        let db_file = sqlite3_database_file_object(z_name);
+        if db_file.is_null() || !ptr::eq((*db_file).pMethods, &global.io_methods) {
+            return None;
+        }
+        Some(&*(*(db_file as *mut EvfsFile)).ctx)
+    }
+}
+
+/// Value of the URI parameter `key` of a main DB, if set and not
+/// empty.
+unsafe fn uri_parameter(z_name: *const c_char, key: &CStr) -> Option<String> {
+    unsafe {
+        let value = sqlite3_uri_parameter(z_name, key.as_ptr());
+        if value.is_null() {
+            return None;
+        }
+        let value = CStr::from_ptr(value).to_string_lossy().into_owned();
+        (!value.is_empty()).then_some(value)
+    }
+}
+
+/// Keyring and file-wide scope of the main DB at `db_path`, from its
+/// URI parameters:
+///
+/// - `evfs_scope=<name>` encrypts the pages no table scope claims
+///   under `KeyScope::Named(name)` instead of `KeyScope::Database`.
+/// - `evfs_keyfile=<path>` wraps the database's DEKs under the KEK in
+///   that keyfile instead of the VFS's provider.
+///
+/// The keyring is bound to the database's sidecar for as long as the
+/// database is open, and shared by the connections to it, so DEKs it
+/// creates never land in another database's sidecar. Other parameters
+/// are SQLite's, or unknown and ignored.
+unsafe fn main_db_keys(
+    config: &EvfsConfig,
+    z_name: *const c_char,
+    db_path: &Path,
+) -> Result<(Arc<Keyring>, KeyScope), String> {
+    let (scope, keyfile) = unsafe {
+        (
+            uri_parameter(z_name, c"evfs_scope"),
+            uri_parameter(z_name, c"evfs_keyfile").map(PathBuf::from),
+        )
+    };
+    let scope = scope.map_or(KeyScope::Database, KeyScope::Named);
+
+    // A sidecar failing authentication may hold DEKs an attacker
+    // knows; never write pages under them, nor create new ones.
+    let keyring = match keyfile {
+        Some(path) => {
+            let provider = DeviceKeyProvider::from_keyfile(path.clone());
+            // Fail the open now rather than on the first page read.
+            provider
+                .get_kek()
+                .map_err(|e| format!("evfs_keyfile {}: {e}", path.display()))?;
+            let keyring = config.keyring.sibling(Arc::new(provider));
+            keyring.set_sidecar_path(db_path).map(|()| Arc::new(keyring))
+        }
+        None => config.keyring.for_database(db_path),
+    };
+    Ok((keyring.map_err(|e| format!("{e:#}"))?, scope))
+}
+
 unsafe extern "C" fn evfs_open(
     vfs: *mut sqlite3_vfs,
     z_name: *const c_char,
@@ -174,12 +464,62 @@ unsafe extern "C" fn evfs_open(
     p_out_flags: *mut c_int,
 ) -> c_int {
     unsafe {
-        let encrypt_enabled = (flags & SQLITE_OPEN_MAIN_DB) != 0;
+        let main_db = (flags & SQLITE_OPEN_MAIN_DB) != 0;
+        let wal = (flags & SQLITE_OPEN_WAL) != 0;
+        let temp = (flags
+            & (SQLITE_OPEN_TEMP_DB
+                | SQLITE_OPEN_TRANSIENT_DB
+                | SQLITE_OPEN_TEMP_JOURNAL
+                | SQLITE_OPEN_SUBJOURNAL))
+            != 0;
 
         let global = &*((*vfs).pAppData as *const EvfsGlobal);
+        let config = global.config.read().clone();
         let inner_vfs = global.inner_vfs;
         let efile = file as *mut EvfsFile;
 
+        // Pages of a database with an unfinished rekey are split between
+        // two DEKs; only the rekey itself may touch it.
+        if main_db
+            && !z_name.is_null()
+            && let Ok(s) = CStr::from_ptr(z_name).to_str()
+            && crate::rekey::rekey_pending(Path::new(s))
+        {
+            return report(
+                SQLITE_CANTOPEN,
+                format!("evfs: {s} has an interrupted rekey; run rekey_database to finish it"),
+            );
+        }
+
+        let wal_db = if wal {
+            wal_db_context(global, z_name)
+        } else {
+            None
+        };
+        let db_path = if main_db && !z_name.is_null() {
+            CStr::from_ptr(z_name).to_str().ok().map(PathBuf::from)
+        } else {
+            None
+        };
+        let (keyring, file_scope) = if let Some(path) = db_path.as_deref() {
+            match main_db_keys(&config, z_name, path) {
+                Ok(keys) => keys,
+                Err(e) => {
+                    return report(
+                        SQLITE_CANTOPEN,
+                        format!(
+                            "evfs: cannot open {}: {e}",
+                            CStr::from_ptr(z_name).to_string_lossy()
+                        ),
+                    );
+                }
+            }
+        } else if let Some(db_ctx) = wal_db {
+            (db_ctx.keyring.clone(), db_ctx.file_scope.clone())
+        } else {
+            (config.keyring.clone(), KeyScope::Database)
+        };
+
         // Allocate the inner file buffer.
         let inner_sz = (*inner_vfs).szOsFile as usize;
         let inner_buf = libc::malloc(inner_sz) as *mut sqlite3_file;
@@ -196,41 +536,89 @@ unsafe extern "C" fn evfs_open(
             return rc;
         }
 
-        // Only pre-create page 1 for a brand new MAIN database file.
-        // Never do this for journals/WAL/temp files.
-        if encrypt_enabled && (flags & SQLITE_OPEN_CREATE) != 0 {
-            let rc = try_reserve_page1(global, inner_buf);
-            if rc != SQLITE_OK {
-                // Close inner file then free buffer.
-                let _ = ((*(*inner_buf).pMethods).xClose.unwrap())(inner_buf);
-                libc::free(inner_buf as *mut c_void);
-                return rc;
+        // The inner VFS falls back to read-only, and says so in the out
+        // flags, when the file can't be opened for writing.
+        let out_flags = if p_out_flags.is_null() {
+            flags
+        } else {
+            *p_out_flags
+        };
+        let read_only = (out_flags & SQLITE_OPEN_READONLY) != 0;
+
+        // An existing DB's header, or its sidecar if it conceals the
+        // header, decides its page geometry, and a WAL follows its DB;
+        // the builder's values only seed new files.
+        let (page_size, reserve_size, conceal_header) = if main_db {
+            match db_page_geometry(&config, inner_buf, db_path.as_deref()) {
+                Ok(geometry) => geometry,
+                Err(e) => {
+                    let _ = ((*(*inner_buf).pMethods).xClose.unwrap())(inner_buf);
+                    libc::free(inner_buf as *mut c_void);
+                    return report(
+                        SQLITE_CANTOPEN,
+                        format!(
+                            "evfs: cannot open {}: {e}",
+                            CStr::from_ptr(z_name).to_string_lossy()
+                        ),
+                    );
+                }
             }
-        }
+        } else if let Some(db_ctx) = wal_db {
+            (db_ctx.page_size, db_ctx.reserve_size, db_ctx.conceal_header)
+        } else {
+            (config.page_size, config.reserve_size, false)
+        };
 
         // Build our per-file context.
         let ctx = Box::into_raw(Box::new(FileContext {
-            keyring: global.keyring.clone(),
-            page_size: global.page_size,
-            reserve_size: global.reserve_size,
-            encrypt_enabled,
+            keyring,
+            page_size,
+            reserve_size,
+            encrypt_enabled: main_db || wal || temp,
+            wal,
+            temp: temp.then(|| TempCipher::new(config.page_size as usize)),
+            read_only,
+            allow_mmap: config.allow_mmap,
+            conceal_header,
+            legacy_pages: legacy_pages_enabled(),
+            db_path: None,
+            database_id: wal_db.and_then(|db_ctx| db_ctx.database_id),
+            file_scope,
+            scoped_tables: if main_db {
+                config.table_scopes.clone()
+            } else {
+                Vec::new()
+            },
             page_scope_map: None,
+            written_scopes: HashMap::new(),
         }));
 
-        // Bind the keyring sidecar only to the MAIN DB file.
-        // SQLite will open additional files (journal, wal, shm, temp) and
-        // we must not overwrite the shared keyring's sidecar path.
-        if encrypt_enabled && !z_name.is_null() {
-            let name = CStr::from_ptr(z_name);
-            if let Ok(s) = name.to_str() {
-                let path = std::path::Path::new(s);
-                (*ctx).keyring.set_sidecar_path(path);
+        // Only the MAIN DB file has a sidecar; SQLite's other files
+        // (journal, wal, shm, temp) use its keyring or none.
+        if let Some(path) = db_path {
+            (*ctx).database_id = keyring::database_id_of(&path);
+            (*ctx).db_path = Some(path);
+        }
+
+        // Only pre-create page 1 for a brand new MAIN database file.
+        // Never do this for journals/WAL/temp files.
+        if main_db && !read_only {
+            let rc = init_new_db(&mut *ctx, flags, inner_buf);
+            if rc != SQLITE_OK {
+                // Drop the context, close inner file then free buffer.
+                drop(Box::from_raw(ctx));
+                let _ = ((*(*inner_buf).pMethods).xClose.unwrap())(inner_buf);
+                libc::free(inner_buf as *mut c_void);
+                return rc;
             }
         }
 
+        global.open_files.fetch_add(1, Ordering::SeqCst);
         (*efile).base.pMethods = &global.io_methods;
         (*efile).inner_file = inner_buf;
         (*efile).ctx = ctx;
+        (*efile).global = global;
+        (*efile).lock_level = SQLITE_LOCK_NONE;
 
         SQLITE_OK
     }
@@ -257,12 +645,48 @@ unsafe extern "C" fn evfs_close(file: *mut sqlite3_file) -> c_int {
         if !(*efile).ctx.is_null() {
             drop(Box::from_raw((*efile).ctx));
             (*efile).ctx = ptr::null_mut();
+            (*(*efile).global).open_files.fetch_sub(1, Ordering::SeqCst);
         }
 
         rc
     }
 }
 
+/// The result code for a failure to encrypt or decrypt a page:
+/// `SQLITE_READONLY` if the page's DEK is missing and can't be created,
+/// `SQLITE_IOERR_AUTH` if the page failed authentication, otherwise
+/// `rc`.
+fn page_crypto_rc(e: &anyhow::Error, rc: c_int) -> c_int {
+    if e.is::<crate::keyring::SidecarReadOnly>() {
+        SQLITE_READONLY
+    } else if e.is::<page::AuthenticationFailed>() {
+        SQLITE_IOERR_AUTH
+    } else {
+        rc
+    }
+}
+
+// ── Error reporting ─────────────────────────────────────────────────
+
+thread_local! {
+    /// The last failure reported on this thread, for xGetLastError.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Report why a VFS call fails with `rc`, and return `rc`.
+///
+/// Host applications rarely see the `log` crate's output, so the
+/// message also goes to SQLite's error log (`SQLITE_CONFIG_LOG`), and
+/// is kept for `xGetLastError`. That takes no file, so it reports the
+/// last failure on the calling thread, as errno does.
+fn report(rc: c_int, message: String) -> c_int {
+    log::error!("{message}");
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    unsafe { sqlite3_log(rc, c"%s".as_ptr(), message.as_ptr()) };
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    rc
+}
+
 // ── Partial page offset helpers ────────────────────────────────────
 
 fn page_no_for_offset(i_ofst: i64, page_size: i64) -> u32 {
@@ -289,6 +713,12 @@ unsafe extern "C" fn evfs_read(
         if !ctx.encrypt_enabled {
             return ((*(*inner).pMethods).xRead.unwrap())(inner, buf, i_amt, i_ofst);
         }
+        if ctx.wal {
+            return wal_read(inner, ctx, buf, i_amt, i_ofst);
+        }
+        if let Some(temp) = &ctx.temp {
+            return temp_read(inner, temp, buf, i_amt, i_ofst);
+        }
 
         let page_size = ctx.page_size as i64;
         let amt = i_amt as usize;
@@ -301,14 +731,12 @@ unsafe extern "C" fn evfs_read(
             }
 
             let page_no = page_no_for_offset(i_ofst, page_size);
-            if page_no != 1 {
-                let slice = std::slice::from_raw_parts_mut(buf as *mut u8, amt);
-                if is_encrypted_page(slice, ctx.reserve_size)
-                    && let Err(e) = ctx.decrypt_page(slice, page_no)
-                {
-                    log::error!("evfs xRead decrypt page {page_no}: {e}");
-                    return SQLITE_IOERR_READ;
-                }
+            let slice = std::slice::from_raw_parts_mut(buf as *mut u8, amt);
+            if let Err(e) = ctx.read_page(slice, page_no) {
+                return report(
+                    page_crypto_rc(&e, SQLITE_IOERR_READ),
+                    format!("evfs xRead decrypt page {page_no}: {e}"),
+                );
             }
 
             return SQLITE_OK;
@@ -333,7 +761,7 @@ unsafe extern "C" fn evfs_read(
             let seg_len = (seg_end - seg_start) as usize;
 
             // Read full page into temp.
-            let mut page_buf = vec![0u8; ctx.page_size as usize];
+            let mut page_buf = Zeroizing::new(vec![0u8; ctx.page_size as usize]);
             let rc = ((*(*inner).pMethods).xRead.unwrap())(
                 inner,
                 page_buf.as_mut_ptr() as *mut c_void,
@@ -346,13 +774,11 @@ unsafe extern "C" fn evfs_read(
             }
 
             // Decrypt if needed.
-            if page_no != 1
-                && !short_read
-                && is_encrypted_page(&page_buf, ctx.reserve_size)
-                && let Err(e) = ctx.decrypt_page(&mut page_buf, page_no)
-            {
-                log::error!("evfs decrypt page {page_no}: {e}");
-                return SQLITE_IOERR_READ;
+            if !short_read && let Err(e) = ctx.read_page(&mut page_buf, page_no) {
+                return report(
+                    page_crypto_rc(&e, SQLITE_IOERR_READ),
+                    format!("evfs decrypt page {page_no}: {e}"),
+                );
             }
 
             let in_page_off = (seg_start - p_start) as usize;
@@ -378,26 +804,43 @@ unsafe extern "C" fn evfs_write(
     unsafe {
         let efile = file as *mut EvfsFile;
         let inner = (*efile).inner_file;
-        let ctx = &*(*efile).ctx;
+        let ctx = &mut *(*efile).ctx;
 
         if !ctx.encrypt_enabled {
             // Pass-through entirely.
             return ((*(*inner).pMethods).xWrite.unwrap())(inner, buf, i_amt, i_ofst);
         }
+        if ctx.wal {
+            return wal_write(inner, ctx, buf, i_amt, i_ofst);
+        }
+        if let Some(temp) = &mut ctx.temp {
+            return temp_write(inner, temp, buf, i_amt, i_ofst);
+        }
 
         let page_size = ctx.page_size as i64;
         let amt = i_amt as usize;
 
+        if !ctx.scoped_tables.is_empty() && ctx.page_scope_map.is_none() {
+            refresh_page_scope_map(inner, ctx);
+        }
+
         // Fast path: full aligned page write.
         if i_amt as u32 == ctx.page_size && i_ofst % page_size == 0 {
             let page_no = page_no_for_offset(i_ofst, page_size);
-            let mut page_buf = std::slice::from_raw_parts(buf as *const u8, amt).to_vec();
+            let mut page_buf =
+                Zeroizing::new(std::slice::from_raw_parts(buf as *const u8, amt).to_vec());
 
             if page_no == 1 && ctx.reserve_size <= u8::MAX as usize && page_buf.len() >= 21 {
                 page_buf[20] = ctx.reserve_size as u8;
-            } else if let Err(e) = ctx.encrypt_page(&mut page_buf, page_no) {
-                log::error!("evfs xWrite encrypt page {page_no}: {e}");
-                return SQLITE_IOERR_WRITE;
+            }
+            if !ctx.is_plaintext_page(page_no) {
+                if let Err(e) = ctx.encrypt_page(&mut page_buf, page_no) {
+                    return report(
+                        page_crypto_rc(&e, SQLITE_IOERR_WRITE),
+                        format!("evfs xWrite encrypt page {page_no}: {e}"),
+                    );
+                }
+                ctx.note_written(page_no);
             }
 
             return ((*(*inner).pMethods).xWrite.unwrap())(
@@ -429,7 +872,7 @@ unsafe extern "C" fn evfs_write(
             let in_page_off = (seg_start - p_start) as usize;
 
             // Load existing page (full), unless the segment covers entire page.
-            let mut page_buf = vec![0u8; ctx.page_size as usize];
+            let mut page_buf = Zeroizing::new(vec![0u8; ctx.page_size as usize]);
             let covers_whole_page = seg_len == ctx.page_size as usize && in_page_off == 0;
 
             if !covers_whole_page {
@@ -445,13 +888,11 @@ unsafe extern "C" fn evfs_write(
                 }
 
                 // Decrypt if needed.
-                if page_no != 1
-                    && !short_read
-                    && is_encrypted_page(&page_buf, ctx.reserve_size)
-                    && let Err(e) = ctx.decrypt_page(&mut page_buf, page_no)
-                {
-                    log::error!("evfs decrypt page {page_no}: {e}");
-                    return SQLITE_IOERR_WRITE;
+                if !short_read && let Err(e) = ctx.read_page(&mut page_buf, page_no) {
+                    return report(
+                        page_crypto_rc(&e, SQLITE_IOERR_WRITE),
+                        format!("evfs decrypt page {page_no}: {e}"),
+                    );
                 }
             } else {
                 // If whole-page overwrite, start from new plaintext bytes.
@@ -463,16 +904,19 @@ unsafe extern "C" fn evfs_write(
                 .copy_from_slice(&inp[in_cursor..in_cursor + seg_len]);
             in_cursor += seg_len;
 
-            // Page 1 stays plaintext, but we still force reserved bytes header.
-            if page_no == 1 {
-                if ctx.reserve_size <= u8::MAX as usize && page_buf.len() >= 21 {
-                    page_buf[20] = ctx.reserve_size as u8;
-                }
-            } else {
+            // Page 1 stays plaintext unless the header is concealed, and
+            // either way we force the reserved bytes header.
+            if page_no == 1 && ctx.reserve_size <= u8::MAX as usize && page_buf.len() >= 21 {
+                page_buf[20] = ctx.reserve_size as u8;
+            }
+            if !ctx.is_plaintext_page(page_no) {
                 if let Err(e) = ctx.encrypt_page(&mut page_buf, page_no) {
-                    log::error!("evfs xWrite encrypt page {page_no}: {e}");
-                    return SQLITE_IOERR_WRITE;
+                    return report(
+                        page_crypto_rc(&e, SQLITE_IOERR_WRITE),
+                        format!("evfs xWrite encrypt page {page_no}: {e}"),
+                    );
                 }
+                ctx.note_written(page_no);
             }
 
             let rc = ((*(*inner).pMethods).xWrite.unwrap())(
@@ -491,24 +935,518 @@ unsafe extern "C" fn evfs_write(
     }
 }
 
-// ── Forwarded I/O methods ───────────────────────────────────────────
+// ── Table key scopes ────────────────────────────────────────────────
+//
+// Pages are encrypted under the scope the map gives them when written.
+// A page newly allocated to a scoped table isn't in the map yet, so
+// before the file is synced - when the transaction's pages have all
+// been written, but in rollback-journal mode before the journal is
+// finalized - the map is rebuilt from the file and any page written
+// under the wrong scope is re-encrypted.
+
+/// Rebuild the page→scope map by walking the scoped tables' b-trees.
+/// If the walk fails the old map is kept: pages written under a stale
+/// scope stay readable, just not under the intended DEK.
+unsafe fn refresh_page_scope_map(inner: *mut sqlite3_file, ctx: &mut FileContext) {
+    let page_size = ctx.page_size as usize;
+    if unsafe { inner_filesize(inner) }.unwrap_or(0) < page_size as i64 {
+        ctx.build_page_scope_map(&[]);
+        return;
+    }
+
+    let read = |page_no: u32| -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let mut page = Zeroizing::new(vec![0u8; page_size]);
+        let rc = unsafe {
+            ((*(*inner).pMethods).xRead.unwrap())(
+                inner,
+                page.as_mut_ptr() as *mut c_void,
+                page_size as c_int,
+                page_start_offset(page_no, page_size as i64),
+            )
+        };
+        anyhow::ensure!(rc == SQLITE_OK, "read page {page_no}: {rc}");
+        ctx.read_page(&mut page, page_no)?;
+        Ok(page)
+    };
+    match crate::btree::table_pages(read, page_size - ctx.reserve_size, &ctx.scoped_tables) {
+        Ok(pages) => ctx.build_page_scope_map(&pages),
+        Err(e) => log::warn!("evfs: could not map the pages of scoped tables: {e}"),
+    }
+}
+
+/// Rebuild the map and re-encrypt pages written since the last rebuild
+/// whose scope has changed.
+unsafe fn settle_page_scopes(inner: *mut sqlite3_file, ctx: &mut FileContext) -> c_int {
+    if ctx.written_scopes.is_empty() {
+        return SQLITE_OK;
+    }
+    unsafe {
+        refresh_page_scope_map(inner, ctx);
+
+        let page_size = ctx.page_size as i64;
+        let file_size = inner_filesize(inner).unwrap_or(0);
+        for (page_no, used) in std::mem::take(&mut ctx.written_scopes) {
+            let offset = page_start_offset(page_no, page_size);
+            if ctx.scope_for(page_no) == used || offset + page_size > file_size {
+                continue;
+            }
+
+            let mut page_buf = Zeroizing::new(vec![0u8; ctx.page_size as usize]);
+            let rc = ((*(*inner).pMethods).xRead.unwrap())(
+                inner,
+                page_buf.as_mut_ptr() as *mut c_void,
+                ctx.page_size as c_int,
+                offset,
+            );
+            if rc != SQLITE_OK {
+                return rc;
+            }
+            if !is_encrypted_page(&page_buf, ctx.reserve_size) {
+                continue;
+            }
+            if let Err(e) = ctx.decrypt_page(&mut page_buf, page_no) {
+                return report(
+                    page_crypto_rc(&e, SQLITE_IOERR_READ),
+                    format!("evfs decrypt page {page_no} to change its scope: {e}"),
+                );
+            }
+            if let Err(e) = ctx.encrypt_page(&mut page_buf, page_no) {
+                return report(
+                    page_crypto_rc(&e, SQLITE_IOERR_WRITE),
+                    format!("evfs encrypt page {page_no} under its new scope: {e}"),
+                );
+            }
+            let rc = ((*(*inner).pMethods).xWrite.unwrap())(
+                inner,
+                page_buf.as_ptr() as *const c_void,
+                ctx.page_size as c_int,
+                offset,
+            );
+            if rc != SQLITE_OK {
+                return rc;
+            }
+        }
+        SQLITE_OK
+    }
+}
+
+// ── Temp files ──────────────────────────────────────────────────────
+//
+// Temp files are sealed block by block under their file's ephemeral
+// DEK (see `crypto::temp`). Reads and writes may start and end
+// anywhere, and the file stays the length SQLite wrote.
+
+/// Read the sealed bytes of `block` and decrypt them, into a buffer
+/// with room for the whole block so patching it never reallocates.
+unsafe fn temp_open_block(
+    inner: *mut sqlite3_file,
+    temp: &TempCipher,
+    block: u64,
+    len: usize,
+) -> Result<Zeroizing<Vec<u8>>, c_int> {
+    let mut data = Zeroizing::new(Vec::with_capacity(temp.block_size()));
+    data.resize(len, 0);
+    let rc = unsafe {
+        ((*(*inner).pMethods).xRead.unwrap())(
+            inner,
+            data.as_mut_ptr() as *mut c_void,
+            len as c_int,
+            (block * temp.block_size() as u64) as i64,
+        )
+    };
+    if rc != SQLITE_OK {
+        return Err(report(SQLITE_IOERR_READ, format!("evfs read temp block {block}: {rc}")));
+    }
+    if let Err(e) = temp.open(block, &mut data) {
+        return Err(report(SQLITE_IOERR_READ, format!("evfs temp block {block}: {e}")));
+    }
+    Ok(data)
+}
+
+/// Seal `data` as the content of `block` and write it.
+unsafe fn temp_seal_block(
+    inner: *mut sqlite3_file,
+    temp: &mut TempCipher,
+    block: u64,
+    mut data: Zeroizing<Vec<u8>>,
+) -> c_int {
+    if let Err(e) = temp.seal(block, &mut data) {
+        return report(SQLITE_IOERR_WRITE, format!("evfs temp block {block}: {e}"));
+    }
+    unsafe {
+        ((*(*inner).pMethods).xWrite.unwrap())(
+            inner,
+            data.as_ptr() as *const c_void,
+            data.len() as c_int,
+            (block * temp.block_size() as u64) as i64,
+        )
+    }
+}
+
+unsafe fn temp_read(
+    inner: *mut sqlite3_file,
+    temp: &TempCipher,
+    buf: *mut c_void,
+    i_amt: c_int,
+    i_ofst: i64,
+) -> c_int {
+    unsafe {
+        // Read through first for SQLite's short-read semantics, then
+        // overlay the plaintext of every sealed block in range.
+        let rc = ((*(*inner).pMethods).xRead.unwrap())(inner, buf, i_amt, i_ofst);
+        if rc != SQLITE_OK && rc != SQLITE_IOERR_SHORT_READ {
+            return rc;
+        }
+        let out = std::slice::from_raw_parts_mut(buf as *mut u8, i_amt as usize);
+        let block_size = temp.block_size() as i64;
+        let end = i_ofst + i_amt as i64;
+
+        let mut pos = i_ofst;
+        while pos < end {
+            let block = (pos / block_size) as u64;
+            let start = block as i64 * block_size;
+            let seg_end = end.min(start + block_size);
+            if let Some(len) = temp.sealed_len(block) {
+                let data = match temp_open_block(inner, temp, block, len) {
+                    Ok(data) => data,
+                    Err(rc) => return rc,
+                };
+                let from = (pos - start) as usize;
+                let to = ((seg_end - start) as usize).min(len);
+                if from < to {
+                    let out_off = (pos - i_ofst) as usize;
+                    out[out_off..out_off + to - from].copy_from_slice(&data[from..to]);
+                }
+            }
+            pos = seg_end;
+        }
+        rc
+    }
+}
+
+unsafe fn temp_write(
+    inner: *mut sqlite3_file,
+    temp: &mut TempCipher,
+    buf: *const c_void,
+    i_amt: c_int,
+    i_ofst: i64,
+) -> c_int {
+    unsafe {
+        let inp = std::slice::from_raw_parts(buf as *const u8, i_amt as usize);
+        let block_size = temp.block_size() as i64;
+        let end = i_ofst + i_amt as i64;
+
+        let mut pos = i_ofst;
+        while pos < end {
+            let block = (pos / block_size) as u64;
+            let start = block as i64 * block_size;
+            let seg_end = end.min(start + block_size);
+
+            // Patch the block's current plaintext, growing it to cover
+            // the write; bytes never written read back as zeros.
+            let mut data = match temp.sealed_len(block) {
+                Some(len) => match temp_open_block(inner, temp, block, len) {
+                    Ok(data) => data,
+                    Err(rc) => return rc,
+                },
+                None => Zeroizing::new(Vec::with_capacity(temp.block_size())),
+            };
+            let from = (pos - start) as usize;
+            let to = (seg_end - start) as usize;
+            if data.len() < to {
+                data.resize(to, 0);
+            }
+            let in_off = (pos - i_ofst) as usize;
+            data[from..to].copy_from_slice(&inp[in_off..in_off + to - from]);
+
+            let rc = temp_seal_block(inner, temp, block, data);
+            if rc != SQLITE_OK {
+                return rc;
+            }
+            pos = seg_end;
+        }
+        SQLITE_OK
+    }
+}
+
+unsafe fn temp_truncate(inner: *mut sqlite3_file, temp: &mut TempCipher, size: i64) -> c_int {
+    unsafe {
+        // Reseal the block the new end cuts through, then forget the
+        // ones past it.
+        let block = (size / temp.block_size() as i64) as u64;
+        let keep = (size - block as i64 * temp.block_size() as i64) as usize;
+        if let Some(len) = temp.sealed_len(block)
+            && keep > 0
+            && keep < len
+        {
+            let mut data = match temp_open_block(inner, temp, block, len) {
+                Ok(data) => data,
+                Err(rc) => return rc,
+            };
+            data.truncate(keep);
+            let rc = temp_seal_block(inner, temp, block, data);
+            if rc != SQLITE_OK {
+                return rc;
+            }
+        }
+        temp.truncate(size as u64);
+        ((*(*inner).pMethods).xTruncate.unwrap())(inner, size)
+    }
+}
+
+// ── WAL frames ──────────────────────────────────────────────────────
+//
+// A WAL file is a 32-byte header followed by frames, each a 24-byte
+// frame header (starting with the page number) and one page image.
+// Headers pass through unchanged so SQLite can checksum and recover
+// them; page images are encrypted like the main DB's pages, under
+// the page number from their frame header.
+
+const WAL_HEADER_SIZE: i64 = 32;
+const WAL_FRAME_HEADER_SIZE: i64 = 24;
+
+/// Where a WAL offset falls.
+enum WalRegion {
+    /// WAL or frame header bytes in `start..end`.
+    Header { end: i64 },
+    /// The page image of the frame starting at `frame`.
+    Page { frame: i64, start: i64 },
+}
+
+fn wal_region(ofst: i64, page_size: i64) -> WalRegion {
+    if ofst < WAL_HEADER_SIZE {
+        return WalRegion::Header {
+            end: WAL_HEADER_SIZE,
+        };
+    }
+    let frame_size = WAL_FRAME_HEADER_SIZE + page_size;
+    let frame = WAL_HEADER_SIZE + (ofst - WAL_HEADER_SIZE) / frame_size * frame_size;
+    let start = frame + WAL_FRAME_HEADER_SIZE;
+    if ofst < start {
+        WalRegion::Header { end: start }
+    } else {
+        WalRegion::Page { frame, start }
+    }
+}
+
+/// Page number from the header of the frame at `frame`, preferring
+/// the bytes being written in `pending` (starting at `pending_ofst`).
+unsafe fn wal_frame_page_no(
+    inner: *mut sqlite3_file,
+    frame: i64,
+    pending: &[u8],
+    pending_ofst: i64,
+) -> Result<u32, c_int> {
+    let mut page_no = [0u8; 4];
+    let rel = frame - pending_ofst;
+    if rel >= 0 && rel as usize + 4 <= pending.len() {
+        page_no.copy_from_slice(&pending[rel as usize..rel as usize + 4]);
+    } else {
+        let rc = unsafe {
+            ((*(*inner).pMethods).xRead.unwrap())(
+                inner,
+                page_no.as_mut_ptr() as *mut c_void,
+                4,
+                frame,
+            )
+        };
+        if rc != SQLITE_OK && rc != SQLITE_IOERR_SHORT_READ {
+            return Err(rc);
+        }
+    }
+    Ok(u32::from_be_bytes(page_no))
+}
 
-macro_rules! forward_io {
-    ($sym:expr, $name:ident ( $($arg:ident : $ty:ty),* ) -> c_int) => {
-        #[allow(non_snake_case)]
-        unsafe extern "C" fn $name(
-            file: *mut sqlite3_file,
-            $( $arg: $ty, )*
-        ) -> c_int { unsafe {
-            let efile = file as *mut EvfsFile;
-            let inner = (*efile).inner_file;
-            ((*(*inner).pMethods).$name.unwrap())(inner, $( $arg, )*)
-        }}
+/// Read the page image at `start` and decrypt it. `Ok(false)` on a
+/// short read, leaving the missing bytes zeroed.
+unsafe fn wal_read_page(
+    inner: *mut sqlite3_file,
+    ctx: &FileContext,
+    frame: i64,
+    start: i64,
+    page_buf: &mut [u8],
+) -> Result<bool, c_int> {
+    let rc = unsafe {
+        ((*(*inner).pMethods).xRead.unwrap())(
+            inner,
+            page_buf.as_mut_ptr() as *mut c_void,
+            page_buf.len() as c_int,
+            start,
+        )
     };
+    let short_read = rc == SQLITE_IOERR_SHORT_READ;
+    if rc != SQLITE_OK && !short_read {
+        return Err(rc);
+    }
+    if !short_read && is_encrypted_page(page_buf, ctx.reserve_size) {
+        let page_no = unsafe { wal_frame_page_no(inner, frame, &[], 0)? };
+        if let Err(e) = ctx.decrypt_page(page_buf, page_no) {
+            return Err(report(
+                page_crypto_rc(&e, SQLITE_IOERR_READ),
+                format!("evfs decrypt WAL frame at {frame} (page {page_no}): {e}"),
+            ));
+        }
+    }
+    Ok(!short_read)
 }
 
-forward_io!("xTruncate", xTruncate(size: i64) -> c_int);
-forward_io!("xSync", xSync(flags: c_int) -> c_int);
+unsafe fn wal_read(
+    inner: *mut sqlite3_file,
+    ctx: &FileContext,
+    buf: *mut c_void,
+    i_amt: c_int,
+    i_ofst: i64,
+) -> c_int {
+    unsafe {
+        let page_size = ctx.page_size as i64;
+        let out = std::slice::from_raw_parts_mut(buf as *mut u8, i_amt as usize);
+        let end = i_ofst + i_amt as i64;
+
+        let mut short_read = false;
+        let mut pos = i_ofst;
+        while pos < end {
+            let out_off = (pos - i_ofst) as usize;
+            match wal_region(pos, page_size) {
+                WalRegion::Header { end: header_end } => {
+                    let seg_end = end.min(header_end);
+                    let rc = ((*(*inner).pMethods).xRead.unwrap())(
+                        inner,
+                        out[out_off..].as_mut_ptr() as *mut c_void,
+                        (seg_end - pos) as c_int,
+                        pos,
+                    );
+                    if rc == SQLITE_IOERR_SHORT_READ {
+                        short_read = true;
+                    } else if rc != SQLITE_OK {
+                        return rc;
+                    }
+                    pos = seg_end;
+                }
+                WalRegion::Page { frame, start } => {
+                    let seg_end = end.min(start + page_size);
+                    let mut page_buf = Zeroizing::new(vec![0u8; ctx.page_size as usize]);
+                    match wal_read_page(inner, ctx, frame, start, &mut page_buf) {
+                        Ok(full) => short_read |= !full,
+                        Err(rc) => return rc,
+                    }
+                    let in_page_off = (pos - start) as usize;
+                    let seg_len = (seg_end - pos) as usize;
+                    out[out_off..out_off + seg_len]
+                        .copy_from_slice(&page_buf[in_page_off..in_page_off + seg_len]);
+                    pos = seg_end;
+                }
+            }
+        }
+
+        if short_read {
+            SQLITE_IOERR_SHORT_READ
+        } else {
+            SQLITE_OK
+        }
+    }
+}
+
+unsafe fn wal_write(
+    inner: *mut sqlite3_file,
+    ctx: &FileContext,
+    buf: *const c_void,
+    i_amt: c_int,
+    i_ofst: i64,
+) -> c_int {
+    unsafe {
+        let page_size = ctx.page_size as i64;
+        let inp = std::slice::from_raw_parts(buf as *const u8, i_amt as usize);
+        let end = i_ofst + i_amt as i64;
+
+        let mut pos = i_ofst;
+        while pos < end {
+            let in_off = (pos - i_ofst) as usize;
+            match wal_region(pos, page_size) {
+                WalRegion::Header { end: header_end } => {
+                    let seg_end = end.min(header_end);
+                    let rc = ((*(*inner).pMethods).xWrite.unwrap())(
+                        inner,
+                        inp[in_off..].as_ptr() as *const c_void,
+                        (seg_end - pos) as c_int,
+                        pos,
+                    );
+                    if rc != SQLITE_OK {
+                        return rc;
+                    }
+                    pos = seg_end;
+                }
+                WalRegion::Page { frame, start } => {
+                    let seg_end = end.min(start + page_size);
+                    let seg_len = (seg_end - pos) as usize;
+                    let in_page_off = (pos - start) as usize;
+
+                    let mut page_buf = Zeroizing::new(vec![0u8; ctx.page_size as usize]);
+                    if seg_len != page_buf.len()
+                        && let Err(rc) = wal_read_page(inner, ctx, frame, start, &mut page_buf)
+                    {
+                        return rc;
+                    }
+                    page_buf[in_page_off..in_page_off + seg_len]
+                        .copy_from_slice(&inp[in_off..in_off + seg_len]);
+
+                    // Page 1 is plaintext unless concealed, as in the main DB.
+                    let page_no = match wal_frame_page_no(inner, frame, inp, i_ofst) {
+                        Ok(page_no) => page_no,
+                        Err(rc) => return rc,
+                    };
+                    if page_no == 0 {
+                        log::warn!("evfs WAL frame at {frame} has no page number, not encrypting");
+                    } else if !ctx.is_plaintext_page(page_no)
+                        && let Err(e) = ctx.encrypt_page(&mut page_buf, page_no)
+                    {
+                        return report(
+                            page_crypto_rc(&e, SQLITE_IOERR_WRITE),
+                            format!("evfs encrypt WAL frame at {frame} (page {page_no}): {e}"),
+                        );
+                    }
+
+                    let rc = ((*(*inner).pMethods).xWrite.unwrap())(
+                        inner,
+                        page_buf.as_ptr() as *const c_void,
+                        ctx.page_size as c_int,
+                        start,
+                    );
+                    if rc != SQLITE_OK {
+                        return rc;
+                    }
+                    pos = seg_end;
+                }
+            }
+        }
+
+        SQLITE_OK
+    }
+}
+
+// ── Forwarded I/O methods ───────────────────────────────────────────
+
+unsafe extern "C" fn evfs_truncate(file: *mut sqlite3_file, size: i64) -> c_int {
+    unsafe {
+        let efile = file as *mut EvfsFile;
+        let inner = (*efile).inner_file;
+        if let Some(temp) = &mut (*(*efile).ctx).temp {
+            return temp_truncate(inner, temp, size);
+        }
+        ((*(*inner).pMethods).xTruncate.unwrap())(inner, size)
+    }
+}
+
+unsafe extern "C" fn evfs_sync(file: *mut sqlite3_file, flags: c_int) -> c_int {
+    unsafe {
+        let efile = file as *mut EvfsFile;
+        let inner = (*efile).inner_file;
+        let rc = settle_page_scopes(inner, &mut *(*efile).ctx);
+        if rc != SQLITE_OK {
+            return rc;
+        }
+        ((*(*inner).pMethods).xSync.unwrap())(inner, flags)
+    }
+}
 
 unsafe extern "C" fn evfs_file_size(file: *mut sqlite3_file, p_size: *mut i64) -> c_int {
     unsafe {
@@ -518,8 +1456,37 @@ unsafe extern "C" fn evfs_file_size(file: *mut sqlite3_file, p_size: *mut i64) -
     }
 }
 
-forward_io!("xLock", xLock(lock_type: c_int) -> c_int);
-forward_io!("xUnlock", xUnlock(lock_type: c_int) -> c_int);
+unsafe extern "C" fn evfs_lock(file: *mut sqlite3_file, lock_type: c_int) -> c_int {
+    unsafe {
+        let efile = file as *mut EvfsFile;
+        let inner = (*efile).inner_file;
+        let rc = ((*(*inner).pMethods).xLock.unwrap())(inner, lock_type);
+        if rc == SQLITE_OK {
+            (*efile).lock_level = lock_type;
+        }
+        rc
+    }
+}
+
+unsafe extern "C" fn evfs_unlock(file: *mut sqlite3_file, lock_type: c_int) -> c_int {
+    unsafe {
+        let efile = file as *mut EvfsFile;
+        let inner = (*efile).inner_file;
+        // With `synchronous=OFF` nothing is synced, so settle scopes
+        // before giving up the write lock instead.
+        if lock_type <= SQLITE_LOCK_SHARED {
+            let rc = settle_page_scopes(inner, &mut *(*efile).ctx);
+            if rc != SQLITE_OK {
+                return rc;
+            }
+        }
+        let rc = ((*(*inner).pMethods).xUnlock.unwrap())(inner, lock_type);
+        if rc == SQLITE_OK {
+            (*efile).lock_level = lock_type;
+        }
+        rc
+    }
+}
 
 unsafe extern "C" fn evfs_check_reserved_lock(
     file: *mut sqlite3_file,
@@ -553,10 +1520,164 @@ unsafe extern "C" fn evfs_file_control(
             return SQLITE_OK;
         }
 
+        // Admin verbs on a main database, as pragmas or our own opcodes.
+        let verb = match op {
+            SQLITE_FCNTL_PRAGMA => pragma_verb(p_arg as *mut *mut c_char),
+            EVFS_FCNTL_REKEY => Some(AdminVerb::Rekey),
+            EVFS_FCNTL_KEY_STATUS => Some(AdminVerb::KeyStatus),
+            EVFS_FCNTL_VERIFY => Some(AdminVerb::Verify),
+            _ => None,
+        };
+        if let Some(verb) = verb
+            && let Some(path) = (*(*efile).ctx).db_path.clone()
+        {
+            let result = match verb {
+                AdminVerb::Rekey => rekey_open_file(efile, &path).map(|r| r.to_string()),
+                AdminVerb::KeyStatus => {
+                    crate::rekey::scoped_key_status(&path, &(*(*efile).ctx).file_scope)
+                        .map(|s| s.to_string())
+                }
+                AdminVerb::Verify => verify_open_file(efile).map(|r| r.to_string()),
+            };
+            return admin_result(p_arg as *mut *mut c_char, result);
+        }
+
+        // Pin the mmap limit of encrypted files to 0 so the inner VFS
+        // never maps ciphertext; `PRAGMA mmap_size` then reports 0.
+        if op == SQLITE_FCNTL_MMAP_SIZE {
+            let ctx = &*(*efile).ctx;
+            if ctx.encrypt_enabled && !ctx.allow_mmap {
+                let p_size = p_arg as *mut i64;
+                if !p_size.is_null() {
+                    *p_size = 0;
+                }
+                return SQLITE_OK;
+            }
+        }
+
         ((*(*inner).pMethods).xFileControl.unwrap())(inner, op, p_arg)
     }
 }
 
+enum AdminVerb {
+    Rekey,
+    KeyStatus,
+    Verify,
+}
+
+/// The admin verb named by a `SQLITE_FCNTL_PRAGMA` argument array.
+unsafe fn pragma_verb(az_arg: *mut *mut c_char) -> Option<AdminVerb> {
+    unsafe {
+        if az_arg.is_null() || (*az_arg.add(1)).is_null() {
+            return None;
+        }
+        let name = CStr::from_ptr(*az_arg.add(1)).to_str().ok()?;
+        if name.eq_ignore_ascii_case("evfs_rekey") {
+            Some(AdminVerb::Rekey)
+        } else if name.eq_ignore_ascii_case("evfs_key_status") {
+            Some(AdminVerb::KeyStatus)
+        } else if name.eq_ignore_ascii_case("evfs_verify") {
+            Some(AdminVerb::Verify)
+        } else {
+            None
+        }
+    }
+}
+
+/// Hand an admin verb's outcome back through `*p_out`, the way
+/// `SQLITE_FCNTL_PRAGMA` expects: the result string on success, the
+/// error message otherwise.
+unsafe fn admin_result(p_out: *mut *mut c_char, result: anyhow::Result<String>) -> c_int {
+    let (rc, msg) = match result {
+        Ok(msg) => (SQLITE_OK, msg),
+        Err(e) => {
+            log::error!("evfs: {e:#}");
+            (SQLITE_ERROR, format!("{e:#}"))
+        }
+    };
+    if !p_out.is_null()
+        && let Ok(msg) = CString::new(msg)
+    {
+        unsafe { *p_out = sqlite3_mprintf(c"%s".as_ptr(), msg.as_ptr()) };
+    }
+    rc
+}
+
+/// Rekey the database open through `efile`, taking its exclusive lock
+/// on the inner file for the duration.
+unsafe fn rekey_open_file(
+    efile: *mut EvfsFile,
+    path: &Path,
+) -> anyhow::Result<crate::rekey::RekeyReport> {
+    unsafe {
+        let inner = (*efile).inner_file;
+        let ctx = &*(*efile).ctx;
+        let held = (*efile).lock_level;
+
+        // The pager only has dirty pages once it holds RESERVED, so
+        // below that everything it wrote has reached the file.
+        anyhow::ensure!(
+            held <= SQLITE_LOCK_SHARED,
+            "cannot rekey inside a write transaction; commit or roll back first"
+        );
+        let methods = &*(*inner).pMethods;
+        let rc = methods.xSync.unwrap()(inner, SQLITE_SYNC_FULL);
+        anyhow::ensure!(rc == SQLITE_OK, "sync before rekey: {rc}");
+
+        for level in [
+            SQLITE_LOCK_SHARED,
+            SQLITE_LOCK_RESERVED,
+            SQLITE_LOCK_EXCLUSIVE,
+        ] {
+            if level <= held {
+                continue;
+            }
+            let rc = methods.xLock.unwrap()(inner, level);
+            if rc != SQLITE_OK {
+                methods.xUnlock.unwrap()(inner, held);
+                anyhow::bail!("{} is in use by another connection ({rc})", path.display());
+            }
+        }
+        let result =
+            crate::rekey::rekey_file(inner, path, &ctx.keyring, &ctx.file_scope, None, None);
+        // A database from before IDs gets one when it is rekeyed.
+        if result.is_ok() {
+            (*(*efile).ctx).database_id = keyring::database_id_of(path);
+        }
+        methods.xUnlock.unwrap()(inner, held);
+        result
+    }
+}
+
+/// Scan the database open through `efile` for pages that fail to
+/// decrypt, holding at least a shared lock on the inner file meanwhile.
+unsafe fn verify_open_file(efile: *mut EvfsFile) -> anyhow::Result<crate::verify::VerifyResult> {
+    unsafe {
+        let inner = (*efile).inner_file;
+        let ctx = &*(*efile).ctx;
+        let held = (*efile).lock_level;
+
+        let deks = ctx.keyring.all_deks()?;
+        let methods = &*(*inner).pMethods;
+        if held < SQLITE_LOCK_SHARED {
+            let rc = methods.xLock.unwrap()(inner, SQLITE_LOCK_SHARED);
+            anyhow::ensure!(rc == SQLITE_OK, "database is locked ({rc})");
+        }
+        let result = crate::verify::verify_file(
+            inner,
+            ctx.page_size,
+            ctx.reserve_size,
+            &deks,
+            ctx.database_id,
+            ctx.is_plaintext_page(1),
+        );
+        if held < SQLITE_LOCK_SHARED {
+            methods.xUnlock.unwrap()(inner, held);
+        }
+        result
+    }
+}
+
 unsafe extern "C" fn evfs_sector_size(file: *mut sqlite3_file) -> c_int {
     unsafe {
         let efile = file as *mut EvfsFile;
@@ -569,7 +1690,68 @@ unsafe extern "C" fn evfs_device_characteristics(file: *mut sqlite3_file) -> c_i
     unsafe {
         let efile = file as *mut EvfsFile;
         let inner = (*efile).inner_file;
-        ((*(*inner).pMethods).xDeviceCharacteristics.unwrap())(inner)
+        (*(*inner).pMethods)
+            .xDeviceCharacteristics
+            .map_or(0, |f| f(inner))
+    }
+}
+
+// ── Shared memory (WAL index) ───────────────────────────────────────
+//
+// The -shm file holds only the WAL index, page numbers and checksums,
+// so it is forwarded to the inner file unencrypted.
+
+unsafe extern "C" fn evfs_shm_map(
+    file: *mut sqlite3_file,
+    i_pg: c_int,
+    pgsz: c_int,
+    b_extend: c_int,
+    pp: *mut *mut c_void,
+) -> c_int {
+    unsafe {
+        let efile = file as *mut EvfsFile;
+        let inner = (*efile).inner_file;
+        match (*(*inner).pMethods).xShmMap {
+            Some(f) => f(inner, i_pg, pgsz, b_extend, pp),
+            None => SQLITE_IOERR_SHMMAP,
+        }
+    }
+}
+
+unsafe extern "C" fn evfs_shm_lock(
+    file: *mut sqlite3_file,
+    offset: c_int,
+    n: c_int,
+    flags: c_int,
+) -> c_int {
+    unsafe {
+        let efile = file as *mut EvfsFile;
+        let inner = (*efile).inner_file;
+        match (*(*inner).pMethods).xShmLock {
+            Some(f) => f(inner, offset, n, flags),
+            None => SQLITE_IOERR_SHMLOCK,
+        }
+    }
+}
+
+unsafe extern "C" fn evfs_shm_barrier(file: *mut sqlite3_file) {
+    unsafe {
+        let efile = file as *mut EvfsFile;
+        let inner = (*efile).inner_file;
+        if let Some(f) = (*(*inner).pMethods).xShmBarrier {
+            f(inner);
+        }
+    }
+}
+
+unsafe extern "C" fn evfs_shm_unmap(file: *mut sqlite3_file, delete_flag: c_int) -> c_int {
+    unsafe {
+        let efile = file as *mut EvfsFile;
+        let inner = (*efile).inner_file;
+        match (*(*inner).pMethods).xShmUnmap {
+            Some(f) => f(inner, delete_flag),
+            None => SQLITE_OK,
+        }
     }
 }
 
@@ -642,11 +1824,24 @@ unsafe extern "C" fn evfs_get_last_error(
 ) -> c_int {
     unsafe {
         let global = &*((*vfs).pAppData as *const EvfsGlobal);
-        if let Some(f) = (*global.inner_vfs).xGetLastError {
+        let rc = if let Some(f) = (*global.inner_vfs).xGetLastError {
             f(global.inner_vfs, n_buf, z_buf)
         } else {
             SQLITE_OK
+        };
+        // Our own last failure says more than the inner VFS's errno.
+        // SQLite asks after every I/O error, so it is kept for the
+        // application to ask too.
+        if let Some(message) = LAST_ERROR.with(|last| last.borrow().clone())
+            && n_buf > 0
+            && !z_buf.is_null()
+        {
+            let message = message.as_bytes();
+            let len = message.len().min(n_buf as usize - 1);
+            ptr::copy_nonoverlapping(message.as_ptr(), z_buf as *mut u8, len);
+            *z_buf.add(len) = 0;
         }
+        rc
     }
 }
 
@@ -669,59 +1864,102 @@ unsafe extern "C" fn evfs_current_time_int64(vfs: *mut sqlite3_vfs, p_time: *mut
 
 // ── Registration ────────────────────────────────────────────────────
 
+/// Register an evfs VFS under `name`, wrapping the VFS named
+/// `inner_vfs`, or SQLite's default VFS if `None`. If one is registered
+/// under `name` already, replace its keyring and settings instead;
+/// files already open through it keep the keyring they were opened
+/// with. Its inner VFS can't be changed without unregistering it.
+#[allow(clippy::too_many_arguments)]
 pub fn register_evfs(
     name: &str,
     keyring: Arc<Keyring>,
     page_size: u32,
     reserve_size: usize,
+    allow_mmap: bool,
+    conceal_header: bool,
+    table_scopes: Vec<String>,
+    inner_vfs: Option<&str>,
 ) -> anyhow::Result<()> {
-    let inner_vfs = unsafe { sqlite3_vfs_find(ptr::null()) };
-    anyhow::ensure!(!inner_vfs.is_null(), "no default sqlite3 VFS found");
+    let c_name = CString::new(name)?;
+    let inner_vfs = match inner_vfs {
+        Some(inner) => {
+            let c_inner = CString::new(inner)?;
+            let vfs = unsafe { sqlite3_vfs_find(c_inner.as_ptr()) };
+            anyhow::ensure!(!vfs.is_null(), "no sqlite3 VFS named {inner:?} found");
+            vfs
+        }
+        None => {
+            let vfs = unsafe { sqlite3_vfs_find(ptr::null()) };
+            anyhow::ensure!(!vfs.is_null(), "no default sqlite3 VFS found");
+            vfs
+        }
+    };
+    let config = Arc::new(EvfsConfig {
+        keyring,
+        page_size,
+        reserve_size,
+        allow_mmap,
+        conceal_header,
+        table_scopes,
+    });
+
+    let mut registry = REGISTRY.lock();
+    if let Some(Registration(vfs)) = registry.get(name) {
+        let global = unsafe { &*((**vfs).pAppData as *const EvfsGlobal) };
+        anyhow::ensure!(
+            global.inner_vfs == inner_vfs,
+            "evfs {name} wraps another inner VFS; unregister it first to change it"
+        );
+        *global.config.write() = config;
+        log::debug!(
+            "evfs {name} re-registered (page_size={page_size}, reserve={reserve_size}, \
+             allow_mmap={allow_mmap}, conceal_header={conceal_header})"
+        );
+        return Ok(());
+    }
 
-    // Build the io_methods table.
+    // Build the io_methods table. Version 2 adds the shared-memory
+    // methods WAL mode needs; version 3's xFetch would hand SQLite
+    // memory-mapped ciphertext, so it is left out.
     let io_methods = sqlite3_io_methods {
-        iVersion: 1,
+        iVersion: 2,
         xClose: Some(evfs_close),
         xRead: Some(evfs_read),
         xWrite: Some(evfs_write),
-        xTruncate: Some(xTruncate),
-        xSync: Some(xSync),
+        xTruncate: Some(evfs_truncate),
+        xSync: Some(evfs_sync),
         xFileSize: Some(evfs_file_size),
-        xLock: Some(xLock),
-        xUnlock: Some(xUnlock),
+        xLock: Some(evfs_lock),
+        xUnlock: Some(evfs_unlock),
         xCheckReservedLock: Some(evfs_check_reserved_lock),
         xFileControl: Some(evfs_file_control),
         xSectorSize: Some(evfs_sector_size),
         xDeviceCharacteristics: Some(evfs_device_characteristics),
-        // v2/v3 methods - not needed for iVersion=1.
-        xShmMap: None,
-        xShmLock: None,
-        xShmBarrier: None,
-        xShmUnmap: None,
+        xShmMap: Some(evfs_shm_map),
+        xShmLock: Some(evfs_shm_lock),
+        xShmBarrier: Some(evfs_shm_barrier),
+        xShmUnmap: Some(evfs_shm_unmap),
         xFetch: None,
         xUnfetch: None,
     };
 
-    let global = Box::leak(Box::new(EvfsGlobal {
-        keyring,
-        page_size,
-        reserve_size,
+    let global = Box::into_raw(Box::new(EvfsGlobal {
+        config: RwLock::new(config),
         inner_vfs,
         io_methods,
+        open_files: AtomicUsize::new(0),
     }));
 
-    let c_name = CString::new(name)?;
-
     // We need szOsFile large enough for our EvfsFile.
     let sz_os_file = std::mem::size_of::<EvfsFile>() as c_int;
 
-    let vfs = Box::leak(Box::new(sqlite3_vfs {
+    let vfs = Box::into_raw(Box::new(sqlite3_vfs {
         iVersion: 2,
         szOsFile: sz_os_file,
         mxPathname: unsafe { (*inner_vfs).mxPathname },
         pNext: ptr::null_mut(),
         zName: c_name.into_raw(),
-        pAppData: global as *mut EvfsGlobal as *mut c_void,
+        pAppData: global as *mut c_void,
         xOpen: Some(evfs_open),
         xDelete: Some(evfs_delete),
         xAccess: Some(evfs_access),
@@ -740,19 +1978,66 @@ pub fn register_evfs(
         xNextSystemCall: None,
     }));
 
-    let rc = unsafe { sqlite3_vfs_register(vfs as *mut sqlite3_vfs, 0) };
-    anyhow::ensure!(rc == SQLITE_OK, "sqlite3_vfs_register failed: {rc}");
+    let rc = unsafe { sqlite3_vfs_register(vfs, 0) };
+    if rc != SQLITE_OK {
+        unsafe { free_registration(vfs) };
+        anyhow::bail!("sqlite3_vfs_register failed: {rc}");
+    }
+    registry.insert(name.to_owned(), Registration(vfs));
+
+    log::debug!(
+        "evfs {name} registered over {:?} (page_size={page_size}, reserve={reserve_size}, \
+         allow_mmap={allow_mmap})",
+        unsafe { CStr::from_ptr((*inner_vfs).zName) }
+    );
+    Ok(())
+}
 
-    log::debug!("evfs registered (page_size={page_size}, reserve={reserve_size})");
+/// Unregister the evfs VFS registered under `name` and free it.
+///
+/// Fails while files are open through it. Close every connection
+/// opened through the VFS first, including in-memory ones, which may
+/// still open temp files through it.
+pub fn unregister_evfs(name: &str) -> anyhow::Result<()> {
+    let mut registry = REGISTRY.lock();
+    let Some(&Registration(vfs)) = registry.get(name) else {
+        anyhow::bail!("no evfs VFS named {name:?} is registered");
+    };
+    unsafe {
+        // Unregister first so no new file can be opened through it
+        // between the check and the free.
+        let rc = sqlite3_vfs_unregister(vfs);
+        anyhow::ensure!(rc == SQLITE_OK, "sqlite3_vfs_unregister failed: {rc}");
+        let open = (*((*vfs).pAppData as *const EvfsGlobal))
+            .open_files
+            .load(Ordering::SeqCst);
+        if open > 0 {
+            sqlite3_vfs_register(vfs, 0);
+            anyhow::bail!("evfs {name} still has {open} open files; close its connections first");
+        }
+        registry.remove(name);
+        free_registration(vfs);
+    }
+    log::debug!("evfs {name} unregistered");
     Ok(())
 }
 
+/// Free a VFS allocated by `register_evfs` that SQLite no longer
+/// knows about.
+unsafe fn free_registration(vfs: *mut sqlite3_vfs) {
+    unsafe {
+        let vfs = Box::from_raw(vfs);
+        drop(Box::from_raw(vfs.pAppData as *mut EvfsGlobal));
+        drop(CString::from_raw(vfs.zName as *mut c_char));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
 
     use super::*;
-    use crate::crypto::keys::KeyScope;
+    use crate::{crypto::keys::KeyScope, kms::mock::MockKmsProvider};
 
     #[test]
     fn test_evfs_file_struct_layout() {
@@ -783,25 +2068,11 @@ mod tests {
 
     #[test]
     fn test_register_evfs_with_valid_args() -> anyhow::Result<()> {
-        use crate::kms::KmsProvider;
-
-        // Mock KmsProvider for registration test
-        struct TestKmsProvider;
-        impl KmsProvider for TestKmsProvider {
-            fn get_kek(&self) -> anyhow::Result<(crate::crypto::keys::KekId, Vec<u8>)> {
-                Ok((crate::crypto::keys::KekId("test".into()), vec![0xAAu8; 32]))
-            }
-
-            fn get_kek_by_id(&self, _id: &crate::crypto::keys::KekId) -> anyhow::Result<Vec<u8>> {
-                Ok(vec![0xBBu8; 32])
-            }
-        }
-
-        let keyring = Arc::new(Keyring::new(Arc::new(TestKmsProvider)));
+        let keyring = Arc::new(Keyring::new(Arc::new(MockKmsProvider::new())));
 
         // Try to register - note this is global state, only run once
         // In a real test suite, you'd want to isolate this
-        let result = register_evfs("test_evfs", keyring, 4096, 16);
+        let result = register_evfs("test_evfs", keyring, 4096, 16, false, false, Vec::new(), None);
 
         // Registration might fail if already registered in test suite
         // Both success and "already registered" are acceptable
@@ -814,27 +2085,126 @@ mod tests {
 
     #[test]
     fn test_register_evfs_invalid_name_with_null_byte() -> anyhow::Result<()> {
-        use crate::kms::KmsProvider;
-
-        struct TestKmsProvider;
-        impl KmsProvider for TestKmsProvider {
-            fn get_kek(&self) -> anyhow::Result<(crate::crypto::keys::KekId, Vec<u8>)> {
-                Ok((crate::crypto::keys::KekId("test".into()), vec![0xCCu8; 32]))
-            }
-
-            fn get_kek_by_id(&self, _id: &crate::crypto::keys::KekId) -> anyhow::Result<Vec<u8>> {
-                Ok(vec![0xDDu8; 32])
-            }
-        }
-
-        let keyring = Arc::new(Keyring::new(Arc::new(TestKmsProvider)));
+        let keyring = Arc::new(Keyring::new(Arc::new(MockKmsProvider::new())));
 
         // Name with null byte should fail
-        let result = register_evfs("test\0invalid", keyring, 4096, 16);
+        let result = register_evfs(
+            "test\0invalid",
+            keyring,
+            4096,
+            16,
+            false,
+            false,
+            Vec::new(),
+            None,
+        );
         assert!(result.is_err());
         Ok(())
     }
 
+    #[test]
+    fn test_reregister_and_unregister() -> anyhow::Result<()> {
+        let keyring = || Arc::new(Keyring::new(Arc::new(MockKmsProvider::new())));
+        let registered = || {
+            let name = c"test_evfs_reregister";
+            !unsafe { sqlite3_vfs_find(name.as_ptr()) }.is_null()
+        };
+
+        register_evfs(
+            "test_evfs_reregister",
+            keyring(),
+            4096,
+            48,
+            false,
+            false,
+            Vec::new(),
+            None,
+        )?;
+        let first = unsafe { sqlite3_vfs_find(c"test_evfs_reregister".as_ptr()) };
+
+        // The same VFS, with the new page size for new files.
+        register_evfs(
+            "test_evfs_reregister",
+            keyring(),
+            8192,
+            48,
+            false,
+            false,
+            Vec::new(),
+            None,
+        )?;
+        let second = unsafe { sqlite3_vfs_find(c"test_evfs_reregister".as_ptr()) };
+        assert_eq!(first, second);
+        let global = unsafe { &*((*second).pAppData as *const EvfsGlobal) };
+        assert_eq!(global.config.read().page_size, 8192);
+
+        // Not over another inner VFS, nor one that doesn't exist.
+        let err = register_evfs(
+            "test_evfs_reregister",
+            keyring(),
+            4096,
+            48,
+            false,
+            false,
+            Vec::new(),
+            Some("unix-none"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unregister it first"), "{err}");
+        let err = register_evfs(
+            "test_evfs_reregister",
+            keyring(),
+            4096,
+            48,
+            false,
+            false,
+            Vec::new(),
+            Some("no-such-vfs"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("no sqlite3 VFS named"), "{err}");
+        assert_eq!(global.config.read().page_size, 8192);
+
+        unregister_evfs("test_evfs_reregister")?;
+        assert!(!registered());
+        assert!(unregister_evfs("test_evfs_reregister").is_err());
+
+        register_evfs(
+            "test_evfs_reregister",
+            keyring(),
+            4096,
+            48,
+            false,
+            false,
+            Vec::new(),
+            None,
+        )?;
+        assert!(registered());
+        unregister_evfs("test_evfs_reregister")
+    }
+
+    #[test]
+    fn test_parse_db_header() {
+        let mut header = vec![0u8; 100];
+        header[..16].copy_from_slice(b"SQLite format 3\0");
+        header[16..18].copy_from_slice(&8192u16.to_be_bytes());
+        header[20] = 48;
+        assert_eq!(parse_db_header(&header), Some((8192, 48)));
+
+        // 1 encodes 65536.
+        header[16..18].copy_from_slice(&1u16.to_be_bytes());
+        assert_eq!(parse_db_header(&header), Some((65536, 48)));
+
+        header[16..18].copy_from_slice(&1000u16.to_be_bytes());
+        assert_eq!(parse_db_header(&header), None);
+
+        header[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        assert_eq!(parse_db_header(&header[..50]), None);
+
+        header[0] = b'X';
+        assert_eq!(parse_db_header(&header), None);
+    }
+
     #[test]
     fn test_cstring_conversion_for_vfs_name() {
         let name = "evfs";
@@ -849,23 +2219,23 @@ mod tests {
     #[test]
     fn test_io_methods_struct_initialization() {
         let io_methods = sqlite3_io_methods {
-            iVersion: 1,
+            iVersion: 2,
             xClose: Some(evfs_close),
             xRead: Some(evfs_read),
             xWrite: Some(evfs_write),
-            xTruncate: Some(xTruncate),
-            xSync: Some(xSync),
+            xTruncate: Some(evfs_truncate),
+            xSync: Some(evfs_sync),
             xFileSize: Some(evfs_file_size),
-            xLock: Some(xLock),
-            xUnlock: Some(xUnlock),
+            xLock: Some(evfs_lock),
+            xUnlock: Some(evfs_unlock),
             xCheckReservedLock: Some(evfs_check_reserved_lock),
             xFileControl: Some(evfs_file_control),
             xSectorSize: Some(evfs_sector_size),
             xDeviceCharacteristics: Some(evfs_device_characteristics),
-            xShmMap: None,
-            xShmLock: None,
-            xShmBarrier: None,
-            xShmUnmap: None,
+            xShmMap: Some(evfs_shm_map),
+            xShmLock: Some(evfs_shm_lock),
+            xShmBarrier: Some(evfs_shm_barrier),
+            xShmUnmap: Some(evfs_shm_unmap),
             xFetch: None,
             xUnfetch: None,
         };
@@ -875,6 +2245,40 @@ mod tests {
         assert!(io_methods.xWrite.is_some());
         assert!(io_methods.xClose.is_some());
         assert!(io_methods.xSync.is_some());
+        // WAL mode needs shared memory; memory-mapped reads would skip decryption
+        assert!(io_methods.xShmMap.is_some());
+        assert!(io_methods.xFetch.is_none());
+    }
+
+    #[test]
+    fn test_wal_region() {
+        let page_size = 4096i64;
+        let frame_size = WAL_FRAME_HEADER_SIZE + page_size;
+
+        let header_end = |ofst| match wal_region(ofst, page_size) {
+            WalRegion::Header { end } => Some(end),
+            WalRegion::Page { .. } => None,
+        };
+        let page = |ofst| match wal_region(ofst, page_size) {
+            WalRegion::Header { .. } => None,
+            WalRegion::Page { frame, start } => Some((frame, start)),
+        };
+
+        // WAL header
+        assert_eq!(header_end(0), Some(32));
+        assert_eq!(header_end(31), Some(32));
+
+        // First frame: header, then page image
+        assert_eq!(header_end(32), Some(56));
+        assert_eq!(page(56), Some((32, 56)));
+        assert_eq!(page(56 + page_size - 1), Some((32, 56)));
+
+        // Second frame
+        assert_eq!(header_end(32 + frame_size), Some(32 + frame_size + 24));
+        assert_eq!(
+            page(32 + frame_size + 24 + 100),
+            Some((32 + frame_size, 32 + frame_size + 24))
+        );
     }
 
     #[test]
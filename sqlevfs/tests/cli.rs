@@ -0,0 +1,321 @@
+//! Drives the `evfs-backup` binary as an operator would.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use rusqlite::{Connection, OpenFlags};
+use serde_json::Value;
+use sqlevfs::{EvfsBuilder, Mode};
+use tempfile::TempDir;
+
+/// Run `evfs-backup` with `args` and `--json`, returning its exit code
+/// and the JSON it printed.
+fn evfs_backup(args: &[&dyn AsRef<std::ffi::OsStr>]) -> (i32, Value) {
+    let output = Command::new(env!("CARGO_BIN_EXE_evfs-backup"))
+        .args(args)
+        .arg("--json")
+        .output()
+        .unwrap();
+    let json = serde_json::from_slice(&output.stdout).unwrap_or_else(|e| {
+        panic!(
+            "{e}: stdout {:?}, stderr {:?}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )
+    });
+    (output.status.code().unwrap(), json)
+}
+
+fn keyfile(dir: &TempDir, name: &str, key: u8) -> PathBuf {
+    let path = dir.path().join(name);
+    fs::write(&path, [key; 32]).unwrap();
+    path
+}
+
+/// Create a database of 8 KiB pages through a VFS named `vfs`, keyed
+/// by `keyfile`.
+fn create_db(path: &Path, keyfile: &Path, vfs: &str) {
+    EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(keyfile.to_path_buf()),
+        passphrase: None,
+    })
+    .page_size(8192)
+    .vfs_name(vfs)
+    .register()
+    .unwrap();
+    let conn = Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs,
+    )
+    .unwrap();
+    conn.execute_batch(
+        "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+         INSERT INTO notes SELECT i, 'cli-marker ' || i FROM n;",
+    )
+    .unwrap();
+}
+
+#[test_log::test]
+fn create_verify_restore_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let db_key = keyfile(&dir, "db.key", 0x31);
+    let backup_key = keyfile(&dir, "backup.key", 0x32);
+    let db_path = dir.path().join("source.db");
+    create_db(&db_path, &db_key, "cli-round-trip");
+
+    let backup = dir.path().join("source.evfs-backup");
+    let manifest = dir.path().join("source.evfs-manifest");
+    let (code, created) = evfs_backup(&[
+        &"create",
+        &db_path,
+        &backup,
+        &"--keyfile",
+        &db_key,
+        &"--backup-keyfile",
+        &backup_key,
+        &"--manifest",
+        &manifest,
+    ]);
+    assert_eq!(code, 0, "{created}");
+    assert_eq!(created["ok"], true);
+    assert_eq!(created["command"], "create");
+    // Read from the database header.
+    assert_eq!(created["page_size"], 8192);
+    assert_eq!(created["reserve"], 80);
+    let page_count = created["page_count"].as_u64().unwrap();
+    assert_eq!(page_count, fs::metadata(&db_path).unwrap().len() / 8192);
+
+    let (code, verified) = evfs_backup(&[
+        &"verify",
+        &backup,
+        &"--backup-keyfile",
+        &backup_key,
+        &"--manifest",
+        &manifest,
+    ]);
+    assert_eq!(code, 0, "{verified}");
+    assert_eq!(verified["pages_ok"], page_count);
+    assert_eq!(verified["pages_bad"], 0);
+    assert_eq!(verified["digests_ok"], true);
+
+    // Creating it again must not overwrite the backup.
+    let (code, again) = evfs_backup(&[&"create", &db_path, &backup, &"--keyfile", &db_key]);
+    assert_eq!(code, 3, "{again}");
+    assert_eq!(again["ok"], false);
+
+    let restored = dir.path().join("restored.db");
+    let (code, restore) = evfs_backup(&[
+        &"restore",
+        &backup,
+        &restored,
+        &"--keyfile",
+        &db_key,
+        &"--backup-keyfile",
+        &backup_key,
+        &"--manifest",
+        &manifest,
+    ]);
+    assert_eq!(code, 0, "{restore}");
+    assert_eq!(restore["digests_ok"], true);
+
+    // The VFS's keyring still holds the source's DEK; key the restored
+    // database by its own keyfile.
+    let conn = Connection::open_with_flags_and_vfs(
+        format!(
+            "file:{}?evfs_keyfile={}",
+            restored.display(),
+            db_key.display()
+        ),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        "cli-round-trip",
+    )
+    .unwrap();
+    let (count, last): (i64, String) = conn
+        .query_row("SELECT COUNT(*), MAX(body) FROM notes", [], |r| {
+            Ok((r.get(0)?, r.get(1)?))
+        })
+        .unwrap();
+    assert_eq!((count, last.as_str()), (500, "cli-marker 99"));
+}
+
+#[test_log::test]
+fn verify_failure_exits_apart_from_errors() {
+    let dir = TempDir::new().unwrap();
+    let key = keyfile(&dir, "db.key", 0x41);
+    let db_path = dir.path().join("source.db");
+    create_db(&db_path, &key, "cli-verify");
+    let backup = dir.path().join("source.evfs-backup");
+    let (code, created) = evfs_backup(&[&"create", &db_path, &backup, &"--keyfile", &key]);
+    assert_eq!(code, 0, "{created}");
+
+    // A flipped byte in the last page fails verification.
+    let mut data = fs::read(&backup).unwrap();
+    let last = data.len() - 100;
+    data[last] ^= 0xff;
+    fs::write(&backup, &data).unwrap();
+    let (code, verified) = evfs_backup(&[&"verify", &backup, &"--keyfile", &key]);
+    assert_eq!(code, 1, "{verified}");
+    assert_eq!(verified["ok"], false);
+    assert_eq!(verified["pages_bad"], 1);
+
+    // A missing backup is an I/O error.
+    let missing = dir.path().join("missing.evfs-backup");
+    let (code, verified) = evfs_backup(&[&"verify", &missing, &"--keyfile", &key]);
+    assert_eq!(code, 3, "{verified}");
+    assert!(verified["error"].as_str().unwrap().contains("No such file"));
+
+    // Usage errors.
+    for args in [
+        &[&"verify" as &dyn AsRef<_>, &backup][..],
+        &[
+            &"verify",
+            &backup,
+            &"--keyfile",
+            &key,
+            &"--passphrase",
+            &"pw",
+        ],
+        &[&"verify", &backup, &"--keyfile"],
+        &[&"verify", &backup, &"--bogus", &"1"],
+        &[&"create", &db_path, &"--keyfile", &key],
+        &[&"frobnicate"],
+    ] {
+        let (code, out) = evfs_backup(args);
+        assert_eq!(code, 2, "{out}");
+        assert!(out["error"].is_string());
+    }
+}
+
+#[test_log::test]
+fn rotate_kek_moves_the_backup_to_the_new_key() {
+    let dir = TempDir::new().unwrap();
+    let key = keyfile(&dir, "db.key", 0x51);
+    let new_key = keyfile(&dir, "new.key", 0x52);
+    let db_path = dir.path().join("source.db");
+    create_db(&db_path, &key, "cli-rotate");
+    let backup = dir.path().join("source.evfs-backup");
+    let (code, created) = evfs_backup(&[&"create", &db_path, &backup, &"--keyfile", &key]);
+    assert_eq!(code, 0, "{created}");
+
+    let (code, rotated) = evfs_backup(&[
+        &"rotate-kek",
+        &backup,
+        &"--backup-keyfile",
+        &key,
+        &"--new-keyfile",
+        &new_key,
+    ]);
+    assert_eq!(code, 0, "{rotated}");
+
+    let (code, verified) = evfs_backup(&[&"verify", &backup, &"--backup-keyfile", &new_key]);
+    assert_eq!(code, 0, "{verified}");
+    let (code, verified) = evfs_backup(&[&"verify", &backup, &"--backup-keyfile", &key]);
+    assert_eq!(code, 3, "{verified}");
+}
+
+#[test_log::test]
+fn migrate_keyring_moves_the_database_to_the_new_key() {
+    let dir = TempDir::new().unwrap();
+    let key = keyfile(&dir, "dev.key", 0x61);
+    let new_key = keyfile(&dir, "prod.key", 0x62);
+    let db_path = dir.path().join("moved.db");
+    create_db(&db_path, &key, "cli-migrate-dev");
+
+    let (code, migrated) = evfs_backup(&[
+        &"migrate-keyring",
+        &db_path,
+        &"--keyfile",
+        &key,
+        &"--new-keyfile",
+        &new_key,
+    ]);
+    assert_eq!(code, 0, "{migrated}");
+    assert_eq!(migrated["scopes"], serde_json::json!(["database"]));
+
+    // Only the new key opens it.
+    let open = |keyfile: &Path, vfs: &str| {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.to_path_buf()),
+            passphrase: None,
+        })
+        .vfs_name(vfs)
+        .register()
+        .unwrap();
+        Connection::open_with_flags_and_vfs(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY, vfs)
+            .and_then(|conn| {
+                conn.query_row("SELECT count(*) FROM notes", [], |r| r.get::<_, i64>(0))
+            })
+    };
+    assert_eq!(open(&new_key, "cli-migrate-prod").unwrap(), 500);
+    assert!(open(&key, "cli-migrate-dev-again").is_err());
+
+    // The old key no longer unwraps anything to migrate.
+    let (code, failed) = evfs_backup(&[
+        &"migrate-keyring",
+        &db_path,
+        &"--keyfile",
+        &key,
+        &"--new-keyfile",
+        &new_key,
+    ]);
+    assert_eq!(code, 3, "{failed}");
+}
+
+#[test_log::test]
+fn change_passphrase_rewraps_under_the_new_passphrase() {
+    let dir = TempDir::new().unwrap();
+    let db_path = dir.path().join("passphrase.db");
+    let open = |passphrase: &str, vfs: &str| {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: None,
+            passphrase: Some(passphrase.to_string()),
+        })
+        .vfs_name(vfs)
+        .register()
+        .unwrap();
+        Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+        .and_then(|conn| {
+            conn.execute_batch("CREATE TABLE IF NOT EXISTS notes (body TEXT)")?;
+            conn.query_row("SELECT count(*) FROM notes", [], |r| r.get::<_, i64>(0))
+        })
+    };
+    assert_eq!(open("old secret", "cli-passphrase-old").unwrap(), 0);
+    let sidecar = sqlevfs::keyring::sidecar_path_for(&db_path);
+    let before = fs::read(&sidecar).unwrap();
+
+    // A wrong old passphrase changes nothing.
+    let (code, failed) = evfs_backup(&[
+        &"change-passphrase",
+        &db_path,
+        &"--passphrase",
+        &"not it",
+        &"--new-passphrase",
+        &"new secret",
+    ]);
+    assert_eq!(code, 3, "{failed}");
+    assert_eq!(fs::read(&sidecar).unwrap(), before);
+
+    let (code, changed) = evfs_backup(&[
+        &"change-passphrase",
+        &db_path,
+        &"--passphrase",
+        &"old secret",
+        &"--new-passphrase",
+        &"new secret",
+    ]);
+    assert_eq!(code, 0, "{changed}");
+    assert_eq!(open("new secret", "cli-passphrase-new").unwrap(), 0);
+    assert!(open("old secret", "cli-passphrase-old-again").is_err());
+
+    let (code, usage) = evfs_backup(&[&"change-passphrase", &db_path, &"--passphrase", &"x"]);
+    assert_eq!(code, 2, "{usage}");
+}
@@ -23,7 +23,7 @@ fn test_builder_device_key_with_keyfile() -> anyhow::Result<()> {
     let builder = EvfsBuilder::new(mode);
     assert_eq!(builder.name, "evfs");
     assert_eq!(builder.page_size, 4096);
-    assert_eq!(builder.reserve_size, 48);
+    assert_eq!(builder.reserve_size, 80);
 
     Ok(())
 }
@@ -40,14 +40,34 @@ fn test_builder_device_key_with_passphrase() {
 }
 
 #[test_log::test]
-#[should_panic(expected = "DeviceKey mode requires keyfile or passphrase")]
-fn test_builder_device_key_no_source_panics() {
-    let mode = Mode::DeviceKey {
+fn test_builder_device_key_no_source_fails() -> anyhow::Result<()> {
+    let mode = || Mode::DeviceKey {
         keyfile: None,
         passphrase: None,
     };
 
-    let _builder = EvfsBuilder::new(mode);
+    let builder = EvfsBuilder::new(mode()).vfs_name("evfs_no_source");
+    assert_eq!(builder.check(), Err(BuildError::MissingKeySource));
+    let Err(err) = builder.register() else {
+        panic!("registered without a key source");
+    };
+    assert_eq!(
+        err.downcast_ref::<BuildError>(),
+        Some(&BuildError::MissingKeySource)
+    );
+    assert!(err.to_string().contains("requires keyfile or passphrase"));
+
+    // A provider of its own makes up for it.
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("test.key");
+    fs::write(&keyfile, (0..32u8).collect::<Vec<_>>())?;
+    let provider = kms::local::DeviceKeyProvider::from_keyfile(keyfile);
+    EvfsBuilder::new(mode())
+        .provider(std::sync::Arc::new(provider))
+        .vfs_name("evfs_no_source")
+        .register()?;
+    sqlevfs::unregister("evfs_no_source")?;
+    Ok(())
 }
 
 #[test_log::test]
@@ -76,15 +96,143 @@ fn test_builder_chaining() -> anyhow::Result<()> {
     let builder = EvfsBuilder::new(mode)
         .page_size(8192)
         .reserve_size(64)
+        .allow_mmap(true)
         .vfs_name("custom_evfs");
 
     assert_eq!(builder.name, "custom_evfs");
     assert_eq!(builder.page_size, 8192);
     assert_eq!(builder.reserve_size, 64);
+    assert!(builder.allow_mmap);
 
     Ok(())
 }
 
+#[test_log::test]
+fn test_register_rejects_invalid_geometry() -> anyhow::Result<()> {
+    use sqlevfs::crypto::{keys::Algorithm, page};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("test.key");
+    fs::write(&keyfile, (0..32u8).collect::<Vec<_>>())?;
+    let builder = || {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name("evfs_bad_geometry")
+    };
+
+    for (page_size, reserve, expected) in [
+        (3000, 80, BuildError::InvalidPageSize { page_size: 3000 }),
+        (256, 80, BuildError::InvalidPageSize { page_size: 256 }),
+        (
+            131072,
+            80,
+            BuildError::InvalidPageSize { page_size: 131072 },
+        ),
+        (
+            4096,
+            40,
+            BuildError::ReserveTooSmall {
+                reserve: 40,
+                min_required: page::min_reserve(Algorithm::Aes256Gcm),
+                algorithm: Algorithm::Aes256Gcm,
+            },
+        ),
+        // The default before key commitment
+        (
+            4096,
+            48,
+            BuildError::ReserveTooSmall {
+                reserve: 48,
+                min_required: page::min_reserve(Algorithm::Aes256Gcm),
+                algorithm: Algorithm::Aes256Gcm,
+            },
+        ),
+        (
+            4096,
+            256,
+            BuildError::ReserveTooLarge {
+                reserve: 256,
+                max: 255,
+            },
+        ),
+        (
+            512,
+            80,
+            BuildError::ReserveTooLarge {
+                reserve: 80,
+                max: 32,
+            },
+        ),
+    ] {
+        let builder = builder().page_size(page_size).reserve_size(reserve);
+        assert_eq!(builder.check().as_ref(), Err(&expected));
+        let Err(err) = builder.register() else {
+            panic!("registered {page_size}-byte pages with a {reserve}-byte reserve");
+        };
+        assert_eq!(err.downcast_ref::<BuildError>(), Some(&expected), "{err}");
+    }
+
+    builder().page_size(65536).reserve_size(255).register()?;
+    sqlevfs::unregister("evfs_bad_geometry")?;
+    Ok(())
+}
+
+#[test_log::test]
+fn test_register_over_named_inner_vfs() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("inner.key");
+    fs::write(&keyfile, vec![0x1E; 32])?;
+    let builder = || {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name("evfs_unix_none")
+    };
+
+    let Err(err) = builder().inner_vfs("no-such-vfs").register() else {
+        panic!("registered over a VFS that doesn't exist");
+    };
+    assert!(err.to_string().contains("no-such-vfs"), "{err}");
+
+    builder().inner_vfs("unix-none").register()?;
+    let db_path = test_db_path(&temp_dir, "inner.db");
+    let open = || {
+        Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "evfs_unix_none",
+        )
+    };
+
+    let conn = open()?;
+    conn.execute_batch(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+         BEGIN;
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+         INSERT INTO t (v) SELECT 'secret-' || i FROM n;
+         COMMIT;
+         DELETE FROM t WHERE id % 2 = 0;",
+    )?;
+    conn.close().map_err(|(_, e)| e)?;
+
+    let raw = fs::read(&db_path)?;
+    assert!(!raw.windows(7).any(|w| w == b"secret-"));
+
+    let conn = open()?;
+    let count: i64 = conn.query_row("SELECT count(*) FROM t", [], |r| r.get(0))?;
+    assert_eq!(count, 100);
+    let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+    assert_eq!(check, "ok");
+    conn.close().map_err(|(_, e)| e)?;
+
+    sqlevfs::unregister("evfs_unix_none")
+}
+
 #[test_log::test]
 fn test_end_to_end_database_operations() -> anyhow::Result<()> {
     use rusqlite::{Connection, OpenFlags};
@@ -175,7 +323,7 @@ fn test_reopening_encrypted_database() -> anyhow::Result<()> {
     fs::write(&keyfile, vec![0xDD; 32])?;
 
     let db_path = test_db_path(&temp_dir, "reopen.db");
-    let reserve_size = 48;
+    let reserve_size = 80;
 
     // First session - create and write
     {
@@ -252,7 +400,7 @@ fn test_large_data_encryption() -> anyhow::Result<()> {
     fs::write(&keyfile, vec![0x22; 32])?;
 
     let db_path = test_db_path(&temp_dir, "large.db");
-    let reserve_size = 48;
+    let reserve_size = 80;
 
     let mode = Mode::DeviceKey {
         keyfile: Some(keyfile),
@@ -300,7 +448,7 @@ fn test_large_data_encryption() -> anyhow::Result<()> {
 
     let bytes = std::fs::read(&db_path)?;
     let page_size = 4096usize;
-    let reserve = 48usize;
+    let reserve = 80usize;
 
     let actual_reserve = bytes[20] as usize;
     assert_eq!(actual_reserve, reserve);
@@ -308,9 +456,11 @@ fn test_large_data_encryption() -> anyhow::Result<()> {
     let page2 = &bytes[page_size..page_size * 2];
     let payload_len = page_size - reserve;
 
-    // tag is [payload_len..payload_len+16], marker is next 6 bytes
+    // tag is [payload_len..payload_len+16], marker is next 6 bytes,
+    // then the 12-byte nonce and the key commitment. The flags byte
+    // records the database ID in the AAD.
     let marker = &page2[payload_len + 16..payload_len + 22];
-    assert_eq!(marker, b"EVFSv1");
+    assert_eq!(marker, b"EVFS\x04\x10");
 
     log::info!("Started reading large data encryption");
     // Read back
@@ -423,13 +573,1489 @@ fn test_keyring_persistence_via_sidecar() -> anyhow::Result<()> {
     let sidecar_bytes = std::fs::read(&sidecar_path)?;
     assert!(!sidecar_bytes.is_empty());
     // Verify sidecar can be decoded into PersistedKeyring
-    let kr: PersistedKeyring = bincode::decode_from_slice(&sidecar_bytes, config::standard())?.0;
+    let kr: PersistedKeyring = PersistedKeyring::decode(&sidecar_bytes)?;
     // Should have at least one key entry
     assert!(!kr.keys.is_empty(), "Keyring should have entries");
 
     Ok(())
 }
 
+#[test_log::test]
+fn test_wal_mode() -> anyhow::Result<()> {
+    use std::thread;
+
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("wal.key");
+    fs::write(&keyfile, vec![0x44; 32])?;
+
+    let db_path = test_db_path(&temp_dir, "wal.db");
+    let wal_path = test_db_path(&temp_dir, "wal.db-wal");
+
+    let mode = Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    };
+
+    EvfsBuilder::new(mode).vfs_name("evfs_wal").register()?;
+
+    let open = |flags: OpenFlags| Connection::open_with_flags_and_vfs(&db_path, flags, "evfs_wal");
+
+    let writer = open(OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE)?;
+    let journal_mode: String = writer.query_row("PRAGMA journal_mode = WAL", [], |r| r.get(0))?;
+    assert_eq!(journal_mode, "wal");
+    writer.execute_batch(
+        r#"
+        PRAGMA wal_autocheckpoint = 0;
+        CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+        INSERT INTO notes (body) VALUES ('wal-plaintext-marker');
+        "#,
+    )?;
+
+    // Frames in the WAL hold page images encrypted like the main DB.
+    let wal = fs::read(&wal_path)?;
+    assert!(wal.len() > 32);
+    assert!(!String::from_utf8_lossy(&wal).contains("wal-plaintext-marker"));
+
+    // A reader keeps its snapshot while the writer commits.
+    let reader = open(OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    reader.execute_batch("BEGIN")?;
+    let before: i64 = reader.query_row("SELECT COUNT(*) FROM notes", [], |r| r.get(0))?;
+
+    let path = db_path.clone();
+    thread::spawn(move || -> rusqlite::Result<()> {
+        let conn = Connection::open_with_flags_and_vfs(
+            &path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE,
+            "evfs_wal",
+        )?;
+        for i in 0..100 {
+            conn.execute(
+                "INSERT INTO notes (body) VALUES (?1)",
+                [format!("note {i}")],
+            )?;
+        }
+        Ok(())
+    })
+    .join()
+    .unwrap()?;
+
+    let during: i64 = reader.query_row("SELECT COUNT(*) FROM notes", [], |r| r.get(0))?;
+    reader.execute_batch("COMMIT")?;
+    let after: i64 = reader.query_row("SELECT COUNT(*) FROM notes", [], |r| r.get(0))?;
+    assert_eq!((before, during, after), (1, 1, 101));
+    drop(reader);
+
+    let (busy, _, checkpointed): (i64, i64, i64) =
+        writer.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+        })?;
+    assert_eq!((busy, checkpointed), (0, 0));
+    assert_eq!(fs::metadata(&wal_path)?.len(), 0);
+    writer.close().map_err(|(_, e)| e)?;
+
+    assert!(!String::from_utf8_lossy(&fs::read(&db_path)?).contains("wal-plaintext-marker"));
+
+    let conn = open(OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+    assert_eq!(check, "ok");
+    let (count, first): (i64, String) = conn.query_row(
+        "SELECT COUNT(*), (SELECT body FROM notes WHERE id = 1) FROM notes",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    assert_eq!((count, first.as_str()), (101, "wal-plaintext-marker"));
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_mmap_size_is_pinned_to_zero() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("mmap.key");
+    fs::write(&keyfile, vec![0x55; 32])?;
+
+    let db_path = test_db_path(&temp_dir, "mmap.db");
+
+    let mode = Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    };
+
+    let builder = EvfsBuilder::new(mode).vfs_name("evfs_mmap");
+    assert!(!builder.allow_mmap);
+    builder.register()?;
+
+    // Ask the file itself for a mapping, as the pager does on v3 VFSes.
+    let request_mmap = |conn: &Connection| {
+        let mut size: i64 = 268435456;
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_file_control(
+                conn.handle(),
+                c"main".as_ptr(),
+                rusqlite::ffi::SQLITE_FCNTL_MMAP_SIZE,
+                &mut size as *mut i64 as *mut std::ffi::c_void,
+            )
+        };
+        assert_eq!(rc, rusqlite::ffi::SQLITE_OK);
+    };
+
+    {
+        let conn = Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "evfs_mmap",
+        )?;
+        conn.execute_batch("PRAGMA mmap_size = 268435456")?;
+        request_mmap(&conn);
+        let mmap_size: i64 = conn.query_row("PRAGMA mmap_size", [], |r| r.get(0))?;
+        assert_eq!(mmap_size, 0);
+
+        conn.execute_batch("CREATE TABLE blobs (id INTEGER PRIMARY KEY, body TEXT)")?;
+        for i in 0..200 {
+            conn.execute(
+                "INSERT INTO blobs (body) VALUES (?1)",
+                [format!("mmap-plaintext-marker {i}")],
+            )?;
+        }
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM blobs", [], |r| r.get(0))?;
+        assert_eq!(count, 200);
+    }
+
+    let raw = fs::read(&db_path)?;
+    assert!(!String::from_utf8_lossy(&raw).contains("mmap-plaintext-marker"));
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+        "evfs_mmap",
+    )?;
+    conn.execute_batch("PRAGMA mmap_size = 268435456")?;
+    request_mmap(&conn);
+    let mmap_size: i64 = conn.query_row("PRAGMA mmap_size", [], |r| r.get(0))?;
+    assert_eq!(mmap_size, 0);
+    let body: String = conn.query_row("SELECT body FROM blobs WHERE id = 150", [], |r| r.get(0))?;
+    assert_eq!(body, "mmap-plaintext-marker 149");
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_page_size_detected_from_header() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("pagesize.key");
+    fs::write(&keyfile, vec![0x66; 32])?;
+
+    let db_path = test_db_path(&temp_dir, "pagesize.db");
+
+    let mode = |keyfile: &PathBuf| Mode::DeviceKey {
+        keyfile: Some(keyfile.clone()),
+        passphrase: None,
+    };
+
+    // Create the DB with 8 KiB pages.
+    EvfsBuilder::new(mode(&keyfile))
+        .page_size(8192)
+        .vfs_name("evfs_8k")
+        .register()?;
+    {
+        let conn = Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "evfs_8k",
+        )?;
+        conn.execute_batch("CREATE TABLE pages (id INTEGER PRIMARY KEY, body TEXT)")?;
+        for i in 0..100 {
+            conn.execute(
+                "INSERT INTO pages (body) VALUES (?1)",
+                [format!("eight-k-marker {i}")],
+            )?;
+        }
+    }
+
+    // Open it through a VFS configured for the default 4 KiB pages.
+    let builder = EvfsBuilder::new(mode(&keyfile)).vfs_name("evfs_4k");
+    assert_eq!(builder.page_size, 4096);
+    builder.register()?;
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE,
+        "evfs_4k",
+    )?;
+    let page_size: i64 = conn.query_row("PRAGMA page_size", [], |r| r.get(0))?;
+    assert_eq!(page_size, 8192);
+    let body: String = conn.query_row("SELECT body FROM pages WHERE id = 42", [], |r| r.get(0))?;
+    assert_eq!(body, "eight-k-marker 41");
+
+    // Writes, including WAL frames, use the file's page size too.
+    let journal_mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |r| r.get(0))?;
+    assert_eq!(journal_mode, "wal");
+    for i in 100..200 {
+        conn.execute(
+            "INSERT INTO pages (body) VALUES (?1)",
+            [format!("eight-k-marker {i}")],
+        )?;
+    }
+    let wal = fs::read(test_db_path(&temp_dir, "pagesize.db-wal"))?;
+    assert!(!String::from_utf8_lossy(&wal).contains("eight-k-marker"));
+
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM pages", [], |r| r.get(0))?;
+    assert_eq!(count, 200);
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+    let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+    assert_eq!(check, "ok");
+    drop(conn);
+
+    let raw = fs::read(&db_path)?;
+    assert_eq!(raw.len() % 8192, 0);
+    assert!(!String::from_utf8_lossy(&raw).contains("eight-k-marker"));
+
+    // The DB still opens through the VFS it was created with.
+    let conn =
+        Connection::open_with_flags_and_vfs(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY, "evfs_8k")?;
+    let body: String = conn.query_row("SELECT body FROM pages WHERE id = 150", [], |r| r.get(0))?;
+    assert_eq!(body, "eight-k-marker 149");
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_vacuum_relocates_pages() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("vacuum.key");
+    fs::write(&keyfile, vec![0x77; 32])?;
+
+    let db_path = test_db_path(&temp_dir, "vacuum.db");
+
+    let mode = Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    };
+    EvfsBuilder::new(mode).vfs_name("evfs_vacuum").register()?;
+
+    let open =
+        |flags: OpenFlags| Connection::open_with_flags_and_vfs(&db_path, flags, "evfs_vacuum");
+
+    let conn = open(OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE)?;
+    conn.execute_batch(
+        r#"
+        PRAGMA auto_vacuum = INCREMENTAL;
+        VACUUM;
+        CREATE TABLE a (id INTEGER PRIMARY KEY, body TEXT);
+        CREATE TABLE b (id INTEGER PRIMARY KEY, body TEXT);
+        CREATE INDEX b_body ON b (body);
+        "#,
+    )?;
+    for i in 0..300 {
+        let body = format!("vacuum-marker {i} {}", "x".repeat(200));
+        conn.execute("INSERT INTO a (body) VALUES (?1)", [&body])?;
+        conn.execute("INSERT INTO b (body) VALUES (?1)", [&body])?;
+    }
+
+    // Free pages at the front of the file so both kinds of vacuum
+    // move the pages behind them.
+    conn.execute_batch("DELETE FROM a WHERE id <= 200")?;
+    let before: i64 = conn.query_row("PRAGMA page_count", [], |r| r.get(0))?;
+    conn.execute_batch("PRAGMA incremental_vacuum(10)")?;
+    let after_incremental: i64 = conn.query_row("PRAGMA page_count", [], |r| r.get(0))?;
+    assert!(after_incremental < before);
+
+    conn.execute_batch("DELETE FROM b WHERE id % 2 = 0; VACUUM;")?;
+    let after_vacuum: i64 = conn.query_row("PRAGMA page_count", [], |r| r.get(0))?;
+    assert!(after_vacuum < after_incremental);
+    drop(conn);
+
+    let raw = fs::read(&db_path)?;
+    assert!(!String::from_utf8_lossy(&raw).contains("vacuum-marker"));
+
+    let conn = open(OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+    assert_eq!(check, "ok");
+
+    let bodies = |sql: &str| -> rusqlite::Result<Vec<String>> {
+        conn.prepare(sql)?.query_map([], |r| r.get(0))?.collect()
+    };
+    let a = bodies("SELECT body FROM a ORDER BY id")?;
+    assert_eq!(a.len(), 100);
+    assert!(a[0].starts_with("vacuum-marker 200 "));
+    let b = bodies("SELECT body FROM b ORDER BY id")?;
+    assert_eq!(b.len(), 150);
+    assert!(b[1].starts_with("vacuum-marker 2 "));
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_rotate_data_key() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+    use sqlevfs::crypto::{envelope, keys::KeyScope, page};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("rekey.key");
+    fs::write(&keyfile, vec![0x88; 32])?;
+
+    let db_path = test_db_path(&temp_dir, "rekey.db");
+    let sidecar_path = db_path.with_extension("evfs-keyring");
+    let database_dek = || -> anyhow::Result<_> {
+        let kr: PersistedKeyring = PersistedKeyring::decode(&fs::read(&sidecar_path)?)?;
+        Ok(kr.keys[&KeyScope::Database.to_string()].clone())
+    };
+
+    let mode = |name| {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name(name)
+    };
+    let keyring = mode("evfs_rekey").register()?;
+    let open = |flags: OpenFlags, vfs| Connection::open_with_flags_and_vfs(&db_path, flags, vfs);
+
+    let conn = open(
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "evfs_rekey",
+    )?;
+    conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT)")?;
+    for i in 0..100 {
+        conn.execute(
+            "INSERT INTO t (body) VALUES (?1)",
+            [format!("rekey-marker {i} {}", "z".repeat(300))],
+        )?;
+    }
+
+    // A connection in a transaction blocks the rekey.
+    conn.execute_batch("BEGIN; SELECT count(*) FROM t;")?;
+    let err = keyring.rotate_data_key(&db_path).unwrap_err();
+    assert!(err.to_string().contains("in use"), "{err}");
+    conn.execute_batch("COMMIT")?;
+    drop(conn);
+
+    let old_wrapped = database_dek()?;
+    keyring.rotate_data_key(&db_path)?;
+    let new_wrapped = database_dek()?;
+    assert_ne!(old_wrapped, new_wrapped);
+
+    // Pages no longer decrypt under the old DEK, but do under the new.
+    let raw = fs::read(&db_path)?;
+    assert!(!String::from_utf8_lossy(&raw).contains("rekey-marker"));
+    let page2 = &raw[4096..8192];
+    let old_dek = envelope::unwrap_dek(&old_wrapped, keyring.provider())?;
+    let new_dek = envelope::unwrap_dek(&new_wrapped, keyring.provider())?;
+    let database_id = sqlevfs::keyring::database_id_of(&db_path);
+    assert!(page::decrypt_page(&mut page2.to_vec(), 2, database_id, &old_dek, 80).is_err());
+    page::decrypt_page(&mut page2.to_vec(), 2, database_id, &new_dek, 80)?;
+
+    // Both the rotating keyring and a fresh one read the data.
+    mode("evfs_rekey_fresh").register()?;
+    for vfs in ["evfs_rekey", "evfs_rekey_fresh"] {
+        let conn = open(OpenFlags::SQLITE_OPEN_READ_ONLY, vfs)?;
+        let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+        assert_eq!(check, "ok");
+        let body: String = conn.query_row("SELECT body FROM t WHERE id = 42", [], |r| r.get(0))?;
+        assert!(body.starts_with("rekey-marker 41 "));
+    }
+
+    // The geometry passed in must match the header.
+    assert!(rekey::rekey_database(&db_path, &keyring, 8192, 80).is_err());
+    rekey::rekey_database(&db_path, &keyring, 4096, 80)?;
+    assert_ne!(database_dek()?, new_wrapped);
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_rekey_pragma() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+    use sqlevfs::crypto::keys::KeyScope;
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("pragma.key");
+    fs::write(&keyfile, vec![0x99; 32])?;
+
+    let db_path = test_db_path(&temp_dir, "pragma.db");
+    let sidecar_path = db_path.with_extension("evfs-keyring");
+    let database_dek = || -> anyhow::Result<_> {
+        let kr: PersistedKeyring = PersistedKeyring::decode(&fs::read(&sidecar_path)?)?;
+        Ok(kr.keys[&KeyScope::Database.to_string()].clone())
+    };
+
+    let mode = |name| {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name(name)
+    };
+    mode("evfs_pragma").register()?;
+    let open = |vfs| {
+        Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+    };
+    let pragma = |conn: &Connection, name: &str| -> rusqlite::Result<String> {
+        conn.query_row(&format!("PRAGMA {name}"), [], |r| r.get(0))
+    };
+
+    let conn = open("evfs_pragma")?;
+    conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT)")?;
+    for i in 0..100 {
+        conn.execute(
+            "INSERT INTO t (body) VALUES (?1)",
+            [format!("pragma-marker {i} {}", "p".repeat(300))],
+        )?;
+    }
+
+    let status = pragma(&conn, "evfs_key_status")?;
+    assert!(status.starts_with("kek_id="), "{status}");
+    assert!(status.ends_with("rekey=idle"), "{status}");
+
+    // Not with dirty pages in this connection's pager...
+    conn.execute_batch("BEGIN IMMEDIATE; INSERT INTO t (body) VALUES ('dirty');")?;
+    let err = pragma(&conn, "evfs_rekey").unwrap_err();
+    assert!(err.to_string().contains("write transaction"), "{err}");
+    conn.execute_batch("ROLLBACK")?;
+
+    // ...nor while another connection is reading.
+    let reader = open("evfs_pragma")?;
+    reader.execute_batch("BEGIN; SELECT count(*) FROM t;")?;
+    let err = pragma(&conn, "evfs_rekey").unwrap_err();
+    assert!(err.to_string().contains("in use"), "{err}");
+    reader.execute_batch("COMMIT")?;
+
+    let old_wrapped = database_dek()?;
+    let report = pragma(&conn, "EVFS_REKEY")?;
+    assert!(report.starts_with("ok: re-encrypted "), "{report}");
+    assert_ne!(database_dek()?, old_wrapped);
+
+    // Both connections carry on under the new DEK.
+    conn.execute("INSERT INTO t (body) VALUES ('after rekey')", [])?;
+    let count: i64 = reader.query_row("SELECT count(*) FROM t", [], |r| r.get(0))?;
+    assert_eq!(count, 101);
+    let check: String = reader.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+    assert_eq!(check, "ok");
+
+    // The opcode form, for tools that can't issue the pragma.
+    let mut out: *mut std::ffi::c_char = std::ptr::null_mut();
+    let rc = unsafe {
+        rusqlite::ffi::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            vfs::EVFS_FCNTL_KEY_STATUS,
+            &mut out as *mut *mut std::ffi::c_char as *mut std::ffi::c_void,
+        )
+    };
+    assert_eq!(rc, rusqlite::ffi::SQLITE_OK);
+    let status = unsafe { std::ffi::CStr::from_ptr(out) }
+        .to_str()?
+        .to_owned();
+    unsafe { rusqlite::ffi::sqlite3_free(out as *mut std::ffi::c_void) };
+    assert_eq!(status, pragma(&conn, "evfs_key_status")?);
+    drop((conn, reader));
+
+    mode("evfs_pragma_fresh").register()?;
+    let conn = open("evfs_pragma_fresh")?;
+    let body: String = conn.query_row("SELECT body FROM t WHERE id = 101", [], |r| r.get(0))?;
+    assert_eq!(body, "after rekey");
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_verify_pragma() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("verify.key");
+    fs::write(&keyfile, vec![0x9A; 32])?;
+    let db_path = test_db_path(&temp_dir, "verify.db");
+
+    let keyring = EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    })
+    .vfs_name("evfs_verify_pragma")
+    .register()?;
+    let open = || {
+        Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "evfs_verify_pragma",
+        )
+    };
+    let verify = |conn: &Connection| -> rusqlite::Result<String> {
+        conn.query_row("PRAGMA evfs_verify", [], |r| r.get(0))
+    };
+
+    let conn = open()?;
+    conn.execute_batch(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+         INSERT INTO t SELECT i, hex(randomblob(300)) FROM n;",
+    )?;
+    let page_count: u32 = conn.query_row("PRAGMA page_count", [], |r| r.get(0))?;
+    assert_eq!(verify(&conn)?, format!("{page_count} pages, 0 bad"));
+    drop(conn);
+
+    // Flip one ciphertext byte of page 3.
+    let mut bytes = fs::read(&db_path)?;
+    bytes[2 * 4096 + 10] ^= 0x01;
+    fs::write(&db_path, &bytes)?;
+
+    let conn = open()?;
+    assert_eq!(
+        verify(&conn)?,
+        format!("{page_count} pages, 1 bad: page 3 bad tag")
+    );
+
+    // The opcode form, and the API on the closed database.
+    let mut out: *mut std::ffi::c_char = std::ptr::null_mut();
+    let rc = unsafe {
+        rusqlite::ffi::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            vfs::EVFS_FCNTL_VERIFY,
+            &mut out as *mut *mut std::ffi::c_char as *mut std::ffi::c_void,
+        )
+    };
+    assert_eq!(rc, rusqlite::ffi::SQLITE_OK);
+    let report = unsafe { std::ffi::CStr::from_ptr(out) }
+        .to_str()?
+        .to_owned();
+    unsafe { rusqlite::ffi::sqlite3_free(out as *mut std::ffi::c_void) };
+    assert_eq!(report, verify(&conn)?);
+    drop(conn);
+
+    let result = sqlevfs::verify::verify_database(&db_path, &keyring, 4096, 80)?;
+    assert_eq!(
+        result.bad_pages,
+        vec![(3, sqlevfs::verify::PageFault::BadTag)]
+    );
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_uri_key_selection() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags, ffi::ErrorCode};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("uri.key");
+    fs::write(&keyfile, vec![0x61; 32])?;
+    let tenant_keyfile = temp_dir.path().join("tenant3.key");
+    fs::write(&tenant_keyfile, vec![0x62; 32])?;
+    let short_keyfile = temp_dir.path().join("short.key");
+    fs::write(&short_keyfile, vec![0x63; 5])?;
+
+    EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    })
+    .vfs_name("evfs_uri")
+    .register()?;
+
+    let db_path = |name: &str| test_db_path(&temp_dir, name);
+    let open = |name: &str, query: &str| {
+        Connection::open_with_flags_and_vfs(
+            format!("file:{}{query}", db_path(name).display()),
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_URI,
+            "evfs_uri",
+        )
+    };
+    let sidecar = |name: &str| -> anyhow::Result<PersistedKeyring> {
+        let path = db_path(name).with_extension("evfs-keyring");
+        PersistedKeyring::decode(&fs::read(path)?)
+    };
+    let fill = |conn: &Connection, tenant: &str| -> rusqlite::Result<()> {
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT)")?;
+        for i in 0..50 {
+            conn.execute(
+                "INSERT INTO t (body) VALUES (?1)",
+                [format!("{tenant} row {i} ").repeat(20)],
+            )?;
+        }
+        Ok(())
+    };
+
+    // Two tenants open side by side through one VFS, each under its own
+    // scope, recorded only in its own sidecar.
+    let tenant1 = open("tenant1.db", "?evfs_scope=tenant1")?;
+    let tenant2 = open("tenant2.db", "?evfs_scope=tenant2")?;
+    fill(&tenant1, "tenant1")?;
+    fill(&tenant2, "tenant2")?;
+    for (name, scope) in [
+        ("tenant1.db", "scope:tenant1"),
+        ("tenant2.db", "scope:tenant2"),
+    ] {
+        let keys = sidecar(name)?.keys;
+        assert_eq!(keys.keys().collect::<Vec<_>>(), vec![scope], "{name}");
+    }
+
+    let status: String = tenant1.query_row("PRAGMA evfs_key_status", [], |r| r.get(0))?;
+    assert!(status.ends_with("deks=1 rekey=idle"), "{status}");
+    let report: String = tenant1.query_row("PRAGMA evfs_rekey", [], |r| r.get(0))?;
+    assert!(report.starts_with("ok: re-encrypted "), "{report}");
+    drop((tenant1, tenant2));
+
+    // Unknown parameters are ignored, and a per-database keyfile wraps
+    // that database's DEKs.
+    let tenant3 = open(
+        "tenant3.db",
+        &format!(
+            "?evfs_keyfile={}&cache=private&evfs_unknown=1",
+            tenant_keyfile.display()
+        ),
+    )?;
+    fill(&tenant3, "tenant3")?;
+    drop(tenant3);
+    let wrapped = &sidecar("tenant3.db")?.keys["database"];
+    assert_eq!(
+        wrapped.kek_id.0,
+        format!("device:file:{}", tenant_keyfile.display())
+    );
+
+    // Without its keyfile the database can't be opened: its sidecar
+    // doesn't authenticate.
+    let err = open("tenant3.db", "").unwrap_err();
+    assert_eq!(
+        err.sqlite_error_code(),
+        Some(ErrorCode::CannotOpen),
+        "{err}"
+    );
+
+    // A keyfile that doesn't hold a KEK fails the open.
+    for bad in [short_keyfile, temp_dir.path().join("missing.key")] {
+        let err = open("tenant4.db", &format!("?evfs_keyfile={}", bad.display())).unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(ErrorCode::CannotOpen),
+            "{err}"
+        );
+    }
+    assert!(!db_path("tenant4.db").exists());
+
+    // The scopes persist across reopening, and each database reads back.
+    for (name, query, tenant) in [
+        ("tenant1.db", "?evfs_scope=tenant1".to_string(), "tenant1"),
+        ("tenant2.db", "?evfs_scope=tenant2".to_string(), "tenant2"),
+        (
+            "tenant3.db",
+            format!("?evfs_keyfile={}", tenant_keyfile.display()),
+            "tenant3",
+        ),
+    ] {
+        let conn = open(name, &query)?;
+        let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+        assert_eq!(check, "ok");
+        let body: String = conn.query_row("SELECT body FROM t WHERE id = 50", [], |r| r.get(0))?;
+        assert!(body.starts_with(&format!("{tenant} row 49")), "{body}");
+    }
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_independent_registrations() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = |name: &str, byte: u8| -> anyhow::Result<PathBuf> {
+        let path = temp_dir.path().join(name);
+        fs::write(&path, vec![byte; 32])?;
+        Ok(path)
+    };
+    let register = |vfs: &str, keyfile: PathBuf| {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile),
+            passphrase: None,
+        })
+        .vfs_name(vfs)
+        .register()
+    };
+    let open = |name: &str, vfs: &str| {
+        Connection::open_with_flags_and_vfs(
+            test_db_path(&temp_dir, name),
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+    };
+    let count =
+        |conn: &Connection| conn.query_row("SELECT count(*) FROM t", [], |r| r.get::<_, i64>(0));
+    // A sidecar under another key already fails to authenticate on open.
+    let unreadable = |name: &str, vfs: &str| open(name, vfs).and_then(|conn| count(&conn)).is_err();
+
+    register("evfs-tenant-a", keyfile("a.key", 0xA1)?)?;
+    register("evfs-tenant-b", keyfile("b.key", 0xB2)?)?;
+    for (name, vfs) in [("a.db", "evfs-tenant-a"), ("b.db", "evfs-tenant-b")] {
+        let conn = open(name, vfs)?;
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT)")?;
+        for i in 0..40 {
+            conn.execute(
+                "INSERT INTO t (body) VALUES (?1)",
+                [format!("{name} {i} ").repeat(30)],
+            )?;
+        }
+    }
+
+    // Each database decrypts through its own VFS only.
+    assert_eq!(count(&open("a.db", "evfs-tenant-a")?)?, 40);
+    assert_eq!(count(&open("b.db", "evfs-tenant-b")?)?, 40);
+    assert!(unreadable("a.db", "evfs-tenant-b"));
+    assert!(unreadable("b.db", "evfs-tenant-a"));
+
+    // Re-registering a name swaps the keyring for new opens; open
+    // connections keep theirs.
+    let held = open("a.db", "evfs-tenant-a")?;
+    register("evfs-tenant-a", keyfile("c.key", 0xC3)?)?;
+    assert!(unreadable("a.db", "evfs-tenant-a"));
+    assert_eq!(count(&held)?, 40);
+
+    // Not while a connection is open through it...
+    let err = sqlevfs::unregister("evfs-tenant-a").unwrap_err();
+    assert!(err.to_string().contains("open files"), "{err}");
+    drop(held);
+
+    // ...and then the name is gone until registered again.
+    sqlevfs::unregister("evfs-tenant-a")?;
+    assert!(open("a.db", "evfs-tenant-a").is_err());
+    assert!(sqlevfs::unregister("evfs-tenant-a").is_err());
+    register("evfs-tenant-a", temp_dir.path().join("a.key"))?;
+    assert_eq!(count(&open("a.db", "evfs-tenant-a")?)?, 40);
+    assert_eq!(count(&open("b.db", "evfs-tenant-b")?)?, 40);
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_databases_keep_their_own_sidecars() -> anyhow::Result<()> {
+    use std::sync::Arc;
+
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("shared.key");
+    fs::write(&keyfile, vec![0x5E; 32])?;
+    let keyring = EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    })
+    .vfs_name("evfs_two_databases")
+    .table_scope("alpha")
+    .table_scope("beta")
+    .register()?;
+    let open = |name: &str| {
+        Connection::open_with_flags_and_vfs(
+            test_db_path(&temp_dir, name),
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "evfs_two_databases",
+        )
+    };
+    let scopes = |name: &str| -> anyhow::Result<Vec<String>> {
+        let sidecar = test_db_path(&temp_dir, name).with_extension("evfs-keyring");
+        let mut scopes: Vec<_> = PersistedKeyring::decode(&fs::read(sidecar)?)?
+            .keys
+            .into_keys()
+            .collect();
+        scopes.sort();
+        Ok(scopes)
+    };
+    let count = |conn: &Connection, table: &str| {
+        conn.query_row(&format!("SELECT count(*) FROM {table}"), [], |r| {
+            r.get::<_, i64>(0)
+        })
+    };
+
+    // Both open at once, each written after the other was opened.
+    let a = open("a.db")?;
+    let b = open("b.db")?;
+    a.execute_batch("CREATE TABLE alpha (id INTEGER PRIMARY KEY, body TEXT)")?;
+    b.execute_batch("CREATE TABLE beta (id INTEGER PRIMARY KEY, body TEXT)")?;
+    for i in 0..50 {
+        a.execute(
+            "INSERT INTO alpha (body) VALUES (?1)",
+            [format!("a {i} ").repeat(40)],
+        )?;
+        b.execute(
+            "INSERT INTO beta (body) VALUES (?1)",
+            [format!("b {i} ").repeat(40)],
+        )?;
+    }
+
+    // The keyring of an open database is the one its files use.
+    let a_path = test_db_path(&temp_dir, "a.db");
+    assert!(Arc::ptr_eq(
+        &keyring.for_database(&a_path)?,
+        &keyring.for_database(&a_path)?
+    ));
+    drop((a, b));
+
+    assert_eq!(scopes("a.db")?, ["database", "table:alpha"]);
+    assert_eq!(scopes("b.db")?, ["database", "table:beta"]);
+    assert_eq!(count(&open("a.db")?, "alpha")?, 50);
+    assert_eq!(count(&open("b.db")?, "beta")?, 50);
+    let check = |name: &str| -> rusqlite::Result<String> {
+        open(name)?.query_row("PRAGMA integrity_check", [], |r| r.get(0))
+    };
+    assert_eq!(check("a.db")?, "ok");
+    assert_eq!(check("b.db")?, "ok");
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_dropped_table_scopes_are_removed() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+    use sqlevfs::crypto::keys::KeyScope;
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("drop.key");
+    fs::write(&keyfile, vec![0x4D; 32])?;
+    let db_path = test_db_path(&temp_dir, "drop.db");
+    let keyring = EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    })
+    .vfs_name("evfs_drop_scope")
+    .table_scope("alpha")
+    .register()?;
+    let conn = Connection::open_with_flags_and_vfs(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "evfs_drop_scope",
+    )?;
+    conn.execute_batch(
+        "CREATE TABLE alpha (id INTEGER PRIMARY KEY, body TEXT);
+         CREATE TABLE beta (id INTEGER PRIMARY KEY, body TEXT);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+         INSERT INTO alpha SELECT i, printf('%.300c', 'a') FROM n;
+         INSERT INTO beta SELECT id, body FROM alpha;",
+    )?;
+    let alpha = KeyScope::Table("alpha".into());
+    let scopes = || -> anyhow::Result<Vec<String>> {
+        let sidecar = fs::read(db_path.with_extension("evfs-keyring"))?;
+        let mut scopes: Vec<_> = PersistedKeyring::decode(&sidecar)?
+            .keys
+            .into_keys()
+            .collect();
+        scopes.sort();
+        Ok(scopes)
+    };
+
+    // Not while the table's pages are under its DEK.
+    let db_keyring = keyring.for_database(&db_path)?;
+    let err = db_keyring.remove_scope(&alpha, false).unwrap_err();
+    assert!(err.to_string().contains("still encrypted"), "{err}");
+    assert_eq!(scopes()?, ["database", "table:alpha"]);
+
+    // Dropping it moves the pages it frees to the database's DEK.
+    conn.execute_batch("DROP TABLE alpha")?;
+    assert!(db_keyring.remove_scope(&alpha, false)?);
+    assert_eq!(scopes()?, ["database"]);
+    let count: i64 = conn.query_row("SELECT count(*) FROM beta", [], |r| r.get(0))?;
+    assert_eq!(count, 200);
+    drop((conn, db_keyring));
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+        "evfs_drop_scope",
+    )?;
+    let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+    assert_eq!(check, "ok");
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_xchacha_algorithm() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+    use sqlevfs::crypto::{keys::Algorithm, page};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("xchacha.key");
+    fs::write(&keyfile, vec![0x5C; 32])?;
+    let db_path = test_db_path(&temp_dir, "xchacha.db");
+
+    let builder = |name| {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name(name)
+    };
+    let open = |vfs| {
+        Connection::open_with_flags_and_vfs(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+    };
+    let page_algorithms = || -> anyhow::Result<Vec<Algorithm>> {
+        let raw = fs::read(&db_path)?;
+        raw.chunks(4096)
+            .skip(1)
+            .map(|p| Ok(page::page_algorithm(p, 80)?.expect("encrypted page")))
+            .collect()
+    };
+
+    // Its longer nonce doesn't fit the smallest AES-GCM reserve.
+    let Err(err) = builder("evfs_xchacha_small")
+        .algorithm(Algorithm::XChaCha20Poly1305)
+        .reserve_size(page::MIN_RESERVE)
+        .register()
+    else {
+        panic!("registered with too small a reserve");
+    };
+    assert!(err.to_string().contains("must be >= 78"), "{err}");
+
+    let xchacha = builder("evfs_xchacha")
+        .algorithm(Algorithm::XChaCha20Poly1305)
+        .register()?;
+    assert_eq!(xchacha.algorithm(), Algorithm::XChaCha20Poly1305);
+    let conn = open("evfs_xchacha")?;
+    conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT)")?;
+    for i in 0..60 {
+        conn.execute(
+            "INSERT INTO t (body) VALUES (?1)",
+            [format!("xchacha {i} ").repeat(30)],
+        )?;
+    }
+    drop(conn);
+    let raw = fs::read(&db_path)?;
+    assert_eq!(&raw[2 * 4096 - 80 + 16..][..6], b"EVFS\x04\x11");
+    assert!(
+        page_algorithms()?
+            .iter()
+            .all(|&a| a == Algorithm::XChaCha20Poly1305)
+    );
+
+    // A default-configured VFS reads and writes it, and the database
+    // keeps its algorithm.
+    let aes = builder("evfs_xchacha_default").register()?;
+    assert_eq!(aes.algorithm(), Algorithm::Aes256Gcm);
+    let conn = open("evfs_xchacha_default")?;
+    conn.execute("INSERT INTO t (body) VALUES ('written by default')", [])?;
+    let count: i64 = conn.query_row("SELECT count(*) FROM t", [], |r| r.get(0))?;
+    assert_eq!(count, 61);
+    drop(conn);
+    assert!(
+        page_algorithms()?
+            .iter()
+            .all(|&a| a == Algorithm::XChaCha20Poly1305)
+    );
+
+    // Rotating the data key moves it to the rotating keyring's.
+    aes.rotate_data_key(&db_path)?;
+    assert!(
+        page_algorithms()?
+            .iter()
+            .all(|&a| a == Algorithm::Aes256Gcm)
+    );
+    builder("evfs_xchacha_fresh")
+        .algorithm(Algorithm::XChaCha20Poly1305)
+        .register()?;
+    for vfs in ["evfs_xchacha_default", "evfs_xchacha_fresh"] {
+        let conn = open(vfs)?;
+        let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+        assert_eq!(check, "ok");
+    }
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_pages_are_bound_to_their_database() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+    use sqlevfs::crypto::keys::DatabaseId;
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("splice.key");
+    fs::write(&keyfile, vec![0x3D; 32])?;
+    let a_path = test_db_path(&temp_dir, "a.db");
+    let b_path = test_db_path(&temp_dir, "b.db");
+
+    let keyring = EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    })
+    .vfs_name("evfs_splice")
+    // B's sidecar is written by hand, unauthenticated.
+    .upgrade_unauthenticated_sidecars(true)
+    .register()?;
+    let create = |path: &PathBuf, marker: &str| -> anyhow::Result<()> {
+        let conn = Connection::open_with_flags_and_vfs(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "evfs_splice",
+        )?;
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT)")?;
+        conn.execute("INSERT INTO t (body) VALUES (?1)", [marker])?;
+        Ok(())
+    };
+
+    create(&a_path, "from a")?;
+    let a_keyring: PersistedKeyring =
+        PersistedKeyring::decode(&fs::read(a_path.with_extension("evfs-keyring"))?)?;
+    let a_id = a_keyring.database_id.expect("new database has an ID");
+
+    // B shares A's DEK, as a copied sidecar would, but has its own ID.
+    let b_keyring = PersistedKeyring {
+        database_id: Some(DatabaseId::generate()),
+        ..a_keyring
+    };
+    fs::write(
+        b_path.with_extension("evfs-keyring"),
+        bincode::encode_to_vec(&b_keyring, config::standard())?,
+    )?;
+    create(&b_path, "from b")?;
+    assert_ne!(keyring::database_id_of(&b_path), Some(a_id));
+
+    // A's page 2, identical in structure, no longer decrypts in B.
+    let a_raw = fs::read(&a_path)?;
+    let mut b_raw = fs::read(&b_path)?;
+    b_raw[4096..8192].copy_from_slice(&a_raw[4096..8192]);
+    fs::write(&b_path, &b_raw)?;
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &b_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+        "evfs_splice",
+    )?;
+    assert!(
+        conn.query_row("SELECT body FROM t", [], |r| r.get::<_, String>(0))
+            .is_err()
+    );
+    drop(conn);
+    let result = sqlevfs::verify::verify_database(&b_path, &keyring, 4096, 80)?;
+    assert_eq!(
+        result.bad_pages,
+        vec![(2, sqlevfs::verify::PageFault::BadTag)]
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test_log::test]
+fn test_read_only_open() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    use rusqlite::{Connection, OpenFlags, ffi::ErrorCode};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("ro.key");
+    fs::write(&keyfile, vec![0x4F; 32])?;
+
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir)?;
+    let db_path = data_dir.join("ro.db");
+    let sidecar_path = db_path.with_extension("evfs-keyring");
+
+    EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    })
+    .vfs_name("evfs_ro")
+    .register()?;
+    let open = |flags: OpenFlags| Connection::open_with_flags_and_vfs(&db_path, flags, "evfs_ro");
+    let read = |conn: &Connection| -> rusqlite::Result<i64> {
+        conn.query_row("SELECT count(*) FROM t WHERE body LIKE 'ro-%'", [], |r| {
+            r.get(0)
+        })
+    };
+
+    {
+        let conn = open(OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE)?;
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT)")?;
+        for i in 0..60 {
+            conn.execute(
+                "INSERT INTO t (body) VALUES (?1)",
+                [format!("ro-{i} ").repeat(40)],
+            )?;
+        }
+    }
+    let db_bytes = fs::read(&db_path)?;
+    let sidecar_bytes = fs::read(&sidecar_path)?;
+
+    // From a directory that can't be written to, with or without
+    // SQLITE_OPEN_CREATE; nothing on disk changes.
+    fs::set_permissions(&data_dir, fs::Permissions::from_mode(0o555))?;
+    let result = (|| -> anyhow::Result<()> {
+        for flags in [
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        ] {
+            let conn = open(flags)?;
+            assert_eq!(read(&conn)?, 60);
+            let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+            assert_eq!(check, "ok");
+        }
+        Ok(())
+    })();
+    fs::set_permissions(&data_dir, fs::Permissions::from_mode(0o755))?;
+    result?;
+    assert_eq!(fs::read(&db_path)?, db_bytes);
+    assert_eq!(fs::read(&sidecar_path)?, sidecar_bytes);
+
+    // A read-only open never creates a DEK, so without its sidecar the
+    // database reports SQLITE_READONLY and no sidecar appears.
+    fs::rename(&sidecar_path, data_dir.join("saved-keyring"))?;
+    // Re-register for a keyring with no DEK cached.
+    EvfsBuilder::new(Mode::DeviceKey {
+        keyfile: Some(temp_dir.path().join("ro.key")),
+        passphrase: None,
+    })
+    .vfs_name("evfs_ro")
+    .register()?;
+    let conn = open(OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let err = read(&conn).unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::ReadOnly), "{err}");
+    assert!(!sidecar_path.exists());
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_concealed_header() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+    use sqlevfs::{crypto::page, keyring::ConcealedHeader};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("conceal.key");
+    fs::write(&keyfile, vec![0x6B; 32])?;
+    let builder = |name: &str| {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name(name)
+    };
+    let keyring = builder("evfs_conceal").conceal_header(true).register()?;
+    builder("evfs_conceal_plain").register()?;
+
+    let db_path = test_db_path(&temp_dir, "concealed.db");
+    let sidecar_path = db_path.with_extension("evfs-keyring");
+    let open = |path: &PathBuf, vfs: &str| {
+        Connection::open_with_flags_and_vfs(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            vfs,
+        )
+    };
+    let check = |conn: &Connection, rows: i64| -> anyhow::Result<()> {
+        let integrity: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+        assert_eq!(integrity, "ok");
+        let count: i64 = conn.query_row(
+            "SELECT count(*) FROM notes WHERE body LIKE 'note-%'",
+            [],
+            |r| r.get(0),
+        )?;
+        assert_eq!(count, rows);
+        Ok(())
+    };
+
+    {
+        let conn = open(&db_path, "evfs_conceal")?;
+        conn.execute_batch(
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT, body TEXT);
+             CREATE INDEX notes_title ON notes (title);",
+        )?;
+        for i in 0..50 {
+            conn.execute(
+                "INSERT INTO notes (title, body) VALUES (?1, ?2)",
+                (format!("title {i}"), format!("note-{i} ").repeat(800)),
+            )?;
+        }
+        check(&conn, 50)?;
+    }
+
+    // Nothing on disk shows a SQLite database or its schema.
+    let raw = fs::read(&db_path)?;
+    assert!(page::is_encrypted_page(&raw[..4096], 80));
+    for needle in [&b"SQLite format 3"[..], b"CREATE TABLE", b"notes_title"] {
+        assert!(!raw.windows(needle.len()).any(|w| w == needle));
+    }
+    let persisted = PersistedKeyring::decode(&fs::read(&sidecar_path)?)?;
+    assert_eq!(
+        persisted.concealed_header,
+        Some(ConcealedHeader {
+            page_size: 4096,
+            reserve_size: 80,
+        })
+    );
+
+    // The sidecar, not the VFS's setting, decides for an existing
+    // database, in rollback and WAL mode alike.
+    {
+        let conn = open(&db_path, "evfs_conceal_plain")?;
+        check(&conn, 50)?;
+        conn.execute_batch("PRAGMA journal_mode=WAL")?;
+        conn.execute(
+            "INSERT INTO notes (title, body) VALUES ('wal', 'note-wal')",
+            [],
+        )?;
+        check(&open(&db_path, "evfs_conceal")?, 51)?;
+        conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE); PRAGMA journal_mode=DELETE")?;
+    }
+    assert!(!fs::read(&db_path)?.starts_with(b"SQLite format 3"));
+
+    // Rotating the data key re-encrypts page 1 too.
+    let before = fs::read(&db_path)?;
+    keyring.rotate_data_key(&db_path)?;
+    let after = fs::read(&db_path)?;
+    assert_ne!(before[..4096], after[..4096]);
+    check(&open(&db_path, "evfs_conceal")?, 51)?;
+
+    // An export has a plaintext header stock SQLite reads.
+    let export = test_db_path(&temp_dir, "export.db");
+    migrate::decrypt_database(&db_path, &export, &keyring)?;
+    check(&Connection::open(&export)?, 51)?;
+
+    // A plaintext header whose sidecar says it is concealed is refused.
+    let plain_path = test_db_path(&temp_dir, "plain.db");
+    open(&plain_path, "evfs_conceal_plain")?.execute_batch("CREATE TABLE t (x)")?;
+    let plain_sidecar = plain_path.with_extension("evfs-keyring");
+    let mixed = PersistedKeyring {
+        keys: Default::default(),
+        concealed_header: persisted.concealed_header,
+        database_id: None,
+    };
+    fs::write(
+        &plain_sidecar,
+        bincode::encode_to_vec(&mixed, config::standard())?,
+    )?;
+    let err = open(&plain_path, "evfs_conceal_plain")
+        .and_then(|conn| conn.query_row("SELECT count(*) FROM t", [], |r| r.get::<_, i64>(0)))
+        .unwrap_err();
+    assert_eq!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::CannotOpen),
+        "{err}"
+    );
+
+    Ok(())
+}
+
+#[test_log::test]
+fn test_table_scopes() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+    use sqlevfs::crypto::{envelope, page};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("scopes.key");
+    fs::write(&keyfile, vec![0xAB; 32])?;
+
+    let db_path = test_db_path(&temp_dir, "scopes.db");
+    let sidecar_path = db_path.with_extension("evfs-keyring");
+
+    let mode = || Mode::DeviceKey {
+        keyfile: Some(keyfile.clone()),
+        passphrase: None,
+    };
+    let keyring = EvfsBuilder::new(mode())
+        .vfs_name("evfs_scopes")
+        .table_scope("alpha")
+        .table_scope("beta")
+        .register()?;
+    let open = |flags: OpenFlags, vfs| Connection::open_with_flags_and_vfs(&db_path, flags, vfs);
+
+    let insert = |conn: &Connection, table: &str, from: i64, to: i64| -> rusqlite::Result<()> {
+        conn.execute_batch("BEGIN")?;
+        for i in from..to {
+            // Every page of the table, overflow pages included, holds
+            // its marker.
+            conn.execute(
+                &format!("INSERT INTO {table} (id, body) VALUES (?1, ?2)"),
+                rusqlite::params![i, format!("{table}-row {i} ").repeat(150)],
+            )?;
+        }
+        conn.execute_batch("COMMIT")
+    };
+
+    // Each page decrypts under exactly one DEK, the one of the table
+    // whose rows it holds.
+    let check_pages = || -> anyhow::Result<()> {
+        let kr: PersistedKeyring = PersistedKeyring::decode(&fs::read(&sidecar_path)?)?;
+        let dek = |scope: &str| envelope::unwrap_dek(&kr.keys[scope], keyring.provider());
+        let deks = [
+            ("alpha-row", dek("table:alpha")?),
+            ("beta-row", dek("table:beta")?),
+            ("gamma-row", dek("database")?),
+        ];
+
+        let raw = fs::read(&db_path)?;
+        let mut used = [0; 3];
+        for (i, chunk) in raw.chunks(4096).enumerate().skip(1) {
+            let page_no = i as u32 + 1;
+            if !page::is_encrypted_page(chunk, 80) {
+                continue;
+            }
+            let mut opened = Vec::new();
+            for (d, (_, dek)) in deks.iter().enumerate() {
+                let mut plain = chunk.to_vec();
+                if page::decrypt_page(&mut plain, page_no, kr.database_id, dek, 80).is_ok() {
+                    opened.push((d, plain));
+                }
+            }
+            assert_eq!(
+                opened.len(),
+                1,
+                "page {page_no} opened by {} DEKs",
+                opened.len()
+            );
+            let (d, plain) = &opened[0];
+            used[*d] += 1;
+            let text = String::from_utf8_lossy(plain);
+            for (m, (marker, _)) in deks.iter().enumerate() {
+                if text.contains(marker) {
+                    assert_eq!(m, *d, "page {page_no} holds {marker} under the wrong DEK");
+                }
+            }
+        }
+        assert!(used.iter().all(|&n| n > 0), "pages per DEK: {used:?}");
+        Ok(())
+    };
+
+    let conn = open(
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "evfs_scopes",
+    )?;
+    conn.execute_batch(
+        r#"
+        CREATE TABLE alpha (id INTEGER PRIMARY KEY, body TEXT);
+        CREATE TABLE beta (id INTEGER PRIMARY KEY, body TEXT);
+        CREATE INDEX beta_body ON beta (substr(body, 1, 500));
+        CREATE TABLE gamma (id INTEGER PRIMARY KEY, body TEXT);
+        "#,
+    )?;
+    for round in 0..3 {
+        for table in ["alpha", "beta", "gamma"] {
+            insert(&conn, table, round * 40, (round + 1) * 40)?;
+        }
+    }
+    check_pages()?;
+
+    // Pages freed by alpha are reused by beta.
+    conn.execute_batch("DELETE FROM alpha WHERE id >= 20")?;
+    insert(&conn, "beta", 1000, 1080)?;
+    check_pages()?;
+    drop(conn);
+
+    // Readable with the same scopes, and with none configured.
+    EvfsBuilder::new(mode())
+        .vfs_name("evfs_scopes_none")
+        .register()?;
+    for vfs in ["evfs_scopes", "evfs_scopes_none"] {
+        let conn = open(OpenFlags::SQLITE_OPEN_READ_ONLY, vfs)?;
+        let check: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+        assert_eq!(check, "ok");
+        let counts: (i64, i64, i64) = conn.query_row(
+            "SELECT (SELECT count(*) FROM alpha), (SELECT count(*) FROM beta), \
+             (SELECT count(*) FROM gamma)",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )?;
+        assert_eq!(counts, (20, 200, 120));
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test_log::test]
+fn test_temp_files_are_encrypted() -> anyhow::Result<()> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("temp.key");
+    fs::write(&keyfile, vec![0x5A; 32])?;
+
+    let db_path = test_db_path(&temp_dir, "temp.db");
+
+    let mode = Mode::DeviceKey {
+        keyfile: Some(keyfile),
+        passphrase: None,
+    };
+    EvfsBuilder::new(mode).vfs_name("evfs_temp").register()?;
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "evfs_temp",
+    )?;
+    conn.execute_batch(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, k INTEGER, body TEXT);
+         PRAGMA cache_size = -16;
+         PRAGMA temp_store = FILE;",
+    )?;
+    conn.execute_batch("BEGIN")?;
+    for i in 0..8000i64 {
+        conn.execute(
+            "INSERT INTO t (k, body) VALUES (?1, ?2)",
+            rusqlite::params![
+                (i * 7919) % 8000,
+                format!("TEMP-SPILL-MARKER {i} ").repeat(16)
+            ],
+        )?;
+    }
+    conn.execute_batch("COMMIT")?;
+
+    // The sort outgrows the sorter's in-memory minimum (250 pages) and
+    // spills to temp files. They are unlinked as soon as they are
+    // opened, so find them through the open descriptors while the
+    // sorted rows are being read.
+    let mut stmt = conn.prepare("SELECT body FROM t ORDER BY k")?;
+    let mut rows = stmt.query([])?;
+    let first: String = rows.next()?.expect("sorted rows").get(0)?;
+    assert!(first.starts_with("TEMP-SPILL-MARKER"));
+
+    let mut spills = Vec::new();
+    for entry in fs::read_dir("/proc/self/fd")? {
+        let fd = entry?.path();
+        let Ok(target) = fs::read_link(&fd) else {
+            continue;
+        };
+        if target.to_string_lossy().contains("etilqs_") {
+            spills.push(fs::read(&fd)?);
+        }
+    }
+    drop(rows);
+
+    assert!(!spills.is_empty(), "the sort should have spilled to disk");
+    assert!(spills.iter().any(|raw| !raw.is_empty()));
+    for raw in &spills {
+        assert!(
+            !raw.windows(17).any(|w| w == b"TEMP-SPILL-MARKER"),
+            "temp file holds plaintext"
+        );
+    }
+
+    // A temp database reads back through its ephemeral key.
+    conn.execute_batch("CREATE TEMP TABLE tt AS SELECT * FROM t ORDER BY k")?;
+    let (count, bytes): (i64, i64) =
+        conn.query_row("SELECT count(*), sum(length(body)) FROM temp.tt", [], |r| {
+            Ok((r.get(0)?, r.get(1)?))
+        })?;
+    let expected: i64 = conn.query_row("SELECT sum(length(body)) FROM t", [], |r| r.get(0))?;
+    assert_eq!((count, bytes), (8000, expected));
+    conn.execute_batch("DELETE FROM temp.tt WHERE id % 2 = 0; VACUUM temp;")?;
+    let check: String = conn.query_row("PRAGMA temp.integrity_check", [], |r| r.get(0))?;
+    assert_eq!(check, "ok");
+
+    Ok(())
+}
+
 #[test_log::test]
 #[ignore]
 fn test_concurrent_access() -> anyhow::Result<()> {
@@ -0,0 +1,134 @@
+//! Restoring streams pages to disk, so its memory use doesn't grow with
+//! the database. This binary counts every allocation, which is why it
+//! is kept apart from the other integration tests.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use sqlevfs::{
+    backup::{self, BackupOptions},
+    crypto::{keys::KeyScope, page},
+    keyring::Keyring,
+    kms::{KmsProvider, local::DeviceKeyProvider},
+};
+use tempfile::TempDir;
+
+/// Bytes allocated now, and the most allocated at once since `PEAK`
+/// was last reset.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct Counting;
+
+impl Counting {
+    fn grew(by: usize) {
+        let live = LIVE.fetch_add(by, Ordering::Relaxed) + by;
+        PEAK.fetch_max(live, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            Self::grew(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+            Self::grew(new_size);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const PAGE_SIZE: usize = 4096;
+const RESERVE: usize = 80;
+/// Most the restore may allocate on top of what was allocated before it.
+const MEMORY_CEILING: usize = 1 << 20;
+
+fn provider(dir: &TempDir, name: &str, key: u8) -> Arc<dyn KmsProvider> {
+    let keyfile = dir.path().join(name);
+    std::fs::write(&keyfile, [key; 32]).unwrap();
+    Arc::new(DeviceKeyProvider::from_keyfile(keyfile))
+}
+
+/// Restores a database of `EVFS_RESTORE_TEST_MIB` MiB, 8 by default;
+/// set it to a few thousand to restore a multi-GB one.
+#[test_log::test]
+fn restore_memory_does_not_grow_with_the_database() -> anyhow::Result<()> {
+    let mib: usize = std::env::var("EVFS_RESTORE_TEST_MIB")
+        .ok()
+        .and_then(|mib| mib.parse().ok())
+        .unwrap_or(8);
+    let page_count = mib * (1 << 20) / PAGE_SIZE;
+
+    let dir = TempDir::new()?;
+    let source_keyring = Keyring::new(provider(&dir, "source.key", 0x21));
+    let source_dek = source_keyring.dek_for(&KeyScope::Database)?;
+    let db_path = dir.path().join("source.db");
+    let mut db = BufWriter::new(File::create(&db_path)?);
+    let mut buf = vec![0u8; PAGE_SIZE];
+    for page_no in 1..=page_count as u32 {
+        buf[..PAGE_SIZE - RESERVE].fill(page_no as u8);
+        page::encrypt_page(&mut buf, page_no, None, &source_dek, RESERVE)?;
+        db.write_all(&buf)?;
+    }
+    db.into_inner()?.sync_all()?;
+
+    let backup_provider = provider(&dir, "backup.key", 0x22);
+    let backup_path = dir.path().join("source.evfs-backup");
+    let mut out = BufWriter::new(File::create(&backup_path)?);
+    backup::create_backup(
+        &db_path,
+        &mut out,
+        &source_keyring,
+        backup_provider.as_ref(),
+        PAGE_SIZE as u32,
+        RESERVE,
+    )?;
+    out.into_inner()?.sync_all()?;
+    std::fs::remove_file(&db_path)?;
+
+    let target_keyring = Keyring::new(provider(&dir, "target.key", 0x23));
+    let target_path = dir.path().join("restored.db");
+    let before = LIVE.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    backup::restore_backup_with(
+        &mut BufReader::new(File::open(&backup_path)?),
+        &target_path,
+        backup_provider.as_ref(),
+        &target_keyring,
+        &BackupOptions::default().threads(4),
+    )?;
+    let grown = PEAK.load(Ordering::Relaxed) - before;
+
+    assert_eq!(
+        std::fs::metadata(&target_path)?.len(),
+        (page_count * PAGE_SIZE) as u64
+    );
+    assert!(
+        grown < MEMORY_CEILING,
+        "restoring {mib} MiB allocated {grown} bytes at once"
+    );
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! The VFS reports why it fails through SQLite's error log and
+//! `xGetLastError`, not only the `log` crate. SQLite's log callback can
+//! only be set before SQLite is initialised, which is why this is kept
+//! apart from the other integration tests.
+
+use std::{
+    ffi::{CStr, c_char, c_int, c_void},
+    fs,
+    sync::Mutex,
+};
+
+use libsqlite3_sys as ffi;
+use rusqlite::{Connection, OpenFlags};
+use sqlevfs::*;
+use tempfile::TempDir;
+
+/// Every message SQLite logged, with its result code.
+static LOGGED: Mutex<Vec<(c_int, String)>> = Mutex::new(Vec::new());
+
+extern "C" fn capture(_: *mut c_void, rc: c_int, message: *const c_char) {
+    let message = unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned();
+    LOGGED.lock().unwrap().push((rc, message));
+}
+
+/// The first message logged with `rc` since the last call.
+fn logged(rc: c_int) -> Option<String> {
+    let messages = std::mem::take(&mut *LOGGED.lock().unwrap());
+    messages
+        .into_iter()
+        .find_map(|(code, message)| (code == rc).then_some(message))
+}
+
+/// What `xGetLastError` of the VFS `name` says on this thread.
+fn last_error(name: &CStr) -> String {
+    let mut buf = [0 as c_char; 512];
+    unsafe {
+        let vfs = ffi::sqlite3_vfs_find(name.as_ptr());
+        assert!(!vfs.is_null());
+        ((*vfs).xGetLastError.unwrap())(vfs, buf.len() as c_int, buf.as_mut_ptr());
+        CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+    }
+}
+
+#[test_log::test]
+fn test_failures_reach_sqlite_log_and_last_error() -> anyhow::Result<()> {
+    unsafe {
+        ffi::sqlite3_shutdown();
+        let rc = ffi::sqlite3_config(
+            ffi::SQLITE_CONFIG_LOG,
+            capture as extern "C" fn(*mut c_void, c_int, *const c_char),
+            std::ptr::null_mut::<c_void>(),
+        );
+        assert_eq!(rc, ffi::SQLITE_OK);
+    }
+
+    let temp_dir = TempDir::new()?;
+    let keyfile = temp_dir.path().join("log.key");
+    fs::write(&keyfile, vec![0x5A; 32])?;
+    let db_path = temp_dir.path().join("log.db");
+    let register = |name: &str| {
+        EvfsBuilder::new(Mode::DeviceKey {
+            keyfile: Some(keyfile.clone()),
+            passphrase: None,
+        })
+        .vfs_name(name)
+        .register()
+    };
+    let open = |name: &str| {
+        Connection::open_with_flags_and_vfs(&db_path, OpenFlags::SQLITE_OPEN_READ_WRITE, name)
+    };
+
+    register("evfs_log")?;
+    let conn = Connection::open_with_flags_and_vfs(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "evfs_log",
+    )?;
+    conn.execute_batch("CREATE TABLE t (v TEXT); INSERT INTO t VALUES ('secret');")?;
+    conn.close().map_err(|(_, e)| e)?;
+    sqlevfs::unregister("evfs_log")?;
+
+    // A page altered on disk fails authentication, rather than with a
+    // bare I/O error.
+    let mut raw = fs::read(&db_path)?;
+    raw[4096 + 200] ^= 0x01;
+    fs::write(&db_path, &raw)?;
+    register("evfs_log")?;
+    let conn = open("evfs_log")?;
+    let err = conn
+        .query_row("SELECT v FROM t", [], |r| r.get::<_, String>(0))
+        .unwrap_err();
+    assert_eq!(
+        err.sqlite_error().map(|e| e.extended_code),
+        Some(ffi::SQLITE_IOERR_AUTH)
+    );
+    let message = logged(ffi::SQLITE_IOERR_AUTH).expect("no SQLITE_IOERR_AUTH logged");
+    assert!(message.contains("page 2"), "{message}");
+    assert!(message.contains("authentication failed"), "{message}");
+    assert!(message.contains("wrong key?"), "{message}");
+    assert_eq!(last_error(c"evfs_log"), message);
+    drop(conn);
+    sqlevfs::unregister("evfs_log")?;
+
+    // So does a database opened under another key, which fails to open.
+    raw[4096 + 200] ^= 0x01;
+    fs::write(&db_path, &raw)?;
+    fs::write(&keyfile, vec![0xA5; 32])?;
+    register("evfs_log_wrong_key")?;
+    let err = open("evfs_log_wrong_key")
+        .and_then(|conn| conn.query_row("SELECT v FROM t", [], |r| r.get::<_, String>(0)))
+        .unwrap_err();
+    assert_eq!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::CannotOpen)
+    );
+    let message = logged(ffi::SQLITE_CANTOPEN).expect("no SQLITE_CANTOPEN logged");
+    assert!(message.contains("log.db"), "{message}");
+    assert!(
+        message.contains("failed authentication (wrong key?)"),
+        "{message}"
+    );
+    assert_eq!(last_error(c"evfs_log_wrong_key"), message);
+    sqlevfs::unregister("evfs_log_wrong_key")
+}
@@ -0,0 +1,89 @@
+//! `sec_label_visible` over 200k rows sharing four labels, memoized per
+//! connection as the views call it, and evaluated for every row as it was
+//! before the memo. SQLite is linked directly, so this runs with
+//! `cargo bench --no-default-features --bench label_visible`.
+
+#[cfg(not(feature = "extension"))]
+mod bench {
+    use criterion::{Criterion, Throughput, criterion_group};
+    use rusqlite::{Connection, functions::FunctionFlags};
+    use sqlsec::{SecureConnection, context::effective_context, label::evaluate::evaluate_by_id};
+
+    const ROWS: u64 = 200_000;
+
+    /// A table whose rows cycle through four labels, two of them visible to
+    /// the context
+    fn docs() -> SecureConnection {
+        let conn = SecureConnection::open_in_memory().unwrap();
+        let labels = [
+            conn.define_label("role=admin").unwrap(),
+            conn.define_label("role=user").unwrap(),
+            conn.define_label("(role=admin|role=auditor)").unwrap(),
+            conn.define_label("role=admin&team=finance").unwrap(),
+        ];
+        conn.execute_batch(&format!(
+            r#"
+            CREATE TABLE __sec_docs (id INTEGER PRIMARY KEY, row_label_id INTEGER, body TEXT);
+            WITH RECURSIVE n(i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i + 1 < {ROWS})
+            INSERT INTO __sec_docs
+            SELECT i, CASE i % 4 WHEN 0 THEN {} WHEN 1 THEN {} WHEN 2 THEN {} ELSE {} END, 'body'
+            FROM n;
+            "#,
+            labels[0], labels[1], labels[2], labels[3]
+        ))
+        .unwrap();
+        conn.set_attr("role", "admin").unwrap();
+        register_per_row(&conn);
+        conn
+    }
+
+    /// `sec_label_visible` as it was before the memo: the context copied and
+    /// the label looked up and evaluated on every call
+    fn register_per_row(conn: &Connection) {
+        let db_ptr = unsafe { conn.handle() as usize };
+        conn.create_scalar_function(
+            "per_row_label_visible",
+            1,
+            FunctionFlags::SQLITE_UTF8,
+            move |ctx| {
+                let id: i64 = ctx.get(0)?;
+                evaluate_by_id(db_ptr, id, &effective_context(db_ptr))
+            },
+        )
+        .unwrap();
+    }
+
+    fn count(conn: &Connection, visible: &str) -> i64 {
+        conn.query_row(
+            &format!("SELECT COUNT(*) FROM __sec_docs WHERE {visible}(row_label_id)"),
+            [],
+            |r| r.get(0),
+        )
+        .unwrap()
+    }
+
+    fn bench_label_visible(c: &mut Criterion) {
+        let conn = docs();
+        assert_eq!(count(&conn, "sec_label_visible"), ROWS as i64 / 2);
+        assert_eq!(count(&conn, "per_row_label_visible"), ROWS as i64 / 2);
+
+        let mut group = c.benchmark_group("label_visible_200k");
+        group.throughput(Throughput::Elements(ROWS));
+        group.sample_size(10);
+        group.bench_function("memoized", |b| b.iter(|| count(&conn, "sec_label_visible")));
+        group.bench_function("per_row", |b| {
+            b.iter(|| count(&conn, "per_row_label_visible"))
+        });
+        group.finish();
+    }
+
+    criterion_group!(benches, bench_label_visible);
+}
+
+#[cfg(not(feature = "extension"))]
+criterion::criterion_main!(bench::benches);
+
+#[cfg(feature = "extension")]
+fn main() {
+    eprintln!("label_visible links SQLite directly: run it with --no-default-features");
+}
@@ -0,0 +1,187 @@
+use std::mem::forget;
+
+use rusqlite::{Connection, Result};
+
+use crate::{config::meta_text, context::effective_context};
+
+/// Context attribute naming the actor when `audit_actor_attr` is unset
+pub const DEFAULT_ACTOR_ATTR: &str = "user";
+
+const LABEL_COLS: &[&str] = &["expr", "source"];
+const LEVEL_COLS: &[&str] = &["level_value"];
+const TABLE_COLS: &[&str] = &[
+    "physical_schema",
+    "physical_name",
+    "row_label_col",
+    "table_label_id",
+    "insert_label_id",
+    "delete_label_id",
+    "allow_implicit_label",
+    "materialize_labels",
+];
+const COLUMN_COLS: &[&str] = &["read_label_id", "update_label_id", "mask_expr"];
+
+/// A policy table whose changes are recorded
+struct Audited {
+    table: &'static str,
+    /// SQL naming the changed object, with `ROW` standing for `NEW` or `OLD`
+    target: &'static str,
+    cols: &'static [&'static str],
+    /// Action recorded for each of INSERT, UPDATE and DELETE, `None` if not
+    /// audited
+    actions: [Option<&'static str>; 3],
+}
+
+const AUDITED: &[Audited] = &[
+    Audited {
+        table: "sec_labels",
+        target: "ROW.id",
+        cols: LABEL_COLS,
+        actions: [
+            Some("define_label"),
+            Some("update_label"),
+            Some("delete_label"),
+        ],
+    },
+    Audited {
+        table: "sec_levels",
+        target: "ROW.attr_name || '.' || ROW.level_name",
+        cols: LEVEL_COLS,
+        actions: [
+            Some("define_level"),
+            Some("update_level"),
+            Some("delete_level"),
+        ],
+    },
+    Audited {
+        table: "sec_tables",
+        target: "ROW.logical_name",
+        cols: TABLE_COLS,
+        actions: [
+            Some("register_table"),
+            Some("update_table"),
+            Some("unregister_table"),
+        ],
+    },
+    Audited {
+        table: "sec_columns",
+        target: "ROW.logical_table || '.' || ROW.column_name",
+        cols: COLUMN_COLS,
+        actions: [
+            None,
+            Some("set_column_security"),
+            Some("drop_column_security"),
+        ],
+    },
+];
+
+fn row_json(row: &str, cols: &[&str]) -> String {
+    let fields = cols
+        .iter()
+        .map(|c| format!("'{c}', {row}.\"{c}\""))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("json_object({fields})")
+}
+
+/// Create the triggers recording every change to the policy tables in
+/// `sec_admin_audit`.
+///
+/// They live in the database rather than the connection, so changes made
+/// directly in SQL, or in the same transaction as anything else, are recorded
+/// alongside them; a connection without the extension cannot change policy.
+pub(crate) fn create_audit_triggers(conn: &Connection) -> Result<()> {
+    for Audited {
+        table,
+        target,
+        cols,
+        actions,
+    } in AUDITED
+    {
+        for (event, action) in ["INSERT", "UPDATE", "DELETE"].iter().zip(actions) {
+            let Some(action) = action else { continue };
+            let (row, detail) = match *event {
+                "INSERT" => ("NEW", row_json("NEW", cols)),
+                "DELETE" => ("OLD", row_json("OLD", cols)),
+                _ => (
+                    "NEW",
+                    format!(
+                        "json_object('old', {}, 'new', {})",
+                        row_json("OLD", cols),
+                        row_json("NEW", cols)
+                    ),
+                ),
+            };
+            let target = target.replace("ROW", row);
+            let suffix = &event[..3].to_lowercase();
+
+            conn.execute_batch(&format!(
+                r#"
+                CREATE TRIGGER IF NOT EXISTS "{table}_audit_{suffix}"
+                AFTER {event} ON "{table}"
+                BEGIN
+                    INSERT INTO sec_admin_audit (ts, actor, action, target, detail_json)
+                    VALUES (unixepoch(), sec_audit_actor(), '{action}', {target}, {detail});
+                END;
+                "#
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// The actor recorded for changes made by this connection: the values of the
+/// `audit_actor_attr` context attribute, comma separated, or `None`
+pub fn audit_actor(conn: &Connection, db_ptr: usize) -> Result<Option<String>> {
+    let attr = meta_text(conn, "audit_actor_attr")?;
+    let attr = attr.as_deref().unwrap_or(DEFAULT_ACTOR_ATTR);
+
+    let ctx = effective_context(db_ptr);
+    let values = ctx.get_attrs(attr);
+    if values.is_empty() {
+        return Ok(None);
+    }
+    let mut values: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+    values.sort();
+    Ok(Some(values.join(",")))
+}
+
+pub fn audit_actor_raw(db_ptr: usize) -> Result<Option<String>> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = audit_actor(&conn, db_ptr);
+
+    forget(conn);
+    result
+}
+
+/// Delete audit records older than `older_than` seconds, returning the count.
+/// The purge is itself recorded.
+pub fn purge_admin_audit(conn: &mut Connection, db_ptr: usize, older_than: i64) -> Result<usize> {
+    let sp = conn.savepoint()?;
+
+    let removed = sp.execute(
+        "DELETE FROM sec_admin_audit WHERE ts < unixepoch() - ?1",
+        [older_than],
+    )?;
+    sp.execute(
+        r#"
+        INSERT INTO sec_admin_audit (ts, actor, action, target, detail_json)
+        VALUES (unixepoch(), ?1, 'purge_admin_audit', NULL,
+                json_object('older_than', ?2, 'removed', ?3))
+        "#,
+        rusqlite::params![audit_actor(&sp, db_ptr)?, older_than, removed as i64],
+    )?;
+
+    sp.commit()?;
+    Ok(removed)
+}
+
+pub fn purge_admin_audit_raw(db_ptr: usize, older_than: i64) -> Result<usize> {
+    let mut conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = purge_admin_audit(&mut conn, db_ptr, older_than);
+
+    forget(conn);
+    result
+}
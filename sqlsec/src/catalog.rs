@@ -0,0 +1,267 @@
+use std::{ffi::c_int, marker::PhantomData, mem::forget};
+
+use rusqlite::{
+    Connection,
+    Result,
+    ffi,
+    params_from_iter,
+    types::Value,
+    vtab::{
+        Context,
+        Filters,
+        IndexConstraintOp,
+        IndexInfo,
+        VTab,
+        VTabConnection,
+        VTabCursor,
+        eponymous_only_module,
+    },
+};
+
+/// A read-only eponymous virtual table over the security metadata: the rows
+/// of `query`, whose result columns are `columns` in order. Equality on the
+/// first column is pushed down into the query.
+pub struct Catalog {
+    name: &'static str,
+    columns: &'static [&'static str],
+    query: &'static str,
+}
+
+const CATALOGS: &[Catalog] = &[
+    Catalog {
+        name: "sqlsec_tables",
+        columns: &[
+            "logical_name",
+            "physical_schema",
+            "physical_name",
+            "row_label_col",
+            "table_label_id",
+            "table_label",
+            "insert_label_id",
+            "insert_label",
+            "delete_label_id",
+            "delete_label",
+            "allow_implicit_label",
+            "materialize_labels",
+            "visible",
+            "insertable",
+            "deletable",
+        ],
+        query: r#"
+            SELECT t.logical_name, t.physical_schema, t.physical_name, t.row_label_col,
+                   t.table_label_id, tl.expr, t.insert_label_id, il.expr,
+                   t.delete_label_id, dl.expr, t.allow_implicit_label, t.materialize_labels,
+                   sec_label_visible(t.table_label_id),
+                   sec_label_visible(t.insert_label_id),
+                   sec_label_visible(t.delete_label_id)
+            FROM sec_tables t
+            LEFT JOIN sec_labels tl ON tl.id = t.table_label_id
+            LEFT JOIN sec_labels il ON il.id = t.insert_label_id
+            LEFT JOIN sec_labels dl ON dl.id = t.delete_label_id
+            ORDER BY t.logical_name
+        "#,
+    },
+    Catalog {
+        name: "sqlsec_columns",
+        columns: &[
+            "logical_table",
+            "column_name",
+            "read_label_id",
+            "read_label",
+            "update_label_id",
+            "update_label",
+            "mask_expr",
+            "readable",
+            "updatable",
+        ],
+        query: r#"
+            SELECT c.logical_table, c.column_name, c.read_label_id, rl.expr,
+                   c.update_label_id, ul.expr, c.mask_expr,
+                   sec_label_visible(c.read_label_id),
+                   sec_label_visible(c.update_label_id)
+            FROM sec_columns c
+            LEFT JOIN sec_labels rl ON rl.id = c.read_label_id
+            LEFT JOIN sec_labels ul ON ul.id = c.update_label_id
+            ORDER BY c.logical_table, c.rowid
+        "#,
+    },
+    Catalog {
+        name: "sqlsec_labels",
+        columns: &["id", "expr", "source", "visible"],
+        query: r#"
+            SELECT id, expr, source, sec_label_visible(id)
+            FROM sec_labels ORDER BY id
+        "#,
+    },
+    Catalog {
+        name: "sqlsec_policies",
+        columns: &[
+            "logical_table",
+            "column_name",
+            "policy",
+            "label_id",
+            "label",
+            "granted",
+        ],
+        query: r#"
+            SELECT p.logical_table, p.column_name, p.policy, p.label_id, l.expr,
+                   sec_label_visible(p.label_id)
+            FROM (
+                SELECT logical_name AS logical_table, NULL AS column_name,
+                       'table' AS policy, table_label_id AS label_id, 0 AS ord
+                FROM sec_tables
+                UNION ALL
+                SELECT logical_name, NULL, 'insert', insert_label_id, 1 FROM sec_tables
+                UNION ALL
+                SELECT logical_name, NULL, 'delete', delete_label_id, 2 FROM sec_tables
+                UNION ALL
+                SELECT logical_table, column_name, 'read', read_label_id, 3 FROM sec_columns
+                UNION ALL
+                SELECT logical_table, column_name, 'update', update_label_id, 4 FROM sec_columns
+            ) p
+            JOIN sec_labels l ON l.id = p.label_id
+            ORDER BY p.logical_table, p.ord, p.column_name
+        "#,
+    },
+];
+
+/// `idx_num` of a scan constrained by `first column = ?1`
+const KEY_EQ: c_int = 1;
+
+/// Register every catalog table on `conn`
+pub(crate) fn register_catalogs(conn: &Connection) -> Result<()> {
+    for catalog in CATALOGS {
+        conn.create_module(
+            catalog.name,
+            eponymous_only_module::<CatalogTab>(),
+            Some(catalog),
+        )?;
+    }
+    Ok(())
+}
+
+#[repr(C)]
+struct CatalogTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    db_ptr: usize,
+    catalog: &'static Catalog,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for CatalogTab {
+    type Aux = &'static Catalog;
+    type Cursor = CatalogCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&&'static Catalog>,
+        _args: &[&[u8]],
+    ) -> Result<(String, Self)> {
+        let catalog = *aux.expect("catalog tables are registered with their catalog");
+        let vtab = Self {
+            base: ffi::sqlite3_vtab::default(),
+            db_ptr: unsafe { db.handle() } as usize,
+            catalog,
+        };
+        let schema = format!("CREATE TABLE x({})", catalog.columns.join(", "));
+        Ok((schema, vtab))
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> Result<()> {
+        let key_eq = info.constraints().position(|c| {
+            c.column() == 0
+                && c.is_usable()
+                && c.operator() == IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ
+        });
+
+        match key_eq {
+            Some(i) => {
+                // SQLite re-checks the constraint, so collation differences
+                // can only widen what the query returns
+                info.constraint_usage(i).set_argv_index(1);
+                info.set_idx_num(KEY_EQ);
+                info.set_estimated_cost(10.0);
+                info.set_estimated_rows(10);
+            }
+            None => {
+                info.set_idx_num(0);
+                info.set_estimated_cost(1000.0);
+                info.set_estimated_rows(1000);
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&'vtab mut self) -> Result<CatalogCursor<'vtab>> {
+        Ok(CatalogCursor {
+            base: ffi::sqlite3_vtab_cursor::default(),
+            db_ptr: self.db_ptr,
+            catalog: self.catalog,
+            rows: Vec::new(),
+            pos: 0,
+            phantom: PhantomData,
+        })
+    }
+}
+
+#[repr(C)]
+struct CatalogCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    db_ptr: usize,
+    catalog: &'static Catalog,
+    rows: Vec<Vec<Value>>,
+    pos: usize,
+    phantom: PhantomData<&'vtab CatalogTab>,
+}
+
+fn query_rows(conn: &Connection, catalog: &Catalog, key: Option<Value>) -> Result<Vec<Vec<Value>>> {
+    let n = catalog.columns.len();
+    let sql = match key {
+        Some(_) => format!(
+            "SELECT * FROM ({}) WHERE {} = ?1",
+            catalog.query, catalog.columns[0]
+        ),
+        None => catalog.query.to_string(),
+    };
+
+    let mut stmt = conn.prepare(&sql)?;
+    stmt.query_map(params_from_iter(key), |r| {
+        (0..n).map(|i| r.get::<_, Value>(i)).collect()
+    })?
+    .collect()
+}
+
+unsafe impl VTabCursor for CatalogCursor<'_> {
+    fn filter(&mut self, idx_num: c_int, _idx_str: Option<&str>, args: &Filters<'_>) -> Result<()> {
+        let key = match idx_num {
+            KEY_EQ => Some(args.get::<Value>(0)?),
+            _ => None,
+        };
+
+        let conn = unsafe { Connection::from_handle(self.db_ptr as *mut _)? };
+        let rows = query_rows(&conn, self.catalog, key);
+        forget(conn);
+
+        self.rows = rows?;
+        self.pos = 0;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        self.pos >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> Result<()> {
+        ctx.set_result(&self.rows[self.pos][i as usize])
+    }
+
+    fn rowid(&self) -> Result<i64> {
+        Ok(self.pos as i64 + 1)
+    }
+}
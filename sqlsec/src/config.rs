@@ -0,0 +1,102 @@
+use std::{collections::HashSet, mem::forget};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rusqlite::{Connection, OptionalExtension, Result, types::Value};
+
+/// `sec_meta` settings that `sec_configure` may change. Bookkeeping such as
+/// `generation` is deliberately left out.
+pub const CONFIG_KEYS: &[&str] = &[
+    "max_clause_requirements",
+    "max_label_clauses",
+    "max_label_length",
+    "persist_context",
+    "session_ttl",
+];
+
+/// `sec_meta` settings holding text rather than an integer
+pub const TEXT_CONFIG_KEYS: &[&str] = &["audit_actor_attr"];
+
+/// Settings held per connection rather than in `sec_meta`
+pub const CONNECTION_KEYS: &[&str] = &["auto_refresh"];
+
+/// Connections that rebuild their views whenever the context changes
+static AUTO_REFRESH: Lazy<Mutex<HashSet<usize>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+pub fn auto_refresh(db_ptr: usize) -> bool {
+    AUTO_REFRESH.lock().contains(&db_ptr)
+}
+
+/// Read an integer setting from `sec_meta`, 0 if it is missing
+pub fn meta_value(conn: &Connection, key: &str) -> Result<i64> {
+    let value: Option<i64> = conn
+        .query_row("SELECT value FROM sec_meta WHERE key = ?1", [key], |r| {
+            r.get(0)
+        })
+        .optional()?;
+    Ok(value.unwrap_or(0))
+}
+
+/// Read a text setting from `sec_meta`, `None` if it is missing
+pub fn meta_text(conn: &Connection, key: &str) -> Result<Option<String>> {
+    conn.query_row("SELECT value FROM sec_meta WHERE key = ?1", [key], |r| {
+        r.get(0)
+    })
+    .optional()
+}
+
+fn config_err(msg: String) -> rusqlite::Error {
+    rusqlite::Error::UserFunctionError(msg.into())
+}
+
+pub fn configure(conn: &Connection, db_ptr: usize, key: &str, value: &Value) -> Result<()> {
+    let key = key.to_lowercase();
+    let text = TEXT_CONFIG_KEYS.contains(&key.as_str());
+    if !text && !CONFIG_KEYS.contains(&key.as_str()) && !CONNECTION_KEYS.contains(&key.as_str()) {
+        let mut known: Vec<&str> = CONFIG_KEYS
+            .iter()
+            .chain(TEXT_CONFIG_KEYS)
+            .chain(CONNECTION_KEYS)
+            .copied()
+            .collect();
+        known.sort();
+        return Err(config_err(format!(
+            "unknown setting '{key}' (expected one of {})",
+            known.join(", ")
+        )));
+    }
+    match (text, value) {
+        (true, Value::Text(_)) | (false, Value::Integer(_)) => {}
+        (true, _) => return Err(config_err(format!("setting '{key}' takes text"))),
+        (false, _) => return Err(config_err(format!("setting '{key}' takes an integer"))),
+    }
+
+    if key == "auto_refresh" {
+        let mut conns = AUTO_REFRESH.lock();
+        if *value != Value::Integer(0) {
+            conns.insert(db_ptr);
+        } else {
+            conns.remove(&db_ptr);
+        }
+        return Ok(());
+    }
+    conn.execute(
+        "INSERT OR REPLACE INTO sec_meta (key, value) VALUES (?1, ?2)",
+        rusqlite::params![key, value],
+    )?;
+    Ok(())
+}
+
+pub fn configure_raw(db_ptr: usize, key: &str, value: &Value) -> Result<()> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = configure(&conn, db_ptr, key, value);
+
+    forget(conn);
+    result
+}
+
+/// Forget the settings of a connection that has closed
+pub(crate) fn release_settings(db_ptr: usize) {
+    AUTO_REFRESH.lock().remove(&db_ptr);
+}
@@ -0,0 +1,251 @@
+use std::{ops::Deref, path::Path};
+
+use rusqlite::{Connection, Result, params};
+
+use crate::init::init_connection;
+
+/// Create the metadata tables and register every `sec_*` function, catalog
+/// table and trigger on `conn`, as loading the extension does.
+pub fn init(conn: &Connection) -> Result<()> {
+    init_connection(conn)
+}
+
+/// How to register a secured table with [`ConnectionExt::sec_register_table`].
+/// Labels are ids from [`ConnectionExt::sec_define_label`]; any left unset
+/// leave their operation unrestricted.
+#[derive(Debug, Clone)]
+pub struct RegisterOpts {
+    logical: String,
+    physical: String,
+    row_label_col: String,
+    table_label: Option<i64>,
+    insert_label: Option<i64>,
+    delete_label: Option<i64>,
+    allow_implicit_label: Option<bool>,
+}
+
+impl RegisterOpts {
+    /// Register `physical` behind the view `logical`, with row labels in
+    /// `row_label_col`
+    pub fn new(logical: &str, physical: &str, row_label_col: &str) -> Self {
+        Self {
+            logical: logical.to_string(),
+            physical: physical.to_string(),
+            row_label_col: row_label_col.to_string(),
+            table_label: None,
+            insert_label: None,
+            delete_label: None,
+            allow_implicit_label: None,
+        }
+    }
+
+    /// Label the context must pass to see the table at all
+    pub fn table_label(mut self, label_id: i64) -> Self {
+        self.table_label = Some(label_id);
+        self
+    }
+
+    /// Label given to rows inserted without one, and required to insert
+    pub fn insert_label(mut self, label_id: i64) -> Self {
+        self.insert_label = Some(label_id);
+        self
+    }
+
+    /// Label the context must pass to delete rows
+    pub fn delete_label(mut self, label_id: i64) -> Self {
+        self.delete_label = Some(label_id);
+        self
+    }
+
+    /// Whether rows may be inserted without a row label (the default)
+    pub fn allow_implicit_label(mut self, allow: bool) -> Self {
+        self.allow_implicit_label = Some(allow);
+        self
+    }
+}
+
+/// The `sec_*` functions as methods of any connection sqlsec is initialized
+/// on, for code that keeps its own [`Connection`].
+///
+/// Errors are those of the SQL functions, so their text starts with the
+/// function name:
+///
+/// ```
+/// # fn main() -> rusqlite::Result<()> {
+/// use rusqlite::Connection;
+/// use sqlsec::{ConnectionExt, RegisterOpts};
+///
+/// let conn = Connection::open_in_memory()?;
+/// sqlsec::init(&conn)?;
+/// conn.execute_batch(
+///     "CREATE TABLE __sec_docs (id INTEGER PRIMARY KEY, row_label_id INTEGER, body TEXT);",
+/// )?;
+///
+/// let staff = conn.sec_define_label("role=staff")?;
+/// conn.sec_register_table(
+///     &RegisterOpts::new("docs", "__sec_docs", "row_label_id")
+///         .insert_label(staff)
+///         .allow_implicit_label(false),
+/// )?;
+/// conn.sec_set_attr("role", "staff")?;
+/// conn.sec_refresh_views()?;
+/// conn.execute("INSERT INTO docs (id, row_label_id, body) VALUES (1, ?1, 'memo')", [staff])?;
+///
+/// let err = conn.execute("INSERT INTO docs (id, body) VALUES (2, 'draft')", []).unwrap_err();
+/// assert!(err.to_string().contains("implicit row_label_col"), "{err}");
+///
+/// let err = conn.sec_define_label("role=").unwrap_err();
+/// assert!(err.to_string().starts_with("define_label:"));
+/// # Ok(())
+/// # }
+/// ```
+pub trait ConnectionExt {
+    /// Add `value` to the context attribute `key`
+    fn sec_set_attr(&self, key: &str, value: &str) -> Result<()>;
+
+    /// Remove every attribute from the current context frame
+    fn sec_clear_context(&self) -> Result<()>;
+
+    /// Define a label, or find the existing one with the same canonical form,
+    /// and return its id
+    fn sec_define_label(&self, expr: &str) -> Result<i64>;
+
+    /// Register a secured table and create its view
+    fn sec_register_table(&self, opts: &RegisterOpts) -> Result<()>;
+
+    /// Rebuild the logical views for the current context
+    fn sec_refresh_views(&self) -> Result<()>;
+}
+
+impl ConnectionExt for Connection {
+    fn sec_set_attr(&self, key: &str, value: &str) -> Result<()> {
+        self.query_row("SELECT sec_set_attr(?1, ?2)", [key, value], |_| Ok(()))
+    }
+
+    fn sec_clear_context(&self) -> Result<()> {
+        self.query_row("SELECT sec_clear_context()", [], |_| Ok(()))
+    }
+
+    fn sec_define_label(&self, expr: &str) -> Result<i64> {
+        self.query_row("SELECT sec_define_label(?1)", [expr], |r| r.get(0))
+    }
+
+    fn sec_register_table(&self, opts: &RegisterOpts) -> Result<()> {
+        // `Connection::savepoint` needs `&mut`, so the savepoint is spelled out
+        // to keep the registration and the flag one change
+        self.execute_batch("SAVEPOINT sec_register_table")?;
+        let result = self
+            .query_row(
+                "SELECT sec_register_table(?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    opts.logical,
+                    opts.physical,
+                    opts.row_label_col,
+                    opts.table_label,
+                    opts.insert_label,
+                    opts.delete_label
+                ],
+                |_| Ok(()),
+            )
+            .and_then(|()| match opts.allow_implicit_label {
+                Some(allow) => self
+                    .execute(
+                        "UPDATE sec_tables SET allow_implicit_label = ?1 WHERE logical_name = ?2",
+                        params![allow, opts.logical],
+                    )
+                    .map(drop),
+                None => Ok(()),
+            });
+        match result {
+            Ok(()) => self.execute_batch("RELEASE sec_register_table"),
+            Err(e) => {
+                // The registration's error is the one worth reporting; if the
+                // rollback fails too there is nothing better to do with it
+                let _ = self.execute_batch("ROLLBACK TO sec_register_table");
+                let _ = self.execute_batch("RELEASE sec_register_table");
+                Err(e)
+            }
+        }
+    }
+
+    fn sec_refresh_views(&self) -> Result<()> {
+        self.query_row("SELECT sec_refresh_views()", [], |_| Ok(()))
+    }
+}
+
+/// A rusqlite connection with sqlsec initialized on it.
+///
+/// Derefs to the [`Connection`], so the logical views are queried as usual;
+/// the methods are typed versions of the `sec_*` functions of the same name.
+pub struct SecureConnection {
+    conn: Connection,
+}
+
+impl SecureConnection {
+    /// Initialize sqlsec on an open connection
+    pub fn new(conn: Connection) -> Result<Self> {
+        init(&conn)?;
+        Ok(Self { conn })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    /// Give back the underlying connection, still initialized
+    pub fn into_inner(self) -> Connection {
+        self.conn
+    }
+
+    /// Add `value` to the context attribute `key`
+    pub fn set_attr(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.sec_set_attr(key, value)
+    }
+
+    /// Remove every attribute from the current context frame
+    pub fn clear_context(&self) -> Result<()> {
+        self.conn.sec_clear_context()
+    }
+
+    /// Define a label, or find the existing one with the same canonical form,
+    /// and return its id
+    pub fn define_label(&self, expr: &str) -> Result<i64> {
+        self.conn.sec_define_label(expr)
+    }
+
+    /// Register `physical` as the secured table behind the view `logical`.
+    /// Labels are ids from [`SecureConnection::define_label`].
+    pub fn register_table(
+        &self,
+        logical: &str,
+        physical: &str,
+        row_label_col: &str,
+        table_label: Option<i64>,
+        insert_label: Option<i64>,
+        delete_label: Option<i64>,
+    ) -> Result<()> {
+        self.conn.sec_register_table(&RegisterOpts {
+            table_label,
+            insert_label,
+            delete_label,
+            ..RegisterOpts::new(logical, physical, row_label_col)
+        })
+    }
+
+    /// Rebuild the logical views for the current context
+    pub fn refresh_views(&self) -> Result<()> {
+        self.conn.sec_refresh_views()
+    }
+}
+
+impl Deref for SecureConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
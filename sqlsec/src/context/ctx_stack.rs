@@ -39,22 +39,34 @@ impl ContextStack {
         self.push(Some(name.to_string()));
     }
 
+    /// Pop back to and including the most recent frame called `name`,
+    /// discarding any frames pushed after it
     pub fn pop_named(&mut self, name: &str) -> Option<SecurityContext> {
-        if let Some(pos) = self
+        let pos = self
             .stack
             .iter()
-            .rposition(|(n, _)| n.as_deref() == Some(name))
-        {
-            Some(self.stack.remove(pos).1)
-        } else {
-            None
-        }
+            .rposition(|(n, _)| n.as_deref() == Some(name))?;
+        self.stack.drain(pos..).next().map(|(_, ctx)| ctx)
     }
 
     /// Context used for access checks
     pub fn effective(&self) -> &SecurityContext {
         &self.stack.last().unwrap().1
     }
+
+    /// Frames from the base up, each with its optional name
+    pub fn frames(&self) -> &[(Option<String>, SecurityContext)] {
+        &self.stack
+    }
+
+    /// Rebuild a stack from `frames()`; `None` if there is no base frame
+    pub fn from_frames(frames: Vec<(Option<String>, SecurityContext)>) -> Option<Self> {
+        if frames.is_empty() {
+            None
+        } else {
+            Some(Self { stack: frames })
+        }
+    }
 }
 
 #[cfg(test)]
@@ -135,23 +147,53 @@ mod tests {
 
         assert_eq!(stack.stack.len(), 4);
 
-        // Remove "second" from the middle
+        // Popping "second" from the middle also discards "third"
         let removed = stack.pop_named("second");
         assert!(removed.is_some());
 
-        // Stack should shrink
-        assert_eq!(stack.stack.len(), 3);
+        // Stack should shrink to base + "first"
+        assert_eq!(stack.stack.len(), 2);
 
-        // Ensure "second" no longer exists
+        // Ensure "second" and "third" no longer exist
         assert!(stack.pop_named("second").is_none());
+        assert!(stack.pop_named("third").is_none());
 
-        // Ensure top is still "third"
+        // Ensure top is now "first"
         assert_eq!(
             stack.stack.last().unwrap().0.as_deref(),
-            Some("third")
+            Some("first")
         );
     }
 
+    #[test]
+    fn pop_named_restores_context_below() {
+        let mut stack = ContextStack::default();
+
+        stack.current_mut().set_attr("role", "user");
+        stack.push_named("request");
+        stack.current_mut().set_attr("role", "admin");
+        stack.push(None);
+        stack.current_mut().set_attr("team", "finance");
+
+        let removed = stack.pop_named("request").unwrap();
+        assert!(removed.has("role", "admin"));
+
+        assert!(stack.current().has("role", "user"));
+        assert!(!stack.current().has("role", "admin"));
+        assert!(!stack.current().has("team", "finance"));
+    }
+
+    #[test]
+    fn pop_named_innermost_duplicate() {
+        let mut stack = ContextStack::default();
+
+        stack.push_named("scope");
+        stack.push_named("scope");
+
+        assert!(stack.pop_named("scope").is_some());
+        assert_eq!(stack.stack.len(), 2);
+    }
+
     #[test]
     fn pop_named_returns_none_if_not_found() {
         let mut stack = ContextStack::default();
@@ -0,0 +1,139 @@
+use serde_json::{Map, Value, json};
+
+use crate::context::sec_ctx::SecurityContext;
+
+pub(crate) fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+impl SecurityContext {
+    /// `{"key": ["value", ...], ...}` with keys and values sorted
+    pub fn to_json(&self) -> Value {
+        let attrs: Map<String, Value> = self
+            .attrs
+            .iter()
+            .map(|(key, values)| {
+                let mut values: Vec<&String> = values.iter().collect();
+                values.sort();
+                (key.clone(), json!(values))
+            })
+            .collect();
+        Value::Object(attrs)
+    }
+
+    /// Build a context from a JSON object whose values are strings or arrays
+    /// of strings. Anything else is rejected rather than stringified.
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        let doc: Value = serde_json::from_str(text).map_err(|e| format!("invalid JSON: {e}"))?;
+        Self::from_json_value(&doc)
+    }
+
+    pub fn from_json_value(doc: &Value) -> Result<Self, String> {
+        let Value::Object(attrs) = doc else {
+            return Err(format!("expected a JSON object, got {}", kind(doc)));
+        };
+
+        let mut ctx = SecurityContext::default();
+        for (key, value) in attrs {
+            match value {
+                Value::String(v) => ctx.set_attr(key, v),
+                Value::Array(values) => {
+                    for (i, v) in values.iter().enumerate() {
+                        let Value::String(v) = v else {
+                            return Err(format!(
+                                "element {i} of '{key}' must be a string, got {}",
+                                kind(v)
+                            ));
+                        };
+                        ctx.set_attr(key, v);
+                    }
+                }
+                other => {
+                    return Err(format!(
+                        "'{key}' must be a string or an array of strings, got {}",
+                        kind(other)
+                    ));
+                }
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_json_sorts_values() {
+        let mut ctx = SecurityContext::default();
+        ctx.set_attr("team", "b");
+        ctx.set_attr("team", "a");
+        ctx.set_attr("role", "admin");
+
+        assert_eq!(
+            ctx.to_json().to_string(),
+            r#"{"role":["admin"],"team":["a","b"]}"#
+        );
+        assert_eq!(SecurityContext::default().to_json().to_string(), "{}");
+    }
+
+    #[test]
+    fn from_json_strings_and_arrays() {
+        let ctx = SecurityContext::from_json(r#"{"role":"admin","team":["a","b"]}"#).unwrap();
+
+        assert!(ctx.has("role", "admin"));
+        assert!(ctx.has("team", "a"));
+        assert!(ctx.has("team", "b"));
+        assert_eq!(ctx.attrs.len(), 2);
+    }
+
+    #[test]
+    fn from_json_round_trips() {
+        let ctx = SecurityContext::from_json(r#"{"Role":["admin","auditor"]}"#).unwrap();
+        let again = SecurityContext::from_json(&ctx.to_json().to_string()).unwrap();
+        assert_eq!(ctx, again);
+    }
+
+    #[test]
+    fn from_json_empty_array_sets_nothing() {
+        let ctx = SecurityContext::from_json(r#"{"groups":[]}"#).unwrap();
+        assert!(ctx.attrs.is_empty());
+    }
+
+    #[test]
+    fn from_json_rejects_non_objects() {
+        assert_eq!(
+            SecurityContext::from_json(r#"["admin"]"#).unwrap_err(),
+            "expected a JSON object, got array"
+        );
+        assert!(
+            SecurityContext::from_json("{role:admin}")
+                .unwrap_err()
+                .starts_with("invalid JSON")
+        );
+    }
+
+    #[test]
+    fn from_json_rejects_nested_and_scalars() {
+        assert_eq!(
+            SecurityContext::from_json(r#"{"user":{"role":"admin"}}"#).unwrap_err(),
+            "'user' must be a string or an array of strings, got object"
+        );
+        assert_eq!(
+            SecurityContext::from_json(r#"{"level":3}"#).unwrap_err(),
+            "'level' must be a string or an array of strings, got number"
+        );
+        assert_eq!(
+            SecurityContext::from_json(r#"{"team":["a",true]}"#).unwrap_err(),
+            "element 1 of 'team' must be a string, got boolean"
+        );
+    }
+}
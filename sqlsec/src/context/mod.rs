@@ -1,26 +1,78 @@
 pub mod sec_ctx;
 pub mod ctx_stack;
+pub mod json;
+pub mod session;
+pub mod snapshot;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, mem::forget, sync::Arc};
 
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
+use rusqlite::{Connection, Result};
 
-use crate::context::{sec_ctx::SecurityContext, ctx_stack::ContextStack};
+use crate::{
+    config::{auto_refresh, release_settings},
+    context::{
+        ctx_stack::ContextStack,
+        sec_ctx::SecurityContext,
+        session::{persist_context, release_session},
+    },
+    label::{evict_labels, forget_levels},
+    views::{bump_generation::bump_generation, refresh_views::refresh_views},
+};
 
-/// Global map: db handle address -> SecurityContext
-pub static CONTEXTS: Lazy<Mutex<HashMap<usize, ContextStack>>> =
+/// Global map: db handle address -> ContextStack
+///
+/// Each stack has its own lock, so threads sharing one serialized handle
+/// mutate it in place rather than racing on a copy.
+pub static CONTEXTS: Lazy<Mutex<HashMap<usize, Arc<Mutex<ContextStack>>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-/// Get or create context for a connection
-pub fn get_context_stack(db_ptr: usize) -> ContextStack {
+/// Get or create the context stack for a connection
+///
+/// Release its lock before running SQL: statements may call back into
+/// functions that read the context.
+pub fn context_stack(db_ptr: usize) -> Arc<Mutex<ContextStack>> {
     CONTEXTS.lock().entry(db_ptr).or_default().clone()
 }
 
-pub fn set_context_stack(db_ptr: usize, ctx: ContextStack) {
-    CONTEXTS.lock().insert(db_ptr, ctx);
+pub fn effective_context(db_ptr: usize) -> SecurityContext {
+    context_stack(db_ptr).lock().effective().clone()
 }
 
-pub fn effective_context(db_ptr: usize) -> SecurityContext {
-    get_context_stack(db_ptr).effective().clone()
+/// Record a change to the connection's context: stale the views, persist the
+/// new context if enabled, and rebuild the views if `auto_refresh` is on
+///
+/// Must be called without the stack's lock held.
+pub fn context_changed(conn: &mut Connection, db_ptr: usize) -> Result<()> {
+    bump_generation(conn)?;
+    persist_context(conn, db_ptr)?;
+
+    if auto_refresh(db_ptr) {
+        let has_tables: bool =
+            conn.query_row("SELECT EXISTS (SELECT 1 FROM sec_tables)", [], |r| r.get(0))?;
+        if has_tables {
+            refresh_views(conn, &effective_context(db_ptr))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn context_changed_raw(db_ptr: usize) -> Result<()> {
+    let mut conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = context_changed(&mut conn, db_ptr);
+
+    forget(conn);
+    result
 }
+
+/// Forget everything held for a connection that has closed
+pub fn release_connection(db_ptr: usize) {
+    CONTEXTS.lock().remove(&db_ptr);
+    release_session(db_ptr);
+    release_settings(db_ptr);
+    evict_labels(db_ptr);
+    forget_levels(db_ptr);
+}
+
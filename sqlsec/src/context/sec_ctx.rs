@@ -2,14 +2,14 @@ use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct SecurityContext {
-    /// key -> set of values
+    /// lowercased key -> set of values
     pub attrs: HashMap<String, HashSet<String>>,
 }
 
 impl SecurityContext {
     pub fn get_attrs(&self, key: &str) -> Vec<&String> {
         self.attrs
-            .get(key)
+            .get(&key.to_lowercase())
             .iter()
             .flat_map(|set| set.iter())
             .collect()
@@ -17,18 +17,34 @@ impl SecurityContext {
 
     pub fn set_attr(&mut self, key: &str, value: &str) {
         self.attrs
-            .entry(key.to_string())
+            .entry(key.to_lowercase())
             .or_default()
             .insert(value.to_string());
     }
 
-    pub fn clear_attr(&mut self, key: &str) {
-        self.attrs.remove(key);
+    /// Drop every value for `key`, returning how many were removed
+    pub fn clear_attr(&mut self, key: &str) -> usize {
+        self.attrs
+            .remove(&key.to_lowercase())
+            .map_or(0, |vals| vals.len())
+    }
+
+    /// Drop one value for `key`, and the key itself once it has no values left
+    pub fn remove_value(&mut self, key: &str, value: &str) -> usize {
+        let key = key.to_lowercase();
+        let Some(vals) = self.attrs.get_mut(&key) else {
+            return 0;
+        };
+        let removed = vals.remove(value) as usize;
+        if vals.is_empty() {
+            self.attrs.remove(&key);
+        }
+        removed
     }
 
     pub fn has(&self, key: &str, value: &str) -> bool {
         self.attrs
-            .get(key)
+            .get(&key.to_lowercase())
             .map(|vals| vals.contains(value))
             .unwrap_or(false)
     }
@@ -36,7 +52,10 @@ impl SecurityContext {
     /// Merge another context into this one
     pub fn merge(&mut self, other: &SecurityContext) {
         for (k, v) in &other.attrs {
-            self.attrs.entry(k.clone()).or_default().extend(v.iter().cloned());
+            self.attrs
+                .entry(k.clone())
+                .or_default()
+                .extend(v.iter().cloned());
         }
     }
 }
@@ -83,6 +102,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unnecessary_get_then_check)]
     fn clear_attr_removes_key_entirely() {
         let mut ctx = SecurityContext::default();
 
@@ -95,6 +115,57 @@ mod tests {
         assert!(ctx.attrs.get("role").is_none());
     }
 
+    #[test]
+    fn keys_are_case_insensitive() {
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("Role", "Admin");
+
+        assert!(ctx.has("role", "Admin"));
+        assert!(ctx.has("ROLE", "Admin"));
+        assert!(!ctx.has("role", "admin"));
+        assert!(ctx.attrs.contains_key("role"));
+
+        ctx.clear_attr("rOLE");
+        assert!(ctx.attrs.is_empty());
+    }
+
+    #[test]
+    fn clear_attr_counts_values() {
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("role", "admin");
+        ctx.set_attr("role", "user");
+
+        assert_eq!(ctx.clear_attr("role"), 2);
+        assert_eq!(ctx.clear_attr("role"), 0);
+    }
+
+    #[test]
+    fn remove_value_keeps_other_values() {
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("role", "admin");
+        ctx.set_attr("role", "user");
+
+        assert_eq!(ctx.remove_value("role", "admin"), 1);
+        assert!(!ctx.has("role", "admin"));
+        assert!(ctx.has("role", "user"));
+
+        assert_eq!(ctx.remove_value("role", "admin"), 0);
+        assert_eq!(ctx.remove_value("missing", "admin"), 0);
+    }
+
+    #[test]
+    fn remove_last_value_removes_key() {
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("role", "admin");
+        assert_eq!(ctx.remove_value("role", "admin"), 1);
+
+        assert!(!ctx.attrs.contains_key("role"));
+    }
+
     #[test]
     fn has_returns_false_for_missing_key() {
         let ctx = SecurityContext::default();
@@ -0,0 +1,119 @@
+use std::{collections::HashMap, mem::forget};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rusqlite::{Connection, OptionalExtension, Result, params};
+
+use crate::{
+    config::meta_value,
+    context::{
+        context_stack,
+        ctx_stack::ContextStack,
+        effective_context,
+        sec_ctx::SecurityContext,
+    },
+};
+
+/// Global map: db handle address -> session id restored on that connection
+static SESSIONS: Lazy<Mutex<HashMap<usize, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Save the effective context under the connection's session, if
+/// `persist_context` is on and `sec_restore_context` has named one
+pub fn persist_context(conn: &Connection, db_ptr: usize) -> Result<()> {
+    let Some(session_id) = SESSIONS.lock().get(&db_ptr).cloned() else {
+        return Ok(());
+    };
+    if meta_value(conn, "persist_context")? == 0 {
+        return Ok(());
+    }
+
+    let context = effective_context(db_ptr).to_json().to_string();
+    let ttl = meta_value(conn, "session_ttl")?;
+    conn.execute(
+        r#"
+        INSERT INTO sec_sessions (session_id, context, updated_at, expires_at)
+        VALUES (
+            ?1, ?2, unixepoch(),
+            CASE WHEN ?3 > 0 THEN unixepoch() + ?3 END
+        )
+        ON CONFLICT (session_id) DO UPDATE SET
+            context = excluded.context,
+            updated_at = excluded.updated_at,
+            expires_at = excluded.expires_at
+        "#,
+        params![session_id, context, ttl],
+    )?;
+    Ok(())
+}
+
+/// Bind the connection to `session_id` and replace its context stack with a
+/// single frame holding the saved context. An unknown or expired session
+/// starts empty. Returns the number of attributes restored.
+pub fn restore_context(conn: &Connection, db_ptr: usize, session_id: &str) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM sec_sessions WHERE session_id = ?1 AND expires_at <= unixepoch()",
+        [session_id],
+    )?;
+    let saved: Option<String> = conn
+        .query_row(
+            "SELECT context FROM sec_sessions WHERE session_id = ?1",
+            [session_id],
+            |r| r.get(0),
+        )
+        .optional()?;
+
+    let ctx = match saved {
+        Some(text) => SecurityContext::from_json(&text).map_err(|e| {
+            rusqlite::Error::UserFunctionError(
+                format!("session '{session_id}' holds an invalid context: {e}").into(),
+            )
+        })?,
+        None => SecurityContext::default(),
+    };
+    let count = ctx.attrs.len();
+
+    {
+        let frames = context_stack(db_ptr);
+        let mut stack = frames.lock();
+        *stack = ContextStack::default();
+        *stack.current_mut() = ctx;
+    }
+    SESSIONS.lock().insert(db_ptr, session_id.to_string());
+    Ok(count)
+}
+
+pub fn restore_context_raw(db_ptr: usize, session_id: &str) -> Result<usize> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = restore_context(&conn, db_ptr, session_id);
+
+    forget(conn);
+    result
+}
+
+/// Delete sessions not updated in the last `older_than` seconds, along with
+/// any past their expiry. Returns the number deleted.
+pub fn purge_sessions(conn: &Connection, older_than: i64) -> Result<usize> {
+    conn.execute(
+        r#"
+        DELETE FROM sec_sessions
+        WHERE updated_at < unixepoch() - ?1
+           OR expires_at <= unixepoch()
+        "#,
+        [older_than],
+    )
+}
+
+pub fn purge_sessions_raw(db_ptr: usize, older_than: i64) -> Result<usize> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = purge_sessions(&conn, older_than);
+
+    forget(conn);
+    result
+}
+
+/// Forget the session bound to a connection that has closed
+pub(crate) fn release_session(db_ptr: usize) {
+    SESSIONS.lock().remove(&db_ptr);
+}
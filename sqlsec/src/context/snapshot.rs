@@ -0,0 +1,95 @@
+use serde_json::{Value, json};
+
+use crate::context::{ctx_stack::ContextStack, json::kind, sec_ctx::SecurityContext};
+
+impl ContextStack {
+    /// Every frame, base first, as `[{"name": ..., "attrs": {...}}, ...]`
+    pub fn snapshot(&self) -> Value {
+        self.frames()
+            .iter()
+            .map(|(name, ctx)| json!({ "name": name, "attrs": ctx.to_json() }))
+            .collect()
+    }
+
+    /// Rebuild a stack from a `snapshot()` token
+    pub fn from_snapshot(text: &str) -> Result<Self, String> {
+        let doc: Value =
+            serde_json::from_str(text).map_err(|e| format!("invalid snapshot: {e}"))?;
+        let Value::Array(frames) = doc else {
+            return Err(format!(
+                "expected a JSON array of frames, got {}",
+                kind(&doc)
+            ));
+        };
+
+        let mut stack = Vec::with_capacity(frames.len());
+        for (i, frame) in frames.iter().enumerate() {
+            let name = match frame.get("name") {
+                None | Some(Value::Null) => None,
+                Some(Value::String(name)) => Some(name.clone()),
+                Some(other) => {
+                    return Err(format!(
+                        "frame {i}: name must be a string or null, got {}",
+                        kind(other)
+                    ));
+                }
+            };
+            let attrs = frame
+                .get("attrs")
+                .ok_or_else(|| format!("frame {i}: missing attrs"))?;
+            let ctx =
+                SecurityContext::from_json_value(attrs).map_err(|e| format!("frame {i}: {e}"))?;
+            stack.push((name, ctx));
+        }
+
+        ContextStack::from_frames(stack).ok_or_else(|| "snapshot has no frames".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_round_trips_frames_and_values() {
+        let mut stack = ContextStack::default();
+        stack.current_mut().set_attr("team", "b");
+        stack.current_mut().set_attr("team", "a");
+        stack.push_named("request");
+        stack.current_mut().set_attr("role", "admin");
+        stack.push(None);
+
+        let token = stack.snapshot().to_string();
+        assert_eq!(
+            token,
+            r#"[{"attrs":{"team":["a","b"]},"name":null},{"attrs":{"role":["admin"],"team":["a","b"]},"name":"request"},{"attrs":{"role":["admin"],"team":["a","b"]},"name":null}]"#
+        );
+
+        let restored = ContextStack::from_snapshot(&token).unwrap();
+        assert_eq!(restored.frames(), stack.frames());
+    }
+
+    #[test]
+    fn from_snapshot_rejects_malformed_tokens() {
+        assert_eq!(
+            ContextStack::from_snapshot("[]").unwrap_err(),
+            "snapshot has no frames"
+        );
+        assert_eq!(
+            ContextStack::from_snapshot(r#"{"role":["admin"]}"#).unwrap_err(),
+            "expected a JSON array of frames, got object"
+        );
+        assert_eq!(
+            ContextStack::from_snapshot(r#"[{"name":null}]"#).unwrap_err(),
+            "frame 0: missing attrs"
+        );
+        assert_eq!(
+            ContextStack::from_snapshot(r#"[{"name":1,"attrs":{}}]"#).unwrap_err(),
+            "frame 0: name must be a string or null, got number"
+        );
+        assert_eq!(
+            ContextStack::from_snapshot(r#"[{"attrs":{"level":3}}]"#).unwrap_err(),
+            "frame 0: 'level' must be a string or an array of strings, got number"
+        );
+    }
+}
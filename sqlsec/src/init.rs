@@ -1,18 +1,96 @@
-use std::mem::forget;
+use rusqlite::{Connection, Result};
 
-use rusqlite::{Connection, Result, ffi::sqlite3};
+use crate::{
+    audit::create_audit_triggers,
+    catalog::register_catalogs,
+    label::{
+        define::{canonicalize_labels, define_label},
+        evaluate::load_levels,
+    },
+    register::register_functions,
+    views::visible_rowids::register_visible_rowids,
+};
 
-use crate::register::register_functions_ffi;
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |r| r.get(0),
+    )
+}
+
+/// Bring `sec_tables` from builds that stored insert policies as expression
+/// text up to the current schema. Each expression becomes a label and the
+/// text column is dropped, so `insert_label_id` is the only insert policy.
+fn migrate_sec_tables(conn: &Connection) -> Result<()> {
+    if !has_column(conn, "sec_tables", "insert_label_id")? {
+        conn.execute(
+            "ALTER TABLE sec_tables ADD COLUMN insert_label_id INTEGER REFERENCES sec_labels(id)",
+            [],
+        )?;
+    }
+    if !has_column(conn, "sec_tables", "delete_label_id")? {
+        conn.execute(
+            "ALTER TABLE sec_tables ADD COLUMN delete_label_id INTEGER REFERENCES sec_labels(id)",
+            [],
+        )?;
+    }
+    if !has_column(conn, "sec_tables", "physical_schema")? {
+        conn.execute(
+            "ALTER TABLE sec_tables ADD COLUMN physical_schema TEXT NOT NULL DEFAULT 'main'",
+            [],
+        )?;
+    }
+    if !has_column(conn, "sec_tables", "allow_implicit_label")? {
+        conn.execute(
+            "ALTER TABLE sec_tables ADD COLUMN allow_implicit_label INTEGER DEFAULT 1",
+            [],
+        )?;
+    }
+    if !has_column(conn, "sec_tables", "materialize_labels")? {
+        conn.execute(
+            "ALTER TABLE sec_tables ADD COLUMN materialize_labels INTEGER NOT NULL DEFAULT 0",
+            [],
+        )?;
+    }
+    if !has_column(conn, "sec_tables", "insert_policy_expr")? {
+        return Ok(());
+    }
+
+    let policies: Vec<(String, String)> = conn
+        .prepare(
+            r#"
+            SELECT logical_name, insert_policy_expr FROM sec_tables
+            WHERE insert_policy_expr IS NOT NULL AND insert_label_id IS NULL
+            "#,
+        )?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<_>>()?;
+    for (logical, expr) in policies {
+        let label_id = define_label(conn, &expr).map_err(|e| {
+            rusqlite::Error::UserFunctionError(
+                format!("cannot migrate insert policy of '{logical}': {e}").into(),
+            )
+        })?;
+        conn.execute(
+            "UPDATE sec_tables SET insert_label_id = ?1 WHERE logical_name = ?2",
+            rusqlite::params![label_id, logical],
+        )?;
+    }
 
-/// Initialize the database objects when extension loads via FFI.
-pub(crate) unsafe fn init_extension_ffi(db: *mut sqlite3) -> Result<()> {
-    let conn = unsafe { Connection::from_handle(db) }?;
+    conn.execute("ALTER TABLE sec_tables DROP COLUMN insert_policy_expr", [])?;
+    Ok(())
+}
 
+/// Create the metadata tables and register every `sec_*` function, catalog
+/// table and trigger on `conn`.
+pub(crate) fn init_connection(conn: &Connection) -> Result<()> {
     conn.execute_batch(
         r#"
         CREATE TABLE IF NOT EXISTS sec_labels (
-            id   INTEGER PRIMARY KEY,
-            expr TEXT NOT NULL UNIQUE
+            id     INTEGER PRIMARY KEY,
+            expr   TEXT NOT NULL UNIQUE,  -- canonical form
+            source TEXT                   -- expression as first defined
         );
 
         CREATE TABLE IF NOT EXISTS sec_levels (
@@ -24,11 +102,14 @@ pub(crate) unsafe fn init_extension_ffi(db: *mut sqlite3) -> Result<()> {
 
         CREATE TABLE IF NOT EXISTS sec_tables (
             logical_name   TEXT PRIMARY KEY,
+            physical_schema TEXT NOT NULL DEFAULT 'main',
             physical_name  TEXT NOT NULL,
             row_label_col  TEXT NOT NULL,
             table_label_id INTEGER REFERENCES sec_labels(id),
             insert_label_id INTEGER REFERENCES sec_labels(id),
-            allow_implicit_label INTEGER DEFAULT 1
+            delete_label_id INTEGER REFERENCES sec_labels(id),
+            allow_implicit_label INTEGER DEFAULT 1,
+            materialize_labels INTEGER NOT NULL DEFAULT 0
         );
 
         CREATE TABLE IF NOT EXISTS sec_columns (
@@ -36,6 +117,7 @@ pub(crate) unsafe fn init_extension_ffi(db: *mut sqlite3) -> Result<()> {
             column_name     TEXT NOT NULL,
             read_label_id   INTEGER REFERENCES sec_labels(id),
             update_label_id INTEGER REFERENCES sec_labels(id),
+            mask_expr       TEXT,  -- shown in place of the value when unreadable
             PRIMARY KEY (logical_table, column_name)
         );
 
@@ -46,14 +128,69 @@ pub(crate) unsafe fn init_extension_ffi(db: *mut sqlite3) -> Result<()> {
         INSERT OR IGNORE INTO sec_meta VALUES ('generation', 0);
         INSERT OR IGNORE INTO sec_meta VALUES ('last_refresh_generation', 0);
         INSERT OR IGNORE INTO sec_meta VALUES ('views_initialized', 0);
+        INSERT OR IGNORE INTO sec_meta VALUES ('max_label_length', 4096);
+        INSERT OR IGNORE INTO sec_meta VALUES ('max_label_clauses', 64);
+        INSERT OR IGNORE INTO sec_meta VALUES ('max_clause_requirements', 64);
+        INSERT OR IGNORE INTO sec_meta VALUES ('persist_context', 0);
+        INSERT OR IGNORE INTO sec_meta VALUES ('session_ttl', 86400);
+        INSERT OR IGNORE INTO sec_meta VALUES ('audit_actor_attr', 'user');
+
+        CREATE TABLE IF NOT EXISTS sec_sessions (
+            session_id TEXT PRIMARY KEY,
+            context    TEXT NOT NULL,     -- effective context as JSON
+            updated_at INTEGER NOT NULL,  -- unix seconds
+            expires_at INTEGER            -- NULL never expires
+        );
+
+        CREATE TABLE IF NOT EXISTS sec_admin_audit (
+            id          INTEGER PRIMARY KEY,
+            ts          INTEGER NOT NULL,  -- unix seconds
+            actor       TEXT,              -- see sec_audit_actor()
+            action      TEXT NOT NULL,
+            target      TEXT,
+            detail_json TEXT
+        );
         "#,
     )?;
 
-    // Ensure we don’t close SQLite’s internal handle
-    forget(conn);
+    // Register scalar functions first: migrations fire the audit triggers of
+    // earlier opens, which call sec_audit_actor()
+    register_functions(conn)?;
+    register_catalogs(conn)?;
+    register_visible_rowids(conn)?;
 
-    // Register scalar functions
-    register_functions_ffi(db);
+    // Databases created before labels were canonicalized
+    if !has_column(conn, "sec_labels", "source")? {
+        conn.execute("ALTER TABLE sec_labels ADD COLUMN source TEXT", [])?;
+    }
+    canonicalize_labels(conn)?;
+
+    migrate_sec_tables(conn)?;
+    if !has_column(conn, "sec_columns", "mask_expr")? {
+        conn.execute("ALTER TABLE sec_columns ADD COLUMN mask_expr TEXT", [])?;
+    }
+
+    create_audit_triggers(conn)?;
+
+    // Levels defined by earlier connections or processes
+    load_levels(conn)?;
+
+    // Keep this connection's label cache in step with edits to sec_labels
+    conn.execute_batch(
+        r#"
+        CREATE TEMP TRIGGER IF NOT EXISTS sec_labels_invalidate_upd
+        AFTER UPDATE ON main.sec_labels
+        BEGIN
+            SELECT sec_invalidate_labels(OLD.id);
+        END;
+
+        CREATE TEMP TRIGGER IF NOT EXISTS sec_labels_invalidate_del
+        AFTER DELETE ON main.sec_labels
+        BEGIN
+            SELECT sec_invalidate_labels(OLD.id);
+        END;
+        "#,
+    )?;
 
     Ok(())
 }
@@ -0,0 +1,314 @@
+use std::ops::Not;
+
+use crate::label::{CompareOp, canonical::render_value, parse::parse};
+
+/// A label expression assembled in code rather than written as text.
+///
+/// ```
+/// use sqlsec::LabelExpr;
+///
+/// let expr = LabelExpr::attr("role")
+///     .eq("admin")
+///     .or(LabelExpr::attr("role").eq("auditor"))
+///     .and(LabelExpr::attr("clearance").ge("secret"));
+/// assert_eq!(
+///     expr.build().unwrap(),
+///     "clearance>=secret&(role=admin|role=auditor)"
+/// );
+/// assert!(LabelExpr::attr("team name").eq("x").build().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelExpr {
+    /// Visible to every context
+    True,
+    Req {
+        key: String,
+        op: CompareOp,
+        value: String,
+    },
+    Not(Box<LabelExpr>),
+    And(Vec<LabelExpr>),
+    Or(Vec<LabelExpr>),
+}
+
+/// The attribute half of a requirement, from [`LabelExpr::attr`]
+#[derive(Debug, Clone)]
+pub struct Attr {
+    key: String,
+}
+
+impl Attr {
+    fn req(self, op: CompareOp, value: &str) -> LabelExpr {
+        LabelExpr::Req {
+            key: self.key,
+            op,
+            value: value.to_string(),
+        }
+    }
+
+    /// `key=value`
+    pub fn eq(self, value: &str) -> LabelExpr {
+        self.req(CompareOp::Eq, value)
+    }
+
+    /// `key>=value`, comparing levels
+    pub fn ge(self, value: &str) -> LabelExpr {
+        self.req(CompareOp::Ge, value)
+    }
+
+    /// `key>value`, comparing levels
+    pub fn gt(self, value: &str) -> LabelExpr {
+        self.req(CompareOp::Gt, value)
+    }
+
+    /// `key<=value`, comparing levels
+    pub fn le(self, value: &str) -> LabelExpr {
+        self.req(CompareOp::Le, value)
+    }
+
+    /// `key<value`, comparing levels
+    pub fn lt(self, value: &str) -> LabelExpr {
+        self.req(CompareOp::Lt, value)
+    }
+
+    /// `key=*`: the context has any value for `key`
+    pub fn present(self) -> LabelExpr {
+        self.req(CompareOp::Present, "*")
+    }
+
+    /// `key in (v1, v2, ...)`
+    pub fn one_of<'a>(self, values: impl IntoIterator<Item = &'a str>) -> LabelExpr {
+        LabelExpr::Or(
+            values
+                .into_iter()
+                .map(|value| self.clone().eq(value))
+                .collect(),
+        )
+    }
+}
+
+fn is_ident(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+impl LabelExpr {
+    /// Start a requirement on the context attribute `key`. Keys are checked
+    /// by [`LabelExpr::build`].
+    pub fn attr(key: &str) -> Attr {
+        Attr {
+            key: key.to_string(),
+        }
+    }
+
+    /// Both `self` and `other`
+    pub fn and(self, other: LabelExpr) -> LabelExpr {
+        match self {
+            LabelExpr::And(mut all) => {
+                all.push(other);
+                LabelExpr::And(all)
+            }
+            first => LabelExpr::And(vec![first, other]),
+        }
+    }
+
+    /// Either `self` or `other`
+    pub fn or(self, other: LabelExpr) -> LabelExpr {
+        match self {
+            LabelExpr::Or(mut any) => {
+                any.push(other);
+                LabelExpr::Or(any)
+            }
+            first => LabelExpr::Or(vec![first, other]),
+        }
+    }
+
+    /// The expression in label syntax, `None` for one no context satisfies
+    fn render(&self) -> Result<Option<String>, String> {
+        Ok(match self {
+            LabelExpr::True => Some("true".to_string()),
+            LabelExpr::Req { key, op, value } => {
+                if !is_ident(key) {
+                    return Err(format!(
+                        "invalid attribute name '{key}': expected letters, digits or '_'"
+                    ));
+                }
+                let op = match op {
+                    CompareOp::Present => return Ok(Some(format!("{key}=*"))),
+                    CompareOp::Eq => "=",
+                    CompareOp::Ge => ">=",
+                    CompareOp::Gt => ">",
+                    CompareOp::Le => "<=",
+                    CompareOp::Lt => "<",
+                };
+                Some(format!("{key}{op}{}", render_value(value)))
+            }
+            LabelExpr::Not(inner) => match inner.render()?.as_deref() {
+                Some("true") => None,
+                Some(inner) => Some(format!("!({inner})")),
+                None => Some("true".to_string()),
+            },
+            LabelExpr::And(all) => {
+                let mut parts = Vec::new();
+                for part in all {
+                    match part.render()? {
+                        None => return Ok(None),
+                        Some(p) if p == "true" => {}
+                        Some(p) => parts.push(format!("({p})")),
+                    }
+                }
+                match parts.is_empty() {
+                    true => Some("true".to_string()),
+                    false => Some(parts.join("&")),
+                }
+            }
+            LabelExpr::Or(any) => {
+                let mut parts = Vec::new();
+                for part in any {
+                    match part.render()? {
+                        None => {}
+                        Some(p) if p == "true" => return Ok(Some(p)),
+                        Some(p) => parts.push(format!("({p})")),
+                    }
+                }
+                match parts.is_empty() {
+                    true => None,
+                    false => Some(parts.join("|")),
+                }
+            }
+        })
+    }
+
+    /// Render in canonical label syntax, ready for `sec_define_label`.
+    ///
+    /// Fails for invalid attribute names, for expressions no context can
+    /// satisfy (which labels cannot express), and past the parser's limits.
+    pub fn build(&self) -> Result<String, String> {
+        let expr = self
+            .render()?
+            .ok_or("expression is never satisfied, which a label cannot express")?;
+        Ok(parse(&expr)?.canonical())
+    }
+}
+
+impl Not for LabelExpr {
+    type Output = LabelExpr;
+
+    fn not(self) -> LabelExpr {
+        match self {
+            LabelExpr::Not(inner) => *inner,
+            other => LabelExpr::Not(Box::new(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(key: &str) -> Attr {
+        LabelExpr::attr(key)
+    }
+
+    /// Building then parsing gives the same CNF as parsing `expr`
+    fn assert_round_trips(built: LabelExpr, expr: &str) {
+        let rendered = built.build().unwrap();
+        let reparsed = parse(&rendered).unwrap();
+        let expected = parse(expr).unwrap();
+        assert_eq!(reparsed.canonical(), expected.canonical(), "{expr}");
+        assert_eq!(reparsed.always_true, expected.always_true, "{expr}");
+        assert_eq!(reparsed.clauses.len(), expected.clauses.len(), "{expr}");
+        assert_eq!(rendered, expected.canonical());
+    }
+
+    #[test]
+    fn builds_comparisons() {
+        assert_round_trips(attr("role").eq("admin"), "role=admin");
+        assert_round_trips(attr("clearance").ge("secret"), "clearance>=secret");
+        assert_round_trips(attr("clearance").gt("public"), "clearance>public");
+        assert_round_trips(attr("clearance").le("secret"), "clearance<=secret");
+        assert_round_trips(attr("clearance").lt("secret"), "clearance<secret");
+        assert_round_trips(attr("tenant").present(), "tenant=*");
+    }
+
+    #[test]
+    fn builds_and_or_groups() {
+        assert_round_trips(
+            attr("role").eq("admin").and(attr("clearance").ge("secret")),
+            "role=admin&clearance>=secret",
+        );
+        assert_round_trips(
+            attr("role")
+                .eq("admin")
+                .or(attr("role").eq("auditor"))
+                .and(attr("team").eq("finance")),
+            "(role=admin|role=auditor)&team=finance",
+        );
+        assert_round_trips(
+            attr("role")
+                .eq("admin")
+                .and(attr("team").eq("finance"))
+                .or(attr("role").eq("auditor")),
+            "(role=admin&team=finance)|role=auditor",
+        );
+        assert_round_trips(attr("role").one_of(["b", "a"]), "role in (a, b)");
+    }
+
+    #[test]
+    fn builds_negation() {
+        assert_round_trips(!attr("role").eq("intern"), "!role=intern");
+        assert_round_trips(
+            !(attr("role").eq("contractor").or(attr("role").eq("intern"))),
+            "!(role=contractor|role=intern)",
+        );
+        assert_eq!(!!attr("role").eq("admin"), attr("role").eq("admin"));
+    }
+
+    #[test]
+    fn quotes_values() {
+        assert_round_trips(attr("dept").eq("Human Resources"), "dept='Human Resources'");
+        assert_round_trips(attr("name").eq("O'Brien"), "name='O''Brien'");
+        assert_round_trips(attr("region").eq(""), "region=''");
+        assert_round_trips(attr("tenant").eq("*"), "tenant='*'");
+        assert_round_trips(attr("role").eq("a|b&c"), "role='a|b&c'");
+    }
+
+    #[test]
+    fn folds_true() {
+        assert_eq!(LabelExpr::True.build().unwrap(), "true");
+        assert_eq!(
+            LabelExpr::True
+                .and(attr("role").eq("admin"))
+                .build()
+                .unwrap(),
+            "role=admin"
+        );
+        assert_eq!(
+            attr("role")
+                .eq("admin")
+                .or(LabelExpr::True)
+                .build()
+                .unwrap(),
+            "true"
+        );
+        assert!((!LabelExpr::True).build().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_identifiers() {
+        for key in ["", "team name", "role=", "a-b", "x'y", "(role"] {
+            let err = attr(key).eq("admin").build().unwrap_err();
+            assert!(err.starts_with("invalid attribute name"), "{key}: {err}");
+        }
+        let err = attr("role")
+            .eq("admin")
+            .and(attr("bad key").present())
+            .build()
+            .unwrap_err();
+        assert!(err.contains("'bad key'"), "{err}");
+    }
+
+    #[test]
+    fn keys_fold_to_lowercase() {
+        assert_eq!(attr("Role").eq("Admin").build().unwrap(), "role=Admin");
+    }
+}
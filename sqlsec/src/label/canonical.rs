@@ -0,0 +1,149 @@
+use crate::label::{AttrReq, CompareOp, Label};
+
+fn is_bare_value(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | '@' | ':'))
+}
+
+pub(crate) fn render_value(value: &str) -> String {
+    if is_bare_value(value) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "''"))
+    }
+}
+
+fn render_req(req: &AttrReq) -> String {
+    let not = if req.negated { "!" } else { "" };
+    let op = match req.op {
+        CompareOp::Present => return format!("{not}{}=*", req.key),
+        CompareOp::Eq => "=",
+        CompareOp::Ge => ">=",
+        CompareOp::Gt => ">",
+        CompareOp::Le => "<=",
+        CompareOp::Lt => "<",
+    };
+    format!("{not}{}{op}{}", req.key, render_value(&req.value))
+}
+
+impl Label {
+    /// Deterministic rendering of the label, used to deduplicate sec_labels.
+    ///
+    /// Requirements within a clause and the clauses themselves are sorted and
+    /// deduplicated, values are quoted only when needed, and whitespace is
+    /// dropped, so any two spellings of the same CNF render identically.
+    pub fn canonical(&self) -> String {
+        if self.always_true {
+            return "true".to_string();
+        }
+
+        let mut clauses: Vec<Vec<&AttrReq>> = self
+            .clauses
+            .iter()
+            .map(|clause| {
+                let mut reqs: Vec<&AttrReq> = clause.iter().collect();
+                reqs.sort();
+                reqs.dedup();
+                reqs
+            })
+            .collect();
+        clauses.sort();
+        clauses.dedup();
+
+        clauses
+            .iter()
+            .map(|reqs| {
+                let rendered: Vec<String> = reqs.iter().map(|r| render_req(r)).collect();
+                match rendered.as_slice() {
+                    [single] => single.clone(),
+                    _ => format!("({})", rendered.join("|")),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::label::parse::parse;
+
+    fn canonical(expr: &str) -> String {
+        parse(expr).unwrap().canonical()
+    }
+
+    #[test]
+    fn canonical_reordered_clauses() {
+        assert_eq!(
+            canonical("role=admin&team=finance"),
+            canonical("team=finance&role=admin")
+        );
+        assert_eq!(
+            canonical("(role=auditor|role=admin)&team=finance"),
+            canonical("team=finance&(role=admin|role=auditor)")
+        );
+        assert_eq!(
+            canonical("team=finance&role=admin"),
+            "role=admin&team=finance"
+        );
+    }
+
+    #[test]
+    fn canonical_whitespace() {
+        assert_eq!(
+            canonical("  ( role = admin |  role=auditor ) &\tteam =finance "),
+            "(role=admin|role=auditor)&team=finance"
+        );
+    }
+
+    #[test]
+    fn canonical_key_case() {
+        assert_eq!(
+            canonical("Role=admin&TEAM=finance"),
+            "role=admin&team=finance"
+        );
+        // Values stay case-sensitive
+        assert_ne!(canonical("role=Admin"), canonical("role=admin"));
+    }
+
+    #[test]
+    fn canonical_quoting() {
+        assert_eq!(canonical("dept='finance'"), "dept=finance");
+        assert_eq!(
+            canonical("dept='Human Resources'"),
+            "dept='Human Resources'"
+        );
+        assert_eq!(canonical("name='O''Brien'"), "name='O''Brien'");
+        assert_eq!(canonical("region=''"), "region=''");
+    }
+
+    #[test]
+    fn canonical_dedups() {
+        assert_eq!(canonical("role=admin&role=admin"), "role=admin");
+        assert_eq!(canonical("(role=admin|role=admin)"), "role=admin");
+        assert_eq!(canonical("role in (b, a, b)"), "(role=a|role=b)");
+    }
+
+    #[test]
+    fn canonical_operators() {
+        assert_eq!(
+            canonical("!tenant=* & clearance >= secret & !role=intern"),
+            "clearance>=secret&!role=intern&!tenant=*"
+        );
+    }
+
+    #[test]
+    fn canonical_round_trips() {
+        for expr in [
+            "true",
+            "(role=admin&team=finance)|role=auditor",
+            "!(role=contractor|role=intern)&dept='Human Resources'",
+            "tenant_id=*&name='O''Brien'",
+        ] {
+            let once = canonical(expr);
+            assert_eq!(canonical(&once), once, "{expr}");
+        }
+    }
+}
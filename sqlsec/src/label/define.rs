@@ -1,27 +1,66 @@
 use std::mem::forget;
 
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, Error, Result};
 
-use crate::label::{LABEL_CACHE, parse::parse};
+use crate::label::{LABEL_CACHE, limits::LabelLimits, parse::parse};
 
 /// Define a label using a Connection reference (for tests and direct use)
+///
+/// The expression is stored in canonical form, so equivalent spellings share
+/// an id; the first spelling seen is kept in `source` for display. Expressions
+/// beyond the configured [`LabelLimits`] are rejected.
 pub fn define_label(conn: &Connection, expr: &str) -> Result<i64> {
+    let invalid =
+        |e: String| Error::UserFunctionError(format!("invalid label expression: {e}").into());
+
+    let limits = LabelLimits::load(conn)?;
+    limits.check_length(expr).map_err(invalid)?;
+    let label = parse(expr).map_err(invalid)?;
+    limits.check(&label).map_err(invalid)?;
+    let canonical = label.canonical();
+
     conn.execute(
-        "INSERT OR IGNORE INTO sec_labels (expr) VALUES (?1)",
-        [expr],
+        "INSERT OR IGNORE INTO sec_labels (expr, source) VALUES (?1, ?2)",
+        [&canonical, expr],
     )?;
 
-    let id: i64 = conn.query_row("SELECT id FROM sec_labels WHERE expr = ?1", [expr], |r| {
-        r.get(0)
-    })?;
+    let id: i64 = conn.query_row(
+        "SELECT id FROM sec_labels WHERE expr = ?1",
+        [&canonical],
+        |r| r.get(0),
+    )?;
 
-    if let Ok(label) = parse(expr) {
-        LABEL_CACHE.lock().insert(id, label);
+    // Cache what a later load from sec_labels would produce
+    if let Ok(label) = parse(&canonical) {
+        let db_ptr = unsafe { conn.handle() as usize };
+        LABEL_CACHE.lock().insert((db_ptr, id), label);
     }
 
     Ok(id)
 }
 
+/// Rewrite labels stored before canonicalization, keeping the original as
+/// `source`. Rows whose canonical form already exists keep their expression.
+pub fn canonicalize_labels(conn: &Connection) -> Result<()> {
+    let legacy = conn
+        .prepare("SELECT id, expr FROM sec_labels WHERE source IS NULL")?
+        .query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>>>()?;
+
+    for (id, expr) in legacy {
+        let canonical = parse(&expr).map(|l| l.canonical()).unwrap_or(expr.clone());
+        let updated = conn.execute(
+            "UPDATE OR IGNORE sec_labels SET expr = ?2, source = ?3 WHERE id = ?1",
+            rusqlite::params![id, canonical, expr],
+        )?;
+        if updated == 0 {
+            conn.execute("UPDATE sec_labels SET source = expr WHERE id = ?1", [id])?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Define a label from raw db pointer (for FFI)
 pub fn define_label_raw(db_ptr: usize, expr: &str) -> Result<i64> {
     let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
@@ -0,0 +1,144 @@
+use std::{collections::HashSet, io::ErrorKind, mem::forget};
+
+use rusqlite::{Connection, Error, Result};
+
+use crate::views::PhysicalTable;
+
+fn in_use(msg: String) -> Error {
+    Error::UserFunctionError(Box::new(std::io::Error::new(ErrorKind::InvalidInput, msg)))
+}
+
+fn physical_tables(conn: &Connection) -> Result<Vec<(PhysicalTable, String)>> {
+    let mut stmt =
+        conn.prepare("SELECT physical_schema, physical_name, row_label_col FROM sec_tables")?;
+    stmt.query_map([], |r| {
+        let table = PhysicalTable {
+            schema: r.get(0)?,
+            name: r.get(1)?,
+        };
+        Ok((table, r.get(2)?))
+    })?
+    .collect()
+}
+
+/// Describe everything that still references `label_id`.
+///
+/// With `scan_rows`, the row label column of every registered physical table
+/// is checked as well, which costs a scan per table.
+pub fn label_references(conn: &Connection, label_id: i64, scan_rows: bool) -> Result<Vec<String>> {
+    let mut refs = Vec::new();
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT 'sec_tables.' || logical_name || '.table_label_id'
+        FROM sec_tables WHERE table_label_id = ?1
+        UNION ALL
+        SELECT 'sec_tables.' || logical_name || '.insert_label_id'
+        FROM sec_tables WHERE insert_label_id = ?1
+        UNION ALL
+        SELECT 'sec_tables.' || logical_name || '.delete_label_id'
+        FROM sec_tables WHERE delete_label_id = ?1
+        UNION ALL
+        SELECT 'sec_columns.' || logical_table || '.' || column_name || '.read_label_id'
+        FROM sec_columns WHERE read_label_id = ?1
+        UNION ALL
+        SELECT 'sec_columns.' || logical_table || '.' || column_name || '.update_label_id'
+        FROM sec_columns WHERE update_label_id = ?1
+        "#,
+    )?;
+    for r in stmt.query_map([label_id], |r| r.get::<_, String>(0))? {
+        refs.push(r?);
+    }
+
+    if scan_rows {
+        for (physical, row_label_col) in physical_tables(conn)? {
+            let count: i64 = conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM {} WHERE \"{row_label_col}\" = ?1",
+                    physical.quoted()
+                ),
+                [label_id],
+                |r| r.get(0),
+            )?;
+            if count > 0 {
+                refs.push(format!("{count} row(s) of {physical}.{row_label_col}"));
+            }
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Delete a label, refusing while anything still references it
+pub fn delete_label(conn: &Connection, label_id: i64, scan_rows: bool) -> Result<()> {
+    let refs = label_references(conn, label_id, scan_rows)?;
+    if !refs.is_empty() {
+        return Err(in_use(format!(
+            "label {label_id} is still referenced by: {}",
+            refs.join(", ")
+        )));
+    }
+
+    let deleted = conn.execute("DELETE FROM sec_labels WHERE id = ?1", [label_id])?;
+    if deleted == 0 {
+        return Err(in_use(format!("label {label_id} does not exist")));
+    }
+
+    Ok(())
+}
+
+/// Delete every unreferenced label, returning how many were removed
+pub fn gc_labels(conn: &Connection, scan_rows: bool) -> Result<i64> {
+    let mut referenced = HashSet::new();
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT table_label_id FROM sec_tables
+        UNION SELECT insert_label_id FROM sec_tables
+        UNION SELECT delete_label_id FROM sec_tables
+        UNION SELECT read_label_id FROM sec_columns
+        UNION SELECT update_label_id FROM sec_columns
+        "#,
+    )?;
+    for id in stmt.query_map([], |r| r.get::<_, Option<i64>>(0))? {
+        referenced.extend(id?);
+    }
+
+    if scan_rows {
+        for (physical, row_label_col) in physical_tables(conn)? {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT DISTINCT \"{row_label_col}\" FROM {}",
+                physical.quoted()
+            ))?;
+            for id in stmt.query_map([], |r| r.get::<_, Option<i64>>(0))? {
+                referenced.extend(id?);
+            }
+        }
+    }
+
+    let ids = conn
+        .prepare("SELECT id FROM sec_labels")?
+        .query_map([], |r| r.get::<_, i64>(0))?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut removed = 0;
+    for id in ids.into_iter().filter(|id| !referenced.contains(id)) {
+        removed += conn.execute("DELETE FROM sec_labels WHERE id = ?1", [id])? as i64;
+    }
+
+    Ok(removed)
+}
+
+pub fn delete_label_raw(db_ptr: usize, label_id: i64, scan_rows: bool) -> Result<()> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+    let result = delete_label(&conn, label_id, scan_rows);
+    forget(conn);
+    result
+}
+
+pub fn gc_labels_raw(db_ptr: usize, scan_rows: bool) -> Result<i64> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+    let result = gc_labels(&conn, scan_rows);
+    forget(conn);
+    result
+}
@@ -0,0 +1,155 @@
+use crate::label::{AttrReq, Clause, CompareOp, Label, Levels};
+
+fn level(levels: &Levels, key: &str, name: &str) -> Option<i64> {
+    levels.get(key)?.get(name).copied()
+}
+
+/// Does the (un-negated) requirement `a` imply `b`?
+///
+/// Both are existential over the context's values for the key, so `a`
+/// implies `b` when every value satisfying `a` also satisfies `b`.
+fn implies_positive(levels: &Levels, a: &AttrReq, b: &AttrReq) -> bool {
+    use CompareOp::*;
+
+    if a.key != b.key {
+        return false;
+    }
+    if a.op == b.op && a.value == b.value {
+        return true;
+    }
+    if b.op == Present {
+        // Any requirement on a key needs a value for it
+        return true;
+    }
+
+    let (Some(la), Some(lb)) = (
+        level(levels, &a.key, &a.value),
+        level(levels, &b.key, &b.value),
+    ) else {
+        return false;
+    };
+
+    match (a.op, b.op) {
+        (Eq, Ge) | (Ge, Ge) | (Gt, Gt) | (Gt, Ge) => la >= lb,
+        (Eq, Gt) | (Ge, Gt) => la > lb,
+        (Eq, Le) | (Le, Le) | (Lt, Lt) | (Lt, Le) => la <= lb,
+        (Eq, Lt) | (Le, Lt) => la < lb,
+        _ => false,
+    }
+}
+
+fn implies(levels: &Levels, a: &AttrReq, b: &AttrReq) -> bool {
+    match (a.negated, b.negated) {
+        (false, false) => implies_positive(levels, a, b),
+        // Contrapositive: !a implies !b when b implies a
+        (true, true) => implies_positive(levels, b, a),
+        _ => false,
+    }
+}
+
+/// A clause implies another when each of its requirements implies one of the
+/// other's
+fn clause_implies(levels: &Levels, a: &Clause, b: &Clause) -> bool {
+    a.iter()
+        .all(|ra| b.iter().any(|rb| implies(levels, ra, rb)))
+}
+
+impl Label {
+    /// Whether every context this label admits is also admitted by `other`.
+    ///
+    /// Each clause of `other` must be implied by some clause of `self`. This is
+    /// sound but not complete: a `false` answer means dominance could not be
+    /// shown, not that a counterexample exists. Levels are ordered by `levels`.
+    pub fn dominates(&self, other: &Label, levels: &Levels) -> bool {
+        if other.always_true {
+            return true;
+        }
+        if self.always_true {
+            return false;
+        }
+
+        other
+            .clauses
+            .iter()
+            .all(|b| self.clauses.iter().any(|a| clause_implies(levels, a, b)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::label::{Levels, parse::parse};
+
+    fn clearance_levels() -> Levels {
+        let mut clearance = HashMap::new();
+        clearance.insert("public".to_string(), 0);
+        clearance.insert("confidential".to_string(), 1);
+        clearance.insert("secret".to_string(), 2);
+        clearance.insert("top_secret".to_string(), 3);
+        Levels::from([("clearance".to_string(), clearance)])
+    }
+
+    fn dominates(a: &str, b: &str) -> bool {
+        parse(a)
+            .unwrap()
+            .dominates(&parse(b).unwrap(), &clearance_levels())
+    }
+
+    #[test]
+    fn dominates_identical_and_true() {
+        assert!(dominates("role=admin", "role=admin"));
+        assert!(dominates("role=admin", "true"));
+        assert!(dominates("true", "true"));
+        assert!(!dominates("true", "role=admin"));
+    }
+
+    #[test]
+    fn dominates_conjunction() {
+        assert!(dominates("role=admin&team=finance", "role=admin"));
+        assert!(!dominates("role=admin", "role=admin&team=finance"));
+    }
+
+    #[test]
+    fn dominates_disjunction() {
+        assert!(dominates("role=admin", "role=admin|role=auditor"));
+        assert!(!dominates("role=admin|role=auditor", "role=admin"));
+        assert!(dominates(
+            "role in (admin, auditor)",
+            "role in (admin, auditor, owner)"
+        ));
+    }
+
+    #[test]
+    fn dominates_levels() {
+        assert!(dominates("clearance>=secret", "clearance>=confidential"));
+        assert!(!dominates("clearance>=confidential", "clearance>=secret"));
+        assert!(dominates("clearance>secret", "clearance>=secret"));
+        assert!(dominates("clearance>=top_secret", "clearance>secret"));
+        assert!(!dominates("clearance>=secret", "clearance>secret"));
+        assert!(dominates("clearance=secret", "clearance>=confidential"));
+        assert!(dominates("clearance<=public", "clearance<confidential"));
+        assert!(!dominates("clearance<=secret", "clearance>=public"));
+    }
+
+    #[test]
+    fn dominates_unknown_levels() {
+        assert!(!dominates("clearance>=cosmic", "clearance>=secret"));
+        assert!(!dominates("rank>=major", "rank>=captain"));
+    }
+
+    #[test]
+    fn dominates_presence() {
+        assert!(dominates("tenant=acme", "tenant=*"));
+        assert!(dominates("clearance>=secret", "clearance=*"));
+        assert!(!dominates("tenant=*", "tenant=acme"));
+    }
+
+    #[test]
+    fn dominates_negation() {
+        assert!(dominates("!tenant=*", "!tenant=acme"));
+        assert!(!dominates("!tenant=acme", "!tenant=*"));
+        assert!(!dominates("!role=admin", "role=admin"));
+        assert!(dominates("!(role=contractor|role=intern)", "!role=intern"));
+    }
+}
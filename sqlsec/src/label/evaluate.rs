@@ -1,29 +1,54 @@
-use std::mem::forget;
+use std::{mem::forget, sync::Arc};
 
 use rusqlite::{Connection, Error, Result};
 
 use crate::{
-    context::sec_ctx::SecurityContext,
-    label::{CompareOp, LABEL_CACHE, LEVELS_CACHE, Label, parse::parse},
+    context::{effective_context, sec_ctx::SecurityContext},
+    label::{
+        AttrReq,
+        CompareOp,
+        LABEL_CACHE,
+        LEVELS_CACHE,
+        Label,
+        Levels,
+        VISIBILITY_CACHE,
+        forget_visibility,
+        levels,
+        parse::parse,
+    },
 };
 
 impl Label {
-    pub fn evaluate(&self, ctx: &SecurityContext) -> bool {
+    /// Whether `ctx` satisfies the label, comparing levels by `levels`
+    pub fn evaluate(&self, ctx: &SecurityContext, levels: &Levels) -> bool {
         if self.always_true {
             return true;
         }
 
-        self.clauses.iter().all(|clause| {
-            clause.iter().any(|req| match req.op {
-                CompareOp::Eq => ctx.has(&req.key, &req.value),
-                _ => evaluate_comparison(ctx, &req.key, req.op, &req.value),
-            })
-        })
+        self.clauses
+            .iter()
+            .all(|clause| clause.iter().any(|req| req.evaluate(ctx, levels)))
+    }
+}
+
+impl AttrReq {
+    pub fn evaluate(&self, ctx: &SecurityContext, levels: &Levels) -> bool {
+        let matched = match self.op {
+            CompareOp::Eq => ctx.has(&self.key, &self.value),
+            CompareOp::Present => ctx.attrs.contains_key(&self.key),
+            _ => evaluate_comparison(levels, ctx, &self.key, self.op, &self.value),
+        };
+        matched != self.negated
     }
 }
 
-fn evaluate_comparison(ctx: &SecurityContext, key: &str, op: CompareOp, required: &str) -> bool {
-    let levels = LEVELS_CACHE.lock();
+fn evaluate_comparison(
+    levels: &Levels,
+    ctx: &SecurityContext,
+    key: &str,
+    op: CompareOp,
+    required: &str,
+) -> bool {
     let attr_levels = match levels.get(key) {
         Some(l) => l,
         None => return false, // No levels defined for this attr
@@ -49,15 +74,17 @@ fn evaluate_comparison(ctx: &SecurityContext, key: &str, op: CompareOp, required
                 CompareOp::Gt => user_level > required_level,
                 CompareOp::Le => user_level <= required_level,
                 CompareOp::Lt => user_level < required_level,
+                CompareOp::Present => true,
             }
         })
 }
 
+/// Read the connection's levels from sec_levels
 pub fn load_levels(conn: &Connection) -> Result<()> {
+    let db_ptr = unsafe { conn.handle() as usize };
     let mut stmt = conn.prepare("SELECT attr_name, level_name, level_value FROM sec_levels")?;
 
-    let mut cache = LEVELS_CACHE.lock();
-    cache.clear();
+    let mut levels = Levels::new();
 
     let rows = stmt.query_map([], |row| {
         Ok((
@@ -69,16 +96,21 @@ pub fn load_levels(conn: &Connection) -> Result<()> {
 
     for row in rows {
         let (attr, name, value) = row?;
-        cache.entry(attr).or_default().insert(name, value);
+        levels
+            .entry(attr.to_lowercase())
+            .or_default()
+            .insert(name, value);
     }
+    LEVELS_CACHE.lock().insert(db_ptr, Arc::new(levels));
+    forget_visibility(db_ptr);
 
     Ok(())
 }
 
-pub fn evaluate_label_expr(expr: &str, ctx: &SecurityContext) -> Option<i64> {
+pub fn evaluate_label_expr(expr: &str, ctx: &SecurityContext, levels: &Levels) -> Option<i64> {
     match parse(expr) {
         Ok(label) => {
-            if label.evaluate(ctx) {
+            if label.evaluate(ctx, levels) {
                 Some(1)
             } else {
                 None
@@ -88,13 +120,11 @@ pub fn evaluate_label_expr(expr: &str, ctx: &SecurityContext) -> Option<i64> {
     }
 }
 
-pub fn evaluate_by_id_conn(
-    conn: &Connection,
-    label_id: i64,
-    ctx: &SecurityContext,
-) -> Result<bool> {
-    if let Some(label) = LABEL_CACHE.lock().get(&label_id) {
-        return Ok(label.evaluate(ctx));
+/// Fetch a parsed label, going to sec_labels on a cache miss
+pub fn load_label_conn(conn: &Connection, label_id: i64) -> Result<Label> {
+    let db_ptr = unsafe { conn.handle() as usize };
+    if let Some(label) = LABEL_CACHE.lock().get(&(db_ptr, label_id)) {
+        return Ok(label.clone());
     }
 
     let expr: String = conn.query_row(
@@ -103,10 +133,26 @@ pub fn evaluate_by_id_conn(
         |r| r.get(0),
     )?;
 
-    let label = parse(&expr).map_err(|_| Error::InvalidQuery)?;
-    LABEL_CACHE.lock().insert(label_id, label.clone());
+    let label = parse(&expr).map_err(|e| {
+        Error::UserFunctionError(format!("label {label_id} '{expr}' does not parse: {e}").into())
+    })?;
+    LABEL_CACHE.lock().insert((db_ptr, label_id), label.clone());
 
-    Ok(label.evaluate(ctx))
+    Ok(label)
+}
+
+pub fn evaluate_by_id_conn(
+    conn: &Connection,
+    label_id: i64,
+    ctx: &SecurityContext,
+) -> Result<bool> {
+    let db_ptr = unsafe { conn.handle() as usize };
+    let levels = levels(db_ptr);
+    if let Some(label) = LABEL_CACHE.lock().get(&(db_ptr, label_id)) {
+        return Ok(label.evaluate(ctx, &levels));
+    }
+
+    Ok(load_label_conn(conn, label_id)?.evaluate(ctx, &levels))
 }
 
 pub fn evaluate_by_id(db_ptr: usize, label_id: i64, ctx: &SecurityContext) -> Result<bool> {
@@ -116,6 +162,26 @@ pub fn evaluate_by_id(db_ptr: usize, label_id: i64, ctx: &SecurityContext) -> Re
     result
 }
 
+/// Whether the connection's effective context can see `label_id`, remembered
+/// until the generation next bumps
+pub fn visible_memoized(db_ptr: usize, label_id: i64) -> Result<bool> {
+    if let Some(visible) = VISIBILITY_CACHE
+        .lock()
+        .get(&db_ptr)
+        .and_then(|memo| memo.get(&label_id))
+    {
+        return Ok(*visible);
+    }
+
+    let visible = evaluate_by_id(db_ptr, label_id, &effective_context(db_ptr))?;
+    VISIBILITY_CACHE
+        .lock()
+        .entry(db_ptr)
+        .or_default()
+        .insert(label_id, visible);
+    Ok(visible)
+}
+
 pub fn is_visible_conn(conn: &Connection, label_id: Option<i64>, ctx: &SecurityContext) -> bool {
     match label_id {
         None => true,
@@ -133,46 +199,67 @@ pub fn is_visible(db_ptr: usize, label_id: Option<i64>, ctx: &SecurityContext) -
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::label::{evict_label, forget_visibility};
+
+    #[test]
+    fn memoized_decisions_until_forgotten() {
+        // Never dereferenced: a memo hit does not touch the connection
+        let db_ptr = 0xdead_0001;
+        VISIBILITY_CACHE
+            .lock()
+            .entry(db_ptr)
+            .or_default()
+            .extend([(7, true), (8, false)]);
+
+        assert!(visible_memoized(db_ptr, 7).unwrap());
+        assert!(!visible_memoized(db_ptr, 8).unwrap());
+
+        evict_label(db_ptr, 7);
+        assert_eq!(
+            VISIBILITY_CACHE.lock()[&db_ptr].keys().collect::<Vec<_>>(),
+            vec![&8]
+        );
+
+        forget_visibility(db_ptr);
+        assert!(!VISIBILITY_CACHE.lock().contains_key(&db_ptr));
+    }
 
     #[test]
     fn evaluate_simple() {
         let label = parse("role=admin").unwrap();
         let mut ctx = SecurityContext::default();
 
-        assert!(!label.evaluate(&ctx));
+        assert!(!label.evaluate(&ctx, &Levels::new()));
 
         ctx.set_attr("role", "admin");
-        assert!(label.evaluate(&ctx));
+        assert!(label.evaluate(&ctx, &Levels::new()));
     }
 
     #[test]
     fn evaluate_comparison() {
         // Setup levels
-        {
-            let mut cache = LEVELS_CACHE.lock();
-            cache.clear();
-            let mut clearance = std::collections::HashMap::new();
-            clearance.insert("public".to_string(), 0);
-            clearance.insert("confidential".to_string(), 1);
-            clearance.insert("secret".to_string(), 2);
-            clearance.insert("top_secret".to_string(), 3);
-            cache.insert("clearance".to_string(), clearance);
-        }
+        let mut levels = Levels::new();
+        let mut clearance = std::collections::HashMap::new();
+        clearance.insert("public".to_string(), 0);
+        clearance.insert("confidential".to_string(), 1);
+        clearance.insert("secret".to_string(), 2);
+        clearance.insert("top_secret".to_string(), 3);
+        levels.insert("clearance".to_string(), clearance);
 
         let label = parse("clearance>=confidential").unwrap();
 
         let mut ctx = SecurityContext::default();
         ctx.set_attr("clearance", "public");
-        assert!(!label.evaluate(&ctx)); // 0 >= 1 is false
+        assert!(!label.evaluate(&ctx, &levels)); // 0 >= 1 is false
 
         ctx.set_attr("clearance", "confidential");
-        assert!(label.evaluate(&ctx)); // 1 >= 1
+        assert!(label.evaluate(&ctx, &levels)); // 1 >= 1
 
         ctx.set_attr("clearance", "secret");
-        assert!(label.evaluate(&ctx)); // 2 >= 1
+        assert!(label.evaluate(&ctx, &levels)); // 2 >= 1
 
         ctx.set_attr("clearance", "top_secret");
-        assert!(label.evaluate(&ctx)); // 3 >= 1
+        assert!(label.evaluate(&ctx, &levels)); // 3 >= 1
     }
 
     #[test]
@@ -181,7 +268,7 @@ mod tests {
         let mut ctx = SecurityContext::default();
 
         ctx.set_attr("role", "auditor");
-        assert!(label.evaluate(&ctx));
+        assert!(label.evaluate(&ctx, &Levels::new()));
     }
 
     #[test]
@@ -190,9 +277,142 @@ mod tests {
         let mut ctx = SecurityContext::default();
 
         ctx.set_attr("role", "admin");
-        assert!(!label.evaluate(&ctx));
+        assert!(!label.evaluate(&ctx, &Levels::new()));
 
         ctx.set_attr("team", "finance");
-        assert!(label.evaluate(&ctx));
+        assert!(label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_quoted_value() {
+        let label = parse("department='Human Resources'|email=bob@example.com").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("department", "Human");
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("department", "Human Resources");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        let mut ctx = SecurityContext::default();
+        ctx.set_attr("email", "bob@example.com");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_wildcard() {
+        let label = parse("tenant_id=*").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        // Missing key
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+
+        // Key set to an empty string still counts as present
+        ctx.set_attr("tenant_id", "");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        // ...but does not match a literal empty value requirement on another key
+        let label = parse("tenant_id=*&region=''").unwrap();
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+        ctx.set_attr("region", "");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_wildcard_in_groups() {
+        let label = parse("(tenant_id=*|role=admin)&!suspended=*").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("role", "admin");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("suspended", "yes");
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+
+        let mut ctx = SecurityContext::default();
+        ctx.set_attr("tenant_id", "acme");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_in_list() {
+        let label = parse("status in ('on hold', active)").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("status", "closed");
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("status", "on hold");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        let label = parse("!status in ('on hold', active)").unwrap();
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_nested() {
+        let label = parse("(role=admin&team=finance)|role=auditor").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("role", "admin");
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("team", "finance");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        let mut ctx = SecurityContext::default();
+        ctx.set_attr("role", "auditor");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_negated_nested() {
+        // !(a & b) == !a | !b
+        let label = parse("!(role=admin&team=finance)").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("role", "admin");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("team", "finance");
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_not() {
+        let label = parse("!role=contractor").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        // No role at all is not a contractor
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("role", "employee");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("role", "contractor");
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_not_group() {
+        let label = parse("!(role=contractor|role=intern)&team=finance").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        ctx.set_attr("team", "finance");
+        assert!(label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("role", "intern");
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+    }
+
+    #[test]
+    fn evaluate_double_not() {
+        let label = parse("!!role=admin").unwrap();
+        let mut ctx = SecurityContext::default();
+
+        assert!(!label.evaluate(&ctx, &Levels::new()));
+
+        ctx.set_attr("role", "admin");
+        assert!(label.evaluate(&ctx, &Levels::new()));
     }
 }
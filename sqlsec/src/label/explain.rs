@@ -0,0 +1,138 @@
+use serde_json::{Value, json};
+
+use crate::{
+    context::sec_ctx::SecurityContext,
+    label::{Label, Levels},
+};
+
+impl Label {
+    /// Describe the clause structure as JSON.
+    ///
+    /// With a context, and the levels to compare it by, every requirement and
+    /// clause is annotated with whether it is satisfied, and the document
+    /// gains a top-level `visible` flag.
+    pub fn explain(&self, ctx: Option<(&SecurityContext, &Levels)>) -> Value {
+        let clauses: Vec<Value> = self
+            .clauses
+            .iter()
+            .map(|clause| {
+                let requirements: Vec<Value> = clause
+                    .iter()
+                    .map(|req| {
+                        let mut doc = json!(req);
+                        if let Some((ctx, levels)) = ctx {
+                            doc["satisfied"] = json!(req.evaluate(ctx, levels));
+                        }
+                        doc
+                    })
+                    .collect();
+
+                let mut doc = json!({ "requirements": requirements });
+                if let Some((ctx, levels)) = ctx {
+                    doc["satisfied"] = json!(clause.iter().any(|req| req.evaluate(ctx, levels)));
+                }
+                doc
+            })
+            .collect();
+
+        let mut doc = json!({
+            "always_true": self.always_true,
+            "clauses": clauses,
+        });
+        if let Some((ctx, levels)) = ctx {
+            doc["visible"] = json!(self.evaluate(ctx, levels));
+        }
+        doc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::label::parse::parse;
+
+    fn clearance_levels() -> Levels {
+        let mut clearance = HashMap::new();
+        clearance.insert("public".to_string(), 0);
+        clearance.insert("confidential".to_string(), 1);
+        clearance.insert("secret".to_string(), 2);
+        clearance.insert("top_secret".to_string(), 3);
+        Levels::from([("clearance".to_string(), clearance)])
+    }
+
+    #[test]
+    fn explain_structure() {
+        let label = parse("(role=admin|!team=finance)&clearance>=secret").unwrap();
+        let doc = label.explain(None);
+
+        assert_eq!(doc["always_true"], json!(false));
+        assert_eq!(doc["clauses"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            doc["clauses"][0]["requirements"][1],
+            json!({ "key": "team", "op": "=", "value": "finance", "negated": true })
+        );
+        assert_eq!(doc["clauses"][1]["requirements"][0]["op"], json!(">="));
+        assert!(doc.get("visible").is_none());
+    }
+
+    #[test]
+    fn explain_always_true() {
+        let doc = parse("true").unwrap().explain(None);
+        assert_eq!(doc, json!({ "always_true": true, "clauses": [] }));
+    }
+
+    #[test]
+    fn explain_comparison_ops() {
+        for (expr, op) in [
+            ("clearance>=secret", ">="),
+            ("clearance>secret", ">"),
+            ("clearance<=secret", "<="),
+            ("clearance<secret", "<"),
+            ("clearance=*", "=*"),
+        ] {
+            let doc = parse(expr).unwrap().explain(None);
+            assert_eq!(doc["clauses"][0]["requirements"][0]["op"], json!(op));
+        }
+    }
+
+    #[test]
+    fn explain_with_context_marks_failed_clause() {
+        let levels = clearance_levels();
+
+        let label = parse("role=admin&clearance>=secret").unwrap();
+        let mut ctx = SecurityContext::default();
+        ctx.set_attr("role", "admin");
+        ctx.set_attr("clearance", "confidential");
+
+        let doc = label.explain(Some((&ctx, &levels)));
+        assert_eq!(doc["visible"], json!(false));
+        assert_eq!(doc["clauses"][0]["satisfied"], json!(true));
+        assert_eq!(doc["clauses"][1]["satisfied"], json!(false));
+        assert_eq!(
+            doc["clauses"][1]["requirements"][0]["satisfied"],
+            json!(false)
+        );
+    }
+
+    #[test]
+    fn explain_with_context_comparison_ops() {
+        let levels = clearance_levels();
+
+        let mut ctx = SecurityContext::default();
+        ctx.set_attr("clearance", "secret");
+
+        for (expr, satisfied) in [
+            ("clearance>=secret", true),
+            ("clearance>secret", false),
+            ("clearance<=secret", true),
+            ("clearance<secret", false),
+            ("clearance>confidential", true),
+            ("clearance<top_secret", true),
+        ] {
+            let doc = parse(expr).unwrap().explain(Some((&ctx, &levels)));
+            assert_eq!(doc["visible"], json!(satisfied), "{expr}");
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use rusqlite::{Connection, OptionalExtension, Result};
+
+use crate::label::Label;
+
+/// Bounds on expressions accepted by `sec_define_label`.
+///
+/// Each is read from `sec_meta` (`max_label_length`, `max_label_clauses`,
+/// `max_clause_requirements`); a value of 0 or less disables that limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LabelLimits {
+    pub max_length: usize,
+    pub max_clauses: usize,
+    pub max_requirements: usize,
+}
+
+impl Default for LabelLimits {
+    fn default() -> Self {
+        Self {
+            max_length: 4096,
+            max_clauses: 64,
+            max_requirements: 64,
+        }
+    }
+}
+
+fn meta_limit(conn: &Connection, key: &str, default: usize) -> Result<usize> {
+    let value: Option<i64> = conn
+        .query_row("SELECT value FROM sec_meta WHERE key = ?1", [key], |r| {
+            r.get(0)
+        })
+        .optional()?;
+    Ok(match value {
+        None => default,
+        Some(v) if v <= 0 => usize::MAX,
+        Some(v) => v as usize,
+    })
+}
+
+impl LabelLimits {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let defaults = Self::default();
+        Ok(Self {
+            max_length: meta_limit(conn, "max_label_length", defaults.max_length)?,
+            max_clauses: meta_limit(conn, "max_label_clauses", defaults.max_clauses)?,
+            max_requirements: meta_limit(
+                conn,
+                "max_clause_requirements",
+                defaults.max_requirements,
+            )?,
+        })
+    }
+
+    /// Checked before parsing, so oversized input is never parsed
+    pub fn check_length(&self, expr: &str) -> Result<(), String> {
+        if expr.len() > self.max_length {
+            return Err(format!(
+                "expression is {} bytes, limit is {}",
+                expr.len(),
+                self.max_length
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, label: &Label) -> Result<(), String> {
+        if label.clauses.len() > self.max_clauses {
+            return Err(format!(
+                "expression has {} clauses, limit is {}",
+                label.clauses.len(),
+                self.max_clauses
+            ));
+        }
+        if let Some((i, clause)) = label
+            .clauses
+            .iter()
+            .enumerate()
+            .find(|(_, c)| c.len() > self.max_requirements)
+        {
+            return Err(format!(
+                "clause {} has {} requirements, limit is {}",
+                i + 1,
+                clause.len(),
+                self.max_requirements
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label::parse::parse;
+
+    fn limits(max_length: usize, max_clauses: usize, max_requirements: usize) -> LabelLimits {
+        LabelLimits {
+            max_length,
+            max_clauses,
+            max_requirements,
+        }
+    }
+
+    #[test]
+    fn length_limit() {
+        let limits = limits(10, 64, 64);
+        assert!(limits.check_length("role=admin").is_ok());
+        assert_eq!(
+            limits.check_length("role=auditor"),
+            Err("expression is 12 bytes, limit is 10".to_string())
+        );
+    }
+
+    #[test]
+    fn clause_limit() {
+        let limits = limits(4096, 2, 64);
+        assert!(limits.check(&parse("a=1&b=2").unwrap()).is_ok());
+        assert_eq!(
+            limits.check(&parse("a=1&b=2&c=3").unwrap()),
+            Err("expression has 3 clauses, limit is 2".to_string())
+        );
+        // Limits apply after normalization: (a&b)|(c&d) is four clauses
+        assert!(
+            limits
+                .check(&parse("(a=1&b=2)|(c=3&d=4)").unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn requirement_limit() {
+        let limits = limits(4096, 64, 2);
+        assert!(limits.check(&parse("a in (1, 2)&b=3").unwrap()).is_ok());
+        assert_eq!(
+            limits.check(&parse("b=3&a in (1, 2, 3)").unwrap()),
+            Err("clause 2 has 3 requirements, limit is 2".to_string())
+        );
+    }
+
+    #[test]
+    fn true_is_within_limits() {
+        assert!(limits(4, 0, 0).check(&parse("true").unwrap()).is_ok());
+    }
+}
@@ -1,39 +1,106 @@
-use std::{collections::HashMap, sync::LazyLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+};
 
 use parking_lot::Mutex;
+use serde::Serialize;
 
+pub mod build;
+pub mod canonical;
 pub mod define;
+pub mod delete;
+pub mod dominates;
 pub mod evaluate;
+pub mod explain;
+pub mod limits;
 pub mod parse;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub enum CompareOp {
-    Eq, // =
-    Ge, // >=
-    Gt, // >
-    Le, // <=
-    Lt, // <
+    #[serde(rename = "=")]
+    Eq,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "=*")]
+    Present,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub struct AttrReq {
-    pub key: String,
+    pub key: String, // lowercased; attribute keys are case-insensitive
     pub op: CompareOp,
     pub value: String,
+    pub negated: bool, // !key=value
 }
 
 pub type Clause = Vec<AttrReq>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Label {
     pub clauses: Vec<Clause>,
     pub always_true: bool,
 }
 
-// Cache: label_id -> Label
-pub static LABEL_CACHE: LazyLock<Mutex<HashMap<i64, Label>>> =
+// Cache: (db handle address, label_id) -> Label
+//
+// Label ids are rowids in each database's own sec_labels table, so the
+// connection is part of the key.
+pub static LABEL_CACHE: LazyLock<Mutex<HashMap<(usize, i64), Label>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
-// Cache: attr_name -> (level_name -> level_value)
-pub static LEVELS_CACHE: LazyLock<Mutex<HashMap<String, HashMap<String, i64>>>> =
+/// Ordered levels of each attribute: attr_name -> (level_name -> level_value)
+pub type Levels = HashMap<String, HashMap<String, i64>>;
+
+// Cache: db handle address -> levels of that database's sec_levels
+//
+// Like labels, levels belong to each database, so one connection loading its
+// levels must not change how another compares.
+pub static LEVELS_CACHE: LazyLock<Mutex<HashMap<usize, Arc<Levels>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Memo: db handle address -> (label_id -> visible to the effective context)
+//
+// Only valid for the generation it was filled in: cleared when the connection
+// bumps the generation, evicts labels or changes its levels.
+pub static VISIBILITY_CACHE: LazyLock<Mutex<HashMap<usize, HashMap<i64, bool>>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Drop all cached labels for a connection
+pub fn evict_labels(db_ptr: usize) {
+    LABEL_CACHE.lock().retain(|(db, _), _| *db != db_ptr);
+    forget_visibility(db_ptr);
+}
+
+/// Drop one cached label for a connection
+pub fn evict_label(db_ptr: usize, label_id: i64) {
+    LABEL_CACHE.lock().remove(&(db_ptr, label_id));
+    if let Some(memo) = VISIBILITY_CACHE.lock().get_mut(&db_ptr) {
+        memo.remove(&label_id);
+    }
+}
+
+/// The levels a connection compares against, none until it loads them
+pub fn levels(db_ptr: usize) -> Arc<Levels> {
+    LEVELS_CACHE
+        .lock()
+        .get(&db_ptr)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Drop a connection's levels
+pub fn forget_levels(db_ptr: usize) {
+    LEVELS_CACHE.lock().remove(&db_ptr);
+}
+
+/// Drop a connection's memoized visibility decisions
+pub fn forget_visibility(db_ptr: usize) {
+    VISIBILITY_CACHE.lock().remove(&db_ptr);
+}
@@ -1,57 +1,310 @@
+use std::borrow::Cow;
+
 use nom::{
+    Err,
     IResult,
     Parser,
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::char,
-    combinator::map,
-    multi::separated_list1,
-    sequence::delimited,
+    bytes::complete::{is_not, tag, tag_no_case, take_while1},
+    character::complete::{char, multispace0, multispace1},
+    combinator::{cut, map, value},
+    error::{ErrorKind, ParseError},
+    multi::many0,
+    sequence::{delimited, preceded, terminated},
 };
 
 use crate::label::{AttrReq, Clause, CompareOp, Label};
 
+/// Upper bound on clauses produced while normalizing into CNF.
+///
+/// Distributing `|` over `&` (and negating conjunctions) multiplies clause
+/// counts, so a short expression can otherwise expand exponentially.
+pub const MAX_EXPANDED_CLAUSES: usize = 10_000;
+
+/// What the parser expected, and the input left where it gave up
+#[derive(Debug)]
+struct LabelError<'a> {
+    input: &'a str,
+    message: Cow<'static, str>,
+}
+
+impl<'a> ParseError<&'a str> for LabelError<'a> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        LabelError {
+            input,
+            message: format!("unexpected input ({kind:?})").into(),
+        }
+    }
+
+    fn from_char(input: &'a str, c: char) -> Self {
+        LabelError {
+            input,
+            message: format!("expected '{c}'").into(),
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(self, other: Self) -> Self {
+        // Report whichever alternative got furthest
+        if other.input.len() <= self.input.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+type PResult<'a, O> = IResult<&'a str, O, LabelError<'a>>;
+
+/// Report recoverable errors at the very start of `parser` as "expected `what`"
+fn expect<'a, O, P>(
+    what: &'static str,
+    mut parser: P,
+) -> impl Parser<&'a str, Output = O, Error = LabelError<'a>>
+where
+    P: Parser<&'a str, Output = O, Error = LabelError<'a>>,
+{
+    move |input: &'a str| match parser.parse(input) {
+        Err(Err::Error(e)) if e.input.len() == input.len() => Err(Err::Error(LabelError {
+            input,
+            message: format!("expected {what}").into(),
+        })),
+        result => result,
+    }
+}
+
+fn too_complex(input: &str) -> Err<LabelError<'_>> {
+    Err::Failure(LabelError {
+        input,
+        message: format!("expression expands to more than {MAX_EXPANDED_CLAUSES} clauses").into(),
+    })
+}
+
 fn is_ident_char(c: char) -> bool {
     c.is_alphanumeric() || c == '_'
 }
 
-fn ident(input: &str) -> IResult<&str, &str> {
-    take_while1(is_ident_char).parse(input)
+/// Attribute key, folded to lowercase
+fn ident(input: &str) -> PResult<'_, String> {
+    expect(
+        "identifier",
+        map(take_while1(is_ident_char), str::to_lowercase),
+    )
+    .parse(input)
 }
 
-fn compare_op(input: &str) -> IResult<&str, CompareOp> {
-    alt((
-        map(tag(">="), |_| CompareOp::Ge),
-        map(tag("<="), |_| CompareOp::Le),
-        map(tag(">"), |_| CompareOp::Gt),
-        map(tag("<"), |_| CompareOp::Lt),
-        map(char('='), |_| CompareOp::Eq),
-    ))
+fn is_bare_value_char(c: char) -> bool {
+    is_ident_char(c) || matches!(c, '.' | '-' | '@' | ':')
+}
+
+/// `'...'` with `''` as an escaped quote
+fn quoted_value(input: &str) -> PResult<'_, String> {
+    preceded(
+        char('\''),
+        cut(terminated(
+            map(many0(alt((is_not("'"), value("'", tag("''"))))), |parts| {
+                parts.concat()
+            }),
+            expect("closing quote", char('\'')),
+        )),
+    )
+    .parse(input)
+}
+
+fn attr_value(input: &str) -> PResult<'_, String> {
+    expect(
+        "value",
+        alt((
+            quoted_value,
+            map(take_while1(is_bare_value_char), str::to_string),
+        )),
+    )
+    .parse(input)
+}
+
+fn compare_op(input: &str) -> PResult<'_, CompareOp> {
+    expect(
+        "comparison operator after identifier",
+        alt((
+            map(tag(">="), |_| CompareOp::Ge),
+            map(tag("<="), |_| CompareOp::Le),
+            map(tag(">"), |_| CompareOp::Gt),
+            map(tag("<"), |_| CompareOp::Lt),
+            map(char('='), |_| CompareOp::Eq),
+        )),
+    )
     .parse(input)
 }
 
-fn attr_req(input: &str) -> IResult<&str, AttrReq> {
-    map((ident, compare_op, ident), |(k, op, v)| AttrReq {
-        key: k.to_string(),
-        op,
-        value: v.to_string(),
+/// `key=*`: the context has at least one value for `key`
+fn presence_req(input: &str) -> PResult<'_, AttrReq> {
+    map((ident, ws(char('=')), char('*')), |(k, _, _)| AttrReq {
+        key: k,
+        op: CompareOp::Present,
+        value: "*".to_string(),
+        negated: false,
     })
     .parse(input)
 }
 
-fn clause(input: &str) -> IResult<&str, Clause> {
+fn attr_req(input: &str) -> PResult<'_, AttrReq> {
     alt((
-        delimited(
-            char('('),
-            separated_list1(char('|'), attr_req),
-            char(')'),
+        presence_req,
+        map(
+            (
+                ident,
+                ws(compare_op),
+                cut(expect("value after operator", attr_value)),
+            ),
+            |(k, op, value)| AttrReq {
+                key: k,
+                op,
+                value,
+                negated: false,
+            },
+        ),
+    ))
+    .parse(input)
+}
+
+fn negate_req(req: AttrReq) -> AttrReq {
+    AttrReq {
+        negated: !req.negated,
+        ..req
+    }
+}
+
+/// Negate a conjunction of clauses, keeping the result in CNF.
+///
+/// By De Morgan, `!(c1 & c2)` is `!c1 | !c2`, and each `!ci` is a conjunction of
+/// negated literals, so the result is the cross product of the negated clauses.
+fn negate_clauses(clauses: Vec<Clause>) -> Vec<Clause> {
+    clauses.into_iter().fold(vec![vec![]], |acc, clause| {
+        acc.iter()
+            .flat_map(|partial| {
+                clause.iter().map(move |req| {
+                    let mut next = partial.clone();
+                    next.push(negate_req(req.clone()));
+                    next
+                })
+            })
+            .collect()
+    })
+}
+
+/// Disjunction of two CNF formulas, keeping the result in CNF.
+///
+/// `(a1 & a2) | (b1 & b2)` distributes to `(a1|b1) & (a1|b2) & (a2|b1) & (a2|b2)`.
+fn or_clauses(lhs: Vec<Clause>, rhs: Vec<Clause>) -> Vec<Clause> {
+    lhs.iter()
+        .flat_map(|a| {
+            rhs.iter().map(move |b| {
+                let mut merged = a.clone();
+                merged.extend(b.iter().cloned());
+                merged
+            })
+        })
+        .collect()
+}
+
+fn ws<'a, O, P>(inner: P) -> impl Parser<&'a str, Output = O, Error = LabelError<'a>>
+where
+    P: Parser<&'a str, Output = O, Error = LabelError<'a>>,
+{
+    delimited(multispace0, inner, multispace0)
+}
+
+/// `key in (v1, v2, ...)`: sugar for `(key=v1|key=v2|...)`
+fn in_list(input: &str) -> PResult<'_, Clause> {
+    map(
+        (
+            ident,
+            delimited(multispace1, tag_no_case("in"), multispace0),
+            cut(delimited(
+                expect("'(' after 'in'", char('(')),
+                (
+                    ws(attr_value),
+                    many0(preceded(char(','), cut(ws(attr_value)))),
+                ),
+                expect("',' or ')'", char(')')),
+            )),
         ),
-        map(attr_req, |r| vec![r]),
+        |(k, _, (first, rest))| {
+            std::iter::once(first)
+                .chain(rest)
+                .map(|value| AttrReq {
+                    key: k.clone(),
+                    op: CompareOp::Eq,
+                    value,
+                    negated: false,
+                })
+                .collect()
+        },
+    )
+    .parse(input)
+}
+
+/// `!unary`, kept in CNF
+fn negation(input: &str) -> PResult<'_, Vec<Clause>> {
+    let (rest, clauses) = preceded(char('!'), cut(unary)).parse(input)?;
+    let expanded = clauses
+        .iter()
+        .fold(1usize, |n, clause| n.saturating_mul(clause.len()));
+    if expanded > MAX_EXPANDED_CLAUSES {
+        return Err(too_complex(input));
+    }
+    Ok((rest, negate_clauses(clauses)))
+}
+
+/// `!unary | ( or_expr ) | key in (...) | key op value`
+fn unary(input: &str) -> PResult<'_, Vec<Clause>> {
+    ws(expect(
+        "identifier, '!' or '('",
+        alt((
+            negation,
+            preceded(
+                char('('),
+                cut(terminated(or_expr, expect("')'", char(')')))),
+            ),
+            map(in_list, |c| vec![c]),
+            map(attr_req, |r| vec![vec![r]]),
+        )),
     ))
     .parse(input)
 }
 
-fn label_expr(input: &str) -> IResult<&str, Label> {
+/// `unary & unary & ...`
+fn and_expr(input: &str) -> PResult<'_, Vec<Clause>> {
+    map(
+        (unary, many0(preceded(char('&'), cut(unary)))),
+        |(first, rest)| {
+            first
+                .into_iter()
+                .chain(rest.into_iter().flatten())
+                .collect()
+        },
+    )
+    .parse(input)
+}
+
+/// `and_expr | and_expr | ...`, normalized into CNF
+fn or_expr(input: &str) -> PResult<'_, Vec<Clause>> {
+    let (mut rest, mut clauses) = and_expr(input)?;
+    while let Some(after) = rest.strip_prefix('|') {
+        let (next, rhs) = cut(and_expr).parse(after)?;
+        if clauses.len().saturating_mul(rhs.len()) > MAX_EXPANDED_CLAUSES {
+            return Err(too_complex(rest));
+        }
+        clauses = or_clauses(clauses, rhs);
+        rest = next;
+    }
+    Ok((rest, clauses))
+}
+
+fn label_expr(input: &str) -> PResult<'_, Label> {
     if input.trim() == "true" {
         return Ok((
             "",
@@ -62,23 +315,35 @@ fn label_expr(input: &str) -> IResult<&str, Label> {
         ));
     }
 
-    map(separated_list1(char('&'), clause), |clauses| Label {
+    map(or_expr, |clauses| Label {
         clauses,
         always_true: false,
     })
     .parse(input)
 }
 
+/// Parse a label expression into CNF.
+///
+/// Errors name what was expected and the byte offset into `expr` where
+/// parsing stopped, e.g. `expected value after operator at position 5`.
 pub fn parse(expr: &str) -> Result<Label, String> {
     let trimmed = expr.trim();
+    let leading = expr.len() - expr.trim_start().len();
+    let position = |rest: &str| leading + trimmed.len() - rest.len();
+
     match label_expr(trimmed) {
         Ok(("", label)) => Ok(label),
-        Ok((rest, _)) => Err(format!("unexpected trailing: {rest}")),
-        Err(e) => Err(format!("parse error: {e}")),
+        Ok((rest, _)) => Err(format!(
+            "expected '&', '|' or end of expression at position {}",
+            position(rest)
+        )),
+        Err(Err::Error(e) | Err::Failure(e)) => {
+            Err(format!("{} at position {}", e.message, position(e.input)))
+        }
+        Err(Err::Incomplete(_)) => Err("incomplete expression".to_string()),
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -112,4 +377,254 @@ mod tests {
         let label = parse("(role=admin|role=auditor)&clearance>=confidential").unwrap();
         assert_eq!(label.clauses.len(), 2);
     }
+
+    #[test]
+    fn parse_negated_req() {
+        let label = parse("!role=contractor").unwrap();
+        assert_eq!(label.clauses.len(), 1);
+        assert_eq!(label.clauses[0][0].key, "role");
+        assert!(label.clauses[0][0].negated);
+    }
+
+    #[test]
+    fn parse_negated_req_in_group() {
+        let label = parse("(role=admin|!team=finance)").unwrap();
+        assert_eq!(label.clauses.len(), 1);
+        assert!(!label.clauses[0][0].negated);
+        assert!(label.clauses[0][1].negated);
+    }
+
+    #[test]
+    fn parse_negated_group() {
+        // !(a|b) == !a & !b
+        let label = parse("!(role=admin|role=auditor)").unwrap();
+        assert_eq!(label.clauses.len(), 2);
+        assert!(label.clauses.iter().all(|c| c.len() == 1 && c[0].negated));
+    }
+
+    #[test]
+    fn parse_double_negation() {
+        let label = parse("!!role=admin").unwrap();
+        assert_eq!(label.clauses.len(), 1);
+        assert!(!label.clauses[0][0].negated);
+
+        let label = parse("!!(role=admin|role=auditor)").unwrap();
+        assert_eq!(label.clauses.len(), 1);
+        assert_eq!(label.clauses[0].len(), 2);
+        assert!(label.clauses[0].iter().all(|r| !r.negated));
+    }
+
+    #[test]
+    fn parse_nested_and_in_or() {
+        // (a & b) | c == (a|c) & (b|c)
+        let label = parse("(role=admin & team=finance) | clearance>=secret").unwrap();
+        assert_eq!(label.clauses.len(), 2);
+        assert!(label.clauses.iter().all(|c| c.len() == 2));
+        assert!(
+            label
+                .clauses
+                .iter()
+                .all(|c| c[1].key == "clearance" && c[1].op == CompareOp::Ge)
+        );
+    }
+
+    #[test]
+    fn parse_unparenthesized_or() {
+        let label = parse("role=admin|role=auditor").unwrap();
+        assert_eq!(label.clauses.len(), 1);
+        assert_eq!(label.clauses[0].len(), 2);
+    }
+
+    #[test]
+    fn parse_and_binds_tighter_than_or() {
+        // a | b & c == (a|b) & (a|c)
+        let label = parse("role=admin|team=finance&region=eu").unwrap();
+        assert_eq!(label.clauses.len(), 2);
+        assert_eq!(label.clauses[0][0].key, "role");
+        assert_eq!(label.clauses[1][0].key, "role");
+    }
+
+    #[test]
+    fn parse_deeply_nested() {
+        let label = parse("((role=admin|(team=finance&!region=us))&level=2)").unwrap();
+        // (role=admin|team=finance) & (role=admin|!region=us) & level=2
+        assert_eq!(label.clauses.len(), 3);
+        assert!(label.clauses[1][1].negated);
+    }
+
+    #[test]
+    fn parse_whitespace() {
+        let label = parse("  ( role = admin | role=auditor ) & team=finance ").unwrap();
+        assert_eq!(label.clauses.len(), 2);
+        assert_eq!(label.clauses[0][0].value, "admin");
+    }
+
+    #[test]
+    fn parse_quoted_value() {
+        let label = parse("department='Human Resources'").unwrap();
+        assert_eq!(label.clauses[0][0].key, "department");
+        assert_eq!(label.clauses[0][0].value, "Human Resources");
+    }
+
+    #[test]
+    fn parse_quoted_value_with_escapes() {
+        let label = parse("(owner='O''Brien'|owner='a & (b|c)')").unwrap();
+        assert_eq!(label.clauses[0][0].value, "O'Brien");
+        assert_eq!(label.clauses[0][1].value, "a & (b|c)");
+
+        let label = parse("note=''''").unwrap();
+        assert_eq!(label.clauses[0][0].value, "'");
+    }
+
+    #[test]
+    fn parse_empty_quoted_value() {
+        let label = parse("tenant_id=''").unwrap();
+        assert_eq!(label.clauses[0][0].value, "");
+    }
+
+    #[test]
+    fn parse_bare_value_special_chars() {
+        let label = parse("email=bob@example.com&host=db-01.internal:5432").unwrap();
+        assert_eq!(label.clauses[0][0].value, "bob@example.com");
+        assert_eq!(label.clauses[1][0].value, "db-01.internal:5432");
+    }
+
+    #[test]
+    fn parse_unterminated_quote_fails() {
+        assert!(parse("department='Human Resources").is_err());
+        assert!(parse("department='O''").is_err());
+    }
+
+    #[test]
+    fn parse_wildcard() {
+        let label = parse("tenant_id=*").unwrap();
+        assert_eq!(label.clauses[0][0].key, "tenant_id");
+        assert_eq!(label.clauses[0][0].op, CompareOp::Present);
+
+        // A quoted star is a literal value
+        let label = parse("tenant_id='*'").unwrap();
+        assert_eq!(label.clauses[0][0].op, CompareOp::Eq);
+        assert_eq!(label.clauses[0][0].value, "*");
+    }
+
+    #[test]
+    fn parse_wildcard_in_groups() {
+        let label = parse("(tenant_id = * | role=admin) & !region=*").unwrap();
+        assert_eq!(label.clauses.len(), 2);
+        assert_eq!(label.clauses[0][0].op, CompareOp::Present);
+        assert_eq!(label.clauses[1][0].op, CompareOp::Present);
+        assert!(label.clauses[1][0].negated);
+    }
+
+    #[test]
+    fn parse_wildcard_only_with_eq() {
+        assert!(parse("clearance>=*").is_err());
+    }
+
+    #[test]
+    fn parse_in_list() {
+        let label = parse("role in (admin,auditor,sre,oncall)").unwrap();
+        assert_eq!(label.clauses.len(), 1);
+        assert_eq!(label.clauses[0].len(), 4);
+        assert!(label.clauses[0].iter().all(|r| r.key == "role"));
+        assert_eq!(label.clauses[0][3].value, "oncall");
+    }
+
+    #[test]
+    fn parse_in_list_whitespace_and_quotes() {
+        let label = parse("status IN ( 'on hold' , active )&team=finance").unwrap();
+        assert_eq!(label.clauses.len(), 2);
+        assert_eq!(label.clauses[0][0].value, "on hold");
+        assert_eq!(label.clauses[0][1].value, "active");
+    }
+
+    #[test]
+    fn parse_in_list_matches_or_group() {
+        let sugar = parse("role in (admin, auditor)").unwrap();
+        let plain = parse("(role=admin|role=auditor)").unwrap();
+        assert_eq!(format!("{:?}", sugar), format!("{:?}", plain));
+    }
+
+    #[test]
+    fn parse_in_list_empty_fails() {
+        assert!(parse("role in ()").is_err());
+        assert!(parse("role in (admin,)").is_err());
+    }
+
+    #[test]
+    fn parse_unbalanced_parens_fails() {
+        assert!(parse("(role=admin|role=auditor").is_err());
+        assert!(parse("role=admin)").is_err());
+    }
+
+    #[test]
+    fn parse_dangling_negation_fails() {
+        assert!(parse("!").is_err());
+        assert!(parse("role=admin&!").is_err());
+    }
+
+    fn error(expr: &str) -> String {
+        parse(expr).unwrap_err()
+    }
+
+    #[test]
+    fn parse_error_positions() {
+        assert_eq!(
+            error("role admin"),
+            "expected comparison operator after identifier at position 5"
+        );
+        assert_eq!(
+            error("role="),
+            "expected value after operator at position 5"
+        );
+        assert_eq!(
+            error("  role=>x"),
+            "expected value after operator at position 7"
+        );
+        assert_eq!(
+            error("role=admin&"),
+            "expected identifier, '!' or '(' at position 11"
+        );
+        assert_eq!(error(""), "expected identifier, '!' or '(' at position 0");
+        assert_eq!(
+            error("x=1&(y=2|)"),
+            "expected identifier, '!' or '(' at position 9"
+        );
+    }
+
+    #[test]
+    fn parse_error_unbalanced() {
+        assert_eq!(error("(role=admin"), "expected ')' at position 11");
+        assert_eq!(
+            error("role=admin)"),
+            "expected '&', '|' or end of expression at position 10"
+        );
+        assert_eq!(
+            error("role=admin team=x"),
+            "expected '&', '|' or end of expression at position 11"
+        );
+    }
+
+    #[test]
+    fn parse_error_values() {
+        assert_eq!(error("dept='abc"), "expected closing quote at position 9");
+        assert_eq!(
+            error("role in admin"),
+            "expected '(' after 'in' at position 8"
+        );
+        assert_eq!(error("role in (a,"), "expected value at position 11");
+        assert_eq!(error("role in (a b)"), "expected ',' or ')' at position 11");
+    }
+
+    #[test]
+    fn parse_expansion_limit() {
+        // 14 two-literal clauses negate to 2^14 clauses
+        let conj: Vec<String> = (0..14).map(|i| format!("(a{i}=1|b{i}=1)")).collect();
+        let expr = format!("!({})", conj.join("&"));
+        assert!(error(&expr).starts_with("expression expands to more than"));
+
+        // ...and the same shape without negation distributes over '|'
+        let disj: Vec<String> = (0..14).map(|i| format!("(a{i}=1&b{i}=1)")).collect();
+        assert!(error(&disj.join("|")).starts_with("expression expands to more than"));
+    }
 }
@@ -0,0 +1,415 @@
+use std::{collections::HashMap, io::ErrorKind, mem::forget};
+
+use rusqlite::{Connection, Error, Result, params};
+use serde_json::{Value as Json, json};
+
+use crate::{
+    label::{define::define_label, evaluate::load_levels},
+    views::bump_generation::bump_generation,
+};
+
+/// Version of the document written by [`export_policies`]
+const FORMAT_VERSION: i64 = 1;
+
+fn invalid<T: ToString>(msg: T) -> Error {
+    Error::UserFunctionError(Box::new(std::io::Error::new(
+        ErrorKind::InvalidInput,
+        msg.to_string(),
+    )))
+}
+
+fn has_shim_policies(conn: &Connection) -> Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '__sqlshim_policies'",
+        [],
+        |r| r.get(0),
+    )
+}
+
+fn label_exprs(conn: &Connection) -> Result<HashMap<i64, String>> {
+    conn.prepare("SELECT id, expr FROM sec_labels")?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect()
+}
+
+/// Every policy in the database as one JSON document.
+///
+/// Labels are referred to by their canonical expression rather than their id,
+/// since ids are rowids local to each database, and every list is sorted, so
+/// equal policies export identically.
+pub fn export_policies(conn: &Connection) -> Result<Json> {
+    let exprs = label_exprs(conn)?;
+    let label = |id: Option<i64>| id.and_then(|id| exprs.get(&id).cloned());
+
+    let labels = conn
+        .prepare("SELECT expr, source FROM sec_labels ORDER BY expr")?
+        .query_map([], |r| {
+            Ok(json!({
+                "expr": r.get::<_, String>(0)?,
+                "source": r.get::<_, Option<String>>(1)?,
+            }))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    let levels = conn
+        .prepare(
+            "SELECT attr_name, level_name, level_value FROM sec_levels
+             ORDER BY attr_name, level_value, level_name",
+        )?
+        .query_map([], |r| {
+            Ok(json!({
+                "attr_name": r.get::<_, String>(0)?,
+                "level_name": r.get::<_, String>(1)?,
+                "level_value": r.get::<_, i64>(2)?,
+            }))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut columns_stmt = conn.prepare(
+        "SELECT column_name, read_label_id, update_label_id, mask_expr
+         FROM sec_columns WHERE logical_table = ?1 ORDER BY rowid",
+    )?;
+    let mut tables = Vec::new();
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT logical_name, physical_schema, physical_name, row_label_col,
+               table_label_id, insert_label_id, delete_label_id,
+               allow_implicit_label, materialize_labels
+        FROM sec_tables ORDER BY logical_name
+        "#,
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let logical: String = r.get(0)?;
+        let columns = columns_stmt
+            .query_map([&logical], |c| {
+                Ok(json!({
+                    "column_name": c.get::<_, String>(0)?,
+                    "read_label": label(c.get(1)?),
+                    "update_label": label(c.get(2)?),
+                    "mask_expr": c.get::<_, Option<String>>(3)?,
+                }))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        tables.push(json!({
+            "logical_name": logical,
+            "physical_schema": r.get::<_, String>(1)?,
+            "physical_name": r.get::<_, String>(2)?,
+            "row_label_col": r.get::<_, String>(3)?,
+            "table_label": label(r.get(4)?),
+            "insert_label": label(r.get(5)?),
+            "delete_label": label(r.get(6)?),
+            "allow_implicit_label": r.get::<_, Option<i64>>(7)?,
+            "materialize_labels": r.get::<_, i64>(8)?,
+            "columns": columns,
+        }));
+    }
+
+    let mut doc = json!({
+        "version": FORMAT_VERSION,
+        "labels": labels,
+        "levels": levels,
+        "tables": tables,
+    });
+
+    // Policies recorded by sqlshim's CREATE POLICY, if it has been used
+    if has_shim_policies(conn)? {
+        doc["shim_policies"] = conn
+            .prepare(
+                "SELECT name, table_name, operation, label_id, expr FROM __sqlshim_policies
+                 ORDER BY table_name, name",
+            )?
+            .query_map([], |r| {
+                Ok(json!({
+                    "name": r.get::<_, String>(0)?,
+                    "table_name": r.get::<_, String>(1)?,
+                    "operation": r.get::<_, String>(2)?,
+                    "label": label(r.get(3)?),
+                    "expr": r.get::<_, String>(4)?,
+                }))
+            })?
+            .collect::<Result<Vec<_>>>()?
+            .into();
+    }
+
+    Ok(doc)
+}
+
+pub fn export_policies_raw(db_ptr: usize) -> Result<Json> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = export_policies(&conn);
+
+    forget(conn);
+    result
+}
+
+/// How [`import_policies`] treats policies already in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Remove every table, column, level and shim policy first
+    Replace,
+    /// Keep existing entries, overwriting those the document also defines
+    Merge,
+}
+
+impl ImportMode {
+    pub fn parse(mode: &str) -> Result<Self> {
+        match mode {
+            "replace" => Ok(Self::Replace),
+            "merge" => Ok(Self::Merge),
+            other => Err(invalid(format!(
+                "unknown mode '{other}' (expected 'replace' or 'merge')"
+            ))),
+        }
+    }
+}
+
+fn field<'a>(obj: &'a Json, what: &str, key: &str) -> Result<&'a Json> {
+    obj.get(key)
+        .ok_or_else(|| invalid(format!("{what} is missing '{key}'")))
+}
+
+fn text(obj: &Json, what: &str, key: &str) -> Result<String> {
+    field(obj, what, key)?
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| invalid(format!("{what} '{key}' must be text")))
+}
+
+fn opt_text(obj: &Json, what: &str, key: &str) -> Result<Option<String>> {
+    match obj.get(key) {
+        None | Some(Json::Null) => Ok(None),
+        Some(Json::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(invalid(format!("{what} '{key}' must be text or null"))),
+    }
+}
+
+fn opt_int(obj: &Json, what: &str, key: &str) -> Result<Option<i64>> {
+    match obj.get(key) {
+        None | Some(Json::Null) => Ok(None),
+        Some(v) => v
+            .as_i64()
+            .map(Some)
+            .ok_or_else(|| invalid(format!("{what} '{key}' must be an integer or null"))),
+    }
+}
+
+fn list<'a>(doc: &'a Json, key: &str) -> Result<&'a [Json]> {
+    match doc.get(key) {
+        None => Ok(&[]),
+        Some(Json::Array(items)) => Ok(items),
+        Some(_) => Err(invalid(format!("'{key}' must be an array"))),
+    }
+}
+
+/// Labels of the document defined in `conn`: canonical expression to id
+struct LabelMap(HashMap<String, i64>);
+
+impl LabelMap {
+    fn id(&self, expr: Option<String>) -> Result<Option<i64>> {
+        expr.map(|expr| {
+            self.0
+                .get(&expr)
+                .copied()
+                .ok_or_else(|| invalid(format!("label '{expr}' is not in 'labels'")))
+        })
+        .transpose()
+    }
+}
+
+fn import_labels(conn: &Connection, doc: &Json) -> Result<LabelMap> {
+    let mut ids = HashMap::new();
+    for label in list(doc, "labels")? {
+        let expr = text(label, "label", "expr")?;
+        let source = opt_text(label, "label", "source")?;
+
+        // Define from the original spelling, so it is kept for display
+        let id = define_label(conn, source.as_deref().unwrap_or(&expr))?;
+        let canonical: String =
+            conn.query_row("SELECT expr FROM sec_labels WHERE id = ?1", [id], |r| {
+                r.get(0)
+            })?;
+        if canonical != expr {
+            return Err(invalid(format!(
+                "label '{expr}' is not in canonical form (it defines '{canonical}')"
+            )));
+        }
+        ids.insert(expr, id);
+    }
+    Ok(LabelMap(ids))
+}
+
+fn import_tables(conn: &Connection, doc: &Json, labels: &LabelMap) -> Result<usize> {
+    let tables = list(doc, "tables")?;
+    for table in tables {
+        let logical = text(table, "table", "logical_name")?;
+        let what = format!("table '{logical}'");
+        let schema = opt_text(table, &what, "physical_schema")?.unwrap_or("main".to_string());
+        let physical = text(table, &what, "physical_name")?;
+
+        let exists: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info(?1, ?2)",
+            [&physical, &schema],
+            |r| r.get(0),
+        )?;
+        if !exists {
+            return Err(invalid(format!(
+                "{what}: physical table {schema}.{physical} does not exist"
+            )));
+        }
+
+        conn.execute(
+            "DELETE FROM sec_columns WHERE logical_table = ?1",
+            [&logical],
+        )?;
+        conn.execute(
+            r#"
+            INSERT OR REPLACE INTO sec_tables (
+                logical_name, physical_schema, physical_name, row_label_col,
+                table_label_id, insert_label_id, delete_label_id,
+                allow_implicit_label, materialize_labels
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            "#,
+            params![
+                logical,
+                schema,
+                physical,
+                text(table, &what, "row_label_col")?,
+                labels.id(opt_text(table, &what, "table_label")?)?,
+                labels.id(opt_text(table, &what, "insert_label")?)?,
+                labels.id(opt_text(table, &what, "delete_label")?)?,
+                opt_int(table, &what, "allow_implicit_label")?,
+                opt_int(table, &what, "materialize_labels")?.unwrap_or(0),
+            ],
+        )?;
+
+        for column in list(table, "columns")? {
+            conn.execute(
+                r#"
+                INSERT INTO sec_columns (
+                    logical_table, column_name, read_label_id, update_label_id, mask_expr
+                ) VALUES (?1, ?2, ?3, ?4, ?5)
+                "#,
+                params![
+                    logical,
+                    text(column, &what, "column_name")?,
+                    labels.id(opt_text(column, &what, "read_label")?)?,
+                    labels.id(opt_text(column, &what, "update_label")?)?,
+                    opt_text(column, &what, "mask_expr")?,
+                ],
+            )?;
+        }
+    }
+    Ok(tables.len())
+}
+
+fn import_shim_policies(conn: &Connection, doc: &Json, labels: &LabelMap) -> Result<usize> {
+    let policies = list(doc, "shim_policies")?;
+    if policies.is_empty() {
+        return Ok(0);
+    }
+
+    // As sqlshim's CREATE POLICY creates it
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS __sqlshim_policies (
+            name TEXT NOT NULL,
+            table_name TEXT NOT NULL,
+            operation TEXT NOT NULL,
+            label_id INTEGER,
+            expr TEXT NOT NULL,
+            PRIMARY KEY (name, table_name)
+        );
+        "#,
+    )?;
+    for policy in policies {
+        let name = text(policy, "shim policy", "name")?;
+        let what = format!("shim policy '{name}'");
+        conn.execute(
+            r#"
+            INSERT OR REPLACE INTO __sqlshim_policies (name, table_name, operation, label_id, expr)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+            params![
+                name,
+                text(policy, &what, "table_name")?,
+                text(policy, &what, "operation")?,
+                labels.id(opt_text(policy, &what, "label")?)?,
+                text(policy, &what, "expr")?,
+            ],
+        )?;
+    }
+    Ok(policies.len())
+}
+
+/// Apply a document from [`export_policies`], all or nothing, returning how
+/// many of each kind of entry it held.
+///
+/// Labels are defined as needed and references remapped to this database's
+/// ids. Existing labels are never removed, even by [`ImportMode::Replace`],
+/// since rows may carry them.
+pub fn import_policies(conn: &mut Connection, doc: &Json, mode: ImportMode) -> Result<Json> {
+    match doc.get("version").and_then(Json::as_i64) {
+        Some(FORMAT_VERSION) => {}
+        Some(v) => {
+            return Err(invalid(format!(
+                "unsupported policy document version {v} (expected {FORMAT_VERSION})"
+            )));
+        }
+        None => return Err(invalid("policy document is missing 'version'")),
+    }
+
+    let sp = conn.savepoint()?;
+
+    if mode == ImportMode::Replace {
+        sp.execute_batch(
+            "DELETE FROM sec_columns; DELETE FROM sec_tables; DELETE FROM sec_levels;",
+        )?;
+        if has_shim_policies(&sp)? {
+            sp.execute("DELETE FROM __sqlshim_policies", [])?;
+        }
+    }
+
+    let labels = import_labels(&sp, doc)?;
+
+    let levels = list(doc, "levels")?;
+    for level in levels {
+        let attr = text(level, "level", "attr_name")?;
+        let name = text(level, "level", "level_name")?;
+        let value = opt_int(level, "level", "level_value")?
+            .ok_or_else(|| invalid(format!("level '{attr}.{name}' is missing 'level_value'")))?;
+        sp.execute(
+            "INSERT OR REPLACE INTO sec_levels (attr_name, level_name, level_value) VALUES (?1, ?2, ?3)",
+            params![attr.to_lowercase(), name, value],
+        )?;
+    }
+
+    let tables = import_tables(&sp, doc, &labels)?;
+    let shim_policies = import_shim_policies(&sp, doc, &labels)?;
+
+    sp.commit()?;
+
+    load_levels(conn)?;
+    bump_generation(conn)?;
+
+    Ok(json!({
+        "labels": labels.0.len(),
+        "levels": levels.len(),
+        "tables": tables,
+        "shim_policies": shim_policies,
+    }))
+}
+
+pub fn import_policies_raw(db_ptr: usize, doc: &str, mode: &str) -> Result<Json> {
+    let doc: Json = serde_json::from_str(doc).map_err(|e| invalid(format!("invalid JSON: {e}")))?;
+    let mode = ImportMode::parse(mode)?;
+
+    let mut conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = import_policies(&mut conn, &doc, mode);
+
+    forget(conn);
+    result
+}
@@ -0,0 +1,22 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    audit::audit_actor_raw,
+    register::{SecFunction, db_ptr, sec_error},
+};
+
+/// `sec_audit_actor()`: who `sec_admin_audit` records as making this
+/// connection's changes, NULL if the context does not say
+pub struct AuditActor;
+
+impl SecFunction for AuditActor {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_audit_actor",
+            0,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_INNOCUOUS,
+            move |_| audit_actor_raw(db_ptr).map_err(|e| sec_error("audit_actor", e)),
+        )
+    }
+}
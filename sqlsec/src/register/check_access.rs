@@ -0,0 +1,36 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags, types::Value};
+
+use crate::{
+    register::{SecFunction, db_ptr, required_text, sec_error},
+    views::check_access::check_access_raw,
+};
+
+/// `sec_check_access(logical, key [, key2 ...])` explains as JSON whether the
+/// effective context can see a row, and which labels stop it
+pub struct CheckAccess;
+
+impl SecFunction for CheckAccess {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_check_access",
+            -1,
+            FunctionFlags::SQLITE_UTF8,
+            move |ctx| {
+                if ctx.len() < 2 {
+                    return Err(sec_error(
+                        "check_access",
+                        "expected a table name and at least one key value",
+                    ));
+                }
+
+                let logical = required_text(ctx, 0, "check_access", "logical")?;
+                let key: Vec<Value> = (1..ctx.len()).map(|i| ctx.get_raw(i).into()).collect();
+
+                check_access_raw(db_ptr, &logical, &key)
+                    .map(|doc| doc.to_string())
+                    .map_err(|e| sec_error("check_access", e))
+            },
+        )
+    }
+}
@@ -0,0 +1,35 @@
+use rusqlite::{
+    Connection,
+    Result,
+    functions::FunctionFlags,
+    types::{Value, ValueRef},
+};
+
+use crate::{
+    config::configure_raw,
+    register::{SecFunction, db_ptr, required_text, sec_error},
+};
+
+/// `sec_configure(key, value)` stores a setting in `sec_meta` and returns it
+pub struct Configure;
+
+impl SecFunction for Configure {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_configure",
+            2,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let key = required_text(ctx, 0, "configure", "key")?;
+                if ctx.get_raw(1) == ValueRef::Null {
+                    return Err(sec_error("configure", "NULL argument 2 'value'"));
+                }
+                let value = Value::from(ctx.get_raw(1));
+
+                configure_raw(db_ptr, &key, &value).map_err(|e| sec_error("configure", e))?;
+                Ok(value)
+            },
+        )
+    }
+}
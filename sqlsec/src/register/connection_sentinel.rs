@@ -0,0 +1,53 @@
+use std::{collections::HashSet, sync::LazyLock};
+
+use parking_lot::Mutex;
+use rusqlite::{Connection, Result, functions::FunctionFlags, types::Null};
+
+use crate::{
+    context::release_connection,
+    register::{SecFunction, db_ptr},
+};
+
+// Handles that currently carry a sentinel
+static SENTINELS: LazyLock<Mutex<HashSet<usize>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Detects the connection closing.
+///
+/// SQLite calls a function's `xDestroy` when the connection that owns it is
+/// closed, so dropping this no-op function's closure drops the per-connection
+/// state kept under the handle's address before the address can be reused.
+pub struct ConnectionSentinel;
+
+/// Releases the state of the connection at `db_ptr` when dropped
+struct SentinelGuard {
+    db_ptr: usize,
+}
+
+impl Drop for SentinelGuard {
+    fn drop(&mut self) {
+        SENTINELS.lock().remove(&self.db_ptr);
+        release_connection(self.db_ptr);
+    }
+}
+
+impl SecFunction for ConnectionSentinel {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        // Re-registering would destroy the old sentinel, and with it the state
+        // of a connection that is still open
+        if !SENTINELS.lock().insert(db_ptr) {
+            return Ok(());
+        }
+
+        let guard = SentinelGuard { db_ptr };
+        conn.create_scalar_function(
+            "sec_connection_sentinel",
+            0,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_INNOCUOUS,
+            move |_| {
+                let _ = &guard;
+                Ok(Null)
+            },
+        )
+    }
+}
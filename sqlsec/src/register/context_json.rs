@@ -0,0 +1,21 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    context::effective_context,
+    register::{SecFunction, db_ptr},
+};
+
+/// `sec_context_json()`: the effective context as `{"key": ["value", ...]}`
+pub struct ContextJson;
+
+impl SecFunction for ContextJson {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_context_json",
+            0,
+            FunctionFlags::SQLITE_UTF8,
+            move |_| Ok(effective_context(db_ptr).to_json().to_string()),
+        )
+    }
+}
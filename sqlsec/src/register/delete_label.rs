@@ -0,0 +1,32 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    label::delete::delete_label_raw,
+    register::{SecFunction, db_ptr, int_arg, required_int, sec_error},
+};
+
+/// `sec_delete_label(id [, scan_rows = 1])`
+pub struct DeleteLabel;
+
+impl SecFunction for DeleteLabel {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_delete_label",
+            -1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                if !(1..=2).contains(&ctx.len()) {
+                    return Err(sec_error("delete_label", "expected 1 or 2 arguments"));
+                }
+
+                let label_id = required_int(ctx, 0, "delete_label", "label_id")?;
+                let scan_rows = ctx.len() == 1 || int_arg(ctx, 1) != 0;
+
+                delete_label_raw(db_ptr, label_id, scan_rows)
+                    .map_err(|e| sec_error("delete_label", e))?;
+                Ok(1)
+            },
+        )
+    }
+}
@@ -0,0 +1,30 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    register::{SecFunction, db_ptr, required_text, sec_error},
+    views::sync_columns::drop_column_raw,
+};
+
+/// `sec_drop_column(logical, column)`: drop a column of the physical table,
+/// rebuilding the views around it; returns the changes to `sec_columns` as
+/// `sec_sync_columns` does
+pub struct DropColumn;
+
+impl SecFunction for DropColumn {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_drop_column",
+            2,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let logical = required_text(ctx, 0, "drop_column", "logical")?;
+                let column = required_text(ctx, 1, "drop_column", "column")?;
+
+                drop_column_raw(db_ptr, &logical, &column)
+                    .map(|changes| changes.to_json().to_string())
+                    .map_err(|e| sec_error("drop_column", e))
+            },
+        )
+    }
+}
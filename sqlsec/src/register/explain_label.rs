@@ -0,0 +1,43 @@
+use rusqlite::{Connection, Error, Result, functions::FunctionFlags};
+
+use crate::{
+    context::effective_context,
+    label::{evaluate::load_label_conn, levels},
+    register::{SecFunction, db_ptr, int_arg, required_int, sec_error},
+};
+
+/// `sec_explain_label(id)` returns the parsed label as JSON;
+/// `sec_explain_label(id, 1)` also evaluates it against the effective context.
+pub struct ExplainLabel;
+
+impl SecFunction for ExplainLabel {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_explain_label",
+            -1,
+            FunctionFlags::SQLITE_UTF8,
+            move |ctx| {
+                if !(1..=2).contains(&ctx.len()) {
+                    return Err(sec_error("explain_label", "expected 1 or 2 arguments"));
+                }
+
+                let label_id = required_int(ctx, 0, "explain_label", "label_id")?;
+                let with_context = ctx.len() == 2 && int_arg(ctx, 1) != 0;
+
+                let conn = unsafe { ctx.get_connection()? };
+                let label = load_label_conn(&conn, label_id).map_err(|e| match e {
+                    Error::QueryReturnedNoRows => {
+                        sec_error("explain_label", format!("label {label_id} does not exist"))
+                    }
+                    e => sec_error("explain_label", e),
+                })?;
+
+                let sec_ctx = with_context.then(|| (effective_context(db_ptr), levels(db_ptr)));
+                Ok(label
+                    .explain(sec_ctx.as_ref().map(|(ctx, levels)| (ctx, &**levels)))
+                    .to_string())
+            },
+        )
+    }
+}
@@ -0,0 +1,26 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    policies::export_policies_raw,
+    register::{SecFunction, db_ptr, sec_error},
+};
+
+/// `sec_export_policies()`: labels, levels, tables and columns as one JSON
+/// document for `sec_import_policies`
+pub struct ExportPolicies;
+
+impl SecFunction for ExportPolicies {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_export_policies",
+            0,
+            FunctionFlags::SQLITE_UTF8,
+            move |_| {
+                export_policies_raw(db_ptr)
+                    .map(|doc| doc.to_string())
+                    .map_err(|e| sec_error("export_policies", e))
+            },
+        )
+    }
+}
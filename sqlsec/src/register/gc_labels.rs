@@ -0,0 +1,28 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    label::delete::gc_labels_raw,
+    register::{SecFunction, db_ptr, int_arg, sec_error},
+};
+
+/// `sec_gc_labels([scan_rows = 1])`
+pub struct GcLabels;
+
+impl SecFunction for GcLabels {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_gc_labels",
+            -1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                if ctx.len() > 1 {
+                    return Err(sec_error("gc_labels", "expected 0 or 1 arguments"));
+                }
+                let scan_rows = ctx.is_empty() || int_arg(ctx, 0) != 0;
+
+                gc_labels_raw(db_ptr, scan_rows).map_err(|e| sec_error("gc_labels", e))
+            },
+        )
+    }
+}
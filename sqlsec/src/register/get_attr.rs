@@ -0,0 +1,29 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    context::effective_context,
+    register::{SecFunction, db_ptr, required_text, sec_error},
+};
+
+/// `sec_get_attr(key)`: the single value for `key` in the effective context,
+/// NULL if unset
+pub struct GetAttr;
+
+impl SecFunction for GetAttr {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function("sec_get_attr", 1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+            let key = required_text(ctx, 0, "get_attr", "key")?;
+            let sec_ctx = effective_context(db_ptr);
+
+            match sec_ctx.get_attrs(&key).as_slice() {
+                [] => Ok(None),
+                [value] => Ok(Some(value.to_string())),
+                values => Err(sec_error(
+                    "get_attr",
+                    format!("'{key}' has {} values, use sec_get_attrs", values.len()),
+                )),
+            }
+        })
+    }
+}
@@ -0,0 +1,24 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    context::effective_context,
+    register::{SecFunction, db_ptr, required_text},
+};
+
+/// `sec_get_attrs(key)`: every value for `key` in the effective context as a
+/// sorted JSON array, `[]` if unset
+pub struct GetAttrs;
+
+impl SecFunction for GetAttrs {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function("sec_get_attrs", 1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+            let key = required_text(ctx, 0, "get_attrs", "key")?;
+            let sec_ctx = effective_context(db_ptr);
+            let mut values = sec_ctx.get_attrs(&key);
+            values.sort();
+
+            Ok(serde_json::to_string(&values).unwrap())
+        })
+    }
+}
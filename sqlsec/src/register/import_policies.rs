@@ -0,0 +1,29 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    policies::import_policies_raw,
+    register::{SecFunction, db_ptr, required_text, sec_error},
+};
+
+/// `sec_import_policies(json, mode)` applies a `sec_export_policies` document
+/// with mode 'replace' or 'merge', returning the counts imported as JSON
+pub struct ImportPolicies;
+
+impl SecFunction for ImportPolicies {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_import_policies",
+            2,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let doc = required_text(ctx, 0, "import_policies", "json")?;
+                let mode = required_text(ctx, 1, "import_policies", "mode")?;
+
+                import_policies_raw(db_ptr, &doc, &mode)
+                    .map(|counts| counts.to_string())
+                    .map_err(|e| sec_error("import_policies", e))
+            },
+        )
+    }
+}
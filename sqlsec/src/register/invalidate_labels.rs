@@ -0,0 +1,40 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    label::{evict_label, evict_labels},
+    register::{SecFunction, db_ptr, required_int, sec_error},
+    views::bump_generation::bump_generation_raw,
+};
+
+/// `sec_invalidate_labels()` drops every cached label for this connection,
+/// `sec_invalidate_labels(id)` drops just one.
+pub struct InvalidateLabels;
+
+impl SecFunction for InvalidateLabels {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_invalidate_labels",
+            -1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                if ctx.len() > 1 {
+                    return Err(sec_error("invalidate_labels", "expected 0 or 1 arguments"));
+                }
+
+                if ctx.len() == 1 {
+                    evict_label(
+                        db_ptr,
+                        required_int(ctx, 0, "invalidate_labels", "label_id")?,
+                    );
+                } else {
+                    evict_labels(db_ptr);
+                }
+
+                // Table and column labels decide the shape of the views
+                bump_generation_raw(db_ptr).map_err(|e| sec_error("invalidate_labels", e))?;
+                Ok(1)
+            },
+        )
+    }
+}
@@ -0,0 +1,30 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    label::{evaluate::load_label_conn, levels},
+    register::{SecFunction, db_ptr, required_int, sec_error},
+};
+
+/// `sec_label_dominates(a, b)`: 1 if every context admitted by label `a` is
+/// also admitted by label `b`
+pub struct LabelDominates;
+
+impl SecFunction for LabelDominates {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_label_dominates",
+            2,
+            FunctionFlags::SQLITE_UTF8,
+            move |ctx| {
+                let a = required_int(ctx, 0, "label_dominates", "label_id_a")?;
+                let b = required_int(ctx, 1, "label_dominates", "label_id_b")?;
+
+                let conn = unsafe { ctx.get_connection()? };
+                let a = load_label_conn(&conn, a).map_err(|e| sec_error("label_dominates", e))?;
+                let b = load_label_conn(&conn, b).map_err(|e| sec_error("label_dominates", e))?;
+                Ok(a.dominates(&b, &levels(db_ptr)))
+            },
+        )
+    }
+}
@@ -1,52 +1,237 @@
 pub mod assert_fresh;
+pub mod audit_actor;
+pub mod check_access;
 pub mod clear_context;
+pub mod configure;
+pub mod connection_sentinel;
+pub mod context_json;
 pub mod define_label;
 pub mod define_level;
+pub mod delete_label;
+pub mod drop_column;
+pub mod explain_label;
+pub mod export_policies;
+pub mod gc_labels;
+pub mod get_attr;
+pub mod get_attrs;
+pub mod import_policies;
+pub mod invalidate_labels;
+pub mod label_dominates;
 pub mod label_visible;
 pub mod pop_context;
+pub mod purge_admin_audit;
+pub mod purge_sessions;
 pub mod push_context;
 pub mod refresh_views;
 pub mod register_table;
+pub mod remove_attr;
+pub mod restore_context;
+pub mod restore_context_snapshot;
+pub mod secure_existing_table;
 pub mod set_attr;
+pub mod set_column_mask;
+pub mod set_context_json;
+pub mod snapshot_context;
+pub mod sync_columns;
 
-use std::{ffi::CString, fmt::Display};
+use std::{
+    ffi::{CStr, c_char},
+    fmt::Display,
+};
 
-use rusqlite::ffi::{sqlite3, sqlite3_context, sqlite3_result_error};
+use rusqlite::{
+    Connection,
+    Error,
+    Result,
+    ffi::{
+        SQLITE_BLOB,
+        SQLITE_FLOAT,
+        SQLITE_INTEGER,
+        SQLITE_TEXT,
+        sqlite3_value,
+        sqlite3_value_blob,
+        sqlite3_value_bytes,
+        sqlite3_value_double,
+        sqlite3_value_int64,
+        sqlite3_value_text,
+        sqlite3_value_type,
+    },
+    functions::Context,
+    types::{Value, ValueRef},
+};
 
 use crate::register::{
     assert_fresh::AssertFresh,
+    audit_actor::AuditActor,
+    check_access::CheckAccess,
     clear_context::ClearContext,
+    configure::Configure,
+    connection_sentinel::ConnectionSentinel,
+    context_json::ContextJson,
     define_label::DefineLabel,
     define_level::DefineLevel,
+    delete_label::DeleteLabel,
+    drop_column::DropColumn,
+    explain_label::ExplainLabel,
+    export_policies::ExportPolicies,
+    gc_labels::GcLabels,
+    get_attr::GetAttr,
+    get_attrs::GetAttrs,
+    import_policies::ImportPolicies,
+    invalidate_labels::InvalidateLabels,
+    label_dominates::LabelDominates,
     label_visible::LabelVisible,
     pop_context::PopContext,
+    purge_admin_audit::PurgeAdminAudit,
+    purge_sessions::PurgeSessions,
     push_context::PushContext,
     refresh_views::RefreshViews,
     register_table::RegisterTable,
+    remove_attr::RemoveAttr,
+    restore_context::RestoreContext,
+    restore_context_snapshot::RestoreContextSnapshot,
+    secure_existing_table::SecureExistingTable,
     set_attr::SetAttr,
+    set_column_mask::SetColumnMask,
+    set_context_json::SetContextJson,
+    snapshot_context::SnapshotContext,
+    sync_columns::SyncColumns,
 };
 
-fn sqlite_error(ctx: *mut sqlite3_context, prefix: &str, e: impl Display) {
-    let msg = CString::new(format!("{prefix}: {e}")).unwrap();
+/// The error a `sec_*` function fails with, its message prefixed with the
+/// function's name
+pub(crate) fn sec_error(prefix: &str, e: impl Display) -> Error {
+    Error::UserFunctionError(format!("{prefix}: {e}").into())
+}
+
+/// The handle of `conn`, which keys the per-connection state
+pub(crate) fn db_ptr(conn: &Connection) -> usize {
+    unsafe { conn.handle() as usize }
+}
+
+/// Argument `i` as text, converted as `sqlite3_value_text` does, or `None`
+/// if it is NULL
+pub(crate) fn text_arg(ctx: &Context, i: usize) -> Option<String> {
+    match ctx.get_raw(i) {
+        ValueRef::Null => None,
+        ValueRef::Integer(v) => Some(v.to_string()),
+        ValueRef::Real(v) => Some(v.to_string()),
+        ValueRef::Text(s) | ValueRef::Blob(s) => Some(String::from_utf8_lossy(s).into_owned()),
+    }
+}
+
+/// Argument `i` as text, failing as `prefix` if it is NULL
+pub(crate) fn required_text(ctx: &Context, i: usize, prefix: &str, param: &str) -> Result<String> {
+    text_arg(ctx, i).ok_or_else(|| sec_error(prefix, format!("NULL argument {} '{param}'", i + 1)))
+}
+
+/// Argument `i` as an integer, converted as `sqlite3_value_int64` does: NULL
+/// and text that does not start with a number are 0
+pub(crate) fn int_arg(ctx: &Context, i: usize) -> i64 {
+    match ctx.get_raw(i) {
+        ValueRef::Null => 0,
+        ValueRef::Integer(v) => v,
+        ValueRef::Real(v) => v as i64,
+        ValueRef::Text(s) | ValueRef::Blob(s) => leading_int(&String::from_utf8_lossy(s)),
+    }
+}
+
+/// Argument `i` as an integer, failing as `prefix` if it is NULL
+pub(crate) fn required_int(ctx: &Context, i: usize, prefix: &str, param: &str) -> Result<i64> {
+    match ctx.get_raw(i) {
+        ValueRef::Null => Err(sec_error(
+            prefix,
+            format!("NULL argument {} '{param}'", i + 1),
+        )),
+        _ => Ok(int_arg(ctx, i)),
+    }
+}
+
+/// The integer `text` starts with, as SQLite reads it
+fn leading_int(text: &str) -> i64 {
+    let text = text.trim_start();
+    let end = text
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && (c == '-' || c == '+'))))
+        .map_or(text.len(), |(i, _)| i);
+    text[..end].parse().unwrap_or(0)
+}
+
+/// Copy an argument out of SQLite's memory
+pub(crate) unsafe fn owned_value(value: *mut sqlite3_value) -> Value {
     unsafe {
-        sqlite3_result_error(ctx, msg.as_ptr(), -1);
+        match sqlite3_value_type(value) {
+            SQLITE_INTEGER => Value::Integer(sqlite3_value_int64(value)),
+            SQLITE_FLOAT => Value::Real(sqlite3_value_double(value)),
+            SQLITE_TEXT => Value::Text(
+                CStr::from_ptr(sqlite3_value_text(value) as *const c_char)
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            SQLITE_BLOB => {
+                let len = sqlite3_value_bytes(value) as usize;
+                let ptr = sqlite3_value_blob(value) as *const u8;
+                if ptr.is_null() {
+                    Value::Blob(Vec::new())
+                } else {
+                    Value::Blob(std::slice::from_raw_parts(ptr, len).to_vec())
+                }
+            }
+            _ => Value::Null,
+        }
     }
 }
 
-trait Sqlite3FunctionV2 {
-    fn register(db: *mut sqlite3);
+/// A `sec_*` function, registered with rusqlite's `create_scalar_function`.
+///
+/// Implementations choose the function flags:
+///
+/// * `SQLITE_DIRECTONLY` for anything that changes the context, labels or
+///   registrations, so a view or trigger in an untrusted schema cannot
+///   escalate by calling it
+/// * `SQLITE_INNOCUOUS` for the functions the generated views and triggers
+///   call, so they keep working with `trusted_schema` off
+/// * neither for the remaining readers, which reveal the context
+trait SecFunction {
+    fn register(conn: &Connection) -> Result<()>;
 }
 
-/// Register all scalar functions using raw FFI
-pub(crate) fn register_functions_ffi(db: *mut sqlite3) {
-    AssertFresh::register(db);
-    ClearContext::register(db);
-    DefineLabel::register(db);
-    DefineLevel::register(db);
-    PopContext::register(db);
-    PushContext::register(db);
-    RefreshViews::register(db);
-    RegisterTable::register(db);
-    LabelVisible::register(db);
-    SetAttr::register(db);
+/// Register all scalar functions on `conn`
+pub(crate) fn register_functions(conn: &Connection) -> Result<()> {
+    AssertFresh::register(conn)?;
+    AuditActor::register(conn)?;
+    CheckAccess::register(conn)?;
+    ClearContext::register(conn)?;
+    Configure::register(conn)?;
+    ConnectionSentinel::register(conn)?;
+    ContextJson::register(conn)?;
+    DefineLabel::register(conn)?;
+    DefineLevel::register(conn)?;
+    DeleteLabel::register(conn)?;
+    DropColumn::register(conn)?;
+    ExplainLabel::register(conn)?;
+    ExportPolicies::register(conn)?;
+    GcLabels::register(conn)?;
+    GetAttr::register(conn)?;
+    GetAttrs::register(conn)?;
+    ImportPolicies::register(conn)?;
+    InvalidateLabels::register(conn)?;
+    PopContext::register(conn)?;
+    PurgeAdminAudit::register(conn)?;
+    PurgeSessions::register(conn)?;
+    PushContext::register(conn)?;
+    RefreshViews::register(conn)?;
+    RegisterTable::register(conn)?;
+    RemoveAttr::register(conn)?;
+    RestoreContext::register(conn)?;
+    RestoreContextSnapshot::register(conn)?;
+    LabelDominates::register(conn)?;
+    LabelVisible::register(conn)?;
+    SecureExistingTable::register(conn)?;
+    SetAttr::register(conn)?;
+    SetColumnMask::register(conn)?;
+    SetContextJson::register(conn)?;
+    SnapshotContext::register(conn)?;
+    SyncColumns::register(conn)?;
+    Ok(())
 }
@@ -1,61 +1,46 @@
-use std::ffi::c_int;
-
-use rusqlite::ffi::{
-    SQLITE_UTF8,
-    sqlite3,
-    sqlite3_context,
-    sqlite3_context_db_handle,
-    sqlite3_create_function_v2,
-    sqlite3_result_int64,
-    sqlite3_value,
-};
+use rusqlite::{Connection, Result, functions::FunctionFlags};
 
 use crate::{
-    context::{get_context_stack, set_context_stack},
-    register::{Sqlite3FunctionV2, sqlite_error},
-    views::bump_generation::bump_generation_raw,
+    context::{context_changed_raw, context_stack},
+    register::{SecFunction, db_ptr, required_text, sec_error},
 };
 
+/// `sec_pop_context()` pops the top frame; `sec_pop_context(name)` pops back to
+/// and including the most recent frame pushed with that name
 pub struct PopContext;
 
-impl Sqlite3FunctionV2 for PopContext {
-    fn register(db: *mut sqlite3) {
-        unsafe {
-            sqlite3_create_function_v2(
-                db,
-                c"sec_pop_context".as_ptr(),
-                0,
-                SQLITE_UTF8,
-                std::ptr::null_mut(),
-                Some(ffi_sec_pop_context),
-                None,
-                None,
-                None,
-            );
-        }
-    }
-}
+impl SecFunction for PopContext {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_pop_context",
+            -1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                if ctx.len() > 1 {
+                    return Err(sec_error("pop_context", "expected 0 or 1 arguments"));
+                }
 
-pub(crate) extern "C" fn ffi_sec_pop_context(
-    ctx: *mut sqlite3_context,
-    _argc: c_int,
-    _argv: *mut *mut sqlite3_value,
-) {
-    unsafe {
-        let db_ptr = sqlite3_context_db_handle(ctx) as usize;
-        let mut stack = get_context_stack(db_ptr);
+                {
+                    let frames = context_stack(db_ptr);
+                    let mut stack = frames.lock();
 
-        if stack.pop().is_none() {
-            sqlite_error(ctx, "pop_context", "cannot pop base context");
-            return;
-        }
-        set_context_stack(db_ptr, stack);
+                    if ctx.len() == 1 {
+                        let name = required_text(ctx, 0, "pop_context", "name")?;
+                        if stack.pop_named(&name).is_none() {
+                            return Err(sec_error(
+                                "pop_context",
+                                format!("no context named '{name}' on the stack"),
+                            ));
+                        }
+                    } else if stack.pop().is_none() {
+                        return Err(sec_error("pop_context", "cannot pop base context"));
+                    }
+                }
 
-        match bump_generation_raw(db_ptr) {
-            Ok(_) => sqlite3_result_int64(ctx, 1),
-            Err(e) => {
-                sqlite_error(ctx, "pop_context", e);
-            }
-        }
+                context_changed_raw(db_ptr).map_err(|e| sec_error("pop_context", e))?;
+                Ok(1)
+            },
+        )
     }
 }
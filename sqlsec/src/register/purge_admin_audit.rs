@@ -0,0 +1,27 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    audit::purge_admin_audit_raw,
+    register::{SecFunction, db_ptr, required_int, sec_error},
+};
+
+/// `sec_purge_admin_audit(older_than)` deletes `sec_admin_audit` records older
+/// than `older_than` seconds and returns the count removed
+pub struct PurgeAdminAudit;
+
+impl SecFunction for PurgeAdminAudit {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_purge_admin_audit",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let older_than = required_int(ctx, 0, "purge_admin_audit", "older_than")?;
+                purge_admin_audit_raw(db_ptr, older_than)
+                    .map(|removed| removed as i64)
+                    .map_err(|e| sec_error("purge_admin_audit", e))
+            },
+        )
+    }
+}
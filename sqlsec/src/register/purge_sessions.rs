@@ -0,0 +1,27 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    context::session::purge_sessions_raw,
+    register::{SecFunction, db_ptr, required_int, sec_error},
+};
+
+/// `sec_purge_sessions(older_than)` deletes sessions idle for more than
+/// `older_than` seconds, or past their expiry, and returns the count removed
+pub struct PurgeSessions;
+
+impl SecFunction for PurgeSessions {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_purge_sessions",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let older_than = required_int(ctx, 0, "purge_sessions", "older_than")?;
+                purge_sessions_raw(db_ptr, older_than)
+                    .map(|removed| removed as i64)
+                    .map_err(|e| sec_error("purge_sessions", e))
+            },
+        )
+    }
+}
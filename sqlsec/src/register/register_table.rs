@@ -1,100 +1,45 @@
-use std::ffi::{CStr, c_char, c_int};
-
-use rusqlite::ffi::{
-    SQLITE_NULL,
-    SQLITE_UTF8,
-    sqlite3,
-    sqlite3_context,
-    sqlite3_context_db_handle,
-    sqlite3_create_function_v2,
-    sqlite3_result_int,
-    sqlite3_value,
-    sqlite3_value_int64,
-    sqlite3_value_text,
-    sqlite3_value_type,
-};
+use rusqlite::{Connection, Result, functions::FunctionFlags, types::ValueRef};
 
 use crate::{
-    register::{Sqlite3FunctionV2, sqlite_error},
+    register::{SecFunction, db_ptr, int_arg, required_text, sec_error},
     views::register_table::register_table_raw,
 };
 
+/// `sec_register_table(logical, physical, row_label_col, table_label_id,
+/// insert_label_id [, delete_label_id])`
 pub struct RegisterTable;
 
-impl Sqlite3FunctionV2 for RegisterTable {
-    fn register(db: *mut sqlite3) {
-        unsafe {
-            sqlite3_create_function_v2(
-                db,
-                c"sec_register_table".as_ptr(),
-                5,
-                SQLITE_UTF8,
-                std::ptr::null_mut(),
-                Some(ffi_sec_register_table),
-                None,
-                None,
-                None,
-            );
-        }
-    }
-}
-
-pub(crate) extern "C" fn ffi_sec_register_table(
-    ctx: *mut sqlite3_context,
-    argc: c_int,
-    argv: *mut *mut sqlite3_value,
-) {
-    unsafe {
-        if argc != 5 {
-            sqlite_error(ctx, "register_table", "expected 5 arguments");
-            return;
-        }
-
-        let logical_ptr = sqlite3_value_text(*argv);
-        let physical_ptr = sqlite3_value_text(*argv.add(1));
-        let row_col_ptr = sqlite3_value_text(*argv.add(2));
-
-        let table_label_id = if sqlite3_value_type(*argv.add(3)) == SQLITE_NULL {
-            None
-        } else {
-            Some(sqlite3_value_int64(*argv.add(3)))
-        };
-        let insert_label_id = if sqlite3_value_type(*argv.add(4)) == SQLITE_NULL {
-            None
-        } else {
-            Some(sqlite3_value_int64(*argv.add(4)))
-        };
-
-        if logical_ptr.is_null() {
-            sqlite_error(ctx, "register_table", "NULL argument 1 'logical'");
-            return;
-        }
-        if physical_ptr.is_null() {
-            sqlite_error(ctx, "register_table", "NULL argument 2 ''physical'");
-            return;
-        }
-        if row_col_ptr.is_null() {
-            sqlite_error(ctx, "register_table", "NULL argument 3 'row_label_col'");
-            return;
-        }
-
-        let logical = CStr::from_ptr(logical_ptr as *const c_char).to_string_lossy();
-        let physical = CStr::from_ptr(physical_ptr as *const c_char).to_string_lossy();
-        let row_col = CStr::from_ptr(row_col_ptr as *const c_char).to_string_lossy();
-
-        let db_ptr = sqlite3_context_db_handle(ctx) as usize;
-        match register_table_raw(
-            db_ptr,
-            &logical,
-            &physical,
-            &row_col,
-            table_label_id,
-            insert_label_id,
-        ) {
-            Ok(_) => sqlite3_result_int(ctx, 1),
-            Err(e) => {
-                sqlite_error(ctx, "register_table", e);
-            }
-        }
+impl SecFunction for RegisterTable {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_register_table",
+            -1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                if !(5..=6).contains(&ctx.len()) {
+                    return Err(sec_error("register_table", "expected 5 or 6 arguments"));
+                }
+
+                let label_arg = |i: usize| {
+                    (i < ctx.len() && ctx.get_raw(i) != ValueRef::Null).then(|| int_arg(ctx, i))
+                };
+                let logical = required_text(ctx, 0, "register_table", "logical")?;
+                let physical = required_text(ctx, 1, "register_table", "'physical")?;
+                let row_col = required_text(ctx, 2, "register_table", "row_label_col")?;
+
+                register_table_raw(
+                    db_ptr,
+                    &logical,
+                    &physical,
+                    &row_col,
+                    label_arg(3),
+                    label_arg(4),
+                    label_arg(5),
+                )
+                .map_err(|e| sec_error("register_table", e))?;
+                Ok(1)
+            },
+        )
     }
 }
@@ -0,0 +1,45 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    context::{context_changed_raw, context_stack},
+    register::{SecFunction, db_ptr, required_text, sec_error},
+};
+
+/// `sec_remove_attr(key [, value])` drops a key (or one of its values) from
+/// the current frame and returns the number of values removed
+pub struct RemoveAttr;
+
+impl SecFunction for RemoveAttr {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_remove_attr",
+            -1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                if !(1..=2).contains(&ctx.len()) {
+                    return Err(sec_error("remove_attr", "expected 1 or 2 arguments"));
+                }
+
+                let key = required_text(ctx, 0, "remove_attr", "key")?;
+                let val = if ctx.len() == 2 {
+                    Some(required_text(ctx, 1, "remove_attr", "value")?)
+                } else {
+                    None
+                };
+
+                let removed = {
+                    let frames = context_stack(db_ptr);
+                    let mut stack = frames.lock();
+                    match &val {
+                        Some(val) => stack.current_mut().remove_value(&key, val),
+                        None => stack.current_mut().clear_attr(&key),
+                    }
+                };
+
+                context_changed_raw(db_ptr).map_err(|e| sec_error("remove_attr", e))?;
+                Ok(removed as i64)
+            },
+        )
+    }
+}
@@ -0,0 +1,36 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    config::auto_refresh,
+    context::{context_changed_raw, session::restore_context_raw},
+    register::{SecFunction, db_ptr, required_text, sec_error},
+    views::refresh_views::refresh_views_raw,
+};
+
+/// `sec_restore_context(session_id)` loads the session's saved context,
+/// refreshes the views and returns the number of attributes restored
+pub struct RestoreContext;
+
+impl SecFunction for RestoreContext {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_restore_context",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let session_id = required_text(ctx, 0, "restore_context", "session_id")?;
+
+                restore_context_raw(db_ptr, &session_id)
+                    .and_then(|count| {
+                        context_changed_raw(db_ptr)?;
+                        if !auto_refresh(db_ptr) {
+                            refresh_views_raw(db_ptr)?;
+                        }
+                        Ok(count as i64)
+                    })
+                    .map_err(|e| sec_error("restore_context", e))
+            },
+        )
+    }
+}
@@ -0,0 +1,33 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    context::{context_changed_raw, context_stack, ctx_stack::ContextStack},
+    register::{SecFunction, db_ptr, required_text, sec_error},
+};
+
+/// `sec_restore_context_snapshot(token)` replaces the whole context stack with
+/// one taken by `sec_snapshot_context` and returns the number of frames
+pub struct RestoreContextSnapshot;
+
+impl SecFunction for RestoreContextSnapshot {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_restore_context_snapshot",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let token = required_text(ctx, 0, "restore_context_snapshot", "token")?;
+
+                let restored = ContextStack::from_snapshot(&token)
+                    .map_err(|e| sec_error("restore_context_snapshot", e))?;
+                let frames = restored.frames().len() as i64;
+                *context_stack(db_ptr).lock() = restored;
+
+                context_changed_raw(db_ptr)
+                    .map_err(|e| sec_error("restore_context_snapshot", e))?;
+                Ok(frames)
+            },
+        )
+    }
+}
@@ -0,0 +1,28 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    register::{SecFunction, db_ptr, required_text, sec_error},
+    views::secure_existing_table::secure_existing_table_raw,
+};
+
+/// `sec_secure_existing_table(table, default_label_expr)` moves a plain table
+/// behind a secured view of the same name, returning the default label's id
+pub struct SecureExistingTable;
+
+impl SecFunction for SecureExistingTable {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_secure_existing_table",
+            2,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let table = required_text(ctx, 0, "secure_existing_table", "table")?;
+                let expr = required_text(ctx, 1, "secure_existing_table", "default_label_expr")?;
+
+                secure_existing_table_raw(db_ptr, &table, &expr)
+                    .map_err(|e| sec_error("secure_existing_table", e))
+            },
+        )
+    }
+}
@@ -0,0 +1,30 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    register::{SecFunction, db_ptr, required_text, sec_error, text_arg},
+    views::mask::set_column_mask_raw,
+};
+
+/// `sec_set_column_mask(logical, column, expr)` shows `expr` in place of the
+/// column to contexts that cannot read it; a NULL `expr` removes the mask
+pub struct SetColumnMask;
+
+impl SecFunction for SetColumnMask {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_set_column_mask",
+            3,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let logical = required_text(ctx, 0, "set_column_mask", "logical")?;
+                let column = required_text(ctx, 1, "set_column_mask", "column")?;
+                let expr = text_arg(ctx, 2);
+
+                set_column_mask_raw(db_ptr, &logical, &column, expr.as_deref())
+                    .map_err(|e| sec_error("set_column_mask", e))?;
+                Ok(1)
+            },
+        )
+    }
+}
@@ -0,0 +1,46 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    context::{context_changed_raw, context_stack, sec_ctx::SecurityContext},
+    register::{SecFunction, db_ptr, int_arg, required_text, sec_error},
+};
+
+/// `sec_set_context_json(json [, merge = 0])` replaces the current frame (or
+/// merges into it) and returns the number of attributes set
+pub struct SetContextJson;
+
+impl SecFunction for SetContextJson {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_set_context_json",
+            -1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                if !(1..=2).contains(&ctx.len()) {
+                    return Err(sec_error("set_context_json", "expected 1 or 2 arguments"));
+                }
+
+                let text = required_text(ctx, 0, "set_context_json", "json")?;
+                let merge = ctx.len() == 2 && int_arg(ctx, 1) != 0;
+
+                let parsed = SecurityContext::from_json(&text)
+                    .map_err(|e| sec_error("set_context_json", e))?;
+                let count = parsed.attrs.len() as i64;
+
+                {
+                    let frames = context_stack(db_ptr);
+                    let mut stack = frames.lock();
+                    if merge {
+                        stack.current_mut().merge(&parsed);
+                    } else {
+                        *stack.current_mut() = parsed;
+                    }
+                }
+
+                context_changed_raw(db_ptr).map_err(|e| sec_error("set_context_json", e))?;
+                Ok(count)
+            },
+        )
+    }
+}
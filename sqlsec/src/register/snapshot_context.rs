@@ -0,0 +1,22 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    context::context_stack,
+    register::{SecFunction, db_ptr},
+};
+
+/// `sec_snapshot_context()`: a token holding the whole context stack, for
+/// `sec_restore_context_snapshot`
+pub struct SnapshotContext;
+
+impl SecFunction for SnapshotContext {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_snapshot_context",
+            0,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |_| Ok(context_stack(db_ptr).lock().snapshot().to_string()),
+        )
+    }
+}
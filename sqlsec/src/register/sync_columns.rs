@@ -0,0 +1,28 @@
+use rusqlite::{Connection, Result, functions::FunctionFlags};
+
+use crate::{
+    register::{SecFunction, db_ptr, required_text, sec_error},
+    views::sync_columns::sync_columns_raw,
+};
+
+/// `sec_sync_columns(logical)`: `{"added":[..],"removed":[..]}`, the columns
+/// of the physical table newly added to or dropped from `sec_columns`
+pub struct SyncColumns;
+
+impl SecFunction for SyncColumns {
+    fn register(conn: &Connection) -> Result<()> {
+        let db_ptr = db_ptr(conn);
+        conn.create_scalar_function(
+            "sec_sync_columns",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| {
+                let logical = required_text(ctx, 0, "sync_columns", "logical")?;
+
+                sync_columns_raw(db_ptr, &logical)
+                    .map(|changes| changes.to_json().to_string())
+                    .map_err(|e| sec_error("sync_columns", e))
+            },
+        )
+    }
+}
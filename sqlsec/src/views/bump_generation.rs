@@ -2,6 +2,8 @@ use std::mem::forget;
 
 use rusqlite::{Connection, Result};
 
+use crate::label::forget_visibility;
+
 pub fn bump_generation(conn: &mut Connection) -> Result<()> {
     conn.execute(
         r#"
@@ -11,6 +13,7 @@ pub fn bump_generation(conn: &mut Connection) -> Result<()> {
         "#,
         [],
     )?;
+    forget_visibility(unsafe { conn.handle() as usize });
     Ok(())
 }
 
@@ -0,0 +1,159 @@
+use std::mem::forget;
+
+use rusqlite::{Connection, OptionalExtension, Result, params_from_iter, types::Value};
+use serde_json::{Value as Json, json};
+
+use crate::{
+    context::{effective_context, sec_ctx::SecurityContext},
+    label::{
+        evaluate::{load_label_conn, load_levels},
+        levels,
+    },
+    views::{PhysicalTable, get_primary_key_columns, get_sec_columns, invalid, rowid_alias},
+};
+
+fn key_json(value: &Value) -> Json {
+    match value {
+        Value::Null => Json::Null,
+        Value::Integer(i) => json!(i),
+        Value::Real(f) => json!(f),
+        Value::Text(s) => json!(s),
+        Value::Blob(b) => json!(b.iter().map(|b| format!("{b:02x}")).collect::<String>()),
+    }
+}
+
+/// A label evaluated against `ctx`: its id, canonical expression and the
+/// annotated clauses of [`crate::label::Label::explain`]. No label is visible.
+fn label_verdict(conn: &Connection, label_id: Option<i64>, ctx: &SecurityContext) -> Result<Json> {
+    let Some(id) = label_id else {
+        return Ok(json!({ "id": null, "visible": true }));
+    };
+
+    let label = load_label_conn(conn, id)?;
+    let expr: String = conn.query_row("SELECT expr FROM sec_labels WHERE id = ?1", [id], |r| {
+        r.get(0)
+    })?;
+
+    let levels = levels(unsafe { conn.handle() as usize });
+    let mut doc = label.explain(Some((ctx, &levels)));
+    doc["id"] = json!(id);
+    doc["expr"] = json!(expr);
+    Ok(doc)
+}
+
+/// Explain whether `ctx` can see the row of `logical` with primary key `key`,
+/// as JSON: the table and row label verdicts, which of their clauses failed,
+/// and whether each registered column is readable.
+///
+/// `key` holds one value per PRIMARY KEY column, in key order, or the rowid
+/// for tables without one.
+pub fn check_access(
+    conn: &Connection,
+    ctx: &SecurityContext,
+    logical: &str,
+    key: &[Value],
+) -> Result<Json> {
+    let (physical, row_label_col, table_label_id) = conn
+        .query_row(
+            r#"
+            SELECT physical_schema, physical_name, row_label_col, table_label_id
+            FROM sec_tables WHERE logical_name = ?1
+            "#,
+            [logical],
+            |r| {
+                Ok((
+                    PhysicalTable {
+                        schema: r.get(0)?,
+                        name: r.get(1)?,
+                    },
+                    r.get::<_, String>(2)?,
+                    r.get::<_, Option<i64>>(3)?,
+                ))
+            },
+        )
+        .optional()?
+        .ok_or_else(|| invalid(format!("table '{logical}' is not registered")))?;
+
+    let key_cols = match rowid_alias(conn, &physical)? {
+        Some(rowid) => vec![rowid.to_string()],
+        None => get_primary_key_columns(conn, &physical)?,
+    };
+    if key.len() != key_cols.len() {
+        return Err(invalid(format!(
+            "table '{logical}' is keyed by ({}): expected {} key value(s), got {}",
+            key_cols.join(", "),
+            key_cols.len(),
+            key.len()
+        )));
+    }
+
+    let key_doc: serde_json::Map<String, Json> = key_cols
+        .iter()
+        .zip(key)
+        .map(|(col, value)| (col.clone(), key_json(value)))
+        .collect();
+
+    let key_where = key_cols
+        .iter()
+        .enumerate()
+        .map(|(i, col)| format!("\"{col}\" = ?{}", i + 1))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let row_label_id: Option<i64> = conn
+        .query_row(
+            &format!(
+                "SELECT \"{row_label_col}\" FROM {} WHERE {key_where}",
+                physical.quoted()
+            ),
+            params_from_iter(key),
+            |r| r.get(0),
+        )
+        .optional()?
+        .ok_or_else(|| {
+            invalid(format!(
+                "no row in '{logical}' with key {}",
+                Json::Object(key_doc.clone())
+            ))
+        })?;
+
+    load_levels(conn)?;
+
+    let table = label_verdict(conn, table_label_id, ctx)?;
+    let row = label_verdict(conn, row_label_id, ctx)?;
+
+    let columns = get_sec_columns(conn, logical)?
+        .iter()
+        .map(|col| {
+            let read = label_verdict(conn, col.read_label_id, ctx)?;
+            let readable = read["visible"] == json!(true);
+            Ok(json!({
+                "column": col.column_name,
+                "read_label_id": col.read_label_id,
+                "read_label": read.get("expr"),
+                "readable": readable,
+                "masked": !readable && col.mask_expr.is_some(),
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let visible = table["visible"] == json!(true) && row["visible"] == json!(true);
+
+    Ok(json!({
+        "table": logical,
+        "key": key_doc,
+        "visible": visible,
+        "table_label": table,
+        "row_label": row,
+        "columns": columns,
+    }))
+}
+
+pub fn check_access_raw(db_ptr: usize, logical: &str, key: &[Value]) -> Result<Json> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let ctx = effective_context(db_ptr);
+    let result = check_access(&conn, &ctx, logical, key);
+
+    forget(conn);
+    result
+}
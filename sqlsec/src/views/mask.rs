@@ -0,0 +1,187 @@
+use std::mem::forget;
+
+use rusqlite::{Connection, OptionalExtension, Result};
+
+use crate::views::{PhysicalTable, SecColumn, get_sec_columns, invalid};
+
+/// Identifiers an SQL expression mentions, lowercased. String literals are
+/// skipped; quoted identifiers (`"x"`, `` `x` ``, `[x]`) are unquoted.
+///
+/// Function names and keywords are included too, so matching the result
+/// against column names errs on the side of finding a reference.
+pub fn referenced_identifiers(expr: &str) -> Vec<String> {
+    let mut idents = Vec::new();
+    let mut chars = expr.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // '' is an escaped quote inside the literal
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+            }
+            '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                let mut ident = String::new();
+                while let Some(c) = chars.next() {
+                    if c == close {
+                        if close != ']' && chars.next_if_eq(&close).is_some() {
+                            ident.push(close);
+                            continue;
+                        }
+                        break;
+                    }
+                    ident.push(c);
+                }
+                idents.push(ident.to_lowercase());
+            }
+            c if c.is_ascii_digit() => {
+                // Skip numeric literals, including suffixes such as 1e5 or 0x1F
+                while chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || *c == '.')
+                    .is_some()
+                {}
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_' || *c == '$')
+                {
+                    ident.push(c);
+                }
+                idents.push(ident.to_lowercase());
+            }
+            _ => {}
+        }
+    }
+
+    idents
+}
+
+/// Columns other than `column` that `expr` reads
+pub(crate) fn other_columns_referenced<'a>(
+    expr: &str,
+    column: &str,
+    columns: &'a [SecColumn],
+) -> impl Iterator<Item = &'a SecColumn> {
+    let idents = referenced_identifiers(expr);
+    columns.iter().filter(move |c| {
+        !c.column_name.eq_ignore_ascii_case(column)
+            && idents.contains(&c.column_name.to_lowercase())
+    })
+}
+
+/// Set (or with `None`, clear) the expression shown in place of a column to
+/// contexts that fail its read label.
+///
+/// The expression must be valid against the physical table and may read the
+/// masked column itself, but no other column with a read label: the mask
+/// would show it to contexts the label hides it from.
+pub fn set_column_mask(
+    conn: &Connection,
+    logical: &str,
+    column: &str,
+    expr: Option<&str>,
+) -> Result<()> {
+    let physical = conn
+        .query_row(
+            "SELECT physical_schema, physical_name FROM sec_tables WHERE logical_name = ?1",
+            [logical],
+            |r| {
+                Ok(PhysicalTable {
+                    schema: r.get(0)?,
+                    name: r.get(1)?,
+                })
+            },
+        )
+        .optional()?
+        .ok_or_else(|| invalid(format!("table '{logical}' is not registered")))?;
+
+    let columns = get_sec_columns(conn, logical)?;
+    let Some(target) = columns
+        .iter()
+        .find(|c| c.column_name.eq_ignore_ascii_case(column))
+    else {
+        return Err(invalid(format!(
+            "column '{column}' does not exist in '{logical}'"
+        )));
+    };
+
+    if let Some(expr) = expr {
+        conn.prepare(&format!(
+            "SELECT ({expr}\n) FROM {} LIMIT 0",
+            physical.quoted()
+        ))
+        .map_err(|e| invalid(format!("invalid mask expression: {e}")))?;
+
+        let protected: Vec<&str> = other_columns_referenced(expr, &target.column_name, &columns)
+            .filter(|c| c.read_label_id.is_some())
+            .map(|c| c.column_name.as_str())
+            .collect();
+        if !protected.is_empty() {
+            return Err(invalid(format!(
+                "mask expression reads protected column(s): {}",
+                protected.join(", ")
+            )));
+        }
+    }
+
+    conn.execute(
+        r#"
+        UPDATE sec_columns SET mask_expr = ?1
+        WHERE logical_table = ?2 AND column_name = ?3
+        "#,
+        rusqlite::params![expr, logical, target.column_name],
+    )?;
+    Ok(())
+}
+
+pub fn set_column_mask_raw(
+    db_ptr: usize,
+    logical: &str,
+    column: &str,
+    expr: Option<&str>,
+) -> Result<()> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = set_column_mask(&conn, logical, column, expr);
+
+    forget(conn);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifiers_skip_string_literals() {
+        assert_eq!(
+            referenced_identifiers("'***-**-' || substr(ssn, -4)"),
+            vec!["substr", "ssn"]
+        );
+        assert_eq!(
+            referenced_identifiers("'it''s salary' || name"),
+            vec!["name"]
+        );
+        assert!(referenced_identifiers("'<redacted>'").is_empty());
+    }
+
+    #[test]
+    fn identifiers_unquote() {
+        assert_eq!(
+            referenced_identifiers(r#""Email" || `a``b` || [dept name]"#),
+            vec!["email", "a`b", "dept name"]
+        );
+    }
+
+    #[test]
+    fn identifiers_skip_numbers() {
+        assert_eq!(
+            referenced_identifiers("(salary / 10000) * 1e4 + 0x1F"),
+            vec!["salary"]
+        );
+    }
+}
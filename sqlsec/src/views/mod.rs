@@ -1,19 +1,105 @@
 pub mod bump_generation;
+pub mod check_access;
+pub mod mask;
 pub mod refresh_views;
 pub mod register_table;
+pub mod secure_existing_table;
+pub mod sync_columns;
+pub mod visible_rowids;
 pub mod write_triggers;
 
-use std::io::ErrorKind;
+use std::{fmt, io::ErrorKind};
 
 use rusqlite::{Connection, Error, Result};
 
+/// A physical table, in `main` or an ATTACHed database
+#[derive(Debug, Clone)]
+pub struct PhysicalTable {
+    pub schema: String,
+    pub name: String,
+}
+
+impl PhysicalTable {
+    /// Split `schema.table` when `schema` names an attached database;
+    /// anything else is a table in `main`
+    pub fn resolve(conn: &Connection, physical: &str) -> Result<Self> {
+        if let Some((schema, name)) = physical.split_once('.') {
+            let attached: bool = conn.query_row(
+                "SELECT COUNT(*) FROM pragma_database_list WHERE name = ?1 COLLATE NOCASE",
+                [schema],
+                |r| r.get(0),
+            )?;
+            if attached {
+                return Ok(Self {
+                    schema: schema.to_string(),
+                    name: name.to_string(),
+                });
+            }
+        }
+        Ok(Self {
+            schema: "main".to_string(),
+            name: physical.to_string(),
+        })
+    }
+
+    /// `"schema"."table"`, for generated SQL
+    pub fn quoted(&self) -> String {
+        format!("{}.{}", quote_ident(&self.schema), quote_ident(&self.name))
+    }
+
+    /// The table an unqualified reference to this one's name finds instead,
+    /// if any. SQLite searches `temp`, then `main`, then attached databases
+    /// in the order they were attached.
+    pub fn shadowed_by(&self, conn: &Connection) -> Result<Option<PhysicalTable>> {
+        let schemas = conn
+            .prepare(
+                r#"
+                SELECT name FROM pragma_database_list
+                ORDER BY CASE name WHEN 'temp' THEN 0 WHEN 'main' THEN 1 ELSE 2 END, seq
+                "#,
+            )?
+            .query_map([], |r| r.get::<_, String>(0))?
+            .collect::<Result<Vec<_>>>()?;
+
+        for schema in schemas {
+            let found: bool = conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM \"{schema}\".sqlite_master \
+                     WHERE type = 'table' AND name = ?1 COLLATE NOCASE"
+                ),
+                [&self.name],
+                |r| r.get(0),
+            )?;
+            if found {
+                return Ok((!schema.eq_ignore_ascii_case(&self.schema)).then(|| PhysicalTable {
+                    schema,
+                    name: self.name.clone(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl fmt::Display for PhysicalTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.schema.eq_ignore_ascii_case("main") {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}.{}", self.schema, self.name)
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SecTable {
     logical_name: String,
-    physical_name: String,
+    physical: PhysicalTable,
     row_label_col: String,
     table_label_id: Option<i64>,
     insert_label_id: Option<i64>,
+    delete_label_id: Option<i64>,
+    materialize_labels: bool,
 }
 
 #[derive(Debug)]
@@ -21,25 +107,33 @@ pub struct SecColumn {
     column_name: String,
     read_label_id: Option<i64>,
     update_label_id: Option<i64>,
+    mask_expr: Option<String>,
+}
+
+fn table_info(table: &PhysicalTable) -> String {
+    format!("PRAGMA \"{}\".table_info(\"{}\")", table.schema, table.name)
+}
+
+/// `name` as a double-quoted SQL identifier, any `"` in it doubled
+pub fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
 }
 
-fn get_physical_columns(conn: &Connection, table: &str) -> Result<Vec<String>> {
-    let mut stmt = conn.prepare(&format!("PRAGMA table_info(\"{}\")", table))?;
+fn get_physical_columns(conn: &Connection, table: &PhysicalTable) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&table_info(table))?;
     let cols = stmt
         .query_map([], |row| row.get::<_, String>(1))?
         .collect::<Result<Vec<_>>>()?;
 
     if cols.is_empty() {
-        return Err(invalid(format!(
-            "table '{table}' does not exist",
-        )));
+        return Err(invalid(format!("table '{table}' does not exist")));
     }
 
     Ok(cols)
 }
 
-fn get_primary_key_columns(conn: &Connection, table: &str) -> Result<Vec<String>> {
-    let mut stmt = conn.prepare(&format!("PRAGMA table_info(\"{}\")", table))?;
+fn get_primary_key_columns(conn: &Connection, table: &PhysicalTable) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&table_info(table))?;
 
     let mut pk_cols: Vec<(i64, String)> = Vec::new();
 
@@ -62,6 +156,29 @@ fn get_primary_key_columns(conn: &Connection, table: &str) -> Result<Vec<String>
     Ok(pk_cols.into_iter().map(|(_, name)| name).collect())
 }
 
+/// View column carrying the rowid of a table with no declared PRIMARY KEY, so
+/// the UPDATE and DELETE triggers can address its rows
+pub const ROWID_COLUMN: &str = "__sec_rowid";
+
+/// How to name the rowid of a table with no declared PRIMARY KEY: the first of
+/// SQLite's aliases not shadowed by a real column. `None` if the table has a
+/// PRIMARY KEY.
+fn rowid_alias(conn: &Connection, table: &PhysicalTable) -> Result<Option<&'static str>> {
+    if !get_primary_key_columns(conn, table)?.is_empty() {
+        return Ok(None);
+    }
+    let cols = get_physical_columns(conn, table)?;
+    let alias = ["rowid", "_rowid_", "oid"]
+        .into_iter()
+        .find(|alias| !cols.iter().any(|c| c.eq_ignore_ascii_case(alias)));
+    match alias {
+        Some(alias) => Ok(Some(alias)),
+        None => Err(invalid(format!(
+            "table '{table}' has no PRIMARY KEY and columns shadow every rowid alias"
+        ))),
+    }
+}
+
 fn invalid<T: ToString>(msg: T) -> Error {
     Error::UserFunctionError(Box::new(std::io::Error::new(
         ErrorKind::InvalidInput,
@@ -72,7 +189,7 @@ fn invalid<T: ToString>(msg: T) -> Error {
 fn get_sec_columns(conn: &Connection, logical_table: &str) -> Result<Vec<SecColumn>> {
     let mut stmt = conn.prepare(
         r#"
-        SELECT column_name, read_label_id, update_label_id
+        SELECT column_name, read_label_id, update_label_id, mask_expr
         FROM sec_columns
         WHERE logical_table = ?1
         "#,
@@ -84,6 +201,7 @@ fn get_sec_columns(conn: &Connection, logical_table: &str) -> Result<Vec<SecColu
                 column_name: row.get(0)?,
                 read_label_id: row.get(1)?,
                 update_label_id: row.get(2)?,
+                mask_expr: row.get(3)?,
             })
         })?
         .collect::<Result<Vec<_>>>()?;
@@ -94,7 +212,8 @@ fn get_sec_columns(conn: &Connection, logical_table: &str) -> Result<Vec<SecColu
 fn get_sec_tables(conn: &Connection) -> Result<Vec<SecTable>> {
     let mut stmt = conn.prepare(
         r#"
-        SELECT logical_name, physical_name, row_label_col, table_label_id, insert_label_id
+        SELECT logical_name, physical_schema, physical_name, row_label_col,
+               table_label_id, insert_label_id, delete_label_id, materialize_labels
         FROM sec_tables
         "#,
     )?;
@@ -103,10 +222,15 @@ fn get_sec_tables(conn: &Connection) -> Result<Vec<SecTable>> {
         .query_map([], |row| {
             Ok(SecTable {
                 logical_name: row.get(0)?,
-                physical_name: row.get(1)?,
-                row_label_col: row.get(2)?,
-                table_label_id: row.get(3)?,
-                insert_label_id: row.get(4)?,
+                physical: PhysicalTable {
+                    schema: row.get(1)?,
+                    name: row.get(2)?,
+                },
+                row_label_col: row.get(3)?,
+                table_label_id: row.get(4)?,
+                insert_label_id: row.get(5)?,
+                delete_label_id: row.get(6)?,
+                materialize_labels: row.get(7)?,
             })
         })?
         .collect::<Result<Vec<_>>>()?;
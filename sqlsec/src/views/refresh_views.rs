@@ -5,7 +5,17 @@ use rusqlite::{Connection, Error, Result};
 use crate::{
     context::{effective_context, sec_ctx::SecurityContext},
     label::evaluate::{is_visible_conn, load_levels},
-    views::{SecTable, get_sec_columns, get_sec_tables, write_triggers::create_write_triggers},
+    views::{
+        ROWID_COLUMN,
+        SecColumn,
+        SecTable,
+        get_sec_columns,
+        get_sec_tables,
+        mask::other_columns_referenced,
+        rowid_alias,
+        sync_columns::sync_columns,
+        write_triggers::create_write_triggers,
+    },
 };
 
 fn refresh_err(err: Error, table: &str) -> Error {
@@ -17,17 +27,30 @@ fn refresh_err(err: Error, table: &str) -> Error {
 
 /// Refresh views using Connection reference
 pub fn refresh_views(conn: &mut Connection, ctx: &SecurityContext) -> Result<()> {
+    let tx = conn.transaction()?; // BEGIN
+    refresh_views_in(&tx, ctx)?;
+    tx.commit()?; // COMMIT
+    Ok(())
+}
+
+/// Refresh views within a transaction the caller already holds
+pub(crate) fn refresh_views_in(conn: &Connection, ctx: &SecurityContext) -> Result<()> {
     load_levels(conn)?;
 
-    let tx = conn.transaction()?; // BEGIN
+    let tables = get_sec_tables(conn)?;
 
-    let tables = get_sec_tables(&tx)?;
+    if tables.iter().any(|t| t.materialize_labels) {
+        materialize_visible_labels(conn, ctx)?;
+    }
 
     for table in tables {
-        refresh_single_view(&tx, &table, ctx).map_err(|e| refresh_err(e, &table.logical_name))?;
+        // Pick up columns added to or dropped from the physical table since
+        // it was registered
+        sync_columns(conn, &table.logical_name).map_err(|e| refresh_err(e, &table.logical_name))?;
+        refresh_single_view(conn, &table, ctx).map_err(|e| refresh_err(e, &table.logical_name))?;
     }
 
-    tx.execute_batch(
+    conn.execute_batch(
         r#"
         INSERT OR REPLACE INTO sec_meta (key, value)
         VALUES ('last_refresh_generation',
@@ -38,7 +61,6 @@ pub fn refresh_views(conn: &mut Connection, ctx: &SecurityContext) -> Result<()>
         "#,
     )?;
 
-    tx.commit()?; // COMMIT
     Ok(())
 }
 
@@ -54,6 +76,32 @@ pub fn refresh_views_raw(db_ptr: usize) -> Result<()> {
     result
 }
 
+/// Fill the `temp._sec_visible_labels` table with the ids of every label `ctx`
+/// can see, for views whose tables set `materialize_labels`. Their row filter
+/// is then an `IN` on the row label column, which can use an index on it
+/// where a per-row `sec_label_visible` call cannot.
+fn materialize_visible_labels(conn: &Connection, ctx: &SecurityContext) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TEMP TABLE IF NOT EXISTS _sec_visible_labels (id INTEGER PRIMARY KEY);
+        DELETE FROM temp._sec_visible_labels;
+        "#,
+    )?;
+
+    let ids = conn
+        .prepare("SELECT id FROM sec_labels")?
+        .query_map([], |r| r.get::<_, i64>(0))?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut insert = conn.prepare("INSERT INTO temp._sec_visible_labels (id) VALUES (?1)")?;
+    for id in ids {
+        if is_visible_conn(conn, Some(id), ctx) {
+            insert.execute([id])?;
+        }
+    }
+    Ok(())
+}
+
 fn refresh_single_view(conn: &Connection, table: &SecTable, ctx: &SecurityContext) -> Result<()> {
     // Check table-level visibility
     if !is_visible_conn(conn, table.table_label_id, ctx) {
@@ -80,12 +128,40 @@ fn refresh_single_view(conn: &Connection, table: &SecTable, ctx: &SecurityContex
         return Ok(());
     }
 
+    // Unreadable columns with a mask are shown through it, unless it reads
+    // another column this context cannot see
+    let mask_of = |col: &SecColumn| -> Option<String> {
+        let mask = col.mask_expr.as_deref()?;
+        let leaks = other_columns_referenced(mask, &col.column_name, &all_columns)
+            .any(|c| !visible_columns.contains(&c.column_name.as_str()));
+        (!leaks).then(|| format!("({mask}\n) AS \"{}\"", col.column_name))
+    };
+
     // Build SELECT list
-    let select_cols = visible_columns
-        .iter()
-        .map(|c| format!("\"{}\"", c))
-        .collect::<Vec<_>>()
-        .join(", ");
+    let mut masked_columns = Vec::new();
+    let mut select_cols = Vec::new();
+    for col in &all_columns {
+        if visible_columns.contains(&col.column_name.as_str()) {
+            select_cols.push(format!("\"{}\"", col.column_name));
+        } else if let Some(masked) = mask_of(col) {
+            select_cols.push(masked);
+            masked_columns.push(col.column_name.as_str());
+        }
+    }
+    if let Some(rowid) = rowid_alias(conn, &table.physical)? {
+        select_cols.push(format!("{rowid} AS \"{ROWID_COLUMN}\""));
+    }
+    let select_cols = select_cols.join(", ");
+
+    let row_label_col = &table.row_label_col;
+    let row_filter = if table.materialize_labels {
+        format!(
+            r#"("{row_label_col}" IS NULL
+               OR "{row_label_col}" IN (SELECT id FROM temp._sec_visible_labels))"#
+        )
+    } else {
+        format!(r#"sec_label_visible("{row_label_col}")"#)
+    };
 
     // Build the view DDL
     let view_sql = format!(
@@ -93,21 +169,21 @@ fn refresh_single_view(conn: &Connection, table: &SecTable, ctx: &SecurityContex
         DROP VIEW IF EXISTS "{}";
         CREATE TEMP VIEW "{}" AS
         SELECT {}
-        FROM "{}"
+        FROM {}
         WHERE sec_assert_fresh()
-          AND sec_label_visible("{}");
+          AND {};
         "#,
         table.logical_name,
         table.logical_name,
         select_cols,
-        table.physical_name,
-        table.row_label_col
+        table.physical.quoted(),
+        row_filter
     );
 
     conn.execute_batch(&view_sql)?;
 
     // Create INSTEAD OF triggers for writes
-    create_write_triggers(conn, table, &visible_columns)?;
+    create_write_triggers(conn, table, &visible_columns, &masked_columns)?;
 
     Ok(())
 }
@@ -2,12 +2,15 @@ use std::mem::forget;
 
 use rusqlite::{Connection, Result};
 
-use crate::views::{get_physical_columns, get_primary_key_columns, invalid};
+use crate::views::{PhysicalTable, get_physical_columns, invalid, rowid_alias};
 
-fn is_without_rowid(conn: &Connection, table: &str) -> Result<bool> {
+fn is_without_rowid(conn: &Connection, table: &PhysicalTable) -> Result<bool> {
     let sql: Option<String> = conn.query_row(
-        "SELECT sql FROM sqlite_master WHERE type='table' AND name=?1",
-        [table],
+        &format!(
+            "SELECT sql FROM \"{}\".sqlite_master WHERE type='table' AND name=?1",
+            table.schema
+        ),
+        [&table.name],
         |row| row.get(0),
     )?;
 
@@ -16,7 +19,11 @@ fn is_without_rowid(conn: &Connection, table: &str) -> Result<bool> {
         .unwrap_or(false))
 }
 
-/// Register a table using Connection reference
+/// Register a table using Connection reference. `physical` may be qualified
+/// with the name of an ATTACHed database, as in `aux.customers`.
+///
+/// Deleting through the view additionally requires `delete_label_id`, when
+/// set, on top of seeing the row.
 pub fn register_table(
     conn: &Connection,
     logical: &str,
@@ -24,9 +31,11 @@ pub fn register_table(
     row_label_col: &str,
     table_label_id: Option<i64>,
     insert_label_id: Option<i64>,
+    delete_label_id: Option<i64>,
 ) -> Result<()> {
     // 1. Physical table exists (implicit via PRAGMA failure)
-    let cols = get_physical_columns(conn, physical)?;
+    let physical = PhysicalTable::resolve(conn, physical)?;
+    let cols = get_physical_columns(conn, &physical)?;
 
     // 2. Row label column exists
     if !cols.iter().any(|c| c == row_label_col) {
@@ -36,21 +45,16 @@ pub fn register_table(
         )));
     }
 
-    // 3. Primary key exists
-    let pk_cols = get_primary_key_columns(conn, physical)?;
-    if pk_cols.is_empty() {
-        return Err(invalid(format!(
-            "secured table '{physical}' must have a PRIMARY KEY"
-        )));
-    }
-
-    // 4. Reject WITHOUT ROWID tables
-    if is_without_rowid(conn, physical)? {
+    // 3. Reject WITHOUT ROWID tables
+    if is_without_rowid(conn, &physical)? {
         return Err(invalid(format!(
             "WITHOUT ROWID table '{physical}' is not supported"
         )));
     }
 
+    // 4. Rows can be addressed, by PRIMARY KEY or else by rowid
+    rowid_alias(conn, &physical)?;
+
     // 5. Column name sanity
     let mut seen = std::collections::HashSet::new();
     for col in &cols {
@@ -64,15 +68,18 @@ pub fn register_table(
     conn.execute(
         r#"
         INSERT OR REPLACE INTO sec_tables
-        (logical_name, physical_name, row_label_col, table_label_id, insert_label_id)
-        VALUES (?1, ?2, ?3, ?4, ?5)
+        (logical_name, physical_schema, physical_name, row_label_col,
+         table_label_id, insert_label_id, delete_label_id)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
         "#,
         rusqlite::params![
             logical,
-            physical,
+            physical.schema,
+            physical.name,
             row_label_col,
             table_label_id,
-            insert_label_id
+            insert_label_id,
+            delete_label_id
         ],
     )?;
 
@@ -97,6 +104,7 @@ pub fn register_table_raw(
     row_label_col: &str,
     table_label_id: Option<i64>,
     insert_label_id: Option<i64>,
+    delete_label_id: Option<i64>,
 ) -> Result<()> {
     let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
     let result = register_table(
@@ -106,6 +114,7 @@ pub fn register_table_raw(
         row_label_col,
         table_label_id,
         insert_label_id,
+        delete_label_id,
     );
     forget(conn);
     result
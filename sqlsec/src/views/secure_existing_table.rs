@@ -0,0 +1,125 @@
+use std::mem::forget;
+
+use rusqlite::{Connection, OptionalExtension, Result};
+
+use crate::{
+    context::{effective_context, sec_ctx::SecurityContext},
+    label::define::define_label,
+    views::{
+        PhysicalTable,
+        get_physical_columns,
+        invalid,
+        refresh_views::refresh_views_in,
+        register_table::register_table,
+    },
+};
+
+/// Row label column added to tables secured by [`secure_existing_table`]
+pub const ROW_LABEL_COLUMN: &str = "row_label_id";
+
+/// Turn the plain table `table` into a secured one: rename it to
+/// `__sec_<table>`, add a `row_label_id` column defaulting to the label of
+/// `default_label_expr`, register it as `table` and refresh the views.
+/// Returns the default label's id.
+///
+/// Everything happens in one transaction, so other connections see either the
+/// plain table or the secured one. `ALTER TABLE ... RENAME` carries the
+/// table's constraints, indexes and triggers across.
+pub fn secure_existing_table(
+    conn: &mut Connection,
+    ctx: &SecurityContext,
+    table: &str,
+    default_label_expr: &str,
+) -> Result<i64> {
+    let mut original = PhysicalTable::resolve(conn, table)?;
+    let registered: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sec_tables WHERE logical_name = ?1 COLLATE NOCASE",
+        [&original.name],
+        |r| r.get(0),
+    )?;
+    if registered {
+        return Err(invalid(format!(
+            "table '{}' is already registered",
+            original.name
+        )));
+    }
+
+    let found: Option<(String, String)> = conn
+        .query_row(
+            &format!(
+                "SELECT name, type FROM \"{}\".sqlite_master WHERE name = ?1 COLLATE NOCASE",
+                original.schema
+            ),
+            [&original.name],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .optional()?;
+    match found {
+        Some((name, kind)) if kind == "table" => original.name = name,
+        Some((_, kind)) => return Err(invalid(format!("'{original}' is a {kind}, not a table"))),
+        None => return Err(invalid(format!("table '{original}' does not exist"))),
+    }
+
+    let physical = PhysicalTable {
+        schema: original.schema.clone(),
+        name: format!("__sec_{}", original.name),
+    };
+    let logical = original.name.as_str();
+
+    if get_physical_columns(conn, &physical).is_ok() {
+        return Err(invalid(format!("table '{physical}' already exists")));
+    }
+    if get_physical_columns(conn, &original)?
+        .iter()
+        .any(|c| c.eq_ignore_ascii_case(ROW_LABEL_COLUMN))
+    {
+        return Err(invalid(format!(
+            "table '{original}' already has a '{ROW_LABEL_COLUMN}' column, register it with sec_register_table"
+        )));
+    }
+
+    let sp = conn.savepoint()?;
+
+    let label_id = define_label(&sp, default_label_expr)?;
+
+    // The column default labels existing rows without rewriting them, and rows
+    // later written straight to the physical table without a label
+    sp.execute_batch(&format!(
+        r#"
+        ALTER TABLE {} RENAME TO "{}";
+        ALTER TABLE {} ADD COLUMN "{ROW_LABEL_COLUMN}" INTEGER DEFAULT {label_id};
+        "#,
+        original.quoted(),
+        physical.name,
+        physical.quoted(),
+    ))?;
+
+    register_table(
+        &sp,
+        logical,
+        &physical.to_string(),
+        ROW_LABEL_COLUMN,
+        None,
+        None,
+        None,
+    )?;
+    refresh_views_in(&sp, ctx)?;
+
+    sp.commit()?;
+    Ok(label_id)
+}
+
+pub fn secure_existing_table_raw(
+    db_ptr: usize,
+    table: &str,
+    default_label_expr: &str,
+) -> Result<i64> {
+    let mut conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let ctx = effective_context(db_ptr);
+
+    let result = secure_existing_table(&mut conn, &ctx, table, default_label_expr);
+
+    forget(conn);
+    result
+}
@@ -0,0 +1,158 @@
+use std::mem::forget;
+
+use rusqlite::{Connection, OptionalExtension, Result};
+use serde_json::{Value, json};
+
+use crate::{
+    context::{effective_context, sec_ctx::SecurityContext},
+    views::{
+        PhysicalTable,
+        get_physical_columns,
+        get_sec_columns,
+        invalid,
+        quote_ident,
+        refresh_views::refresh_views_in,
+    },
+};
+
+/// Columns [`sync_columns`] added to or removed from `sec_columns`
+#[derive(Debug, Default, PartialEq)]
+pub struct ColumnChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ColumnChanges {
+    pub fn to_json(&self) -> Value {
+        json!({ "added": self.added, "removed": self.removed })
+    }
+}
+
+/// Bring `sec_columns` for `logical` in line with its physical table: columns
+/// added since registration get rows with no labels, rows for dropped columns
+/// are deleted along with their labels and mask.
+///
+/// A renamed column is seen as one dropped and one added, so its labels must
+/// be set again.
+pub fn sync_columns(conn: &Connection, logical: &str) -> Result<ColumnChanges> {
+    let (physical, _) = registered_table(conn, logical)?;
+
+    let physical_cols = get_physical_columns(conn, &physical)?;
+    let sec_cols = get_sec_columns(conn, logical)?;
+
+    let mut changes = ColumnChanges::default();
+
+    for col in &physical_cols {
+        if !sec_cols
+            .iter()
+            .any(|c| c.column_name.eq_ignore_ascii_case(col))
+        {
+            conn.execute(
+                r#"
+                INSERT INTO sec_columns (logical_table, column_name, read_label_id, update_label_id)
+                VALUES (?1, ?2, NULL, NULL)
+                "#,
+                rusqlite::params![logical, col],
+            )?;
+            changes.added.push(col.clone());
+        }
+    }
+
+    for col in &sec_cols {
+        if !physical_cols
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(&col.column_name))
+        {
+            conn.execute(
+                "DELETE FROM sec_columns WHERE logical_table = ?1 AND column_name = ?2",
+                rusqlite::params![logical, col.column_name],
+            )?;
+            changes.removed.push(col.column_name.clone());
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Drop `column` from the physical table behind `logical` and rebuild the
+/// views without it. Returns the changes to `sec_columns`, as
+/// [`sync_columns`] does.
+///
+/// SQLite will not drop a column that a view or trigger still names, and this
+/// connection's secured view and its write triggers name every column, so the
+/// view is dropped first. It all happens in one transaction.
+pub fn drop_column(
+    conn: &mut Connection,
+    ctx: &SecurityContext,
+    logical: &str,
+    column: &str,
+) -> Result<ColumnChanges> {
+    let (physical, row_label_col) = registered_table(conn, logical)?;
+    if column.eq_ignore_ascii_case(&row_label_col) {
+        return Err(invalid(format!(
+            "cannot drop '{column}', the row label column of '{logical}'"
+        )));
+    }
+    let column = get_physical_columns(conn, &physical)?
+        .into_iter()
+        .find(|c| c.eq_ignore_ascii_case(column))
+        .ok_or_else(|| invalid(format!("no column '{column}' in '{logical}'")))?;
+
+    let sp = conn.savepoint()?;
+
+    // Dropping the view drops its write triggers with it
+    sp.execute(&format!("DROP VIEW IF EXISTS {}", quote_ident(logical)), [])?;
+    sp.execute(
+        &format!(
+            "ALTER TABLE {} DROP COLUMN {}",
+            physical.quoted(),
+            quote_ident(&column)
+        ),
+        [],
+    )?;
+    let changes = sync_columns(&sp, logical)?;
+    refresh_views_in(&sp, ctx)?;
+
+    sp.commit()?;
+    Ok(changes)
+}
+
+/// The physical table and row label column `logical` is registered with
+fn registered_table(conn: &Connection, logical: &str) -> Result<(PhysicalTable, String)> {
+    conn.query_row(
+        r#"
+        SELECT physical_schema, physical_name, row_label_col
+        FROM sec_tables WHERE logical_name = ?1
+        "#,
+        [logical],
+        |r| {
+            let physical = PhysicalTable {
+                schema: r.get(0)?,
+                name: r.get(1)?,
+            };
+            Ok((physical, r.get(2)?))
+        },
+    )
+    .optional()?
+    .ok_or_else(|| invalid(format!("table '{logical}' is not registered")))
+}
+
+pub fn sync_columns_raw(db_ptr: usize, logical: &str) -> Result<ColumnChanges> {
+    let conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let result = sync_columns(&conn, logical);
+
+    forget(conn);
+    result
+}
+
+pub fn drop_column_raw(db_ptr: usize, logical: &str, column: &str) -> Result<ColumnChanges> {
+    let mut conn = unsafe { Connection::from_handle(db_ptr as *mut _)? };
+
+    let ctx = effective_context(db_ptr);
+
+    let result = drop_column(&mut conn, &ctx, logical, column);
+
+    forget(conn);
+    result
+}
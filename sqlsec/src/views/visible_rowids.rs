@@ -0,0 +1,266 @@
+use std::{
+    ffi::{CStr, CString, c_int},
+    marker::PhantomData,
+    mem::forget,
+    ptr,
+};
+
+use rusqlite::{
+    Connection,
+    Error,
+    OptionalExtension,
+    Result,
+    ffi,
+    vtab::{
+        Context,
+        Filters,
+        IndexConstraintOp,
+        IndexInfo,
+        VTab,
+        VTabConnection,
+        VTabCursor,
+        eponymous_only_module,
+    },
+};
+
+use crate::{
+    label::evaluate::visible_memoized,
+    register::owned_value,
+    views::{PhysicalTable, get_primary_key_columns, invalid, rowid_alias},
+};
+
+const COLUMN_KEY: c_int = 0;
+const COLUMN_TABLE: c_int = 1;
+
+/// `idx_num` of a scan given its table
+const HAS_TABLE: c_int = 1;
+
+/// Register `sec_visible_rowids(table)` on `conn`
+pub(crate) fn register_visible_rowids(conn: &Connection) -> Result<()> {
+    conn.create_module(
+        c"sec_visible_rowids",
+        eponymous_only_module::<VisibleRowidsTab>(),
+        None,
+    )
+}
+
+/// The statement yielding the keys of the rows of `logical` visible to the
+/// effective context, `None` if its table label hides every row.
+///
+/// The key is the PRIMARY KEY value, a JSON array of the values in key order
+/// for a composite key, or the rowid for a table without one.
+fn visible_keys_sql(conn: &Connection, db_ptr: usize, logical: &str) -> Result<Option<String>> {
+    let (physical, row_label_col, table_label_id) = conn
+        .query_row(
+            r#"
+            SELECT physical_schema, physical_name, row_label_col, table_label_id
+            FROM sec_tables WHERE logical_name = ?1
+            "#,
+            [logical],
+            |r| {
+                Ok((
+                    PhysicalTable {
+                        schema: r.get(0)?,
+                        name: r.get(1)?,
+                    },
+                    r.get::<_, String>(2)?,
+                    r.get::<_, Option<i64>>(3)?,
+                ))
+            },
+        )
+        .optional()?
+        .ok_or_else(|| invalid(format!("table '{logical}' is not registered")))?;
+
+    if let Some(id) = table_label_id
+        && !visible_memoized(db_ptr, id)?
+    {
+        return Ok(None);
+    }
+
+    let key_cols = match rowid_alias(conn, &physical)? {
+        Some(rowid) => vec![rowid.to_string()],
+        None => get_primary_key_columns(conn, &physical)?,
+    };
+    let key_cols: Vec<String> = key_cols.iter().map(|c| format!("\"{c}\"")).collect();
+    let key = match key_cols.as_slice() {
+        [col] => col.clone(),
+        cols => format!("json_array({})", cols.join(", ")),
+    };
+
+    Ok(Some(format!(
+        "SELECT {key} FROM {} WHERE sec_label_visible(\"{row_label_col}\")",
+        physical.quoted()
+    )))
+}
+
+#[repr(C)]
+struct VisibleRowidsTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    db: *mut ffi::sqlite3,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for VisibleRowidsTab {
+    type Aux = ();
+    type Cursor = VisibleRowidsCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        _aux: Option<&()>,
+        _args: &[&[u8]],
+    ) -> Result<(String, Self)> {
+        let vtab = Self {
+            base: ffi::sqlite3_vtab::default(),
+            db: unsafe { db.handle() },
+        };
+        Ok(("CREATE TABLE x(key, tbl HIDDEN)".to_owned(), vtab))
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> Result<()> {
+        let mut table = None;
+        for (i, c) in info.constraints().enumerate() {
+            if c.column() != COLUMN_TABLE
+                || c.operator() != IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ
+            {
+                continue;
+            }
+            if !c.is_usable() {
+                // Have the planner find an order that supplies the table
+                return Err(Error::SqliteFailure(
+                    ffi::Error::new(ffi::SQLITE_CONSTRAINT),
+                    None,
+                ));
+            }
+            table = Some(i);
+        }
+
+        if let Some(i) = table {
+            let mut usage = info.constraint_usage(i);
+            usage.set_argv_index(1);
+            usage.set_omit(true);
+            info.set_idx_num(HAS_TABLE);
+        }
+        info.set_estimated_cost(1_000_000.0);
+        Ok(())
+    }
+
+    fn open(&'vtab mut self) -> Result<VisibleRowidsCursor<'vtab>> {
+        Ok(VisibleRowidsCursor {
+            base: ffi::sqlite3_vtab_cursor::default(),
+            db: self.db,
+            stmt: ptr::null_mut(),
+            table: String::new(),
+            row: 0,
+            eof: true,
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// Steps a statement over the physical table, so rows are produced as they
+/// are read rather than collected up front
+#[repr(C)]
+struct VisibleRowidsCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    db: *mut ffi::sqlite3,
+    stmt: *mut ffi::sqlite3_stmt,
+    table: String,
+    row: i64,
+    eof: bool,
+    phantom: PhantomData<&'vtab VisibleRowidsTab>,
+}
+
+impl VisibleRowidsCursor<'_> {
+    fn error(&self, rc: c_int) -> Error {
+        let msg = unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.db)) };
+        Error::SqliteFailure(
+            ffi::Error::new(rc),
+            Some(msg.to_string_lossy().into_owned()),
+        )
+    }
+
+    fn finalize(&mut self) {
+        if !self.stmt.is_null() {
+            unsafe { ffi::sqlite3_finalize(self.stmt) };
+            self.stmt = ptr::null_mut();
+        }
+    }
+
+    fn step(&mut self) -> Result<()> {
+        match unsafe { ffi::sqlite3_step(self.stmt) } {
+            ffi::SQLITE_ROW => {
+                self.row += 1;
+                Ok(())
+            }
+            ffi::SQLITE_DONE => {
+                self.eof = true;
+                Ok(())
+            }
+            rc => {
+                self.eof = true;
+                Err(self.error(rc))
+            }
+        }
+    }
+}
+
+impl Drop for VisibleRowidsCursor<'_> {
+    fn drop(&mut self) {
+        self.finalize();
+    }
+}
+
+unsafe impl VTabCursor for VisibleRowidsCursor<'_> {
+    fn filter(&mut self, idx_num: c_int, _idx_str: Option<&str>, args: &Filters<'_>) -> Result<()> {
+        self.finalize();
+        self.row = 0;
+        self.eof = true;
+
+        if idx_num != HAS_TABLE {
+            return Err(invalid(
+                "sec_visible_rowids: expected a table, as in sec_visible_rowids('table')",
+            ));
+        }
+        self.table = args.get::<String>(0)?;
+
+        let conn = unsafe { Connection::from_handle(self.db)? };
+        let sql = visible_keys_sql(&conn, self.db as usize, &self.table);
+        forget(conn);
+        let Some(sql) = sql? else {
+            return Ok(());
+        };
+
+        let sql = CString::new(sql).map_err(|e| invalid(e.to_string()))?;
+        let rc = unsafe {
+            ffi::sqlite3_prepare_v2(self.db, sql.as_ptr(), -1, &mut self.stmt, ptr::null_mut())
+        };
+        if rc != ffi::SQLITE_OK {
+            return Err(self.error(rc));
+        }
+
+        self.eof = false;
+        self.step()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.step()
+    }
+
+    fn eof(&self) -> bool {
+        self.eof
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> Result<()> {
+        match i {
+            COLUMN_KEY => {
+                ctx.set_result(&unsafe { owned_value(ffi::sqlite3_column_value(self.stmt, 0)) })
+            }
+            _ => ctx.set_result(&self.table),
+        }
+    }
+
+    fn rowid(&self) -> Result<i64> {
+        Ok(self.row)
+    }
+}
@@ -3,30 +3,75 @@ use rusqlite::{Connection, Result};
 use crate::{
     context::effective_context,
     label::evaluate::is_visible_conn,
-    views::{SecTable, get_primary_key_columns, get_sec_columns, invalid},
+    views::{
+        PhysicalTable,
+        ROWID_COLUMN,
+        SecTable,
+        get_primary_key_columns,
+        get_sec_columns,
+        rowid_alias,
+    },
 };
 
+/// `masked_cols` appear in the view through their mask expression and are
+/// never written back
 pub fn create_write_triggers(
     conn: &Connection,
     table: &SecTable,
     visible_cols: &[&str],
+    masked_cols: &[&str],
 ) -> Result<()> {
-    create_insert_trigger(conn, table, visible_cols)?;
-    create_update_trigger(conn, table, visible_cols)?;
+    // Statements in a trigger cannot name a schema, so writes can only reach
+    // the physical table if its bare name finds it
+    if let Some(shadow) = table.physical.shadowed_by(conn)? {
+        return create_read_only_triggers(conn, table, &shadow);
+    }
+
+    create_insert_trigger(conn, table, visible_cols, masked_cols)?;
+    create_update_trigger(conn, table, visible_cols, masked_cols)?;
     create_delete_trigger(conn, table)?;
 
     Ok(())
 }
 
+fn create_read_only_triggers(
+    conn: &Connection,
+    table: &SecTable,
+    shadow: &PhysicalTable,
+) -> Result<(), rusqlite::Error> {
+    let logical = &table.logical_name;
+    let message = format!(
+        "cannot write to {}.{} through {logical}: {}.{} has the same name",
+        table.physical.schema, table.physical.name, shadow.schema, shadow.name
+    )
+    .replace('\'', "''");
+
+    for (suffix, event) in [("ins", "INSERT"), ("upd", "UPDATE"), ("del", "DELETE")] {
+        conn.execute_batch(&format!(
+            r#"
+            DROP TRIGGER IF EXISTS "{logical}_sec_{suffix}";
+            CREATE TEMP TRIGGER "{logical}_sec_{suffix}"
+            INSTEAD OF {event} ON "{logical}"
+            BEGIN
+                SELECT RAISE(ABORT, '{message}');
+            END;
+            "#
+        ))
+        .map_err(|e| trigger_err(e, logical, event))?;
+    }
+    Ok(())
+}
+
 fn create_delete_trigger(conn: &Connection, table: &SecTable) -> Result<(), rusqlite::Error> {
     let logical = &table.logical_name;
-    let physical = &table.physical_name;
+    let physical = &table.physical.name;
     let row_label_col = &table.row_label_col;
 
-    let pk_cols = pk_cols(conn, physical)?;
+    let pk_cols = pk_cols(conn, &table.physical)?;
     let pk_where_old = pk_where_old(&pk_cols);
 
     let refesh_guard = refresh_guard();
+    let delete_label_guard = delete_label_guard(logical, table.delete_label_id);
 
     let delete_trigger = format!(
         r#"
@@ -35,6 +80,7 @@ fn create_delete_trigger(conn: &Connection, table: &SecTable) -> Result<(), rusq
         INSTEAD OF DELETE ON "{logical}"
         BEGIN
             {refesh_guard}
+            {delete_label_guard}
 
             DELETE FROM "{physical}"
             WHERE {pk_where_old}
@@ -52,9 +98,10 @@ fn create_update_trigger(
     conn: &Connection,
     table: &SecTable,
     visible_cols: &[&str],
+    masked_cols: &[&str],
 ) -> Result<(), rusqlite::Error> {
     let logical = &table.logical_name;
-    let physical = &table.physical_name;
+    let physical = &table.physical.name;
     let row_label_col = &table.row_label_col;
 
     let update_sets = visible_cols
@@ -63,13 +110,14 @@ fn create_update_trigger(
         .collect::<Vec<_>>()
         .join(", ");
 
-    let pk_cols = pk_cols(conn, physical)?;
+    let pk_cols = pk_cols(conn, &table.physical)?;
     let pk_where_old = pk_where_old(&pk_cols);
 
     let refresh_guard = refresh_guard();
     let update_pk_guard = update_pk_guard(pk_cols);
     let update_label_guard = update_label_guard(row_label_col);
     let column_policy_guards = column_update_policy_guards(conn, logical)?;
+    let masked_update_guards = masked_update_guards(masked_cols);
 
     let update_trigger = format!(
         r#"
@@ -81,6 +129,7 @@ fn create_update_trigger(
             {update_pk_guard}
             {update_label_guard}
             {column_policy_guards}
+            {masked_update_guards}
 
             UPDATE "{physical}"
             SET {update_sets}
@@ -99,9 +148,10 @@ fn create_insert_trigger(
     conn: &Connection,
     table: &SecTable,
     visible_cols: &[&str],
+    masked_cols: &[&str],
 ) -> Result<(), rusqlite::Error> {
     let logical = &table.logical_name;
-    let physical = &table.physical_name;
+    let physical = &table.physical.name;
     let row_label_col = &table.row_label_col;
 
     let insert_cols = visible_cols
@@ -141,6 +191,7 @@ fn create_insert_trigger(
     let refesh_guard = refresh_guard();
     let implicit_label_guard = implicit_label_guard(logical, row_label_col);
     let label_visible_guard = label_visible_guard(row_label_col);
+    let masked_insert_guards = masked_insert_guards(masked_cols);
 
     let insert_trigger = format!(
         r#"
@@ -151,6 +202,7 @@ fn create_insert_trigger(
             {refesh_guard}
             {implicit_label_guard}
             {label_visible_guard}
+            {masked_insert_guards}
 
             INSERT INTO "{physical}" ("{row_label_col}", {insert_cols})
             VALUES (
@@ -166,10 +218,10 @@ fn create_insert_trigger(
     Ok(())
 }
 
-fn update_pk_guard(pk_cols: Vec<String>) -> String {
+fn update_pk_guard(pk_cols: Vec<(String, String)>) -> String {
     let pk_updated = pk_cols
         .iter()
-        .map(|col| format!("OLD.\"{col}\" != NEW.\"{col}\""))
+        .map(|(_, col)| format!("OLD.\"{col}\" != NEW.\"{col}\""))
         .collect::<Vec<_>>()
         .join(" OR ");
     format!(
@@ -181,6 +233,20 @@ fn update_pk_guard(pk_cols: Vec<String>) -> String {
     )
 }
 
+fn delete_label_guard(logical: &str, delete_label_id: Option<i64>) -> String {
+    let Some(delete_label_id) = delete_label_id else {
+        return String::new();
+    };
+    format!(
+        r#"
+        SELECT CASE
+            WHEN NOT sec_label_visible({delete_label_id})
+            THEN RAISE(ABORT, 'delete denied on table {logical}')
+        END;
+        "#
+    )
+}
+
 fn label_visible_guard(row_label_col: &String) -> String {
     format!(
         r#"
@@ -218,6 +284,38 @@ fn implicit_label_guard(logical: &String, row_label_col: &String) -> String {
     )
 }
 
+fn masked_insert_guards(masked_cols: &[&str]) -> String {
+    masked_cols
+        .iter()
+        .map(|col| {
+            format!(
+                r#"
+        SELECT CASE
+            WHEN NEW."{col}" IS NOT NULL
+            THEN RAISE(ABORT, 'insert denied on masked column {col}')
+        END;
+        "#
+            )
+        })
+        .collect()
+}
+
+fn masked_update_guards(masked_cols: &[&str]) -> String {
+    masked_cols
+        .iter()
+        .map(|col| {
+            format!(
+                r#"
+        SELECT CASE
+            WHEN OLD."{col}" IS NOT NEW."{col}"
+            THEN RAISE(ABORT, 'update denied on masked column {col}')
+        END;
+        "#
+            )
+        })
+        .collect()
+}
+
 fn refresh_guard() -> &'static str {
     (r#"
     SELECT CASE
@@ -228,21 +326,24 @@ fn refresh_guard() -> &'static str {
     "#) as _
 }
 
-fn pk_cols(conn: &Connection, physical: &String) -> Result<Vec<String>, rusqlite::Error> {
-    let pk_cols = get_primary_key_columns(conn, physical)?;
-    if pk_cols.is_empty() {
-        return Err(invalid(format!(
-            "secured table '{physical}' must have a PRIMARY KEY"
-        )));
+/// Columns identifying a row, as (physical column, view column) pairs
+fn pk_cols(
+    conn: &Connection,
+    physical: &PhysicalTable,
+) -> Result<Vec<(String, String)>, rusqlite::Error> {
+    if let Some(rowid) = rowid_alias(conn, physical)? {
+        return Ok(vec![(rowid.to_string(), ROWID_COLUMN.to_string())]);
     }
-    Ok(pk_cols)
+    Ok(get_primary_key_columns(conn, physical)?
+        .into_iter()
+        .map(|col| (col.clone(), col))
+        .collect())
 }
 
-fn pk_where_old(pk_cols: &[String]) -> String {
-    let pk_cols: &[String] = pk_cols;
+fn pk_where_old(pk_cols: &[(String, String)]) -> String {
     pk_cols
         .iter()
-        .map(|col| format!("\"{col}\" = OLD.\"{col}\""))
+        .map(|(col, view_col)| format!("\"{col}\" = OLD.\"{view_col}\""))
         .collect::<Vec<_>>()
         .join(" AND ")
 }
@@ -0,0 +1,70 @@
+.output /dev/null
+CREATE TABLE __sec_payroll (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    salary       INTEGER
+);
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Policy changes are recorded with the acting user]
+.output /dev/null
+SELECT sec_set_attr('user', 'alice');
+SELECT sec_define_label('role=hr');
+SELECT sec_define_label('role=temp');
+SELECT sec_define_level('clearance', 'secret', 2);
+SELECT sec_register_table('payroll', '__sec_payroll', 'row_label_id', NULL, 1);
+UPDATE sec_columns SET read_label_id = 1
+WHERE logical_table = 'payroll' AND column_name = 'salary';
+SELECT sec_delete_label(2);
+.output stdout
+SELECT id, actor, action, target FROM sec_admin_audit ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Details hold the changed values]
+SELECT action, detail_json FROM sec_admin_audit
+WHERE action IN ('define_label', 'set_column_security') ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Defining an existing label changes nothing and records nothing]
+.output /dev/null
+SELECT sec_define_label('role=hr');
+.output stdout
+SELECT COUNT(*) AS records FROM sec_admin_audit;
+
+.print ------------------------------------------------------------
+.print [The actor attribute is configurable]
+SELECT sec_configure('audit_actor_attr', 'username') AS audit_actor_attr;
+SELECT sec_audit_actor() AS actor;
+SELECT sec_set_attr('username', 'bob') AS ok;
+SELECT sec_audit_actor() AS actor;
+.output /dev/null
+SELECT sec_define_label('role=payroll');
+.output stdout
+SELECT actor, action, target FROM sec_admin_audit ORDER BY id DESC LIMIT 1;
+
+.print ------------------------------------------------------------
+.print [Without the attribute the actor is NULL]
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_define_level('clearance', 'top_secret', 3);
+.output stdout
+SELECT actor IS NULL AS no_actor, action, target FROM sec_admin_audit
+ORDER BY id DESC LIMIT 1;
+
+.print ------------------------------------------------------------
+.print [Settings are type checked]
+SELECT sec_configure('audit_actor_attr', 1);
+SELECT sec_configure('session_ttl', 'forever');
+
+.print ------------------------------------------------------------
+.print [Purging removes old records and is itself recorded]
+.output /dev/null
+SELECT sec_set_attr('username', 'carol');
+.output stdout
+SELECT sec_purge_admin_audit(3600) AS removed;
+UPDATE sec_admin_audit SET ts = ts - 7200 WHERE id <= 3;
+SELECT sec_purge_admin_audit(3600) AS removed;
+SELECT id, actor, action, detail_json FROM sec_admin_audit
+WHERE action = 'purge_admin_audit' ORDER BY id;
+SELECT COUNT(*) AS records FROM sec_admin_audit;
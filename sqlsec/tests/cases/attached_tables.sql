@@ -0,0 +1,64 @@
+.output /dev/null
+ATTACH DATABASE ':memory:' AS aux;
+
+CREATE TABLE main.__sec_customers (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT
+);
+CREATE TABLE aux.__sec_customers (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT,
+    region       TEXT
+);
+CREATE TABLE aux.__sec_orders (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    item         TEXT
+);
+
+SELECT sec_define_label('true');
+SELECT sec_define_label('role=sales');
+INSERT INTO main.__sec_customers VALUES (1, NULL, 'main-public'), (2, 2, 'main-sales');
+INSERT INTO aux.__sec_customers VALUES (1, NULL, 'aux-public', 'eu'), (2, 2, 'aux-sales', 'us');
+INSERT INTO aux.__sec_orders VALUES (1, NULL, 'widget'), (2, 2, 'gadget');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Register tables in main and aux]
+SELECT sec_register_table('customers', '__sec_customers', 'row_label_id', NULL, NULL) AS registered;
+SELECT sec_register_table('aux_customers', 'aux.__sec_customers', 'row_label_id', NULL, NULL) AS registered;
+SELECT sec_register_table('orders', 'aux.__sec_orders', 'row_label_id', NULL, NULL) AS registered;
+SELECT logical_name, physical_schema, physical_name FROM sec_tables ORDER BY logical_name;
+SELECT logical_table, column_name FROM sec_columns ORDER BY logical_table, column_name;
+
+.print ------------------------------------------------------------
+.print [Same-named tables stay isolated]
+.output /dev/null
+SELECT sec_set_attr('role', 'user');
+SELECT sec_refresh_views();
+.output stdout
+SELECT * FROM customers;
+SELECT * FROM aux_customers;
+
+.print ------------------------------------------------------------
+.print [Writes reach a table in aux]
+INSERT INTO orders (id, item) VALUES (3, 'sprocket');
+UPDATE orders SET item = 'widget v2' WHERE id = 1;
+DELETE FROM orders WHERE id = 2;
+SELECT id, item FROM aux.__sec_orders;
+
+.print ------------------------------------------------------------
+.print [A table shadowed by main is read-only]
+UPDATE aux_customers SET name = 'renamed' WHERE id = 1;
+SELECT id, name FROM main.__sec_customers;
+SELECT id, name FROM aux.__sec_customers;
+
+.print ------------------------------------------------------------
+.print [Row labels in attached tables count as references]
+SELECT sec_delete_label(2);
+
+.print ------------------------------------------------------------
+.print [An unknown schema prefix is part of the table name]
+SELECT sec_register_table('nowhere', 'nosuch.__sec_customers', 'row_label_id', NULL, NULL);
@@ -0,0 +1,46 @@
+.print ------------------------------------------------------------
+.print [No secure tables yet: nothing to refresh]
+SELECT sec_configure('auto_refresh', 1) AS auto_refresh;
+SELECT sec_set_attr('role', 'user') AS attr;
+SELECT value AS views_initialized FROM sec_meta WHERE key = 'views_initialized';
+
+.output /dev/null
+SELECT sec_define_label('role=manager');
+
+CREATE TABLE __sec_employees (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT
+);
+
+INSERT INTO __sec_employees VALUES
+    (1, NULL, 'Alice'),
+    (2, 1,    'Bob');
+
+SELECT sec_register_table('employees', '__sec_employees', 'row_label_id', NULL, NULL);
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [set_attr refreshes]
+SELECT sec_set_attr('role', 'manager') AS attr;
+SELECT * FROM employees;
+
+.print ------------------------------------------------------------
+.print [push and pop refresh]
+SELECT sec_push_context() AS pushed;
+SELECT sec_remove_attr('role', 'manager') AS removed;
+SELECT * FROM employees;
+SELECT sec_pop_context() AS popped;
+SELECT * FROM employees;
+
+.print ------------------------------------------------------------
+.print [clear_context refreshes]
+SELECT sec_clear_context() AS cleared;
+SELECT * FROM employees;
+
+.print ------------------------------------------------------------
+.print [Off again: views go stale]
+SELECT sec_configure('auto_refresh', 0) AS auto_refresh;
+SELECT sec_set_attr('role', 'manager') AS attr;
+SELECT * FROM employees;
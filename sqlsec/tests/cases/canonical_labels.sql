@@ -0,0 +1,26 @@
+.print ------------------------------------------------------------
+.print [Equivalent spellings share an id]
+SELECT sec_define_label('role=admin&team=finance') AS id;
+SELECT sec_define_label('team=finance&role=admin') AS id;
+SELECT sec_define_label('  team = finance &  role=admin ') AS id;
+SELECT sec_define_label('Role=admin&TEAM=finance') AS id;
+SELECT sec_define_label('(role=auditor|role=admin)&team=''finance''') AS id;
+SELECT sec_define_label('team=finance&role in (admin, auditor)') AS id;
+
+.print ------------------------------------------------------------
+.print [Values stay case-sensitive]
+SELECT sec_define_label('role=Admin&team=finance') AS id;
+
+.print ------------------------------------------------------------
+.print [Stored forms]
+SELECT id, expr, source FROM sec_labels ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Keys match the context case-insensitively]
+SELECT sec_set_attr('ROLE', 'admin');
+SELECT sec_set_attr('Team', 'finance');
+SELECT sec_label_visible(1) AS visible;
+
+.print ------------------------------------------------------------
+.print [Invalid expressions are rejected]
+SELECT sec_define_label('role=admin&');
@@ -0,0 +1,67 @@
+.output /dev/null
+SELECT sec_define_label('true');
+SELECT sec_define_label('role=hr');
+SELECT sec_define_label('role=hr&clearance>=secret');
+SELECT sec_define_level('clearance', 'public', 0);
+SELECT sec_define_level('clearance', 'secret', 2);
+
+CREATE TABLE __sec_employees (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT,
+    salary       INTEGER
+);
+CREATE TABLE __sec_notes (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    body         TEXT
+);
+SELECT sec_register_table('employees', '__sec_employees', 'row_label_id', NULL, 2, 3);
+SELECT sec_register_table('notes', '__sec_notes', 'row_label_id', 2, NULL);
+UPDATE sec_columns SET read_label_id = 3, update_label_id = 2
+WHERE logical_table = 'employees' AND column_name = 'salary';
+SELECT sec_set_column_mask('employees', 'salary', 'NULL');
+
+SELECT sec_set_attr('role', 'hr');
+SELECT sec_set_attr('clearance', 'public');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Registered tables with their labels and the context's verdicts]
+SELECT logical_name, physical_name, table_label, insert_label, delete_label,
+       visible, insertable, deletable
+FROM sqlsec_tables;
+
+.print ------------------------------------------------------------
+.print [Columns of one table]
+SELECT column_name, read_label, update_label, mask_expr, readable, updatable
+FROM sqlsec_columns WHERE logical_table = 'employees';
+
+.print ------------------------------------------------------------
+.print [Labels]
+SELECT * FROM sqlsec_labels;
+
+.print ------------------------------------------------------------
+.print [Every policy that names a label]
+SELECT * FROM sqlsec_policies;
+
+.print ------------------------------------------------------------
+.print [Verdicts follow the context]
+.output /dev/null
+SELECT sec_set_attr('clearance', 'secret');
+.output stdout
+SELECT column_name, readable, updatable FROM sqlsec_columns
+WHERE logical_table = 'employees' AND column_name = 'salary';
+.output /dev/null
+SELECT sec_clear_context();
+.output stdout
+SELECT logical_name, visible, insertable, deletable FROM sqlsec_tables;
+
+.print ------------------------------------------------------------
+.print [Lookups by key use the index]
+EXPLAIN QUERY PLAN SELECT * FROM sqlsec_columns WHERE logical_table = 'notes';
+SELECT COUNT(*) AS columns FROM sqlsec_columns WHERE logical_table = 'nothing';
+
+.print ------------------------------------------------------------
+.print [Catalog tables are read-only]
+DELETE FROM sqlsec_labels;
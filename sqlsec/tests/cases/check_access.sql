@@ -0,0 +1,82 @@
+.output /dev/null
+SELECT sec_define_label('true');
+SELECT sec_define_label('role=finance&region=eu');
+SELECT sec_define_label('role=auditor');
+SELECT sec_define_level('clearance', 'public', 0);
+SELECT sec_define_level('clearance', 'secret', 2);
+SELECT sec_define_label('clearance>=secret');
+
+CREATE TABLE __sec_invoices (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    customer     TEXT,
+    amount       INTEGER
+);
+INSERT INTO __sec_invoices VALUES
+    (1234, 2, 'Acme', 500),
+    (1235, NULL, 'Initech', 75);
+SELECT sec_register_table('invoices', '__sec_invoices', 'row_label_id', NULL, NULL);
+UPDATE sec_columns SET read_label_id = 4
+WHERE logical_table = 'invoices' AND column_name = 'amount';
+SELECT sec_set_column_mask('invoices', 'amount', '''***''');
+
+CREATE TABLE __sec_lines (
+    invoice_id   INTEGER,
+    line_no      INTEGER,
+    row_label_id INTEGER,
+    item         TEXT,
+    PRIMARY KEY (invoice_id, line_no)
+);
+INSERT INTO __sec_lines VALUES (1234, 1, 3, 'widgets'), (1234, 2, 1, 'gadgets');
+SELECT sec_register_table('lines', '__sec_lines', 'row_label_id', 3, NULL);
+
+SELECT sec_set_attr('role', 'finance');
+SELECT sec_set_attr('region', 'us');
+SELECT sec_set_attr('clearance', 'public');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [A failing clause is marked unsatisfied]
+SELECT json_extract(sec_check_access('invoices', 1234), '$.visible') AS visible,
+       json_extract(sec_check_access('invoices', 1234), '$.row_label.expr') AS row_label;
+SELECT json_extract(value, '$.satisfied') AS satisfied,
+       json_extract(value, '$.requirements[0].key') AS key
+FROM json_each(sec_check_access('invoices', 1234), '$.row_label.clauses');
+
+.print ------------------------------------------------------------
+.print [Column read results]
+SELECT json_extract(value, '$.column') AS "column",
+       json_extract(value, '$.read_label') AS read_label,
+       json_extract(value, '$.readable') AS readable,
+       json_extract(value, '$.masked') AS masked
+FROM json_each(sec_check_access('invoices', 1234), '$.columns');
+
+.print ------------------------------------------------------------
+.print [Unlabelled rows are visible]
+SELECT sec_check_access('invoices', 1235) ->> '$.row_label' AS row_label,
+       sec_check_access('invoices', 1235) ->> '$.visible' AS visible;
+
+.print ------------------------------------------------------------
+.print [Satisfying the label makes the row visible]
+.output /dev/null
+SELECT sec_set_attr('region', 'eu');
+.output stdout
+SELECT sec_check_access('invoices', 1234) ->> '$.visible' AS visible;
+
+.print ------------------------------------------------------------
+.print [Composite keys, with the table label checked too]
+SELECT sec_check_access('lines', 1234, 1) ->> '$.key' AS "key",
+       sec_check_access('lines', 1234, 1) ->> '$.table_label.visible' AS table_visible,
+       sec_check_access('lines', 1234, 1) ->> '$.row_label.visible' AS row_visible,
+       sec_check_access('lines', 1234, 1) ->> '$.visible' AS visible;
+.output /dev/null
+SELECT sec_set_attr('role', 'auditor');
+.output stdout
+SELECT sec_check_access('lines', 1234, 1) ->> '$.visible' AS visible;
+
+.print ------------------------------------------------------------
+.print [Errors]
+SELECT sec_check_access('lines', 1234);
+SELECT sec_check_access('invoices', 9999);
+SELECT sec_check_access('nosuch', 1);
+SELECT sec_check_access('invoices');
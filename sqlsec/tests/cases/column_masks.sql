@@ -0,0 +1,66 @@
+.output /dev/null
+CREATE TABLE __sec_employees (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT,
+    email        TEXT,
+    ssn          TEXT,
+    salary       INTEGER
+);
+INSERT INTO __sec_employees VALUES
+    (1, NULL, 'Alice', 'alice@example.com', '123-45-6789', 52000),
+    (2, NULL, 'Bob',   'bob@corp.test',     '987-65-4321', 91000);
+
+SELECT sec_register_table('employees', '__sec_employees', 'row_label_id', NULL, NULL);
+UPDATE sec_columns SET read_label_id = sec_define_label('role=hr')
+WHERE logical_table = 'employees' AND column_name IN ('email', 'ssn', 'salary');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Masks may read the masked column itself]
+SELECT sec_set_column_mask('employees', 'ssn', '''***-**-'' || substr(ssn, -4)') AS ssn_mask;
+SELECT sec_set_column_mask('employees', 'email', 'substr(email, instr(email, ''@'') + 1)') AS email_mask;
+SELECT sec_set_column_mask('employees', 'salary', '(salary / 10000) * 10000') AS salary_mask;
+
+.print ------------------------------------------------------------
+.print [Unreadable columns are shown through their mask]
+.output /dev/null
+SELECT sec_set_attr('role', 'user');
+SELECT sec_refresh_views();
+.output stdout
+SELECT * FROM employees;
+
+.print ------------------------------------------------------------
+.print [Readers of the label see the real values]
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('role', 'hr');
+SELECT sec_refresh_views();
+.output stdout
+SELECT * FROM employees;
+
+.print ------------------------------------------------------------
+.print [Masked values cannot be written back]
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('role', 'user');
+SELECT sec_refresh_views();
+.output stdout
+UPDATE employees SET name = 'Alicia' WHERE id = 1;
+SELECT id, name, ssn FROM __sec_employees;
+UPDATE employees SET ssn = '000-00-0000' WHERE id = 1;
+INSERT INTO employees (id, name, ssn) VALUES (3, 'Carol', '111-11-1111');
+
+.print ------------------------------------------------------------
+.print [Removing a mask hides the column again]
+SELECT sec_set_column_mask('employees', 'salary', NULL) AS salary_mask;
+SELECT sec_refresh_views() AS refreshed;
+SELECT * FROM employees;
+
+.print ------------------------------------------------------------
+.print [Invalid masks are rejected]
+SELECT sec_set_column_mask('employees', 'name', 'no_such_column || 1');
+SELECT sec_set_column_mask('employees', 'name', 'substr(ssn, -4)');
+SELECT sec_set_column_mask('employees', 'name', '1) FROM __sec_employees; DROP TABLE __sec_employees; --');
+SELECT sec_set_column_mask('employees', 'phone', '''x''');
+SELECT sec_set_column_mask('staff', 'name', '''x''');
@@ -0,0 +1,26 @@
+.mode list
+.print ------------------------------------------------------------
+.print [Loading the extension again keeps the context]
+SELECT sec_set_attr('role', 'user') AS attr;
+.load ./target/debug/libsqlsec
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Context on a second connection]
+.connection 1
+.load ./target/debug/libsqlsec
+SELECT sec_set_attr('role', 'admin') AS attr;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Reopened connection starts empty]
+.connection 0
+.connection close 1
+.connection 1
+.load ./target/debug/libsqlsec
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [First connection is untouched]
+.connection 0
+SELECT sec_context_json() AS ctx;
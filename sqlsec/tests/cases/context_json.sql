@@ -0,0 +1,35 @@
+.print ------------------------------------------------------------
+.print [Empty context]
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Replace from claims]
+SELECT sec_set_attr('legacy', 'x') AS attr;
+SELECT sec_set_context_json('{"role":"admin","team":["b","a"]}') AS attrs_set;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Merge]
+SELECT sec_set_context_json('{"team":"c","tenant_id":"acme"}', 1) AS attrs_set;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Pushed frame]
+.output /dev/null
+SELECT sec_push_context();
+.output stdout
+SELECT sec_set_context_json('{"role":"auditor"}') AS attrs_set;
+SELECT sec_context_json() AS ctx;
+.output /dev/null
+SELECT sec_pop_context();
+.output stdout
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Invalid input]
+SELECT sec_set_context_json('["admin"]');
+SELECT sec_set_context_json('{"user":{"role":"admin"}}');
+SELECT sec_set_context_json('{"level":3}');
+SELECT sec_set_context_json('{"team":["a",null]}');
+SELECT sec_set_context_json('{role:admin}');
+SELECT sec_context_json() AS unchanged;
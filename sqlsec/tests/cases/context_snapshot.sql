@@ -0,0 +1,33 @@
+.mode list
+.print ------------------------------------------------------------
+.print [Snapshot holds every frame]
+SELECT sec_set_attr('team', 'b') AS attr;
+SELECT sec_set_attr('team', 'a') AS attr;
+SELECT sec_push_context('request') AS pushed;
+SELECT sec_set_attr('role', 'admin') AS attr;
+CREATE TEMP TABLE saved AS SELECT sec_snapshot_context() AS token;
+SELECT token FROM saved;
+
+.print ------------------------------------------------------------
+.print [Mutate the context after the snapshot]
+SELECT sec_set_attr('role', 'auditor') AS attr;
+SELECT sec_push_context() AS pushed;
+SELECT sec_remove_attr('team') AS removed;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Restore replaces the stack and stales the views]
+SELECT value AS generation FROM sec_meta WHERE key = 'generation';
+SELECT sec_restore_context_snapshot(token) AS frames FROM saved;
+SELECT value AS generation FROM sec_meta WHERE key = 'generation';
+SELECT sec_context_json() AS ctx;
+SELECT sec_snapshot_context() = token AS identical FROM saved;
+
+.print ------------------------------------------------------------
+.print [Frame names survive the round trip]
+SELECT sec_pop_context('request') AS popped;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Malformed tokens are rejected]
+SELECT sec_restore_context_snapshot('[]') AS frames;
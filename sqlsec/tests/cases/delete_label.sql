@@ -0,0 +1,59 @@
+.output /dev/null
+SELECT sec_define_label('true');
+SELECT sec_define_label('role=analyst|role=admin');
+SELECT sec_define_label('role=admin');
+
+CREATE TABLE __sec_reports (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    title        TEXT
+);
+INSERT INTO __sec_reports VALUES
+    (1, 2, 'Q1 revenue'),
+    (2, 2, 'Q2 revenue'),
+    (3, 1, 'Press release');
+
+CREATE TABLE __sec_notes (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    body         TEXT
+);
+INSERT INTO __sec_notes VALUES (1, 2, 'scratch');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [The delete label is stored with the registration]
+SELECT sec_register_table('reports', '__sec_reports', 'row_label_id', NULL, NULL, 3) AS registered;
+SELECT sec_register_table('notes', '__sec_notes', 'row_label_id', NULL, NULL) AS registered;
+SELECT logical_name, delete_label_id FROM sec_tables ORDER BY logical_name;
+
+.print ------------------------------------------------------------
+.print [Analysts read every row but cannot delete]
+.output /dev/null
+SELECT sec_set_attr('role', 'analyst');
+SELECT sec_refresh_views();
+.output stdout
+SELECT id, title FROM reports ORDER BY id;
+DELETE FROM reports WHERE id = 1;
+SELECT COUNT(*) AS remaining FROM __sec_reports;
+
+.print ------------------------------------------------------------
+.print [Tables without a delete label still follow row visibility]
+DELETE FROM notes WHERE id = 1;
+SELECT COUNT(*) AS remaining FROM __sec_notes;
+
+.print ------------------------------------------------------------
+.print [Admins can delete]
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('role', 'admin');
+SELECT sec_refresh_views();
+.output stdout
+DELETE FROM reports WHERE id = 1;
+SELECT id, title FROM __sec_reports ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Delete labels are references]
+SELECT sec_delete_label(3);
+SELECT sec_gc_labels() AS removed;
+SELECT id FROM sec_labels ORDER BY id;
@@ -0,0 +1,44 @@
+.output /dev/null
+
+CREATE TABLE __sec_docs (
+    id            INTEGER PRIMARY KEY,
+    row_label_id  INTEGER NOT NULL,
+    title         TEXT,
+    body          TEXT
+);
+
+INSERT INTO __sec_docs VALUES (1, 1, 'Welcome', 'hello');
+
+.load ./target/debug/libsqlsec
+
+SELECT sec_define_label('true');                       -- id = 1, used by rows
+SELECT sec_define_label('role=admin');                 -- id = 2, table label
+SELECT sec_define_label('role=auditor');               -- id = 3, column label
+SELECT sec_define_label('role=intern');                -- id = 4, unused
+SELECT sec_define_label('role=contractor');            -- id = 5, unused
+
+SELECT sec_register_table('docs', '__sec_docs', 'row_label_id', 2, NULL);
+UPDATE sec_columns SET read_label_id = 3
+  WHERE logical_table = 'docs' AND column_name = 'body';
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Delete referenced labels]
+SELECT sec_delete_label(2);
+SELECT sec_delete_label(3);
+SELECT sec_delete_label(1);
+
+.print ------------------------------------------------------------
+.print [Row references are only checked when scanning]
+SELECT sec_delete_label(1, 0) AS deleted;
+INSERT INTO sec_labels (id, expr) VALUES (1, 'true');
+
+.print ------------------------------------------------------------
+.print [Delete an unreferenced label]
+SELECT sec_delete_label(4) AS deleted;
+SELECT sec_delete_label(4);
+
+.print ------------------------------------------------------------
+.print [Garbage collect]
+SELECT sec_gc_labels() AS removed;
+SELECT id, expr FROM sec_labels ORDER BY id;
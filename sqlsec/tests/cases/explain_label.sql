@@ -0,0 +1,21 @@
+.output /dev/null
+SELECT sec_define_level('clearance', 'confidential', 1);
+SELECT sec_define_level('clearance', 'secret', 2);
+SELECT sec_define_label('role=admin&clearance>=secret');   -- id = 1
+SELECT sec_clear_context();
+SELECT sec_set_attr('role', 'admin');
+SELECT sec_set_attr('clearance', 'confidential');
+.output stdout
+.mode list
+
+.print ------------------------------------------------------------
+.print [Structure]
+SELECT sec_explain_label(1);
+
+.print ------------------------------------------------------------
+.print [Evaluated against the current context]
+SELECT sec_explain_label(1, 1);
+
+.print ------------------------------------------------------------
+.print [Unknown label]
+SELECT sec_explain_label(99);
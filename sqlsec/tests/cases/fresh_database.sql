@@ -0,0 +1,30 @@
+.print ------------------------------------------------------------
+.print [Loading creates the bookkeeping tables]
+SELECT key, value FROM sec_meta
+WHERE key IN ('generation', 'last_refresh_generation', 'views_initialized')
+ORDER BY key;
+
+.output /dev/null
+CREATE TABLE __sec_notes (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    body         TEXT
+);
+INSERT INTO __sec_notes VALUES
+    (1, NULL, 'public'),
+    (2, sec_define_label('role=admin'), 'admin only');
+SELECT sec_register_table('notes', '__sec_notes', 'row_label_id', NULL, NULL);
+.output stdout
+
+.print ------------------------------------------------------------
+.print [SET CONTEXT, REFRESH, SELECT]
+SELECT sec_set_attr('role', 'admin') AS attr;
+SELECT value AS generation FROM sec_meta WHERE key = 'generation';
+SELECT sec_refresh_views() AS refreshed;
+SELECT sec_assert_fresh() AS fresh;
+SELECT id, body FROM notes;
+
+.print ------------------------------------------------------------
+.print [Missing bookkeeping is reported, not defaulted]
+DELETE FROM sec_meta WHERE key = 'last_refresh_generation';
+SELECT sec_assert_fresh() AS fresh;
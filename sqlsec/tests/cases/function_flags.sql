@@ -0,0 +1,57 @@
+.output /dev/null
+SELECT sec_define_label('true');
+SELECT sec_define_label('role=admin');
+
+CREATE TABLE __sec_docs (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    title        TEXT
+);
+INSERT INTO __sec_docs VALUES (1, 1, 'public'), (2, 2, 'admin only');
+SELECT sec_register_table('docs', '__sec_docs', 'row_label_id', NULL, NULL);
+SELECT sec_set_attr('role', 'user');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Views in the schema cannot change the context]
+CREATE VIEW escalate AS SELECT sec_set_attr('role', 'admin') AS x;
+SELECT * FROM escalate;
+SELECT sec_get_attrs('role') AS role;
+
+.print ------------------------------------------------------------
+.print [Neither can triggers]
+CREATE TABLE audit (msg TEXT);
+CREATE TRIGGER audit_escalate AFTER INSERT ON audit
+BEGIN
+    SELECT sec_set_attr('role', 'admin');
+    SELECT sec_refresh_views();
+END;
+INSERT INTO audit VALUES ('hello');
+SELECT sec_get_attrs('role') AS role;
+
+.print ------------------------------------------------------------
+.print [Label changes and registration are direct-only too]
+CREATE VIEW relabel AS SELECT sec_define_label('role=user') AS id;
+SELECT * FROM relabel;
+CREATE VIEW reregister AS
+SELECT sec_register_table('docs', '__sec_docs', 'row_label_id', NULL, NULL) AS ok;
+SELECT * FROM reregister;
+
+.print ------------------------------------------------------------
+.print [Secure views still work with trusted_schema off]
+PRAGMA trusted_schema = OFF;
+SELECT * FROM docs;
+INSERT INTO docs (id, title) VALUES (3, 'inserted');
+SELECT id, title FROM docs ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [so do schema views filtering by label]
+CREATE VIEW labelled AS
+SELECT id, sec_label_visible(row_label_id) AS visible FROM __sec_docs;
+SELECT * FROM labelled ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [but not ones reading the context]
+CREATE VIEW whoami AS SELECT sec_get_attr('role') AS role;
+SELECT * FROM whoami;
@@ -0,0 +1,30 @@
+.print ------------------------------------------------------------
+.print [Unset]
+SELECT sec_get_attr('tenant_id') IS NULL AS is_null, sec_get_attrs('tenant_id') AS attrs;
+
+.output /dev/null
+SELECT sec_set_attr('tenant_id', 'acme');
+SELECT sec_set_attr('role', 'auditor');
+SELECT sec_set_attr('role', 'admin');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Single and multiple values]
+SELECT sec_get_attr('tenant_id') AS tenant_id, sec_get_attr('TENANT_ID') AS upper;
+SELECT sec_get_attrs('role') AS roles;
+SELECT sec_get_attr('role');
+
+.print ------------------------------------------------------------
+.print [Pushed frame]
+.output /dev/null
+SELECT sec_push_context();
+SELECT sec_set_attr('tenant_id', 'globex');
+.output stdout
+SELECT sec_get_attrs('tenant_id') AS tenant_ids;
+
+.print ------------------------------------------------------------
+.print [After pop]
+.output /dev/null
+SELECT sec_pop_context();
+.output stdout
+SELECT sec_get_attr('tenant_id') AS tenant_id;
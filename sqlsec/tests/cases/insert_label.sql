@@ -0,0 +1,60 @@
+.output /dev/null
+SELECT sec_define_label('role=clerk');
+SELECT sec_define_label('role=manager');
+
+CREATE TABLE __sec_invoices (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    amount       INTEGER
+);
+SELECT sec_register_table('invoices', '__sec_invoices', 'row_label_id', NULL, 1);
+
+SELECT sec_set_attr('role', 'clerk');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Rows inserted through the view get the insert label]
+INSERT INTO invoices (id, amount) VALUES (1, 100);
+SELECT id, amount FROM invoices;
+SELECT id, row_label_id FROM __sec_invoices;
+
+.print ------------------------------------------------------------
+.print [Legacy insert_policy_expr is migrated on load]
+.open "file:insert_label?mode=memory&cache=shared"
+CREATE TABLE sec_tables (
+    logical_name       TEXT PRIMARY KEY,
+    physical_name      TEXT NOT NULL,
+    row_label_col      TEXT NOT NULL,
+    table_label_id     INTEGER,
+    insert_policy_expr TEXT
+);
+CREATE TABLE __sec_ledger (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    entry        TEXT
+);
+INSERT INTO sec_tables VALUES ('ledger', '__sec_ledger', 'row_label_id', NULL, 'role = manager');
+CREATE TABLE sec_columns (
+    logical_table   TEXT NOT NULL,
+    column_name     TEXT NOT NULL,
+    read_label_id   INTEGER,
+    update_label_id INTEGER,
+    PRIMARY KEY (logical_table, column_name)
+);
+INSERT INTO sec_columns VALUES
+    ('ledger', 'id', NULL, NULL),
+    ('ledger', 'row_label_id', NULL, NULL),
+    ('ledger', 'entry', NULL, NULL);
+.load ./target/debug/libsqlsec
+SELECT name FROM pragma_table_info('sec_tables') ORDER BY cid;
+SELECT t.logical_name, l.expr AS insert_label
+FROM sec_tables t JOIN sec_labels l ON l.id = t.insert_label_id;
+
+.output /dev/null
+SELECT sec_set_attr('role', 'manager');
+SELECT sec_refresh_views();
+.output stdout
+INSERT INTO ledger (id, entry) VALUES (1, 'opening balance');
+SELECT l.id, s.expr AS row_label
+FROM __sec_ledger l JOIN sec_labels s ON s.id = l.row_label_id;
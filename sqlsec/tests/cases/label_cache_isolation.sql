@@ -0,0 +1,22 @@
+.output /dev/null
+SELECT sec_define_label('role=admin');     -- id = 1 in connection 0
+
+.connection 1
+.load ./target/debug/libsqlsec
+SELECT sec_define_label('role=auditor');   -- id = 1 in connection 1
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Connection 1: label 1 is role=auditor]
+SELECT sec_set_attr('role', 'admin') AS attr;
+SELECT sec_label_visible(1) AS admin_visible;
+SELECT sec_set_attr('role', 'auditor') AS attr;
+SELECT sec_label_visible(1) AS auditor_visible;
+
+.connection 0
+.print ------------------------------------------------------------
+.print [Connection 0: label 1 is role=admin]
+SELECT sec_set_attr('role', 'auditor') AS attr;
+SELECT sec_label_visible(1) AS auditor_visible;
+SELECT sec_set_attr('role', 'admin') AS attr;
+SELECT sec_label_visible(1) AS admin_visible;
@@ -0,0 +1,28 @@
+.output /dev/null
+SELECT sec_define_level('clearance', 'public', 0);
+SELECT sec_define_level('clearance', 'confidential', 1);
+SELECT sec_define_level('clearance', 'secret', 2);
+SELECT sec_define_label('clearance>=secret');                  -- id = 1
+SELECT sec_define_label('clearance>=confidential');            -- id = 2
+SELECT sec_define_label('role=admin&clearance>=secret');       -- id = 3
+SELECT sec_define_label('role=admin|role=auditor');            -- id = 4
+SELECT sec_define_label('true');                               -- id = 5
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Level comparisons]
+SELECT sec_label_dominates(1, 2) AS secret_dominates_confidential;
+SELECT sec_label_dominates(2, 1) AS confidential_dominates_secret;
+
+.print ------------------------------------------------------------
+.print [Clause structure]
+SELECT sec_label_dominates(3, 2) AS admin_secret_dominates_confidential;
+SELECT sec_label_dominates(3, 4) AS admin_secret_dominates_admin_or_auditor;
+SELECT sec_label_dominates(4, 3) AS admin_or_auditor_dominates_admin_secret;
+SELECT sec_label_dominates(4, 5) AS anything_dominates_true;
+SELECT sec_label_dominates(5, 4) AS true_dominates_admin_or_auditor;
+
+.print ------------------------------------------------------------
+.print [Unparseable label]
+INSERT INTO sec_labels (id, expr) VALUES (6, 'role=');
+SELECT sec_label_dominates(6, 1);
@@ -0,0 +1,21 @@
+.output /dev/null
+SELECT sec_define_label('role=admin');     -- id = 1
+SELECT sec_set_attr('role', 'auditor');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Before edit]
+SELECT sec_label_visible(1) AS visible;
+
+.print ------------------------------------------------------------
+.print [After UPDATE sec_labels (automatic invalidation)]
+UPDATE sec_labels SET expr = 'role=auditor' WHERE id = 1;
+SELECT sec_label_visible(1) AS visible;
+
+.print ------------------------------------------------------------
+.print [After edit behind the triggers' back]
+DROP TRIGGER temp.sec_labels_invalidate_upd;
+UPDATE sec_labels SET expr = 'role=admin' WHERE id = 1;
+SELECT sec_label_visible(1) AS stale_visible;
+SELECT sec_invalidate_labels(1) AS invalidated;
+SELECT sec_label_visible(1) AS visible;
@@ -0,0 +1,25 @@
+.print ------------------------------------------------------------
+.print [Parse errors report position and expected token]
+SELECT sec_define_label('role admin');
+SELECT sec_define_label('role=admin&(team=finance|');
+SELECT sec_define_label('dept=''Human Resources');
+SELECT sec_define_label('role in admin');
+
+.print ------------------------------------------------------------
+.print [Default limits]
+SELECT key, value FROM sec_meta WHERE key LIKE 'max_%' ORDER BY key;
+
+.print ------------------------------------------------------------
+.print [Configured limits]
+UPDATE sec_meta SET value = 20 WHERE key = 'max_label_length';
+UPDATE sec_meta SET value = 2 WHERE key = 'max_label_clauses';
+UPDATE sec_meta SET value = 2 WHERE key = 'max_clause_requirements';
+SELECT sec_define_label('role=administrator&team=finance');
+SELECT sec_define_label('a=1&b=2&c=3');
+SELECT sec_define_label('a in (1, 2, 3)');
+SELECT sec_define_label('a=1&b in (2, 3)') AS id;
+
+.print ------------------------------------------------------------
+.print [Non-positive values disable a limit]
+UPDATE sec_meta SET value = 0 WHERE key = 'max_label_clauses';
+SELECT sec_define_label('a=1&b=2&c=3') AS id;
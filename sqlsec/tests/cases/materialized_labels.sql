@@ -0,0 +1,60 @@
+.output /dev/null
+SELECT sec_define_label('true');
+SELECT sec_define_label('role=sales');
+SELECT sec_define_label('role=hr');
+SELECT sec_define_label('role=sales|role=hr');
+
+CREATE TABLE __sec_customers (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT
+);
+CREATE INDEX customers_row_label ON __sec_customers (row_label_id);
+INSERT INTO __sec_customers VALUES
+    (1, 1,    'Public Co'),
+    (2, 2,    'Sales Lead'),
+    (3, 3,    'Payroll Ltd'),
+    (4, 4,    'Shared Inc'),
+    (5, NULL, 'Unlabelled');
+
+SELECT sec_register_table('customers', '__sec_customers', 'row_label_id', NULL, NULL);
+UPDATE sec_tables SET materialize_labels = 1 WHERE logical_name = 'customers';
+SELECT sec_set_attr('role', 'sales');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Visible label ids are materialized at refresh]
+SELECT id FROM _sec_visible_labels ORDER BY id;
+SELECT id, name FROM customers ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [The row filter can use an index on the row label column]
+EXPLAIN QUERY PLAN SELECT id, name FROM customers;
+
+.print ------------------------------------------------------------
+.print [The set is rebuilt on every refresh]
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('role', 'hr');
+.output stdout
+SELECT id, name FROM customers ORDER BY id;
+.output /dev/null
+SELECT sec_refresh_views();
+.output stdout
+SELECT id FROM _sec_visible_labels ORDER BY id;
+SELECT id, name FROM customers ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Writes through the view are unaffected]
+UPDATE customers SET name = 'Payroll Group' WHERE id = 3;
+DELETE FROM customers WHERE id = 2;
+SELECT id, name FROM __sec_customers ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Opting out goes back to per-row evaluation]
+.output /dev/null
+UPDATE sec_tables SET materialize_labels = 0 WHERE logical_name = 'customers';
+SELECT sec_refresh_views();
+.output stdout
+SELECT id, name FROM customers ORDER BY id;
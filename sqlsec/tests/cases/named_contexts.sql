@@ -0,0 +1,30 @@
+.output /dev/null
+SELECT sec_set_attr('role', 'user');
+SELECT sec_push_context('request');
+SELECT sec_set_attr('role', 'admin');
+SELECT sec_push_context('library');
+SELECT sec_set_attr('team', 'finance');
+SELECT sec_push_context();
+SELECT sec_set_attr('tenant_id', 'acme');
+.output stdout
+.mode list
+
+.print ------------------------------------------------------------
+.print [Nested frames]
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Pop a middle frame, discarding frames above it]
+SELECT sec_pop_context('library') AS popped;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Unknown or already popped name]
+SELECT sec_pop_context('library');
+SELECT sec_pop_context('missing');
+SELECT sec_context_json() AS unchanged;
+
+.print ------------------------------------------------------------
+.print [Pop the outer frame]
+SELECT sec_pop_context('request') AS popped;
+SELECT sec_context_json() AS ctx;
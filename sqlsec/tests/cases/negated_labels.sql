@@ -0,0 +1,48 @@
+.output /dev/null
+
+CREATE TABLE __sec_docs (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER NOT NULL,
+    title        TEXT
+);
+
+.load ./target/debug/libsqlsec
+
+SELECT sec_define_label('true');                               -- id = 1
+SELECT sec_define_label('!role=contractor');                   -- id = 2
+SELECT sec_define_label('!(role=contractor|role=intern)');     -- id = 3
+SELECT sec_register_table('docs', '__sec_docs', 'row_label_id', NULL, NULL);
+
+INSERT INTO __sec_docs VALUES (1, 1, 'handbook');
+INSERT INTO __sec_docs VALUES (2, 2, 'roadmap');
+INSERT INTO __sec_docs VALUES (3, 3, 'salaries');
+
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('role', 'employee');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [role=employee]
+SELECT id, title FROM docs ORDER BY id;
+
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('role', 'intern');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [role=intern]
+SELECT id, title FROM docs ORDER BY id;
+
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('role', 'contractor');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [role=contractor]
+SELECT id, title FROM docs ORDER BY id;
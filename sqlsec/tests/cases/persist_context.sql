@@ -0,0 +1,58 @@
+.mode list
+.open "file:persist_context?mode=memory&cache=shared"
+.load ./target/debug/libsqlsec
+
+.print ------------------------------------------------------------
+.print [Persistence is off by default]
+SELECT sec_restore_context('sess-1') AS restored;
+SELECT sec_set_attr('tenant', 'acme') AS attr;
+SELECT COUNT(*) AS sessions FROM sec_sessions;
+
+.print ------------------------------------------------------------
+.print [Every change is saved once enabled]
+SELECT sec_configure('persist_context', 1) AS persist;
+SELECT sec_set_attr('role', 'user') AS attr;
+SELECT session_id, context, expires_at - updated_at AS ttl FROM sec_sessions;
+SELECT sec_push_context() AS pushed;
+SELECT sec_set_attr('role', 'admin') AS attr;
+SELECT context FROM sec_sessions;
+SELECT sec_pop_context() AS popped;
+SELECT context FROM sec_sessions;
+
+.print ------------------------------------------------------------
+.print [Restored on another connection]
+.connection 1
+.open "file:persist_context?mode=memory&cache=shared"
+.load ./target/debug/libsqlsec
+SELECT sec_context_json() AS ctx;
+SELECT sec_restore_context('sess-1') AS restored;
+SELECT sec_context_json() AS ctx;
+SELECT sec_assert_fresh() AS fresh;
+
+.print ------------------------------------------------------------
+.print [Unknown session starts empty]
+SELECT sec_restore_context('sess-2') AS restored;
+SELECT sec_context_json() AS ctx;
+SELECT session_id, context FROM sec_sessions ORDER BY session_id;
+
+.print ------------------------------------------------------------
+.print [Expired session is not restored]
+UPDATE sec_sessions SET expires_at = unixepoch() - 1 WHERE session_id = 'sess-1';
+SELECT sec_restore_context('sess-1') AS restored;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [No expiry when session_ttl is 0]
+SELECT sec_configure('session_ttl', 0) AS ttl;
+SELECT sec_set_attr('role', 'auditor') AS attr;
+SELECT session_id, expires_at IS NULL AS never_expires FROM sec_sessions ORDER BY session_id;
+
+.print ------------------------------------------------------------
+.print [Purge idle sessions]
+UPDATE sec_sessions SET updated_at = updated_at - 1000 WHERE session_id = 'sess-2';
+SELECT sec_purge_sessions(500) AS purged;
+SELECT session_id FROM sec_sessions;
+
+.print ------------------------------------------------------------
+.print [Only known settings can be configured]
+SELECT sec_configure('generation', 0) AS generation;
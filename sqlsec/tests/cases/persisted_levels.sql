@@ -0,0 +1,21 @@
+.output /dev/null
+
+-- Levels written by an earlier connection, without going through
+-- sec_define_level in this process
+INSERT INTO sec_levels VALUES ('clearance', 'public', 0);
+INSERT INTO sec_levels VALUES ('clearance', 'confidential', 1);
+INSERT INTO sec_levels VALUES ('clearance', 'secret', 2);
+
+-- Reloading the extension picks them up
+.load ./target/debug/libsqlsec
+
+SELECT sec_define_label('clearance>=secret');   -- id = 1
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Levels loaded at init]
+SELECT sec_clear_context() AS cleared;
+SELECT sec_set_attr('clearance', 'confidential') AS attr;
+SELECT sec_label_visible(1) AS confidential_sees_secret;
+SELECT sec_set_attr('clearance', 'secret') AS attr;
+SELECT sec_label_visible(1) AS secret_sees_secret;
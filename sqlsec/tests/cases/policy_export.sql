@@ -0,0 +1,124 @@
+.output /dev/null
+SELECT sec_define_level('clearance', 'public', 0);
+SELECT sec_define_level('clearance', 'secret', 2);
+SELECT sec_define_label('role = hr');
+SELECT sec_define_label('clearance>=secret');
+SELECT sec_define_label('role=hr|role=admin');
+SELECT sec_define_label('role=admin');
+
+CREATE TABLE __sec_staff (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT,
+    salary       INTEGER
+);
+CREATE TABLE __sec_notes (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    body         TEXT
+);
+SELECT sec_register_table('staff', '__sec_staff', 'row_label_id', 3, 1, 4);
+SELECT sec_register_table('notes', '__sec_notes', 'row_label_id', NULL, NULL);
+UPDATE sec_columns SET read_label_id = 2 WHERE logical_table = 'staff' AND column_name = 'salary';
+SELECT sec_set_column_mask('staff', 'salary', '0');
+
+INSERT INTO __sec_staff VALUES (1, 1, 'alice', 100), (2, 2, 'bob', 200), (3, 4, 'carol', 300);
+INSERT INTO __sec_notes VALUES (1, 1, 'hr note'), (2, 4, 'admin note');
+
+SELECT sec_set_attr('role', 'hr');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Source database as role=hr]
+SELECT * FROM staff ORDER BY id;
+SELECT * FROM notes ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [The export names labels by canonical expression]
+SELECT json_extract(sec_export_policies(), '$.labels') AS labels;
+SELECT json_extract(sec_export_policies(), '$.tables[1]') AS staff;
+
+.headers off
+.mode list
+.once target/policy_export.json
+SELECT sec_export_policies();
+.headers on
+.mode column
+
+.print ------------------------------------------------------------
+.print [Import into a fresh database with other label ids]
+.open :memory:
+.load ./target/debug/libsqlsec
+.output /dev/null
+SELECT sec_define_label('role=admin');
+SELECT sec_define_label('role = hr');
+CREATE TABLE __sec_staff (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT,
+    salary       INTEGER
+);
+CREATE TABLE __sec_notes (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    body         TEXT
+);
+.output stdout
+SELECT sec_import_policies(readfile('target/policy_export.json'), 'replace') AS imported;
+SELECT id, expr FROM sec_labels ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Exporting again gives the same document]
+SELECT sec_export_policies() = trim(readfile('target/policy_export.json'), char(10)) AS same;
+
+.print ------------------------------------------------------------
+.print [Same rows under the same labels are visible as in the source]
+.output /dev/null
+INSERT INTO __sec_staff VALUES
+    (1, (SELECT id FROM sec_labels WHERE expr = 'role=hr'), 'alice', 100),
+    (2, (SELECT id FROM sec_labels WHERE expr = 'clearance>=secret'), 'bob', 200),
+    (3, (SELECT id FROM sec_labels WHERE expr = 'role=admin'), 'carol', 300);
+INSERT INTO __sec_notes VALUES
+    (1, (SELECT id FROM sec_labels WHERE expr = 'role=hr'), 'hr note'),
+    (2, (SELECT id FROM sec_labels WHERE expr = 'role=admin'), 'admin note');
+SELECT sec_set_attr('role', 'hr');
+SELECT sec_refresh_views();
+.output stdout
+SELECT * FROM staff ORDER BY id;
+SELECT * FROM notes ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Levels come across with the tables]
+.output /dev/null
+SELECT sec_set_attr('clearance', 'secret');
+SELECT sec_refresh_views();
+.output stdout
+SELECT * FROM staff ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Merge keeps tables the document does not mention]
+.output /dev/null
+CREATE TABLE __sec_extra (id INTEGER PRIMARY KEY, row_label_id INTEGER);
+SELECT sec_register_table('extra', '__sec_extra', 'row_label_id', NULL, NULL);
+.output stdout
+SELECT sec_import_policies(readfile('target/policy_export.json'), 'merge') AS imported;
+SELECT logical_name FROM sec_tables ORDER BY logical_name;
+SELECT sec_import_policies(readfile('target/policy_export.json'), 'replace') AS imported;
+SELECT logical_name FROM sec_tables ORDER BY logical_name;
+
+.print ------------------------------------------------------------
+.print [A missing physical table fails the whole import]
+.output /dev/null
+DROP TABLE __sec_notes;
+.output stdout
+SELECT sec_import_policies(readfile('target/policy_export.json'), 'replace');
+SELECT logical_name FROM sec_tables ORDER BY logical_name;
+
+.print ------------------------------------------------------------
+.print [Bad documents and modes]
+SELECT sec_import_policies('{"version": 1}', 'overwrite');
+SELECT sec_import_policies('{"version": 2}', 'merge');
+SELECT sec_import_policies('not json', 'merge');
+SELECT sec_import_policies('{"version": 1, "labels": [{"expr": "role = hr"}]}', 'merge');
+SELECT sec_import_policies(NULL, 'merge');
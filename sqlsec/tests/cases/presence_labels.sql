@@ -0,0 +1,47 @@
+.output /dev/null
+
+CREATE TABLE __sec_orders (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER NOT NULL,
+    item         TEXT
+);
+
+.load ./target/debug/libsqlsec
+
+SELECT sec_define_label('true');          -- id = 1
+SELECT sec_define_label('tenant_id=*');   -- id = 2
+SELECT sec_define_label('!tenant_id=*');  -- id = 3
+SELECT sec_register_table('orders', '__sec_orders', 'row_label_id', NULL, NULL);
+
+INSERT INTO __sec_orders VALUES (1, 1, 'public');
+INSERT INTO __sec_orders VALUES (2, 2, 'tenant-only');
+INSERT INTO __sec_orders VALUES (3, 3, 'anonymous-only');
+
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [No tenant_id]
+SELECT id, item FROM orders ORDER BY id;
+
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('tenant_id', '');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [tenant_id set to empty string]
+SELECT id, item FROM orders ORDER BY id;
+
+.output /dev/null
+SELECT sec_clear_context();
+SELECT sec_set_attr('tenant_id', 'acme');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [tenant_id=acme]
+SELECT id, item FROM orders ORDER BY id;
@@ -0,0 +1,35 @@
+.output /dev/null
+SELECT sec_define_label('role=clerk');
+SELECT sec_define_label('role=manager');
+
+CREATE TABLE __sec_plain (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    body         TEXT
+);
+CREATE TABLE __sec_filed (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    body         TEXT
+);
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Without an insert label]
+SELECT sec_register_table('plain', '__sec_plain', 'row_label_id', NULL, NULL) AS registered;
+
+.print ------------------------------------------------------------
+.print [With table and insert labels]
+SELECT sec_register_table('filed', '__sec_filed', 'row_label_id', 2, 1) AS registered;
+
+.print ------------------------------------------------------------
+.print [Stored registrations]
+SELECT logical_name, physical_name, row_label_col, table_label_id, insert_label_id
+FROM sec_tables
+ORDER BY logical_name;
+SELECT logical_table, column_name FROM sec_columns ORDER BY logical_table, column_name;
+
+.print ------------------------------------------------------------
+.print [Re-registering replaces the labels]
+SELECT sec_register_table('filed', '__sec_filed', 'row_label_id', NULL, 2) AS registered;
+SELECT logical_name, table_label_id, insert_label_id FROM sec_tables WHERE logical_name = 'filed';
@@ -0,0 +1,25 @@
+.output /dev/null
+SELECT sec_set_attr('role', 'admin');
+SELECT sec_set_attr('role', 'auditor');
+SELECT sec_set_attr('team', 'finance');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Remove one of two values]
+SELECT sec_remove_attr('role', 'admin') AS removed;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Remove a whole key]
+SELECT sec_remove_attr('TEAM') AS removed;
+SELECT sec_context_json() AS ctx;
+
+.print ------------------------------------------------------------
+.print [Remove missing key or value]
+SELECT sec_remove_attr('tenant_id') AS removed;
+SELECT sec_remove_attr('role', 'admin') AS removed;
+
+.print ------------------------------------------------------------
+.print [Removing the last value drops the key]
+SELECT sec_remove_attr('role', 'auditor') AS removed;
+SELECT sec_context_json() AS ctx;
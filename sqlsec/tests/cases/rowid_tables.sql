@@ -0,0 +1,58 @@
+.output /dev/null
+CREATE TABLE __sec_notes (
+    row_label_id INTEGER,
+    author       TEXT,
+    body         TEXT
+);
+
+SELECT sec_define_label('true');
+SELECT sec_define_label('role=admin');
+
+INSERT INTO __sec_notes VALUES
+    (1, 'alice', 'public note'),
+    (2, 'bob',   'admin note'),
+    (1, 'carol', 'another public note');
+
+SELECT sec_register_table('notes', '__sec_notes', 'row_label_id', NULL, NULL);
+SELECT sec_set_attr('role', 'user');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Table without a PRIMARY KEY is addressed by rowid]
+SELECT author, body, __sec_rowid FROM notes;
+
+.print ------------------------------------------------------------
+.print [UPDATE through the view]
+UPDATE notes SET body = 'edited' WHERE author = 'carol';
+SELECT rowid, author, body FROM __sec_notes;
+
+.print ------------------------------------------------------------
+.print [Hidden rows are not touched]
+UPDATE notes SET body = 'overwritten';
+DELETE FROM notes WHERE author = 'alice';
+SELECT rowid, author, body FROM __sec_notes;
+
+.print ------------------------------------------------------------
+.print [INSERT is unchanged]
+INSERT INTO notes (author, body) VALUES ('dave', 'new note');
+SELECT author, body FROM notes;
+
+.print ------------------------------------------------------------
+.print [A column named rowid falls back to _rowid_]
+.output /dev/null
+CREATE TABLE __sec_legacy (
+    rowid        TEXT,
+    row_label_id INTEGER,
+    body         TEXT
+);
+INSERT INTO __sec_legacy VALUES ('r-1', 1, 'first'), ('r-2', 1, 'second');
+SELECT sec_register_table('legacy', '__sec_legacy', 'row_label_id', NULL, NULL);
+SELECT sec_refresh_views();
+.output stdout
+DELETE FROM legacy WHERE rowid = 'r-1';
+SELECT _rowid_ AS internal_rowid, rowid, body FROM __sec_legacy;
+
+.print ------------------------------------------------------------
+.print [UPDATE of the rowid column is refused]
+UPDATE notes SET __sec_rowid = 99;
@@ -0,0 +1,81 @@
+.output /dev/null
+CREATE TABLE departments (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+CREATE TABLE employees (
+    id      INTEGER PRIMARY KEY,
+    email   TEXT NOT NULL UNIQUE,
+    dept_id INTEGER REFERENCES departments (id),
+    salary  INTEGER CHECK (salary >= 0)
+);
+CREATE INDEX employees_dept ON employees (dept_id);
+CREATE TABLE hires (email TEXT);
+CREATE TRIGGER employees_hired AFTER INSERT ON employees
+BEGIN
+    INSERT INTO hires (email) VALUES (NEW.email);
+END;
+
+INSERT INTO departments VALUES (1, 'eng');
+INSERT INTO employees VALUES (1, 'alice@example.com', 1, 100), (2, 'bob@example.com', 1, 200);
+DELETE FROM hires;
+.output stdout
+
+.print ------------------------------------------------------------
+.print [The table moves behind a view of the same name]
+SELECT sec_secure_existing_table('employees', 'role=staff') AS default_label;
+SELECT type, name, tbl_name FROM sqlite_master
+WHERE tbl_name LIKE '%employees' ORDER BY type, name;
+SELECT logical_name, physical_name, row_label_col FROM sec_tables;
+
+.print ------------------------------------------------------------
+.print [Existing rows carry the default label]
+SELECT id, email, row_label_id FROM __sec_employees ORDER BY id;
+SELECT COUNT(*) AS visible FROM employees;
+.output /dev/null
+SELECT sec_set_attr('role', 'staff');
+SELECT sec_refresh_views();
+.output stdout
+SELECT id, email, dept_id, salary, row_label_id FROM employees ORDER BY id;
+
+.print ------------------------------------------------------------
+.print [Constraints, indexes and triggers still apply]
+INSERT INTO employees (id, email, dept_id, salary) VALUES (3, 'carol@example.com', 1, 300);
+SELECT email FROM hires;
+INSERT INTO employees (id, email, dept_id, salary) VALUES (4, 'alice@example.com', 1, 10);
+INSERT INTO employees (id, email, dept_id, salary) VALUES (5, 'dave@example.com', 1, -1);
+EXPLAIN QUERY PLAN SELECT id FROM __sec_employees WHERE dept_id = 1;
+PRAGMA foreign_key_list(__sec_employees);
+
+.print ------------------------------------------------------------
+.print [Rows written straight to the physical table get the default label]
+INSERT INTO __sec_employees (id, email) VALUES (6, 'erin@example.com');
+SELECT id, row_label_id FROM __sec_employees WHERE id = 6;
+
+.print ------------------------------------------------------------
+.print [Failures leave the table as it was]
+CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+SELECT sec_secure_existing_table('notes', 'role=');
+SELECT name FROM sqlite_master WHERE name LIKE '%notes';
+CREATE TABLE keyed (k TEXT PRIMARY KEY, v TEXT) WITHOUT ROWID;
+SELECT sec_secure_existing_table('keyed', 'true');
+SELECT name FROM sqlite_master WHERE name LIKE '%keyed';
+
+.print ------------------------------------------------------------
+.print [Rolling back the caller's transaction undoes it all]
+BEGIN;
+SELECT sec_secure_existing_table('notes', 'true') AS default_label;
+SELECT name FROM sqlite_master WHERE name LIKE '%notes';
+ROLLBACK;
+SELECT name FROM sqlite_master WHERE name LIKE '%notes';
+SELECT COUNT(*) AS registered FROM sec_tables WHERE logical_name = 'notes';
+
+.print ------------------------------------------------------------
+.print [Errors]
+SELECT sec_secure_existing_table('employees', 'true');
+SELECT sec_secure_existing_table('missing', 'true');
+CREATE VIEW eng AS SELECT * FROM departments;
+SELECT sec_secure_existing_table('eng', 'true');
+CREATE TABLE labelled (id INTEGER PRIMARY KEY, row_label_id INTEGER);
+SELECT sec_secure_existing_table('labelled', 'true');
+CREATE TABLE clash (id INTEGER PRIMARY KEY);
+CREATE TABLE __sec_clash (id INTEGER PRIMARY KEY);
+SELECT sec_secure_existing_table('clash', 'true');
+SELECT sec_secure_existing_table(NULL, 'true');
@@ -0,0 +1,63 @@
+.output /dev/null
+CREATE TABLE __sec_customers (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT,
+    email        TEXT
+);
+INSERT INTO __sec_customers VALUES
+    (1, NULL, 'Alice', 'alice@example.com'),
+    (2, NULL, 'Bob',   'bob@corp.test');
+
+SELECT sec_register_table('customers', '__sec_customers', 'row_label_id', NULL, NULL);
+UPDATE sec_columns SET read_label_id = sec_define_label('role=sales')
+WHERE logical_table = 'customers' AND column_name = 'email';
+SELECT sec_set_attr('role', 'sales');
+SELECT sec_refresh_views();
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Nothing to sync after registration]
+SELECT sec_sync_columns('customers') AS changes;
+
+.print ------------------------------------------------------------
+.print [Added columns appear in the view on the next refresh]
+.output /dev/null
+ALTER TABLE __sec_customers ADD COLUMN phone TEXT;
+UPDATE __sec_customers SET phone = '555-0100' WHERE id = 1;
+SELECT sec_refresh_views();
+.output stdout
+SELECT * FROM customers;
+SELECT column_name, read_label_id FROM sec_columns
+WHERE logical_table = 'customers' ORDER BY column_name;
+
+.print ------------------------------------------------------------
+.print [New columns can be written through the view]
+INSERT INTO customers (id, name, email, phone) VALUES (3, 'Carol', 'carol@example.com', '555-0199');
+SELECT id, name, phone FROM __sec_customers;
+
+.print ------------------------------------------------------------
+.print [Dropped columns leave the view and sec_columns, labels and all]
+SELECT sec_drop_column('customers', 'email') AS changes;
+SELECT * FROM customers;
+SELECT column_name, read_label_id FROM sec_columns
+WHERE logical_table = 'customers' ORDER BY column_name;
+
+.print ------------------------------------------------------------
+.print [The row label column cannot be dropped]
+SELECT sec_drop_column('customers', 'row_label_id') AS changes;
+
+.print ------------------------------------------------------------
+.print [Only columns of the physical table can be dropped]
+SELECT sec_drop_column('customers', 'x"; DROP TABLE sec_tables; --') AS changes;
+SELECT logical_name FROM sec_tables;
+
+.print ------------------------------------------------------------
+.print [Syncing explicitly reports what changed]
+ALTER TABLE __sec_customers ADD COLUMN email TEXT;
+SELECT sec_sync_columns('customers') AS changes;
+SELECT sec_sync_columns('customers') AS changes;
+
+.print ------------------------------------------------------------
+.print [Unregistered tables are rejected]
+SELECT sec_sync_columns('nosuch');
@@ -0,0 +1,39 @@
+.output /dev/null
+SELECT sec_define_label('role=analyst');
+SELECT sec_define_level('clearance', 'confidential', 1);
+SELECT sec_define_level('clearance', 'secret', 2);
+SELECT sec_define_label('clearance>=secret');
+SELECT sec_set_attr('clearance', 'confidential');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Decisions follow the context]
+SELECT sec_label_visible(1) AS analyst, sec_label_visible(2) AS secret;
+.output /dev/null
+SELECT sec_set_attr('role', 'analyst');
+.output stdout
+SELECT sec_label_visible(1) AS analyst, sec_label_visible(2) AS secret;
+
+.print ------------------------------------------------------------
+.print [Popping a scope restores earlier decisions]
+.output /dev/null
+SELECT sec_push_context();
+SELECT sec_remove_attr('role');
+.output stdout
+SELECT sec_label_visible(1) AS analyst;
+.output /dev/null
+SELECT sec_pop_context();
+.output stdout
+SELECT sec_label_visible(1) AS analyst;
+
+.print ------------------------------------------------------------
+.print [Redefining a level changes decisions for the same context]
+.output /dev/null
+SELECT sec_define_level('clearance', 'confidential', 3);
+.output stdout
+SELECT sec_label_visible(2) AS secret;
+
+.print ------------------------------------------------------------
+.print [Repeated rows reuse one decision per label]
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+SELECT SUM(sec_label_visible(1 + i % 2)) AS visible_rows FROM n;
@@ -0,0 +1,85 @@
+.output /dev/null
+SELECT sec_define_label('role=sales');
+SELECT sec_define_label('role=admin');
+
+CREATE TABLE __sec_customers (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER,
+    name         TEXT
+);
+INSERT INTO __sec_customers VALUES
+    (1, 1, 'Acme'),
+    (2, 2, 'Globex'),
+    (3, NULL, 'Initech'),
+    (4, 1, 'Umbrella');
+SELECT sec_register_table('customers', '__sec_customers', 'row_label_id', NULL, NULL);
+
+CREATE TABLE __sec_visits (
+    region       TEXT,
+    day          INTEGER,
+    row_label_id INTEGER,
+    notes        TEXT,
+    PRIMARY KEY (region, day)
+);
+INSERT INTO __sec_visits VALUES
+    ('north', 1, 1, 'kickoff'),
+    ('north', 2, 2, 'audit'),
+    ('south', 1, NULL, 'demo');
+SELECT sec_register_table('visits', '__sec_visits', 'row_label_id', NULL, NULL);
+
+CREATE TABLE __sec_logs (
+    row_label_id INTEGER,
+    line         TEXT
+);
+INSERT INTO __sec_logs VALUES (1, 'started'), (2, 'rotated keys');
+SELECT sec_register_table('logs', '__sec_logs', 'row_label_id', NULL, NULL);
+
+CREATE TABLE __sec_secrets (
+    id           INTEGER PRIMARY KEY,
+    row_label_id INTEGER
+);
+INSERT INTO __sec_secrets VALUES (1, NULL);
+SELECT sec_register_table('secrets', '__sec_secrets', 'row_label_id', 2, NULL);
+
+SELECT sec_set_attr('role', 'sales');
+.output stdout
+
+.print ------------------------------------------------------------
+.print [Keys of the rows the context can see]
+SELECT key FROM sec_visible_rowids('customers');
+
+.print ------------------------------------------------------------
+.print [Joined into an application query]
+SELECT c.name FROM __sec_customers c
+JOIN sec_visible_rowids('customers') v ON v.key = c.id
+ORDER BY c.name;
+SELECT COUNT(*) AS visible FROM sec_visible_rowids('customers');
+
+.print ------------------------------------------------------------
+.print [Composite keys are JSON arrays in key order]
+SELECT key FROM sec_visible_rowids('visits');
+
+.print ------------------------------------------------------------
+.print [Tables without a PRIMARY KEY yield the rowid]
+SELECT key FROM sec_visible_rowids('logs');
+
+.print ------------------------------------------------------------
+.print [A hidden table yields nothing]
+SELECT COUNT(*) AS visible FROM sec_visible_rowids('secrets');
+
+.print ------------------------------------------------------------
+.print [Decisions follow the context]
+.output /dev/null
+SELECT sec_set_attr('role', 'admin');
+.output stdout
+SELECT key FROM sec_visible_rowids('customers');
+SELECT COUNT(*) AS visible FROM sec_visible_rowids('secrets');
+
+.print ------------------------------------------------------------
+.print [Stops early with LIMIT]
+SELECT key FROM sec_visible_rowids('customers') LIMIT 2;
+
+.print ------------------------------------------------------------
+.print [Errors]
+SELECT key FROM sec_visible_rowids('nothing');
+SELECT key FROM sec_visible_rowids;
@@ -1,25 +1,10 @@
-use std::{
-    collections::HashMap,
-    fs,
-    io::Write,
-    path::{Path, PathBuf},
-    process::{Command, Stdio},
-};
-
-/// Helper: Get absolute path to extension
-fn extension_path() -> PathBuf {
-    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    path.push("target");
-    path.push("debug");
-    path.push(if cfg!(target_os = "windows") {
-        "sqlsec.dll"
-    } else if cfg!(target_os = "macos") {
-        "libsqlsec.dylib"
-    } else {
-        "libsqlsec.so"
-    });
-    path
-}
+//! Each `tests/cases/*.sql` script runs in a sqlite3 shell and its output is
+//! compared with `tests/expected`. With the default `extension` feature the
+//! scripts run in the `sqlite3` binary against the loadable extension; with
+//! `cargo test --no-default-features` they run through [`SecureConnection`]
+//! instead, so both builds are held to the same expected output.
+
+use std::{collections::HashMap, fs, path::Path};
 
 /// Run a single .sql test and compare output.
 fn run_test_case(name: &str) -> bool {
@@ -41,50 +26,12 @@ fn run_test_case(name: &str) -> bool {
     let expect_output = expected_out_path.exists();
     let expect_error = expected_err_path.exists();
 
-    let lib_path = extension_path();
-    assert!(
-        lib_path.exists(),
-        "extension not built: {}",
-        lib_path.display()
-    );
-
     let sql_content = fs::read_to_string(&sql_path).expect("could not read SQL test case file");
 
-    // Feed script via stdin
-    let script = format!(
-        ".load {}\n.headers on\n.mode column\n{}\n",
-        lib_path.display(),
-        sql_content
-    );
-
-    let mut child = match Command::new("sqlite3")
-        .current_dir(base_dir)
-        .arg(":memory:")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(child) => child,
-        Err(err) => {
-            eprintln!("Failed to run sqlite3: {}", err);
-            return false;
-        }
-    };
-
-    // Send to stdin
-    if let Some(stdin) = &mut child.stdin {
-        if let Err(err) = stdin.write_all(script.as_bytes()) {
-            eprintln!("Failed to write to sqlite3 stdin: {}", err);
-            return false;
-        }
-    }
-
-    // Capture result
-    let output = match child.wait_with_output() {
-        Ok(o) => o,
+    let (stdout_str, stderr_str) = match shell::run(base_dir, &sql_content) {
+        Ok(output) => output,
         Err(err) => {
-            eprintln!("Failed to get sqlite3 output: {}", err);
+            eprintln!("{err}");
             return false;
         }
     };
@@ -92,9 +39,6 @@ fn run_test_case(name: &str) -> bool {
     // Decide whether this test passed:
     let mut success = true;
 
-    let stdout_str = String::from_utf8_lossy(&output.stdout);
-    let stderr_str = String::from_utf8_lossy(&output.stderr);
-
     let expected_output = if expect_output {
         fs::read_to_string(expected_out_path)
             .expect("could not read expected output file")
@@ -145,10 +89,10 @@ fn test_cases() -> Vec<String> {
     let mut names = vec![];
     if let Ok(entries) = fs::read_dir(dir) {
         for entry in entries.flatten() {
-            if entry.path().extension().and_then(|s| s.to_str()) == Some("sql") {
-                if let Some(stem) = entry.path().file_stem() {
-                    names.push(stem.to_string_lossy().to_string());
-                }
+            if entry.path().extension().and_then(|s| s.to_str()) == Some("sql")
+                && let Some(stem) = entry.path().file_stem()
+            {
+                names.push(stem.to_string_lossy().to_string());
             }
         }
     }
@@ -188,3 +132,789 @@ fn run_all_sql_tests() {
     );
     assert!(fail_count == 0, "some test cases failed");
 }
+
+/// The `sqlite3` binary, with the extension loaded
+#[cfg(feature = "extension")]
+mod shell {
+    use std::{
+        io::Write,
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+    };
+
+    /// Helper: Get absolute path to extension
+    fn extension_path() -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("target");
+        path.push("debug");
+        path.push(if cfg!(target_os = "windows") {
+            "sqlsec.dll"
+        } else if cfg!(target_os = "macos") {
+            "libsqlsec.dylib"
+        } else {
+            "libsqlsec.so"
+        });
+        path
+    }
+
+    /// Run `sql` in a fresh shell, returning its stdout and stderr
+    pub fn run(base_dir: &Path, sql: &str) -> Result<(String, String), String> {
+        let lib_path = extension_path();
+        assert!(
+            lib_path.exists(),
+            "extension not built: {}",
+            lib_path.display()
+        );
+
+        // Feed script via stdin
+        let script = format!(
+            ".load {}\n.headers on\n.mode column\n{}\n",
+            lib_path.display(),
+            sql
+        );
+
+        let mut child = Command::new("sqlite3")
+            .current_dir(base_dir)
+            .arg(":memory:")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("Failed to run sqlite3: {err}"))?;
+
+        // Send to stdin
+        if let Some(stdin) = &mut child.stdin {
+            stdin
+                .write_all(script.as_bytes())
+                .map_err(|err| format!("Failed to write to sqlite3 stdin: {err}"))?;
+        }
+
+        // Capture result
+        let output = child
+            .wait_with_output()
+            .map_err(|err| format!("Failed to get sqlite3 output: {err}"))?;
+
+        Ok((
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
+}
+
+/// Just enough of the sqlite3 shell to run the cases on [`SecureConnection`]s:
+/// the dot-commands they use, list and column output, and errors reported as
+/// the shell reports them.
+#[cfg(not(feature = "extension"))]
+mod shell {
+    use std::{
+        collections::BTreeMap,
+        ffi::CString,
+        fs,
+        ops::Deref,
+        path::{Path, PathBuf},
+    };
+
+    use rusqlite::{
+        Batch,
+        Connection,
+        Error,
+        Result,
+        Statement,
+        fallible_iterator::FallibleIterator,
+        ffi,
+        functions::FunctionFlags,
+        types::ValueRef,
+    };
+    use sqlsec::SecureConnection;
+
+    #[derive(Clone, Copy)]
+    enum Mode {
+        List,
+        Column,
+    }
+
+    /// A shell connection, secured once sqlsec is `.load`ed into it
+    enum Slot {
+        Plain(Connection),
+        Secure(SecureConnection),
+    }
+
+    impl Deref for Slot {
+        type Target = Connection;
+
+        fn deref(&self) -> &Connection {
+            match self {
+                Slot::Plain(conn) => conn,
+                Slot::Secure(conn) => conn,
+            }
+        }
+    }
+
+    /// Where results and `.print` go
+    enum Sink {
+        Stdout,
+        Null,
+        File(PathBuf, String),
+    }
+
+    struct Shell<'a> {
+        base_dir: &'a Path,
+        /// Connections by `.connection` slot, opened on first use
+        slots: BTreeMap<usize, Slot>,
+        current: usize,
+        headers: bool,
+        mode: Mode,
+        sink: Sink,
+        /// Commands left before a `.once` file is written
+        once: u8,
+        stdout: String,
+        stderr: String,
+    }
+
+    /// Run `sql` in a fresh shell, returning its stdout and stderr
+    pub fn run(base_dir: &Path, sql: &str) -> Result<(String, String), String> {
+        let script = format!(".load sqlsec\n.headers on\n.mode column\n{sql}\n");
+        let mut shell = Shell {
+            base_dir,
+            slots: BTreeMap::new(),
+            current: 0,
+            headers: false,
+            mode: Mode::List,
+            sink: Sink::Stdout,
+            once: 0,
+            stdout: String::new(),
+            stderr: String::new(),
+        };
+        shell.run(&script).map_err(|e| e.to_string())?;
+        Ok((shell.stdout, shell.stderr))
+    }
+
+    /// Split a dot-command's arguments as the shell does
+    fn meta_args(line: &str) -> Vec<String> {
+        let mut args = vec![];
+        let mut chars = line.trim_start_matches('.').chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let Some(&first) = chars.peek() else {
+                return args;
+            };
+            let mut arg = String::new();
+            if first == '"' || first == '\'' {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' if first == '"' => arg.extend(chars.next()),
+                        c if c == first => break,
+                        c => arg.push(c),
+                    }
+                }
+            } else {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+            }
+            args.push(arg);
+        }
+    }
+
+    /// Whether `sql` ends in a complete statement
+    fn is_complete(sql: &str) -> bool {
+        let sql = CString::new(sql).unwrap();
+        unsafe { ffi::sqlite3_complete(sql.as_ptr()) != 0 }
+    }
+
+    /// A value as the shell prints it, NULL as the empty string
+    fn render(value: ValueRef) -> String {
+        match value {
+            ValueRef::Null => String::new(),
+            ValueRef::Integer(v) => v.to_string(),
+            ValueRef::Real(v) => format!("{v:?}"),
+            ValueRef::Text(s) | ValueRef::Blob(s) => String::from_utf8_lossy(s).into_owned(),
+        }
+    }
+
+    /// The lines column mode prints `cell` on: its own lines, each cut every
+    /// 60 characters
+    fn wrap(cell: &str) -> Vec<String> {
+        cell.split('\n')
+            .flat_map(|line| {
+                let chars: Vec<char> = line.chars().collect();
+                if chars.is_empty() {
+                    return vec![String::new()];
+                }
+                chars
+                    .chunks(60)
+                    .map(|chunk| chunk.iter().collect())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The lines of an `EXPLAIN QUERY PLAN` tree below the node `parent`
+    fn plan_lines(nodes: &[(i64, i64, String)], parent: i64, indent: &str, out: &mut String) {
+        let children: Vec<_> = nodes.iter().filter(|(_, p, _)| *p == parent).collect();
+        for (i, (id, _, detail)) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            let (branch, more) = if last { ("`--", "   ") } else { ("|--", "|  ") };
+            *out += &format!("{indent}{branch}{detail}\n");
+            plan_lines(nodes, *id, &format!("{indent}{more}"), out);
+        }
+    }
+
+    impl Shell<'_> {
+        fn run(&mut self, script: &str) -> Result<()> {
+            let mut sql = String::new();
+            let mut start_line = 0;
+            for (i, line) in script.lines().enumerate() {
+                if sql.is_empty() {
+                    let trimmed = line.trim();
+                    if trimmed.is_empty() || trimmed.starts_with("--") {
+                        continue;
+                    }
+                    if line.starts_with('.') {
+                        self.meta(line)?;
+                        self.end_command();
+                        continue;
+                    }
+                    start_line = i + 1;
+                    sql.push_str(line.trim_start());
+                } else {
+                    sql.push('\n');
+                    sql.push_str(line);
+                }
+                if is_complete(&sql) {
+                    self.sql(&std::mem::take(&mut sql), start_line)?;
+                    self.end_command();
+                }
+            }
+            Ok(())
+        }
+
+        /// The connection in the current slot, opening it if needed
+        fn conn(&mut self) -> Result<&Connection> {
+            if !self.slots.contains_key(&self.current) {
+                self.open(":memory:")?;
+            }
+            Ok(&self.slots[&self.current])
+        }
+
+        /// Replace the current slot's connection, adding the shell's
+        /// `readfile()` as the sqlite3 binary does
+        fn open(&mut self, path: &str) -> Result<()> {
+            self.slots.remove(&self.current);
+            let conn = Connection::open(path)?;
+            let base_dir = self.base_dir.to_path_buf();
+            conn.create_scalar_function(
+                "readfile",
+                1,
+                FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+                move |ctx| Ok(fs::read(base_dir.join(ctx.get::<String>(0)?)).ok()),
+            )?;
+            self.slots.insert(self.current, Slot::Plain(conn));
+            Ok(())
+        }
+
+        /// Load sqlsec into the current connection; loading it again
+        /// initializes it again, as the extension's entry point does
+        fn load(&mut self) -> Result<()> {
+            self.conn()?;
+            let slot = match self.slots.remove(&self.current).unwrap() {
+                Slot::Plain(conn) => Slot::Secure(SecureConnection::new(conn)?),
+                Slot::Secure(conn) => {
+                    sqlsec::init(&conn)?;
+                    Slot::Secure(conn)
+                }
+            };
+            self.slots.insert(self.current, slot);
+            Ok(())
+        }
+
+        fn print(&mut self, text: &str) {
+            match &mut self.sink {
+                Sink::Stdout => self.stdout.push_str(text),
+                Sink::Null => {}
+                Sink::File(_, buf) => buf.push_str(text),
+            }
+        }
+
+        fn set_sink(&mut self, sink: Sink) {
+            if let Sink::File(path, buf) = std::mem::replace(&mut self.sink, sink) {
+                fs::write(path, buf).unwrap();
+            }
+        }
+
+        /// A `.once` file takes the output of the one command after it
+        fn end_command(&mut self) {
+            if self.once > 0 {
+                self.once -= 1;
+                if self.once == 0 {
+                    self.set_sink(Sink::Stdout);
+                }
+            }
+        }
+
+        fn meta(&mut self, line: &str) -> Result<()> {
+            let args = meta_args(line);
+            match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                ["print", ref text @ ..] => self.print(&format!("{}\n", text.join(" "))),
+                ["headers", on] => self.headers = on == "on",
+                ["mode", "list"] => self.mode = Mode::List,
+                ["mode", "column"] => self.mode = Mode::Column,
+                ["output", "stdout"] => self.set_sink(Sink::Stdout),
+                ["output", "/dev/null"] => self.set_sink(Sink::Null),
+                ["once", path] => {
+                    self.set_sink(Sink::File(self.base_dir.join(path), String::new()));
+                    self.once = 2;
+                }
+                ["load", _] => self.load()?,
+                ["open", path] => self.open(path)?,
+                ["connection", "close", slot] => {
+                    self.slots.remove(&slot.parse::<usize>().unwrap());
+                }
+                ["connection", slot] => self.current = slot.parse().unwrap(),
+                ["tables", pattern] => self.tables(pattern)?,
+                _ => panic!("unsupported dot-command: {line}"),
+            }
+            Ok(())
+        }
+
+        /// `.tables`, laid out in as many columns as fit 80 characters
+        fn tables(&mut self, pattern: &str) -> Result<()> {
+            let names: Vec<String> = {
+                let conn = self.conn()?;
+                let schemas: Vec<String> = conn
+                    .prepare("SELECT name FROM pragma_database_list ORDER BY seq")?
+                    .query_map([], |r| r.get(0))?
+                    .collect::<Result<_>>()?;
+                let mut names = vec![];
+                for schema in schemas {
+                    let prefix = if schema == "main" {
+                        String::new()
+                    } else {
+                        format!("{schema}.")
+                    };
+                    let mut stmt = conn.prepare(&format!(
+                        "SELECT ?1 || name FROM \"{schema}\".sqlite_schema
+                         WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite_%'
+                           AND name LIKE ?2"
+                    ))?;
+                    let rows = stmt.query_map([&prefix, pattern], |r| r.get(0))?;
+                    names.extend(rows.collect::<Result<Vec<String>>>()?);
+                }
+                names.sort();
+                names
+            };
+
+            let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0);
+            let cols = (80 / (width + 2)).max(1);
+            let rows = names.len().div_ceil(cols);
+            for row in 0..rows {
+                let line: Vec<String> = names
+                    .iter()
+                    .skip(row)
+                    .step_by(rows)
+                    .map(|n| format!("{n:width$}"))
+                    .collect();
+                self.print(&format!("{}\n", line.join("  ")));
+            }
+            Ok(())
+        }
+
+        /// Run each statement of `sql`, stopping at the first error
+        fn sql(&mut self, sql: &str, line: usize) -> Result<()> {
+            self.conn()?;
+            let conn = self.slots.remove(&self.current).unwrap();
+            let result = self.statements(&conn, sql);
+            self.slots.insert(self.current, conn);
+
+            if let Err((kind, err)) = result {
+                let message = match &err {
+                    Error::SqliteFailure(e, Some(msg)) if e.extended_code & 0xff > 1 => {
+                        format!("{msg} ({})", e.extended_code & 0xff)
+                    }
+                    Error::SqliteFailure(_, Some(msg)) => msg.clone(),
+                    err => err.to_string(),
+                };
+                self.stderr
+                    .push_str(&format!("{kind} error near line {line}: {message}\n"));
+            }
+            Ok(())
+        }
+
+        fn statements(
+            &mut self,
+            conn: &Connection,
+            sql: &str,
+        ) -> Result<(), (&'static str, Error)> {
+            let mut batch = Batch::new(conn, sql);
+            while let Some(mut stmt) = batch.next().map_err(|e| ("Parse", e))? {
+                self.results(&mut stmt).map_err(|e| ("Runtime", e))?;
+            }
+            Ok(())
+        }
+
+        /// Print an `EXPLAIN QUERY PLAN` as the shell draws it
+        fn query_plan(&mut self, stmt: &mut Statement) -> Result<()> {
+            let nodes: Vec<(i64, i64, String)> = stmt
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(3)?)))?
+                .collect::<Result<_>>()?;
+            let mut out = String::from("QUERY PLAN\n");
+            plan_lines(&nodes, 0, "", &mut out);
+            self.print(&out);
+            Ok(())
+        }
+
+        /// Print the rows of `stmt` in the current mode
+        fn results(&mut self, stmt: &mut Statement) -> Result<()> {
+            if stmt.is_explain() == 2 {
+                return self.query_plan(stmt);
+            }
+            let names: Vec<String> = stmt.column_names().iter().map(|n| n.to_string()).collect();
+            let mut rows = stmt.query([])?;
+            let mut values: Vec<Vec<String>> = vec![];
+            while let Some(row) = rows.next()? {
+                values.push(
+                    (0..names.len())
+                        .map(|i| row.get_ref(i).map(render))
+                        .collect::<Result<_>>()?,
+                );
+            }
+            if names.is_empty() || values.is_empty() {
+                return Ok(());
+            }
+
+            let mut out = String::new();
+            match self.mode {
+                Mode::List => {
+                    if self.headers {
+                        out += &format!("{}\n", names.join("|"));
+                    }
+                    for row in &values {
+                        out += &format!("{}\n", row.join("|"));
+                    }
+                }
+                Mode::Column => {
+                    // Each cell as the lines it wraps to
+                    let rows: Vec<Vec<Vec<String>>> = values
+                        .iter()
+                        .map(|row| row.iter().map(|cell| wrap(cell)).collect())
+                        .collect();
+                    let widths: Vec<usize> = (0..names.len())
+                        .map(|i| {
+                            rows.iter()
+                                .flat_map(|row| &row[i])
+                                .map(|line| line.chars().count())
+                                .chain([names[i].chars().count()])
+                                .max()
+                                .unwrap()
+                        })
+                        .collect();
+                    let line = |cells: &[&str]| {
+                        let cells: Vec<String> = cells
+                            .iter()
+                            .zip(&widths)
+                            .map(|(cell, &width)| format!("{cell:width$}"))
+                            .collect();
+                        format!("{}\n", cells.join("  "))
+                    };
+                    if self.headers {
+                        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                        let rule: Vec<String> = widths.iter().map(|&w| "-".repeat(w)).collect();
+                        let rule: Vec<&str> = rule.iter().map(String::as_str).collect();
+                        out += &line(&names);
+                        out += &line(&rule);
+                    }
+                    // Rows are set apart when any of them wraps
+                    let multi_line = rows.iter().flatten().any(|cell| cell.len() > 1);
+                    for (r, row) in rows.iter().enumerate() {
+                        if multi_line && r > 0 {
+                            out += "\n";
+                        }
+                        let height = row.iter().map(Vec::len).max().unwrap();
+                        for l in 0..height {
+                            let cells: Vec<&str> = row
+                                .iter()
+                                .map(|cell| cell.get(l).map_or("", String::as_str))
+                                .collect();
+                            out += &line(&cells);
+                        }
+                    }
+                }
+            }
+            self.print(&out);
+            Ok(())
+        }
+    }
+}
+
+/// `SecureConnection` and `ConnectionExt` behave as the loaded extension does
+#[cfg(not(feature = "extension"))]
+mod embedding {
+    use std::{mem::forget, thread};
+
+    use rusqlite::{Connection, OpenFlags, Result};
+    use sqlsec::{ConnectionExt, RegisterOpts, SecureConnection};
+
+    /// The customers of `tests/cases/row_security.sql`, with the email column
+    /// readable by admins and auditors only
+    fn customers() -> Result<SecureConnection> {
+        let conn = SecureConnection::open_in_memory()?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE __sec_customers (
+                id           INTEGER PRIMARY KEY,
+                row_label_id INTEGER NOT NULL,
+                name         TEXT,
+                email        TEXT
+            );
+            INSERT INTO __sec_customers VALUES
+                (1, 1, 'Alice',   'alice@ex.com'),
+                (2, 2, 'Bob',     'bob@ex.com'),
+                (3, 3, 'Charlie', 'charlie@ex.com');
+            "#,
+        )?;
+
+        assert_eq!(conn.define_label("true")?, 1);
+        assert_eq!(conn.define_label("role=admin")?, 2);
+        assert_eq!(conn.define_label("(role=admin|role=auditor)")?, 3);
+        conn.register_table(
+            "customers",
+            "__sec_customers",
+            "row_label_id",
+            None,
+            None,
+            None,
+        )?;
+        conn.execute(
+            "UPDATE sec_columns SET read_label_id = 3
+             WHERE logical_table = 'customers' AND column_name = 'email'",
+            [],
+        )?;
+        Ok(conn)
+    }
+
+    fn visible(conn: &SecureConnection) -> Result<Vec<String>> {
+        conn.prepare("SELECT name FROM customers ORDER BY id")?
+            .query_map([], |r| r.get(0))?
+            .collect()
+    }
+
+    fn has_email(conn: &SecureConnection) -> Result<bool> {
+        conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('customers') WHERE name = 'email'",
+            [],
+            |r| r.get(0),
+        )
+    }
+
+    #[test]
+    fn rows_and_columns_follow_the_context() -> Result<()> {
+        let conn = customers()?;
+
+        conn.set_attr("role", "user")?;
+        conn.refresh_views()?;
+        assert_eq!(visible(&conn)?, ["Alice"]);
+        assert!(!has_email(&conn)?);
+
+        conn.clear_context()?;
+        conn.set_attr("role", "auditor")?;
+        conn.refresh_views()?;
+        assert_eq!(visible(&conn)?, ["Alice", "Charlie"]);
+        assert!(has_email(&conn)?);
+
+        conn.clear_context()?;
+        conn.set_attr("role", "admin")?;
+        conn.refresh_views()?;
+        assert_eq!(visible(&conn)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn stale_views_are_refused() -> Result<()> {
+        let conn = customers()?;
+        conn.set_attr("role", "admin")?;
+        conn.refresh_views()?;
+
+        conn.set_attr("role", "user")?;
+        let err = visible(&conn).unwrap_err();
+        assert!(err.to_string().contains("stale"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn errors_carry_the_function_message() -> Result<()> {
+        let conn = SecureConnection::open_in_memory()?;
+
+        let err = conn.define_label("role=").unwrap_err();
+        assert!(err.to_string().starts_with("define_label:"), "{err}");
+
+        let err = conn
+            .register_table("ghosts", "__sec_ghosts", "row_label_id", None, None, None)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("register_table:"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn contexts_are_per_connection() -> Result<()> {
+        let admin = customers()?;
+        let user = SecureConnection::open_in_memory()?;
+
+        admin.set_attr("role", "admin")?;
+        let roles: String = user.query_row("SELECT sec_get_attrs('role')", [], |r| r.get(0))?;
+        assert_eq!(roles, "[]");
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_set_attr_loses_nothing() -> Result<()> {
+        // One serialized handle shared by every thread
+        let conn = SecureConnection::new(Connection::open_in_memory_with_flags(
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_FULL_MUTEX,
+        )?)?;
+        let handle = unsafe { conn.handle() } as usize;
+
+        thread::scope(|s| {
+            for t in 0..8 {
+                s.spawn(move || {
+                    let shared = unsafe { Connection::from_handle(handle as *mut _) }.unwrap();
+                    for i in 0..100 {
+                        shared
+                            .query_row(
+                                "SELECT sec_set_attr(?1, ?2)",
+                                [format!("t{t}"), i.to_string()],
+                                |_| Ok(()),
+                            )
+                            .unwrap();
+                    }
+                    forget(shared);
+                });
+            }
+        });
+
+        for t in 0..8 {
+            let values: i64 = conn.query_row(
+                "SELECT json_array_length(sec_get_attrs(?1))",
+                [format!("t{t}")],
+                |r| r.get(0),
+            )?;
+            assert_eq!(values, 100, "t{t}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn levels_are_per_connection() -> Result<()> {
+        let visible = |conn: &SecureConnection, label: i64| -> Result<bool> {
+            conn.query_row("SELECT sec_label_visible(?1)", [label], |r| r.get(0))
+        };
+
+        let a = SecureConnection::open_in_memory()?;
+        a.execute_batch(
+            "SELECT sec_define_level('clearance', 'low', 0);
+             SELECT sec_define_level('clearance', 'high', 1);",
+        )?;
+        let label = a.define_label("clearance>=high")?;
+        a.set_attr("clearance", "high")?;
+        assert!(visible(&a, label)?);
+
+        // Another database, initialized and ordering the levels the other way
+        let b = SecureConnection::open_in_memory()?;
+        b.execute_batch(
+            "SELECT sec_define_level('clearance', 'low', 1);
+             SELECT sec_define_level('clearance', 'high', 0);",
+        )?;
+        assert_eq!(b.define_label("clearance>=high")?, label);
+        b.set_attr("clearance", "low")?;
+
+        assert!(visible(&a, label)?);
+        assert!(visible(&b, label)?);
+        a.clear_context()?;
+        a.set_attr("clearance", "low")?;
+        assert!(!visible(&a, label)?);
+        Ok(())
+    }
+
+    #[test]
+    fn reopening_keeps_the_policy() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sqlsec-embedding-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.db");
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let conn = SecureConnection::open(&path)?;
+            conn.define_label("role=admin")?;
+        }
+        let conn = SecureConnection::open(&path)?;
+        assert_eq!(conn.define_label("role=admin")?, 1);
+        let labels: i64 = conn.query_row("SELECT COUNT(*) FROM sqlsec_labels", [], |r| r.get(0))?;
+        assert_eq!(labels, 1);
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn extension_trait_registers_with_options() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        sqlsec::init(&conn)?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE __sec_notes (id INTEGER PRIMARY KEY, row_label_id INTEGER, body TEXT);
+            INSERT INTO __sec_notes VALUES (1, NULL, 'hello');
+            "#,
+        )?;
+
+        let staff = conn.sec_define_label("role=staff")?;
+        let admin = conn.sec_define_label("role=admin")?;
+        conn.sec_register_table(
+            &RegisterOpts::new("notes", "__sec_notes", "row_label_id")
+                .table_label(staff)
+                .delete_label(admin),
+        )?;
+        let (table_label, delete_label): (i64, i64) = conn.query_row(
+            "SELECT table_label_id, delete_label_id FROM sec_tables WHERE logical_name = 'notes'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        assert_eq!((table_label, delete_label), (staff, admin));
+
+        conn.sec_set_attr("role", "staff")?;
+        conn.sec_refresh_views()?;
+        let err = conn.execute("DELETE FROM notes", []).unwrap_err();
+        assert!(err.to_string().contains("delete denied"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn extension_trait_registration_follows_the_enclosing_transaction() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        sqlsec::init(&conn)?;
+        conn.execute_batch(
+            "CREATE TABLE __sec_notes (id INTEGER PRIMARY KEY, row_label_id INTEGER, body TEXT);",
+        )?;
+        let staff = conn.sec_define_label("role=staff")?;
+
+        conn.execute_batch("BEGIN")?;
+        conn.sec_register_table(
+            &RegisterOpts::new("notes", "__sec_notes", "row_label_id")
+                .insert_label(staff)
+                .allow_implicit_label(false),
+        )?;
+        let allow: i64 = conn.query_row(
+            "SELECT allow_implicit_label FROM sec_tables WHERE logical_name = 'notes'",
+            [],
+            |r| r.get(0),
+        )?;
+        assert_eq!(allow, 0);
+        conn.execute_batch("ROLLBACK")?;
+
+        let tables: i64 = conn.query_row("SELECT COUNT(*) FROM sec_tables", [], |r| r.get(0))?;
+        assert_eq!(tables, 0);
+        Ok(())
+    }
+}
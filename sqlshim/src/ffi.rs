@@ -1,4 +1,7 @@
-use std::ffi::{CStr, CString};
+use std::{
+    ffi::{CStr, CString},
+    ptr,
+};
 
 use libc::{RTLD_NEXT, c_char, c_int, c_void};
 
@@ -40,6 +43,32 @@ pub(crate) unsafe fn resolve_exec() -> Exec {
     unsafe { std::mem::transmute(addr) }
 }
 
+/// Borrow the SQL text handed to a prepare call.
+///
+/// A non-negative `n_byte` bounds the text, which need not be NUL-terminated.
+/// Non-UTF-8 input is never rewritten.
+unsafe fn sql_text<'a>(z_sql: *const c_char, n_byte: c_int) -> Option<&'a str> {
+    if z_sql.is_null() {
+        return None;
+    }
+    let bytes = if n_byte >= 0 {
+        let bytes = unsafe { std::slice::from_raw_parts(z_sql as *const u8, n_byte as usize) };
+        // A NUL inside the bound still terminates the statement
+        bytes.split(|b| *b == 0).next().unwrap_or_default()
+    } else {
+        unsafe { CStr::from_ptr(z_sql).to_bytes() }
+    };
+    std::str::from_utf8(bytes).ok()
+}
+
+/// Point `pz_tail` back into the caller's buffer, just past the rewritten
+/// statement, so callers iterating over a batch continue with the original SQL.
+unsafe fn set_tail(pz_tail: *mut *const c_char, z_sql: *const c_char, consumed: usize) {
+    if !pz_tail.is_null() {
+        unsafe { *pz_tail = z_sql.add(consumed) };
+    }
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn sqlite3_prepare_v2(
     db: *mut Sqlite3,
@@ -49,16 +78,19 @@ pub unsafe extern "C" fn sqlite3_prepare_v2(
     pz_tail: *mut *const c_char,
 ) -> c_int {
     let real = unsafe { resolve_prepare_v2() };
-    let sql = unsafe { CStr::from_ptr(z_sql).to_string_lossy() };
 
-    if let Some(new_sql) = parse_and_rewrite(&sql) {
+    if let Some(sql) = unsafe { sql_text(z_sql, n_byte) }
+        && let Some(rewrite) = parse_and_rewrite(sql)
+    {
         if debug() {
             eprintln!("sqlshim: prepare_v2 rewrite!");
-            eprintln!("  original: {}", sql.trim());
-            eprintln!("  rewritten: {}", new_sql.trim());
+            eprintln!("  original: {}", sql[..rewrite.consumed].trim());
+            eprintln!("  rewritten: {}", rewrite.sql.trim());
         }
-        let csql = CString::new(new_sql).unwrap();
-        return unsafe { real(db, csql.as_ptr(), -1, pp_stmt, pz_tail) };
+        let csql = CString::new(rewrite.sql).unwrap();
+        let rc = unsafe { real(db, csql.as_ptr(), -1, pp_stmt, ptr::null_mut()) };
+        unsafe { set_tail(pz_tail, z_sql, rewrite.consumed) };
+        return rc;
     }
 
     unsafe { real(db, z_sql, n_byte, pp_stmt, pz_tail) }
@@ -74,16 +106,19 @@ pub unsafe extern "C" fn sqlite3_prepare_v3(
     pz_tail: *mut *const c_char,
 ) -> c_int {
     let real = unsafe { resolve_prepare_v3() };
-    let sql = unsafe { CStr::from_ptr(z_sql).to_string_lossy() };
 
-    if let Some(new_sql) = parse_and_rewrite(&sql) {
+    if let Some(sql) = unsafe { sql_text(z_sql, n_byte) }
+        && let Some(rewrite) = parse_and_rewrite(sql)
+    {
         if debug() {
             eprintln!("sqlshim: prepare_v3 rewrite!");
-            eprintln!("  original: {}", sql.trim());
-            eprintln!("  rewritten: {}", new_sql.trim());
+            eprintln!("  original: {}", sql[..rewrite.consumed].trim());
+            eprintln!("  rewritten: {}", rewrite.sql.trim());
         }
-        let csql = CString::new(new_sql).unwrap();
-        return unsafe { real(db, csql.as_ptr(), -1, prep_flags, pp_stmt, pz_tail) };
+        let csql = CString::new(rewrite.sql).unwrap();
+        let rc = unsafe { real(db, csql.as_ptr(), -1, prep_flags, pp_stmt, ptr::null_mut()) };
+        unsafe { set_tail(pz_tail, z_sql, rewrite.consumed) };
+        return rc;
     }
 
     unsafe { real(db, z_sql, n_byte, prep_flags, pp_stmt, pz_tail) }
@@ -98,17 +133,19 @@ pub unsafe extern "C" fn sqlite3_exec(
     errmsg: *mut *mut c_char,
 ) -> c_int {
     let real = unsafe { resolve_exec() };
-    let sql_str = unsafe { CStr::from_ptr(sql).to_string_lossy() };
 
-    // sqlite3_exec can contain multiple statements - we need to handle each
-    // For now, try to rewrite the whole thing if it's a single custom statement
-    if let Some(new_sql) = parse_and_rewrite(&sql_str) {
+    // sqlite3_exec can contain multiple statements: rewrite a leading custom
+    // statement and keep the remainder, which reaches our prepare hooks anyway
+    if let Some(sql_str) = unsafe { sql_text(sql, -1) }
+        && let Some(rewrite) = parse_and_rewrite(sql_str)
+    {
+        let rest = &sql_str[rewrite.consumed..];
         if debug() {
             eprintln!("sqlshim: exec rewrite!");
-            eprintln!("  original: {}", sql_str.trim());
-            eprintln!("  rewritten: {}", new_sql.trim());
+            eprintln!("  original: {}", sql_str[..rewrite.consumed].trim());
+            eprintln!("  rewritten: {}", rewrite.sql.trim());
         }
-        let csql = CString::new(new_sql).unwrap();
+        let csql = CString::new(format!("{}\n{}", rewrite.sql, rest)).unwrap();
         return unsafe { real(db, csql.as_ptr(), callback, arg, errmsg) };
     }
 
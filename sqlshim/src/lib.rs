@@ -50,7 +50,7 @@ fn disabled() -> bool {
     std::env::var("SQLSHIM_DISABLE").is_ok()
 }
 
-fn parse_and_rewrite(sql: &str) -> Option<String> {
+fn parse_and_rewrite(sql: &str) -> Option<parser::Rewrite> {
     if disabled() {
         return None;
     }
@@ -84,6 +84,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_define_label_nested_quotes() {
+        let sql = "DEFINE LABEL 'department=''Human Resources''&email=bob@example.com';";
+        let stmt = parser::parse(sql).unwrap();
+        match stmt {
+            statement::CustomStatement::DefineLabel(d) => {
+                assert_eq!(d.expr, "department='Human Resources'&email=bob@example.com")
+            }
+            _ => panic!("Expected DefineLabel"),
+        }
+
+        let rewritten = parse_and_rewrite(sql).unwrap().sql;
+        assert!(rewritten.contains("sec_define_label('department=''Human Resources''&"));
+    }
+
     #[test]
     fn test_parse_define_level() {
         let sql = "DEFINE LEVEL clearance 'secret' = 2;";
@@ -126,17 +141,196 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_set_column_security_mask() {
+        let sql = "SET COLUMN SECURITY employees.salary READ 'role=hr' MASK '''<redacted>''';";
+        let stmt = parser::parse(sql).unwrap();
+        match stmt {
+            statement::CustomStatement::SetColumnSecurity(s) => {
+                assert_eq!(s.table, "employees");
+                assert_eq!(s.column, "salary");
+                assert_eq!(s.read_label.as_deref(), Some("role=hr"));
+                assert_eq!(s.update_label, None);
+                assert_eq!(s.mask.as_deref(), Some("'<redacted>'"));
+            }
+            _ => panic!("Expected SetColumnSecurity"),
+        }
+    }
+
+    #[test]
+    fn test_rewrite_set_column_security_mask() {
+        let sql = "SET COLUMN SECURITY employees.ssn MASK '''***'' || substr(ssn, -4)';";
+        let rewritten = parse_and_rewrite(sql).unwrap().sql;
+        assert_eq!(
+            rewritten,
+            "SELECT sec_set_column_mask('employees', 'ssn', '''***'' || substr(ssn, -4)');"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_register_secure_table_delete_label() {
+        let sql = "REGISTER SECURE TABLE reports ON __sec_reports WITH ROW LABEL row_label_id \
+                   DELETE LABEL 'role=admin';";
+        let rewritten = parse_and_rewrite(sql).unwrap().sql;
+        assert_eq!(
+            rewritten,
+            "SELECT sec_register_table('reports', '__sec_reports', 'row_label_id', NULL, NULL, sec_define_label('role=admin'));"
+        );
+    }
+
+    #[test]
+    fn test_parse_secure_table() {
+        let sql = "SECURE TABLE employees WITH DEFAULT LABEL 'role=staff';";
+        let stmt = parser::parse(sql).unwrap();
+        match stmt {
+            statement::CustomStatement::SecureTable(s) => {
+                assert_eq!(s.table, "employees");
+                assert_eq!(s.default_label, "role=staff");
+            }
+            _ => panic!("Expected SecureTable"),
+        }
+    }
+
+    #[test]
+    fn test_rewrite_secure_table() {
+        let sql = "SECURE TABLE employees WITH DEFAULT LABEL 'dept=''R&D''';";
+        let rewritten = parse_and_rewrite(sql).unwrap().sql;
+        assert_eq!(
+            rewritten,
+            "SELECT sec_secure_existing_table('employees', 'dept=''R&D''');"
+        );
+    }
+
     #[test]
     fn test_passthrough_normal_sql() {
         let sql = "SELECT * FROM users WHERE id = 1;";
         assert!(parser::parse(sql).is_none());
     }
 
+    #[test]
+    fn test_rewrite_passthrough_normal_sql() {
+        let sql = "CREATE TABLE a (x); CREATE TABLE b (y);";
+        assert!(parse_and_rewrite(sql).is_none());
+    }
+
+    #[test]
+    fn test_rewrite_reports_consumed_input() {
+        let sql = "DEFINE LEVEL clearance 'secret' = 2;\n  SELECT 1;";
+        let rewritten = parse_and_rewrite(sql).unwrap();
+        assert_eq!(&sql[rewritten.consumed..], "SELECT 1;");
+
+        let sql = "DEFINE LABEL 'owner=''Zoë''';";
+        let rewritten = parse_and_rewrite(sql).unwrap();
+        assert_eq!(rewritten.consumed, sql.len());
+
+        let sql = "PUSH CONTEXT";
+        let rewritten = parse_and_rewrite(sql).unwrap();
+        assert_eq!(rewritten.consumed, sql.len());
+    }
+
+    #[test]
+    fn test_rewrite_push_pop_context() {
+        let rewrite = |sql| parse_and_rewrite(sql).unwrap().sql;
+
+        assert_eq!(rewrite("PUSH CONTEXT;"), "SELECT sec_push_context();");
+        assert_eq!(rewrite("POP CONTEXT;"), "SELECT sec_pop_context();");
+        assert_eq!(
+            rewrite("PUSH CONTEXT 'request';"),
+            "SELECT sec_push_context('request');"
+        );
+        assert_eq!(
+            rewrite("POP CONTEXT 'o''reilly';"),
+            "SELECT sec_pop_context('o''reilly');"
+        );
+    }
+
+    #[test]
+    fn test_parse_pop_context_name() {
+        match parser::parse("POP CONTEXT 'request';") {
+            Some(statement::CustomStatement::PopContext(name)) => {
+                assert_eq!(name.as_deref(), Some("request"))
+            }
+            other => panic!("Expected PopContext, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_rewrite_define_label() {
         let sql = "DEFINE LABEL 'role=admin';";
-        let rewritten = parse_and_rewrite(sql).unwrap();
+        let rewritten = parse_and_rewrite(sql).unwrap().sql;
         assert!(rewritten.contains("sec_define_label"));
         assert!(rewritten.contains("role=admin"));
     }
+
+    #[test]
+    fn test_rewrite_define_label_invalid_expr() {
+        // Label syntax is checked by sec_define_label, so malformed expressions
+        // are passed through verbatim for its positioned error to reach the client
+        let sql = "DEFINE LABEL 'role admin';";
+        let rewritten = parse_and_rewrite(sql).unwrap().sql;
+        assert_eq!(rewritten, "SELECT sec_define_label('role admin');");
+    }
+
+    fn parse_audit_ops(sql: &str) -> Vec<PolicyOperation> {
+        match parser::parse(sql) {
+            Some(statement::CustomStatement::EnableAudit(a)) => a.operations,
+            other => panic!("Expected EnableAudit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_enable_audit_default_all() {
+        let ops = parse_audit_ops("ENABLE AUDIT ON t;");
+        assert_eq!(ops, vec![PolicyOperation::All]);
+    }
+
+    #[test]
+    fn test_parse_enable_audit_one_op() {
+        let ops = parse_audit_ops("ENABLE AUDIT ON t FOR INSERT;");
+        assert_eq!(ops, vec![PolicyOperation::Insert]);
+    }
+
+    #[test]
+    fn test_parse_enable_audit_two_ops() {
+        let ops = parse_audit_ops("ENABLE AUDIT ON t FOR UPDATE, INSERT;");
+        assert_eq!(ops, vec![PolicyOperation::Update, PolicyOperation::Insert]);
+    }
+
+    #[test]
+    fn test_parse_enable_audit_three_ops() {
+        let ops = parse_audit_ops("ENABLE AUDIT ON t FOR INSERT, UPDATE, DELETE;");
+        assert_eq!(
+            ops,
+            vec![
+                PolicyOperation::Insert,
+                PolicyOperation::Update,
+                PolicyOperation::Delete
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_enable_audit_dedupes_ops() {
+        let ops = parse_audit_ops("ENABLE AUDIT ON t FOR DELETE, INSERT, DELETE;");
+        assert_eq!(ops, vec![PolicyOperation::Delete, PolicyOperation::Insert]);
+    }
+
+    #[test]
+    fn test_parse_enable_audit_all_mixed_with_specific() {
+        let ops = parse_audit_ops("ENABLE AUDIT ON t FOR INSERT, ALL, DELETE;");
+        assert_eq!(ops, vec![PolicyOperation::All]);
+    }
+
+    #[test]
+    fn test_parse_enable_audit_rejects_unknown_op() {
+        assert!(parser::parse("ENABLE AUDIT ON t FOR INSERT, TRUNCATE;").is_none());
+        assert!(parser::parse("ENABLE AUDIT ON t FOR INSERT TRUNCATE;").is_none());
+    }
+
+    #[test]
+    fn test_rewrite_enable_audit_keeps_all_ops() {
+        let sql = "ENABLE AUDIT ON t FOR INSERT, UPDATE, DELETE;";
+        let rewritten = parse_and_rewrite(sql).unwrap().sql;
+        assert!(rewritten.contains("FOR INSERT, UPDATE, DELETE"));
+    }
 }
@@ -2,7 +2,7 @@ use sqlparser::{
     ast::Ident,
     dialect::{Dialect, GenericDialect},
     parser::{Parser, ParserError},
-    tokenizer::{Token, TokenWithSpan},
+    tokenizer::{Location, Token, TokenWithSpan},
 };
 
 use crate::{
@@ -28,18 +28,46 @@ impl Dialect for CustomDialect {
 
 /// Wraps sqlparser's Parser for custom statement parsing
 pub struct CustomParser {
+    sql: String,
     parser: Parser<'static>,
     registry: &'static PluginRegistry,
 }
 
+/// A custom statement rewritten into plain SQL
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    /// The SQL to hand to SQLite in place of the custom statement
+    pub sql: String,
+    /// Byte length of the input consumed by the custom statement,
+    /// including its terminating semicolon
+    pub consumed: usize,
+}
+
 /// Create a parser error with span context
-fn error_at_span(message: &str, token: &TokenWithSpan) -> ParserError {
+pub(crate) fn error_at_span(message: &str, token: &TokenWithSpan) -> ParserError {
     ParserError::ParserError(format!(
         "{} (at line {}, column {})",
         message, token.span.start.line, token.span.start.column
     ))
 }
 
+/// Convert a 1-based tokenizer location into a byte offset into `sql`
+fn byte_offset(sql: &str, loc: Location) -> usize {
+    let (mut line, mut col) = (1, 1);
+    for (i, ch) in sql.char_indices() {
+        if line == loc.line && col == loc.column {
+            return i;
+        }
+        if ch == '\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+    }
+    sql.len()
+}
+
 fn consume_prefix(parser: &mut Parser<'_>, words: &[&str]) -> Result<(), ParserError> {
     for word in words {
         let token = parser.next_token();
@@ -57,12 +85,18 @@ fn consume_prefix(parser: &mut Parser<'_>, words: &[&str]) -> Result<(), ParserE
 impl CustomParser {
     pub fn new(sql: &str, registry: &'static PluginRegistry) -> Result<Self, ParserError> {
         let parser = Parser::new(&CUSTOM_DIALECT).try_with_sql(sql)?;
-        Ok(Self { parser, registry })
+        Ok(Self {
+            sql: sql.to_string(),
+            parser,
+            registry,
+        })
     }
 
     /// Parse a single statement, returning custom or standard SQL
     pub fn parse(&mut self) -> Result<Option<CustomStatement>, ParserError> {
-        let Self { parser, registry } = self;
+        let Self {
+            parser, registry, ..
+        } = self;
         if let Some(plugin) = registry.find_match(parser) {
             consume_prefix(parser, plugin.prefix())?;
             let stmt = plugin.parse(parser)?;
@@ -73,19 +107,34 @@ impl CustomParser {
         Ok(None)
     }
 
-    /// Parse and rewrite a single statement
-    pub fn parse_rewrite(&mut self) -> Result<String, ParserError> {
-        let Self { parser, registry } = self;
+    /// Parse and rewrite a single leading custom statement.
+    ///
+    /// Standard SQL is left untouched and yields `None`, so that it reaches
+    /// SQLite byte-for-byte (including any statements that follow it).
+    pub fn parse_rewrite(&mut self) -> Result<Option<Rewrite>, ParserError> {
+        let Self {
+            sql,
+            parser,
+            registry,
+        } = self;
         if let Some(plugin) = registry.find_match(parser) {
             consume_prefix(parser, plugin.prefix())?;
             let stmt = plugin.parse(parser)?;
-            let rewritten = plugin.rewrite(stmt);
-            return Ok(rewritten);
+            let _ = parser.consume_token(&Token::SemiColon);
+
+            let next = parser.peek_token();
+            let consumed = match next.token {
+                Token::EOF => sql.len(),
+                _ => byte_offset(sql, next.span.start),
+            };
+
+            return Ok(Some(Rewrite {
+                sql: plugin.rewrite(stmt),
+                consumed,
+            }));
         }
 
-        // Fall back to standard SQL parsing
-        let stmt = self.parser.parse_statement()?;
-        Ok(stmt.to_string())
+        Ok(None)
     }
 }
 
@@ -174,7 +223,15 @@ impl ParserExt for Parser<'_> {
     fn parse_operation_list(&mut self) -> Result<Vec<PolicyOperation>, ParserError> {
         let mut ops = vec![self.parse_policy_operation()?];
         while self.consume_token(&Token::Comma) {
-            ops.push(self.parse_policy_operation()?);
+            let op = self.parse_policy_operation()?;
+            if !ops.contains(&op) {
+                ops.push(op);
+            }
+        }
+
+        // ALL subsumes every specific operation
+        if ops.contains(&PolicyOperation::All) {
+            return Ok(vec![PolicyOperation::All]);
         }
         Ok(ops)
     }
@@ -223,9 +280,9 @@ impl ParserExt for Parser<'_> {
 }
 
 /// Convenience function matching original API
-pub fn parse_rewrite(sql: &str) -> Option<String> {
+pub fn parse_rewrite(sql: &str) -> Option<Rewrite> {
     let mut parser = CustomParser::new(sql, &PLUGIN_REGISTRY).ok()?;
-    parser.parse_rewrite().ok()
+    parser.parse_rewrite().ok().flatten()
 }
 
 /// Convenience function matching original API
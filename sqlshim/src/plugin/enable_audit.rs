@@ -4,7 +4,7 @@ use sqlparser::{
 };
 
 use crate::{
-    parser::ParserExt,
+    parser::{ParserExt, error_at_span},
     plugin::CustomPlugin,
     rewriter::escape_sql_string,
     statement::{CustomStatement, EnableAuditStmt, PolicyOperation},
@@ -27,6 +27,14 @@ impl CustomPlugin for EnableAuditPlugin {
             vec![PolicyOperation::All]
         };
 
+        if !parser.is_statement_end() {
+            let token = parser.peek_token();
+            return Err(error_at_span(
+                &format!("Unexpected '{}' after operation list", token.token),
+                &token,
+            ));
+        }
+
         Ok(CustomStatement::EnableAudit(EnableAuditStmt {
             table,
             operations,
@@ -10,6 +10,7 @@ mod pop_context;
 mod push_context;
 mod refresh_secure_views;
 mod register_secure_table;
+mod secure_table;
 mod set_column_security;
 mod set_context;
 
@@ -38,6 +39,7 @@ pub static PLUGIN_REGISTRY: LazyLock<PluginRegistry> = LazyLock::new(|| {
         Box::new(push_context::PushContextPlugin),
         Box::new(refresh_secure_views::RefreshSecureViewsPlugin),
         Box::new(register_secure_table::RegisterSecureTablePlugin),
+        Box::new(secure_table::SecureTablePlugin),
         Box::new(set_column_security::SetColumnSecurityPlugin),
         Box::new(set_context::SetContextPlugin),
     ]);
@@ -1,6 +1,11 @@
 use sqlparser::parser::{Parser, ParserError};
 
-use crate::{plugin::CustomPlugin, statement::CustomStatement};
+use crate::{
+    parser::ParserExt,
+    plugin::CustomPlugin,
+    rewriter::escape_sql_string,
+    statement::CustomStatement,
+};
 
 pub struct PopContextPlugin;
 
@@ -9,13 +14,23 @@ impl CustomPlugin for PopContextPlugin {
         &["POP", "CONTEXT"]
     }
 
-    fn parse(&self, _parser: &mut Parser<'_>) -> Result<CustomStatement, ParserError> {
-        Ok(CustomStatement::PopContext)
+    fn parse(&self, parser: &mut Parser<'_>) -> Result<CustomStatement, ParserError> {
+        let name = if parser.is_statement_end() {
+            None
+        } else {
+            Some(parser.parse_literal_string()?)
+        };
+
+        Ok(CustomStatement::PopContext(name))
     }
 
     fn rewrite(&self, stmt: CustomStatement) -> String {
         match stmt {
-            CustomStatement::PopContext => "SELECT sec_pop_context();".to_string(),
+            CustomStatement::PopContext(None) => "SELECT sec_pop_context();".to_string(),
+            CustomStatement::PopContext(Some(name)) => {
+                let escaped = escape_sql_string(&name);
+                format!("SELECT sec_pop_context('{escaped}');")
+            }
             _ => unreachable!(),
         }
     }
@@ -1,6 +1,11 @@
 use sqlparser::parser::{Parser, ParserError};
 
-use crate::{plugin::CustomPlugin, statement::CustomStatement};
+use crate::{
+    parser::ParserExt,
+    plugin::CustomPlugin,
+    rewriter::escape_sql_string,
+    statement::CustomStatement,
+};
 
 pub struct PushContextPlugin;
 
@@ -9,13 +14,23 @@ impl CustomPlugin for PushContextPlugin {
         &["PUSH", "CONTEXT"]
     }
 
-    fn parse(&self, _parser: &mut Parser<'_>) -> Result<CustomStatement, ParserError> {
-        Ok(CustomStatement::PushContext)
+    fn parse(&self, parser: &mut Parser<'_>) -> Result<CustomStatement, ParserError> {
+        let name = if parser.is_statement_end() {
+            None
+        } else {
+            Some(parser.parse_literal_string()?)
+        };
+
+        Ok(CustomStatement::PushContext(name))
     }
 
     fn rewrite(&self, stmt: CustomStatement) -> String {
         match stmt {
-            CustomStatement::PushContext => "SELECT sec_push_context();".to_string(),
+            CustomStatement::PushContext(None) => "SELECT sec_push_context();".to_string(),
+            CustomStatement::PushContext(Some(name)) => {
+                let escaped = escape_sql_string(&name);
+                format!("SELECT sec_push_context('{escaped}');")
+            }
             _ => unreachable!(),
         }
     }
@@ -31,12 +31,15 @@ impl CustomPlugin for RegisterSecureTablePlugin {
 
         let mut table_label = None;
         let mut insert_label = None;
+        let mut delete_label = None;
 
         while !parser.is_statement_end() {
             if parser.parse_keyword_seq(&["TABLE", "LABEL"]) {
                 table_label = Some(parser.parse_literal_string()?);
             } else if parser.parse_keyword_seq(&["INSERT", "LABEL"]) {
                 insert_label = Some(parser.parse_literal_string()?);
+            } else if parser.parse_keyword_seq(&["DELETE", "LABEL"]) {
+                delete_label = Some(parser.parse_literal_string()?);
             } else {
                 break;
             }
@@ -49,6 +52,7 @@ impl CustomPlugin for RegisterSecureTablePlugin {
                 row_label_column,
                 table_label,
                 insert_label,
+                delete_label,
             },
         ))
     }
@@ -70,8 +74,14 @@ impl CustomPlugin for RegisterSecureTablePlugin {
                     .map(|l| format!("sec_define_label('{}')", escape_sql_string(&l)))
                     .unwrap_or_else(|| "NULL".to_string());
 
+                // Only pass the optional sixth argument when it is given
+                let delete_label = stmt
+                    .delete_label
+                    .map(|l| format!(", sec_define_label('{}')", escape_sql_string(&l)))
+                    .unwrap_or_default();
+
                 format!(
-                    "SELECT sec_register_table('{escaped_logical}', '{escaped_physical}', '{escaped_row_col}', {table_label}, {insert_label});"
+                    "SELECT sec_register_table('{escaped_logical}', '{escaped_physical}', '{escaped_row_col}', {table_label}, {insert_label}{delete_label});"
                 )
             }
             _ => unreachable!(),
@@ -0,0 +1,44 @@
+use sqlparser::{
+    keywords::Keyword,
+    parser::{Parser, ParserError},
+};
+
+use crate::{
+    parser::ParserExt,
+    plugin::CustomPlugin,
+    rewriter::escape_sql_string,
+    statement::{CustomStatement, SecureTableStmt},
+};
+
+pub struct SecureTablePlugin;
+
+impl CustomPlugin for SecureTablePlugin {
+    fn prefix(&self) -> &'static [&'static str] {
+        &["SECURE", "TABLE"]
+    }
+
+    fn parse(&self, parser: &mut Parser<'_>) -> Result<CustomStatement, ParserError> {
+        let table = parser.parse_identifier()?.value;
+
+        parser.expect_keyword(Keyword::WITH)?;
+        parser.expect_word("DEFAULT")?;
+        parser.expect_word("LABEL")?;
+        let default_label = parser.parse_literal_string()?;
+
+        Ok(CustomStatement::SecureTable(SecureTableStmt {
+            table,
+            default_label,
+        }))
+    }
+
+    fn rewrite(&self, stmt: CustomStatement) -> String {
+        match stmt {
+            CustomStatement::SecureTable(stmt) => {
+                let escaped_table = escape_sql_string(&stmt.table);
+                let escaped_label = escape_sql_string(&stmt.default_label);
+                format!("SELECT sec_secure_existing_table('{escaped_table}', '{escaped_label}');")
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -21,12 +21,15 @@ impl CustomPlugin for SetColumnSecurityPlugin {
 
         let mut read_label = None;
         let mut update_label = None;
+        let mut mask = None;
 
         while !parser.is_statement_end() {
             if parser.parse_keyword_seq(&["READ"]) {
                 read_label = Some(parser.parse_literal_string()?);
             } else if parser.parse_keyword_seq(&["UPDATE"]) {
                 update_label = Some(parser.parse_literal_string()?);
+            } else if parser.parse_keyword_seq(&["MASK"]) {
+                mask = Some(parser.parse_literal_string()?);
             } else {
                 break;
             }
@@ -37,6 +40,7 @@ impl CustomPlugin for SetColumnSecurityPlugin {
             column,
             read_label,
             update_label,
+            mask,
         }))
     }
 
@@ -72,6 +76,14 @@ impl CustomPlugin for SetColumnSecurityPlugin {
                     ));
                 }
 
+                // After READ, so the mask is checked against the new label
+                if let Some(mask) = stmt.mask {
+                    let escaped = escape_sql_string(&mask);
+                    stmts.push(format!(
+                        "SELECT sec_set_column_mask('{escaped_table}', '{escaped_column}', '{escaped}');"
+                    ));
+                }
+
                 if stmts.is_empty() {
                     "SELECT 1;".to_string()
                 } else {
@@ -16,11 +16,11 @@ pub enum CustomStatement {
     /// CLEAR CONTEXT
     ClearContext,
 
-    /// PUSH CONTEXT
-    PushContext,
+    /// PUSH CONTEXT ['name']
+    PushContext(Option<String>),
 
-    /// POP CONTEXT
-    PopContext,
+    /// POP CONTEXT ['name']
+    PopContext(Option<String>),
 
     /// REFRESH SECURITY VIEWS
     RefreshSecureViews,
@@ -29,9 +29,12 @@ pub enum CustomStatement {
     CreateSecureView(CreateSecureViewStmt),
 
     /// REGISTER SECURE TABLE logical ON physical WITH ROW LABEL column
-    ///     [TABLE LABEL label_expr] [INSERT LABEL label_expr]
+    ///     [TABLE LABEL label_expr] [INSERT LABEL label_expr] [DELETE LABEL label_expr]
     RegisterSecureTable(RegisterSecureTableStmt),
 
+    /// SECURE TABLE name WITH DEFAULT LABEL 'label_expr'
+    SecureTable(SecureTableStmt),
+
     /// DEFINE LABEL 'expr'
     DefineLabel(DefineLabelStmt),
 
@@ -95,6 +98,14 @@ pub struct RegisterSecureTableStmt {
     pub row_label_column: String,
     pub table_label: Option<String>,
     pub insert_label: Option<String>,
+    pub delete_label: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SecureTableStmt {
+    pub table: String,
+    /// Label given to the rows already in the table
+    pub default_label: String,
 }
 
 #[derive(Debug, Clone)]
@@ -115,6 +126,8 @@ pub struct SetColumnSecurityStmt {
     pub column: String,
     pub read_label: Option<String>,
     pub update_label: Option<String>,
+    /// SQL expression shown in place of the value when `read_label` fails
+    pub mask: Option<String>,
 }
 
 #[derive(Debug, Clone)]